@@ -0,0 +1,198 @@
+//! A fake LSP that is used by the examples
+//!
+//! [`MockTransport`] implements [`LspClient`] without talking to
+//! lightningd. Every request is answered by a [`MockLsp`] that runs
+//! in the same process.
+//!
+//! Not every example uses every helper
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::json;
+
+use cln_lsps::client::LspClient;
+use lsp_primitives::json_rpc::{ErrorData, JsonRpcId};
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps1::schema::{Lsps1CreateOrderRequest, Lsps1Options};
+
+const LSP_ID: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+/// The state of the fake LSP
+pub struct MockLsp {
+    options: Lsps1Options,
+    orders: HashMap<String, serde_json::Value>,
+    /// A silent LSP never responds
+    silent: bool,
+}
+
+impl MockLsp {
+    pub fn shared() -> Arc<Mutex<Self>> {
+        let options = serde_json::from_value(json!({
+            "min_required_channel_confirmations": 0,
+            "min_funding_confirms_within_blocks": 6,
+            "min_onchain_payment_confirmations": null,
+            "supports_zero_channel_reserve": false,
+            "min_onchain_payment_size_sat": null,
+            "max_channel_expiry_blocks": 20160,
+            "min_initial_client_balance_sat": "0",
+            "max_initial_client_balance_sat": "0",
+            "min_initial_lsp_balance_sat": "100000",
+            "max_initial_lsp_balance_sat": "10000000",
+            "min_channel_balance_sat": "100000",
+            "max_channel_balance_sat": "10000000"
+        }))
+        .expect("Valid options");
+
+        Arc::new(Mutex::new(Self {
+            options,
+            orders: HashMap::new(),
+            silent: false,
+        }))
+    }
+
+    /// Makes the LSP stop responding to requests
+    pub fn go_silent(&mut self) {
+        self.silent = true;
+    }
+
+    /// Pays the invoice of an order
+    ///
+    /// A real client would call `pay` on its node. The channel is
+    /// opened once the LSP sees the payment.
+    pub fn pay(&mut self, bolt11_invoice: &str) -> Result<()> {
+        let order = self
+            .orders
+            .values_mut()
+            .find(|o| o["payment"]["bolt11_invoice"] == bolt11_invoice)
+            .context("Unknown invoice")?;
+        order["payment"]["state"] = json!("PAID");
+        Ok(())
+    }
+
+    /// Returns `None` if the LSP doesn't respond
+    fn handle(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Option<Result<serde_json::Value, ErrorData>> {
+        if self.silent {
+            return None;
+        }
+
+        let result = match method {
+            "lsps1.get_info" => Ok(json!({ "options" : self.options })),
+            "lsps1.create_order" => self.create_order(params),
+            "lsps1.get_order" => self.get_order(params),
+            _ => Err(ErrorData::method_not_found(method)),
+        };
+        Some(result)
+    }
+
+    fn create_order(&mut self, params: serde_json::Value) -> Result<serde_json::Value, ErrorData> {
+        let request: Lsps1CreateOrderRequest = serde_json::from_value(params)
+            .map_err(|err| ErrorData::invalid_params(json!(err.to_string())))?;
+        self.options.validate_order(&request)?;
+
+        let order_id = format!("00000000-0000-4000-8000-{:012}", self.orders.len());
+        let order = json!({
+            "order_id" : order_id,
+            "lsp_balance_sat" : request.lsp_balance_sat,
+            "client_balance_sat" : request.client_balance_sat,
+            "funding_confirms_within_blocks" : request.funding_confirms_within_blocks,
+            "required_channel_confirmations" : request.required_channel_confirmations,
+            "channel_expiry_blocks" : request.channel_expiry_blocks,
+            "announce_channel" : request.announce_channel,
+            "created_at" : "2024-01-01T00:00:00.000Z",
+            "expires_at" : "2024-01-02T00:00:00.000Z",
+            "order_state" : "CREATED",
+            "payment" : {
+                "state" : "EXPECT_PAYMENT",
+                "fee_total_sat" : "1000",
+                "order_total_sat" : "1000",
+                "bolt11_invoice" : format!("lnbcrt10u1mock{}", self.orders.len()),
+                "onchain_address" : null,
+                "min_onchain_payment_confirmations" : null,
+                "min_fee_for_0conf" : null,
+                "onchain_payment" : null
+            },
+            "channel" : null
+        });
+        self.orders.insert(order_id, order.clone());
+        Ok(order)
+    }
+
+    fn get_order(&mut self, params: serde_json::Value) -> Result<serde_json::Value, ErrorData> {
+        let order_id = params["order_id"].as_str().unwrap_or_default();
+        let order = self
+            .orders
+            .get_mut(order_id)
+            .ok_or_else(ErrorData::not_found)?;
+        let response = order.clone();
+
+        // The channel of a paid order is ready at the next request
+        if order["payment"]["state"] == "PAID" {
+            order["order_state"] = json!("COMPLETED");
+            order["channel"] = json!({
+                "funded_at" : "2024-01-01T00:10:00.000Z",
+                "funding_outpoint" : "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0",
+                "expires_at" : "2024-01-31T00:10:00.000Z"
+            });
+        }
+        Ok(response)
+    }
+}
+
+/// An [`LspClient`] that sends its requests to a [`MockLsp`]
+pub struct MockTransport {
+    lsp_id: PublicKey,
+    lsp: Arc<Mutex<MockLsp>>,
+    timeout: Duration,
+}
+
+impl MockTransport {
+    pub fn new(lsp: Arc<Mutex<MockLsp>>) -> Self {
+        Self {
+            lsp_id: PublicKey::from_hex(LSP_ID).expect("Valid public key"),
+            lsp,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+#[async_trait]
+impl LspClient for MockTransport {
+    async fn request_value(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        rpc_id: JsonRpcId,
+    ) -> Result<serde_json::Value> {
+        anyhow::ensure!(peer_id == &self.lsp_id, "Unknown peer {}", peer_id.to_hex());
+
+        let response = self.lsp.lock().unwrap().handle(method, params);
+        let response_value = match response {
+            Some(Ok(result)) => json!({ "jsonrpc" : "2.0", "id" : rpc_id, "result" : result }),
+            Some(Err(error)) => json!({ "jsonrpc" : "2.0", "id" : rpc_id, "error" : error }),
+            // Behaves like the real client when the peer doesn't respond
+            None => tokio::time::timeout(self.timeout, std::future::pending())
+                .await
+                .with_context(|| "Time-out, waiting for peer to respond")?,
+        };
+
+        Ok(response_value)
+    }
+
+    async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+        Ok(vec![self.lsp_id])
+    }
+}
@@ -0,0 +1,85 @@
+//! Handles the errors a client should expect
+//!
+//! - An order that doesn't satisfy the options of the LSP is refused
+//!   with the `option_mismatch` reason. The client can catch most of
+//!   these before sending the request.
+//! - An LSP that doesn't respond results in a time-out.
+//!
+//! The LSP is mocked, so the example runs without lightningd.
+//!
+//! ```text
+//! cargo run --example handle_errors
+//! ```
+mod common;
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::time::error::Elapsed;
+
+use cln_lsps::client::{LspClient, LspClientExt};
+use lsp_primitives::json_rpc::{JsonRpcResponse, LspsErrorReason};
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::lsps1::builders::Lsps1CreateOrderRequestBuilder;
+use lsp_primitives::lsps1::util::Lsps1OptionMismatchError;
+use lsp_primitives::methods;
+
+use crate::common::{MockLsp, MockTransport};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let lsp = MockLsp::shared();
+    let mut client = MockTransport::new(lsp.clone());
+    let lsps = client.list_lsps().await?;
+    let peer_id = lsps.first().context("No LSP found")?;
+
+    // The LSP sells channels of at most 10_000_000 sat
+    let info = client.lsps1_get_info(peer_id).await?;
+    let request = Lsps1CreateOrderRequestBuilder::new()
+        .lsp_balance_sat(SatAmount::new(50_000_000))
+        .channel_expiry_blocks(4_320)
+        .build()?;
+
+    // Checking the options first saves a round-trip
+    match info.options.validate_order(&request) {
+        Ok(()) => bail!("Expected the order to exceed the options"),
+        Err(err) => println!("Refused before sending: {:?}", err),
+    }
+
+    // The LSP performs the same check. The `reason` identifies the error
+    let response = client
+        .request(peer_id, methods::LSPS1_CREATE_ORDER, request)
+        .await?;
+    let error = match response {
+        JsonRpcResponse::Ok(_) => bail!("Expected the LSP to refuse the order"),
+        JsonRpcResponse::Error(err) => err.error,
+    };
+    match error.reason() {
+        Some(LspsErrorReason::OptionMismatch) => {
+            let data = error.data.context("An option_mismatch has data")?;
+            let mismatch: Lsps1OptionMismatchError = serde_json::from_value(data.clone())?;
+            println!("Refused by the LSP: {:?}", mismatch);
+            anyhow::ensure!(data["property"] == "max_initial_lsp_balance_sat");
+        }
+        _ => bail!("Unexpected error: {}", error),
+    }
+
+    // An LSP that doesn't respond fails the request with a time-out
+    lsp.lock().unwrap().go_silent();
+    client.set_timeout(Duration::from_millis(50));
+    let err = match client.lsps1_get_info(peer_id).await {
+        Ok(_) => bail!("Expected a time-out"),
+        Err(err) => err,
+    };
+    match err.downcast_ref::<Elapsed>() {
+        Some(_) => println!("The LSP didn't respond. Try again later: {:#}", err),
+        None => bail!("Unexpected error: {:?}", err),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn handle_errors() {
+    main().unwrap()
+}
@@ -0,0 +1,88 @@
+//! Buys a channel from an LSP
+//!
+//! The example finds an LSP, checks its options, creates an order, pays
+//! it and waits until the channel is ready. The LSP is mocked, so the
+//! example runs without lightningd.
+//!
+//! ```text
+//! cargo run --example order_channel
+//! ```
+mod common;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use cln_lsps::client::{DynLspClient, LspClient, LspClientExt};
+use cln_lsps::updates::FinalStates;
+use lsp_primitives::json_rpc::JsonRpcResponse;
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::lsps1::builders::Lsps1CreateOrderRequestBuilder;
+use lsp_primitives::lsps1::schema::Lsps1GetOrderRequest;
+use lsp_primitives::methods;
+
+use crate::common::{MockLsp, MockTransport};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    // Any LspClient can be boxed. E.g: to store it in a struct
+    let lsp = MockLsp::shared();
+    let mut client: DynLspClient = Box::new(MockTransport::new(lsp.clone()));
+
+    // Connect to the first LSP we know about
+    let lsps = client.list_lsps().await?;
+    let peer_id = lsps.first().context("No LSP found")?;
+
+    // The options tell which orders the LSP accepts
+    let info = client.lsps1_get_info(peer_id).await?;
+    let request = Lsps1CreateOrderRequestBuilder::new()
+        .lsp_balance_sat(SatAmount::new(1_000_000))
+        .channel_expiry_blocks(4_320)
+        .build()?;
+    info.options
+        .validate_order(&request)
+        .map_err(|err| anyhow!("The LSP doesn't accept the order: {:?}", err))?;
+
+    let order = client.lsps1_create_order(peer_id, request).await?;
+    println!(
+        "Created order {} for {} sat",
+        order.order_id, order.payment.order_total_sat
+    );
+
+    // A wallet pays the invoice using its node
+    lsp.lock().unwrap().pay(&order.payment.bolt11_invoice)?;
+
+    // Poll the order until it completes or fails
+    let final_states = FinalStates::default();
+    let update = loop {
+        let request = Lsps1GetOrderRequest {
+            order_id: order.order_id,
+            wait_for_change_seconds: None,
+        };
+        let order = match client
+            .request(peer_id, methods::LSPS1_GET_ORDER, request)
+            .await?
+        {
+            JsonRpcResponse::Ok(ok) => ok.result,
+            JsonRpcResponse::Error(err) => bail!("Failed to get order: {}", err.error),
+        };
+
+        if let Some(update) = final_states.take_update(peer_id, &order) {
+            break update;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    anyhow::ensure!(
+        update["order_state"] == "COMPLETED",
+        "Order failed: {}",
+        update
+    );
+    println!("Channel {} is ready", update["channel"]["funding_outpoint"]);
+    Ok(())
+}
+
+#[test]
+fn order_channel() {
+    main().unwrap()
+}
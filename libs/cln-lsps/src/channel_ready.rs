@@ -0,0 +1,444 @@
+//! Tells the wallet when the channel of an order can be used
+//!
+//! A wallet wants to know when it can receive over a purchased channel
+//! without polling `lsps1.get_order`. Our node sees the channel become
+//! ready itself. The channel is correlated with the orders that were
+//! bought from the same peer.
+//!
+//! The funding outpoint identifies the order once the LSP reported it in
+//! `lsps1.get_order`. Otherwise, an order with the same capacity that was
+//! created within [`CAPACITY_MATCH_WINDOW`] before the channel became
+//! ready is picked. Such a match comes with a warning because another
+//! order to the same LSP might have the same capacity.
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use lsp_primitives::lsps0::common_schemas::{
+    IsoDatetime, Outpoint, PublicKey, SatAmount, TransactionId,
+};
+use lsp_primitives::lsps1::schema::OrderState;
+
+use crate::pins::{OrderPins, PinnedOrder};
+
+/// The warning attached to orders that were matched by capacity
+pub const MATCHED_BY_CAPACITY: &str = "matched_by_capacity";
+
+/// The warning attached if other orders could have matched the channel
+pub const AMBIGUOUS_ORDER: &str = "ambiguous_order";
+
+/// An order only matches a channel by capacity if the channel became
+/// ready within this duration after the order was created
+pub const CAPACITY_MATCH_WINDOW: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// The state of a channel that can be used to make payments
+const CHANNELD_NORMAL: &str = "CHANNELD_NORMAL";
+
+/// Returns the peer of a channel that became ready
+///
+/// Handles the `channel_opened` and `channel_state_changed`
+/// notifications. Returns `None` if the channel isn't ready yet.
+pub fn ready_peer_id(topic: &str, value: &serde_json::Value) -> Result<Option<PublicKey>> {
+    // Newer versions of Core Lightning wrap the notification data
+    // in an object named after the topic
+    let data = value.get(topic).unwrap_or(value);
+    let (peer_id, is_ready) = match topic {
+        "channel_opened" => (&data["id"], data["channel_ready"].as_bool() == Some(true)),
+        "channel_state_changed" => (
+            &data["peer_id"],
+            data["new_state"].as_str() == Some(CHANNELD_NORMAL),
+        ),
+        _ => return Ok(None),
+    };
+    if !is_ready {
+        return Ok(None);
+    }
+
+    let peer_id = peer_id
+        .as_str()
+        .with_context(|| format!("Missing peer in {} notification", topic))?;
+    Ok(Some(PublicKey::from_hex(peer_id)?))
+}
+
+/// A channel that our node can use to make payments
+#[derive(Debug, Clone)]
+pub struct ReadyChannel {
+    pub peer_id: PublicKey,
+    pub funding_outpoint: Outpoint,
+    pub capacity: SatAmount,
+    pub short_channel_id: Option<String>,
+    pub ready_at: IsoDatetime,
+}
+
+impl ReadyChannel {
+    /// Reads a channel listed by `listpeerchannels`
+    ///
+    /// Returns `None` if the channel isn't ready.
+    pub fn from_peer_channel(
+        peer_id: &PublicKey,
+        channel: &serde_json::Value,
+        ready_at: IsoDatetime,
+    ) -> Result<Option<Self>> {
+        if channel["state"].as_str() != Some(CHANNELD_NORMAL) {
+            return Ok(None);
+        }
+
+        let funding_txid = channel["funding_txid"]
+            .as_str()
+            .context("Missing funding_txid")?;
+        let outnum = channel["funding_outnum"]
+            .as_u64()
+            .context("Missing funding_outnum")?;
+        let total_msat = channel["total_msat"]
+            .as_u64()
+            .context("Missing total_msat")?;
+
+        Ok(Some(Self {
+            peer_id: *peer_id,
+            funding_outpoint: Outpoint {
+                txid: funding_txid.parse::<TransactionId>()?,
+                outnum: u32::try_from(outnum)?,
+            },
+            capacity: SatAmount::new(total_msat / 1000),
+            short_channel_id: channel["short_channel_id"].as_str().map(|s| s.to_string()),
+            ready_at,
+        }))
+    }
+}
+
+/// How a channel was correlated with its order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchedBy {
+    FundingOutpoint,
+    Capacity,
+}
+
+impl MatchedBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::FundingOutpoint => "funding_outpoint",
+            Self::Capacity => "capacity",
+        }
+    }
+}
+
+/// The order that a [`ReadyChannel`] belongs to
+#[derive(Debug, Clone)]
+pub struct ChannelMatch {
+    pub order: PinnedOrder,
+    pub matched_by: MatchedBy,
+    pub warnings: Vec<&'static str>,
+}
+
+/// Finds the order that `channel` belongs to
+///
+/// Returns `None` if no order matches or if the order was reported
+/// as ready before.
+pub fn find_order(orders: &[PinnedOrder], channel: &ReadyChannel) -> Option<ChannelMatch> {
+    let orders: Vec<&PinnedOrder> = orders
+        .iter()
+        .filter(|o| o.peer_id == channel.peer_id)
+        .collect();
+
+    if let Some(order) = orders
+        .iter()
+        .find(|o| o.funding_outpoint.as_ref() == Some(&channel.funding_outpoint))
+    {
+        if order.channel_ready {
+            return None;
+        }
+        return Some(ChannelMatch {
+            order: (*order).clone(),
+            matched_by: MatchedBy::FundingOutpoint,
+            warnings: vec![],
+        });
+    }
+
+    // Orders with a known outpoint belong to another channel
+    let ready_at = channel.ready_at.unix_timestamp();
+    let window = i64::try_from(CAPACITY_MATCH_WINDOW.as_secs()).ok()?;
+    let mut candidates: Vec<(&PinnedOrder, i64)> = orders
+        .into_iter()
+        .filter(|o| !o.channel_ready && o.funding_outpoint.is_none())
+        .filter(|o| o.order_state != Some(OrderState::Failed))
+        .filter(|o| o.channel_capacity == Some(channel.capacity))
+        .filter_map(|o| Some((o, o.created_at?.unix_timestamp())))
+        .filter(|(_, created_at)| *created_at <= ready_at && ready_at <= created_at + window)
+        .collect();
+    candidates.sort_by(|a, b| (a.1, &a.0.order_id).cmp(&(b.1, &b.0.order_id)));
+
+    let (order, _) = candidates.first()?;
+    let mut warnings = vec![MATCHED_BY_CAPACITY];
+    if candidates.len() > 1 {
+        warnings.push(AMBIGUOUS_ORDER);
+    }
+    Some(ChannelMatch {
+        order: (*order).clone(),
+        matched_by: MatchedBy::Capacity,
+        warnings,
+    })
+}
+
+/// The result of [`complete_ready_orders`] for a single order
+#[derive(Debug)]
+pub struct ReadyOrder {
+    /// The order after it was completed. It must be stored
+    pub order: PinnedOrder,
+    /// The content of the `lsps1_channel_ready`-notification
+    pub notification: serde_json::Value,
+    pub warnings: Vec<&'static str>,
+}
+
+/// Completes the orders whose channel is in `channels`
+///
+/// Every order is completed once. The completed orders must be stored
+/// and reported using the `lsps1_channel_ready`-notification.
+pub fn complete_ready_orders(pins: &OrderPins, channels: &[ReadyChannel]) -> Vec<ReadyOrder> {
+    let mut completed = vec![];
+    for channel in channels {
+        let Some(found) = find_order(&pins.list(), channel) else {
+            continue;
+        };
+        let Some(order) = pins.complete(
+            &channel.peer_id,
+            &found.order.order_id,
+            &channel.funding_outpoint,
+        ) else {
+            continue;
+        };
+
+        let notification = json!({
+            "peer_id" : channel.peer_id,
+            "order_id" : order.order_id,
+            "funding_outpoint" : channel.funding_outpoint,
+            "short_channel_id" : channel.short_channel_id,
+            "capacity_sat" : channel.capacity,
+            "matched_by" : found.matched_by.as_str(),
+            "warnings" : found.warnings,
+        });
+        completed.push(ReadyOrder {
+            order,
+            notification,
+            warnings: found.warnings,
+        });
+    }
+    completed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps1::schema::Lsps1GetOrderResponse;
+
+    const ORDER: &str = "bb4b5d0a-8334-49d8-9463-90a6d413af7c";
+    const OTHER_ORDER: &str = "5cdc8a2d-4c2d-4d43-9b4c-c5f0a1d1e1b8";
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn peer() -> PublicKey {
+        PublicKey::from_hex("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap()
+    }
+
+    fn datetime(value: &str) -> IsoDatetime {
+        serde_json::from_value(json!(value)).unwrap()
+    }
+
+    fn order(
+        order_id: &str,
+        created_at: &str,
+        funding_outpoint: Option<&str>,
+    ) -> Lsps1GetOrderResponse {
+        let channel = funding_outpoint.map(|outpoint| {
+            json!({
+                "funded_at" : created_at,
+                "funding_outpoint" : outpoint,
+                "expires_at" : "2025-01-01T00:00:00.000Z",
+            })
+        });
+        serde_json::from_value(json!({
+            "order_id" : order_id,
+            "lsp_balance_sat" : "100000",
+            "client_balance_sat" : "0",
+            "funding_confirms_within_blocks" : 6,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks" : 144,
+            "announce_channel" : false,
+            "created_at" : created_at,
+            "expires_at" : "2024-01-02T00:00:00.000Z",
+            "order_state" : "CREATED",
+            "payment" : {
+                "state" : "PAID",
+                "fee_total_sat" : "1000",
+                "order_total_sat" : "101000",
+                "bolt11_invoice" : "lnbcrt1a",
+                "onchain_address" : null,
+                "min_onchain_payment_confirmations" : null,
+                "min_fee_for_0conf" : null,
+                "onchain_payment" : null
+            },
+            "channel" : channel
+        }))
+        .unwrap()
+    }
+
+    /// Pins and tracks the orders as the client does for every response
+    fn seeded_pins(orders: &[Lsps1GetOrderResponse]) -> OrderPins {
+        let pins = OrderPins::default();
+        for order in orders {
+            pins.check(&peer(), &order.order_id.to_string(), &order.payment);
+            assert!(pins.track(&peer(), order).is_some());
+        }
+        pins
+    }
+
+    /// Reads the channel of a synthetic notification as the plugin does
+    fn ready_channel(topic: &str, notification: serde_json::Value, outnum: u32) -> ReadyChannel {
+        let peer_id = ready_peer_id(topic, &notification).unwrap().unwrap();
+        let listed = json!({
+            "peer_id" : peer_id,
+            "state" : "CHANNELD_NORMAL",
+            "short_channel_id" : "103x1x0",
+            "funding_txid" : TXID,
+            "funding_outnum" : outnum,
+            "total_msat" : 100_000_000,
+        });
+        ReadyChannel::from_peer_channel(&peer_id, &listed, datetime("2024-01-01T02:00:00.000Z"))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn channel_state_changed(new_state: &str) -> serde_json::Value {
+        json!({
+            "channel_state_changed" : {
+                "peer_id" : peer().to_hex(),
+                "channel_id" : "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a",
+                "short_channel_id" : "103x1x0",
+                "timestamp" : "2024-01-01T02:00:00.000Z",
+                "old_state" : "CHANNELD_AWAITING_LOCKIN",
+                "new_state" : new_state,
+                "cause" : "remote",
+                "message" : "Lockin complete"
+            }
+        })
+    }
+
+    #[test]
+    fn only_ready_channels_are_reported() {
+        let awaiting = channel_state_changed("CHANNELD_AWAITING_LOCKIN");
+        assert!(ready_peer_id("channel_state_changed", &awaiting)
+            .unwrap()
+            .is_none());
+
+        let mut opened = json!({
+            "id" : peer().to_hex(),
+            "funding_msat" : 100_000_000,
+            "funding_txid" : TXID,
+            "channel_ready" : false,
+        });
+        assert!(ready_peer_id("channel_opened", &opened).unwrap().is_none());
+
+        // A zero-conf channel is ready when it is opened
+        opened["channel_ready"] = json!(true);
+        let wrapped = json!({ "channel_opened" : opened });
+        assert_eq!(
+            ready_peer_id("channel_opened", &wrapped).unwrap(),
+            Some(peer())
+        );
+    }
+
+    #[test]
+    fn channel_is_matched_by_funding_outpoint() {
+        // Both orders have the same capacity
+        let pins = seeded_pins(&[
+            order(
+                ORDER,
+                "2024-01-01T00:00:00.000Z",
+                Some(&format!("{}:1", TXID)),
+            ),
+            order(OTHER_ORDER, "2024-01-01T01:00:00.000Z", None),
+        ]);
+        let channel = ready_channel(
+            "channel_state_changed",
+            channel_state_changed("CHANNELD_NORMAL"),
+            1,
+        );
+
+        let ready = complete_ready_orders(&pins, &[channel.clone()]);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].order.order_id, ORDER);
+        assert!(ready[0].warnings.is_empty());
+        assert_eq!(ready[0].notification["matched_by"], "funding_outpoint");
+        assert_eq!(
+            ready[0].notification["funding_outpoint"],
+            format!("{}:1", TXID)
+        );
+        assert_eq!(ready[0].notification["capacity_sat"], "100000");
+
+        // The stored order is completed and reported only once
+        let stored = pins.get(&peer(), ORDER).unwrap();
+        assert_eq!(stored.order_state, Some(OrderState::Completed));
+        assert!(stored.channel_ready);
+        assert!(complete_ready_orders(&pins, &[channel]).is_empty());
+        assert!(!pins.get(&peer(), OTHER_ORDER).unwrap().channel_ready);
+    }
+
+    #[test]
+    fn channel_falls_back_to_capacity_with_a_warning() {
+        let pins = seeded_pins(&[
+            order(ORDER, "2024-01-01T00:00:00.000Z", None),
+            order(OTHER_ORDER, "2024-01-01T01:00:00.000Z", None),
+        ]);
+        let channel = ready_channel(
+            "channel_state_changed",
+            channel_state_changed("CHANNELD_NORMAL"),
+            0,
+        );
+
+        // The oldest order is picked but the other one could have matched
+        let ready = complete_ready_orders(&pins, &[channel]);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].order.order_id, ORDER);
+        assert_eq!(
+            ready[0].warnings,
+            vec![MATCHED_BY_CAPACITY, AMBIGUOUS_ORDER]
+        );
+        assert_eq!(ready[0].notification["matched_by"], "capacity");
+        assert_eq!(
+            pins.get(&peer(), ORDER).unwrap().funding_outpoint,
+            Some(format!("{}:0", TXID).parse().unwrap())
+        );
+
+        // The LSP doesn't revert the local completion
+        let mut reported = order(
+            ORDER,
+            "2024-01-01T00:00:00.000Z",
+            Some(&format!("{}:0", TXID)),
+        );
+        reported.order_state = OrderState::Created;
+        pins.track(&peer(), &reported);
+        assert_eq!(
+            pins.get(&peer(), ORDER).unwrap().order_state,
+            Some(OrderState::Completed)
+        );
+    }
+
+    #[test]
+    fn capacity_match_requires_the_time_window() {
+        // The channel became ready long after the order was created
+        let pins = seeded_pins(&[order(ORDER, "2023-11-01T00:00:00.000Z", None)]);
+        let channel = ready_channel(
+            "channel_state_changed",
+            channel_state_changed("CHANNELD_NORMAL"),
+            0,
+        );
+        assert!(find_order(&pins.list(), &channel).is_none());
+
+        // A channel of another size belongs to another order
+        let pins = seeded_pins(&[order(ORDER, "2024-01-01T00:00:00.000Z", None)]);
+        let mut channel = channel;
+        channel.capacity = SatAmount::new(200_000);
+        assert!(find_order(&pins.list(), &channel).is_none());
+    }
+}
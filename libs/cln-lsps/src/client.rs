@@ -1,17 +1,24 @@
 use std::io::{Cursor, Write};
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use lsp_primitives::json_rpc::{
-    generate_random_rpc_id, JsonRpcId, JsonRpcMethod, JsonRpcResponse, NoParams,
+    generate_random_rpc_id, ErrorData, JsonRpcId, JsonRpcMethod, JsonRpcResponse,
+    JsonRpcResponseSuccess, NoParams,
 };
 
+use lsp_primitives::hex;
 use lsp_primitives::lsps0;
 use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps0::parameter_validation::ExpectedFields;
+use lsp_primitives::lsps0::response_validation::validate_response;
 use lsp_primitives::lsps1;
 use lsp_primitives::methods;
 
 use async_trait::async_trait;
 
+use crate::deadline::{Deadline, Stage};
+
 #[derive(Hash, PartialEq, Eq, Debug, Clone)]
 pub struct RequestId {
     peer_id: PublicKey,
@@ -24,9 +31,146 @@ impl RequestId {
     }
 }
 
-// BOLT8 message ID 37913
-pub const LSPS_MESSAGE_ID: [u8; 2] = [0x94, 0x19];
-pub const LSPS_MESSAGE_ID_U16: u16 = 30971;
+/// Errors of the [`LspClient`] that a caller might want to handle
+///
+/// They are returned wrapped in an [`anyhow::Error`] and can be
+/// retrieved using `downcast_ref`.
+#[derive(Debug)]
+pub enum LspClientError {
+    /// The LSP-server sent a response that isn't a valid JSON-RPC 2.0
+    /// response or doesn't match the schema of the method.
+    ProtocolViolation {
+        message: String,
+        payload: serde_json::Value,
+    },
+    /// The LSP-server responded to `method` with a JSON-RPC 2.0 error
+    ErrorResponse { method: String, error: ErrorData },
+    /// The LSP-server can't handle `method` right now
+    ///
+    /// E.g: because the operator disabled it for maintenance. The request
+    /// can be retried after `retry_after` if the server provided it.
+    Retryable {
+        method: String,
+        error: ErrorData,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl LspClientError {
+    fn error_response(method: &str, error: ErrorData) -> anyhow::Error {
+        let method = method.to_string();
+        if error.is_retryable() {
+            let retry_after = error.retry_after();
+            anyhow::Error::new(Self::Retryable {
+                method,
+                error,
+                retry_after,
+            })
+        } else {
+            anyhow::Error::new(Self::ErrorResponse { method, error })
+        }
+    }
+
+    /// Returns true if the LSP-server doesn't implement the method
+    ///
+    /// Some LSP-servers list a protocol but don't answer all of its
+    /// methods. E.g: because they are misconfigured.
+    pub fn is_method_not_found(&self) -> bool {
+        match self {
+            Self::ErrorResponse { error, .. } => {
+                error.code == lsp_primitives::json_rpc::error::codes::METHOD_NOT_FOUND_CODE
+            }
+            Self::ProtocolViolation { .. } | Self::Retryable { .. } => false,
+        }
+    }
+}
+
+/// Returns the time to wait if `err` is a [`LspClientError::Retryable`]
+///
+/// The outer `None` means the request shouldn't be retried. The inner
+/// `None` means the LSP-server didn't say how long to wait.
+pub fn retry_after(err: &anyhow::Error) -> Option<Option<Duration>> {
+    match err.downcast_ref::<LspClientError>() {
+        Some(LspClientError::Retryable { retry_after, .. }) => Some(*retry_after),
+        _ => None,
+    }
+}
+
+/// Returns true if `err` is a [`LspClientError`] that reports a method
+/// the LSP-server doesn't implement
+pub fn is_method_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<LspClientError>()
+        .is_some_and(LspClientError::is_method_not_found)
+}
+
+impl std::fmt::Display for LspClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ProtocolViolation { message, .. } => {
+                write!(f, "LSP-server violated the protocol: {}", message)
+            }
+            Self::ErrorResponse { method, error } | Self::Retryable { method, error, .. } => {
+                write!(f, "LSP-server responded to {} with {}", method, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LspClientError {}
+
+/// Parses the response of an LSP-server
+///
+/// Malformed responses result in a [`LspClientError::ProtocolViolation`]
+pub fn parse_response<O, E>(payload: serde_json::Value) -> Result<JsonRpcResponse<O, E>>
+where
+    O: serde::de::DeserializeOwned,
+    E: serde::de::DeserializeOwned,
+{
+    serde_json::from_value(payload.clone()).map_err(|err| {
+        anyhow::Error::new(LspClientError::ProtocolViolation {
+            message: err.to_string(),
+            payload,
+        })
+    })
+}
+
+/// How strictly the results of an LSP-server are validated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseValidation {
+    /// Unknown properties are ignored or kept as extra fields
+    #[default]
+    Lenient,
+    /// Every property must be part of the schema of the method
+    ///
+    /// A malformed result is reported with the path of the property.
+    /// E.g: `payment.fee_total_sat`
+    Strict,
+}
+
+/// Parses the result of a successful response according to `validation`
+///
+/// Malformed results result in a [`LspClientError::ProtocolViolation`]
+pub fn parse_result<O>(result: serde_json::Value, validation: ResponseValidation) -> Result<O>
+where
+    O: serde::de::DeserializeOwned + ExpectedFields,
+{
+    let parsed = match validation {
+        ResponseValidation::Lenient => {
+            serde_json::from_value(result.clone()).map_err(|err| err.to_string())
+        }
+        ResponseValidation::Strict => {
+            validate_response(result.clone()).map_err(|err| err.to_string())
+        }
+    };
+    parsed.map_err(|message| {
+        anyhow::Error::new(LspClientError::ProtocolViolation {
+            message,
+            payload: result,
+        })
+    })
+}
+
+pub use lsp_primitives::lsps0::message_id::{LSPS_MESSAGE_ID, LSPS_MESSAGE_ID_U16};
 pub const TIMEOUT_MILLIS: u128 = 30_000;
 
 /// The LspClient
@@ -45,8 +189,227 @@ pub const TIMEOUT_MILLIS: u128 = 30_000;
 /// - `GrpcLspClient`: Talks to Core Lighting using gRPC. Recommended when
 ///   the LSP-client and Core Lightning node are not running on the same
 ///   device. (e.g: Greenlight)
+///
+/// The trait is object-safe. A client can be stored as [`DynLspClient`].
+/// The typed requests are provided by [`LspClientExt`].
+#[async_trait]
+pub trait LspClient: Send {
+    /// Make a JSON-RPC 2.0 request to an LSP-server and return the
+    /// response without parsing it.
+    ///
+    /// This is the only request an implementation has to provide.
+    /// Callers should use [`LspClientExt::request`] which serializes
+    /// the params and parses the response.
+    async fn request_value(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        rpc_id: JsonRpcId,
+    ) -> Result<serde_json::Value>;
+
+    /// Like [`LspClient::request_value`] but gives up once `deadline` passes
+    ///
+    /// Implementations that can tell sending the request apart from
+    /// waiting for the response should override this method. The default
+    /// attributes all time to [`Stage::AwaitResponse`].
+    async fn request_value_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        rpc_id: JsonRpcId,
+        deadline: &Deadline,
+    ) -> Result<serde_json::Value> {
+        deadline
+            .run(
+                Stage::AwaitResponse,
+                self.request_value(peer_id, method, params, rpc_id),
+            )
+            .await?
+    }
+
+    async fn list_lsps(&mut self) -> Result<Vec<PublicKey>>;
+
+    /// How strictly the typed requests validate the results
+    ///
+    /// See [`LspClientExt::request_validated`]
+    fn response_validation(&self) -> ResponseValidation {
+        ResponseValidation::Lenient
+    }
+
+    async fn lsps0_list_protocols(
+        &mut self,
+        peer_id: &PublicKey,
+    ) -> Result<lsps0::schema::ListprotocolsResponse> {
+        self.lsps0_list_protocols_with_deadline(peer_id, &Deadline::none())
+            .await
+    }
+
+    async fn lsps0_list_protocols_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        deadline: &Deadline,
+    ) -> Result<lsps0::schema::ListprotocolsResponse> {
+        let response = self
+            .request_validated_with_deadline(
+                peer_id,
+                methods::LSPS0_LIST_PROTOCOLS,
+                NoParams,
+                deadline,
+            )
+            .await?;
+        match response {
+            JsonRpcResponse::Error(err) => Err(LspClientError::error_response(
+                methods::LSPS0_LIST_PROTOCOLS.name(),
+                err.error,
+            )),
+            JsonRpcResponse::Ok(ok) => Ok(ok.result),
+        }
+    }
+
+    async fn lsps1_get_info(
+        &mut self,
+        peer_id: &PublicKey,
+    ) -> Result<lsps1::schema::Lsps1GetInfoResponse> {
+        self.lsps1_get_info_with_deadline(peer_id, &Deadline::none())
+            .await
+    }
+
+    async fn lsps1_get_info_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        deadline: &Deadline,
+    ) -> Result<lsps1::schema::Lsps1GetInfoResponse> {
+        let response = self
+            .request_validated_with_deadline(peer_id, methods::LSPS1_GETINFO, NoParams, deadline)
+            .await?;
+        match response {
+            JsonRpcResponse::Error(err) => Err(LspClientError::error_response(
+                methods::LSPS1_GETINFO.name(),
+                err.error,
+            )),
+            JsonRpcResponse::Ok(ok) => Ok(ok.result),
+        }
+    }
+
+    async fn lsps1_create_order(
+        &mut self,
+        peer_id: &PublicKey,
+        order_request: lsps1::schema::Lsps1CreateOrderRequest,
+    ) -> Result<lsps1::schema::Lsps1CreateOrderResponse> {
+        self.lsps1_create_order_with_deadline(peer_id, order_request, &Deadline::none())
+            .await
+    }
+
+    async fn lsps1_create_order_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        order_request: lsps1::schema::Lsps1CreateOrderRequest,
+        deadline: &Deadline,
+    ) -> Result<lsps1::schema::Lsps1CreateOrderResponse> {
+        let response = self
+            .request_validated_with_deadline(
+                peer_id,
+                methods::LSPS1_CREATE_ORDER,
+                order_request,
+                deadline,
+            )
+            .await?;
+
+        // TODO: We probably want to store this order in the data-store
+        match response {
+            JsonRpcResponse::Error(err) => Err(LspClientError::error_response(
+                methods::LSPS1_CREATE_ORDER.name(),
+                err.error,
+            )),
+            JsonRpcResponse::Ok(ok) => Ok(ok.result),
+        }
+    }
+    async fn lsps1_get_order(
+        &mut self,
+        peer_id: &PublicKey,
+        order_id: lsps1::schema::OrderId,
+    ) -> Result<lsps1::schema::Lsps1GetOrderResponse> {
+        self.lsps1_get_order_with_deadline(peer_id, order_id, &Deadline::none())
+            .await
+    }
+
+    async fn lsps1_get_order_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        order_id: lsps1::schema::OrderId,
+        deadline: &Deadline,
+    ) -> Result<lsps1::schema::Lsps1GetOrderResponse> {
+        let get_order_request = lsps1::schema::Lsps1GetOrderRequest {
+            order_id,
+            wait_for_change_seconds: None,
+        };
+        let response = self
+            .request_validated_with_deadline(
+                peer_id,
+                methods::LSPS1_GET_ORDER,
+                get_order_request,
+                deadline,
+            )
+            .await?;
+        match response {
+            JsonRpcResponse::Error(err) => Err(LspClientError::error_response(
+                methods::LSPS1_GET_ORDER.name(),
+                err.error,
+            )),
+            JsonRpcResponse::Ok(ok) => Ok(ok.result),
+        }
+    }
+}
+
+/// An [`LspClient`] whose type is only known at runtime
+///
+/// Allows to store a client in a struct without naming its type.
+pub type DynLspClient = Box<dyn LspClient + Send>;
+
+#[async_trait]
+impl<C: LspClient + ?Sized> LspClient for Box<C> {
+    async fn request_value(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        rpc_id: JsonRpcId,
+    ) -> Result<serde_json::Value> {
+        (**self)
+            .request_value(peer_id, method, params, rpc_id)
+            .await
+    }
+
+    async fn request_value_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        rpc_id: JsonRpcId,
+        deadline: &Deadline,
+    ) -> Result<serde_json::Value> {
+        (**self)
+            .request_value_with_deadline(peer_id, method, params, rpc_id, deadline)
+            .await
+    }
+
+    async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+        (**self).list_lsps().await
+    }
+
+    fn response_validation(&self) -> ResponseValidation {
+        (**self).response_validation()
+    }
+}
+
+/// Typed JSON-RPC 2.0 requests
+///
+/// Implemented for every [`LspClient`], including a [`DynLspClient`].
+/// These methods are generic and can't be part of an object-safe trait.
 #[async_trait]
-pub trait LspClient {
+pub trait LspClientExt: LspClient {
     /// Make a JSON-RPC 2.0 request to an LSP-server and specify the
     /// message-id explicitly.
     ///
@@ -56,7 +419,6 @@ pub trait LspClient {
     ///
     /// However, when writing tests it is useful to be able
     /// to manipulate or access the `rpc_id` explicitly.
-    /// We recommend using `request` over `request_with_id` because it the
     async fn request_with_id<'a, I, O, E>(
         &mut self,
         peer_id: &PublicKey,
@@ -67,9 +429,14 @@ pub trait LspClient {
     where
         I: serde::Serialize + Send,
         O: serde::de::DeserializeOwned + Send,
-        E: serde::de::DeserializeOwned + Send;
-
-    async fn list_lsps(&mut self) -> Result<Vec<PublicKey>>;
+        E: serde::de::DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(param).context("Failed to serialize params")?;
+        let response = self
+            .request_value(peer_id, method.name(), params, rpc_id)
+            .await?;
+        parse_response(response)
+    }
 
     /// Make a JSON-RPC 2.0 request to an LSP-server
     async fn request<'a, I, O, E>(
@@ -87,55 +454,72 @@ pub trait LspClient {
         self.request_with_id(peer_id, method, param, rpc_id).await
     }
 
-    async fn lsps0_list_protocols(
-        &mut self,
-        peer_id: &PublicKey,
-    ) -> Result<lsps0::schema::ListprotocolsResponse> {
-        let response = self
-            .request(peer_id, methods::LSPS0_LIST_PROTOCOLS, NoParams)
-            .await?;
-        match response {
-            JsonRpcResponse::Error(err) => {
-                Err(anyhow!("{} : {}", err.error.code, err.error.message))
-            }
-            JsonRpcResponse::Ok(ok) => Ok(ok.result),
-        }
-    }
-
-    async fn lsps1_get_info(
+    /// Make a JSON-RPC 2.0 request to an LSP-server and validate the result
+    ///
+    /// The result is validated according to [`LspClient::response_validation`].
+    /// Error responses are returned as is.
+    async fn request_validated<'a, I, O, E>(
         &mut self,
         peer_id: &PublicKey,
-    ) -> Result<lsps1::schema::Lsps1GetInfoResponse> {
-        let response = self
-            .request(peer_id, methods::LSPS1_GETINFO, NoParams)
-            .await?;
-        match response {
-            JsonRpcResponse::Error(err) => {
-                Err(anyhow!("{} : {}", err.error.code, err.error.message))
-            }
-            JsonRpcResponse::Ok(ok) => Ok(ok.result),
-        }
+        method: JsonRpcMethod<'a, I, O, E>,
+        param: I,
+    ) -> Result<JsonRpcResponse<O, E>>
+    where
+        I: serde::Serialize + Send,
+        O: serde::de::DeserializeOwned + ExpectedFields + Send,
+        E: serde::de::DeserializeOwned + Send,
+    {
+        self.request_validated_with_deadline(peer_id, method, param, &Deadline::none())
+            .await
     }
 
-    async fn lsps1_create_order(
+    /// Like [`LspClientExt::request_validated`] but gives up once `deadline` passes
+    ///
+    /// Every stage of the request only gets the time that remains.
+    /// A request that runs out of time fails with a
+    /// [`DeadlineExceeded`](crate::deadline::DeadlineExceeded).
+    async fn request_validated_with_deadline<'a, I, O, E>(
         &mut self,
         peer_id: &PublicKey,
-        order_request: lsps1::schema::Lsps1CreateOrderRequest,
-    ) -> Result<lsps1::schema::Lsps1CreateOrderResponse> {
+        method: JsonRpcMethod<'a, I, O, E>,
+        param: I,
+        deadline: &Deadline,
+    ) -> Result<JsonRpcResponse<O, E>>
+    where
+        I: serde::Serialize + Send,
+        O: serde::de::DeserializeOwned + ExpectedFields + Send,
+        E: serde::de::DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(param).context("Failed to serialize params")?;
         let response = self
-            .request(peer_id, methods::LSPS1_CREATE_ORDER, order_request)
+            .request_value_with_deadline(
+                peer_id,
+                method.name(),
+                params,
+                generate_random_rpc_id(),
+                deadline,
+            )
             .await?;
-
-        // TODO: We probably want to store this order in the data-store
+        let response = deadline.check(
+            Stage::Decode,
+            parse_response::<serde_json::Value, E>(response),
+        )?;
         match response {
-            JsonRpcResponse::Error(err) => {
-                Err(anyhow!("{} :  {}", err.error.code, err.error.message))
+            JsonRpcResponse::Error(err) => Ok(JsonRpcResponse::Error(err)),
+            JsonRpcResponse::Ok(ok) => {
+                let result = parse_result(ok.result, self.response_validation());
+                Ok(JsonRpcResponse::Ok(JsonRpcResponseSuccess {
+                    id: ok.id,
+                    result: deadline.check(Stage::Validate, result)?,
+                    jsonrpc: ok.jsonrpc,
+                }))
             }
-            JsonRpcResponse::Ok(ok) => Ok(ok.result),
         }
     }
 }
 
+impl<C: LspClient + ?Sized> LspClientExt for C {}
+
 pub fn rpc_request_to_data<I, O, E>(
     json_rpc_id: &JsonRpcId,
     method: JsonRpcMethod<I, O, E>,
@@ -157,3 +541,432 @@ where
     let request_hex = hex::encode(cursor.into_inner());
     Ok(request_hex)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::json_rpc::DefaultError;
+
+    #[test]
+    fn malformed_response_is_a_protocol_violation() {
+        let payload = serde_json::json!({
+            "jsonrpc" : "2.0",
+            "id" : "abcdef",
+            "result" : "content",
+            "error" : {"code" : -32600, "message" : "Invalid request"}
+        });
+
+        let err = parse_response::<String, DefaultError>(payload.clone()).unwrap_err();
+        match err.downcast_ref::<LspClientError>() {
+            Some(LspClientError::ProtocolViolation {
+                payload: raw_payload,
+                ..
+            }) => assert_eq!(raw_payload, &payload),
+            _ => panic!("Expected a protocol violation but got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn valid_response_is_parsed() {
+        let payload = serde_json::json!({
+            "jsonrpc" : "2.0",
+            "id" : "abcdef",
+            "result" : "content",
+        });
+
+        let response = parse_response::<String, DefaultError>(payload).unwrap();
+        assert!(matches!(response, JsonRpcResponse::Ok(ok) if ok.result == "content"));
+    }
+
+    /// Responds to every request with the method that was called
+    struct EchoClient;
+
+    #[async_trait]
+    impl LspClient for EchoClient {
+        async fn request_value(
+            &mut self,
+            _peer_id: &PublicKey,
+            method: &str,
+            _params: serde_json::Value,
+            rpc_id: JsonRpcId,
+        ) -> Result<serde_json::Value> {
+            Ok(serde_json::json!({ "jsonrpc" : "2.0", "id" : rpc_id, "result" : method }))
+        }
+
+        async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+            Ok(vec![])
+        }
+    }
+
+    /// Consumers can store a client without naming its type
+    struct Wallet {
+        client: DynLspClient,
+    }
+
+    #[tokio::test]
+    async fn boxed_client_makes_typed_requests() {
+        let mut wallet = Wallet {
+            client: Box::new(EchoClient),
+        };
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        type Method<'a> = JsonRpcMethod<'a, NoParams, String, DefaultError>;
+        let response = wallet
+            .client
+            .request(&peer_id, Method::new("lsps0.echo"), NoParams)
+            .await
+            .unwrap();
+        assert!(matches!(response, JsonRpcResponse::Ok(ok) if ok.result == "lsps0.echo"));
+
+        // A result that doesn't match the schema of the method
+        let err = wallet.client.lsps1_get_info(&peer_id).await.unwrap_err();
+        assert!(err.downcast_ref::<LspClientError>().is_some());
+    }
+
+    /// Lists LSPS1 but doesn't answer `lsps1.get_info`
+    struct PartialLsp;
+
+    #[async_trait]
+    impl LspClient for PartialLsp {
+        async fn request_value(
+            &mut self,
+            _peer_id: &PublicKey,
+            method: &str,
+            _params: serde_json::Value,
+            rpc_id: JsonRpcId,
+        ) -> Result<serde_json::Value> {
+            let response = match method {
+                "lsps0.list_protocols" => {
+                    serde_json::json!({ "jsonrpc" : "2.0", "id" : rpc_id, "result" : { "protocols" : [0, 1], "name" : "Partial LSP" }})
+                }
+                _ => serde_json::json!({
+                    "jsonrpc" : "2.0",
+                    "id" : rpc_id,
+                    "error" : ErrorData::method_not_found(method)
+                }),
+            };
+            Ok(response)
+        }
+
+        async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn method_not_found_can_be_detected() {
+        let mut client = PartialLsp;
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        let protocols = client.lsps0_list_protocols(&peer_id).await.unwrap();
+        assert_eq!(protocols.protocols, vec![0, 1]);
+        assert_eq!(protocols.extra_fields["name"], "Partial LSP");
+
+        let err = client.lsps1_get_info(&peer_id).await.unwrap_err();
+        assert!(is_method_not_found(&err));
+        match err.downcast_ref::<LspClientError>() {
+            Some(LspClientError::ErrorResponse { method, error }) => {
+                assert_eq!(method, "lsps1.get_info");
+                assert_eq!(error.data.as_ref().unwrap()["method"], "lsps1.get_info");
+            }
+            _ => panic!("Expected an error response but got {:?}", err),
+        }
+
+        // Other errors are reported as is
+        let err = anyhow::anyhow!("Time-out");
+        assert!(!is_method_not_found(&err));
+    }
+
+    /// Reports the fee of an order as a number instead of a string
+    struct SloppyLsp {
+        response_validation: ResponseValidation,
+    }
+
+    #[async_trait]
+    impl LspClient for SloppyLsp {
+        async fn request_value(
+            &mut self,
+            _peer_id: &PublicKey,
+            _method: &str,
+            _params: serde_json::Value,
+            rpc_id: JsonRpcId,
+        ) -> Result<serde_json::Value> {
+            let result = serde_json::json!({
+                "protocols" : [0, 1],
+                "name" : "Sloppy LSP",
+            });
+            Ok(serde_json::json!({ "jsonrpc" : "2.0", "id" : rpc_id, "result" : result }))
+        }
+
+        async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+            Ok(vec![])
+        }
+
+        fn response_validation(&self) -> ResponseValidation {
+            self.response_validation
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_client_rejects_unexpected_properties() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        let mut client: DynLspClient = Box::new(SloppyLsp {
+            response_validation: ResponseValidation::Lenient,
+        });
+        let protocols = client.lsps0_list_protocols(&peer_id).await.unwrap();
+        assert_eq!(protocols.extra_fields["name"], "Sloppy LSP");
+
+        let mut client: DynLspClient = Box::new(SloppyLsp {
+            response_validation: ResponseValidation::Strict,
+        });
+        let err = client.lsps0_list_protocols(&peer_id).await.unwrap_err();
+        match err.downcast_ref::<LspClientError>() {
+            Some(LspClientError::ProtocolViolation { message, payload }) => {
+                assert_eq!(message, "Unexpected properties name");
+                assert_eq!(payload["name"], "Sloppy LSP");
+            }
+            _ => panic!("Expected a protocol violation but got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn strict_parsing_reports_the_property_path() {
+        let result = serde_json::json!({ "protocols" : [0, "1"] });
+        let err = parse_result::<lsps0::schema::ListprotocolsResponse>(
+            result,
+            ResponseValidation::Strict,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("protocols[1]"), "{}", err);
+    }
+
+    /// Answers every request with `temporarily_unavailable`
+    struct PausedLsp {
+        retry_after: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl LspClient for PausedLsp {
+        async fn request_value(
+            &mut self,
+            _peer_id: &PublicKey,
+            _method: &str,
+            _params: serde_json::Value,
+            rpc_id: JsonRpcId,
+        ) -> Result<serde_json::Value> {
+            let mut error = ErrorData::temporarily_unavailable();
+            if let Some(retry_after) = self.retry_after {
+                error = error.with_retry_after(retry_after);
+            }
+            Ok(serde_json::json!({ "jsonrpc" : "2.0", "id" : rpc_id, "error" : error }))
+        }
+
+        async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn temporarily_unavailable_is_retryable() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        let mut client = PausedLsp {
+            retry_after: Some(Duration::from_secs(600)),
+        };
+        let err = client.lsps1_get_info(&peer_id).await.unwrap_err();
+        assert!(!is_method_not_found(&err));
+        assert_eq!(retry_after(&err), Some(Some(Duration::from_secs(600))));
+        match err.downcast_ref::<LspClientError>() {
+            Some(LspClientError::Retryable { method, .. }) => {
+                assert_eq!(method, "lsps1.get_info")
+            }
+            _ => panic!("Expected a retryable error but got {:?}", err),
+        }
+
+        let mut client = PausedLsp { retry_after: None };
+        let err = client.lsps1_get_info(&peer_id).await.unwrap_err();
+        assert_eq!(retry_after(&err), Some(None));
+
+        // Errors that can't be retried
+        let err = anyhow::anyhow!("Time-out");
+        assert_eq!(retry_after(&err), None);
+    }
+
+    /// Sends and answers `lsps1.get_info` after the injected delays
+    struct SlowTransport {
+        send_delay: Duration,
+        response_delay: Duration,
+        sent: usize,
+        awaited: usize,
+    }
+
+    impl SlowTransport {
+        fn new(send_delay_ms: u64, response_delay_ms: u64) -> Self {
+            Self {
+                send_delay: Duration::from_millis(send_delay_ms),
+                response_delay: Duration::from_millis(response_delay_ms),
+                sent: 0,
+                awaited: 0,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LspClient for SlowTransport {
+        async fn request_value(
+            &mut self,
+            peer_id: &PublicKey,
+            method: &str,
+            params: serde_json::Value,
+            rpc_id: JsonRpcId,
+        ) -> Result<serde_json::Value> {
+            self.request_value_with_deadline(peer_id, method, params, rpc_id, &Deadline::none())
+                .await
+        }
+
+        async fn request_value_with_deadline(
+            &mut self,
+            _peer_id: &PublicKey,
+            _method: &str,
+            _params: serde_json::Value,
+            rpc_id: JsonRpcId,
+            deadline: &Deadline,
+        ) -> Result<serde_json::Value> {
+            deadline
+                .run(Stage::SendRequest, tokio::time::sleep(self.send_delay))
+                .await?;
+            self.sent += 1;
+
+            self.awaited += 1;
+            deadline
+                .run(
+                    Stage::AwaitResponse,
+                    tokio::time::sleep(self.response_delay),
+                )
+                .await?;
+            let result = serde_json::json!({
+                "options" : {
+                    "min_required_channel_confirmations": 0,
+                    "min_funding_confirms_within_blocks": 6,
+                    "min_onchain_payment_confirmations": null,
+                    "supports_zero_channel_reserve": false,
+                    "min_onchain_payment_size_sat": null,
+                    "max_channel_expiry_blocks": 20160,
+                    "min_initial_client_balance_sat": "0",
+                    "max_initial_client_balance_sat": "0",
+                    "min_initial_lsp_balance_sat": "100000",
+                    "max_initial_lsp_balance_sat": "10000000",
+                    "min_channel_balance_sat": "100000",
+                    "max_channel_balance_sat": "10000000"
+                }
+            });
+            Ok(serde_json::json!({ "jsonrpc" : "2.0", "id" : rpc_id, "result" : result }))
+        }
+
+        async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+            Ok(vec![])
+        }
+    }
+
+    fn deadline_stage(err: &anyhow::Error) -> Stage {
+        crate::deadline::exceeded_stage(err)
+            .unwrap_or_else(|| panic!("Expected an exceeded deadline but got {:?}", err))
+    }
+
+    #[tokio::test]
+    async fn slow_response_is_attributed_to_await_response() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let mut client = SlowTransport::new(0, 5_000);
+
+        let start = std::time::Instant::now();
+        let deadline = Deadline::after(Duration::from_millis(50));
+        let err = client
+            .lsps1_get_info_with_deadline(&peer_id, &deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(deadline_stage(&err), Stage::AwaitResponse);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(client.sent, 1);
+    }
+
+    #[tokio::test]
+    async fn slow_send_aborts_later_stages() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let mut client: DynLspClient = Box::new(SlowTransport::new(5_000, 0));
+
+        let deadline = Deadline::after(Duration::from_millis(50));
+        let err = client
+            .lsps1_get_info_with_deadline(&peer_id, &deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(deadline_stage(&err), Stage::SendRequest);
+    }
+
+    #[tokio::test]
+    async fn stages_share_the_budget() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        // Each stage fits in the budget but both together don't
+        let mut client = SlowTransport::new(60, 60);
+        let deadline = Deadline::after(Duration::from_millis(90));
+        let err = client
+            .lsps1_get_info_with_deadline(&peer_id, &deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(deadline_stage(&err), Stage::AwaitResponse);
+        assert!(err.to_string().starts_with("Deadline of 90ms exceeded"));
+
+        // A budget that is exhausted before the request starts
+        let mut client = SlowTransport::new(0, 0);
+        let deadline = Deadline::after(Duration::ZERO);
+        let err = client
+            .lsps1_get_info_with_deadline(&peer_id, &deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(deadline_stage(&err), Stage::SendRequest);
+        assert_eq!((client.sent, client.awaited), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn requests_without_deadline_are_unchanged() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let mut client = SlowTransport::new(20, 20);
+
+        let info = client.lsps1_get_info(&peer_id).await.unwrap();
+        assert_eq!(info.options.max_channel_expiry_blocks, 20160);
+
+        let deadline = Deadline::after(Duration::from_secs(5));
+        let info = client
+            .lsps1_get_info_with_deadline(&peer_id, &deadline)
+            .await
+            .unwrap();
+        assert_eq!(info.options.max_channel_expiry_blocks, 20160);
+        assert_eq!(client.sent, 2);
+    }
+}
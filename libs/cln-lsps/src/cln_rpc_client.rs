@@ -1,8 +1,11 @@
-use crate::client::{rpc_request_to_data, LspClient, RequestId};
+use crate::client::{
+    parse_response, rpc_request_to_data, LspClient, RequestId, ResponseValidation,
+};
+use crate::deadline::{Deadline, Stage};
 use crate::transport::RequestResponseMatcher;
-use lsp_primitives::json_rpc::{JsonRpcId, JsonRpcMethod, JsonRpcResponse};
+use lsp_primitives::json_rpc::{generate_random_rpc_id, JsonRpcId, JsonRpcMethod, JsonRpcResponse};
 use lsp_primitives::lsps0::common_schemas::PublicKey;
-use lsp_primitives::lsps0::util::{is_feature_bit_enabled, FeatureBitMap, LSP_SERVER_FEATURE_BIT};
+use lsp_primitives::lsps0::features::{has_feature_bit, LSPS_FEATURE_BIT};
 
 use async_trait::async_trait;
 use cln_rpc::{
@@ -12,53 +15,161 @@ use cln_rpc::{
     },
     ClnRpc,
 };
-use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use futures::future::{select_all, BoxFuture, FutureExt};
 
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use log::info;
 
 type Matcher = Arc<Mutex<RequestResponseMatcher<RequestId, serde_json::Value>>>;
 
+/// A connection to Core Lightning that can be shared by many clients
+pub type SharedClnRpc = Arc<tokio::sync::Mutex<ClnRpc>>;
+
+/// The default time we wait for the LSP-server to respond
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Describes how a request made by [`ClnRpcLspClient::request_with_retries`]
+/// was answered
+#[derive(Debug, Clone)]
+pub struct RequestAttempts {
+    /// The number of times the request was sent
+    pub attempts: u32,
+    /// The time between sending the first request and receiving the response
+    pub elapsed: Duration,
+    /// The id of the request that was answered
+    pub answered_id: JsonRpcId,
+}
+
 pub struct ClnRpcLspClient {
     matcher: Matcher,
-    rpc: ClnRpc,
+    rpc: SharedClnRpc,
+    timeout: Duration,
+    response_validation: ResponseValidation,
 }
 
 impl ClnRpcLspClient {
     pub fn new(matcher: Matcher, rpc: ClnRpc) -> Self {
-        Self { matcher, rpc }
+        Self::with_shared_rpc(matcher, Arc::new(tokio::sync::Mutex::new(rpc)))
     }
-}
 
-#[async_trait]
-impl LspClient for ClnRpcLspClient {
-    async fn request_with_id<'a, I, O, E>(
+    /// Creates a client that reuses an existing connection
+    ///
+    /// The connection is only locked while a message is sent to
+    /// Core Lightning. Clients that wait for a response of an
+    /// LSP-server don't block each other.
+    pub fn with_shared_rpc(matcher: Matcher, rpc: SharedClnRpc) -> Self {
+        Self {
+            matcher,
+            rpc,
+            timeout: DEFAULT_RESPONSE_TIMEOUT,
+            response_validation: ResponseValidation::default(),
+        }
+    }
+
+    /// Sets how long we wait for the LSP-server to respond
+    ///
+    /// Requests such as a long-poll on `lsps1.get_order` need
+    /// a longer timeout than the default.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how strictly the results of the LSP-server are validated
+    pub fn set_response_validation(&mut self, response_validation: ResponseValidation) {
+        self.response_validation = response_validation;
+    }
+
+    /// Makes a request and re-sends it up to `retries` times if the
+    /// LSP-server doesn't respond in time.
+    ///
+    /// Every attempt uses a new id because LSPS0 forbids re-using them.
+    /// A late response to an earlier attempt is accepted as long as no
+    /// other attempt has been answered. All other attempts are abandoned
+    /// once a response has been received.
+    pub async fn request_with_retries<'a, I, O, E>(
         &mut self,
         peer_id: &PublicKey,
         method: JsonRpcMethod<'a, I, O, E>,
         params: I,
-        json_rpc_id: JsonRpcId,
-    ) -> Result<JsonRpcResponse<O, E>>
+        retries: u32,
+    ) -> Result<(JsonRpcResponse<O, E>, RequestAttempts)>
     where
-        I: serde::Serialize + Send,
+        I: serde::Serialize + Clone + Send,
         O: serde::de::DeserializeOwned + Send,
         E: serde::de::DeserializeOwned + Send,
     {
-        // Construct the request
-        // The request_data is hex-encoded message, The first two bytes represent the BOLT-8 msg id
-        let request_data: String = rpc_request_to_data(&json_rpc_id, method, params).unwrap();
-        let request_id = RequestId::new(peer_id.clone(), json_rpc_id);
+        let start = Instant::now();
+        let mut pending_ids: Vec<JsonRpcId> = Vec::new();
+        let mut pending_futures: Vec<BoxFuture<'static, serde_json::Value>> = Vec::new();
 
-        log::debug!("JSON-rpc request '{}'", request_data);
+        for attempt in 0..=retries {
+            let json_rpc_id = generate_random_rpc_id();
+            let request_data = rpc_request_to_data(
+                &json_rpc_id,
+                JsonRpcMethod::<I, O, E>::new(method.name()),
+                params.clone(),
+            )?;
+            let request_id = RequestId::new(*peer_id, json_rpc_id.clone());
 
-        // Start listening for the response
-        // We do this before sending it to avoid race-conditions
-        let response_future = self.matcher.lock().unwrap().process_request(request_id);
+            // Start listening for the response before sending the request
+            let response_future = self.matcher.lock().unwrap().process_request(request_id);
+            pending_ids.push(json_rpc_id);
+            pending_futures.push(response_future.boxed());
 
-        // Send the custom message
+            if let Err(err) = self.send_custom_msg(peer_id, request_data).await {
+                self.abandon(peer_id, &pending_ids);
+                return Err(err);
+            }
+
+            // Wait for a response to any of the attempts
+            let response =
+                tokio::time::timeout(self.timeout, select_all(pending_futures.iter_mut())).await;
+            match response {
+                Ok((response_value, index, _)) => {
+                    let answered_id = pending_ids.remove(index);
+                    self.abandon(peer_id, &pending_ids);
+
+                    let response = parse_response(response_value)?;
+                    let attempts = RequestAttempts {
+                        attempts: attempt + 1,
+                        elapsed: start.elapsed(),
+                        answered_id,
+                    };
+                    return Ok((response, attempts));
+                }
+                Err(_) => log::debug!(
+                    "Attempt {} of {} timed out for method '{}'",
+                    attempt + 1,
+                    retries + 1,
+                    method.name()
+                ),
+            }
+        }
+
+        self.abandon(peer_id, &pending_ids);
+        Err(anyhow!(
+            "Time-out, waiting for peer to respond after {} attempts",
+            retries + 1
+        ))
+    }
+
+    fn abandon(&mut self, peer_id: &PublicKey, json_rpc_ids: &[JsonRpcId]) {
+        let mut matcher = self.matcher.lock().unwrap();
+        for id in json_rpc_ids {
+            matcher.abandon(RequestId::new(*peer_id, id.clone()));
+        }
+    }
+
+    async fn send_custom_msg(&mut self, peer_id: &PublicKey, request_data: String) -> Result<()> {
+        log::debug!(
+            "Sending JSON-rpc request of {} bytes to peer {:?}",
+            request_data.len(),
+            peer_id
+        );
         let cln_rpc_pubkey =
             cln_rpc::primitives::PublicKey::from_slice(&peer_id.inner().serialize())
                 .context("Unexpected failure in PublicKey")?;
@@ -67,9 +178,8 @@ impl LspClient for ClnRpcLspClient {
             msg: request_data,
         };
         let request = Request::SendCustomMsg(request_data);
-        log::debug!("SendCustomMessageRequest {:?}", request.clone());
         let result =
-            self.rpc.call(request).await.with_context(|| {
+            self.rpc.lock().await.call(request).await.with_context(|| {
                 "Failed to SendcustomMsg to peer. Are you connected to the peer?"
             })?;
 
@@ -82,22 +192,70 @@ impl LspClient for ClnRpcLspClient {
             },
             _ => panic!("An unexpected error occured from which cannot be recovered. Should have responded with SendcustomMsg-response.")
         }
+        Ok(())
+    }
+}
 
-        // Wait for the response
-        let timeout = std::time::Duration::from_secs(10);
-        let response_value: serde_json::Value = tokio::time::timeout(timeout, response_future)
+#[async_trait]
+impl LspClient for ClnRpcLspClient {
+    async fn request_value(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        json_rpc_id: JsonRpcId,
+    ) -> Result<serde_json::Value> {
+        self.request_value_with_deadline(peer_id, method, params, json_rpc_id, &Deadline::none())
             .await
+    }
+
+    /// Sends the request and waits for the response within `deadline`
+    ///
+    /// The wait never exceeds the time-out set by [`ClnRpcLspClient::set_timeout`].
+    async fn request_value_with_deadline(
+        &mut self,
+        peer_id: &PublicKey,
+        method: &str,
+        params: serde_json::Value,
+        json_rpc_id: JsonRpcId,
+        deadline: &Deadline,
+    ) -> Result<serde_json::Value> {
+        // Construct the request
+        // The request_data is hex-encoded message, The first two bytes represent the BOLT-8 msg id
+        let method = JsonRpcMethod::<serde_json::Value, (), ()>::new(method);
+        let request_data: String = rpc_request_to_data(&json_rpc_id, method, params)?;
+        let request_id = RequestId::new(peer_id.clone(), json_rpc_id);
+
+        // Start listening for the response
+        // We do this before sending it to avoid race-conditions
+        let response_future = self.matcher.lock().unwrap().process_request(request_id);
+
+        // Send the custom message
+        deadline
+            .run(
+                Stage::SendRequest,
+                self.send_custom_msg(peer_id, request_data),
+            )
+            .await??;
+
+        // Wait for the response
+        let response_value: serde_json::Value = deadline
+            .run(
+                Stage::AwaitResponse,
+                tokio::time::timeout(self.timeout, response_future),
+            )
+            .await?
             .with_context(|| "Time-out, waiting for peer to respond")?;
 
-        // Parse the response and return the value
-        serde_json::from_value(response_value)
-            .with_context(|| "Failed to parse response from LSPS-server")
+        Ok(response_value)
     }
 
     async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
         let list_nodes_request = ListnodesRequest { id: None };
         let response = self
             .rpc
+            .lock()
+            .await
             .call(Request::ListNodes(list_nodes_request))
             .await?;
 
@@ -106,11 +264,13 @@ impl LspClient for ClnRpcLspClient {
                 .nodes
                 .iter()
                 .filter_map(|n| {
-                    // fix me: spurious clone
-                    let features = FeatureBitMap::from_str(&n.features.clone()?).ok()?;
+                    let features = n.features.as_ref()?;
 
-                    if is_feature_bit_enabled(&features, LSP_SERVER_FEATURE_BIT) {
-                        return Some(PublicKey::from_hex(&n.nodeid.to_string()));
+                    if has_feature_bit(features, LSPS_FEATURE_BIT).ok()? {
+                        return Some(
+                            PublicKey::from_slice(&n.nodeid.serialize())
+                                .map_err(anyhow::Error::from),
+                        );
                     } else {
                         return None;
                     }
@@ -120,4 +280,178 @@ impl LspClient for ClnRpcLspClient {
             panic!("Should respond with listNodes")
         }
     }
+
+    fn response_validation(&self) -> ResponseValidation {
+        self.response_validation
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{UnixListener, UnixStream};
+
+    use crate::deadline::exceeded_stage;
+
+    /// A lightningd that knows no nodes and counts its connections
+    struct FakeLightningd {
+        path: PathBuf,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl FakeLightningd {
+        fn start() -> Self {
+            Self::start_with_send_delay(Duration::ZERO)
+        }
+
+        /// Answers `sendcustommsg` after `send_delay`
+        fn start_with_send_delay(send_delay: Duration) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("lightning-rpc-{}", rand::random::<u64>()));
+            let listener = UnixListener::bind(&path).unwrap();
+            let connections = Arc::new(AtomicUsize::new(0));
+
+            let counter = connections.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(serve(stream, send_delay));
+                }
+            });
+            Self { path, connections }
+        }
+
+        fn connections(&self) -> usize {
+            self.connections.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Drop for FakeLightningd {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Responds to `sendcustommsg` and to every other request with an
+    /// empty list of nodes
+    async fn serve(mut stream: UnixStream, send_delay: Duration) {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            }
+
+            // The buffer might end with an incomplete request
+            let mut requests =
+                serde_json::Deserializer::from_slice(&buffer).into_iter::<serde_json::Value>();
+            let mut responses = Vec::new();
+            while let Some(Ok(request)) = requests.next() {
+                let result = if request["method"] == "sendcustommsg" {
+                    tokio::time::sleep(send_delay).await;
+                    serde_json::json!({ "status" : "Message sent to connectd for delivery" })
+                } else {
+                    serde_json::json!({ "nodes" : [] })
+                };
+                let response = serde_json::json!({
+                    "jsonrpc" : "2.0",
+                    "id" : request["id"],
+                    "result" : result,
+                });
+                responses.extend_from_slice(response.to_string().as_bytes());
+                responses.extend_from_slice(b"\n\n");
+            }
+            let consumed = requests.byte_offset();
+            buffer.drain(..consumed);
+
+            if stream.write_all(&responses).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    const CALLS: usize = 20;
+
+    /// Compares connecting for every call with a shared connection
+    ///
+    /// Run with `--nocapture` to see the timings
+    #[tokio::test]
+    async fn shared_connection_is_reused() {
+        let lightningd = FakeLightningd::start();
+        let matcher: Matcher = Arc::new(Mutex::new(RequestResponseMatcher::new()));
+
+        let start = Instant::now();
+        for _ in 0..CALLS {
+            let rpc = ClnRpc::new(&lightningd.path).await.unwrap();
+            let mut client = ClnRpcLspClient::new(matcher.clone(), rpc);
+            assert!(client.list_lsps().await.unwrap().is_empty());
+        }
+        let reconnecting = start.elapsed();
+        assert_eq!(lightningd.connections(), CALLS);
+
+        let start = Instant::now();
+        let rpc = ClnRpc::new(&lightningd.path).await.unwrap();
+        let rpc: SharedClnRpc = Arc::new(tokio::sync::Mutex::new(rpc));
+        for _ in 0..CALLS {
+            let mut client = ClnRpcLspClient::with_shared_rpc(matcher.clone(), rpc.clone());
+            assert!(client.list_lsps().await.unwrap().is_empty());
+        }
+        let shared = start.elapsed();
+        assert_eq!(lightningd.connections(), CALLS + 1);
+
+        println!(
+            "{} calls took {:?} when connecting for every call and {:?} using a shared connection",
+            CALLS, reconnecting, shared
+        );
+    }
+
+    async fn request_with_deadline(
+        lightningd: &FakeLightningd,
+        deadline: &Deadline,
+    ) -> anyhow::Error {
+        let matcher: Matcher = Arc::new(Mutex::new(RequestResponseMatcher::new()));
+        let rpc = ClnRpc::new(&lightningd.path).await.unwrap();
+        let mut client = ClnRpcLspClient::new(matcher, rpc);
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        client
+            .request_value_with_deadline(
+                &peer_id,
+                "lsps0.list_protocols",
+                serde_json::json!({}),
+                generate_random_rpc_id(),
+                deadline,
+            )
+            .await
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn deadline_reports_the_stage_that_ran_out_of_time() {
+        // lightningd is slow to send the message
+        let lightningd = FakeLightningd::start_with_send_delay(Duration::from_secs(5));
+        let deadline = Deadline::after(Duration::from_millis(100));
+        let err = request_with_deadline(&lightningd, &deadline).await;
+        assert_eq!(exceeded_stage(&err), Some(Stage::SendRequest), "{:?}", err);
+
+        // The message is sent but the LSP never responds
+        let lightningd = FakeLightningd::start();
+        let deadline = Deadline::after(Duration::from_millis(100));
+        let err = request_with_deadline(&lightningd, &deadline).await;
+        assert_eq!(
+            exceeded_stage(&err),
+            Some(Stage::AwaitResponse),
+            "{:?}",
+            err
+        );
+    }
 }
@@ -1,4 +1,5 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
+use lsp_primitives::hex;
 use lsp_primitives::lsps0::common_schemas::PublicKey;
 use serde::{Deserialize, Serialize};
 
@@ -11,7 +12,7 @@ pub struct RpcCustomMsgMessage {
 impl RpcCustomMsgMessage {
     pub fn to_raw(&self) -> Result<RawCustomMsgMessage> {
         let peer_id = self.peer_id;
-        let payload = hex::decode(&self.payload).with_context(|| "payload is not valid hex")?;
+        let payload = hex::decode("custommsg-payload", &self.payload)?;
 
         RawCustomMsgMessage::new(peer_id, payload)
     }
@@ -0,0 +1,227 @@
+//! Limits the time a caller is willing to wait for a request
+//!
+//! A wallet that calls `lsps1-create-order` with a budget of 30 seconds
+//! doesn't care how the time is spent. The request fetches the info of
+//! the LSP, sends a message, waits for the response and validates it.
+//! Each of these steps is a [`Stage`] that may only use the time that
+//! remains of the budget.
+//!
+//! A request that runs out of time fails with [`DeadlineExceeded`] which
+//! reports the stage that was running. Later stages are never started.
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+/// A step of a request that consumes part of the budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Fetching `lsps1.get_info` or waiting for a cached response
+    GetInfo,
+    /// Sending the request to the LSP-server
+    SendRequest,
+    /// Waiting for the LSP-server to respond
+    AwaitResponse,
+    /// Parsing the JSON-RPC 2.0 response
+    Decode,
+    /// Checking the result against the schema of the method
+    Validate,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::GetInfo => "get_info",
+            Self::SendRequest => "send_request",
+            Self::AwaitResponse => "await_response",
+            Self::Decode => "decode",
+            Self::Validate => "validate",
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The budget of a request ran out during `stage`
+///
+/// It is returned wrapped in an [`anyhow::Error`] and can be
+/// retrieved using `downcast_ref`.
+#[derive(Debug, Clone)]
+pub struct DeadlineExceeded {
+    pub stage: Stage,
+    pub budget: Duration,
+    /// The time between creating the deadline and noticing it passed
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Deadline of {}ms exceeded during {} after {}ms",
+            self.budget.as_millis(),
+            self.stage,
+            self.elapsed.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Returns the stage that ran out of time if `err` is a [`DeadlineExceeded`]
+pub fn exceeded_stage(err: &anyhow::Error) -> Option<Stage> {
+    err.downcast_ref::<DeadlineExceeded>().map(|e| e.stage)
+}
+
+/// The moment a request must be finished
+///
+/// [`Deadline::none`] never passes. Requests that use it behave like
+/// requests without a deadline.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    started_at: Instant,
+    budget: Option<Duration>,
+}
+
+impl Deadline {
+    /// A deadline that passes `budget` from now
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            started_at: Instant::now(),
+            budget: Some(budget),
+        }
+    }
+
+    /// A deadline that never passes
+    pub fn none() -> Self {
+        Self {
+            started_at: Instant::now(),
+            budget: None,
+        }
+    }
+
+    /// Converts the `timeout_ms` a user passed to an rpcmethod
+    pub fn from_timeout_ms(timeout_ms: Option<u64>) -> Self {
+        match timeout_ms {
+            Some(timeout_ms) => Self::after(Duration::from_millis(timeout_ms)),
+            None => Self::none(),
+        }
+    }
+
+    /// The time that is left or `None` if there is no deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.budget
+            .map(|budget| budget.saturating_sub(self.started_at.elapsed()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// The error that reports `stage` ran out of time
+    pub fn exceeded(&self, stage: Stage) -> anyhow::Error {
+        anyhow::Error::new(DeadlineExceeded {
+            stage,
+            budget: self.budget.unwrap_or(Duration::MAX),
+            elapsed: self.started_at.elapsed(),
+        })
+    }
+
+    /// Awaits `future` for at most the remaining time
+    ///
+    /// Fails without polling `future` if the deadline already passed.
+    /// A nested stage that uses the same deadline fails first. Its
+    /// [`DeadlineExceeded`] is the one that reaches the caller.
+    pub async fn run<F: Future>(&self, stage: Stage, future: F) -> Result<F::Output> {
+        match self.remaining() {
+            None => Ok(future.await),
+            Some(remaining) if remaining.is_zero() => Err(self.exceeded(stage)),
+            Some(remaining) => tokio::time::timeout(remaining, future)
+                .await
+                .map_err(|_| self.exceeded(stage)),
+        }
+    }
+
+    /// Fails if the deadline passed while `stage` computed `value`
+    ///
+    /// Used for stages that don't await and can't be interrupted.
+    /// Errors of the stage take precedence.
+    pub fn check<T>(&self, stage: Stage, value: Result<T>) -> Result<T> {
+        let value = value?;
+        if self.is_expired() {
+            return Err(self.exceeded(stage));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn no_deadline_never_expires() {
+        let deadline = Deadline::from_timeout_ms(None);
+        assert_eq!(deadline.remaining(), None);
+        assert!(!deadline.is_expired());
+
+        let value = deadline
+            .run(Stage::AwaitResponse, async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                42
+            })
+            .await
+            .unwrap();
+        assert_eq!(value, 42);
+        assert_eq!(deadline.check(Stage::Decode, Ok(42)).unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn slow_stage_is_reported() {
+        let deadline = Deadline::from_timeout_ms(Some(30));
+        let start = Instant::now();
+        let err = deadline
+            .run(
+                Stage::AwaitResponse,
+                tokio::time::sleep(Duration::from_secs(5)),
+            )
+            .await
+            .unwrap_err();
+        // The stage is interrupted instead of running to completion
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(exceeded_stage(&err), Some(Stage::AwaitResponse));
+
+        let exceeded = err.downcast_ref::<DeadlineExceeded>().unwrap();
+        assert_eq!(exceeded.budget, Duration::from_millis(30));
+        assert!(exceeded.elapsed >= Duration::from_millis(30));
+        assert!(err
+            .to_string()
+            .starts_with("Deadline of 30ms exceeded during await_response after"));
+    }
+
+    #[tokio::test]
+    async fn expired_deadline_does_not_start_a_stage() {
+        let deadline = Deadline::after(Duration::ZERO);
+        assert!(deadline.is_expired());
+
+        let mut started = false;
+        let err = deadline
+            .run(Stage::SendRequest, async { started = true })
+            .await
+            .unwrap_err();
+        assert!(!started);
+        assert_eq!(exceeded_stage(&err), Some(Stage::SendRequest));
+
+        // Stages that don't await are checked once they finished
+        let err = deadline.check(Stage::Validate, Ok(())).unwrap_err();
+        assert_eq!(exceeded_stage(&err), Some(Stage::Validate));
+        let err = deadline
+            .check::<()>(Stage::Validate, Err(anyhow::anyhow!("Invalid result")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid result");
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::{anyhow, Result};
+
+/// The scheme of a BIP21-style lightning uri
+pub const LIGHTNING_URI_SCHEME: &str = "lightning:";
+
+/// Returns the invoice in lowercase
+///
+/// Bech32 strings are either all lowercase or all uppercase.
+/// A `lightning:`-prefix is removed if present.
+pub fn normalize_bolt11(invoice: &str) -> Result<String> {
+    let invoice = invoice.trim();
+    let invoice = match invoice.get(..LIGHTNING_URI_SCHEME.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(LIGHTNING_URI_SCHEME) => {
+            &invoice[LIGHTNING_URI_SCHEME.len()..]
+        }
+        _ => invoice,
+    };
+
+    if invoice.is_empty() {
+        return Err(anyhow!("The order doesn't contain an invoice"));
+    }
+
+    let has_lower = invoice.chars().any(|c| c.is_ascii_lowercase());
+    let has_upper = invoice.chars().any(|c| c.is_ascii_uppercase());
+    if has_lower && has_upper {
+        return Err(anyhow!("Invoice uses mixed case"));
+    }
+
+    Ok(invoice.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INVOICE: &str = "lnbcrt1m1pjtest";
+
+    #[test]
+    fn normalize_invoice() {
+        assert_eq!(normalize_bolt11(INVOICE).unwrap(), INVOICE);
+        assert_eq!(normalize_bolt11("LNBCRT1M1PJTEST").unwrap(), INVOICE);
+        assert_eq!(
+            normalize_bolt11(" lightning:lnbcrt1m1pjtest ").unwrap(),
+            INVOICE
+        );
+        assert_eq!(
+            normalize_bolt11("LIGHTNING:LNBCRT1M1PJTEST").unwrap(),
+            INVOICE
+        );
+
+        assert!(normalize_bolt11("lnbcRT1m1pjtest").is_err());
+        assert!(normalize_bolt11("").is_err());
+        assert!(normalize_bolt11("lightning:").is_err());
+    }
+}
@@ -1,5 +1,12 @@
+pub mod channel_ready;
 pub mod client;
+pub mod deadline;
+pub mod invoice;
+pub mod liquidity;
+pub mod pins;
+pub mod tracker;
 pub mod transport;
+pub mod updates;
 
 // #[cfg(feature="cln-rpc")]
 pub mod cln_rpc_client;
@@ -0,0 +1,454 @@
+//! Plans the purchase of inbound liquidity
+//!
+//! The fee of an LSP doesn't have to grow linearly with the capacity of
+//! the channel. Two channels of 1_000_000 sat can be cheaper or more
+//! expensive than one channel of 2_000_000 sat. [`plan_liquidity`]
+//! compares the options and returns the cheapest one.
+//!
+//! The planner doesn't talk to the LSP. The caller provides the fee of
+//! an order. [`FeeHistory`] estimates it from previous orders.
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::lsps1::schema::Lsps1GetInfoResponse;
+use lsp_primitives::sat_amount_json;
+
+use crate::pins::PinnedOrder;
+
+/// The largest number of orders a plan consists of
+pub const MAX_PLANNED_ORDERS: u32 = 4;
+
+/// An order in a [`LiquidityPlan`]
+///
+/// The orders of a plan don't request a client balance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedOrder {
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub lsp_balance_sat: SatAmount,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub fee_total_sat: SatAmount,
+}
+
+/// The orders that buy the requested inbound liquidity
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LiquidityPlan {
+    pub orders: Vec<PlannedOrder>,
+    /// The sum of the `lsp_balance_sat` of all orders
+    ///
+    /// Can exceed the target if the target is below the minimum of the LSP
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub inbound_sat: SatAmount,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub fee_total_sat: SatAmount,
+}
+
+/// Returns the cheapest way to buy `target_inbound_sat` of inbound liquidity
+///
+/// The target is split in 1 up to [`MAX_PLANNED_ORDERS`] orders of
+/// (almost) equal size. An LSP that advertises `max_open_orders_per_peer`
+/// isn't asked for more orders. An order below the minimum of the LSP is
+/// raised to the minimum. A split whose orders exceed the maximum of the
+/// LSP isn't considered.
+///
+/// `quote_fn` returns the fee of an order of `lsp_balance_sat`. It is
+/// called once per distinct amount. If several plans cost the same, the
+/// plan with the fewest orders is returned.
+pub fn plan_liquidity<F>(
+    target_inbound_sat: SatAmount,
+    info: &Lsps1GetInfoResponse,
+    mut quote_fn: F,
+) -> Result<LiquidityPlan>
+where
+    F: FnMut(SatAmount) -> Result<SatAmount>,
+{
+    let target = target_inbound_sat.sat_value();
+    if target == 0 {
+        return Err(anyhow!("target_inbound_sat must be positive"));
+    }
+
+    let options = &info.options;
+    let min_order = options
+        .min_initial_lsp_balance_sat
+        .sat_value()
+        .max(options.min_channel_balance_sat.sat_value());
+    let max_order = options
+        .max_initial_lsp_balance_sat
+        .sat_value()
+        .min(options.max_channel_balance_sat.sat_value());
+    let max_orders = info
+        .lsp_extensions()
+        .max_open_orders_per_peer
+        .map_or(MAX_PLANNED_ORDERS, |limit| limit.min(MAX_PLANNED_ORDERS));
+
+    let mut quotes: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut cheapest: Option<LiquidityPlan> = None;
+    for order_count in 1..=max_orders {
+        let Some(amounts) = split(target, order_count, min_order, max_order) else {
+            continue;
+        };
+
+        let mut orders = Vec::with_capacity(amounts.len());
+        for amount in amounts {
+            let fee = match quotes.get(&amount) {
+                Some(fee) => *fee,
+                None => {
+                    let fee = quote_fn(SatAmount::new(amount))?.sat_value();
+                    quotes.insert(amount, fee);
+                    fee
+                }
+            };
+            orders.push(PlannedOrder {
+                lsp_balance_sat: SatAmount::new(amount),
+                fee_total_sat: SatAmount::new(fee),
+            });
+        }
+
+        let plan = LiquidityPlan {
+            inbound_sat: SatAmount::new(orders.iter().map(|o| o.lsp_balance_sat.sat_value()).sum()),
+            fee_total_sat: SatAmount::new(orders.iter().map(|o| o.fee_total_sat.sat_value()).sum()),
+            orders,
+        };
+        let is_cheaper = cheapest
+            .as_ref()
+            .map_or(true, |best| plan.fee_total_sat < best.fee_total_sat);
+        if is_cheaper {
+            cheapest = Some(plan);
+        }
+    }
+
+    cheapest.ok_or_else(|| {
+        anyhow!(
+            "The LSP sells channels of {} to {} sat. {} sat can't be bought in at most {} orders",
+            min_order,
+            max_order,
+            target,
+            max_orders
+        )
+    })
+}
+
+/// Splits `target` in `order_count` amounts that differ by at most 1 sat
+///
+/// Returns `None` if the amounts exceed `max_order`
+fn split(target: u64, order_count: u32, min_order: u64, max_order: u64) -> Option<Vec<u64>> {
+    let order_count = u64::from(order_count);
+    let base = target / order_count;
+    let remainder = target % order_count;
+
+    let amounts: Vec<u64> = (0..order_count)
+        .map(|index| base + u64::from(index < remainder))
+        .map(|amount| amount.max(min_order))
+        .collect();
+    if amounts.iter().any(|amount| *amount > max_order) {
+        return None;
+    }
+    Some(amounts)
+}
+
+/// How much an estimated fee can be trusted
+///
+/// Sorted from the most to the least trustworthy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EstimateConfidence {
+    /// The amount lies between the capacities of previous orders
+    Interpolated,
+    /// The amount is smaller or larger than the capacity of every
+    /// previous order
+    Extrapolated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeEstimate {
+    pub fee_total_sat: SatAmount,
+    pub confidence: EstimateConfidence,
+}
+
+/// The fees an LSP charged for previous orders
+///
+/// Only orders without a client balance describe the fee for inbound
+/// liquidity. The capacity of an order is used as its `lsp_balance_sat`.
+#[derive(Debug, Clone, Default)]
+pub struct FeeHistory {
+    /// The fee by the capacity of the channel
+    fees: BTreeMap<u64, u64>,
+}
+
+impl FeeHistory {
+    /// Collects the fees of `orders`
+    ///
+    /// The caller selects the orders of a single LSP. Orders whose capacity
+    /// is unknown or that the LSP changed after they were pinned are ignored.
+    pub fn from_orders<'a>(orders: impl IntoIterator<Item = &'a PinnedOrder>) -> Self {
+        let fees = orders
+            .into_iter()
+            .filter(|order| !order.is_inconsistent())
+            .filter_map(|order| {
+                let capacity = order.channel_capacity?.sat_value();
+                Some((capacity, order.fee_total_sat().sat_value()))
+            })
+            .filter(|(capacity, _)| *capacity > 0)
+            .collect();
+        Self { fees }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fees.is_empty()
+    }
+
+    /// Estimates the fee of an order of `lsp_balance_sat`
+    ///
+    /// The fee is interpolated linearly between the two closest
+    /// capacities. Outside of the known capacities the closest two are
+    /// extrapolated. A single order is scaled proportionally.
+    pub fn estimate(&self, lsp_balance_sat: SatAmount) -> Option<FeeEstimate> {
+        let amount = lsp_balance_sat.sat_value();
+        let below = self.fees.range(..=amount).next_back();
+        let above = self.fees.range(amount..).next();
+
+        let (fee, confidence) = match (below, above) {
+            (Some((_, fee)), Some((capacity, _))) if *capacity == amount => {
+                (*fee as f64, EstimateConfidence::Interpolated)
+            }
+            (Some(low), Some(high)) => (
+                interpolate(low, high, amount),
+                EstimateConfidence::Interpolated,
+            ),
+            _ => {
+                // The two capacities that are closest to the amount
+                let mut closest: Vec<(&u64, &u64)> = if below.is_some() {
+                    self.fees.iter().rev().take(2).collect()
+                } else {
+                    self.fees.iter().take(2).collect()
+                };
+                closest.sort();
+                let fee = match closest.as_slice() {
+                    [(capacity, fee)] => **fee as f64 * amount as f64 / **capacity as f64,
+                    [low, high] => interpolate(*low, *high, amount),
+                    _ => return None,
+                };
+                (fee, EstimateConfidence::Extrapolated)
+            }
+        };
+
+        Some(FeeEstimate {
+            fee_total_sat: SatAmount::new(fee.max(0.0).round() as u64),
+            confidence,
+        })
+    }
+}
+
+/// The fee at `amount` on the line through `low` and `high`
+fn interpolate(low: (&u64, &u64), high: (&u64, &u64), amount: u64) -> f64 {
+    let (x0, y0) = (*low.0 as f64, *low.1 as f64);
+    let (x1, y1) = (*high.0 as f64, *high.1 as f64);
+    y0 + (y1 - y0) * (amount as f64 - x0) / (x1 - x0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::PublicKey;
+    use lsp_primitives::lsps1::schema::{Payment, PaymentState};
+    use serde_json::json;
+
+    use crate::pins::PaymentPin;
+
+    /// An LSP that sells channels of 100_000 up to 2_000_000 sat
+    fn info(max_open_orders_per_peer: Option<u32>) -> Lsps1GetInfoResponse {
+        let mut info = json!({
+            "options" : {
+                "min_required_channel_confirmations" : 0,
+                "min_funding_confirms_within_blocks" : 6,
+                "min_onchain_payment_confirmations" : null,
+                "supports_zero_channel_reserve" : false,
+                "min_onchain_payment_size_sat" : null,
+                "max_channel_expiry_blocks" : 20_000,
+                "min_initial_client_balance_sat" : "0",
+                "max_initial_client_balance_sat" : "0",
+                "min_initial_lsp_balance_sat" : "100000",
+                "max_initial_lsp_balance_sat" : "2000000",
+                "min_channel_balance_sat" : "100000",
+                "max_channel_balance_sat" : "2000000",
+            }
+        });
+        if let Some(limit) = max_open_orders_per_peer {
+            info["lsp_extensions"] = json!({ "max_open_orders_per_peer" : limit });
+        }
+        serde_json::from_value(info).unwrap()
+    }
+
+    fn amounts(plan: &LiquidityPlan) -> Vec<u64> {
+        plan.orders
+            .iter()
+            .map(|order| order.lsp_balance_sat.sat_value())
+            .collect()
+    }
+
+    #[test]
+    fn one_big_order_if_the_fee_is_concave() {
+        // A base fee of 10_000 sat plus 0.1%
+        let plan = plan_liquidity(SatAmount::new(2_000_000), &info(None), |amount| {
+            Ok(SatAmount::new(10_000 + amount.sat_value() / 1_000))
+        })
+        .unwrap();
+
+        assert_eq!(amounts(&plan), vec![2_000_000]);
+        assert_eq!(plan.fee_total_sat, SatAmount::new(12_000));
+        assert_eq!(plan.inbound_sat, SatAmount::new(2_000_000));
+    }
+
+    #[test]
+    fn plan_serializes_amounts_as_numbers() {
+        let plan = plan_liquidity(SatAmount::new(2_000_000), &info(None), |amount| {
+            Ok(SatAmount::new(10_000 + amount.sat_value() / 1_000))
+        })
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(plan).unwrap(),
+            json!({
+                "orders" : [{ "lsp_balance_sat" : 2_000_000, "fee_total_sat" : 12_000 }],
+                "inbound_sat" : 2_000_000,
+                "fee_total_sat" : 12_000,
+            })
+        );
+    }
+
+    #[test]
+    fn smaller_orders_if_the_fee_is_convex() {
+        // The fee grows with the square of the amount
+        let plan = plan_liquidity(SatAmount::new(2_000_000), &info(None), |amount| {
+            let amount = amount.sat_value();
+            Ok(SatAmount::new(amount * amount / 100_000_000))
+        })
+        .unwrap();
+
+        assert_eq!(amounts(&plan), vec![500_000; 4]);
+        assert_eq!(plan.fee_total_sat, SatAmount::new(4 * 2_500));
+    }
+
+    #[test]
+    fn respect_the_limits_of_the_lsp() {
+        let convex = |amount: SatAmount| {
+            let amount = amount.sat_value();
+            Ok(SatAmount::new(amount * amount / 100_000_000))
+        };
+
+        // The LSP doesn't accept more than two open orders
+        let plan = plan_liquidity(SatAmount::new(2_000_000), &info(Some(2)), convex).unwrap();
+        assert_eq!(amounts(&plan), vec![1_000_000, 1_000_000]);
+
+        // Uneven amounts are split to the sat
+        let plan = plan_liquidity(SatAmount::new(3_000_001), &info(Some(2)), convex).unwrap();
+        assert_eq!(amounts(&plan), vec![1_500_001, 1_500_000]);
+
+        // Small targets are raised to the minimum of the LSP
+        let plan = plan_liquidity(SatAmount::new(50_000), &info(None), convex).unwrap();
+        assert_eq!(amounts(&plan), vec![100_000]);
+        assert_eq!(plan.inbound_sat, SatAmount::new(100_000));
+
+        // Even four orders of the maximum can't buy this much
+        let err = plan_liquidity(SatAmount::new(8_000_001), &info(None), convex).unwrap_err();
+        assert!(err.to_string().contains("can't be bought"), "{}", err);
+    }
+
+    #[test]
+    fn quote_every_amount_once() {
+        let mut quoted = Vec::new();
+        let plan = plan_liquidity(SatAmount::new(1_200_000), &info(None), |amount| {
+            quoted.push(amount.sat_value());
+            Ok(SatAmount::new(5_000))
+        })
+        .unwrap();
+
+        // Equal fees prefer the plan with the fewest orders
+        assert_eq!(amounts(&plan), vec![1_200_000]);
+        assert_eq!(quoted, vec![1_200_000, 600_000, 400_000, 300_000]);
+
+        let err = plan_liquidity(SatAmount::new(1_200_000), &info(None), |_| {
+            Err(anyhow!("The LSP is offline"))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "The LSP is offline");
+    }
+
+    fn pinned_order(capacity: u64, fee: u64) -> PinnedOrder {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        PinnedOrder {
+            peer_id,
+            order_id: format!("order-{}", capacity),
+            pin: PaymentPin::from_payment(&Payment {
+                state: PaymentState::Paid,
+                fee_total_sat: SatAmount::new(fee),
+                order_total_sat: SatAmount::new(capacity + fee),
+                bolt11_invoice: "lnbcrt1a".to_string(),
+                onchain_address: None,
+                min_onchain_payment_confirmations: None,
+                min_fee_for_0conf: None,
+                onchain_payment: None,
+            }),
+            changed_fields: vec![],
+            order_state: None,
+            channel_capacity: Some(SatAmount::new(capacity)),
+            created_at: None,
+            funding_outpoint: None,
+            channel_ready: false,
+            external_payment: false,
+        }
+    }
+
+    #[test]
+    fn estimate_fees_from_previous_orders() {
+        let history = FeeHistory::from_orders(&[
+            pinned_order(1_000_000, 2_000),
+            pinned_order(500_000, 1_500),
+        ]);
+        let estimate = |amount| history.estimate(SatAmount::new(amount)).unwrap();
+
+        assert_eq!(
+            estimate(500_000),
+            FeeEstimate {
+                fee_total_sat: SatAmount::new(1_500),
+                confidence: EstimateConfidence::Interpolated,
+            }
+        );
+        assert_eq!(estimate(750_000).fee_total_sat, SatAmount::new(1_750));
+        assert_eq!(
+            estimate(750_000).confidence,
+            EstimateConfidence::Interpolated
+        );
+        assert_eq!(
+            estimate(2_000_000),
+            FeeEstimate {
+                fee_total_sat: SatAmount::new(3_000),
+                confidence: EstimateConfidence::Extrapolated,
+            }
+        );
+        assert_eq!(estimate(100_000).fee_total_sat, SatAmount::new(1_100));
+
+        // A single order is scaled
+        let history = FeeHistory::from_orders(&[pinned_order(1_000_000, 2_000)]);
+        assert_eq!(
+            history.estimate(SatAmount::new(500_000)).unwrap(),
+            FeeEstimate {
+                fee_total_sat: SatAmount::new(1_000),
+                confidence: EstimateConfidence::Extrapolated,
+            }
+        );
+
+        // The LSP changed the order after it was pinned
+        let mut changed = pinned_order(1_000_000, 2_000);
+        changed.changed_fields = vec!["payment.fee_total_sat"];
+        let history = FeeHistory::from_orders(&[changed]);
+        assert!(history.is_empty());
+        assert!(history.estimate(SatAmount::new(500_000)).is_none());
+    }
+}
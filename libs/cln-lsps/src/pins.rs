@@ -0,0 +1,887 @@
+//! Protects the client against LSPs that change an order after it was created
+//!
+//! An LSP could return one invoice in `lsps1.create_order` and report
+//! another one with a higher amount in `lsps1.get_order`, hoping the
+//! client pays twice. The payment details of the first response are
+//! pinned. Every later response for the same order is compared against
+//! the pin. An order that doesn't match is flagged for good.
+//!
+//! Pins are kept in the datastore of lightningd. An order stays pinned
+//! and flagged after the node restarts.
+//!
+//! The registry also remembers what is needed to recognize the channel
+//! of an order once our node sees it. See [`crate::channel_ready`].
+//!
+//! Completed and failed orders can be compacted after a retention period.
+//! All orders can be exported to and imported from a versioned JSON-file
+//! to back up and restore a wallet.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use cln_rpc::model::requests::{
+    DatastoreMode, DatastoreRequest, DeldatastoreRequest, ListdatastoreRequest,
+};
+use cln_rpc::ClnRpc;
+use serde::{Deserialize, Serialize};
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, Outpoint, PublicKey, SatAmount};
+use lsp_primitives::lsps1::schema::{Lsps1GetOrderResponse, OrderState, Payment};
+
+use crate::invoice::normalize_bolt11;
+
+/// The warning attached to orders the LSP changed
+pub const LSP_INCONSISTENT: &str = "lsp_inconsistent";
+
+/// The payment fields that are compared against the pin
+const PINNED_FIELDS: [&str; 3] = ["bolt11_invoice", "fee_total_sat", "order_total_sat"];
+
+/// All pins are stored under this key in the datastore
+const DATASTORE_KEY: [&str; 2] = ["lsps-client", "pins"];
+
+/// The payment details of an order as first reported by the LSP
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentPin {
+    /// The normalized invoice
+    bolt11: String,
+    fee_total_sat: SatAmount,
+    order_total_sat: SatAmount,
+}
+
+impl PaymentPin {
+    pub fn from_payment(payment: &Payment) -> Self {
+        // An invalid invoice is pinned as is, so a later change is still noticed
+        let bolt11 = normalize_bolt11(&payment.bolt11_invoice)
+            .unwrap_or_else(|_| payment.bolt11_invoice.clone());
+        Self {
+            bolt11,
+            fee_total_sat: payment.fee_total_sat,
+            order_total_sat: payment.order_total_sat,
+        }
+    }
+
+    /// Lists the fields that differ from `other`
+    fn changed_fields(&self, other: &PaymentPin) -> Vec<&'static str> {
+        let [bolt11, fee_total_sat, order_total_sat] = PINNED_FIELDS;
+        let mut fields = vec![];
+        if self.bolt11 != other.bolt11 {
+            fields.push(bolt11);
+        }
+        if self.fee_total_sat != other.fee_total_sat {
+            fields.push(fee_total_sat);
+        }
+        if self.order_total_sat != other.order_total_sat {
+            fields.push(order_total_sat);
+        }
+        fields
+    }
+}
+
+/// An order seen by the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinnedOrder {
+    pub peer_id: PublicKey,
+    pub order_id: String,
+    pub pin: PaymentPin,
+    /// All fields the LSP has changed since the order was pinned
+    pub changed_fields: Vec<&'static str>,
+    /// The state of the order as last reported by the LSP
+    ///
+    /// The order is `COMPLETED` once our node has seen its channel become ready
+    pub order_state: Option<OrderState>,
+    /// The sum of the LSP and client balance of the channel
+    pub channel_capacity: Option<SatAmount>,
+    pub created_at: Option<IsoDatetime>,
+    pub funding_outpoint: Option<Outpoint>,
+    /// Our node has seen the channel of the order become ready
+    pub channel_ready: bool,
+    /// The invoice is paid by an external wallet. See [`crate::tracker`]
+    pub external_payment: bool,
+}
+
+/// The representation of a [`PinnedOrder`] in the datastore and in exports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredOrder {
+    peer_id: PublicKey,
+    order_id: String,
+    pin: PaymentPin,
+    changed_fields: Vec<String>,
+    #[serde(default)]
+    order_state: Option<OrderState>,
+    #[serde(default)]
+    channel_capacity: Option<SatAmount>,
+    #[serde(default)]
+    created_at: Option<IsoDatetime>,
+    #[serde(default)]
+    funding_outpoint: Option<Outpoint>,
+    #[serde(default)]
+    channel_ready: bool,
+    #[serde(default)]
+    external_payment: bool,
+}
+
+impl PinnedOrder {
+    pub fn is_inconsistent(&self) -> bool {
+        !self.changed_fields.is_empty()
+    }
+
+    pub fn warnings(&self) -> Vec<&'static str> {
+        if self.is_inconsistent() {
+            vec![LSP_INCONSISTENT]
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn fee_total_sat(&self) -> SatAmount {
+        self.pin.fee_total_sat
+    }
+
+    pub fn order_total_sat(&self) -> SatAmount {
+        self.pin.order_total_sat
+    }
+
+    fn datastore_key(&self) -> Vec<String> {
+        let mut key: Vec<String> = DATASTORE_KEY.iter().map(|k| k.to_string()).collect();
+        key.push(format!("{}-{}", self.peer_id.to_hex(), self.order_id));
+        key
+    }
+
+    /// Orders in a final state can be compacted
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self.order_state,
+            Some(OrderState::Completed) | Some(OrderState::Failed)
+        )
+    }
+
+    /// Lists the properties that differ from `other`
+    fn differing_fields(&self, other: &PinnedOrder) -> Vec<&'static str> {
+        let sorted = |fields: &[&'static str]| {
+            let mut fields = fields.to_vec();
+            fields.sort();
+            fields
+        };
+
+        let mut fields = vec![];
+        if self.pin != other.pin {
+            fields.push("pin");
+        }
+        if sorted(&self.changed_fields) != sorted(&other.changed_fields) {
+            fields.push("changed_fields");
+        }
+        if self.order_state != other.order_state {
+            fields.push("order_state");
+        }
+        if self.channel_capacity != other.channel_capacity {
+            fields.push("channel_capacity");
+        }
+        if self.created_at != other.created_at {
+            fields.push("created_at");
+        }
+        if self.funding_outpoint != other.funding_outpoint {
+            fields.push("funding_outpoint");
+        }
+        if self.channel_ready != other.channel_ready {
+            fields.push("channel_ready");
+        }
+        if self.external_payment != other.external_payment {
+            fields.push("external_payment");
+        }
+        fields
+    }
+
+    fn to_stored(&self) -> StoredOrder {
+        StoredOrder {
+            peer_id: self.peer_id,
+            order_id: self.order_id.clone(),
+            pin: self.pin.clone(),
+            changed_fields: self.changed_fields.iter().map(|f| f.to_string()).collect(),
+            order_state: self.order_state.clone(),
+            channel_capacity: self.channel_capacity,
+            created_at: self.created_at,
+            funding_outpoint: self.funding_outpoint.clone(),
+            channel_ready: self.channel_ready,
+            external_payment: self.external_payment,
+        }
+    }
+
+    fn from_stored(stored: StoredOrder) -> Self {
+        let changed_fields = PINNED_FIELDS
+            .into_iter()
+            .filter(|field| stored.changed_fields.iter().any(|f| f == field))
+            .collect();
+        Self {
+            peer_id: stored.peer_id,
+            order_id: stored.order_id,
+            pin: stored.pin,
+            changed_fields,
+            order_state: stored.order_state,
+            channel_capacity: stored.channel_capacity,
+            created_at: stored.created_at,
+            funding_outpoint: stored.funding_outpoint,
+            channel_ready: stored.channel_ready,
+            external_payment: stored.external_payment,
+        }
+    }
+
+    fn to_datastore_string(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.to_stored())?)
+    }
+
+    fn from_datastore_string(value: &str) -> Result<Self> {
+        let stored: StoredOrder = serde_json::from_str(value)?;
+        Ok(Self::from_stored(stored))
+    }
+
+    /// Writes the order to the datastore of lightningd
+    pub async fn store(&self, rpc: &mut ClnRpc) -> Result<()> {
+        rpc.call_typed(&DatastoreRequest {
+            key: self.datastore_key(),
+            string: Some(self.to_datastore_string()?),
+            hex: None,
+            mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+            generation: None,
+        })
+        .await
+        .with_context(|| format!("Failed to store pin of order {}", self.order_id))?;
+        Ok(())
+    }
+
+    /// Removes the order from the datastore of lightningd
+    pub async fn delete(&self, rpc: &mut ClnRpc) -> Result<()> {
+        rpc.call_typed(&DeldatastoreRequest {
+            key: self.datastore_key(),
+            generation: None,
+        })
+        .await
+        .with_context(|| format!("Failed to delete pin of order {}", self.order_id))?;
+        Ok(())
+    }
+}
+
+/// The version of the format written by [`OrderPins::export`]
+pub const EXPORT_SCHEMA_VERSION: u32 = 1;
+
+/// All orders known by the client
+///
+/// Used to back up the orders of a wallet and to restore them on
+/// another node using [`OrderPins::import`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderExport {
+    pub schema_version: u32,
+    orders: Vec<StoredOrder>,
+}
+
+impl OrderExport {
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parses an export
+    ///
+    /// Fails if the export was written by a newer version of the client
+    pub fn from_json(value: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(value).context("The export isn't valid JSON")?;
+        let version = value
+            .get("schema_version")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| anyhow!("The export has no schema_version"))?;
+        if version == 0 || version > u64::from(EXPORT_SCHEMA_VERSION) {
+            return Err(anyhow!(
+                "Unsupported schema_version {}. Expected a version up to {}",
+                version,
+                EXPORT_SCHEMA_VERSION
+            ));
+        }
+        serde_json::from_value(value).context("Invalid orders in export")
+    }
+}
+
+/// An imported order that differs from the one the client knows
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportConflict {
+    pub peer_id: PublicKey,
+    pub order_id: String,
+    /// The properties that differ. The local value is kept
+    pub fields: Vec<&'static str>,
+}
+
+/// The result of [`OrderPins::import`]
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// The orders that were unknown. They must be stored
+    pub imported: Vec<PinnedOrder>,
+    /// The number of orders that were already known as is
+    pub unchanged: usize,
+    pub conflicts: Vec<ImportConflict>,
+}
+
+/// The result of [`OrderPins::check`]
+#[derive(Debug)]
+pub struct PinCheck {
+    /// The fields the LSP changed in this response
+    pub changed_fields: Vec<&'static str>,
+    /// The order after the check
+    pub order: PinnedOrder,
+    /// The order was pinned or flagged by this check and must be stored
+    pub needs_store: bool,
+}
+
+/// Keeps the pinned payment details of every order
+///
+/// All order responses handled by the client pass through the registry.
+/// Orders that were created elsewhere are pinned the first time we see
+/// them.
+#[derive(Default)]
+pub struct OrderPins {
+    orders: Mutex<HashMap<(PublicKey, String), PinnedOrder>>,
+}
+
+impl OrderPins {
+    /// Loads all pins from the datastore of lightningd
+    ///
+    /// Returns the number of orders that were loaded. Orders that
+    /// are already known are kept as is.
+    pub async fn load(&self, rpc: &mut ClnRpc) -> Result<usize> {
+        let response = rpc
+            .call_typed(&ListdatastoreRequest {
+                key: Some(DATASTORE_KEY.iter().map(|k| k.to_string()).collect()),
+            })
+            .await
+            .context("Failed to list pinned orders")?;
+
+        let mut orders = self.orders.lock().unwrap();
+        let mut loaded = 0;
+        for entry in response.datastore {
+            let Some(value) = entry.string else {
+                continue;
+            };
+            match PinnedOrder::from_datastore_string(&value) {
+                Ok(order) => {
+                    let key = (order.peer_id, order.order_id.clone());
+                    orders.entry(key).or_insert(order);
+                    loaded += 1;
+                }
+                Err(err) => log::warn!("Ignoring invalid pin {:?}: {:?}", entry.key, err),
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Compares `payment` against the pinned payment details of the order
+    ///
+    /// The first payment seen for an order is pinned. The fields the LSP
+    /// changed in this response are also added to the order, which stays
+    /// flagged even if the LSP reverts the change.
+    pub fn check(&self, peer_id: &PublicKey, order_id: &str, payment: &Payment) -> PinCheck {
+        let pin = PaymentPin::from_payment(payment);
+        let mut orders = self.orders.lock().unwrap();
+        let mut needs_store = false;
+        let order = orders
+            .entry((*peer_id, order_id.to_string()))
+            .or_insert_with(|| {
+                needs_store = true;
+                PinnedOrder {
+                    peer_id: *peer_id,
+                    order_id: order_id.to_string(),
+                    pin: pin.clone(),
+                    changed_fields: vec![],
+                    order_state: None,
+                    channel_capacity: None,
+                    created_at: None,
+                    funding_outpoint: None,
+                    channel_ready: false,
+                    external_payment: false,
+                }
+            });
+
+        let changed_fields = order.pin.changed_fields(&pin);
+        for field in &changed_fields {
+            if !order.changed_fields.contains(field) {
+                order.changed_fields.push(field);
+                needs_store = true;
+            }
+        }
+        PinCheck {
+            changed_fields,
+            order: order.clone(),
+            needs_store,
+        }
+    }
+
+    /// Records the details of `order` that identify its channel
+    ///
+    /// Returns the updated order if it changed and must be stored.
+    /// Returns `None` if the order isn't pinned yet. An order that was
+    /// completed by our node stays `COMPLETED`.
+    pub fn track(&self, peer_id: &PublicKey, order: &Lsps1GetOrderResponse) -> Option<PinnedOrder> {
+        let mut orders = self.orders.lock().unwrap();
+        let pinned = orders.get_mut(&(*peer_id, order.order_id.to_string()))?;
+
+        let mut tracked = pinned.clone();
+        tracked.channel_capacity = order.lsp_balance_sat.checked_add(&order.client_balance_sat);
+        tracked.created_at = Some(order.created_at);
+        if let Some(channel) = &order.channel {
+            tracked.funding_outpoint = Some(channel.funding_outpoint.clone());
+        }
+        if !tracked.channel_ready {
+            tracked.order_state = Some(order.order_state.clone());
+        }
+
+        if tracked == *pinned {
+            return None;
+        }
+        *pinned = tracked.clone();
+        Some(tracked)
+    }
+
+    /// Completes the order because our node has seen its channel become ready
+    ///
+    /// Returns the updated order which must be stored. Returns `None`
+    /// if the order isn't pinned.
+    pub fn complete(
+        &self,
+        peer_id: &PublicKey,
+        order_id: &str,
+        funding_outpoint: &Outpoint,
+    ) -> Option<PinnedOrder> {
+        let mut orders = self.orders.lock().unwrap();
+        let order = orders.get_mut(&(*peer_id, order_id.to_string()))?;
+        order.order_state = Some(OrderState::Completed);
+        order.funding_outpoint = Some(funding_outpoint.clone());
+        order.channel_ready = true;
+        Some(order.clone())
+    }
+
+    /// Marks the order as paid by an external wallet
+    ///
+    /// Returns the updated order which must be stored. Returns `None`
+    /// if the order isn't pinned or was marked before.
+    pub fn mark_external_payment(
+        &self,
+        peer_id: &PublicKey,
+        order_id: &str,
+    ) -> Option<PinnedOrder> {
+        let mut orders = self.orders.lock().unwrap();
+        let order = orders.get_mut(&(*peer_id, order_id.to_string()))?;
+        if order.external_payment {
+            return None;
+        }
+        order.external_payment = true;
+        Some(order.clone())
+    }
+
+    /// Returns the order if it was seen before
+    pub fn get(&self, peer_id: &PublicKey, order_id: &str) -> Option<PinnedOrder> {
+        self.orders
+            .lock()
+            .unwrap()
+            .get(&(*peer_id, order_id.to_string()))
+            .cloned()
+    }
+
+    /// Returns all orders sorted by peer and order_id
+    pub fn list(&self) -> Vec<PinnedOrder> {
+        let mut orders: Vec<_> = self.orders.lock().unwrap().values().cloned().collect();
+        orders.sort_by(|a, b| {
+            (a.peer_id.serialize(), &a.order_id).cmp(&(b.peer_id.serialize(), &b.order_id))
+        });
+        orders
+    }
+
+    /// Removes the orders in a final state that were created before `created_before`
+    ///
+    /// Returns the removed orders, which must be deleted from the
+    /// datastore. Orders whose creation time is unknown are kept.
+    pub fn compact(&self, created_before: IsoDatetime) -> Vec<PinnedOrder> {
+        let mut orders = self.orders.lock().unwrap();
+        let expired: Vec<_> = orders
+            .iter()
+            .filter(|(_, order)| {
+                order.is_final()
+                    && order
+                        .created_at
+                        .is_some_and(|created_at| created_at < created_before)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut removed: Vec<_> = expired
+            .iter()
+            .filter_map(|key| orders.remove(key))
+            .collect();
+        removed.sort_by(|a, b| a.order_id.cmp(&b.order_id));
+        removed
+    }
+
+    /// Exports all orders
+    pub fn export(&self) -> OrderExport {
+        OrderExport {
+            schema_version: EXPORT_SCHEMA_VERSION,
+            orders: self.list().iter().map(PinnedOrder::to_stored).collect(),
+        }
+    }
+
+    /// Adds the orders of `export` that are unknown
+    ///
+    /// The client never loses what it has seen of an order. An order
+    /// that is already known keeps its local state, even if the export
+    /// differs. The differences are reported as conflicts.
+    pub fn import(&self, export: OrderExport) -> ImportReport {
+        let mut orders = self.orders.lock().unwrap();
+        let mut report = ImportReport::default();
+        for stored in export.orders {
+            let order = PinnedOrder::from_stored(stored);
+            let key = (order.peer_id, order.order_id.clone());
+            match orders.get(&key) {
+                None => {
+                    orders.insert(key, order.clone());
+                    report.imported.push(order);
+                }
+                Some(local) => {
+                    let fields = local.differing_fields(&order);
+                    if fields.is_empty() {
+                        report.unchanged += 1;
+                    } else {
+                        report.conflicts.push(ImportConflict {
+                            peer_id: order.peer_id,
+                            order_id: order.order_id,
+                            fields,
+                        });
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps1::schema::PaymentState;
+
+    const ORDER: &str = "bb4b5d0a-8334-49d8-9463-90a6d413af7c";
+
+    fn peer() -> PublicKey {
+        PublicKey::from_hex("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap()
+    }
+
+    fn other_peer() -> PublicKey {
+        PublicKey::from_hex("02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5")
+            .unwrap()
+    }
+
+    fn payment(bolt11: &str, fee_total_sat: u64, order_total_sat: u64) -> Payment {
+        Payment {
+            state: PaymentState::ExpectPayment,
+            fee_total_sat: SatAmount::new(fee_total_sat),
+            order_total_sat: SatAmount::new(order_total_sat),
+            bolt11_invoice: bolt11.to_string(),
+            onchain_address: None,
+            min_onchain_payment_confirmations: None,
+            min_fee_for_0conf: None,
+            onchain_payment: None,
+        }
+    }
+
+    #[test]
+    fn unchanged_order_is_consistent() {
+        let pins = OrderPins::default();
+        let check = pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        assert!(check.changed_fields.is_empty());
+        assert!(check.needs_store);
+
+        let check = pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        assert!(check.changed_fields.is_empty());
+        assert!(!check.needs_store);
+
+        // The case of the invoice doesn't matter
+        let check = pins.check(&peer(), ORDER, &payment("LNBCRT1A", 1_000, 101_000));
+        assert!(check.changed_fields.is_empty());
+
+        let order = pins.get(&peer(), ORDER).unwrap();
+        assert!(!order.is_inconsistent());
+        assert!(order.warnings().is_empty());
+    }
+
+    #[test]
+    fn changed_invoice_is_flagged() {
+        let pins = OrderPins::default();
+        pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+
+        let check = pins.check(&peer(), ORDER, &payment("lnbcrt1b", 1_000, 101_000));
+        assert_eq!(check.changed_fields, vec!["bolt11_invoice"]);
+        assert!(check.needs_store);
+        assert!(check.order.is_inconsistent());
+
+        // The order stays flagged after the LSP reverts the change
+        let check = pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        assert!(check.changed_fields.is_empty());
+        assert!(!check.needs_store);
+        let order = pins.get(&peer(), ORDER).unwrap();
+        assert_eq!(order.changed_fields, vec!["bolt11_invoice"]);
+        assert_eq!(order.warnings(), vec![LSP_INCONSISTENT]);
+    }
+
+    #[test]
+    fn changed_totals_are_flagged() {
+        let pins = OrderPins::default();
+        pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+
+        let check = pins.check(&peer(), ORDER, &payment("lnbcrt1a", 2_000, 102_000));
+        assert_eq!(
+            check.changed_fields,
+            vec!["fee_total_sat", "order_total_sat"]
+        );
+
+        // The pin keeps the totals of the first response
+        let order = pins.get(&peer(), ORDER).unwrap();
+        assert_eq!(order.fee_total_sat(), SatAmount::new(1_000));
+        assert_eq!(order.order_total_sat(), SatAmount::new(101_000));
+        assert!(order.is_inconsistent());
+    }
+
+    #[test]
+    fn orders_are_pinned_per_peer() {
+        let pins = OrderPins::default();
+        pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        let check = pins.check(&other_peer(), ORDER, &payment("lnbcrt1b", 2_000, 102_000));
+        assert!(check.changed_fields.is_empty());
+
+        let orders = pins.list();
+        assert_eq!(orders.len(), 2);
+        assert!(orders.iter().all(|o| !o.is_inconsistent()));
+    }
+
+    #[test]
+    fn flagged_order_survives_the_datastore() {
+        let pins = OrderPins::default();
+        pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        let order = pins
+            .check(&peer(), ORDER, &payment("lnbcrt1a", 2_000, 101_000))
+            .order;
+
+        assert_eq!(
+            order.datastore_key(),
+            vec![
+                "lsps-client".to_string(),
+                "pins".to_string(),
+                format!("{}-{}", peer().to_hex(), ORDER)
+            ]
+        );
+
+        let value = order.to_datastore_string().unwrap();
+        let restored = PinnedOrder::from_datastore_string(&value).unwrap();
+        assert_eq!(restored.peer_id, peer());
+        assert_eq!(restored.order_id, ORDER);
+        assert_eq!(restored.pin, order.pin);
+        assert_eq!(restored.changed_fields, vec!["fee_total_sat"]);
+        assert_eq!(restored.warnings(), vec![LSP_INCONSISTENT]);
+    }
+
+    #[test]
+    fn completed_order_survives_the_datastore() {
+        let pins = OrderPins::default();
+        pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        let outpoint: Outpoint =
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1"
+                .parse()
+                .unwrap();
+        let order = pins.complete(&peer(), ORDER, &outpoint).unwrap();
+
+        let restored =
+            PinnedOrder::from_datastore_string(&order.to_datastore_string().unwrap()).unwrap();
+        assert_eq!(restored, order);
+        assert_eq!(restored.order_state, Some(OrderState::Completed));
+        assert_eq!(restored.funding_outpoint, Some(outpoint));
+        assert!(restored.channel_ready);
+
+        // Pins stored before orders were tracked are still valid
+        let value = serde_json::json!({
+            "peer_id" : peer(),
+            "order_id" : ORDER,
+            "pin" : order.pin,
+            "changed_fields" : [],
+        });
+        let restored = PinnedOrder::from_datastore_string(&value.to_string()).unwrap();
+        assert_eq!(restored.order_state, None);
+        assert!(!restored.channel_ready);
+        assert!(!restored.external_payment);
+    }
+
+    #[test]
+    fn external_payment_survives_the_datastore() {
+        let pins = OrderPins::default();
+        assert!(pins.mark_external_payment(&peer(), ORDER).is_none());
+
+        pins.check(&peer(), ORDER, &payment("lnbcrt1a", 1_000, 101_000));
+        let order = pins.mark_external_payment(&peer(), ORDER).unwrap();
+        assert!(order.external_payment);
+        assert!(pins.mark_external_payment(&peer(), ORDER).is_none());
+
+        let restored =
+            PinnedOrder::from_datastore_string(&order.to_datastore_string().unwrap()).unwrap();
+        assert_eq!(restored, order);
+    }
+
+    const OTHER_ORDER: &str = "5cdc8a2d-4c2d-4d43-9b4c-c5f0a1d1e1b8";
+    const NOW: i64 = 1_700_000_000;
+    const DAY: i64 = 24 * 3600;
+
+    fn at(timestamp: i64) -> IsoDatetime {
+        IsoDatetime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    /// Pins an order that was created at `created_at` and is in `state`
+    fn pin_order(
+        pins: &OrderPins,
+        peer_id: &PublicKey,
+        order_id: &str,
+        state: OrderState,
+        created_at: i64,
+    ) -> PinnedOrder {
+        pins.check(peer_id, order_id, &payment("lnbcrt1a", 1_000, 101_000));
+        let mut orders = pins.orders.lock().unwrap();
+        let order = orders.get_mut(&(*peer_id, order_id.to_string())).unwrap();
+        order.order_state = Some(state);
+        order.created_at = Some(at(created_at));
+        order.channel_capacity = Some(SatAmount::new(100_000));
+        order.clone()
+    }
+
+    #[test]
+    fn compaction_removes_old_final_orders() {
+        let pins = OrderPins::default();
+        pin_order(&pins, &peer(), ORDER, OrderState::Completed, NOW - 40 * DAY);
+        pin_order(&pins, &peer(), OTHER_ORDER, OrderState::Failed, NOW - DAY);
+        // Orders that are still open are never compacted
+        pin_order(
+            &pins,
+            &other_peer(),
+            ORDER,
+            OrderState::Created,
+            NOW - 40 * DAY,
+        );
+        // Orders of unknown age are kept
+        pins.check(&other_peer(), OTHER_ORDER, &payment("lnbcrt1b", 1, 2));
+        pins.orders
+            .lock()
+            .unwrap()
+            .get_mut(&(other_peer(), OTHER_ORDER.to_string()))
+            .unwrap()
+            .order_state = Some(OrderState::Failed);
+
+        let removed = pins.compact(at(NOW - 30 * DAY));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].peer_id, peer());
+        assert_eq!(removed[0].order_id, ORDER);
+        assert!(pins.get(&peer(), ORDER).is_none());
+        assert_eq!(pins.list().len(), 3);
+
+        assert!(pins.compact(at(NOW - 30 * DAY)).is_empty());
+        let removed = pins.compact(at(NOW));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].order_id, OTHER_ORDER);
+    }
+
+    #[test]
+    fn export_wipe_import_round_trip() {
+        let pins = OrderPins::default();
+        pin_order(&pins, &peer(), ORDER, OrderState::Created, NOW);
+        pins.check(&peer(), ORDER, &payment("lnbcrt1b", 1_000, 101_000));
+        let outpoint: Outpoint =
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1"
+                .parse()
+                .unwrap();
+        pin_order(&pins, &other_peer(), ORDER, OrderState::Created, NOW - DAY);
+        pins.complete(&other_peer(), ORDER, &outpoint).unwrap();
+        pins.check(&other_peer(), OTHER_ORDER, &payment("lnbcrt1c", 1, 2));
+
+        let json = pins.export().to_json().unwrap();
+        let export = OrderExport::from_json(&json).unwrap();
+        assert_eq!(export.schema_version, EXPORT_SCHEMA_VERSION);
+        assert_eq!(export.len(), 3);
+
+        // Restore the orders on a wallet that doesn't know them
+        let restored = OrderPins::default();
+        let report = restored.import(export);
+        assert_eq!(report.imported.len(), 3);
+        assert_eq!(report.unchanged, 0);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(restored.list(), pins.list());
+        assert!(restored.get(&peer(), ORDER).unwrap().is_inconsistent());
+
+        // Importing twice doesn't duplicate orders
+        let report = restored.import(OrderExport::from_json(&json).unwrap());
+        assert!(report.imported.is_empty());
+        assert_eq!(report.unchanged, 3);
+        assert_eq!(restored.list().len(), 3);
+    }
+
+    #[test]
+    fn import_keeps_the_local_state() {
+        let backup = OrderPins::default();
+        pin_order(&backup, &peer(), ORDER, OrderState::Created, NOW);
+        pin_order(&backup, &peer(), OTHER_ORDER, OrderState::Created, NOW);
+        let export = backup.export();
+
+        // The order progressed after the backup was made
+        let pins = OrderPins::default();
+        pin_order(&pins, &peer(), ORDER, OrderState::Created, NOW);
+        let outpoint: Outpoint =
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1"
+                .parse()
+                .unwrap();
+        let local = pins.complete(&peer(), ORDER, &outpoint).unwrap();
+
+        let report = pins.import(export);
+        assert_eq!(report.imported.len(), 1);
+        assert_eq!(report.imported[0].order_id, OTHER_ORDER);
+        assert_eq!(report.unchanged, 0);
+        assert_eq!(
+            report.conflicts,
+            vec![ImportConflict {
+                peer_id: peer(),
+                order_id: ORDER.to_string(),
+                fields: vec!["order_state", "funding_outpoint", "channel_ready"],
+            }]
+        );
+        assert_eq!(pins.get(&peer(), ORDER).unwrap(), local);
+    }
+
+    #[test]
+    fn unsupported_export_versions_are_rejected() {
+        let export = OrderPins::default().export();
+        let mut value = serde_json::to_value(&export).unwrap();
+        assert_eq!(value["schema_version"], 1);
+
+        value["schema_version"] = serde_json::json!(2);
+        let err = OrderExport::from_json(&value.to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported schema_version 2. Expected a version up to 1"
+        );
+
+        let err = OrderExport::from_json(r#"{"orders" : []}"#).unwrap_err();
+        assert_eq!(err.to_string(), "The export has no schema_version");
+        assert!(OrderExport::from_json(r#"{"schema_version" : 1}"#).is_err());
+        assert!(
+            OrderExport::from_json(r#"{"schema_version" : 1, "orders" : []}"#)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
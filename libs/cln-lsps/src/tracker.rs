@@ -0,0 +1,370 @@
+//! Follows orders that are paid by an external wallet
+//!
+//! Some nodes only coordinate. The invoice of an order is paid from
+//! another wallet, so our node never sees the payment. The plugin polls
+//! `lsps1.get_order` instead, until the LSP reports the order as final.
+//!
+//! The progress of an order is detected from the responses of the LSP
+//! alone. A polled response is handled like the response of any other
+//! request that fetched the order. Whichever response reports the
+//! payment first results in a single `lsps1_payment_received`-update.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde_json::json;
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, PublicKey};
+use lsp_primitives::lsps1::schema::{Lsps1GetOrderResponse, OrderState, PaymentState};
+
+/// How far an order has progressed according to the LSP
+///
+/// The variants are ordered. An order never moves back to an
+/// earlier variant, even if a later response says so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OrderProgress {
+    /// The LSP hasn't received the payment
+    AwaitingPayment,
+    /// The payment is `HOLD` or `PAID`
+    PaymentReceived,
+    /// The LSP reported the funding outpoint of the channel
+    ChannelFunded,
+    Completed,
+    /// The order failed. The payment might have been refunded
+    Failed,
+}
+
+impl OrderProgress {
+    pub fn of(order: &Lsps1GetOrderResponse) -> Self {
+        match (&order.order_state, &order.payment.state) {
+            (OrderState::Completed, _) => Self::Completed,
+            (OrderState::Failed, _) | (_, PaymentState::Refunded) => Self::Failed,
+            _ if order.channel.is_some() => Self::ChannelFunded,
+            (_, PaymentState::Hold) | (_, PaymentState::Paid) => Self::PaymentReceived,
+            (_, PaymentState::ExpectPayment) => Self::AwaitingPayment,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AwaitingPayment => "awaiting_payment",
+            Self::PaymentReceived => "payment_received",
+            Self::ChannelFunded => "channel_funded",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+        }
+    }
+
+    /// The order won't change anymore
+    pub fn is_final(&self) -> bool {
+        matches!(self, Self::Completed | Self::Failed)
+    }
+}
+
+/// Returns true if the LSP has received the payment of `order`
+///
+/// A refunded payment was received before it was refunded
+fn is_payment_received(order: &Lsps1GetOrderResponse) -> bool {
+    match order.payment.state {
+        PaymentState::Hold | PaymentState::Paid | PaymentState::Refunded => true,
+        PaymentState::ExpectPayment => {
+            order.channel.is_some() || order.order_state == OrderState::Completed
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WatchedOrder {
+    progress: OrderProgress,
+    payment_received: bool,
+}
+
+/// The orders that are paid by an external wallet
+#[derive(Default)]
+pub struct OrderTracker {
+    watched: Mutex<HashMap<(PublicKey, String), WatchedOrder>>,
+}
+
+impl OrderTracker {
+    /// Starts watching an order
+    ///
+    /// Returns false if the order is already watched
+    pub fn watch(&self, peer_id: &PublicKey, order_id: &str) -> bool {
+        let mut watched = self.watched.lock().unwrap();
+        let key = (*peer_id, order_id.to_string());
+        if watched.contains_key(&key) {
+            return false;
+        }
+        watched.insert(
+            key,
+            WatchedOrder {
+                progress: OrderProgress::AwaitingPayment,
+                payment_received: false,
+            },
+        );
+        true
+    }
+
+    pub fn is_watched(&self, peer_id: &PublicKey, order_id: &str) -> bool {
+        self.watched
+            .lock()
+            .unwrap()
+            .contains_key(&(*peer_id, order_id.to_string()))
+    }
+
+    /// Stops watching an order
+    ///
+    /// E.g: because our node has seen its channel become ready
+    pub fn forget(&self, peer_id: &PublicKey, order_id: &str) -> bool {
+        self.watched
+            .lock()
+            .unwrap()
+            .remove(&(*peer_id, order_id.to_string()))
+            .is_some()
+    }
+
+    /// Handles a response of the LSP, no matter which request fetched it
+    ///
+    /// Returns the content of the `lsps1_payment_received`-notification
+    /// the first time the LSP reports the payment. Orders that reached a
+    /// final state are no longer watched. Orders that aren't watched are
+    /// ignored.
+    pub fn observe(
+        &self,
+        peer_id: &PublicKey,
+        order: &Lsps1GetOrderResponse,
+    ) -> Option<serde_json::Value> {
+        let mut watched = self.watched.lock().unwrap();
+        let key = (*peer_id, order.order_id.to_string());
+        let tracked = watched.get_mut(&key)?;
+
+        let progress = OrderProgress::of(order).max(tracked.progress);
+        tracked.progress = progress;
+        let is_first_payment = is_payment_received(order) && !tracked.payment_received;
+        tracked.payment_received |= is_first_payment;
+        if progress.is_final() {
+            watched.remove(&key);
+        }
+
+        is_first_payment.then(|| {
+            json!({
+                "peer_id" : peer_id,
+                "order_id" : order.order_id,
+                "order_state" : order.order_state,
+                "payment_state" : order.payment.state,
+                "progress" : progress.as_str(),
+                "observed_at" : IsoDatetime::now(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use lsp_primitives::json_rpc::JsonRpcId;
+    use lsp_primitives::lsps1::schema::OrderId;
+
+    use crate::client::LspClient;
+
+    const ORDER: &str = "bb4b5d0a-8334-49d8-9463-90a6d413af7c";
+
+    fn peer() -> PublicKey {
+        PublicKey::from_hex("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap()
+    }
+
+    fn order(order_state: &str, payment_state: &str, funded: bool) -> serde_json::Value {
+        let channel = funded.then(|| {
+            json!({
+                "funded_at" : "2024-01-01T00:10:00.000Z",
+                "funding_outpoint" : "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0",
+                "expires_at" : "2024-01-02T00:10:00.000Z"
+            })
+        });
+        json!({
+            "order_id" : ORDER,
+            "lsp_balance_sat" : "100000",
+            "client_balance_sat" : "0",
+            "funding_confirms_within_blocks" : 6,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks" : 144,
+            "announce_channel" : false,
+            "created_at" : "2024-01-01T00:00:00.000Z",
+            "expires_at" : "2024-01-02T00:00:00.000Z",
+            "order_state" : order_state,
+            "payment" : {
+                "state" : payment_state,
+                "fee_total_sat" : "1000",
+                "order_total_sat" : "101000",
+                "bolt11_invoice" : "lnbcrt1a",
+                "onchain_address" : null,
+                "min_onchain_payment_confirmations" : null,
+                "min_fee_for_0conf" : null,
+                "onchain_payment" : null
+            },
+            "channel" : channel
+        })
+    }
+
+    fn parsed(order_state: &str, payment_state: &str, funded: bool) -> Lsps1GetOrderResponse {
+        serde_json::from_value(order(order_state, payment_state, funded)).unwrap()
+    }
+
+    /// Answers every `lsps1.get_order` with the next scripted order
+    ///
+    /// The wallet pays the invoice elsewhere. The payment only shows up
+    /// in the responses of the LSP.
+    #[derive(Default)]
+    struct ScriptedLsp {
+        orders: VecDeque<serde_json::Value>,
+        requests: Vec<serde_json::Value>,
+    }
+
+    #[async_trait]
+    impl LspClient for ScriptedLsp {
+        async fn request_value(
+            &mut self,
+            _peer_id: &PublicKey,
+            method: &str,
+            params: serde_json::Value,
+            rpc_id: JsonRpcId,
+        ) -> Result<serde_json::Value> {
+            assert_eq!(method, "lsps1.get_order");
+            self.requests.push(params);
+            let order = self.orders.pop_front().expect("No more scripted orders");
+            Ok(json!({ "jsonrpc" : "2.0", "id" : rpc_id, "result" : order }))
+        }
+
+        async fn list_lsps(&mut self) -> Result<Vec<PublicKey>> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn progress_is_detected_from_the_order() {
+        let progress = |order_state, payment_state, funded| {
+            OrderProgress::of(&parsed(order_state, payment_state, funded))
+        };
+        assert_eq!(
+            progress("CREATED", "EXPECT_PAYMENT", false),
+            OrderProgress::AwaitingPayment
+        );
+        assert_eq!(
+            progress("CREATED", "HOLD", false),
+            OrderProgress::PaymentReceived
+        );
+        assert_eq!(
+            progress("CREATED", "PAID", true),
+            OrderProgress::ChannelFunded
+        );
+        assert_eq!(
+            progress("COMPLETED", "PAID", true),
+            OrderProgress::Completed
+        );
+        assert_eq!(progress("FAILED", "REFUNDED", false), OrderProgress::Failed);
+        assert_eq!(
+            progress("FAILED", "EXPECT_PAYMENT", false),
+            OrderProgress::Failed
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_is_detected_by_polling() {
+        let mut lsp = ScriptedLsp::default();
+        lsp.orders.extend([
+            order("CREATED", "EXPECT_PAYMENT", false),
+            order("CREATED", "EXPECT_PAYMENT", false),
+            order("CREATED", "PAID", false),
+            order("CREATED", "PAID", true),
+            order("COMPLETED", "PAID", true),
+        ]);
+        let tracker = OrderTracker::default();
+        assert!(tracker.watch(&peer(), ORDER));
+        assert!(!tracker.watch(&peer(), ORDER));
+
+        let order_id: OrderId = ORDER.parse().unwrap();
+        let mut updates = vec![];
+        let mut polls = 0;
+        while tracker.is_watched(&peer(), ORDER) {
+            let order = lsp.lsps1_get_order(&peer(), order_id).await.unwrap();
+            polls += 1;
+            if let Some(update) = tracker.observe(&peer(), &order) {
+                updates.push((polls, update));
+            }
+        }
+
+        // The order is polled until it is final
+        assert_eq!(polls, 5);
+        assert!(lsp.orders.is_empty());
+        assert_eq!(lsp.requests[0], json!({ "order_id" : ORDER }));
+
+        assert_eq!(updates.len(), 1);
+        let (poll, update) = &updates[0];
+        assert_eq!(*poll, 3);
+        assert_eq!(update["order_id"], ORDER);
+        assert_eq!(update["payment_state"], "PAID");
+        assert_eq!(update["progress"], "payment_received");
+    }
+
+    #[tokio::test]
+    async fn unpaid_order_that_fails_is_not_reported() {
+        let mut lsp = ScriptedLsp::default();
+        lsp.orders.extend([
+            order("CREATED", "EXPECT_PAYMENT", false),
+            order("FAILED", "EXPECT_PAYMENT", false),
+        ]);
+        let tracker = OrderTracker::default();
+        tracker.watch(&peer(), ORDER);
+
+        let order_id: OrderId = ORDER.parse().unwrap();
+        while tracker.is_watched(&peer(), ORDER) {
+            let order = lsp.lsps1_get_order(&peer(), order_id).await.unwrap();
+            assert!(tracker.observe(&peer(), &order).is_none());
+        }
+        assert!(lsp.orders.is_empty());
+    }
+
+    #[test]
+    fn payment_is_reported_once_whatever_the_source() {
+        let tracker = OrderTracker::default();
+
+        // Orders that aren't paid externally are none of our business
+        assert!(tracker
+            .observe(&peer(), &parsed("CREATED", "PAID", false))
+            .is_none());
+
+        tracker.watch(&peer(), ORDER);
+        // E.g: a response of lsps1-get-order that skipped PAID
+        let update = tracker
+            .observe(&peer(), &parsed("CREATED", "EXPECT_PAYMENT", true))
+            .unwrap();
+        assert_eq!(update["progress"], "channel_funded");
+
+        // E.g: a poll that got a stale response
+        assert!(tracker
+            .observe(&peer(), &parsed("CREATED", "PAID", false))
+            .is_none());
+        assert!(tracker.is_watched(&peer(), ORDER));
+
+        assert!(tracker
+            .observe(&peer(), &parsed("FAILED", "REFUNDED", true))
+            .is_none());
+        assert!(!tracker.is_watched(&peer(), ORDER));
+    }
+
+    #[test]
+    fn forgotten_order_is_not_watched() {
+        let tracker = OrderTracker::default();
+        tracker.watch(&peer(), ORDER);
+        assert!(tracker.forget(&peer(), ORDER));
+        assert!(!tracker.forget(&peer(), ORDER));
+        assert!(tracker
+            .observe(&peer(), &parsed("CREATED", "PAID", false))
+            .is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::hash::Hash;
 use std::pin::Pin;
@@ -7,6 +7,9 @@ use std::task::{Context, Poll, Waker};
 
 type MatcherState<RequestId, Response> = HashMap<RequestId, Arc<Mutex<RequestState<Response>>>>;
 
+/// The number of abandoned requests we remember
+const MAX_ABANDONED_REQUESTS: usize = 1024;
+
 /// Matches requests and responses based on `RequestId` coming from
 /// multiple sources.
 ///
@@ -16,11 +19,16 @@ type MatcherState<RequestId, Response> = HashMap<RequestId, Arc<Mutex<RequestSta
 /// `Future<Output=Response>`. Calling `process_response` using the same `RequestId`
 /// will awake the future and set the state to ready.
 ///
+/// A request can be abandoned when the caller is no longer interested in the
+/// response. E.g: because it has been retried using a new `RequestId`.
+/// A late response to an abandoned request is expected and can be ignored.
+///
 /// The implementation is thread-safe due to the over-use of Arc<Mutex<_>>. Performance,
 /// on large number of requests and responses might be suboptimal.
 #[derive(Clone)]
 pub struct RequestResponseMatcher<RequestId, Response> {
     futures: Arc<Mutex<MatcherState<RequestId, Response>>>,
+    abandoned: Arc<Mutex<VecDeque<RequestId>>>,
 }
 
 impl<RequestId, Response> RequestResponseMatcher<RequestId, Response> {
@@ -29,6 +37,7 @@ impl<RequestId, Response> RequestResponseMatcher<RequestId, Response> {
         let futures = HashMap::new();
         return Self {
             futures: Arc::new(Mutex::new(futures)),
+            abandoned: Arc::new(Mutex::new(VecDeque::new())),
         };
     }
 
@@ -72,6 +81,25 @@ where
             None => false,
         }
     }
+
+    /// Marks a request as abandoned
+    ///
+    /// A pending future for this request will never be resolved.
+    /// Only the most recent abandoned requests are remembered.
+    pub fn abandon(&mut self, request_id: RequestId) {
+        self.futures.lock().unwrap().remove(&request_id);
+
+        let mut abandoned = self.abandoned.lock().unwrap();
+        if abandoned.len() >= MAX_ABANDONED_REQUESTS {
+            abandoned.pop_front();
+        }
+        abandoned.push_back(request_id);
+    }
+
+    /// Returns `true` if the request was abandoned
+    pub fn is_abandoned(&self, request_id: &RequestId) -> bool {
+        self.abandoned.lock().unwrap().contains(request_id)
+    }
 }
 
 /// The `RequestState` is shared between the `RequestResponseMatcher` and the
@@ -298,4 +326,61 @@ mod test {
         assert!(!map.contains_key("request_2")); // Has been dropped
         assert!(map.contains_key("request_3")); // Still in the HashMap
     }
+
+    #[tokio::test]
+    async fn test_late_response_to_retried_request() {
+        let mut matcher = RequestResponseMatcher::<String, u64>::new();
+
+        // The first attempt times out and is retried using a new id
+        let req_1 = Box::pin(matcher.process_request(String::from("request_1")));
+        let timeout = tokio::time::timeout(std::time::Duration::from_millis(1), req_1).await;
+        assert!(timeout.is_err());
+        matcher.abandon(String::from("request_1"));
+
+        let req_2 = matcher.process_request(String::from("request_2"));
+
+        // The late response to the first attempt is ignorable
+        assert!(!matcher.process_response(&String::from("request_1"), 1));
+        assert!(matcher.is_abandoned(&String::from("request_1")));
+
+        // A response that doesn't match any request is not
+        assert!(!matcher.process_response(&String::from("ghost"), 1));
+        assert!(!matcher.is_abandoned(&String::from("ghost")));
+
+        assert!(matcher.process_response(&String::from("request_2"), 2));
+        assert_eq!(req_2.await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_first_response_of_any_attempt_wins() {
+        let mut matcher = RequestResponseMatcher::<String, u64>::new();
+
+        // Both attempts are still pending
+        let req_1 = Box::pin(matcher.process_request(String::from("request_1")));
+        let req_2 = Box::pin(matcher.process_request(String::from("request_2")));
+
+        // The response to the first attempt arrives after the retry
+        assert!(matcher.process_response(&String::from("request_1"), 1));
+        let (response, index, remaining) = futures::future::select_all(vec![req_1, req_2]).await;
+        assert_eq!(response, 1);
+        assert_eq!(index, 0);
+
+        // The retry is abandoned and its response is ignored
+        matcher.abandon(String::from("request_2"));
+        drop(remaining);
+        assert!(!matcher.process_response(&String::from("request_2"), 2));
+        assert!(matcher.is_abandoned(&String::from("request_2")));
+    }
+
+    #[test]
+    fn test_abandoned_requests_are_bounded() {
+        let mut matcher = RequestResponseMatcher::<usize, u64>::new();
+        for i in 0..MAX_ABANDONED_REQUESTS + 1 {
+            matcher.abandon(i);
+        }
+
+        assert!(!matcher.is_abandoned(&0));
+        assert!(matcher.is_abandoned(&1));
+        assert!(matcher.is_abandoned(&MAX_ABANDONED_REQUESTS));
+    }
 }
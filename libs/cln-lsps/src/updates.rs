@@ -0,0 +1,139 @@
+//! Reports orders that reached their final state
+//!
+//! A wallet wants to know when an order completed or failed without
+//! polling itself. E.g: to show "refund sent: <txid>". Every order
+//! response handled by the client passes through [`FinalStates`]. The
+//! first response in which an order is `COMPLETED` or `FAILED` results
+//! in a single update.
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use serde_json::json;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps1::schema::{Lsps1GetOrderResponse, OrderState};
+
+/// Remembers which orders have been reported as final
+#[derive(Default)]
+pub struct FinalStates {
+    reported: Mutex<HashSet<(PublicKey, String)>>,
+}
+
+impl FinalStates {
+    /// Returns the update for `order` if it reached its final state
+    ///
+    /// Returns `None` if the order is still in progress or if
+    /// the update was returned before.
+    pub fn take_update(
+        &self,
+        peer_id: &PublicKey,
+        order: &Lsps1GetOrderResponse,
+    ) -> Option<serde_json::Value> {
+        if order.order_state == OrderState::Created {
+            return None;
+        }
+
+        let key = (*peer_id, order.order_id.to_string());
+        if !self.reported.lock().unwrap().insert(key) {
+            return None;
+        }
+
+        Some(json!({
+            "peer_id" : peer_id,
+            "order_id" : order.order_id,
+            "order_state" : order.order_state,
+            "payment_state" : order.payment.state,
+            "channel" : order.channel,
+            "refund" : order.refund,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer() -> PublicKey {
+        PublicKey::from_hex("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap()
+    }
+
+    fn other_peer() -> PublicKey {
+        PublicKey::from_hex("02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5")
+            .unwrap()
+    }
+
+    fn order(order_state: &str, payment_state: &str) -> Lsps1GetOrderResponse {
+        serde_json::from_value(json!({
+            "order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+            "lsp_balance_sat" : "100000",
+            "client_balance_sat" : "0",
+            "funding_confirms_within_blocks" : 6,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks" : 144,
+            "announce_channel" : false,
+            "created_at" : "2024-01-01T00:00:00.000Z",
+            "expires_at" : "2024-01-02T00:00:00.000Z",
+            "order_state" : order_state,
+            "payment" : {
+                "state" : payment_state,
+                "fee_total_sat" : "1000",
+                "order_total_sat" : "101000",
+                "bolt11_invoice" : "lnbcrt1a",
+                "onchain_address" : null,
+                "min_onchain_payment_confirmations" : null,
+                "min_fee_for_0conf" : null,
+                "onchain_payment" : null
+            },
+            "channel" : null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn order_in_progress_is_not_reported() {
+        let states = FinalStates::default();
+        assert!(states
+            .take_update(&peer(), &order("CREATED", "EXPECT_PAYMENT"))
+            .is_none());
+        assert!(states
+            .take_update(&peer(), &order("CREATED", "PAID"))
+            .is_none());
+    }
+
+    #[test]
+    fn refunded_order_is_reported_once() {
+        let states = FinalStates::default();
+        let mut failed = order("FAILED", "REFUNDED");
+        failed.refund = serde_json::from_value(json!({
+            "txid" : "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            "amount_sat" : "101000",
+            "address" : "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            "broadcast_at" : "2024-01-01T01:00:00.000Z"
+        }))
+        .unwrap();
+
+        let update = states.take_update(&peer(), &failed).unwrap();
+        assert_eq!(update["order_state"], "FAILED");
+        assert_eq!(update["payment_state"], "REFUNDED");
+        assert_eq!(
+            update["refund"]["txid"],
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+        );
+        assert_eq!(update["refund"]["amount_sat"], "101000");
+
+        assert!(states.take_update(&peer(), &failed).is_none());
+    }
+
+    #[test]
+    fn completed_order_is_reported_per_peer() {
+        let states = FinalStates::default();
+        let completed = order("COMPLETED", "PAID");
+
+        let update = states.take_update(&peer(), &completed).unwrap();
+        assert_eq!(update["order_state"], "COMPLETED");
+        assert!(update["refund"].is_null());
+
+        assert!(states.take_update(&other_peer(), &completed).is_some());
+    }
+}
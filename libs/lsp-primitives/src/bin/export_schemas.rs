@@ -0,0 +1,19 @@
+//! Writes a JSON Schema for every LSPS-method to a directory
+//!
+//! Usage: `cargo run -p lsp-primitives --features schemars --bin export-schemas [dir]`
+use std::path::PathBuf;
+
+use anyhow::Result;
+use lsp_primitives::json_schema::write_schemas;
+
+fn main() -> Result<()> {
+    let dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("schemas"));
+
+    for path in write_schemas(&dir)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! The error type of the constructors, builders and parsers in this crate
+//!
+//! Library consumers can match on the variants. E.g: a wallet can tell
+//! an invalid address apart from a builder that misses a field.
+//! The type implements [`std::error::Error`] and converts into an
+//! `anyhow::Error` using `?`.
+use std::fmt;
+
+use crate::lsps0::bounded_range::BoundedRangeError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Explains why a value couldn't be constructed
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    /// A builder misses a required field
+    #[error("Missing field '{field}' in {builder}")]
+    MissingField {
+        /// The name of the builder. E.g: `PaymentBuilder`
+        builder: &'static str,
+        field: &'static str,
+    },
+    /// A field has a value that isn't allowed
+    #[error("{reason} ({field})")]
+    InvalidValue { field: String, reason: String },
+    /// A string or byte-array couldn't be parsed
+    #[error("Invalid {what}: {reason}")]
+    Parse {
+        /// The kind of value that was parsed. E.g: `public-key`
+        what: &'static str,
+        reason: String,
+    },
+    /// The minimum of a range exceeds the maximum
+    #[error("{message} ({property})")]
+    RangeViolation {
+        /// The name of the violated option. E.g: `min_channel_balance_sat`
+        property: String,
+        message: String,
+    },
+}
+
+impl Error {
+    pub(crate) fn missing_field(builder: &'static str, field: &'static str) -> Self {
+        Self::MissingField { builder, field }
+    }
+
+    pub(crate) fn invalid_value(field: impl Into<String>, reason: impl fmt::Display) -> Self {
+        Self::InvalidValue {
+            field: field.into(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub(crate) fn parse(what: &'static str, reason: impl fmt::Display) -> Self {
+        Self::Parse {
+            what,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl From<BoundedRangeError> for Error {
+    fn from(err: BoundedRangeError) -> Self {
+        Self::RangeViolation {
+            property: err.property,
+            message: err.message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::lsps0::common_schemas::TransactionId;
+
+    #[test]
+    fn converts_into_anyhow() {
+        fn parse(txid: &str) -> anyhow::Result<TransactionId> {
+            Ok(TransactionId::from_str(txid)?)
+        }
+
+        let err = parse("abcd").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid txid: Expected 32 bytes but got 2 bytes"
+        );
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Parse { what: "txid", .. })
+        ));
+    }
+
+    #[test]
+    fn range_violations_keep_the_property() {
+        let err: Error = BoundedRangeError {
+            property: "min_channel_balance_sat".to_string(),
+            message: "The minimum exceeds the maximum".to_string(),
+        }
+        .into();
+        assert_eq!(
+            err.to_string(),
+            "The minimum exceeds the maximum (min_channel_balance_sat)"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Hex-encoding used by all types in this crate
+//!
+//! [`decode`] is strict. It accepts upper- and lower-case digits and
+//! rejects
+//! - an odd number of digits
+//! - whitespace, including leading and trailing whitespace
+//! - a `0x`-prefix
+//! - any other character that isn't a hex digit
+//!
+//! [`encode`] always returns lower-case digits.
+use crate::error::{Error, Result};
+
+/// Encodes `data` as lower-case hex
+pub fn encode<T: AsRef<[u8]>>(data: T) -> String {
+    ::hex::encode(data)
+}
+
+/// Decodes the hex-string `value`
+///
+/// `what` names the kind of value that is decoded. E.g: `public-key`.
+/// It is used in the [`Error::Parse`] that is returned if `value` is malformed.
+pub fn decode(what: &'static str, value: &str) -> Result<Vec<u8>> {
+    if value.starts_with("0x") || value.starts_with("0X") {
+        return Err(Error::parse(what, "Unexpected '0x'-prefix"));
+    }
+    if let Some((index, c)) = value.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        return Err(Error::parse(
+            what,
+            format!("Invalid character {:?} at position {}", c, index),
+        ));
+    }
+    if value.len() % 2 == 1 {
+        return Err(Error::parse(
+            what,
+            format!("Odd number of digits ({})", value.len()),
+        ));
+    }
+    ::hex::decode(value).map_err(|e| Error::parse(what, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_bytes(rng: &mut impl Rng) -> Vec<u8> {
+        let mut data = vec![0u8; rng.gen_range(0..64)];
+        rng.fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn decode_encoded_data() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1_000 {
+            let data = random_bytes(&mut rng);
+            let encoded = encode(&data);
+            assert_eq!(encoded, encoded.to_lowercase());
+            assert_eq!(decode("data", &encoded).unwrap(), data);
+            assert_eq!(decode("data", &encoded.to_uppercase()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn reject_malformed_data() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..1_000 {
+            let data = random_bytes(&mut rng);
+            let encoded = encode(&data);
+            let index = rng.gen_range(0..=encoded.len());
+
+            let malformed = [
+                format!("0x{}", encoded),
+                format!("0X{}", encoded),
+                format!("{}0", encoded),
+                format!("{} ", encoded),
+                format!(" {}", encoded),
+                format!("{}\n", encoded),
+                format!("{}g{}", &encoded[..index], &encoded[index..]),
+                format!("{} 0{}", &encoded[..index], &encoded[index..]),
+            ];
+            for value in malformed {
+                let err = decode("data", &value).unwrap_err();
+                assert!(
+                    matches!(err, Error::Parse { what: "data", .. }),
+                    "{:?} was accepted",
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn errors_name_the_offending_input() {
+        let err = |value| decode("public-key", value).unwrap_err().to_string();
+        assert_eq!(err("0xab"), "Invalid public-key: Unexpected '0x'-prefix");
+        assert_eq!(
+            err("ab cd"),
+            "Invalid public-key: Invalid character ' ' at position 2"
+        );
+        assert_eq!(
+            err("abé"),
+            "Invalid public-key: Invalid character 'é' at position 2"
+        );
+        assert_eq!(err("abc"), "Invalid public-key: Odd number of digits (3)");
+        assert_eq!(decode("public-key", "").unwrap(), Vec::<u8>::new());
+    }
+}
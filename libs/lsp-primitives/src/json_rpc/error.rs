@@ -1,11 +1,14 @@
+use std::time::Duration;
+
 use crate::json_rpc::{JsonRpcId, JsonRpcResponseFailure};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 pub mod codes {
     pub const PARSE_ERROR_CODE: i64 = -32700;
     pub const PARSE_ERROR_MSG: &str = "Parse Error";
 
-    pub const INVALID_REQUEST_CODE: i64 = -36200;
+    pub const INVALID_REQUEST_CODE: i64 = -32600;
     pub const INVALID_REQUEST_MSG: &str = "Invalid Request";
 
     pub const METHOD_NOT_FOUND_CODE: i64 = -32601;
@@ -20,8 +23,11 @@ pub mod codes {
     pub const NOT_FOUND_CODE: i64 = 404;
     pub const NOT_FOUND_MSG: &str = "Not Found";
 
+    pub const TEMPORARILY_UNAVAILABLE_CODE: i64 = 503;
+    pub const TEMPORARILY_UNAVAILABLE_MSG: &str = "Temporarily unavailable";
+
     pub const OPTIONS_MISMATCH_CODE: i64 = 1000;
-    pub const OPTIONS_MISMATCH_MSG: &str = "Options mismatch";
+    pub const OPTIONS_MISMATCH_MSG: &str = "Option mismatch";
 
     pub const CLIENT_REJECTED_CODE: i64 = 1001;
     pub const CLIENT_REJECTED_MSG: &str = "Client rejected";
@@ -29,6 +35,72 @@ pub mod codes {
 
 pub type DefaultError = serde_json::Value;
 
+/// A stable and machine-readable identifier for an error
+///
+/// The LSP-server includes the reason as the `reason`-field
+/// in the `data` of every error it returns. The `message` is meant
+/// for humans and might change over time. Clients that want to handle
+/// specific errors should match on the `reason` instead.
+///
+/// | reason                    | meaning                                                         |
+/// |---------------------------|-----------------------------------------------------------------|
+/// | `parse_error`             | The request is not valid JSON                                   |
+/// | `invalid_request`         | The request is not a valid JSON-RPC request                     |
+/// | `method_not_found`        | The LSP-server doesn't support the requested method             |
+/// | `invalid_params`          | A parameter has an invalid value                                |
+/// | `unrecognized_params`     | The request contains parameters the server doesn't know         |
+/// | `option_mismatch`         | The request doesn't satisfy the options of the LSP-server       |
+/// | `network_mismatch`        | An address is for a different network than the LSP-server       |
+/// | `not_found`               | The requested resource doesn't exist                            |
+/// | `temporarily_unavailable` | The LSP-server can't handle the request right now. Retry later  |
+///
+/// A `temporarily_unavailable` error might include `retry_after_seconds`
+/// in `data`. Clients should wait at least that long before retrying.
+/// | `internal`                | Something went wrong. The `incident_id` is in the server logs   |
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LspsErrorReason {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    UnrecognizedParams,
+    OptionMismatch,
+    NetworkMismatch,
+    NotFound,
+    TemporarilyUnavailable,
+    Internal,
+}
+
+impl LspsErrorReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ParseError => "parse_error",
+            Self::InvalidRequest => "invalid_request",
+            Self::MethodNotFound => "method_not_found",
+            Self::InvalidParams => "invalid_params",
+            Self::UnrecognizedParams => "unrecognized_params",
+            Self::OptionMismatch => "option_mismatch",
+            Self::NetworkMismatch => "network_mismatch",
+            Self::NotFound => "not_found",
+            Self::TemporarilyUnavailable => "temporarily_unavailable",
+            Self::Internal => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for LspsErrorReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The `error`-field of a JSON-RPC response
+///
+/// The constructors for the codes in [`codes`] use the short message
+/// of the code as `message`. This message is stable, so clients can
+/// log or display it. Anything specific to the request, such as a
+/// human readable explanation, goes into `data`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorData<E = DefaultError> {
     pub code: i64,
@@ -37,6 +109,35 @@ pub struct ErrorData<E = DefaultError> {
 }
 
 impl<E> ErrorData<E> {
+    /// Creates an error with any code
+    ///
+    /// The `message` and `data` are sent as is.
+    pub fn new(code: i64, message: impl Into<String>, data: Option<E>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data,
+        }
+    }
+
+    /// Replaces the `data` of the error
+    pub fn with_data<F>(self, data: F) -> ErrorData<F> {
+        ErrorData {
+            code: self.code,
+            message: self.message,
+            data: Some(data),
+        }
+    }
+
+    /// Converts the `data` of the error. The `code` and `message` are kept
+    pub fn map_data<F>(self, f: impl FnOnce(E) -> F) -> ErrorData<F> {
+        ErrorData {
+            code: self.code,
+            message: self.message,
+            data: self.data.map(f),
+        }
+    }
+
     pub fn into_response<O>(self, id: JsonRpcId) -> JsonRpcResponseFailure<E> {
         JsonRpcResponseFailure {
             id,
@@ -47,61 +148,367 @@ impl<E> ErrorData<E> {
 }
 
 impl ErrorData<DefaultError> {
-    pub fn parse_error(_message: String) -> Self {
-        Self {
-            code: codes::PARSE_ERROR_CODE,
-            message: String::from(codes::PARSE_ERROR_MSG),
-            data: None,
-        }
+    /// The request is not valid JSON
+    ///
+    /// The `message` explains why and is sent in `data`
+    pub fn parse_error(message: impl Into<String>) -> Self {
+        Self::new(codes::PARSE_ERROR_CODE, codes::PARSE_ERROR_MSG, None)
+            .with_message(message)
+            .with_reason(LspsErrorReason::ParseError)
     }
 
-    pub fn invalid_request(_message: String) -> Self {
-        Self {
-            code: codes::INVALID_REQUEST_CODE,
-            message: codes::INVALID_REQUEST_MSG.into(),
-            data: None,
-        }
+    /// The request is not a valid JSON-RPC request
+    ///
+    /// The `message` explains why and is sent in `data`
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(
+            codes::INVALID_REQUEST_CODE,
+            codes::INVALID_REQUEST_MSG,
+            None,
+        )
+        .with_message(message)
+        .with_reason(LspsErrorReason::InvalidRequest)
     }
 
     pub fn method_not_found(method: &str) -> Self {
-        Self {
-            code: codes::METHOD_NOT_FOUND_CODE,
-            message: codes::METHOD_NOT_FOUND_MSG.into(),
-            data: Some(serde_json::json!({"method" : method})),
-        }
+        Self::new(
+            codes::METHOD_NOT_FOUND_CODE,
+            codes::METHOD_NOT_FOUND_MSG,
+            Some(serde_json::json!({"method" : method})),
+        )
+        .with_reason(LspsErrorReason::MethodNotFound)
+    }
+
+    /// The request doesn't satisfy the options of the LSP-server
+    ///
+    /// The `data` describes which option isn't satisfied
+    pub fn option_mismatch(data: Value) -> Self {
+        Self::new(
+            codes::OPTIONS_MISMATCH_CODE,
+            codes::OPTIONS_MISMATCH_MSG,
+            Some(data),
+        )
+        .with_reason(LspsErrorReason::OptionMismatch)
     }
 
     pub fn not_found() -> Self {
-        Self {
-            code: 404,
-            message: String::from("Not Found"),
-            data: None,
-        }
+        Self::new(codes::NOT_FOUND_CODE, codes::NOT_FOUND_MSG, None)
+            .with_reason(LspsErrorReason::NotFound)
+    }
+
+    pub fn temporarily_unavailable() -> Self {
+        Self::new(
+            codes::TEMPORARILY_UNAVAILABLE_CODE,
+            codes::TEMPORARILY_UNAVAILABLE_MSG,
+            None,
+        )
+        .with_reason(LspsErrorReason::TemporarilyUnavailable)
+    }
+
+    /// Sets the `retry_after_seconds`-field in `data`
+    ///
+    /// Tells the client how long it should wait before retrying
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        self.with_field("retry_after_seconds", Value::from(retry_after.as_secs()))
+    }
+
+    /// Returns true if the client can retry the request later
+    pub fn is_retryable(&self) -> bool {
+        self.code == codes::TEMPORARILY_UNAVAILABLE_CODE
+    }
+
+    /// The `retry_after_seconds` in `data` if it is present
+    pub fn retry_after(&self) -> Option<Duration> {
+        let seconds = self.data.as_ref()?.get("retry_after_seconds")?.as_u64()?;
+        Some(Duration::from_secs(seconds))
+    }
+
+    /// Sets the `message`-field in `data`
+    ///
+    /// Follows the same rules as [`ErrorData::with_reason`]
+    pub fn with_message(self, message: impl Into<String>) -> Self {
+        self.with_field("message", Value::String(message.into()))
+    }
+
+    /// Sets the `reason`-field in `data`
+    ///
+    /// If `data` is not a JSON-object the original value is
+    /// preserved in the `detail`-field.
+    pub fn with_reason(self, reason: LspsErrorReason) -> Self {
+        self.with_field("reason", Value::from(reason.as_str()))
+    }
+
+    fn with_field(mut self, key: &str, value: Value) -> Self {
+        let mut map = match self.data.take() {
+            None | Some(Value::Null) => Map::new(),
+            Some(Value::Object(map)) => map,
+            Some(other) => {
+                let mut map = Map::new();
+                map.insert("detail".to_string(), other);
+                map
+            }
+        };
+        map.insert(key.to_string(), value);
+        self.data = Some(Value::Object(map));
+        self
+    }
+
+    /// The `reason` in `data` if it is present and known
+    pub fn reason(&self) -> Option<LspsErrorReason> {
+        let reason = self.data.as_ref()?.get("reason")?;
+        serde_json::from_value(reason.clone()).ok()
     }
 }
 
-impl<E> ErrorData<E> {
-    pub fn invalid_params(data: E) -> Self {
-        Self {
-            code: codes::INVALID_PARAMS_CODE,
-            message: codes::INVALID_PARAMS_MSG.into(),
-            data: Some(data),
+impl std::fmt::Display for ErrorData<DefaultError> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = self
+            .data
+            .as_ref()
+            .and_then(|d| d.get("reason"))
+            .and_then(|r| r.as_str());
+
+        if let Some(reason) = reason {
+            write!(f, "{}: ", reason)?;
+        }
+        write!(f, "Code {} - {}", self.code, self.message)?;
+
+        let mut data = self.data.clone();
+        if let Some(Value::Object(map)) = data.as_mut() {
+            map.remove("reason");
+            if map.is_empty() {
+                data = None;
+            }
+        }
+        match data {
+            None | Some(Value::Null) => Ok(()),
+            Some(data) => write!(f, " \t {}", data),
         }
     }
 }
 
 impl<E> ErrorData<E> {
+    pub fn invalid_params(data: E) -> Self {
+        Self::new(
+            codes::INVALID_PARAMS_CODE,
+            codes::INVALID_PARAMS_MSG,
+            Some(data),
+        )
+    }
+
+    #[deprecated(note = "Use `ErrorData::internalize` which also sets the `reason`")]
     pub fn internal_error(data: E) -> Self {
-        Self {
-            code: codes::INTERNAL_ERROR_CODE,
-            message: codes::INTERNAL_ERROR_MSG.into(),
-            data: Some(data),
-        }
+        Self::new(
+            codes::INTERNAL_ERROR_CODE,
+            codes::INTERNAL_ERROR_MSG,
+            Some(data),
+        )
     }
 }
 
 impl ErrorData<DefaultError> {
     pub fn internalize<T: core::fmt::Debug>(err: T) -> Self {
-        Self::internal_error(serde_json::Value::String(format!("{:?}", err)))
+        Self::new(
+            codes::INTERNAL_ERROR_CODE,
+            codes::INTERNAL_ERROR_MSG,
+            Some(Value::String(format!("{:?}", err))),
+        )
+        .with_reason(LspsErrorReason::Internal)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn constructors_set_reason() {
+        let cases = [
+            (ErrorData::parse_error(""), LspsErrorReason::ParseError),
+            (
+                ErrorData::invalid_request(""),
+                LspsErrorReason::InvalidRequest,
+            ),
+            (
+                ErrorData::method_not_found("a.b"),
+                LspsErrorReason::MethodNotFound,
+            ),
+            (ErrorData::not_found(), LspsErrorReason::NotFound),
+            (
+                ErrorData::temporarily_unavailable(),
+                LspsErrorReason::TemporarilyUnavailable,
+            ),
+            (ErrorData::internalize("oops"), LspsErrorReason::Internal),
+        ];
+
+        for (error, reason) in cases {
+            assert_eq!(error.reason(), Some(reason));
+            assert_eq!(error.data.unwrap()["reason"], reason.as_str());
+        }
+    }
+
+    #[test]
+    fn wire_shape_of_errors() {
+        let cases = [
+            (
+                ErrorData::parse_error("Invalid JSON"),
+                json!({
+                    "code" : -32700,
+                    "message" : "Parse Error",
+                    "data" : {"message" : "Invalid JSON", "reason" : "parse_error"}
+                }),
+            ),
+            (
+                ErrorData::invalid_request("Missing field `id`"),
+                json!({
+                    "code" : -32600,
+                    "message" : "Invalid Request",
+                    "data" : {"message" : "Missing field `id`", "reason" : "invalid_request"}
+                }),
+            ),
+            (
+                ErrorData::method_not_found("a.b"),
+                json!({
+                    "code" : -32601,
+                    "message" : "Method not found",
+                    "data" : {"method" : "a.b", "reason" : "method_not_found"}
+                }),
+            ),
+            (
+                ErrorData::option_mismatch(json!({"property" : "max_channel_balance_sat"})),
+                json!({
+                    "code" : 1000,
+                    "message" : "Option mismatch",
+                    "data" : {"property" : "max_channel_balance_sat", "reason" : "option_mismatch"}
+                }),
+            ),
+            (
+                ErrorData::not_found(),
+                json!({
+                    "code" : 404,
+                    "message" : "Not Found",
+                    "data" : {"reason" : "not_found"}
+                }),
+            ),
+            (
+                ErrorData::temporarily_unavailable(),
+                json!({
+                    "code" : 503,
+                    "message" : "Temporarily unavailable",
+                    "data" : {"reason" : "temporarily_unavailable"}
+                }),
+            ),
+            (
+                ErrorData::internalize("oops"),
+                json!({
+                    "code" : -32603,
+                    "message" : "Internal Error",
+                    "data" : {"detail" : "\"oops\"", "reason" : "internal"}
+                }),
+            ),
+        ];
+
+        for (error, expected) in cases {
+            assert_eq!(serde_json::to_value(&error).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn retry_after_is_read_from_data() {
+        let error = ErrorData::temporarily_unavailable().with_retry_after(Duration::from_secs(600));
+        assert!(error.is_retryable());
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(600)));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "code" : 503,
+                "message" : "Temporarily unavailable",
+                "data" : {"reason" : "temporarily_unavailable", "retry_after_seconds" : 600}
+            })
+        );
+
+        let error = ErrorData::temporarily_unavailable();
+        assert!(error.is_retryable());
+        assert_eq!(error.retry_after(), None);
+
+        let error = ErrorData::method_not_found("a.b");
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn new_preserves_message_and_data() {
+        let error = ErrorData::new(1001, "Client rejected", Some(json!({"a" : 1})));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({"code" : 1001, "message" : "Client rejected", "data" : {"a" : 1}})
+        );
+
+        let error = ErrorData::<()>::new(42, "Custom", None).with_data("detail");
+        assert_eq!(error.message, "Custom");
+        assert_eq!(error.data, Some("detail"));
+
+        let error = error.map_data(|d| d.len());
+        assert_eq!((error.code, error.data), (42, Some(6)));
+    }
+
+    #[test]
+    fn reason_preserves_existing_data() {
+        let error = ErrorData::method_not_found("a.b");
+        assert_eq!(
+            error.data.unwrap(),
+            json!({"method" : "a.b", "reason" : "method_not_found"})
+        );
+
+        let error = ErrorData::internalize("oops");
+        assert_eq!(
+            error.data.unwrap(),
+            json!({"detail" : "\"oops\"", "reason" : "internal"})
+        );
+    }
+
+    #[test]
+    fn with_reason_overrides_reason() {
+        let error = ErrorData::not_found().with_reason(LspsErrorReason::InvalidParams);
+        assert_eq!(error.reason(), Some(LspsErrorReason::InvalidParams));
+    }
+
+    #[test]
+    fn reason_serializes_as_snake_case() {
+        let reasons = [
+            LspsErrorReason::ParseError,
+            LspsErrorReason::InvalidRequest,
+            LspsErrorReason::MethodNotFound,
+            LspsErrorReason::InvalidParams,
+            LspsErrorReason::UnrecognizedParams,
+            LspsErrorReason::OptionMismatch,
+            LspsErrorReason::NetworkMismatch,
+            LspsErrorReason::NotFound,
+            LspsErrorReason::TemporarilyUnavailable,
+            LspsErrorReason::Internal,
+        ];
+
+        for reason in reasons {
+            assert_eq!(serde_json::to_value(reason).unwrap(), reason.as_str());
+        }
+    }
+
+    #[test]
+    fn display_shows_reason_first() {
+        let error = ErrorData::method_not_found("a.b");
+        assert_eq!(
+            error.to_string(),
+            "method_not_found: Code -32601 - Method not found \t {\"method\":\"a.b\"}"
+        );
+
+        let error = ErrorData::not_found();
+        assert_eq!(error.to_string(), "not_found: Code 404 - Not Found");
+
+        let error: ErrorData = ErrorData {
+            code: 1,
+            message: "Unknown".to_string(),
+            data: None,
+        };
+        assert_eq!(error.to_string(), "Code 1 - Unknown");
     }
 }
@@ -5,7 +5,7 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-pub use crate::json_rpc::error::{DefaultError, ErrorData};
+pub use crate::json_rpc::error::{DefaultError, ErrorData, LspsErrorReason};
 use crate::lsps0::parameter_validation;
 pub use crate::no_params::NoParams;
 
@@ -44,7 +44,7 @@ impl From<&str> for JsonRpcId {
 }
 
 impl FromStr for JsonRpcId {
-    type Err = anyhow::Error;
+    type Err = crate::Error;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
         Ok(Self::String(value.to_string()))
@@ -62,6 +62,34 @@ pub fn generate_random_rpc_id() -> JsonRpcId {
     JsonRpcId::String(str_id)
 }
 
+/// The kind of a JSON-RPC message that was received from a peer
+///
+/// Requests and responses use the same custom message type. A node that
+/// runs the LSP-client and the LSP-server receives both. Each plugin
+/// handles one kind of message and ignores the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonRpcMessageKind {
+    Request,
+    Response,
+}
+
+impl JsonRpcMessageKind {
+    /// Classifies `message` without validating it
+    ///
+    /// A message without a `method` that contains a `result` or an `error`
+    /// is a response. Everything else is a request. A malformed request is
+    /// still a request, so the sender can be told what is wrong with it.
+    pub fn of(message: &serde_json::Value) -> Self {
+        let is_response = message.get("method").is_none()
+            && (message.get("result").is_some() || message.get("error").is_some());
+        if is_response {
+            Self::Response
+        } else {
+            Self::Request
+        }
+    }
+}
+
 /// Defines a json-rpc method and describes the schema
 /// of the input I, output O and error-type E.
 ///
@@ -172,7 +200,7 @@ where
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct JsonRpcRequest<I> {
     pub jsonrpc: String,
     pub id: JsonRpcId,
@@ -180,6 +208,41 @@ pub struct JsonRpcRequest<I> {
     pub params: I,
 }
 
+/// Clients encode a request without params differently. They omit the
+/// `params`-field or set it to `null` or `{}`. A missing field is read
+/// as `null`. [`parameter_validation::from_value`] treats `null` as `{}`.
+impl<'de, I> Deserialize<'de> for JsonRpcRequest<I>
+where
+    I: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize)]
+        struct RawRequest {
+            jsonrpc: String,
+            id: JsonRpcId,
+            method: String,
+            #[serde(default)]
+            params: serde_json::Value,
+        }
+
+        let raw = RawRequest::deserialize(deserializer)?;
+        let params = I::deserialize(raw.params)
+            .map_err(|e| D::Error::custom(format!("Invalid params: {}", e)))?;
+
+        Ok(Self {
+            jsonrpc: raw.jsonrpc,
+            id: raw.id,
+            method: raw.method,
+            params,
+        })
+    }
+}
+
 impl<I> JsonRpcRequest<I> {
     pub fn new<O, E>(method: JsonRpcMethod<I, O, E>, params: I) -> Self {
         Self {
@@ -231,13 +294,79 @@ pub struct JsonRpcResponseFailure<E> {
     pub jsonrpc: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A JSON-RPC 2.0 response
+///
+/// A response is only accepted if `jsonrpc` is `"2.0"` and it contains
+/// exactly one of `result` or `error`.
+#[derive(Debug, Serialize)]
 #[serde(untagged)]
 pub enum JsonRpcResponse<O, E> {
     Error(JsonRpcResponseFailure<E>),
     Ok(JsonRpcResponseSuccess<O>),
 }
 
+impl<'de, O, E> Deserialize<'de> for JsonRpcResponse<O, E>
+where
+    O: Deserialize<'de>,
+    E: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let mut object = serde_json::Map::<String, serde_json::Value>::deserialize(deserializer)?;
+
+        let jsonrpc = match object.remove("jsonrpc") {
+            Some(serde_json::Value::String(version)) if version == "2.0" => version,
+            Some(version) => {
+                return Err(D::Error::custom(format!(
+                    "Invalid JSON-RPC response: expected jsonrpc \"2.0\" but got {}",
+                    version
+                )))
+            }
+            None => {
+                return Err(D::Error::custom(
+                    "Invalid JSON-RPC response: missing field `jsonrpc`",
+                ))
+            }
+        };
+
+        let id = object
+            .remove("id")
+            .ok_or_else(|| D::Error::custom("Invalid JSON-RPC response: missing field `id`"))?;
+        let id = JsonRpcId::deserialize(id).map_err(|e| {
+            D::Error::custom(format!("Invalid JSON-RPC response: invalid id: {}", e))
+        })?;
+
+        match (object.remove("result"), object.remove("error")) {
+            (Some(result), None) => {
+                let result = O::deserialize(result).map_err(|e| {
+                    D::Error::custom(format!("Invalid JSON-RPC response: invalid result: {}", e))
+                })?;
+                Ok(Self::Ok(JsonRpcResponseSuccess {
+                    id,
+                    result,
+                    jsonrpc,
+                }))
+            }
+            (None, Some(error)) => {
+                let error = ErrorData::<E>::deserialize(error).map_err(|e| {
+                    D::Error::custom(format!("Invalid JSON-RPC response: invalid error: {}", e))
+                })?;
+                Ok(Self::Error(JsonRpcResponseFailure { id, error, jsonrpc }))
+            }
+            (Some(_), Some(_)) => Err(D::Error::custom(
+                "Invalid JSON-RPC response: contains both `result` and `error`",
+            )),
+            (None, None) => Err(D::Error::custom(
+                "Invalid JSON-RPC response: contains neither `result` nor `error`",
+            )),
+        }
+    }
+}
+
 impl<E, O> JsonRpcResponse<E, O> {
     pub fn jsonrpc(&self) -> &str {
         match self {
@@ -406,6 +535,110 @@ mod test {
         }
     }
 
+    fn parse_response(
+        json_value: serde_json::Value,
+    ) -> Result<JsonRpcResponse<String, DefaultError>, serde_json::Error> {
+        let rpc_method = JsonRpcMethod::<NoParams, String, DefaultError>::new("test.return_string");
+        rpc_method.parse_json_response_value(json_value)
+    }
+
+    #[test]
+    fn parse_rpc_response_with_result_and_error_fails() {
+        let err = parse_response(serde_json::json!({
+            "jsonrpc" : "2.0",
+            "result" : "result_data",
+            "error" : { "code" : -32700, "message" : "Failed to parse response"},
+            "id" : "request_id"
+        }))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("both `result` and `error`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_rpc_response_without_result_or_error_fails() {
+        let err = parse_response(serde_json::json!({
+            "jsonrpc" : "2.0",
+            "id" : "request_id"
+        }))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("neither `result` nor `error`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_rpc_response_with_wrong_version_fails() {
+        let err = parse_response(serde_json::json!({
+            "jsonrpc" : "1.0",
+            "result" : "result_data",
+            "id" : "request_id"
+        }))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("expected jsonrpc \"2.0\""),
+            "{}",
+            err
+        );
+
+        let err = parse_response(serde_json::json!({
+            "result" : "result_data",
+            "id" : "request_id"
+        }))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("missing field `jsonrpc`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_rpc_response_error_with_null_code_fails() {
+        let err = parse_response(serde_json::json!({
+            "jsonrpc" : "2.0",
+            "error" : { "code" : null, "message" : "Failed to parse response"},
+            "id" : "request_id"
+        }))
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid error"), "{}", err);
+    }
+
+    #[test]
+    fn parse_rpc_response_with_null_result() {
+        let rpc_method = JsonRpcMethod::<NoParams, (), DefaultError>::new("test.return_nothing");
+        let response = rpc_method
+            .parse_json_response_value(serde_json::json!({
+                "jsonrpc" : "2.0",
+                "result" : null,
+                "id" : "request_id"
+            }))
+            .unwrap();
+        assert!(matches!(response, JsonRpcResponse::Ok(_)));
+    }
+
+    #[test]
+    fn serialized_responses_can_be_parsed() {
+        let ok: JsonRpcResponse<String, DefaultError> =
+            JsonRpcResponse::success("abc".into(), "result_data".to_string());
+        let json_str = serde_json::to_string(&ok).unwrap();
+        let parsed: JsonRpcResponse<String, DefaultError> =
+            serde_json::from_str(&json_str).unwrap();
+        assert!(matches!(parsed, JsonRpcResponse::Ok(ok) if ok.result == "result_data"));
+
+        let error: JsonRpcResponse<String, DefaultError> =
+            JsonRpcResponse::error(JsonRpcId::None, ErrorData::method_not_found("test.method"));
+        let json_str = serde_json::to_string(&error).unwrap();
+        let parsed: JsonRpcResponse<String, DefaultError> =
+            serde_json::from_str(&json_str).unwrap();
+        assert!(matches!(parsed, JsonRpcResponse::Error(err) if err.id == JsonRpcId::None));
+    }
+
     #[test]
     fn serialize_json_rpc_id() {
         let id_str = JsonRpcId::String("id_string".to_string());
@@ -433,4 +666,31 @@ mod test {
 
         let _: JsonRpcResponseFailure<DefaultError> = serde_json::from_value(data).unwrap();
     }
+
+    #[test]
+    fn classify_requests_and_responses() {
+        use serde_json::json;
+
+        let kind = |value| JsonRpcMessageKind::of(&value);
+        assert_eq!(
+            kind(json!({"jsonrpc" : "2.0", "id" : "a", "method" : "lsps0.list_protocols"})),
+            JsonRpcMessageKind::Request
+        );
+        assert_eq!(
+            kind(json!({"jsonrpc" : "2.0", "id" : "a", "result" : {"protocols" : [1]}})),
+            JsonRpcMessageKind::Response
+        );
+        let error = json!({"code" : -32601, "message" : "Method not found"});
+        assert_eq!(
+            kind(json!({"jsonrpc" : "2.0", "id" : "a", "error" : error})),
+            JsonRpcMessageKind::Response
+        );
+        // Malformed messages are requests, so the server can reject them
+        assert_eq!(kind(json!({"id" : "a"})), JsonRpcMessageKind::Request);
+        assert_eq!(kind(json!([1, 2])), JsonRpcMessageKind::Request);
+        assert_eq!(
+            kind(json!({"id" : "a", "method" : "a.b", "result" : null})),
+            JsonRpcMessageKind::Request
+        );
+    }
 }
@@ -255,19 +255,8 @@ where
     E: Serialize,
 {
     fn erase(self) -> Result<JsonRpcErrorDataErased, serde_json::Error> {
-        let error_data = if let Some(error) = &self.data {
-            Some(serde_json::to_vec(error)?)
-        } else {
-            None
-        };
-
-        let x = JsonRpcErrorDataErased {
-            code: self.code,
-            data: error_data,
-            message: self.message,
-        };
-
-        Ok(x)
+        let data = self.data.as_ref().map(serde_json::to_vec).transpose()?;
+        Ok(JsonRpcErrorDataErased::new(self.code, self.message, data))
     }
 }
 
@@ -364,10 +353,42 @@ mod test {
         let result: Result<JsonRpcResponseErased, serde_json::Error> =
             rpc_method_erased.parse_json_response_value(json_value);
         assert!(result.is_err());
+        assert!(format!("{:?}", result).contains("jsonrpc"));
+    }
+
+    #[test]
+    fn parse_malformed_responses_from_method_erased_fails() {
+        let rpc_method = JsonRpcMethod::<TestRequestStruct, TestResponseStruct, DefaultError>::new(
+            "test.method",
+        );
+        let rpc_method_erased = rpc_method.erase_box();
 
-        // TODO: improve the error-message here
-        // It currently gives a vague error-message about not matching one of the enum scenarios in JsonRpcResponse
-        // It should at least mention that the field jsonrpc is missing
-        //assert!(format!("{:?}", result).contains("jsonrpc"));
+        let malformed = [
+            serde_json::json!({
+                "jsonrpc" : "2.0",
+                "id" : "abcdef",
+                "result" : {"response" : "content"},
+                "error" : {"code" : -32600, "message" : "Invalid request"}
+            }),
+            serde_json::json!({
+                "jsonrpc" : "2.0",
+                "id" : "abcdef",
+            }),
+            serde_json::json!({
+                "jsonrpc" : "1.0",
+                "id" : "abcdef",
+                "result" : {"response" : "content"}
+            }),
+            serde_json::json!({
+                "jsonrpc" : "2.0",
+                "id" : "abcdef",
+                "error" : {"code" : null, "message" : "Invalid request"}
+            }),
+        ];
+
+        for json_value in malformed {
+            let result = rpc_method_erased.parse_json_response_value(json_value.clone());
+            assert!(result.is_err(), "Accepted {}", json_value);
+        }
     }
 }
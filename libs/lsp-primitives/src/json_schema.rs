@@ -0,0 +1,476 @@
+//! JSON Schema's for all LSPS-methods
+//!
+//! Most types derive their schema. The types in [`crate::lsps0::common_schemas`]
+//! have hand-written serde-implementations. The schema's for these types are
+//! implemented manually in this module and must be kept in sync with serde.
+//!
+//! Use `cargo run -p lsp-primitives --features schemars --bin export-schemas <dir>`
+//! to write a schema for every method to `<dir>`.
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use jsonschema::JSONSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{
+    InstanceType, Metadata, ObjectValidation, RootSchema, Schema, SchemaObject, StringValidation,
+};
+use schemars::{schema_for, JsonSchema};
+use serde_json::Value;
+
+use crate::json_rpc::{DefaultError, JsonRpcMethod, NoParams};
+use crate::lsps0::common_schemas::{
+    IsoDatetime, MsatAmount, OnchainAddress, Outpoint, PublicKey, SatAmount, ShortChannelId,
+    TransactionId,
+};
+use crate::lsps1::schema::OrderId;
+use crate::lsps2::schema::{
+    Lsps2BuyRequest, Lsps2BuyResponse, Lsps2GetInfoRequest, Lsps2GetInfoResponse,
+    Lsps2GetVersionsResponse,
+};
+use crate::methods::JsonRpcMethodEnum;
+
+/// A string that must match `pattern`
+pub(crate) fn string_schema(pattern: Option<&str>, description: &str) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: pattern.map(String::from),
+            ..Default::default()
+        })),
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+macro_rules! impl_string_schema {
+    ($type:ty, $name:literal, $pattern:expr, $description:literal) => {
+        impl JsonSchema for $type {
+            fn schema_name() -> String {
+                String::from($name)
+            }
+
+            fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+                string_schema($pattern, $description)
+            }
+        }
+    };
+}
+
+impl_string_schema!(
+    SatAmount,
+    "SatAmount",
+    Some("^[0-9]+$"),
+    "An amount in satoshi encoded as a string"
+);
+impl_string_schema!(
+    MsatAmount,
+    "MsatAmount",
+    Some("^[0-9]+$"),
+    "An amount in millisatoshi encoded as a string"
+);
+impl_string_schema!(
+    IsoDatetime,
+    "IsoDatetime",
+    Some(r"^[0-9]{4}-[0-9]{2}-[0-9]{2}T[0-9]{2}:[0-9]{2}:[0-9]{2}\.[0-9]{3}Z$"),
+    "A UTC datetime in the format YYYY-MM-DDThh:mm:ss.uuuZ"
+);
+impl_string_schema!(
+    PublicKey,
+    "PublicKey",
+    Some("^0[23][0-9a-fA-F]{64}$"),
+    "A hex-encoded compressed public key"
+);
+impl_string_schema!(
+    ShortChannelId,
+    "ShortChannelId",
+    Some("^[0-9]+x[0-9]+x[0-9]+$"),
+    "A short channel id in the format <block>x<txindex>x<outnum>"
+);
+impl_string_schema!(
+    TransactionId,
+    "TransactionId",
+    Some("^[0-9a-fA-F]{64}$"),
+    "A hex-encoded transaction id"
+);
+impl_string_schema!(
+    Outpoint,
+    "Outpoint",
+    Some("^[0-9a-fA-F]{64}:[0-9]+$"),
+    "A transaction output in the format <txid>:<outnum>"
+);
+impl_string_schema!(OnchainAddress, "OnchainAddress", None, "A bitcoin address");
+impl_string_schema!(
+    OrderId,
+    "OrderId",
+    Some("^[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$"),
+    "A lowercase uuid with hyphens"
+);
+
+impl JsonSchema for NoParams {
+    fn schema_name() -> String {
+        String::from("NoParams")
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            object: Some(Box::new(ObjectValidation {
+                additional_properties: Some(Box::new(Schema::Bool(false))),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// The params and result of an LSPS-method
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct MethodSchema<I, O> {
+    params: I,
+    result: O,
+}
+
+/// Creates a schema that describes the `params` and `result` of `method`
+pub fn method_schema<I, O, E>(method: &JsonRpcMethod<'_, I, O, E>) -> RootSchema
+where
+    I: JsonSchema,
+    O: JsonSchema,
+{
+    let mut schema = schema_for!(MethodSchema<I, O>);
+    schema.schema.metadata().title = Some(method.name().to_string());
+    schema
+}
+
+// LSPS2 isn't part of `crate::methods` yet
+const LSPS2_GET_VERSIONS: JsonRpcMethod<NoParams, Lsps2GetVersionsResponse, DefaultError> =
+    JsonRpcMethod::new("lsps2.get_versions");
+const LSPS2_GET_INFO: JsonRpcMethod<Lsps2GetInfoRequest, Lsps2GetInfoResponse, DefaultError> =
+    JsonRpcMethod::new("lsps2.get_info");
+const LSPS2_BUY: JsonRpcMethod<Lsps2BuyRequest, Lsps2BuyResponse, DefaultError> =
+    JsonRpcMethod::new("lsps2.buy");
+
+/// Returns the name and schema of every LSPS-method
+pub fn method_schemas() -> Vec<(&'static str, RootSchema)> {
+    let mut schemas: Vec<_> = JsonRpcMethodEnum::all_methods()
+        .iter()
+        .map(|method| (method.name(), method.schema()))
+        .collect();
+    schemas.extend([
+        (
+            LSPS2_GET_VERSIONS.name(),
+            method_schema(&LSPS2_GET_VERSIONS),
+        ),
+        (LSPS2_GET_INFO.name(), method_schema(&LSPS2_GET_INFO)),
+        (LSPS2_BUY.name(), method_schema(&LSPS2_BUY)),
+    ]);
+    schemas
+}
+
+/// Checks that `params` and `result` match the schema of `method`
+///
+/// The error lists every violation that was found.
+pub fn validate(method: &str, params: &Value, result: &Value) -> Result<()> {
+    let (_, schema) = method_schemas()
+        .into_iter()
+        .find(|(name, _)| *name == method)
+        .with_context(|| format!("Unknown method {}", method))?;
+    let schema = serde_json::to_value(schema)?;
+    let compiled = JSONSchema::compile(&schema)
+        .map_err(|e| anyhow!("Invalid schema for {}: {}", method, e))?;
+
+    let instance = serde_json::json!({"params" : params, "result" : result});
+    let result = compiled.validate(&instance);
+    if let Err(errors) = result {
+        let errors: Vec<String> = errors
+            .map(|e| format!("{}: {}", e.instance_path, e))
+            .collect();
+        return Err(anyhow!("Invalid {}: {}", method, errors.join(", ")));
+    }
+    Ok(())
+}
+
+/// Writes `<method>.json` to `dir` for every LSPS-method
+///
+/// Returns the paths of all files that were written
+pub fn write_schemas(dir: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+
+    let mut paths = Vec::new();
+    for (name, schema) in method_schemas() {
+        let path = dir.join(format!("{}.json", name));
+        let json = serde_json::to_string_pretty(&schema)?;
+        std::fs::write(&path, json)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn schema_for_method(name: &str) -> Value {
+        let (_, schema) = method_schemas()
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .expect("Method exists");
+        serde_json::to_value(schema).unwrap()
+    }
+
+    fn is_valid(method: &str, params: Value, result: Value) -> bool {
+        validate(method, &params, &result).is_ok()
+    }
+
+    fn lsps1_options() -> Value {
+        json!({
+            "min_required_channel_confirmations": 0,
+            "min_funding_confirms_within_blocks": 6,
+            "min_onchain_payment_confirmations": null,
+            "supports_zero_channel_reserve": true,
+            "min_onchain_payment_size_sat": null,
+            "max_channel_expiry_blocks": 20160,
+            "min_initial_client_balance_sat": "20000",
+            "max_initial_client_balance_sat": "100000000",
+            "min_initial_lsp_balance_sat": "0",
+            "max_initial_lsp_balance_sat": "100000000",
+            "min_channel_balance_sat": "50000",
+            "max_channel_balance_sat": "100000000"
+        })
+    }
+
+    fn lsps1_create_order_request() -> Value {
+        json!({
+            "lsp_balance_sat": "5000000",
+            "client_balance_sat": "2000000",
+            "required_channel_confirmations": 0,
+            "funding_confirms_within_blocks": 6,
+            "channel_expiry_blocks": 144,
+            "token": "",
+            "refund_onchain_address": "bc1qvmsy0f3yyes6z9jvddk8xqwznndmdwapvrc0xrmhd3vqj5rhdrrq6hz49h",
+            "announce_channel": true
+        })
+    }
+
+    fn lsps1_order_response() -> Value {
+        json!({
+            "order_id": "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+            "lsp_balance_sat": "5000000",
+            "client_balance_sat": "2000000",
+            "required_channel_confirmations": 0,
+            "funding_confirms_within_blocks": 1,
+            "channel_expiry_blocks": 12,
+            "token": "",
+            "created_at": "2012-04-23T18:25:43.511Z",
+            "expires_at": "2015-01-25T19:29:44.612Z",
+            "announce_channel": true,
+            "order_state": "COMPLETED",
+            "payment": {
+                "state": "PAID",
+                "fee_total_sat": "8888",
+                "order_total_sat": "2008888",
+                "bolt11_invoice": "lnbc252u1p3aht9ysp580g4633gd2x9lc5al0wd8wx0mpn9748jeyz46kqjrpxn52uhfpjqpp5qgf67tcqmuqehzgjm8mzya90h73deafvr4m5705l5u5l4r05l8cqdpud3h8ymm4w3jhytnpwpczqmt0de6xsmre9cs9w6t5dpcqzpuxqr23ssp5pe7kmh6kpd5e2mz5v6s6w9k4rkhq7zh9r0mtqn0wqp4xqpn5u8cq",
+                "onchain_address": "bc1p5uvtaxzkjwvey2tfy49k5vtqfpjmrgm09cvs88ezyy8h2zv7jhas9tu4yr",
+                "min_onchain_payment_confirmations": 0,
+                "min_fee_for_0conf": 253,
+                "onchain_payment": null
+            },
+            "channel": {
+                "funded_at": "2012-04-23T18:25:43.511Z",
+                "funding_outpoint": "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:0",
+                "expires_at": "2012-04-23T18:25:43.511Z"
+            }
+        })
+    }
+
+    fn lsps2_opening_fee_params() -> Value {
+        json!({
+            "min_fee_msat": "546000",
+            "proportional": 1200,
+            "valid_until": "2023-02-23T08:47:30.511Z",
+            "min_lifetime": 1008,
+            "max_client_to_self_delay": 2016,
+            "promise": "abcdefghijklmnopqrstuvwxyz"
+        })
+    }
+
+    #[test]
+    fn schema_for_every_method() {
+        let names: Vec<&str> = method_schemas().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(
+            names,
+            vec![
+                "lsps0.list_protocols",
+                "lsps1.get_info",
+                "lsps1.create_order",
+                "lsps1.get_order",
+                "lsps2.get_versions",
+                "lsps2.get_info",
+                "lsps2.buy"
+            ]
+        );
+
+        for name in names {
+            let schema = schema_for_method(name);
+            assert_eq!(schema["title"], name);
+            JSONSchema::compile(&schema).expect("Schema is valid");
+        }
+    }
+
+    #[test]
+    fn validate_lsps0_examples() {
+        assert!(is_valid(
+            "lsps0.list_protocols",
+            json!({}),
+            json!({"protocols" : [1, 2]})
+        ));
+        assert!(!is_valid(
+            "lsps0.list_protocols",
+            json!({"param_a" : "a"}),
+            json!({"protocols" : [1, 2]})
+        ));
+    }
+
+    #[test]
+    fn validate_lsps1_examples() {
+        assert!(is_valid(
+            "lsps1.get_info",
+            json!({}),
+            json!({"options" : lsps1_options()})
+        ));
+        assert!(is_valid(
+            "lsps1.create_order",
+            lsps1_create_order_request(),
+            lsps1_order_response()
+        ));
+        assert!(is_valid(
+            "lsps1.get_order",
+            json!({"order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c"}),
+            lsps1_order_response()
+        ));
+    }
+
+    #[test]
+    fn validate_lsps2_examples() {
+        assert!(is_valid(
+            "lsps2.get_info",
+            json!({"version" : 1, "token" : "SECRET"}),
+            json!({
+                "opening_fee_params_menu" : [lsps2_opening_fee_params()],
+                "min_payment_size_msat" : "1000",
+                "max_payment_size_msat" : "1000000"
+            })
+        ));
+        assert!(is_valid(
+            "lsps2.buy",
+            json!({
+                "version" : "1",
+                "opening_fee_params" : lsps2_opening_fee_params(),
+                "payment_size_msat" : "42000"
+            }),
+            json!({
+                "jit_channel_scid" : "29451x4815x1",
+                "lsp_cltv_expiry_delta" : 144,
+                "client_trusts_lsp" : false
+            })
+        ));
+    }
+
+    #[test]
+    fn schema_matches_custom_serde_impls() {
+        // Amounts are encoded as strings
+        let mut request = lsps1_create_order_request();
+        request["lsp_balance_sat"] = json!(5000000);
+        assert!(!is_valid(
+            "lsps1.create_order",
+            request,
+            lsps1_order_response()
+        ));
+
+        // Datetimes require milliseconds and UTC
+        let mut response = lsps1_order_response();
+        response["created_at"] = json!("2012-04-23T18:25:43Z");
+        assert!(!is_valid(
+            "lsps1.get_order",
+            json!({"order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c"}),
+            response
+        ));
+
+        // Outpoints are <txid>:<outnum>
+        let mut response = lsps1_order_response();
+        response["channel"]["funding_outpoint"] = json!("0301e0480b374b32");
+        assert!(!is_valid(
+            "lsps1.get_order",
+            json!({"order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c"}),
+            response
+        ));
+
+        // Short channel ids use the <block>x<txindex>x<outnum>-format
+        assert!(!is_valid(
+            "lsps2.buy",
+            json!({
+                "version" : "1",
+                "opening_fee_params" : lsps2_opening_fee_params(),
+                "payment_size_msat" : "42000"
+            }),
+            json!({"jit_channel_scid" : "29451:4815:1", "lsp_cltv_expiry_delta" : 144})
+        ));
+
+        // Opening fee params reject unknown fields
+        let mut fee_params = lsps2_opening_fee_params();
+        fee_params["extra_field"] = json!("a");
+        assert!(!is_valid(
+            "lsps2.buy",
+            json!({
+                "version" : "1",
+                "opening_fee_params" : fee_params,
+                "payment_size_msat" : "42000"
+            }),
+            json!({"jit_channel_scid" : "29451x4815x1", "lsp_cltv_expiry_delta" : 144})
+        ));
+    }
+
+    #[test]
+    fn validate_reports_violations() {
+        let error = validate(
+            "lsps1.get_order",
+            &json!({"order_id" : 42}),
+            &lsps1_order_response(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("/params/order_id"));
+
+        validate("lsps9.unknown", &json!({}), &json!({})).unwrap_err();
+    }
+
+    #[test]
+    fn public_key_schema() {
+        let schema = serde_json::to_value(schema_for!(PublicKey)).unwrap();
+        let compiled = JSONSchema::compile(&schema).unwrap();
+
+        let pubkey = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        assert!(compiled.is_valid(&json!(pubkey)));
+        assert!(!compiled.is_valid(&json!(&pubkey[2..])));
+    }
+
+    #[test]
+    fn write_schema_files() {
+        let dir = std::env::temp_dir().join(format!("lsps-schemas-{}", std::process::id()));
+        let paths = write_schemas(&dir).unwrap();
+        assert_eq!(paths.len(), method_schemas().len());
+
+        let content = std::fs::read_to_string(dir.join("lsps1.create_order.json")).unwrap();
+        let schema: Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(schema["title"], "lsps1.create_order");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,17 @@
+pub mod error;
+pub mod hex;
 pub mod json_rpc;
 pub mod json_rpc_erased;
+#[cfg(feature = "schemars")]
+pub mod json_schema;
 pub mod lsps0;
 pub mod lsps1;
 pub mod lsps2;
 
 pub mod methods;
 pub mod no_params;
+pub mod redact;
+pub mod sat_amount_json;
 
+pub use error::Error;
 pub use secp256k1;
@@ -0,0 +1,137 @@
+//! Inclusive ranges that are advertised as a `min_*` and `max_*` pair
+//!
+//! LSPS-options often describe a range using two properties. E.g:
+//! `min_channel_balance_sat` and `max_channel_balance_sat`. A
+//! [`BoundedRange`] keeps both bounds together with the name they share,
+//! so every check and error message is phrased the same way.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Explains which bound of a range was violated
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundedRangeError {
+    /// The name of the violated option. E.g: `min_channel_balance_sat`
+    pub property: String,
+    pub message: String,
+}
+
+impl fmt::Display for BoundedRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for BoundedRangeError {}
+
+/// An inclusive range where `min <= max`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoundedRange<T> {
+    name: &'static str,
+    min: T,
+    max: T,
+}
+
+impl<T> BoundedRange<T>
+where
+    T: PartialOrd + Copy + fmt::Display,
+{
+    /// Creates the range of the options `min_{name}` and `max_{name}`
+    pub fn new(name: &'static str, min: T, max: T) -> Result<Self, BoundedRangeError> {
+        if min > max {
+            return Err(BoundedRangeError {
+                property: format!("min_{}", name),
+                message: format!(
+                    "min_{name} ({min}) should be less than or equal to max_{name} ({max})"
+                ),
+            });
+        }
+        Ok(Self { name, min, max })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn min(&self) -> T {
+        self.min
+    }
+
+    pub fn max(&self) -> T {
+        self.max
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.min <= *value && *value <= self.max
+    }
+
+    /// Returns the value in the range that is closest to `value`
+    pub fn clamp(&self, value: T) -> T {
+        if value < self.min {
+            self.min
+        } else if value > self.max {
+            self.max
+        } else {
+            value
+        }
+    }
+
+    /// Checks that the requested `field` lies within the range
+    pub fn check(&self, field: &str, value: T) -> Result<(), BoundedRangeError> {
+        if value < self.min {
+            return Err(BoundedRangeError {
+                property: format!("min_{}", self.name),
+                message: format!(
+                    "You've requested a channel with {}={} but the LSP-server requires at least {}",
+                    field, value, self.min
+                ),
+            });
+        }
+        if value > self.max {
+            return Err(BoundedRangeError {
+                property: format!("max_{}", self.name),
+                message: format!(
+                    "You've requested a channel with {}={} but the LSP-server doesn't allow this value to exceed {}",
+                    field, value, self.max
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn min_must_not_exceed_max() {
+        let err = BoundedRange::new("channel_balance_sat", 2, 1).unwrap_err();
+        assert_eq!(err.property, "min_channel_balance_sat");
+        assert_eq!(
+            err.to_string(),
+            "min_channel_balance_sat (2) should be less than or equal to max_channel_balance_sat (1)"
+        );
+
+        // A range can contain a single value
+        let range = BoundedRange::new("channel_balance_sat", 1, 1).unwrap();
+        assert!(range.contains(&1));
+    }
+
+    #[test]
+    fn check_names_the_violated_bound() {
+        let range = BoundedRange::new("channel_expiry_blocks", 10, 20).unwrap();
+        assert!(range.contains(&10));
+        assert!(range.contains(&20));
+        range.check("channel_expiry_blocks", 15).unwrap();
+
+        let err = range.check("channel_expiry_blocks", 9).unwrap_err();
+        assert_eq!(err.property, "min_channel_expiry_blocks");
+        let err = range.check("channel_expiry_blocks", 21).unwrap_err();
+        assert_eq!(err.property, "max_channel_expiry_blocks");
+
+        assert_eq!(range.clamp(5), 10);
+        assert_eq!(range.clamp(15), 15);
+        assert_eq!(range.clamp(25), 20);
+    }
+}
@@ -1,10 +1,12 @@
-use anyhow::{Context, Result};
+use crate::error::{Error, Result};
+use crate::lsps0::schema::{ListprotocolsResponse, LSPS0_LIST_PROTOCOLS_FIELDS};
 
-use crate::lsps0::schema::ListprotocolsResponse;
+const BUILDER: &str = "ListprotocolsResponseBuilder";
 
 #[derive(Debug, Default)]
 pub struct ListprotocolsResponseBuilder {
     protocols: Option<Vec<u32>>,
+    extra_fields: serde_json::Map<String, serde_json::Value>,
 }
 
 impl ListprotocolsResponseBuilder {
@@ -17,11 +19,82 @@ impl ListprotocolsResponseBuilder {
         self
     }
 
+    /// Fields that are added to the response next to the fields defined in the spec
+    pub fn extra_fields(
+        mut self,
+        extra_fields: serde_json::Map<String, serde_json::Value>,
+    ) -> Self {
+        self.extra_fields = extra_fields;
+        self
+    }
+
     pub fn build(self) -> Result<ListprotocolsResponse> {
-        let protocols = self.protocols.context("Missing field 'protocols'")?;
+        let protocols = self
+            .protocols
+            .ok_or(Error::missing_field(BUILDER, "protocols"))?;
 
-        let result = ListprotocolsResponse { protocols };
+        if let Some(field) = LSPS0_LIST_PROTOCOLS_FIELDS
+            .iter()
+            .find(|field| self.extra_fields.contains_key(**field))
+        {
+            return Err(Error::invalid_value(
+                *field,
+                "Extra field would overwrite a field defined in the spec",
+            ));
+        }
+
+        let result = ListprotocolsResponse {
+            protocols,
+            extra_fields: self.extra_fields,
+        };
 
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_response_with_extra_fields() {
+        let response = ListprotocolsResponseBuilder::new()
+            .protocols(vec![0, 1])
+            .build()
+            .unwrap();
+        assert!(response.extra_fields.is_empty());
+
+        let mut extra_fields = serde_json::Map::new();
+        extra_fields.insert("name".to_string(), "Example LSP".into());
+        let response = ListprotocolsResponseBuilder::new()
+            .protocols(vec![0, 1])
+            .extra_fields(extra_fields.clone())
+            .build()
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            serde_json::json!({ "protocols" : [0, 1], "name" : "Example LSP" })
+        );
+
+        extra_fields.insert("protocols".to_string(), serde_json::json!([2]));
+        let err = ListprotocolsResponseBuilder::new()
+            .protocols(vec![0, 1])
+            .extra_fields(extra_fields)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("protocols"));
+        assert!(matches!(err, Error::InvalidValue { field, .. } if field == "protocols"));
+    }
+
+    #[test]
+    fn missing_protocols_are_reported() {
+        let err = ListprotocolsResponseBuilder::new().build().unwrap_err();
+        assert_eq!(
+            err,
+            Error::MissingField {
+                builder: "ListprotocolsResponseBuilder",
+                field: "protocols"
+            }
+        );
+    }
+}
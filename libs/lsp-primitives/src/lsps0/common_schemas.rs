@@ -1,7 +1,6 @@
 use core::str::FromStr;
 use std::fmt::{Display, Formatter};
 
-use anyhow::{anyhow, Context, Result};
 use bitcoin::address::Address;
 pub use bitcoin::address::{NetworkChecked, NetworkUnchecked, NetworkValidation};
 pub use bitcoin::network::Network;
@@ -14,6 +13,8 @@ use time::format_description::FormatItem;
 use time::macros::{format_description, offset};
 use time::{OffsetDateTime, PrimitiveDateTime};
 
+use crate::error::{Error, Result};
+use crate::hex;
 use crate::secp256k1::PublicKey as _PublicKey;
 
 #[derive(Serialize, Clone, Debug)]
@@ -41,10 +42,13 @@ pub trait NetworkCheckable {
 impl NetworkCheckable for OnchainAddress {
     fn require_network(&self, network: &bitcoin::Network) -> Result<()> {
         if network != self.address.network() {
-            Err(anyhow!(
-                "Network mismatch: Expected {} but got {}",
-                network,
-                self.address.network()
+            Err(Error::invalid_value(
+                "network",
+                format!(
+                    "Network mismatch: Expected {} but got {}",
+                    network,
+                    self.address.network()
+                ),
             ))
         } else {
             Ok(())
@@ -62,10 +66,11 @@ impl NetworkCheckable for Option<OnchainAddress> {
 }
 
 impl FromStr for OnchainAddress {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let address = Address::<NetworkUnchecked>::from_str(s)?;
+        let address =
+            Address::<NetworkUnchecked>::from_str(s).map_err(|e| Error::parse("address", e))?;
         Ok(Self {
             address: address.assume_checked(),
         })
@@ -78,6 +83,92 @@ impl Display for OnchainAddress {
     }
 }
 
+impl OnchainAddress {
+    /// The type of the output script the address pays to
+    pub fn address_type(&self) -> AddressType {
+        match self.address.address_type() {
+            Some(bitcoin::AddressType::P2pkh) => AddressType::P2pkh,
+            Some(bitcoin::AddressType::P2sh) => AddressType::P2sh,
+            Some(bitcoin::AddressType::P2wpkh) => AddressType::P2wpkh,
+            Some(bitcoin::AddressType::P2wsh) => AddressType::P2wsh,
+            Some(bitcoin::AddressType::P2tr) => AddressType::P2tr,
+            _ => AddressType::Unknown,
+        }
+    }
+}
+
+/// The type of the output script of an [`OnchainAddress`]
+///
+/// `Unknown` covers future segwit versions and non-standard programs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AddressType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Unknown,
+}
+
+impl AddressType {
+    /// The types of addresses bitcoind relays transactions to
+    pub const STANDARD: [AddressType; 5] = [
+        AddressType::P2pkh,
+        AddressType::P2sh,
+        AddressType::P2wpkh,
+        AddressType::P2wsh,
+        AddressType::P2tr,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::P2pkh => "p2pkh",
+            Self::P2sh => "p2sh",
+            Self::P2wpkh => "p2wpkh",
+            Self::P2wsh => "p2wsh",
+            Self::P2tr => "p2tr",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// The weight of a transaction output that pays to this type
+    ///
+    /// An output consists of an 8-byte amount, the length of the script
+    /// and the script. Returns `None` if the size of the script is unknown.
+    pub fn output_weight(&self) -> Option<u64> {
+        let script_len = match self {
+            Self::P2pkh => 25,
+            Self::P2sh => 23,
+            Self::P2wpkh => 22,
+            Self::P2wsh | Self::P2tr => 34,
+            Self::Unknown => return None,
+        };
+        Some((8 + 1 + script_len) * 4)
+    }
+}
+
+impl Display for AddressType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AddressType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::STANDARD
+            .into_iter()
+            .find(|address_type| address_type.as_str() == s)
+            .ok_or_else(|| {
+                Error::parse(
+                    "address_type",
+                    format!("'{}' is not one of p2pkh, p2sh, p2wpkh, p2wsh or p2tr", s),
+                )
+            })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PublicKey(_PublicKey);
 
@@ -122,12 +213,7 @@ impl<'de> Deserialize<'de> for PublicKey {
             where
                 E: serde::de::Error,
             {
-                let data =
-                    hex::decode(v).map_err(|_| serde::de::Error::custom("Expected valid hex"))?;
-                let pubkey = _PublicKey::from_slice(&data).map_err(|err| {
-                    serde::de::Error::custom(format!("Invalid public-key: {}", err))
-                })?;
-                Ok(pubkey.into())
+                PublicKey::from_hex(v).map_err(|err| serde::de::Error::custom(format!("{:#}", err)))
             }
         }
 
@@ -136,14 +222,43 @@ impl<'de> Deserialize<'de> for PublicKey {
     }
 }
 
+/// The length of a compressed public key in bytes
+pub const PUBLIC_KEY_SIZE: usize = 33;
+
 impl PublicKey {
+    /// Parses a hex-encoded compressed public key
+    ///
+    /// The hex-string is case-insensitive. Note, that [`PublicKey::to_hex`]
+    /// always returns the canonical lower-case form.
     pub fn from_hex(hex: &str) -> Result<Self> {
-        let data = hex::decode(hex).context("Invalid hex")?;
-        let publickey =
-            _PublicKey::from_slice(&data).map_err(|m| anyhow!("Error parsing PublicKey: {}", m))?;
+        let data = hex::decode("public-key", hex)?;
+        Self::from_slice(&data)
+    }
+
+    /// Parses a compressed public key
+    ///
+    /// Node ids are always compressed. Uncompressed keys are rejected
+    /// because they would serialize to a different string.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        if data.len() != PUBLIC_KEY_SIZE {
+            return Err(Error::parse(
+                "public-key",
+                format!(
+                    "Expected a compressed key of {} bytes but got {} bytes",
+                    PUBLIC_KEY_SIZE,
+                    data.len()
+                ),
+            ));
+        }
+        let publickey = _PublicKey::from_slice(data).map_err(|e| Error::parse("public-key", e))?;
         Ok(PublicKey(publickey))
     }
 
+    /// The compressed serialization of the key
+    pub fn serialize(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.0.serialize()
+    }
+
     pub fn to_hex(&self) -> String {
         let data = self.0.serialize();
         hex::encode(data)
@@ -191,8 +306,8 @@ impl IsoDatetime {
     }
 
     pub fn from_unix_timestamp(value: i64) -> Result<Self> {
-        let offset =
-            OffsetDateTime::from_unix_timestamp(value).context("Failed to construct datetime")?;
+        let offset = OffsetDateTime::from_unix_timestamp(value)
+            .map_err(|e| Error::invalid_value("unix_timestamp", e))?;
         Ok(Self::from_offset_date_time(offset))
     }
 
@@ -323,6 +438,30 @@ impl SatAmount {
         let sat_value = self.0.checked_add(other.0)?;
         Some(SatAmount::new(sat_value))
     }
+
+    pub fn checked_mul(&self, factor: u64) -> Option<Self> {
+        let sat_value = self.0.checked_mul(factor)?;
+        Some(SatAmount::new(sat_value))
+    }
+
+    pub fn checked_div(&self, divisor: u64) -> Option<Self> {
+        let sat_value = self.0.checked_div(divisor)?;
+        Some(SatAmount::new(sat_value))
+    }
+
+    /// Converts to sat and rounds any fraction of a sat up.
+    ///
+    /// Use this when the result is an amount the other party has to pay us.
+    pub fn try_from_msat_round_up(amount: MsatAmount) -> Option<Self> {
+        Some(SatAmount::new(amount.msat_value().div_ceil(1000)))
+    }
+
+    /// Converts to sat and drops any fraction of a sat.
+    ///
+    /// Use this when the result is an amount we have to pay.
+    pub fn try_from_msat_round_down(amount: MsatAmount) -> Option<Self> {
+        Some(SatAmount::new(amount.msat_value() / 1000))
+    }
 }
 
 impl MsatAmount {
@@ -330,6 +469,22 @@ impl MsatAmount {
         let sat_value = self.0.checked_add(other.0)?;
         Some(MsatAmount::new(sat_value))
     }
+
+    pub fn checked_mul(&self, factor: u64) -> Option<Self> {
+        let msat_value = self.0.checked_mul(factor)?;
+        Some(MsatAmount::new(msat_value))
+    }
+
+    pub fn checked_div(&self, divisor: u64) -> Option<Self> {
+        let msat_value = self.0.checked_div(divisor)?;
+        Some(MsatAmount::new(msat_value))
+    }
+
+    /// Returns `None` if the amount doesn't fit in a `u64` of msat
+    pub fn from_sat(amount: SatAmount) -> Option<Self> {
+        let msat_value = amount.sat_value().checked_mul(1000)?;
+        Some(MsatAmount::new(msat_value))
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -356,14 +511,12 @@ impl<'de> Deserialize<'de> for ShortChannelId {
 }
 
 impl FromStr for ShortChannelId {
-    type Err = anyhow::Error;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Result<Vec<u64>, _> = s.split('x').map(|p| p.parse()).collect();
-        let parts = parts.with_context(|| format!("Malformed short_channel_id: {}", s))?;
+        let parts = parts.map_err(|e| Error::parse("short_channel_id", format!("{}: {}", s, e)))?;
         if parts.len() != 3 {
-            return Err(anyhow!(
-                "Malformed short_channel_id: element count mismatch"
-            ));
+            return Err(Error::parse("short_channel_id", "element count mismatch"));
         }
 
         Ok(ShortChannelId(
@@ -389,6 +542,7 @@ impl ShortChannelId {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(transparent)]
 pub struct FeeRate {
     fee_rate: u64,
@@ -409,7 +563,12 @@ pub struct TransactionId([u8; 32]);
 
 impl TransactionId {
     pub fn from_slice(data: &[u8]) -> Result<Self> {
-        let txid: [u8; 32] = data.try_into().context("Invalid transaction Id")?;
+        let txid: [u8; 32] = data.try_into().map_err(|_| {
+            Error::parse(
+                "txid",
+                format!("Expected 32 bytes but got {} bytes", data.len()),
+            )
+        })?;
         Ok(Self(txid))
     }
 }
@@ -452,10 +611,10 @@ impl<'de> Deserialize<'de> for TransactionId {
 }
 
 impl FromStr for TransactionId {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(txid: &str) -> Result<Self> {
-        let txid = hex::decode(txid).context("txid is not valid hex")?;
+        let txid = hex::decode("txid", txid)?;
         Self::from_slice(&txid)
     }
 }
@@ -480,20 +639,21 @@ impl Display for Outpoint {
 }
 
 impl FromStr for Outpoint {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(v: &str) -> Result<Self, Self::Err> {
         let split: Vec<&str> = v.split(":").collect();
 
         if split.len() != 2 {
-            return Err(anyhow!("Invalid outpoint. Should be a txid and outnum. e.g: '4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0'"));
+            return Err(Error::parse("outpoint", "Should be a txid and outnum. e.g: '4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0'"));
         }
 
         let txid = split[0];
         let outnum = split[1];
 
-        let txid = TransactionId::from_str(txid).context("Invalid outpoint (txid)")?;
-        let outnum = u32::from_str(outnum).context("Invalid outpoint (outnum)")?;
+        let txid = TransactionId::from_str(txid).map_err(|e| Error::parse("outpoint", e))?;
+        let outnum = u32::from_str(outnum)
+            .map_err(|e| Error::parse("outpoint", format!("Invalid outnum: {}", e)))?;
 
         Ok(Self { txid, outnum })
     }
@@ -669,6 +829,73 @@ mod test {
         );
     }
 
+    const PUBKEY_HEX: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const UNCOMPRESSED_PUBKEY_HEX: &str = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    #[test]
+    fn pubkey_from_hex_is_case_insensitive() {
+        let lower = PublicKey::from_hex(PUBKEY_HEX).unwrap();
+        let upper = PublicKey::from_hex(&PUBKEY_HEX.to_uppercase()).unwrap();
+
+        assert_eq!(lower, upper);
+        assert_eq!(upper.to_hex(), PUBKEY_HEX);
+
+        let json = serde_json::json!(PUBKEY_HEX.to_uppercase());
+        let deserialized: PublicKey = serde_json::from_value(json).unwrap();
+        assert_eq!(serde_json::json!(deserialized), PUBKEY_HEX);
+    }
+
+    #[test]
+    fn pubkey_rejects_uncompressed_key() {
+        // The uncompressed key is valid for libsecp256k1
+        let data = hex::decode("public-key", UNCOMPRESSED_PUBKEY_HEX).unwrap();
+        assert!(_PublicKey::from_slice(&data).is_ok());
+
+        let err = PublicKey::from_hex(UNCOMPRESSED_PUBKEY_HEX).unwrap_err();
+        assert!(err.to_string().contains("compressed"), "{}", err);
+
+        let json = serde_json::json!(UNCOMPRESSED_PUBKEY_HEX);
+        let err = serde_json::from_value::<PublicKey>(json).unwrap_err();
+        assert!(err.to_string().contains("compressed"), "{}", err);
+    }
+
+    #[test]
+    fn hex_call_sites_reject_malformed_input() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert!(TransactionId::from_str(txid).is_ok());
+        for malformed in [
+            format!("0x{}", txid),
+            format!(" {}", txid),
+            format!("{}\n", txid),
+        ] {
+            assert!(
+                TransactionId::from_str(&malformed).is_err(),
+                "{}",
+                malformed
+            );
+        }
+
+        for malformed in [
+            format!("0x{}", PUBKEY_HEX),
+            format!("{} ", PUBKEY_HEX),
+            PUBKEY_HEX[1..].to_string(),
+        ] {
+            assert!(PublicKey::from_hex(&malformed).is_err(), "{}", malformed);
+            let json = serde_json::json!(malformed);
+            assert!(serde_json::from_value::<PublicKey>(json).is_err());
+        }
+    }
+
+    #[test]
+    fn pubkey_serialize_returns_compressed_bytes() {
+        let public_key = PublicKey::from_hex(PUBKEY_HEX).unwrap();
+        assert_eq!(hex::encode(public_key.serialize()), PUBKEY_HEX);
+        assert_eq!(
+            PublicKey::from_slice(&public_key.serialize()).unwrap(),
+            public_key
+        );
+    }
+
     #[test]
     fn deserialize_onchain_address() {
         let regtest_address_json = "\"bcrt1qkm08480v79rzjp7tx2pjrly423ncv85k65nsmu\"";
@@ -690,6 +917,61 @@ mod test {
         // let parsed = serde_json::from_str::<OnchainAddress<NetworkChecked>>(regtest_address_json).unwrap();
     }
 
+    #[test]
+    fn onchain_address_types() {
+        let addresses = [
+            ("mfcHP2WMCVLsVZA8yrovmhMgxNFW9r98xw", AddressType::P2pkh),
+            ("2MsLZ5FqqYpjM1Q1W4X81zMVZTF9gdbhVwd", AddressType::P2sh),
+            (
+                "bcrt1qkm08480v79rzjp7tx2pjrly423ncv85k65nsmu",
+                AddressType::P2wpkh,
+            ),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                AddressType::P2wsh,
+            ),
+            (
+                "bcrt1pyqsjygeyy5nzw2pf9g4jctfw9ucrzv3nxs6nvdec8yark0pa8clsjqmhvl",
+                AddressType::P2tr,
+            ),
+            (
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+                AddressType::P2tr,
+            ),
+        ];
+        for (address, expected) in addresses {
+            let parsed = OnchainAddress::from_str(address).unwrap();
+            assert_eq!(parsed.address_type(), expected, "{}", address);
+            assert_eq!(parsed.to_string(), address);
+        }
+
+        // A segwit version without a standard script
+        let future = OnchainAddress::from_str("bcrt1sw50qt2uwha").unwrap();
+        assert_eq!(future.address_type(), AddressType::Unknown);
+    }
+
+    #[test]
+    fn parse_address_types() {
+        for address_type in AddressType::STANDARD {
+            assert_eq!(
+                AddressType::from_str(address_type.as_str()).unwrap(),
+                address_type
+            );
+        }
+        assert!(AddressType::from_str("unknown").is_err());
+        assert!(AddressType::from_str("P2TR").is_err());
+    }
+
+    #[test]
+    fn output_weight_of_address_types() {
+        let weights: Vec<_> = AddressType::STANDARD
+            .iter()
+            .map(|address_type| address_type.output_weight().unwrap())
+            .collect();
+        assert_eq!(weights, vec![136, 128, 124, 172, 172]);
+        assert_eq!(AddressType::Unknown.output_weight(), None);
+    }
+
     #[test]
     fn serialize_onchain_address() {
         // Generate random key pair.
@@ -747,4 +1029,88 @@ mod test {
         let outpoint_serialized = serde_json::to_value(&outpoint).unwrap();
         assert_eq!(outpoint_serialized, serde_json::json!(outpoint_str));
     }
+
+    #[test]
+    fn parse_errors_name_the_kind_of_value() {
+        let err = Outpoint::from_str("4a5e1e4b").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "outpoint",
+                ..
+            }
+        ));
+
+        let err = Outpoint::from_str("4a5e1e4b:0").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid outpoint: Invalid txid: Expected 32 bytes but got 4 bytes"
+        );
+
+        let err = ShortChannelId::from_str("1x2").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "short_channel_id",
+                ..
+            }
+        ));
+
+        let err = PublicKey::from_hex("not hex").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "public-key",
+                ..
+            }
+        ));
+
+        let err = OnchainAddress::from_str("bc1qnotanaddress").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "address",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn convert_between_sat_and_msat() {
+        let sat = SatAmount::new(21);
+        assert_eq!(MsatAmount::from_sat(sat), Some(MsatAmount::new(21_000)));
+        assert_eq!(MsatAmount::from_sat(SatAmount::new(u64::MAX)), None);
+
+        let exact = MsatAmount::new(21_000);
+        assert_eq!(SatAmount::try_from_msat_round_up(exact), Some(sat));
+        assert_eq!(SatAmount::try_from_msat_round_down(exact), Some(sat));
+
+        let fraction = MsatAmount::new(21_001);
+        assert_eq!(
+            SatAmount::try_from_msat_round_up(fraction),
+            Some(SatAmount::new(22))
+        );
+        assert_eq!(SatAmount::try_from_msat_round_down(fraction), Some(sat));
+
+        let max = MsatAmount::new(u64::MAX);
+        assert_eq!(
+            SatAmount::try_from_msat_round_up(max),
+            Some(SatAmount::new(u64::MAX / 1000 + 1))
+        );
+    }
+
+    #[test]
+    fn checked_arithmetic_on_amounts() {
+        let sat = SatAmount::new(1_000);
+        assert_eq!(sat.checked_mul(3), Some(SatAmount::new(3_000)));
+        assert_eq!(sat.checked_mul(u64::MAX), None);
+        assert_eq!(sat.checked_div(3), Some(SatAmount::new(333)));
+        assert_eq!(sat.checked_div(0), None);
+
+        let msat = MsatAmount::new(1_000);
+        assert_eq!(msat.checked_mul(3), Some(MsatAmount::new(3_000)));
+        assert_eq!(msat.checked_mul(u64::MAX), None);
+        assert_eq!(msat.checked_div(3), Some(MsatAmount::new(333)));
+        assert_eq!(msat.checked_div(0), None);
+    }
 }
@@ -0,0 +1,130 @@
+//! Utilities to construct and inspect BOLT9 feature vectors
+//!
+//! An LSP-server signals support for LSPS0 by setting
+//! [`LSPS_FEATURE_BIT`] in its node announcement and init message.
+//!
+//! Core Lightning represents a feature vector as a hex-string.
+//! The vector is big-endian: bit 0 is the least significant bit
+//! of the last byte.
+use crate::error::Result;
+use crate::hex;
+use crate::lsps0::util::is_feature_bit_enabled;
+
+pub const LSPS_FEATURE_BIT: usize = 729;
+
+/// Decodes a hex-encoded feature vector
+///
+/// The vector is a big-endian number. A string of odd length is
+/// interpreted as if it were padded with a leading `0`. Otherwise the
+/// rules of [`crate::hex::decode`] apply.
+fn decode_feature_vector(hex_str: &str) -> Result<Vec<u8>> {
+    if hex_str.len() % 2 == 1 {
+        hex::decode("feature-vector", &format!("0{}", hex_str))
+    } else {
+        hex::decode("feature-vector", hex_str)
+    }
+}
+
+/// Returns the hex-encoded feature vector `hex_str` with `bit` set
+///
+/// The vector is padded with leading zero-bytes if it is too short
+/// to contain `bit`. Pass an empty string to create a new vector.
+pub fn set_feature_bit(hex_str: &str, bit: usize) -> Result<String> {
+    let mut features = decode_feature_vector(hex_str)?;
+
+    let byte_index = bit / 8;
+    if byte_index >= features.len() {
+        let mut padded = vec![0u8; byte_index + 1 - features.len()];
+        padded.extend(features);
+        features = padded;
+    }
+
+    let n_bytes = features.len();
+    features[n_bytes - 1 - byte_index] |= 1u8 << (bit % 8);
+
+    Ok(hex::encode(features))
+}
+
+/// Returns true if `bit` is set in the hex-encoded feature vector `hex_str`
+pub fn has_feature_bit(hex_str: &str, bit: usize) -> Result<bool> {
+    let features = decode_feature_vector(hex_str)?;
+    Ok(is_feature_bit_enabled(&features, bit))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::error::Error;
+
+    #[test]
+    fn set_bits_at_byte_boundaries() {
+        assert_eq!(set_feature_bit("", 0).unwrap(), "01");
+        assert_eq!(set_feature_bit("", 7).unwrap(), "80");
+        assert_eq!(set_feature_bit("", 8).unwrap(), "0100");
+        assert_eq!(set_feature_bit("", 15).unwrap(), "8000");
+        assert_eq!(set_feature_bit("", 16).unwrap(), "010000");
+
+        for bit in [0, 7, 8, 15, 16, 729] {
+            let features = set_feature_bit("", bit).unwrap();
+            assert!(has_feature_bit(&features, bit).unwrap());
+            assert!(!has_feature_bit(&features, bit + 1).unwrap());
+        }
+    }
+
+    #[test]
+    fn set_bit_preserves_existing_bits() {
+        // The vector is padded when it is shorter than the bit-index
+        let features = set_feature_bit("02", 8).unwrap();
+        assert_eq!(features, "0102");
+
+        // The vector keeps its length when the bit fits
+        let features = set_feature_bit("0002", 8).unwrap();
+        assert_eq!(features, "0102");
+    }
+
+    #[test]
+    fn feature_vector_shorter_than_bit_index() {
+        assert!(!has_feature_bit("", 0).unwrap());
+        assert!(!has_feature_bit("ff", 8).unwrap());
+        assert!(!has_feature_bit("ffff", LSPS_FEATURE_BIT).unwrap());
+    }
+
+    #[test]
+    fn odd_length_feature_vector() {
+        assert!(has_feature_bit("100", 8).unwrap());
+        assert!(!has_feature_bit("100", 0).unwrap());
+        assert_eq!(set_feature_bit("100", 0).unwrap(), "0101");
+    }
+
+    #[test]
+    fn invalid_feature_vector() {
+        let err = has_feature_bit("xyz", 0).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "feature-vector",
+                ..
+            }
+        ));
+        let err = set_feature_bit("0g", 0).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "feature-vector",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn compatible_with_lsps0_feature_string() {
+        // Copied from LSPS0
+        let data = "0200000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
+
+        assert_eq!(set_feature_bit("", LSPS_FEATURE_BIT).unwrap(), data);
+        assert!(has_feature_bit(data, LSPS_FEATURE_BIT).unwrap());
+        assert!(!has_feature_bit(data, LSPS_FEATURE_BIT - 1).unwrap());
+        assert!(!has_feature_bit(data, LSPS_FEATURE_BIT + 1).unwrap());
+    }
+}
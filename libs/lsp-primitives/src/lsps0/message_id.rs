@@ -0,0 +1,38 @@
+/// The BOLT8 message id used to transport LSPS messages
+///
+/// LSPS0 messages are sent as custom messages with type 37913.
+/// BOLT1 states that a node must ignore unknown messages of odd type
+/// and must close the connection for unknown messages of even type.
+/// The id is odd so nodes that don't support LSPS can safely ignore it.
+pub const LSPS_MESSAGE_ID_U16: u16 = 37913;
+
+/// [`LSPS_MESSAGE_ID_U16`] encoded as big-endian bytes
+pub const LSPS_MESSAGE_ID: [u8; 2] = [0x94, 0x19];
+
+const _: () = assert!(u16::from_be_bytes(LSPS_MESSAGE_ID) == LSPS_MESSAGE_ID_U16);
+const _: () = assert!(LSPS_MESSAGE_ID_U16 % 2 == 1, "The message id must be odd");
+
+/// Returns true if a custom message with id `msg_id` carries an LSPS message
+pub fn is_lsps_message(msg_id: u16) -> bool {
+    msg_id == LSPS_MESSAGE_ID_U16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_id_matches_spec() {
+        assert_eq!(LSPS_MESSAGE_ID_U16, 37913);
+        assert_eq!(LSPS_MESSAGE_ID, LSPS_MESSAGE_ID_U16.to_be_bytes());
+    }
+
+    #[test]
+    fn detect_lsps_message() {
+        assert!(is_lsps_message(37913));
+        assert!(is_lsps_message(u16::from_be_bytes([0x94, 0x19])));
+
+        assert!(!is_lsps_message(37912));
+        assert!(!is_lsps_message(u16::from_le_bytes([0x94, 0x19])));
+    }
+}
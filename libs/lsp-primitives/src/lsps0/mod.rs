@@ -1,6 +1,10 @@
+pub mod bounded_range;
 pub mod builders;
 pub mod common_schemas;
+pub mod features;
+pub mod message_id;
 pub mod parameter_validation;
+pub mod response_validation;
 pub mod schema;
 pub mod util;
 
@@ -6,7 +6,7 @@
 //! These error-types can be easily converted to LSPS0-compliant
 //! error data.
 
-use crate::json_rpc::ErrorData;
+use crate::json_rpc::{ErrorData, LspsErrorReason};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
@@ -36,13 +36,14 @@ impl ParamValidationError {
 
 impl From<ParamValidationError> for ErrorData<serde_json::Value> {
     fn from(err: ParamValidationError) -> Self {
+        let reason = match err {
+            ParamValidationError::Unrecognized(_) => LspsErrorReason::UnrecognizedParams,
+            _ => LspsErrorReason::InvalidParams,
+        };
         let result_ser = serde_json::to_value(err);
         match result_ser {
-            Ok(data) => ErrorData::invalid_params(data),
-            Err(err) => ErrorData::internal_error(Value::String(format!(
-                "Deserialization failed: {:?}",
-                err
-            ))),
+            Ok(data) => ErrorData::invalid_params(data).with_reason(reason),
+            Err(err) => ErrorData::internalize(err),
         }
     }
 }
@@ -67,13 +68,27 @@ pub trait ExpectedFields {
     fn expected_fields() -> Vec<String>;
 }
 
+/// The expected fields of `T` when it is nested under `prefix`
+///
+/// E.g: the fields of a `Payment` nested under `payment` are
+/// `payment.state`, `payment.fee_total_sat`, ...
+pub fn nested_fields<T: ExpectedFields>(prefix: &str) -> Vec<String> {
+    T::expected_fields()
+        .iter()
+        .map(|field| format!("{}.{}", prefix, field))
+        .collect()
+}
+
 /// Parses a request from a json_value
+///
+/// Omitted params, `null` and `{}` are equivalent. A missing parameter
+/// results in an [`InvalidParam`] for that parameter.
 pub fn from_value<'de, T: Deserialize<'de> + ExpectedFields>(
     value: serde_json::Value,
 ) -> Result<T, ParamValidationError> {
     let value = match value {
         Value::Object(_) => value,
-        Value::Null => Value::Null,
+        Value::Null => Value::Object(Map::new()),
         _ => {
             return Err(ParamValidationError::Custom(Custom {
                 message: "Arguments should be passed by name".to_string(),
@@ -92,15 +107,65 @@ pub fn from_value<'de, T: Deserialize<'de> + ExpectedFields>(
     }
 
     serde_path_to_error::deserialize::<'de, _, T>(value).map_err(|e| {
-        ParamValidationError::InvalidParam(InvalidParam {
-            property: e.path().to_string(),
-            message: e.to_string(),
-        })
+        let path = e.path().to_string();
+        match missing_field(&e.inner().to_string()) {
+            Some(field) => {
+                let property = match path.as_str() {
+                    "." => field.to_string(),
+                    _ => format!("{}.{}", path, field),
+                };
+                ParamValidationError::InvalidParam(InvalidParam {
+                    message: format!("Missing required parameter {}", property),
+                    property,
+                })
+            }
+            None => ParamValidationError::InvalidParam(InvalidParam {
+                property: path,
+                message: e.to_string(),
+            }),
+        }
     })
 }
 
+/// Reads the name of the field from a `missing field` error of serde
+///
+/// The path of such an error points to the struct that misses the field
+pub(crate) fn missing_field(message: &str) -> Option<&str> {
+    message.strip_prefix("missing field `")?.split('`').next()
+}
+
+/// Validates a free-form string provided by a client
+///
+/// The string must be at most `max_length` bytes and may not contain
+/// control characters.
+pub fn validate_free_form_string(
+    property: &str,
+    value: &str,
+    max_length: usize,
+) -> Result<(), ParamValidationError> {
+    if value.len() > max_length {
+        return Err(ParamValidationError::invalid_params(
+            property.to_string(),
+            format!(
+                "Length of {} bytes exceeds the maximum of {} bytes",
+                value.len(),
+                max_length
+            ),
+        ));
+    }
+
+    if value.chars().any(char::is_control) {
+        return Err(ParamValidationError::invalid_params(
+            property.to_string(),
+            "Contains control characters".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 /// Computes a list of unrecognized fields
-fn list_unrecogninzed_fields(
+pub(crate) fn list_unrecogninzed_fields(
     expected_arguments: &[&str],
     json_value: &serde_json::Value,
 ) -> Vec<String> {
@@ -155,6 +220,7 @@ fn list_unrecognized_fields_impl(
 mod test {
     use super::*;
     use crate::lsps0::parameter_validation::ParamValidationError;
+    use crate::no_params::NoParams;
     use serde_json::json;
 
     #[test]
@@ -232,6 +298,40 @@ mod test {
         )
     }
 
+    #[test]
+    fn wire_shape_of_invalid_params() {
+        let error: ErrorData = ParamValidationError::unrecognized(vec!["a".to_string()]).into();
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code" : -32602,
+                "message" : "Invalid Params",
+                "data" : {
+                    "type" : "unrecognized",
+                    "unrecognized" : ["a"],
+                    "reason" : "unrecognized_params"
+                }
+            })
+        );
+
+        let error: ErrorData =
+            ParamValidationError::invalid_params("token".to_string(), "Too long".to_string())
+                .into();
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code" : -32602,
+                "message" : "Invalid Params",
+                "data" : {
+                    "type" : "invalid_param",
+                    "property" : "token",
+                    "message" : "Too long",
+                    "reason" : "invalid_params"
+                }
+            })
+        );
+    }
+
     #[test]
     fn convert_invalid_params_to_error_data() {
         let error = ParamValidationError::unrecognized(vec!["param_a".to_string()]);
@@ -247,4 +347,77 @@ mod test {
             "param_a"
         );
     }
+
+    #[test]
+    fn invalid_params_error_data_has_reason() {
+        let error: ErrorData = ParamValidationError::unrecognized(vec!["a".to_string()]).into();
+        assert_eq!(error.reason(), Some(LspsErrorReason::UnrecognizedParams));
+
+        let error: ErrorData =
+            ParamValidationError::invalid_params("a".to_string(), "b".to_string()).into();
+        assert_eq!(error.reason(), Some(LspsErrorReason::InvalidParams));
+        assert_eq!(error.data.unwrap()["property"], "a");
+
+        let error: ErrorData = ParamValidationError::custom("a".to_string()).into();
+        assert_eq!(error.reason(), Some(LspsErrorReason::InvalidParams));
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Params {
+        #[allow(dead_code)]
+        order_id: String,
+    }
+
+    impl ExpectedFields for Params {
+        fn expected_fields() -> Vec<String> {
+            vec!["order_id".to_string()]
+        }
+    }
+
+    #[test]
+    fn null_and_empty_params_are_equivalent() {
+        from_value::<NoParams>(Value::Null).unwrap();
+        from_value::<NoParams>(json!({})).unwrap();
+
+        for value in [Value::Null, json!({})] {
+            match from_value::<Params>(value).unwrap_err() {
+                ParamValidationError::InvalidParam(err) => {
+                    assert_eq!(err.property, "order_id");
+                    assert_eq!(err.message, "Missing required parameter order_id");
+                }
+                err => panic!("Expected InvalidParam but got {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn params_must_be_passed_by_name() {
+        let err = from_value::<Params>(json!(["abc"])).unwrap_err();
+        assert!(matches!(err, ParamValidationError::Custom(_)));
+    }
+
+    #[test]
+    fn validate_free_form_string_accepts_printable_utf8() {
+        validate_free_form_string("token", "", 4).unwrap();
+        validate_free_form_string("token", "a b", 4).unwrap();
+        validate_free_form_string("token", "\u{20ac}", 4).unwrap();
+    }
+
+    #[test]
+    fn validate_free_form_string_rejects_oversized_strings() {
+        // The limit is in bytes, not in characters
+        let err = validate_free_form_string("token", "\u{20ac}\u{20ac}", 4).unwrap_err();
+        match err {
+            ParamValidationError::InvalidParam(err) => assert_eq!(err.property, "token"),
+            _ => panic!("Expected InvalidParam"),
+        }
+    }
+
+    #[test]
+    fn validate_free_form_string_rejects_control_characters() {
+        for value in ["a\nb", "\0", "\u{1b}[31m", "\u{7f}"] {
+            let result = validate_free_form_string("token", value, 512);
+            assert!(result.is_err(), "{:?} should be rejected", value);
+        }
+    }
 }
@@ -0,0 +1,307 @@
+//! Response validation for LSP-clients.
+//!
+//! The counterpart of [`parameter_validation`] for the results an
+//! LSP-server returns. A client that validates responses strictly learns
+//! which property of a response is malformed, what was expected and what
+//! the server sent instead.
+//!
+//! [`parameter_validation`]: crate::lsps0::parameter_validation
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use serde_path_to_error::Segment;
+
+use crate::lsps0::parameter_validation::{
+    list_unrecogninzed_fields, missing_field, ExpectedFields,
+};
+
+/// The maximum length of the snippet of a malformed value in an error
+const MAX_SNIPPET_LENGTH: usize = 64;
+
+/// Explains why the result of an LSP-server is malformed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResponseValidationError {
+    /// The result is not a JSON-object
+    NotAnObject { actual: String },
+    /// A required property is absent
+    MissingProperty { property: String },
+    /// A property has the wrong type or an invalid value
+    InvalidProperty {
+        property: String,
+        expected: String,
+        actual: String,
+    },
+    /// Properties that are not part of the schema
+    UnexpectedProperties { properties: Vec<String> },
+}
+
+impl ResponseValidationError {
+    /// The paths of the properties that are malformed. E.g: `payment.fee_total_sat`
+    pub fn properties(&self) -> Vec<&str> {
+        match self {
+            Self::NotAnObject { .. } => vec![],
+            Self::MissingProperty { property } | Self::InvalidProperty { property, .. } => {
+                vec![property]
+            }
+            Self::UnexpectedProperties { properties } => {
+                properties.iter().map(|p| p.as_str()).collect()
+            }
+        }
+    }
+}
+
+impl fmt::Display for ResponseValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAnObject { actual } => {
+                write!(f, "Expected a JSON-object but got {}", actual)
+            }
+            Self::MissingProperty { property } => {
+                write!(f, "Missing required property {}", property)
+            }
+            Self::InvalidProperty {
+                property,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Invalid property {}: expected {} but got {}",
+                property, expected, actual
+            ),
+            Self::UnexpectedProperties { properties } => {
+                write!(f, "Unexpected properties {}", properties.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResponseValidationError {}
+
+/// Parses the result of an LSP-server strictly
+///
+/// Mirrors [`from_value`] but every property that isn't part of the schema
+/// of `T` is reported as unexpected.
+///
+/// [`from_value`]: crate::lsps0::parameter_validation::from_value
+pub fn validate_response<T: DeserializeOwned + ExpectedFields>(
+    value: Value,
+) -> Result<T, ResponseValidationError> {
+    if !value.is_object() {
+        return Err(ResponseValidationError::NotAnObject {
+            actual: snippet(&value),
+        });
+    }
+
+    let expected_fields = T::expected_fields();
+    let expected_fields: Vec<&str> = expected_fields.iter().map(|x| x.as_ref()).collect();
+    let unexpected = list_unrecogninzed_fields(expected_fields.as_ref(), &value);
+    if !unexpected.is_empty() {
+        return Err(ResponseValidationError::UnexpectedProperties {
+            properties: unexpected,
+        });
+    }
+
+    serde_path_to_error::deserialize::<_, T>(&value).map_err(|e| {
+        let path = e.path().to_string();
+        let message = e.inner().to_string();
+        if let Some(field) = missing_field(&message) {
+            let property = match path.as_str() {
+                "." => field.to_string(),
+                _ => format!("{}.{}", path, field),
+            };
+            return ResponseValidationError::MissingProperty { property };
+        }
+
+        // Serde phrases type errors as "invalid type: integer `1`, expected a string"
+        let expected = match message.split_once(", expected ") {
+            Some((_, expected)) => expected.to_string(),
+            None => message,
+        };
+        let actual = match lookup(&value, e.path()) {
+            Some(actual) => snippet(actual),
+            None => "nothing".to_string(),
+        };
+        ResponseValidationError::InvalidProperty {
+            property: path,
+            expected,
+            actual,
+        }
+    })
+}
+
+/// Finds the value at `path`
+fn lookup<'a>(value: &'a Value, path: &serde_path_to_error::Path) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, segment| match segment {
+        Segment::Map { key } => value.get(key),
+        Segment::Seq { index } => value.get(index),
+        _ => Some(value),
+    })
+}
+
+/// The JSON of `value`, shortened to at most [`MAX_SNIPPET_LENGTH`] characters
+fn snippet(value: &Value) -> String {
+    let json = value.to_string();
+    if json.chars().count() <= MAX_SNIPPET_LENGTH {
+        return json;
+    }
+    let shortened: String = json.chars().take(MAX_SNIPPET_LENGTH).collect();
+    format!("{}...", shortened)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::lsps0::schema::ListprotocolsResponse;
+    use crate::lsps1::schema::{Lsps1GetInfoResponse, Lsps1GetOrderResponse};
+
+    fn get_info_json() -> Value {
+        json!({
+            "options": {
+                "min_required_channel_confirmations": 0,
+                "min_funding_confirms_within_blocks": 6,
+                "min_onchain_payment_confirmations": null,
+                "supports_zero_channel_reserve": true,
+                "min_onchain_payment_size_sat": null,
+                "max_channel_expiry_blocks": 20160,
+                "min_initial_client_balance_sat": "20000",
+                "max_initial_client_balance_sat": "100000000",
+                "min_initial_lsp_balance_sat": "0",
+                "max_initial_lsp_balance_sat": "100000000",
+                "min_channel_balance_sat": "50000",
+                "max_channel_balance_sat": "100000000"
+            }
+        })
+    }
+
+    fn get_order_json() -> Value {
+        json!({
+            "order_id": "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+            "lsp_balance_sat": "5000000",
+            "client_balance_sat": "2000000",
+            "funding_confirms_within_blocks" : 1,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks": 12,
+            "token": "",
+            "created_at": "2012-04-23T18:25:43.511Z",
+            "expires_at": "2015-01-25T19:29:44.612Z",
+            "announce_channel": true,
+            "order_state": "COMPLETED",
+            "payment": {
+                "state": "PAID",
+                "fee_total_sat": "8888",
+                "order_total_sat": "2008888",
+                "bolt11_invoice": "lnbc252u1p3aht9ysp580g4633gd2x9lc5al0wd8wx0mpn9748jeyz46kqjrpxn52uhfpjqpp5qgf67tcqmuqehzgjm8mzya90h73deafvr4m5705l5u5l4r05l8cqdpud3h8ymm4w3jhytnpwpczqmt0de6xsmre9cs9w6t5dpcqzpuxqr23ssp5pe7kmh6kpd5e2mz5v6s6w9k4rkhq7zh9r0mtqn0wqp4xqpn5u8cq",
+                "onchain_address": null,
+                "min_onchain_payment_confirmations": null,
+                "min_fee_for_0conf": null,
+                "onchain_payment": null
+            },
+            "channel": {
+                "funded_at": "2012-04-23T18:29:43.511Z",
+                "funding_outpoint": "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:0",
+                "expires_at": "2012-04-23T18:25:43.511Z"
+            }
+        })
+    }
+
+    #[test]
+    fn spec_examples_are_valid() {
+        validate_response::<Lsps1GetInfoResponse>(get_info_json()).unwrap();
+        validate_response::<Lsps1GetOrderResponse>(get_order_json()).unwrap();
+        validate_response::<ListprotocolsResponse>(json!({"protocols" : [1, 2]})).unwrap();
+    }
+
+    #[test]
+    fn report_path_of_wrong_types() {
+        let mut value = get_order_json();
+        value["payment"]["fee_total_sat"] = json!(8888);
+        let err = validate_response::<Lsps1GetOrderResponse>(value).unwrap_err();
+        assert_eq!(err.properties(), vec!["payment.fee_total_sat"]);
+        match err {
+            ResponseValidationError::InvalidProperty {
+                expected, actual, ..
+            } => {
+                assert_eq!(actual, "8888");
+                assert!(expected.contains("string"), "{}", expected);
+            }
+            err => panic!("Expected InvalidProperty but got {:?}", err),
+        }
+
+        let mut value = get_info_json();
+        value["options"]["max_channel_balance_sat"] = json!(true);
+        let err = validate_response::<Lsps1GetInfoResponse>(value).unwrap_err();
+        assert_eq!(err.properties(), vec!["options.max_channel_balance_sat"]);
+
+        let value = json!({"protocols" : ["1"]});
+        let err = validate_response::<ListprotocolsResponse>(value).unwrap_err();
+        assert_eq!(err.properties(), vec!["protocols[0]"]);
+        assert!(err.to_string().contains("but got \"1\""), "{}", err);
+    }
+
+    #[test]
+    fn report_path_of_missing_properties() {
+        let mut value = get_order_json();
+        value["payment"]
+            .as_object_mut()
+            .unwrap()
+            .remove("bolt11_invoice");
+        let err = validate_response::<Lsps1GetOrderResponse>(value).unwrap_err();
+        assert_eq!(
+            err,
+            ResponseValidationError::MissingProperty {
+                property: "payment.bolt11_invoice".to_string()
+            }
+        );
+
+        let mut value = get_info_json();
+        value.as_object_mut().unwrap().remove("options");
+        let err = validate_response::<Lsps1GetInfoResponse>(value).unwrap_err();
+        assert_eq!(err.properties(), vec!["options"]);
+    }
+
+    #[test]
+    fn report_unexpected_properties() {
+        let mut value = get_order_json();
+        value["payment"]["tip_sat"] = json!("100");
+        value["channel"]["scid"] = json!("1x2x3");
+        let err = validate_response::<Lsps1GetOrderResponse>(value).unwrap_err();
+        let mut properties = err.properties();
+        properties.sort();
+        assert_eq!(properties, vec!["channel.scid", "payment.tip_sat"]);
+
+        // Lenient parsing keeps unknown fields but strict validation rejects them
+        let value = json!({"protocols" : [1], "versions" : {"1" : 2}});
+        serde_json::from_value::<ListprotocolsResponse>(value.clone()).unwrap();
+        let err = validate_response::<ListprotocolsResponse>(value).unwrap_err();
+        assert_eq!(err.properties(), vec!["versions.1"]);
+    }
+
+    #[test]
+    fn result_must_be_an_object() {
+        let err = validate_response::<ListprotocolsResponse>(json!([1, 2])).unwrap_err();
+        assert_eq!(
+            err,
+            ResponseValidationError::NotAnObject {
+                actual: "[1,2]".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn long_values_are_shortened() {
+        let mut value = get_order_json();
+        value["payment"]["bolt11_invoice"] = json!(["x".repeat(100)]);
+        let err = validate_response::<Lsps1GetOrderResponse>(value).unwrap_err();
+        match err {
+            ResponseValidationError::InvalidProperty { actual, .. } => {
+                assert!(actual.ends_with("..."));
+                assert_eq!(actual.chars().count(), MAX_SNIPPET_LENGTH + 3);
+            }
+            err => panic!("Expected InvalidProperty but got {:?}", err),
+        }
+    }
+}
@@ -1,9 +1,31 @@
 pub use crate::lsps0::common_schemas::*;
+use crate::lsps0::parameter_validation::ExpectedFields;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ListprotocolsResponse {
     pub protocols: Vec<u32>,
+    /// Fields that are not part of the spec
+    ///
+    /// Some servers add e.g. the versions of the protocols. The fields are
+    /// kept so the response can be forwarded without losing them.
+    #[serde(flatten)]
+    pub extra_fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Fields of [`ListprotocolsResponse`] that cannot be set as extra fields
+pub const LSPS0_LIST_PROTOCOLS_FIELDS: [&str; 1] = ["protocols"];
+
+/// Unknown fields are collected in `extra_fields` when parsing.
+/// A strict client reports them as unexpected instead
+impl ExpectedFields for ListprotocolsResponse {
+    fn expected_fields() -> Vec<String> {
+        LSPS0_LIST_PROTOCOLS_FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -14,9 +36,32 @@ mod test {
     fn serialize_protocol_list() {
         let protocols = ListprotocolsResponse {
             protocols: vec![1, 3],
+            extra_fields: Default::default(),
         };
 
         let json_str = serde_json::to_string(&protocols).unwrap();
         assert_eq!(json_str, "{\"protocols\":[1,3]}")
     }
+
+    #[test]
+    fn unknown_fields_survive_a_round_trip() {
+        let value = serde_json::json!({
+            "protocols" : [0, 1, 2],
+            "versions" : { "1" : [1, 2] },
+            "name" : "Example LSP",
+        });
+
+        let response: ListprotocolsResponse = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(response.protocols, vec![0, 1, 2]);
+        assert_eq!(response.extra_fields["name"], "Example LSP");
+        assert_eq!(
+            response.extra_fields["versions"]["1"],
+            serde_json::json!([1, 2])
+        );
+
+        assert_eq!(serde_json::to_value(&response).unwrap(), value);
+        let reparsed: ListprotocolsResponse =
+            serde_json::from_value(serde_json::to_value(&response).unwrap()).unwrap();
+        assert_eq!(reparsed, response);
+    }
 }
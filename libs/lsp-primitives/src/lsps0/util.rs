@@ -1,4 +1,3 @@
-use anyhow::Context;
 /// Contains LSPS0 related utilities
 ///
 /// To determine if a Lightning-node is an LSP-server you can
@@ -6,16 +5,19 @@ use anyhow::Context;
 ///
 use std::str::FromStr;
 
-pub const LSP_SERVER_FEATURE_BIT: usize = 729;
+use crate::error::Error;
+use crate::hex;
+
+pub const LSP_SERVER_FEATURE_BIT: usize = crate::lsps0::features::LSPS_FEATURE_BIT;
 
 pub struct FeatureBitMap(Vec<u8>);
 
 impl FromStr for FeatureBitMap {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let hex = hex::decode(s).context("Failed to parse feature-bitmap")?;
-        Ok(FeatureBitMap(hex))
+        let bitmap = hex::decode("feature-bitmap", s)?;
+        Ok(FeatureBitMap(bitmap))
     }
 }
 
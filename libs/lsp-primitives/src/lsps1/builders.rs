@@ -1,11 +1,14 @@
-use anyhow::{anyhow, Context, Result};
 use uuid::Uuid;
 
+use crate::error::{Error, Result};
+use crate::lsps0::bounded_range::BoundedRange;
+use crate::lsps0::common_schemas::Network;
 use crate::lsps0::schema::{FeeRate, IsoDatetime, OnchainAddress, SatAmount};
+use crate::lsps1::receipt::OrderReceipt;
 use crate::lsps1::schema::{
-    Channel, Lsps1CreateOrderRequest, Lsps1CreateOrderResponse, Lsps1GetInfoResponse,
-    Lsps1GetOrderRequest, Lsps1InfoRequest, Lsps1Options, OnchainPayment, OrderState, Payment,
-    PaymentState,
+    Channel, ChannelTypeFeature, LspExtensions, Lsps1CreateOrderRequest, Lsps1CreateOrderResponse,
+    Lsps1GetInfoResponse, Lsps1GetOrderRequest, Lsps1InfoRequest, Lsps1Options, OnchainPayment,
+    OrderId, OrderState, Payment, PaymentState, Refund, LSPS1_GET_INFO_FIELDS,
 };
 
 #[derive(Default, Debug)]
@@ -24,6 +27,11 @@ impl LspsInfoRequestBuilder {
 #[derive(Default, Debug)]
 pub struct Lsps1InfoResponseBuilder {
     options: Option<Lsps1Options>,
+    extensions: Vec<String>,
+    website: Option<String>,
+    network: Option<Network>,
+    lsp_extensions: Option<LspExtensions>,
+    extra_fields: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Lsps1InfoResponseBuilder {
@@ -36,12 +44,61 @@ impl Lsps1InfoResponseBuilder {
         self
     }
 
+    pub fn extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    pub fn website(mut self, website: Option<String>) -> Self {
+        self.website = website;
+        self
+    }
+
+    pub fn network(mut self, network: Option<Network>) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn lsp_extensions(mut self, lsp_extensions: Option<LspExtensions>) -> Self {
+        self.lsp_extensions = lsp_extensions;
+        self
+    }
+
+    /// Fields that are added to the response next to the fields defined in the spec
+    pub fn extra_fields(
+        mut self,
+        extra_fields: serde_json::Map<String, serde_json::Value>,
+    ) -> Self {
+        self.extra_fields = extra_fields;
+        self
+    }
+
     pub fn build(self) -> Result<Lsps1GetInfoResponse> {
-        let options = self
-            .options
-            .context("Missing field 'options' in Lsps1InfoResponseBuilder")?;
+        let missing = |field| Error::missing_field("Lsps1InfoResponseBuilder", field);
+        let options = self.options.ok_or_else(|| missing("options"))?;
+
+        if let Some(field) = LSPS1_GET_INFO_FIELDS
+            .iter()
+            .find(|field| self.extra_fields.contains_key(**field))
+        {
+            return Err(Error::invalid_value(
+                *field,
+                "Extra field would overwrite a field defined in the spec",
+            ));
+        }
+
+        if let Some(lsp_extensions) = &self.lsp_extensions {
+            lsp_extensions.validate()?;
+        }
 
-        let result = Lsps1GetInfoResponse { options };
+        let result = Lsps1GetInfoResponse {
+            options,
+            extensions: self.extensions,
+            website: self.website,
+            network: self.network,
+            lsp_extensions: self.lsp_extensions,
+            extra_fields: self.extra_fields,
+        };
         Ok(result)
     }
 }
@@ -146,51 +203,58 @@ impl Lsps1OptionsBuilder {
     }
 
     pub fn build(self) -> Result<Lsps1Options> {
-        let min_required_channel_confirmations = self.min_required_channel_confirmations.context(
-            "No value specified for 'min_required_channel_confirmations' in Lsps1OptionBuilder",
-        )?;
-        let min_funding_confirms_within_blocks = self.min_funding_confirms_within_blocks.context(
-            "No value specified for 'min_funding_confirms_within_blocks' in Lsps1OptionBuilder",
-        )?;
-        let supports_zero_channel_reserve = self.supports_zero_channel_reserve.context(
-            "No value specified for 'supports_zero_channel_reserve' in Lsps1OptionsBuilder",
-        )?;
+        let missing = |field| Error::missing_field("Lsps1OptionsBuilder", field);
+        let min_required_channel_confirmations = self
+            .min_required_channel_confirmations
+            .ok_or_else(|| missing("min_required_channel_confirmations"))?;
+        let min_funding_confirms_within_blocks = self
+            .min_funding_confirms_within_blocks
+            .ok_or_else(|| missing("min_funding_confirms_within_blocks"))?;
+        let supports_zero_channel_reserve = self
+            .supports_zero_channel_reserve
+            .ok_or_else(|| missing("supports_zero_channel_reserve"))?;
         let max_channel_expiry_blocks = self
             .max_channel_expiry_blocks
-            .context("No value specified for 'max_channel_expiry_blocks' in Lsps1OptionsBuilder")?;
-        let min_initial_lsp_balance_sat = self.min_initial_lsp_balance_sat.context(
-            "No value specified for 'min_initial_lsp_balance_sat' in Lsps1OptionsBuilder",
-        )?;
-        let max_initial_lsp_balance_sat = self.max_initial_lsp_balance_sat.context(
-            "No value specified for 'max_initial_lsp_balance_sat' in Lsps1OptionsBuilder",
-        )?;
-
-        let min_initial_client_balance_sat = self.min_initial_client_balance_sat.context(
-            "No value specified for 'min_initial_client_balance_sat' in Lsps1OptionsBuilder",
-        )?;
-        let max_initial_client_balance_sat = self.max_initial_client_balance_sat.context(
-            "No value specified for 'max_initial_client_balance_sat' in Lsps1OptionsBuilder",
-        )?;
+            .ok_or_else(|| missing("max_channel_expiry_blocks"))?;
+        let min_initial_lsp_balance_sat = self
+            .min_initial_lsp_balance_sat
+            .ok_or_else(|| missing("min_initial_lsp_balance_sat"))?;
+        let max_initial_lsp_balance_sat = self
+            .max_initial_lsp_balance_sat
+            .ok_or_else(|| missing("max_initial_lsp_balance_sat"))?;
+
+        let min_initial_client_balance_sat = self
+            .min_initial_client_balance_sat
+            .ok_or_else(|| missing("min_initial_client_balance_sat"))?;
+        let max_initial_client_balance_sat = self
+            .max_initial_client_balance_sat
+            .ok_or_else(|| missing("max_initial_client_balance_sat"))?;
         let min_channel_balance_sat = self
             .min_channel_balance_sat
-            .context("No value specified for 'min_channel_balance_sat' in Lsps1OptionsBuilder")?;
+            .ok_or_else(|| missing("min_channel_balance_sat"))?;
         let max_channel_balance_sat = self
             .max_channel_balance_sat
-            .context("No value specified for 'max_channel_balance_sat' in Lsps1OptionsBuilder")?;
+            .ok_or_else(|| missing("max_channel_balance_sat"))?;
 
         // Maybe NULL if the LSP doesn't support on chain payments
         let min_onchain_payment_size_sat = self.min_onchain_payment_size_sat;
         let min_onchain_payment_confirmations = self.min_onchain_payment_confirmations;
 
-        if min_channel_balance_sat > max_channel_balance_sat {
-            return Err(anyhow!("min_channel_balance_sat ({}) should be less than or equal to max_channel_balance_sat ({})", min_channel_balance_sat, max_channel_balance_sat));
-        }
-        if min_initial_client_balance_sat > max_initial_client_balance_sat {
-            return Err(anyhow!("min_initial_client_balance_sat ({}) should be less than or equal to max_initial_client_balance_sat ({})", min_initial_client_balance_sat, max_initial_client_balance_sat));
-        }
-        if min_initial_lsp_balance_sat > max_initial_lsp_balance_sat {
-            return Err(anyhow!("min_initial_lsp_balance_sat ({}) should be less than or equal to max_initial_lsp_balance_sat ({})", min_initial_lsp_balance_sat, max_initial_lsp_balance_sat));
-        }
+        BoundedRange::new(
+            "channel_balance_sat",
+            min_channel_balance_sat,
+            max_channel_balance_sat,
+        )?;
+        BoundedRange::new(
+            "initial_client_balance_sat",
+            min_initial_client_balance_sat,
+            max_initial_client_balance_sat,
+        )?;
+        BoundedRange::new(
+            "initial_lsp_balance_sat",
+            min_initial_lsp_balance_sat,
+            max_initial_lsp_balance_sat,
+        )?;
 
         Ok(Lsps1Options {
             min_required_channel_confirmations,
@@ -219,6 +283,9 @@ pub struct Lsps1CreateOrderRequestBuilder {
     token: Option<String>,
     refund_onchain_address: Option<OnchainAddress>,
     announce_channel: Option<bool>,
+    zero_channel_reserve: Option<bool>,
+    channel_type: Option<Vec<ChannelTypeFeature>>,
+    funding_source: Option<String>,
 }
 
 impl Lsps1CreateOrderRequestBuilder {
@@ -269,14 +336,30 @@ impl Lsps1CreateOrderRequestBuilder {
         self
     }
 
+    pub fn zero_channel_reserve(mut self, zero_channel_reserve: Option<bool>) -> Self {
+        self.zero_channel_reserve = zero_channel_reserve;
+        self
+    }
+
+    pub fn channel_type(mut self, channel_type: Option<Vec<ChannelTypeFeature>>) -> Self {
+        self.channel_type = channel_type;
+        self
+    }
+
+    pub fn funding_source(mut self, funding_source: Option<String>) -> Self {
+        self.funding_source = funding_source;
+        self
+    }
+
     pub fn build(self) -> Result<Lsps1CreateOrderRequest> {
+        let missing = |field| Error::missing_field("Lsps1CreateOrderRequestBuilder", field);
         // Required fields
         let lsp_balance_sat = self
             .lsp_balance_sat
-            .context("Missing field 'lsp_balance_sat' in Lsps1CreateOrderRequestBuilder")?;
+            .ok_or_else(|| missing("lsp_balance_sat"))?;
         let channel_expiry_blocks = self
             .channel_expiry_blocks
-            .context("Missing field 'channel_expirty_blocks' in Lsps1CreateOrderRequestBuilder")?;
+            .ok_or_else(|| missing("channel_expiry_blocks"))?;
 
         // Fields that allow for reasonable defaults
         let client_balance_sat = self.client_balance_sat.unwrap_or(SatAmount::new(0));
@@ -287,6 +370,9 @@ impl Lsps1CreateOrderRequestBuilder {
         // Non-required fields
         let token = self.token;
         let refund_onchain_address = self.refund_onchain_address;
+        let zero_channel_reserve = self.zero_channel_reserve;
+        let channel_type = self.channel_type;
+        let funding_source = self.funding_source;
 
         let request = Lsps1CreateOrderRequest {
             lsp_balance_sat,
@@ -297,6 +383,9 @@ impl Lsps1CreateOrderRequestBuilder {
             token,
             refund_onchain_address,
             announce_channel,
+            zero_channel_reserve,
+            channel_type,
+            funding_source,
         };
 
         Ok(request)
@@ -318,6 +407,10 @@ pub struct Lsps1CreateOrderResponseBuilder {
     order_state: Option<OrderState>,
     payment: Option<Payment>,
     channel: Option<Channel>,
+    refund: Option<Refund>,
+    clamped_fields: Vec<String>,
+    receipt: Option<OrderReceipt>,
+    channel_type: Option<Vec<ChannelTypeFeature>>,
 }
 
 impl Lsps1CreateOrderResponseBuilder {
@@ -332,7 +425,7 @@ impl Lsps1CreateOrderResponseBuilder {
             .funding_confirms_within_blocks(request.funding_confirms_within_blocks)
             .required_channel_confirmations(request.required_channel_confirmations)
             .channel_expiry_blocks(request.channel_expiry_blocks)
-            .token(request.token.unwrap_or("".to_string()))
+            .token(request.token)
             // .refund_onchain_address(request.refund_onchain_address)
             .announce_channel(request.announce_channel)
     }
@@ -363,8 +456,8 @@ impl Lsps1CreateOrderResponseBuilder {
         self.channel_expiry_blocks = Some(channel_expiry_blocks);
         self
     }
-    pub fn token(mut self, token: String) -> Self {
-        self.token = Some(token);
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.token = token;
         self
     }
     pub fn announce_channel(mut self, announce_channel: bool) -> Self {
@@ -391,47 +484,58 @@ impl Lsps1CreateOrderResponseBuilder {
         self.channel = channel;
         self
     }
+    pub fn refund(mut self, refund: Option<Refund>) -> Self {
+        self.refund = refund;
+        self
+    }
+    pub fn clamped_fields(mut self, clamped_fields: Vec<String>) -> Self {
+        self.clamped_fields = clamped_fields;
+        self
+    }
+    pub fn receipt(mut self, receipt: Option<OrderReceipt>) -> Self {
+        self.receipt = receipt;
+        self
+    }
+    pub fn channel_type(mut self, channel_type: Option<Vec<ChannelTypeFeature>>) -> Self {
+        self.channel_type = channel_type;
+        self
+    }
 
     pub fn build(self) -> Result<Lsps1CreateOrderResponse> {
+        let missing = |field| Error::missing_field("Lsps1CreateOrderResponseBuilder", field);
         //required variables
-        let order_id = self
-            .uuid
-            .context("Missing field 'order_id' in Lsps1CreateOrderRequestBuilder")?;
+        let order_id = self.uuid.ok_or_else(|| missing("order_id"))?;
         let lsp_balance_sat = self
             .lsp_balance_sat
-            .context("Missing field 'lsp_balance_sat' in Lsps1CreateOrderRequestBuilder")?;
+            .ok_or_else(|| missing("lsp_balance_sat"))?;
         let client_balance_sat = self
             .client_balance_sat
-            .context("Missing field 'client_balance_sat' in Lsps1CreateOrderRequestBuilder")?;
+            .ok_or_else(|| missing("client_balance_sat"))?;
         let funding_confirms_within_blocks = self
             .funding_confirms_within_blocks
-            .context("Missing field 'funding_confirms_within_blocks' in Lsps1CreateOrderRequestBuilder")?;
-        let required_channel_confirmations = 
-            self.required_channel_confirmations
-            .context("Missing field 'required_channel_confirmations' in Lsps1CreateOrderRequestBuilder")?;
+            .ok_or_else(|| missing("funding_confirms_within_blocks"))?;
+        let required_channel_confirmations = self
+            .required_channel_confirmations
+            .ok_or_else(|| missing("required_channel_confirmations"))?;
         let channel_expiry_blocks = self
             .channel_expiry_blocks
-            .context("Missing field 'channel_expiry_blocks' in Lsps1CreateOrderRequestBuilder")?;
-        let token = self.token.unwrap_or("".to_string());
+            .ok_or_else(|| missing("channel_expiry_blocks"))?;
+        let token = self.token;
         let announce_channel = self
             .announce_channel
-            .context("Missing field 'announce_channel' in Lsps1CreateOrderRequestBuilder")?;
-        let created_at = self
-            .created_at
-            .context("Missing field 'created_at' in Lsps1CreateOrderRequestBuilder")?;
-        let order_state = self
-            .order_state
-            .context("Missing field 'order_state' in Lsps1CreateOrderRequestBuilder")?;
-        let expires_at = self
-            .expires_at
-            .context("Missing field 'expires_at' in Lsps1CreateOrderRequestBuilder")?;
-        let payment = self
-            .payment
-            .context("Missing field 'payment' in Lsps1CreateOrderRequestBuilder")?;
+            .ok_or_else(|| missing("announce_channel"))?;
+        let created_at = self.created_at.ok_or_else(|| missing("created_at"))?;
+        let order_state = self.order_state.ok_or_else(|| missing("order_state"))?;
+        let expires_at = self.expires_at.ok_or_else(|| missing("expires_at"))?;
+        let payment = self.payment.ok_or_else(|| missing("payment"))?;
         let channel = self.channel;
+        let refund = self.refund;
+        let clamped_fields = self.clamped_fields;
+        let receipt = self.receipt;
+        let channel_type = self.channel_type;
 
         let request = Lsps1CreateOrderResponse {
-            order_id,
+            order_id: OrderId::from(order_id),
             lsp_balance_sat,
             client_balance_sat,
             funding_confirms_within_blocks,
@@ -444,6 +548,10 @@ impl Lsps1CreateOrderResponseBuilder {
             order_state,
             payment,
             channel,
+            refund,
+            clamped_fields,
+            receipt,
+            channel_type,
         };
 
         Ok(request)
@@ -474,9 +582,10 @@ impl OnchainPaymentBuilder {
     }
 
     pub fn build(self) -> Result<OnchainPayment> {
-        let outpoint = self.outpoint.context("Missing field 'outpoint'")?;
-        let sat = self.sat.context("Missing field 'sat'")?;
-        let confirmed = self.confirmed.context("Missing field 'confirmed'")?;
+        let missing = |field| Error::missing_field("OnchainPaymentBuilder", field);
+        let outpoint = self.outpoint.ok_or_else(|| missing("outpoint"))?;
+        let sat = self.sat.ok_or_else(|| missing("sat"))?;
+        let confirmed = self.confirmed.ok_or_else(|| missing("confirmed"))?;
 
         let payment = OnchainPayment {
             outpoint,
@@ -504,7 +613,8 @@ pub struct PaymentBuilder {
 
 #[derive(Default, Debug)]
 pub struct Lsps1GetOrderRequestBuilder {
-    order_id: Option<String>,
+    order_id: Option<OrderId>,
+    wait_for_change_seconds: Option<u64>,
 }
 
 impl Lsps1GetOrderRequestBuilder {
@@ -512,16 +622,21 @@ impl Lsps1GetOrderRequestBuilder {
         Self::default()
     }
 
-    pub fn order_id(mut self, order_id: String) -> Self {
+    pub fn order_id(mut self, order_id: OrderId) -> Self {
         self.order_id = Some(order_id);
         self
     }
 
+    pub fn wait_for_change_seconds(mut self, wait_for_change_seconds: Option<u64>) -> Self {
+        self.wait_for_change_seconds = wait_for_change_seconds;
+        self
+    }
+
     pub fn build(self) -> Result<Lsps1GetOrderRequest> {
+        let missing = |field| Error::missing_field("Lsps1GetOrderRequestBuilder", field);
         Ok(Lsps1GetOrderRequest {
-            order_id: self
-                .order_id
-                .context("Missing field 'order_id' in Lsps1GetOrderRequestBuilder")?,
+            order_id: self.order_id.ok_or_else(|| missing("order_id"))?,
+            wait_for_change_seconds: self.wait_for_change_seconds,
         })
     }
 }
@@ -575,17 +690,16 @@ impl PaymentBuilder {
     }
 
     pub fn build(self) -> Result<Payment> {
+        let missing = |field| Error::missing_field("PaymentBuilder", field);
         // Required fields
-        let state = self.state.context("Missing field 'state'")?;
-        let fee_total_sat = self
-            .fee_total_sat
-            .context("Missing field 'fee_total_sat'")?;
+        let state = self.state.ok_or_else(|| missing("state"))?;
+        let fee_total_sat = self.fee_total_sat.ok_or_else(|| missing("fee_total_sat"))?;
         let order_total_sat = self
             .order_total_sat
-            .context("Missing field 'order_total_sat'")?;
+            .ok_or_else(|| missing("order_total_sat"))?;
         let bolt11_invoice = self
             .bolt11_invoice
-            .context("Missing field 'bolt11_invoice'")?;
+            .ok_or_else(|| missing("bolt11_invoice"))?;
 
         // Optional parameters
         let onchain_address = self.onchain_address;
@@ -595,12 +709,16 @@ impl PaymentBuilder {
 
         if onchain_address.is_none() {
             if required_onchain_block_confirmations.is_some() {
-                return Err(anyhow!("'required_onchain_block_confirmations' expected to be null because 'onchain_address' is null"));
+                return Err(Error::invalid_value(
+                    "required_onchain_block_confirmations",
+                    "Expected to be null because 'onchain_address' is null",
+                ));
             }
 
             if minimum_fee_for_0conf.is_some() {
-                return Err(anyhow!(
-                    "'minimum_fee_for_0conf' expected to be null because 'onchain_address' is null"
+                return Err(Error::invalid_value(
+                    "minimum_fee_for_0conf",
+                    "Expected to be null because 'onchain_address' is null",
                 ));
             }
         }
@@ -625,6 +743,86 @@ mod tests {
 
     use super::*;
 
+    fn test_options() -> Lsps1Options {
+        Lsps1OptionsBuilder::new()
+            .min_required_channel_confirmations(0)
+            .min_onchain_payment_confirmations(None)
+            .min_funding_confirms_within_blocks(6)
+            .supports_zero_channel_reserve(false)
+            .max_channel_expiry_blocks(1000)
+            .min_onchain_payment_size_sat(None)
+            .min_channel_balance_sat(SatAmount::new(0))
+            .max_channel_balance_sat(SatAmount::new(100_000))
+            .min_initial_client_balance_sat(SatAmount::new(0))
+            .max_initial_client_balance_sat(SatAmount::new(100_000))
+            .min_initial_lsp_balance_sat(SatAmount::new(0))
+            .max_initial_lsp_balance_sat(SatAmount::new(100_000))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn info_response_includes_extra_fields() {
+        let mut extra_fields = serde_json::Map::new();
+        extra_fields.insert("support_email".to_string(), "support@lsp.example".into());
+
+        let response = Lsps1InfoResponseBuilder::new()
+            .options(test_options())
+            .website(Some("https://lsp.example".to_string()))
+            .extra_fields(extra_fields)
+            .build()
+            .unwrap();
+
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["website"], "https://lsp.example");
+        assert_eq!(value["support_email"], "support@lsp.example");
+    }
+
+    #[test]
+    fn info_response_refuses_to_overwrite_spec_fields() {
+        for field in LSPS1_GET_INFO_FIELDS {
+            let mut extra_fields = serde_json::Map::new();
+            extra_fields.insert(field.to_string(), serde_json::Value::Null);
+
+            let err = Lsps1InfoResponseBuilder::new()
+                .options(test_options())
+                .extra_fields(extra_fields)
+                .build()
+                .unwrap_err();
+            assert!(err.to_string().contains(field));
+        }
+    }
+
+    #[test]
+    fn info_response_refuses_limits_that_can_never_be_met() {
+        let lsp_extensions = LspExtensions {
+            max_open_orders_per_peer: Some(0),
+            ..Default::default()
+        };
+        let err = Lsps1InfoResponseBuilder::new()
+            .options(test_options())
+            .lsp_extensions(Some(lsp_extensions))
+            .build()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("max_open_orders_per_peer"));
+
+        let lsp_extensions = LspExtensions {
+            max_open_orders_per_peer: Some(5),
+            long_poll_supported: Some(true),
+            ..Default::default()
+        };
+        let response = Lsps1InfoResponseBuilder::new()
+            .options(test_options())
+            .lsp_extensions(Some(lsp_extensions))
+            .build()
+            .unwrap();
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(
+            value["lsp_extensions"],
+            serde_json::json!({"max_open_orders_per_peer" : 5, "long_poll_supported" : true})
+        );
+    }
+
     #[test]
     fn options_cannot_be_constructed_if_required_parameters_are_missing() {
         let _ = Lsps1OptionsBuilder::new()
@@ -632,6 +830,57 @@ mod tests {
             .expect_err("Should fail because a required parameter is missing");
     }
 
+    #[test]
+    fn missing_fields_are_reported_by_name() {
+        let err = Lsps1OptionsBuilder::new()
+            .min_required_channel_confirmations(6)
+            .supports_zero_channel_reserve(false)
+            .min_funding_confirms_within_blocks(6)
+            .min_channel_balance_sat(SatAmount::new(50_001))
+            .max_channel_balance_sat(SatAmount::new(100_001))
+            .min_initial_client_balance_sat(SatAmount::new(0))
+            .max_initial_client_balance_sat(SatAmount::new(1))
+            .min_initial_lsp_balance_sat(SatAmount::new(2))
+            .max_initial_lsp_balance_sat(SatAmount::new(100_003))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            Error::MissingField {
+                builder: "Lsps1OptionsBuilder",
+                field: "max_channel_expiry_blocks"
+            }
+        );
+
+        let err = PaymentBuilder::new()
+            .state(PaymentState::ExpectPayment)
+            .fee_total_sat(SatAmount::new(1_000))
+            .order_total_sat(SatAmount::new(1_000))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Missing field 'bolt11_invoice' in PaymentBuilder"
+        );
+    }
+
+    #[test]
+    fn onchain_fields_require_an_address() {
+        let err = PaymentBuilder::new()
+            .state(PaymentState::ExpectPayment)
+            .fee_total_sat(SatAmount::new(1_000))
+            .order_total_sat(SatAmount::new(1_000))
+            .bolt11_invoice("lnbc".to_string())
+            .required_onchain_block_confirmations(6)
+            .build()
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::InvalidValue { field, .. } if field == "required_onchain_block_confirmations"),
+            "{:?}",
+            err
+        );
+    }
+
     #[test]
     fn options_can_be_constructed_when_all_params_are_specified() {
         // I picked unique values on each field to test that I am not switching
@@ -720,7 +969,8 @@ mod tests {
             .build()
             .expect_err("Should fail because max_channel_balance_sat < min_channel_balance_sat");
 
-        assert!(format!("{:?}", error).contains("channel_balance_sat"))
+        assert!(format!("{:?}", error).contains("channel_balance_sat"));
+        assert!(matches!(error, Error::RangeViolation { .. }), "{:?}", error);
     }
 
     #[test]
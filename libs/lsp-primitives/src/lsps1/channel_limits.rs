@@ -0,0 +1,96 @@
+//! Lower bounds on the size of an ordered channel
+//!
+//! An output below the dust limit isn't relayed and a channel that is
+//! barely larger than its reserve can't forward payments. Such orders
+//! should be refused before the LSP tries to open the channel.
+use crate::lsps0::common_schemas::{Network, SatAmount};
+
+/// The dust limit of bitcoind's default relay policy
+///
+/// Core Lightning uses it as the minimum `dust_limit_satoshis`
+pub const DEFAULT_DUST_LIMIT_SAT: u64 = 546;
+
+/// The reserve that is expected unless both peers agree on zero reserve
+///
+/// BOLT #2 recommends 1% of the capacity
+pub const CHANNEL_RESERVE_PERCENT: u64 = 1;
+
+/// Returns the dust limit of channels on `network`
+///
+/// Every network uses the default relay policy of bitcoind
+pub fn dust_limit_sat(network: &Network) -> SatAmount {
+    match network {
+        Network::Bitcoin | Network::Testnet | Network::Signet | Network::Regtest => {
+            SatAmount::new(DEFAULT_DUST_LIMIT_SAT)
+        }
+        _ => SatAmount::new(DEFAULT_DUST_LIMIT_SAT),
+    }
+}
+
+/// Returns the reserve that is expected in a channel of `capacity`
+///
+/// The reserve is rounded up to the next sat
+pub fn expected_reserve_sat(capacity: SatAmount, zero_reserve: bool) -> SatAmount {
+    if zero_reserve {
+        return SatAmount::new(0);
+    }
+
+    let reserve = u128::from(capacity.sat_value()) * u128::from(CHANNEL_RESERVE_PERCENT);
+    let reserve = reserve / 100 + u128::from(reserve % 100 != 0);
+    SatAmount::new(reserve as u64)
+}
+
+/// Returns true if `capacity` exceeds the dust limit plus the reserve
+pub fn exceeds_channel_floor(capacity: SatAmount, network: &Network, zero_reserve: bool) -> bool {
+    let dust = u128::from(dust_limit_sat(network).sat_value());
+    let reserve = u128::from(expected_reserve_sat(capacity, zero_reserve).sat_value());
+    u128::from(capacity.sat_value()) > dust + reserve
+}
+
+/// Returns the smallest capacity that exceeds the dust limit plus the reserve
+pub fn min_channel_capacity_sat(network: &Network, zero_reserve: bool) -> SatAmount {
+    // The reserve grows by 1 sat per 100 sat of capacity.
+    // This takes a few iterations at most
+    let mut capacity = dust_limit_sat(network).sat_value() + 1;
+    while !exceeds_channel_floor(SatAmount::new(capacity), network, zero_reserve) {
+        capacity += 1;
+    }
+    SatAmount::new(capacity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reserve_is_rounded_up() {
+        assert_eq!(
+            expected_reserve_sat(SatAmount::new(100_000), false),
+            SatAmount::new(1_000)
+        );
+        assert_eq!(
+            expected_reserve_sat(SatAmount::new(100_001), false),
+            SatAmount::new(1_001)
+        );
+        assert_eq!(
+            expected_reserve_sat(SatAmount::new(100_000), true),
+            SatAmount::new(0)
+        );
+    }
+
+    #[test]
+    fn min_capacity_exceeds_dust_and_reserve() {
+        let network = Network::Regtest;
+
+        // 553 sat keeps a reserve of 6 sat and 547 sat exceed the dust limit
+        let floor = min_channel_capacity_sat(&network, false);
+        assert_eq!(floor, SatAmount::new(553));
+        assert!(exceeds_channel_floor(floor, &network, false));
+        assert!(!exceeds_channel_floor(SatAmount::new(552), &network, false));
+
+        // Without reserve the capacity only has to exceed the dust limit
+        let floor = min_channel_capacity_sat(&network, true);
+        assert_eq!(floor, SatAmount::new(547));
+        assert!(!exceeds_channel_floor(SatAmount::new(546), &network, true));
+    }
+}
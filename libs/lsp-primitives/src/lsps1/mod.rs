@@ -1,3 +1,5 @@
 pub mod builders;
+pub mod channel_limits;
+pub mod receipt;
 pub mod schema;
 pub mod util;
@@ -0,0 +1,136 @@
+//! Receipts that prove a client purchased a channel
+//!
+//! When an order completes the LSP signs a [`ReceiptPayload`] with its
+//! node key using lightningd's `signmessage`. Anyone who knows the node
+//! id of the LSP can verify the receipt using `checkmessage`.
+//!
+//! The signature covers the exact bytes of the payload. The payload is
+//! serialized as canonical JSON: the keys are sorted and there is no
+//! whitespace.
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::lsps0::common_schemas::{IsoDatetime, Outpoint, PublicKey, SatAmount};
+use crate::lsps0::parameter_validation::ExpectedFields;
+
+/// Extension: `lsps1.get_order` includes a receipt once the order completed
+///
+/// See [`Lsps1CreateOrderResponse::receipt`](crate::lsps1::schema::Lsps1CreateOrderResponse::receipt)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct OrderReceipt {
+    /// The canonical JSON of a [`ReceiptPayload`]
+    pub payload: String,
+    /// The signature of `payload` by the node key of the LSP
+    pub zbase_signature: String,
+}
+
+impl ExpectedFields for OrderReceipt {
+    fn expected_fields() -> Vec<String> {
+        vec!["payload".to_string(), "zbase_signature".to_string()]
+    }
+}
+
+/// The fields of a completed order that are covered by the signature
+///
+/// The fields are declared in alphabetical order. This is the order
+/// in which they are serialized.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiptPayload {
+    /// The sum of `lsp_balance_sat` and `client_balance_sat`
+    pub capacity: SatAmount,
+    pub client_node_id: PublicKey,
+    pub completed_at: IsoDatetime,
+    pub fee_total_sat: SatAmount,
+    pub funding_outpoint: Outpoint,
+    pub order_id: Uuid,
+}
+
+impl ReceiptPayload {
+    /// Returns the message that is signed by the LSP
+    pub fn to_canonical_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|err| Error::invalid_value("receipt-payload", err))
+    }
+
+    /// Parses a payload that was signed by the LSP
+    ///
+    /// Fails if the payload isn't in canonical form. Two different
+    /// messages could otherwise describe the same receipt.
+    pub fn from_canonical_json(payload: &str) -> Result<Self> {
+        let parsed: Self =
+            serde_json::from_str(payload).map_err(|err| Error::parse("receipt-payload", err))?;
+        if parsed.to_canonical_json()? != payload {
+            return Err(Error::parse("receipt-payload", "Not in canonical form"));
+        }
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn payload() -> ReceiptPayload {
+        ReceiptPayload {
+            capacity: SatAmount::new(1_000_000),
+            client_node_id: PublicKey::from_hex(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+            completed_at: IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap(),
+            fee_total_sat: SatAmount::new(1_510),
+            funding_outpoint: Outpoint::from_str(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1",
+            )
+            .unwrap(),
+            order_id: Uuid::from_str("bb4b5d0a-8334-49d8-9463-90a6d413af7c").unwrap(),
+        }
+    }
+
+    #[test]
+    fn payload_is_serialized_with_sorted_keys() {
+        let json = payload().to_canonical_json().unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"capacity":"1000000","#,
+                r#""client_node_id":"0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798","#,
+                r#""completed_at":"2023-11-14T22:13:20.000Z","#,
+                r#""fee_total_sat":"1510","#,
+                r#""funding_outpoint":"4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1","#,
+                r#""order_id":"bb4b5d0a-8334-49d8-9463-90a6d413af7c"}"#
+            )
+        );
+        assert_eq!(
+            ReceiptPayload::from_canonical_json(&json).unwrap(),
+            payload()
+        );
+    }
+
+    #[test]
+    fn non_canonical_payload_is_rejected() {
+        let json = payload().to_canonical_json().unwrap();
+
+        // The same fields with different whitespace or key order
+        let pretty = serde_json::to_string_pretty(&payload()).unwrap();
+        let err = ReceiptPayload::from_canonical_json(&pretty).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                what: "receipt-payload",
+                ..
+            }
+        ));
+        let (capacity, rest) = json[1..json.len() - 1].split_once(',').unwrap();
+        let reordered = format!("{{{},{}}}", rest, capacity);
+        assert!(ReceiptPayload::from_canonical_json(&reordered).is_err());
+
+        // Fields that aren't covered by the receipt
+        let extended = json.replacen('{', r#"{"bonus":true,"#, 1);
+        assert!(ReceiptPayload::from_canonical_json(&extended).is_err());
+    }
+}
@@ -1,19 +1,215 @@
 use crate::json_rpc::NoParams;
-use crate::lsps0::common_schemas::{FeeRate, IsoDatetime, OnchainAddress, Outpoint, SatAmount};
-use crate::lsps0::parameter_validation::ExpectedFields;
-use serde::{Deserialize, Serialize};
+use crate::lsps0::common_schemas::{
+    FeeRate, IsoDatetime, Network, NetworkCheckable, OnchainAddress, Outpoint, SatAmount,
+    ShortChannelId, TransactionId,
+};
+use crate::lsps0::parameter_validation::{nested_fields, ExpectedFields};
+use crate::lsps1::receipt::OrderReceipt;
+use crate::redact::{redact_address, redact_invoice, redact_token};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
 use uuid::Uuid;
 
 pub type Lsps1InfoRequest = NoParams;
 
+/// Extension that allows `lsps1.get_order` to wait until the order changes
+///
+/// See [`Lsps1GetOrderRequest::wait_for_change_seconds`]
+pub const EXTENSION_GET_ORDER_WAIT_FOR_CHANGE: &str = "get_order_wait_for_change";
+
+/// Extension that lowers a `channel_expiry_blocks` beyond the maximum
+///
+/// See [`Lsps1CreateOrderResponse::clamped_fields`]
+pub const EXTENSION_CLAMP_CHANNEL_EXPIRY: &str = "clamp_channel_expiry";
+
+/// Extension that adds a signed receipt to completed orders
+///
+/// See [`Lsps1CreateOrderResponse::receipt`]
+pub const EXTENSION_ORDER_RECEIPT: &str = "order_receipt";
+
+/// Extension that lets the client request a channel type
+///
+/// See [`Lsps1CreateOrderRequest::channel_type`]
+pub const EXTENSION_CHANNEL_TYPE: &str = "channel_type";
+
+/// Extension that lets privileged clients pick the wallet that funds the channel
+///
+/// See [`Lsps1CreateOrderRequest::funding_source`]
+pub const EXTENSION_FUNDING_SOURCE: &str = "funding_source";
+
+/// Extension that tells the client when it can use the channel of an order
+///
+/// See [`Channel::scid_alias`] and [`Channel::usable`]
+pub const EXTENSION_CHANNEL_USABILITY: &str = "channel_usability";
+
+/// The server never waits longer than this for an order to change
+pub const MAX_WAIT_FOR_CHANGE_SECONDS: u64 = 60;
+
+/// Maximum length of [`Lsps1CreateOrderRequest::token`] in bytes
+pub const MAX_TOKEN_LENGTH: usize = 512;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps1GetInfoResponse {
     pub options: Lsps1Options,
+    /// Extensions to LSPS1 that are supported by the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    /// Where the client can find contact and support information
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub website: Option<String>,
+    /// Extension: the network of the LSP. E.g: `bitcoin` or `regtest`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schemars", schemars(with = "Option<String>"))]
+    pub network: Option<Network>,
+    /// Extension: the limits and optional features of the server
+    ///
+    /// Use [`Lsps1GetInfoResponse::lsp_extensions`] to read it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsp_extensions: Option<LspExtensions>,
+    /// Fields that are not part of the spec
+    #[serde(flatten)]
+    pub extra_fields: serde_json::Map<String, serde_json::Value>,
     // Prevents struct initialization. Use Lsps1InfoResponseBuilder instead
 }
 
+/// Fields of [`Lsps1GetInfoResponse`] that cannot be set as extra fields
+pub const LSPS1_GET_INFO_FIELDS: [&str; 5] = [
+    "options",
+    "extensions",
+    "website",
+    "network",
+    "lsp_extensions",
+];
+
+/// Unknown top-level fields are collected in `extra_fields` when parsing.
+/// A strict client reports them as unexpected instead
+impl ExpectedFields for Lsps1GetInfoResponse {
+    fn expected_fields() -> Vec<String> {
+        let mut fields = vec![
+            "extensions".to_string(),
+            "website".to_string(),
+            "network".to_string(),
+            "lsp_extensions".to_string(),
+        ];
+        fields.extend(nested_fields::<Lsps1Options>("options"));
+        fields.extend(nested_fields::<LspExtensions>("lsp_extensions"));
+        fields
+    }
+}
+
+/// The limits and optional features an LSP advertises in `lsps1.get_info`
+///
+/// Every field is optional. An absent limit is unknown, which doesn't
+/// mean there is no limit. An absent feature must not be used.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct LspExtensions {
+    /// The number of orders a peer can have that are not completed or failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_open_orders_per_peer: Option<u32>,
+    /// The number of LSPS1 requests a peer can make per minute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    /// The server can check an order without creating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_run_supported: Option<bool>,
+    /// The client can cancel an order that hasn't been paid yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel_supported: Option<bool>,
+    /// See [`EXTENSION_GET_ORDER_WAIT_FOR_CHANGE`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_poll_supported: Option<bool>,
+}
+
+impl ExpectedFields for LspExtensions {
+    fn expected_fields() -> Vec<String> {
+        vec![
+            "max_open_orders_per_peer".to_string(),
+            "requests_per_minute".to_string(),
+            "dry_run_supported".to_string(),
+            "cancel_supported".to_string(),
+            "long_poll_supported".to_string(),
+        ]
+    }
+}
+
+impl LspExtensions {
+    /// Fails if a limit can never be met
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        if self.max_open_orders_per_peer == Some(0) {
+            return Err(crate::Error::invalid_value(
+                "max_open_orders_per_peer",
+                "Must be positive",
+            ));
+        }
+        if self.requests_per_minute == Some(0) {
+            return Err(crate::Error::invalid_value(
+                "requests_per_minute",
+                "Must be positive",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn supports_dry_run(&self) -> bool {
+        self.dry_run_supported == Some(true)
+    }
+
+    pub fn supports_cancel(&self) -> bool {
+        self.cancel_supported == Some(true)
+    }
+
+    pub fn supports_long_poll(&self) -> bool {
+        self.long_poll_supported == Some(true)
+    }
+}
+
+impl Lsps1GetInfoResponse {
+    /// Returns the limits and optional features of the LSP
+    ///
+    /// Servers that don't advertise `lsp_extensions` might still list
+    /// [`EXTENSION_GET_ORDER_WAIT_FOR_CHANGE`] in `extensions`. An invalid
+    /// `lsp_extensions` is ignored, so the client doesn't rely on it.
+    pub fn lsp_extensions(&self) -> LspExtensions {
+        let mut lsp_extensions = match &self.lsp_extensions {
+            Some(lsp_extensions) if lsp_extensions.validate().is_ok() => lsp_extensions.clone(),
+            _ => LspExtensions::default(),
+        };
+        if lsp_extensions.long_poll_supported.is_none()
+            && self
+                .extensions
+                .iter()
+                .any(|e| e == EXTENSION_GET_ORDER_WAIT_FOR_CHANGE)
+        {
+            lsp_extensions.long_poll_supported = Some(true);
+        }
+        lsp_extensions
+    }
+}
+
+/// Checks that the LSP runs on `network`
+///
+/// LSPs that don't advertise their network are accepted.
+impl NetworkCheckable for Lsps1GetInfoResponse {
+    fn require_network(&self, network: &Network) -> Result<(), crate::Error> {
+        match self.network {
+            Some(lsp_network) if lsp_network != *network => Err(crate::Error::invalid_value(
+                "network",
+                format!(
+                    "Network mismatch: Expected {} but the LSP runs on {}",
+                    network, lsp_network
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// Options returned when calling lsps1.info
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps1Options {
     pub min_required_channel_confirmations: u16,
     pub min_funding_confirms_within_blocks: u16,
@@ -29,7 +225,27 @@ pub struct Lsps1Options {
     pub max_channel_balance_sat: SatAmount,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl ExpectedFields for Lsps1Options {
+    fn expected_fields() -> Vec<String> {
+        vec![
+            "min_required_channel_confirmations".to_string(),
+            "min_funding_confirms_within_blocks".to_string(),
+            "min_onchain_payment_confirmations".to_string(),
+            "supports_zero_channel_reserve".to_string(),
+            "min_onchain_payment_size_sat".to_string(),
+            "max_channel_expiry_blocks".to_string(),
+            "min_initial_client_balance_sat".to_string(),
+            "max_initial_client_balance_sat".to_string(),
+            "min_initial_lsp_balance_sat".to_string(),
+            "max_initial_lsp_balance_sat".to_string(),
+            "min_channel_balance_sat".to_string(),
+            "max_channel_balance_sat".to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps1CreateOrderRequest {
     pub lsp_balance_sat: SatAmount,
     pub client_balance_sat: SatAmount,
@@ -39,6 +255,52 @@ pub struct Lsps1CreateOrderRequest {
     pub token: Option<String>,
     pub refund_onchain_address: Option<OnchainAddress>,
     pub announce_channel: bool,
+    /// Extension: the client doesn't require the LSP to keep a reserve
+    ///
+    /// Only honored if the LSP `supports_zero_channel_reserve`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zero_channel_reserve: Option<bool>,
+    /// Extension: the features of the channel the client wants
+    ///
+    /// Only honored if the LSP advertises [`EXTENSION_CHANNEL_TYPE`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_type: Option<Vec<ChannelTypeFeature>>,
+    /// Extension: the label of the funding source that funds the channel
+    ///
+    /// Only honored if the `token` may select the source. See
+    /// [`EXTENSION_FUNDING_SOURCE`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub funding_source: Option<String>,
+}
+
+impl fmt::Debug for Lsps1CreateOrderRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps1CreateOrderRequest")
+            .field("lsp_balance_sat", &self.lsp_balance_sat)
+            .field("client_balance_sat", &self.client_balance_sat)
+            .field(
+                "funding_confirms_within_blocks",
+                &self.funding_confirms_within_blocks,
+            )
+            .field(
+                "required_channel_confirmations",
+                &self.required_channel_confirmations,
+            )
+            .field("channel_expiry_blocks", &self.channel_expiry_blocks)
+            .field("token", &self.token.as_deref().map(redact_token))
+            .field(
+                "refund_onchain_address",
+                &self
+                    .refund_onchain_address
+                    .as_ref()
+                    .map(|a| redact_address(&a.to_string())),
+            )
+            .field("announce_channel", &self.announce_channel)
+            .field("zero_channel_reserve", &self.zero_channel_reserve)
+            .field("channel_type", &self.channel_type)
+            .field("funding_source", &self.funding_source)
+            .finish()
+    }
 }
 
 impl ExpectedFields for Lsps1CreateOrderRequest {
@@ -52,19 +314,106 @@ impl ExpectedFields for Lsps1CreateOrderRequest {
             "token".to_string(),
             "refund_onchain_address".to_string(),
             "announce_channel".to_string(),
+            "zero_channel_reserve".to_string(),
+            "channel_type".to_string(),
+            "funding_source".to_string(),
         ]
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Identifies an order of a server
+///
+/// An order id is a uuid encoded as a lowercase string with hyphens.
+/// E.g: `bb4b5d0a-8334-49d8-9463-90a6d413af7c`. Other encodings such as
+/// uppercase, braces or a `urn:uuid:`-prefix are rejected rather than
+/// normalized. Servers compare order ids as strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OrderId(Uuid);
+
+impl OrderId {
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for OrderId {
+    fn from(uuid: Uuid) -> Self {
+        Self(uuid)
+    }
+}
+
+impl From<OrderId> for Uuid {
+    fn from(order_id: OrderId) -> Self {
+        order_id.0
+    }
+}
+
+impl FromStr for OrderId {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| crate::Error::parse("order_id", reason);
+        if s.starts_with('{') || s.ends_with('}') {
+            return Err(invalid(format!("remove the braces from '{}'", s)));
+        }
+        if s.to_ascii_lowercase().starts_with("urn:") {
+            return Err(invalid(format!(
+                "remove the 'urn:uuid:'-prefix from '{}'",
+                s
+            )));
+        }
+
+        let uuid =
+            Uuid::parse_str(s).map_err(|e| invalid(format!("'{}' is not a UUID: {}", s, e)))?;
+        let canonical = uuid.hyphenated().to_string();
+        if canonical == s {
+            Ok(Self(uuid))
+        } else if canonical == s.to_ascii_lowercase() {
+            Err(invalid(format!("use lowercase. E.g: '{}'", canonical)))
+        } else {
+            Err(invalid(format!("use hyphens. E.g: '{}'", canonical)))
+        }
+    }
+}
+
+impl fmt::Display for OrderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.hyphenated())
+    }
+}
+
+impl Serialize for OrderId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let s: String = Deserialize::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(|e| Error::custom(format!("{:#}", e)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps1CreateOrderResponse {
-    pub order_id: Uuid,
+    pub order_id: OrderId,
     pub lsp_balance_sat: SatAmount,
     pub client_balance_sat: SatAmount,
     pub funding_confirms_within_blocks: u16,
     pub required_channel_confirmations: u16,
     pub channel_expiry_blocks: u32,
-    pub token: String,
+    /// The token as provided in the request. Absent if the client didn't provide one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
     pub announce_channel: bool,
     pub created_at: IsoDatetime,
     pub expires_at: IsoDatetime,
@@ -72,9 +421,86 @@ pub struct Lsps1CreateOrderResponse {
 
     pub payment: Payment,
     pub channel: Option<Channel>,
+
+    /// Extension: only included if the server enables extensions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund: Option<Refund>,
+
+    /// Extension: the requested fields that the server lowered to its maximum
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clamped_fields: Vec<String>,
+
+    /// Extension: proof that the LSP completed the order. Signed by the LSP's node key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<OrderReceipt>,
+
+    /// Extension: the channel type the server accepted
+    ///
+    /// Absent if the client didn't request a channel type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_type: Option<Vec<ChannelTypeFeature>>,
+}
+
+impl ExpectedFields for Lsps1CreateOrderResponse {
+    fn expected_fields() -> Vec<String> {
+        let mut fields = vec![
+            "order_id".to_string(),
+            "lsp_balance_sat".to_string(),
+            "client_balance_sat".to_string(),
+            "funding_confirms_within_blocks".to_string(),
+            "required_channel_confirmations".to_string(),
+            "channel_expiry_blocks".to_string(),
+            "token".to_string(),
+            "announce_channel".to_string(),
+            "created_at".to_string(),
+            "expires_at".to_string(),
+            "order_state".to_string(),
+            "channel".to_string(),
+            "refund".to_string(),
+            "clamped_fields".to_string(),
+            "receipt".to_string(),
+            "channel_type".to_string(),
+        ];
+        fields.extend(nested_fields::<Payment>("payment"));
+        fields.extend(nested_fields::<Channel>("channel"));
+        fields.extend(nested_fields::<Refund>("refund"));
+        fields.extend(nested_fields::<OrderReceipt>("receipt"));
+        fields
+    }
+}
+
+impl fmt::Debug for Lsps1CreateOrderResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps1CreateOrderResponse")
+            .field("order_id", &self.order_id)
+            .field("lsp_balance_sat", &self.lsp_balance_sat)
+            .field("client_balance_sat", &self.client_balance_sat)
+            .field(
+                "funding_confirms_within_blocks",
+                &self.funding_confirms_within_blocks,
+            )
+            .field(
+                "required_channel_confirmations",
+                &self.required_channel_confirmations,
+            )
+            .field("channel_expiry_blocks", &self.channel_expiry_blocks)
+            .field("token", &self.token.as_deref().map(redact_token))
+            .field("announce_channel", &self.announce_channel)
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .field("order_state", &self.order_state)
+            .field("payment", &self.payment)
+            .field("channel", &self.channel)
+            .field("refund", &self.refund)
+            .field("clamped_fields", &self.clamped_fields)
+            .field("receipt", &self.receipt)
+            .field("channel_type", &self.channel_type)
+            .finish()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum OrderState {
     #[serde(rename = "CREATED")]
     Created,
@@ -85,6 +511,7 @@ pub enum OrderState {
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum PaymentState {
     #[serde(rename = "EXPECT_PAYMENT")]
     ExpectPayment,
@@ -96,14 +523,92 @@ pub enum PaymentState {
     Refunded,
 }
 
+/// A feature of the channel type that a client can request
+///
+/// This is not part of the LSPS1 spec. See [`EXTENSION_CHANNEL_TYPE`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ChannelTypeFeature {
+    /// `option_anchors_zero_fee_htlc_tx`
+    #[serde(rename = "anchors")]
+    Anchors,
+    /// `option_scid_alias`: the channel is only known by an alias
+    #[serde(rename = "scid_alias")]
+    ScidAlias,
+    /// `option_zeroconf`: the channel is usable before the funding transaction confirms
+    #[serde(rename = "zeroconf")]
+    Zeroconf,
+}
+
+impl ChannelTypeFeature {
+    pub const ALL: [ChannelTypeFeature; 3] = [
+        ChannelTypeFeature::Anchors,
+        ChannelTypeFeature::ScidAlias,
+        ChannelTypeFeature::Zeroconf,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Anchors => "anchors",
+            Self::ScidAlias => "scid_alias",
+            Self::Zeroconf => "zeroconf",
+        }
+    }
+
+    /// The even BOLT9 feature bit of the feature
+    ///
+    /// A node that sets the bit or the odd bit next to it supports the feature
+    pub fn feature_bit(&self) -> usize {
+        match self {
+            Self::Anchors => 22,
+            Self::ScidAlias => 46,
+            Self::Zeroconf => 50,
+        }
+    }
+}
+
+impl fmt::Display for ChannelTypeFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ChannelTypeFeature {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.as_str() == s)
+            .ok_or_else(|| {
+                crate::Error::parse(
+                    "channel_type",
+                    format!("'{}' is not one of anchors, scid_alias or zeroconf", s),
+                )
+            })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct OnchainPayment {
     pub outpoint: String,
     pub sat: SatAmount,
     pub confirmed: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl ExpectedFields for OnchainPayment {
+    fn expected_fields() -> Vec<String> {
+        vec![
+            "outpoint".to_string(),
+            "sat".to_string(),
+            "confirmed".to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Payment {
     pub state: PaymentState,
     pub fee_total_sat: SatAmount,
@@ -117,21 +622,130 @@ pub struct Payment {
     pub onchain_payment: Option<OnchainPayment>,
 }
 
+impl ExpectedFields for Payment {
+    fn expected_fields() -> Vec<String> {
+        let mut fields = vec![
+            "state".to_string(),
+            "fee_total_sat".to_string(),
+            "order_total_sat".to_string(),
+            "bolt11_invoice".to_string(),
+            "onchain_address".to_string(),
+            "min_onchain_payment_confirmations".to_string(),
+            "min_fee_for_0conf".to_string(),
+            "onchain_payment".to_string(),
+        ];
+        fields.extend(nested_fields::<OnchainPayment>("onchain_payment"));
+        fields
+    }
+}
+
+impl fmt::Debug for Payment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Payment")
+            .field("state", &self.state)
+            .field("fee_total_sat", &self.fee_total_sat)
+            .field("order_total_sat", &self.order_total_sat)
+            .field("bolt11_invoice", &redact_invoice(&self.bolt11_invoice))
+            .field(
+                "onchain_address",
+                &self
+                    .onchain_address
+                    .as_ref()
+                    .map(|a| redact_address(&a.to_string())),
+            )
+            .field(
+                "min_onchain_payment_confirmations",
+                &self.min_onchain_payment_confirmations,
+            )
+            .field("min_fee_for_0conf", &self.min_fee_for_0conf)
+            .field("onchain_payment", &self.onchain_payment)
+            .finish()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Channel {
     pub funded_at: IsoDatetime,
     pub funding_outpoint: Outpoint,
     pub expires_at: IsoDatetime,
+    /// Extension: the alias the client can use to route through the channel
+    ///
+    /// A zero-conf channel can be used with its alias before it confirms.
+    /// See [`EXTENSION_CHANNEL_USABILITY`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scid_alias: Option<ShortChannelId>,
+    /// Extension: the channel can forward payments right now
+    ///
+    /// A channel that awaits confirmations isn't usable. See
+    /// [`EXTENSION_CHANNEL_USABILITY`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usable: Option<bool>,
+}
+
+impl ExpectedFields for Channel {
+    fn expected_fields() -> Vec<String> {
+        vec![
+            "funded_at".to_string(),
+            "funding_outpoint".to_string(),
+            "expires_at".to_string(),
+            "scid_alias".to_string(),
+            "usable".to_string(),
+        ]
+    }
+}
+
+/// Details about the refund of a failed order
+///
+/// This is not part of the LSPS1 spec. It is an extension
+/// that allows the client to track the refund.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Refund {
+    pub txid: TransactionId,
+    pub amount_sat: SatAmount,
+    pub address: OnchainAddress,
+    pub broadcast_at: IsoDatetime,
+}
+
+impl ExpectedFields for Refund {
+    fn expected_fields() -> Vec<String> {
+        vec![
+            "txid".to_string(),
+            "amount_sat".to_string(),
+            "address".to_string(),
+            "broadcast_at".to_string(),
+        ]
+    }
+}
+
+impl fmt::Debug for Refund {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Refund")
+            .field("txid", &self.txid)
+            .field("amount_sat", &self.amount_sat)
+            .field("address", &redact_address(&self.address.to_string()))
+            .field("broadcast_at", &self.broadcast_at)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps1GetOrderRequest {
-    pub order_id: String,
+    pub order_id: OrderId,
+    /// Extension: The server delays the response until the order
+    /// changes or the timeout elapses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_for_change_seconds: Option<u64>,
 }
 
 impl ExpectedFields for Lsps1GetOrderRequest {
     fn expected_fields() -> Vec<String> {
-        vec!["order_id".to_string()]
+        vec![
+            "order_id".to_string(),
+            "wait_for_change_seconds".to_string(),
+        ]
     }
 }
 
@@ -142,6 +756,23 @@ mod test {
 
     use super::*;
 
+    fn get_options_json() -> serde_json::Value {
+        serde_json::json!({
+            "min_required_channel_confirmations": 0,
+            "min_funding_confirms_within_blocks": 12,
+            "min_onchain_payment_confirmations": 1,
+            "supports_zero_channel_reserve": true,
+            "min_onchain_payment_size_sat": null,
+            "max_channel_expiry_blocks": 20160,
+            "min_initial_client_balance_sat": "20000",
+            "max_initial_client_balance_sat": "100000000",
+            "min_initial_lsp_balance_sat": "0",
+            "max_initial_lsp_balance_sat": "100000000",
+            "min_channel_balance_sat": "50000",
+            "max_channel_balance_sat": "100000000"
+        })
+    }
+
     #[test]
     fn deserialize_lsps1_options() {
         // Example is copie pasted from the spec
@@ -206,8 +837,354 @@ mod test {
             token: None,
             refund_onchain_address: Some(onchain),
             announce_channel: false,
+            zero_channel_reserve: None,
+            channel_type: None,
+            funding_source: None,
         };
 
         let _ = serde_json::to_value(request).unwrap();
     }
+
+    fn get_order_response_json() -> serde_json::Value {
+        serde_json::json!({
+            "order_id": "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+            "lsp_balance_sat": "5000000",
+            "client_balance_sat": "2000000",
+            "funding_confirms_within_blocks" : 1,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks": 12,
+            "token": "",
+            "created_at": "2012-04-23T18:25:43.511Z",
+            "expires_at": "2015-01-25T19:29:44.612Z",
+            "announce_channel": true,
+            "order_state": "FAILED",
+            "payment": {
+                "state": "REFUNDED",
+                "fee_total_sat": "8888",
+                "order_total_sat": "2008888",
+                "bolt11_invoice": "lnbc252u1p3aht9ysp580g4633gd2x9lc5al0wd8wx0mpn9748jeyz46kqjrpxn52uhfpjqpp5qgf67tcqmuqehzgjm8mzya90h73deafvr4m5705l5u5l4r05l8cqdpud3h8ymm4w3jhytnpwpczqmt0de6xsmre9cs9w6t5dpcqzpuxqr23ssp5pe7kmh6kpd5e2mz5v6s6w9k4rkhq7zh9r0mtqn0wqp4xqpn5u8cq",
+                "onchain_address": null,
+                "min_onchain_payment_confirmations": null,
+                "min_fee_for_0conf": null,
+                "onchain_payment": null
+            },
+            "channel": null
+        })
+    }
+
+    #[test]
+    fn order_id_is_a_lowercase_hyphenated_uuid() {
+        let json_data = serde_json::json!("bb4b5d0a-8334-49d8-9463-90a6d413af7c");
+        let order_id = serde_json::from_value::<OrderId>(json_data.clone()).unwrap();
+        assert_eq!(
+            order_id.as_uuid(),
+            &Uuid::from_str("bb4b5d0a-8334-49d8-9463-90a6d413af7c").unwrap()
+        );
+        assert_eq!(serde_json::to_value(order_id).unwrap(), json_data);
+
+        let rejected = [
+            ("BB4B5D0A-8334-49D8-9463-90A6D413AF7C", "use lowercase"),
+            ("bb4b5d0a833449d8946390a6d413af7c", "use hyphens"),
+            (
+                "{bb4b5d0a-8334-49d8-9463-90a6d413af7c}",
+                "remove the braces",
+            ),
+            (
+                "urn:uuid:bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+                "remove the 'urn:uuid:'-prefix",
+            ),
+            ("0", "Invalid order_id: '0' is not a UUID"),
+            ("", "Invalid order_id: '' is not a UUID"),
+        ];
+        for (value, message) in rejected {
+            let err = serde_json::from_value::<OrderId>(serde_json::json!(value)).unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", value, err);
+
+            let err = OrderId::from_str(value).unwrap_err();
+            assert!(matches!(
+                err,
+                crate::Error::Parse {
+                    what: "order_id",
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn extensions_are_optional() {
+        let json_data = serde_json::json!({"order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c"});
+        let request = serde_json::from_value::<Lsps1GetOrderRequest>(json_data.clone()).unwrap();
+        assert!(request.wait_for_change_seconds.is_none());
+        assert_eq!(serde_json::to_value(request).unwrap(), json_data);
+
+        let json_data = serde_json::json!({"options" : get_options_json()});
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data.clone()).unwrap();
+        assert!(response.extensions.is_empty());
+        assert_eq!(serde_json::to_value(response).unwrap(), json_data);
+    }
+
+    #[test]
+    fn serialize_extensions() {
+        let json_data = serde_json::json!({
+            "order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+            "wait_for_change_seconds" : 30
+        });
+        let request = serde_json::from_value::<Lsps1GetOrderRequest>(json_data.clone()).unwrap();
+        assert_eq!(request.wait_for_change_seconds, Some(30));
+        assert_eq!(serde_json::to_value(request).unwrap(), json_data);
+
+        let json_data = serde_json::json!({
+            "options" : get_options_json(),
+            "extensions" : [EXTENSION_GET_ORDER_WAIT_FOR_CHANGE]
+        });
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data.clone()).unwrap();
+        assert_eq!(
+            response.extensions,
+            vec![EXTENSION_GET_ORDER_WAIT_FOR_CHANGE]
+        );
+        assert_eq!(serde_json::to_value(response).unwrap(), json_data);
+    }
+
+    #[test]
+    fn lsp_extensions_are_parsed() {
+        let json_data = serde_json::json!({
+            "options" : get_options_json(),
+            "lsp_extensions" : {
+                "max_open_orders_per_peer" : 3,
+                "cancel_supported" : false,
+                "long_poll_supported" : true,
+                "future_limit" : 12
+            }
+        });
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data).unwrap();
+        let lsp_extensions = response.lsp_extensions();
+        assert_eq!(lsp_extensions.max_open_orders_per_peer, Some(3));
+        assert_eq!(lsp_extensions.requests_per_minute, None);
+        assert!(lsp_extensions.supports_long_poll());
+        assert!(!lsp_extensions.supports_cancel());
+        assert!(!lsp_extensions.supports_dry_run());
+    }
+
+    #[test]
+    fn absent_lsp_extensions_fall_back_to_extension_names() {
+        let json_data = serde_json::json!({"options" : get_options_json()});
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data).unwrap();
+        assert!(response.lsp_extensions.is_none());
+        assert_eq!(response.lsp_extensions(), LspExtensions::default());
+
+        let json_data = serde_json::json!({
+            "options" : get_options_json(),
+            "extensions" : [EXTENSION_GET_ORDER_WAIT_FOR_CHANGE]
+        });
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data).unwrap();
+        assert!(response.lsp_extensions().supports_long_poll());
+
+        // An LSP that can't meet its own limits isn't trusted
+        let json_data = serde_json::json!({
+            "options" : get_options_json(),
+            "lsp_extensions" : { "requests_per_minute" : 0, "cancel_supported" : true }
+        });
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data).unwrap();
+        assert_eq!(response.lsp_extensions(), LspExtensions::default());
+    }
+
+    #[test]
+    fn serialize_website_and_extra_fields() {
+        let json_data = serde_json::json!({
+            "options" : get_options_json(),
+            "website" : "https://lsp.example",
+            "support_email" : "support@lsp.example"
+        });
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data.clone()).unwrap();
+        assert_eq!(response.website.as_deref(), Some("https://lsp.example"));
+        assert_eq!(
+            response.extra_fields.get("support_email"),
+            Some(&serde_json::json!("support@lsp.example"))
+        );
+        assert_eq!(serde_json::to_value(response).unwrap(), json_data);
+    }
+
+    #[test]
+    fn info_with_other_network_is_refused() {
+        let json_data = serde_json::json!({
+            "options" : get_options_json(),
+            "network" : "bitcoin"
+        });
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data.clone()).unwrap();
+        assert_eq!(response.network, Some(Network::Bitcoin));
+        assert_eq!(serde_json::to_value(&response).unwrap(), json_data);
+
+        response.require_network(&Network::Bitcoin).unwrap();
+        let err = response.require_network(&Network::Regtest).unwrap_err();
+        assert!(err.to_string().contains("Network mismatch"));
+        assert!(matches!(err, crate::Error::InvalidValue { field, .. } if field == "network"));
+
+        // An LSP that doesn't advertise its network is accepted
+        let json_data = serde_json::json!({"options" : get_options_json()});
+        let response = serde_json::from_value::<Lsps1GetInfoResponse>(json_data).unwrap();
+        assert!(response.network.is_none());
+        response.require_network(&Network::Regtest).unwrap();
+    }
+
+    #[test]
+    fn absent_and_empty_token_are_distinct() {
+        let json_data = get_order_response_json();
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+        assert_eq!(response.token, Some("".to_string()));
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["token"], "");
+
+        let mut json_data = get_order_response_json();
+        json_data.as_object_mut().unwrap().remove("token");
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+        assert!(response.token.is_none());
+        let value = serde_json::to_value(response).unwrap();
+        assert!(value.get("token").is_none());
+    }
+
+    #[test]
+    fn refund_is_optional_in_get_order_response() {
+        let json_data = get_order_response_json();
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+        assert!(response.refund.is_none());
+
+        // The extension is omitted if there is no refund
+        let value = serde_json::to_value(response).unwrap();
+        assert!(value.get("refund").is_none());
+    }
+
+    #[test]
+    fn serialize_refund_in_get_order_response() {
+        let mut json_data = get_order_response_json();
+        json_data["refund"] = serde_json::json!({
+            "txid": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            "amount_sat": "2008888",
+            "address": "32iVBEu4dxkUQk9dJbZUiBiQdmypcEyJRf",
+            "broadcast_at": "2015-01-25T19:29:44.612Z"
+        });
+
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data.clone()).unwrap();
+        let refund = response.refund.as_ref().unwrap();
+        assert_eq!(refund.amount_sat, SatAmount::new(2008888));
+        assert_eq!(
+            refund.address.to_string(),
+            "32iVBEu4dxkUQk9dJbZUiBiQdmypcEyJRf"
+        );
+
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["refund"], json_data["refund"]);
+    }
+
+    #[test]
+    fn serialize_clamped_fields() {
+        let json_data = get_order_response_json();
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+        assert!(response.clamped_fields.is_empty());
+        let value = serde_json::to_value(response).unwrap();
+        assert!(value.get("clamped_fields").is_none());
+
+        let mut json_data = get_order_response_json();
+        json_data["clamped_fields"] = serde_json::json!(["channel_expiry_blocks"]);
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data.clone()).unwrap();
+        assert_eq!(response.clamped_fields, vec!["channel_expiry_blocks"]);
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["clamped_fields"], json_data["clamped_fields"]);
+    }
+
+    #[test]
+    fn serialize_channel_type() {
+        let json_data = get_order_response_json();
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+        assert!(response.channel_type.is_none());
+        let value = serde_json::to_value(response).unwrap();
+        assert!(value.get("channel_type").is_none());
+
+        let mut json_data = get_order_response_json();
+        json_data["channel_type"] = serde_json::json!(["anchors", "zeroconf"]);
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data.clone()).unwrap();
+        assert_eq!(
+            response.channel_type,
+            Some(vec![
+                ChannelTypeFeature::Anchors,
+                ChannelTypeFeature::Zeroconf
+            ])
+        );
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["channel_type"], json_data["channel_type"]);
+
+        // Unknown features are refused
+        let mut json_data = get_order_response_json();
+        json_data["channel_type"] = serde_json::json!(["static_remotekey"]);
+        assert!(serde_json::from_value::<Lsps1GetOrderResponse>(json_data).is_err());
+        assert!("static_remotekey".parse::<ChannelTypeFeature>().is_err());
+        for feature in ChannelTypeFeature::ALL {
+            assert_eq!(feature.as_str().parse::<ChannelTypeFeature>(), Ok(feature));
+        }
+    }
+
+    #[test]
+    fn serialize_channel_usability() {
+        let channel = serde_json::json!({
+            "funded_at" : "2012-04-23T18:25:43.511Z",
+            "funding_outpoint" : "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae:0",
+            "expires_at" : "2012-04-23T18:25:43.511Z",
+        });
+        let mut json_data = get_order_response_json();
+        json_data["channel"] = channel.clone();
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+        let parsed = response.channel.as_ref().unwrap();
+        assert!(parsed.scid_alias.is_none());
+        assert!(parsed.usable.is_none());
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["channel"], channel);
+
+        let mut json_data = get_order_response_json();
+        json_data["channel"] = channel;
+        json_data["channel"]["scid_alias"] = serde_json::json!("8573349x2947x1");
+        json_data["channel"]["usable"] = serde_json::json!(true);
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data.clone()).unwrap();
+        let parsed = response.channel.as_ref().unwrap();
+        assert_eq!(parsed.scid_alias.unwrap().to_string(), "8573349x2947x1");
+        assert_eq!(parsed.usable, Some(true));
+        let value = serde_json::to_value(response).unwrap();
+        assert_eq!(value["channel"], json_data["channel"]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "unredacted-debug"))]
+    fn debug_output_is_redacted() {
+        let token = "my-secret-token";
+        let address = "32iVBEu4dxkUQk9dJbZUiBiQdmypcEyJRf";
+        let request = serde_json::from_value::<Lsps1CreateOrderRequest>(serde_json::json!({
+            "lsp_balance_sat": "5000000",
+            "client_balance_sat": "2000000",
+            "funding_confirms_within_blocks" : 1,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks": 12,
+            "token": token,
+            "refund_onchain_address": address,
+            "announce_channel": true
+        }))
+        .unwrap();
+
+        let debug = format!("{:?}", request);
+        assert!(!debug.contains(token), "{}", debug);
+        assert!(!debug.contains(address), "{}", debug);
+        assert!(debug.contains("<redacted 15 bytes>"), "{}", debug);
+        assert!(debug.contains("32iV…yJRf"), "{}", debug);
+
+        let mut json_data = get_order_response_json();
+        json_data["token"] = serde_json::json!(token);
+        let invoice = json_data["payment"]["bolt11_invoice"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let response = serde_json::from_value::<Lsps1GetOrderResponse>(json_data).unwrap();
+
+        let debug = format!("{:?}", response);
+        assert!(!debug.contains(token), "{}", debug);
+        assert!(!debug.contains(&invoice), "{}", debug);
+        assert!(debug.contains("lnbc252u…<sha256:"), "{}", debug);
+    }
 }
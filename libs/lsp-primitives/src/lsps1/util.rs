@@ -1,6 +1,9 @@
 use crate::json_rpc::ErrorData;
-use crate::lsps1::schema::{Lsps1CreateOrderRequest, Lsps1Options};
-use anyhow::Result;
+use crate::lsps0::bounded_range::{BoundedRange, BoundedRangeError};
+use crate::lsps0::common_schemas::{Network, SatAmount};
+use crate::lsps0::parameter_validation::{validate_free_form_string, ParamValidationError};
+use crate::lsps1::channel_limits::{dust_limit_sat, min_channel_capacity_sat};
+use crate::lsps1::schema::{Lsps1CreateOrderRequest, Lsps1Options, MAX_TOKEN_LENGTH};
 
 use serde::{Deserialize, Serialize};
 
@@ -13,95 +16,217 @@ pub struct Lsps1OptionMismatchError {
 impl From<Lsps1OptionMismatchError> for ErrorData {
     fn from(options_error: Lsps1OptionMismatchError) -> Self {
         match serde_json::to_value(options_error) {
-            Ok(data) => ErrorData {
-                code: 1000,
-                message: "Option mismatch".to_string(),
-                data: Some(data),
-            },
-            Err(e) => ErrorData::internal_error(serde_json::Value::String(format!("{:?}", e))),
+            Ok(data) => ErrorData::option_mismatch(data),
+            Err(e) => ErrorData::internalize(e),
         }
     }
 }
 
+/// How an order that exceeds `max_channel_expiry_blocks` is handled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpiryMode {
+    /// The order is refused with an option_mismatch
+    #[default]
+    Reject,
+    /// The `channel_expiry_blocks` is lowered to the maximum
+    Clamp,
+}
+
 impl Lsps1OptionMismatchError {
-    fn new(property: String, message: String) -> Self {
+    pub fn new(property: String, message: String) -> Self {
         Self { property, message }
     }
 }
 
-impl Lsps1CreateOrderRequest {
-    pub fn validate_options(&self, options: &Lsps1Options) -> Result<(), Lsps1OptionMismatchError> {
-        if self.client_balance_sat < options.min_initial_client_balance_sat {
-            return Err(Lsps1OptionMismatchError::new(
-                "min_initial_client_balance_sat".to_string(), 
-                format!("You've requested client_balance_sat={} but the LSP-server requires at least {}",
-                        self.client_balance_sat,
-                        options.min_initial_client_balance_sat)));
-        }
+impl std::fmt::Display for Lsps1OptionMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.property)
+    }
+}
 
-        if self.client_balance_sat > options.max_initial_client_balance_sat {
-            return Err(Lsps1OptionMismatchError::new(
-                "max_initial_client_balance_sat".to_string(), 
-                format!("You've requested client_balance_sat={} but the LSP-server doesn't allow this value to exceed {}",
-                        self.client_balance_sat,
-                        options.max_initial_client_balance_sat)));
-        }
+impl From<BoundedRangeError> for Lsps1OptionMismatchError {
+    fn from(err: BoundedRangeError) -> Self {
+        Self::new(err.property, err.message)
+    }
+}
 
-        if self.lsp_balance_sat < options.min_initial_lsp_balance_sat {
-            return Err(Lsps1OptionMismatchError::new(
-                    "min_initial_lsp_balance_sat".to_string(),
-                    format!("You've requested a channel with lsp_balance_sat={} but the LSP-server requires at least {}",
-                            self.lsp_balance_sat,
-                            options.min_initial_lsp_balance_sat)));
-        }
+impl Lsps1Options {
+    /// The range of `client_balance_sat` the LSP accepts
+    pub fn client_balance_range(&self) -> Result<BoundedRange<SatAmount>, BoundedRangeError> {
+        BoundedRange::new(
+            "initial_client_balance_sat",
+            self.min_initial_client_balance_sat,
+            self.max_initial_client_balance_sat,
+        )
+    }
 
-        if self.lsp_balance_sat > options.max_initial_lsp_balance_sat {
-            return Err(Lsps1OptionMismatchError::new(
-                    "max_initial_lsp_balance_sat".to_string(),
-                    format!("You've requested a channel with lsp_balance_sat={} but the LSP-server doesn't allow this value to exceed {}",
-                            self.lsp_balance_sat,
-                            options.max_initial_lsp_balance_sat)));
+    /// The range of `lsp_balance_sat` the LSP accepts
+    pub fn lsp_balance_range(&self) -> Result<BoundedRange<SatAmount>, BoundedRangeError> {
+        BoundedRange::new(
+            "initial_lsp_balance_sat",
+            self.min_initial_lsp_balance_sat,
+            self.max_initial_lsp_balance_sat,
+        )
+    }
+
+    /// The range of the channel capacity the LSP accepts
+    pub fn capacity_range(&self) -> Result<BoundedRange<SatAmount>, BoundedRangeError> {
+        BoundedRange::new(
+            "channel_balance_sat",
+            self.min_channel_balance_sat,
+            self.max_channel_balance_sat,
+        )
+    }
+
+    /// Returns the first option that is violated by `order`
+    pub fn validate_order(
+        &self,
+        order: &Lsps1CreateOrderRequest,
+    ) -> Result<(), Lsps1OptionMismatchError> {
+        match self.order_violations(order).into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
+    }
 
-        // Compute the capacity of the channel and validate it
-        // The checked_add returns None on overflow
-        let capacity_option = self.lsp_balance_sat.checked_add(&self.client_balance_sat);
-        let capacity = match capacity_option {
-            Some(c) => c,
-            None => {
-                return Err(Lsps1OptionMismatchError::new(
-                    "max_channel_balance_sat".to_string(),
-                    "Overflow when computing channel_capacity".to_string(),
-                ));
+    /// Returns every option that is violated by `order`
+    ///
+    /// The violations are ordered like the checks in [`Self::validate_order`].
+    /// A client can use it to report all problems with an order at once.
+    pub fn order_violations(
+        &self,
+        order: &Lsps1CreateOrderRequest,
+    ) -> Vec<Lsps1OptionMismatchError> {
+        let mut violations = Vec::new();
+        let mut check = |result: Result<(), BoundedRangeError>| {
+            if let Err(err) = result {
+                violations.push(err.into());
             }
         };
 
-        if capacity < options.min_channel_balance_sat {
-            return Err(Lsps1OptionMismatchError::new(
-                    "min_channel_balance_sat".to_string(),
-                    format!("You've requested a channel with capacity={} but the LSP-server requires at least {}", 
-                            capacity,
-                            options.min_channel_balance_sat
-                    )));
-        };
+        check(
+            self.client_balance_range()
+                .and_then(|r| r.check("client_balance_sat", order.client_balance_sat)),
+        );
+        check(
+            self.lsp_balance_range()
+                .and_then(|r| r.check("lsp_balance_sat", order.lsp_balance_sat)),
+        );
+
+        // The checked_add returns None on overflow
+        let capacity = order
+            .lsp_balance_sat
+            .checked_add(&order.client_balance_sat)
+            .ok_or_else(|| BoundedRangeError {
+                property: "max_channel_balance_sat".to_string(),
+                message: "Overflow when computing channel_capacity".to_string(),
+            });
+        check(capacity.and_then(|c| self.capacity_range()?.check("capacity", c)));
+
+        // Only the upper bound of the expiry and the lower bound of the
+        // confirmation target are advertised
+        check(
+            BoundedRange::new("channel_expiry_blocks", 0, self.max_channel_expiry_blocks)
+                .and_then(|r| r.check("channel_expiry_blocks", order.channel_expiry_blocks)),
+        );
+        check(
+            BoundedRange::new(
+                "funding_confirms_within_blocks",
+                self.min_funding_confirms_within_blocks,
+                u16::MAX,
+            )
+            .and_then(|r| {
+                r.check(
+                    "funding_confirms_within_blocks",
+                    order.funding_confirms_within_blocks,
+                )
+            }),
+        );
+
+        violations
+    }
+}
+
+impl Lsps1CreateOrderRequest {
+    /// Validates all strings that are stored and echoed back by the server
+    pub fn validate_free_form_fields(&self) -> Result<(), ParamValidationError> {
+        if let Some(token) = &self.token {
+            validate_free_form_string("token", token, MAX_TOKEN_LENGTH)?;
+        }
+        Ok(())
+    }
+
+    /// Validates the order against the options using the given [`ExpiryMode`]
+    ///
+    /// Returns the names of the fields that were clamped. The list is
+    /// always empty in [`ExpiryMode::Reject`].
+    pub fn validate_options_with_mode(
+        &mut self,
+        options: &Lsps1Options,
+        mode: ExpiryMode,
+    ) -> Result<Vec<String>, Lsps1OptionMismatchError> {
+        let mut clamped_fields = Vec::new();
+        if mode == ExpiryMode::Clamp
+            && self.channel_expiry_blocks > options.max_channel_expiry_blocks
+        {
+            self.channel_expiry_blocks = options.max_channel_expiry_blocks;
+            clamped_fields.push("channel_expiry_blocks".to_string());
+        }
+
+        options.validate_order(self)?;
+        Ok(clamped_fields)
+    }
+
+    /// Validates the order against the options
+    ///
+    /// See [`Lsps1Options::validate_order`]
+    pub fn validate_options(&self, options: &Lsps1Options) -> Result<(), Lsps1OptionMismatchError> {
+        options.validate_order(self)
+    }
 
-        if capacity > options.max_channel_balance_sat {
+    /// Checks that the channel is usable after the dust limit and reserve
+    ///
+    /// A non-zero `client_balance_sat` must exceed the dust limit. The
+    /// capacity must exceed the dust limit plus the reserve. The reserve
+    /// is only left out if the LSP supports zero-reserve channels and the
+    /// order requests one.
+    pub fn validate_channel_size(
+        &self,
+        network: &Network,
+        options: &Lsps1Options,
+    ) -> Result<(), Lsps1OptionMismatchError> {
+        let dust_limit = dust_limit_sat(network);
+        if self.client_balance_sat.sat_value() != 0 && self.client_balance_sat <= dust_limit {
             return Err(Lsps1OptionMismatchError::new(
-                    "max_channel_balance_sat".to_string(),
-                    format!("You've requested a channel with capacity={} but the LSP-server only allows values up to {}",
-                            capacity,
-                            options.max_channel_balance_sat
-                            )));
+                "min_initial_client_balance_sat".to_string(),
+                format!(
+                    "You've requested client_balance_sat={} but it must exceed the dust limit of {}",
+                    self.client_balance_sat, dust_limit
+                ),
+            ));
         }
 
-        // Verify the channel_expiry_blocks
-        if self.channel_expiry_blocks > options.max_channel_expiry_blocks {
+        let capacity = self
+            .lsp_balance_sat
+            .checked_add(&self.client_balance_sat)
+            .ok_or_else(|| {
+                Lsps1OptionMismatchError::new(
+                    "max_channel_balance_sat".to_string(),
+                    "Overflow when computing channel_capacity".to_string(),
+                )
+            })?;
+
+        let zero_reserve =
+            options.supports_zero_channel_reserve && self.zero_channel_reserve == Some(true);
+        let min_capacity = min_channel_capacity_sat(network, zero_reserve);
+        if capacity < min_capacity {
             return Err(Lsps1OptionMismatchError::new(
-                    "max_channel_expiry_blocks".to_string(),
-                    format!("You've requested to lease a channel for channel_expiry_block={} but the LSP-server only allows max_channel_expiry_blocks={}",
-                            self.channel_expiry_blocks,
-                            options.max_channel_expiry_blocks
-                            )));
+                "min_channel_balance_sat".to_string(),
+                format!(
+                    "You've requested a channel with capacity={} but it must be at least {} to exceed the dust limit and reserve",
+                    capacity, min_capacity
+                ),
+            ));
         }
         Ok(())
     }
@@ -110,14 +235,18 @@ impl Lsps1CreateOrderRequest {
 #[cfg(test)]
 mod tests {
 
-    use crate::lsps0::common_schemas::SatAmount;
+    use super::ExpiryMode;
+    use crate::json_rpc::{ErrorData, LspsErrorReason};
+
+    use crate::lsps0::common_schemas::{Network, SatAmount};
     use crate::lsps1::builders::{Lsps1CreateOrderRequestBuilder, Lsps1OptionsBuilder};
+    use crate::lsps1::schema::MAX_TOKEN_LENGTH;
 
     fn get_options_builder() -> Lsps1OptionsBuilder {
         Lsps1OptionsBuilder::new()
             .min_required_channel_confirmations(0)
             .min_onchain_payment_confirmations(None)
-            .min_funding_confirms_within_blocks(10)
+            .min_funding_confirms_within_blocks(6)
             .supports_zero_channel_reserve(true)
             .min_onchain_payment_size_sat(None)
             .max_channel_expiry_blocks(1_000)
@@ -228,4 +357,284 @@ mod tests {
         assert_eq!(err1.property, "min_channel_balance_sat");
         assert_eq!(err2.property, "max_channel_balance_sat");
     }
+
+    #[test]
+    fn funding_target_below_min_is_rejected() {
+        let options = get_options_builder()
+            .min_funding_confirms_within_blocks(10)
+            .build()
+            .unwrap();
+
+        let order = get_order_builder()
+            .funding_confirms_within_blocks(Some(10))
+            .build()
+            .unwrap();
+        options.validate_order(&order).unwrap();
+
+        let order = get_order_builder()
+            .funding_confirms_within_blocks(Some(9))
+            .build()
+            .unwrap();
+        let err = options.validate_order(&order).unwrap_err();
+        assert_eq!(err.property, "min_funding_confirms_within_blocks");
+    }
+
+    #[test]
+    fn ranges_are_built_from_options() {
+        let options = get_options_builder().build().unwrap();
+
+        let range = options.lsp_balance_range().unwrap();
+        assert_eq!(range.min(), SatAmount::new(100_000));
+        assert_eq!(range.max(), SatAmount::new(100_000_000));
+        assert_eq!(
+            range.clamp(SatAmount::new(1)),
+            options.min_initial_lsp_balance_sat
+        );
+        assert!(options
+            .client_balance_range()
+            .unwrap()
+            .contains(&SatAmount::new(0)));
+        assert_eq!(
+            options.capacity_range().unwrap().max(),
+            options.max_channel_balance_sat
+        );
+
+        // Options received from a peer aren't checked by the builder
+        let mut options = options;
+        options.min_channel_balance_sat = SatAmount::new(100_000_001);
+        let order = get_order_builder().build().unwrap();
+        let err = options.validate_order(&order).unwrap_err();
+        assert_eq!(err.property, "min_channel_balance_sat");
+        assert!(err.message.contains("should be less than or equal to"));
+    }
+
+    #[test]
+    fn all_violations_are_reported() {
+        let options = get_options_builder().build().unwrap();
+        let order = get_order_builder()
+            .client_balance_sat(Some(SatAmount::new(1)))
+            .lsp_balance_sat(SatAmount::new(0))
+            .channel_expiry_blocks(1_001)
+            .funding_confirms_within_blocks(Some(1))
+            .build()
+            .unwrap();
+
+        let properties: Vec<String> = options
+            .order_violations(&order)
+            .into_iter()
+            .map(|err| err.property)
+            .collect();
+        assert_eq!(
+            properties,
+            vec![
+                "max_initial_client_balance_sat",
+                "min_initial_lsp_balance_sat",
+                "min_channel_balance_sat",
+                "max_channel_expiry_blocks",
+                "min_funding_confirms_within_blocks",
+            ]
+        );
+
+        // The first violation is returned by validate_order
+        let err = options.validate_order(&order).unwrap_err();
+        assert_eq!(err.property, "max_initial_client_balance_sat");
+        assert_eq!(
+            err.message,
+            "You've requested a channel with client_balance_sat=1 sat but the LSP-server doesn't allow this value to exceed 0 sat"
+        );
+
+        let order = get_order_builder().build().unwrap();
+        assert!(options.order_violations(&order).is_empty());
+    }
+
+    #[test]
+    fn expiry_beyond_max_is_rejected_by_default() {
+        let options = get_options_builder().build().unwrap();
+        let mut order = get_order_builder()
+            .channel_expiry_blocks(1_001)
+            .build()
+            .unwrap();
+
+        let err = order
+            .validate_options_with_mode(&options, ExpiryMode::default())
+            .unwrap_err();
+        assert_eq!(err.property, "max_channel_expiry_blocks");
+        assert_eq!(order.channel_expiry_blocks, 1_001);
+    }
+
+    #[test]
+    fn expiry_beyond_max_is_clamped() {
+        let options = get_options_builder().build().unwrap();
+
+        let mut order = get_order_builder()
+            .channel_expiry_blocks(1_001)
+            .build()
+            .unwrap();
+        let clamped = order
+            .validate_options_with_mode(&options, ExpiryMode::Clamp)
+            .unwrap();
+        assert_eq!(clamped, vec!["channel_expiry_blocks"]);
+        assert_eq!(order.channel_expiry_blocks, 1_000);
+
+        // An order within the options is left as is
+        let mut order = get_order_builder()
+            .channel_expiry_blocks(999)
+            .build()
+            .unwrap();
+        let clamped = order
+            .validate_options_with_mode(&options, ExpiryMode::Clamp)
+            .unwrap();
+        assert!(clamped.is_empty());
+        assert_eq!(order.channel_expiry_blocks, 999);
+
+        // Clamping doesn't hide other mismatches
+        let mut order = get_order_builder()
+            .channel_expiry_blocks(1_001)
+            .lsp_balance_sat(SatAmount::new(0))
+            .build()
+            .unwrap();
+        let err = order
+            .validate_options_with_mode(&options, ExpiryMode::Clamp)
+            .unwrap_err();
+        assert_eq!(err.property, "min_initial_lsp_balance_sat");
+    }
+
+    #[test]
+    fn channel_at_the_floor_is_accepted() {
+        let network = Network::Regtest;
+        let options = get_options_builder()
+            .supports_zero_channel_reserve(false)
+            .build()
+            .unwrap();
+
+        // 553 sat exceed the dust limit of 546 sat plus a reserve of 6 sat
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(553))
+            .build()
+            .unwrap();
+        order.validate_channel_size(&network, &options).unwrap();
+
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(552))
+            .build()
+            .unwrap();
+        let err = order.validate_channel_size(&network, &options).unwrap_err();
+        assert_eq!(err.property, "min_channel_balance_sat");
+
+        // The reserve is kept if the LSP doesn't support zero-reserve channels
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(552))
+            .zero_channel_reserve(Some(true))
+            .build()
+            .unwrap();
+        let err = order.validate_channel_size(&network, &options).unwrap_err();
+        assert_eq!(err.property, "min_channel_balance_sat");
+    }
+
+    #[test]
+    fn zero_reserve_channel_only_exceeds_dust() {
+        let network = Network::Regtest;
+        let options = get_options_builder()
+            .supports_zero_channel_reserve(true)
+            .build()
+            .unwrap();
+
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(547))
+            .zero_channel_reserve(Some(true))
+            .build()
+            .unwrap();
+        order.validate_channel_size(&network, &options).unwrap();
+
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(546))
+            .zero_channel_reserve(Some(true))
+            .build()
+            .unwrap();
+        let err = order.validate_channel_size(&network, &options).unwrap_err();
+        assert_eq!(err.property, "min_channel_balance_sat");
+
+        // Without the request the LSP expects a reserve
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(547))
+            .build()
+            .unwrap();
+        assert!(order.validate_channel_size(&network, &options).is_err());
+    }
+
+    #[test]
+    fn client_balance_must_exceed_dust() {
+        let network = Network::Regtest;
+        let options = get_options_builder().build().unwrap();
+
+        let order = get_order_builder()
+            .client_balance_sat(Some(SatAmount::new(547)))
+            .build()
+            .unwrap();
+        order.validate_channel_size(&network, &options).unwrap();
+
+        let order = get_order_builder()
+            .client_balance_sat(Some(SatAmount::new(546)))
+            .build()
+            .unwrap();
+        let err = order.validate_channel_size(&network, &options).unwrap_err();
+        assert_eq!(err.property, "min_initial_client_balance_sat");
+    }
+
+    #[test]
+    fn option_mismatch_error_data_has_reason() {
+        let options = get_options_builder().build().unwrap();
+        let order = get_order_builder()
+            .lsp_balance_sat(SatAmount::new(0))
+            .build()
+            .unwrap();
+
+        let error: ErrorData = order.validate_options(&options).unwrap_err().into();
+        assert_eq!(error.code, 1000);
+        assert_eq!(error.reason(), Some(LspsErrorReason::OptionMismatch));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code" : 1000,
+                "message" : "Option mismatch",
+                "data" : {
+                    "property" : "min_initial_lsp_balance_sat",
+                    "message" : "You've requested a channel with lsp_balance_sat=0 sat but the LSP-server requires at least 100000 sat",
+                    "reason" : "option_mismatch"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn validate_token() {
+        let order = get_order_builder().token(None).build().unwrap();
+        order.validate_free_form_fields().unwrap();
+
+        let order = get_order_builder()
+            .token(Some("".to_string()))
+            .build()
+            .unwrap();
+        order.validate_free_form_fields().unwrap();
+
+        let order = get_order_builder()
+            .token(Some("a".repeat(MAX_TOKEN_LENGTH)))
+            .build()
+            .unwrap();
+        order.validate_free_form_fields().unwrap();
+
+        let order = get_order_builder()
+            .token(Some("a".repeat(MAX_TOKEN_LENGTH + 1)))
+            .build()
+            .unwrap();
+        let error: ErrorData = order.validate_free_form_fields().unwrap_err().into();
+        assert_eq!(error.reason(), Some(LspsErrorReason::InvalidParams));
+        assert_eq!(error.data.unwrap()["property"], "token");
+
+        let order = get_order_builder()
+            .token(Some("my\ntoken".to_string()))
+            .build()
+            .unwrap();
+        assert!(order.validate_free_form_fields().is_err());
+    }
 }
@@ -43,18 +43,36 @@ impl<'de> Deserialize<'de> for Promise {
     }
 }
 
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for Promise {
+    fn schema_name() -> String {
+        String::from("Promise")
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let mut schema = crate::json_schema::string_schema(None, "An opaque promise of the LSP");
+        if let schemars::schema::Schema::Object(object) = &mut schema {
+            object.string().max_length = Some(MAX_PROMISE_LEN_BYTES as u32);
+        }
+        schema
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps2GetVersionsResponse {
     versions: Vec<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps2GetInfoRequest {
     version: i64,
     token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps2GetInfoResponse {
     opening_fee_params_menu: Vec<OpeningFeeParamsMenuItem>,
     min_payment_size_msat: String,
@@ -62,6 +80,7 @@ pub struct Lsps2GetInfoResponse {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(deny_unknown_fields)]
 pub struct OpeningFeeParamsMenuItem {
     min_fee_msat: MsatAmount,
@@ -73,6 +92,7 @@ pub struct OpeningFeeParamsMenuItem {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps2BuyRequest {
     version: String,
     opening_fee_params: OpeningFeeParamsMenuItem,
@@ -80,6 +100,7 @@ pub struct Lsps2BuyRequest {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Lsps2BuyResponse {
     jit_channel_scid: ShortChannelId,
     lsp_cltv_expiry_delta: u64,
@@ -17,49 +17,150 @@ use serde::de::{Deserializer, Visitor};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 
-use anyhow::{anyhow, Result};
+use crate::error::{Error, Result};
 
-// Every time I read this file I am tempted to write a macro that
-// generates it. I've tried it a few times but the result never improved
-// the code-base.
-//
-// If you can write a macro that makes this code more readable, shorter and
-// easier to maintain let me know. I'd be happy to merge
+use crate::json_rpc_erased::JsonRpcMethodErased;
+
+/// Generates the language bindings for the rpc-methods
+///
+/// Every entry has the form
+///
+/// ```text
+/// Variant(CONSTANT: TypeAlias) => "method.name" : Params -> Result
+/// ```
+///
+/// For each entry the macro defines
+/// - the type alias `TypeAlias` for a [`JsonRpcMethod`] with `DefaultError`
+/// - the constant `CONSTANT` with the name of the method
+/// - the variant `JsonRpcMethodEnum::Variant`
+///
+/// [`JsonRpcMethodEnum::from_method_name`], [`JsonRpcMethodEnum::name`] and
+/// [`JsonRpcMethodEnum::all_methods`] are generated from the same list.
+/// A method can't be added to one of them and be forgotten in another.
+macro_rules! define_lsps_methods {
+    ($(
+        $(#[$meta:meta])*
+        $variant:ident($constant:ident: $alias:ident) => $name:literal : $params:ident -> $result:ident
+    ),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            pub type $alias = JsonRpcMethod<'static, $params, $result, DefaultError>;
+            pub const $constant: $alias = $alias::new($name);
+        )*
+
+        /// All rpc-methods the LSP-server dispatches
+        pub enum JsonRpcMethodEnum {
+            $($variant($alias),)*
+        }
+
+        impl JsonRpcMethodEnum {
+            const ALL_METHODS: &'static [JsonRpcMethodEnum] = &[$(Self::$variant($constant)),*];
+
+            pub fn from_method_name(value: &str) -> Result<JsonRpcMethodEnum> {
+                match value {
+                    $($name => Ok(Self::$variant($constant)),)*
+                    default => Err(Error::invalid_value(
+                        "method",
+                        format!("Unknown method '{}'", default),
+                    )),
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant(x) => x.name(),)*
+                }
+            }
+
+            /// Every method in the order in which they are defined
+            pub fn all_methods() -> &'static [JsonRpcMethodEnum] {
+                Self::ALL_METHODS
+            }
+
+            pub fn ref_erase(&self) -> &dyn JsonRpcMethodErased<'_> {
+                match self {
+                    $(Self::$variant(x) => x.ref_erase(),)*
+                }
+            }
+
+            /// The JSON schema of the params and result of the method
+            #[cfg(feature = "schemars")]
+            pub fn schema(&self) -> schemars::schema::RootSchema {
+                match self {
+                    $(Self::$variant(x) => crate::json_schema::method_schema(x),)*
+                }
+            }
+        }
+    };
+}
 
 // All rpc-methods defined in the LSPS standard
-// The generics are <I,O,E> where
-// - I represents the params
-// - O represents the result data
-// - E represents the error if present
 //
-// To create language bindings for a new rpc-call you must
-// 1. Add it to the JsonRpcMethodEnum
-// 2. Add it to the from_method_name function
-// 3. Add it to the ref_erase function
-pub type Lsps0ListProtocols = JsonRpcMethod<'static, NoParams, ListprotocolsResponse, DefaultError>;
-
-pub type Lsps1GetInfo =
-    JsonRpcMethod<'static, Lsps1InfoRequest, Lsps1GetInfoResponse, DefaultError>;
-pub type Lsps1CreateOrder =
-    JsonRpcMethod<'static, Lsps1CreateOrderRequest, Lsps1CreateOrderResponse, DefaultError>;
-
-pub type Lsps1GetOrder =
-    JsonRpcMethod<'static, Lsps1GetOrderRequest, Lsps1GetOrderResponse, DefaultError>;
-
-// LSPS0: Transport layer
-pub const LSPS0_LIST_PROTOCOLS: Lsps0ListProtocols =
-    Lsps0ListProtocols::new("lsps0.list_protocols");
-
-// LSPS1: Buy Channels
-pub const LSPS1_GETINFO: Lsps1GetInfo = Lsps1GetInfo::new("lsps1.get_info");
-pub const LSPS1_CREATE_ORDER: Lsps1CreateOrder = Lsps1CreateOrder::new("lsps1.create_order");
-pub const LSPS1_GET_ORDER: Lsps1GetOrder = Lsps1GetOrder::new("lsps1.get_order");
-
-pub enum JsonRpcMethodEnum {
-    Lsps0ListProtocols(Lsps0ListProtocols),
-    Lsps1Info(Lsps1GetInfo),
-    Lsps1CreateOrder(Lsps1CreateOrder),
-    Lsps1GetOrder(Lsps1GetOrder),
+// The params and result are the types of the params and result data.
+// The error is always `DefaultError`.
+//
+// To create language bindings for a new rpc-call you add a single line
+// below. Add the protocol to KNOWN_PROTOCOLS if it is new.
+define_lsps_methods! {
+    // LSPS0: Transport layer
+    Lsps0ListProtocols(LSPS0_LIST_PROTOCOLS: Lsps0ListProtocols) => "lsps0.list_protocols" : NoParams -> ListprotocolsResponse,
+    // LSPS1: Buy Channels
+    Lsps1Info(LSPS1_GETINFO: Lsps1GetInfo) => "lsps1.get_info" : Lsps1InfoRequest -> Lsps1GetInfoResponse,
+    Lsps1CreateOrder(LSPS1_CREATE_ORDER: Lsps1CreateOrder) => "lsps1.create_order" : Lsps1CreateOrderRequest -> Lsps1CreateOrderResponse,
+    Lsps1GetOrder(LSPS1_GET_ORDER: Lsps1GetOrder) => "lsps1.get_order" : Lsps1GetOrderRequest -> Lsps1GetOrderResponse,
+}
+
+/// Human readable information about an LSPS protocol
+///
+/// The numbers are the ones returned by `lsps0.list_protocols`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProtocolInfo {
+    pub number: u32,
+    pub name: String,
+    #[serde(rename = "description")]
+    pub short_description: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spec_url: Option<&'static str>,
+}
+
+// Update this table when adding the methods of a new protocol above
+const KNOWN_PROTOCOLS: [(u32, &str, &str); 4] = [
+    (
+        0,
+        "Transport layer",
+        "https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS0",
+    ),
+    (
+        1,
+        "Channel purchase",
+        "https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS1",
+    ),
+    (
+        2,
+        "JIT channels",
+        "https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS2",
+    ),
+    (
+        5,
+        "Webhook notifications",
+        "https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS5",
+    ),
+];
+
+impl ProtocolInfo {
+    pub fn from_number(number: u32) -> Self {
+        let known = KNOWN_PROTOCOLS.iter().find(|(n, _, _)| *n == number);
+        Self {
+            number,
+            name: format!("LSPS{}", number),
+            short_description: known.map(|(_, d, _)| *d).unwrap_or("Unknown protocol"),
+            spec_url: known.map(|(_, _, url)| *url),
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.spec_url.is_some()
+    }
 }
 
 impl Serialize for JsonRpcMethodEnum {
@@ -98,23 +199,166 @@ impl<'de> Deserialize<'de> for JsonRpcMethodEnum {
     }
 }
 
-impl JsonRpcMethodEnum {
-    pub fn from_method_name(value: &str) -> Result<JsonRpcMethodEnum> {
-        match value {
-            "lsps0.list_protocols" => Ok(Self::Lsps0ListProtocols(LSPS0_LIST_PROTOCOLS)),
-            "lsps1.get_info" => Ok(Self::Lsps1Info(LSPS1_GETINFO)),
-            "lsps1.create_order" => Ok(Self::Lsps1CreateOrder(LSPS1_CREATE_ORDER)),
-            "lsps1.get_order" => Ok(Self::Lsps1GetOrder(LSPS1_GET_ORDER)),
-            default => Err(anyhow!("Unknown method '{}'", default)),
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::json_rpc::error::codes;
+    use crate::json_rpc::{ErrorData, JsonRpcRequest};
+
+    /// Parses a request the way the LSP-server dispatches it
+    fn dispatch(message: serde_json::Value) -> Result<(), ErrorData> {
+        let request = serde_json::from_value::<JsonRpcRequest<serde_json::Value>>(message)
+            .map_err(|e| ErrorData::invalid_request(e.to_string()))?;
+        let method = JsonRpcMethodEnum::from_method_name(&request.method)
+            .map_err(|_| ErrorData::method_not_found(&request.method))?;
+        match method {
+            JsonRpcMethodEnum::Lsps0ListProtocols(m) => m.into_typed_request(request).map(|_| ()),
+            JsonRpcMethodEnum::Lsps1Info(m) => m.into_typed_request(request).map(|_| ()),
+            JsonRpcMethodEnum::Lsps1CreateOrder(m) => m.into_typed_request(request).map(|_| ()),
+            JsonRpcMethodEnum::Lsps1GetOrder(m) => m.into_typed_request(request).map(|_| ()),
+        }
+    }
+
+    /// A request without params, with `"params" : null` and with `"params" : {}`
+    fn encodings_without_params(method: &str) -> Vec<serde_json::Value> {
+        let omitted = json!({"jsonrpc" : "2.0", "id" : "abc", "method" : method});
+        let mut null = omitted.clone();
+        null["params"] = serde_json::Value::Null;
+        let mut empty = omitted.clone();
+        empty["params"] = json!({});
+        vec![omitted, null, empty]
+    }
+
+    #[test]
+    fn list_protocols_accepts_all_encodings_without_params() {
+        for message in encodings_without_params("lsps0.list_protocols") {
+            dispatch(message.clone()).unwrap_or_else(|e| panic!("{} failed: {}", message, e));
+        }
+    }
+
+    #[test]
+    fn get_order_without_params_misses_order_id() {
+        for message in encodings_without_params("lsps1.get_order") {
+            let error = dispatch(message).unwrap_err();
+            assert_eq!(error.code, codes::INVALID_PARAMS_CODE);
+
+            let data = error.data.unwrap();
+            assert_eq!(data["type"], "invalid_param");
+            assert_eq!(data["property"], "order_id");
+            assert_eq!(data["message"], "Missing required parameter order_id");
+        }
+    }
+
+    fn get_order(order_id: &str) -> serde_json::Value {
+        json!({
+            "jsonrpc" : "2.0",
+            "id" : "abc",
+            "method" : "lsps1.get_order",
+            "params" : { "order_id" : order_id }
+        })
+    }
+
+    #[test]
+    fn get_order_accepts_lowercase_order_id() {
+        dispatch(get_order("bb4b5d0a-8334-49d8-9463-90a6d413af7c")).unwrap();
+    }
+
+    #[test]
+    fn get_order_rejects_malformed_order_id() {
+        for order_id in [
+            "BB4B5D0A-8334-49D8-9463-90A6D413AF7C",
+            "{bb4b5d0a-8334-49d8-9463-90a6d413af7c}",
+            "not-a-uuid",
+        ] {
+            let error = dispatch(get_order(order_id)).unwrap_err();
+            assert_eq!(error.code, codes::INVALID_PARAMS_CODE);
+
+            let data = error.data.unwrap();
+            assert_eq!(data["type"], "invalid_param");
+            assert_eq!(data["property"], "order_id");
+        }
+    }
+
+    #[test]
+    fn every_method_round_trips_by_name() {
+        let names: Vec<&str> = JsonRpcMethodEnum::all_methods()
+            .iter()
+            .map(|m| m.name())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "lsps0.list_protocols",
+                "lsps1.get_info",
+                "lsps1.create_order",
+                "lsps1.get_order"
+            ]
+        );
+
+        for method in JsonRpcMethodEnum::all_methods() {
+            let parsed = JsonRpcMethodEnum::from_method_name(method.name()).unwrap();
+            assert_eq!(parsed.name(), method.name());
+            assert_eq!(method.ref_erase().name(), method.name());
+
+            let json = serde_json::to_value(method).unwrap();
+            assert_eq!(json, json!(method.name()));
+            let deserialized: JsonRpcMethodEnum = serde_json::from_value(json).unwrap();
+            assert_eq!(deserialized.name(), method.name());
         }
     }
 
-    pub fn name(&self) -> &'static str {
-        match self {
-            Self::Lsps0ListProtocols(x) => x.name(),
-            Self::Lsps1Info(x) => x.name(),
-            Self::Lsps1CreateOrder(x) => x.name(),
-            Self::Lsps1GetOrder(x) => x.name(),
+    #[test]
+    fn every_method_has_a_unique_name_of_a_known_protocol() {
+        let mut names = std::collections::HashSet::new();
+        for method in JsonRpcMethodEnum::all_methods() {
+            let name = method.name();
+            assert!(names.insert(name), "{} is defined twice", name);
+
+            let (protocol, _) = name.split_once('.').unwrap();
+            let number = protocol.strip_prefix("lsps").unwrap().parse().unwrap();
+            assert!(ProtocolInfo::from_number(number).is_known(), "{}", name);
         }
     }
+
+    #[test]
+    fn unknown_method_name() {
+        let err = JsonRpcMethodEnum::from_method_name("lsps1.unknown")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Unknown method 'lsps1.unknown' (method)");
+        assert!(matches!(err, Error::InvalidValue { field, .. } if field == "method"));
+        assert!(serde_json::from_value::<JsonRpcMethodEnum>(json!("lsps1.unknown")).is_err());
+    }
+
+    #[test]
+    fn describe_known_protocol() {
+        let info = ProtocolInfo::from_number(1);
+        assert!(info.is_known());
+        assert_eq!(
+            serde_json::to_value(info).unwrap(),
+            serde_json::json!({
+                "number" : 1,
+                "name" : "LSPS1",
+                "description" : "Channel purchase",
+                "spec_url" : "https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS1",
+            })
+        );
+    }
+
+    #[test]
+    fn describe_unknown_protocol() {
+        let info = ProtocolInfo::from_number(42);
+        assert!(!info.is_known());
+        assert_eq!(
+            serde_json::to_value(info).unwrap(),
+            serde_json::json!({
+                "number" : 42,
+                "name" : "LSPS42",
+                "description" : "Unknown protocol",
+            })
+        );
+    }
 }
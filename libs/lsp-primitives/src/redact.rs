@@ -0,0 +1,119 @@
+//! Helpers to keep sensitive values out of logs
+//!
+//! Operators routinely share their logs when reporting issues.
+//! The `Debug`-implementations of types that contain a token,
+//! invoice or refund address use these helpers.
+//!
+//! Enable the `unredacted-debug` feature to print the full values
+//! when debugging locally.
+use std::fmt;
+
+#[cfg(not(feature = "unredacted-debug"))]
+use bitcoin::hashes::{sha256, Hash};
+
+/// Formats a value that has been redacted
+///
+/// Unlike a `String` the value is printed without quotes
+pub struct Redacted(String);
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Only shows the length of the token
+pub fn redact_token(token: &str) -> Redacted {
+    #[cfg(feature = "unredacted-debug")]
+    return Redacted(format!("{:?}", token));
+
+    #[cfg(not(feature = "unredacted-debug"))]
+    Redacted(format!("<redacted {} bytes>", token.len()))
+}
+
+/// Shows the human-readable part of the invoice and a short hash
+///
+/// The hash allows to correlate log lines without revealing the invoice
+pub fn redact_invoice(invoice: &str) -> Redacted {
+    #[cfg(feature = "unredacted-debug")]
+    return Redacted(format!("{:?}", invoice));
+
+    #[cfg(not(feature = "unredacted-debug"))]
+    {
+        // The bech32 separator is the last '1' in the string
+        let hrp = match invoice.rfind('1') {
+            Some(index) => &invoice[..index],
+            None => "",
+        };
+        let hash = sha256::Hash::hash(invoice.as_bytes()).to_string();
+        Redacted(format!("{}…<sha256:{}>", hrp, &hash[..8]))
+    }
+}
+
+/// Shows the first and last 4 characters of the address
+pub fn redact_address(address: &str) -> Redacted {
+    #[cfg(feature = "unredacted-debug")]
+    return Redacted(format!("{:?}", address));
+
+    #[cfg(not(feature = "unredacted-debug"))]
+    {
+        let chars: Vec<char> = address.chars().collect();
+        if chars.len() <= 8 {
+            return Redacted("<redacted>".to_string());
+        }
+        let start: String = chars[..4].iter().collect();
+        let end: String = chars[chars.len() - 4..].iter().collect();
+        Redacted(format!("{}…{}", start, end))
+    }
+}
+
+/// Only shows the length of a message
+pub fn redact_message(message: &str) -> Redacted {
+    #[cfg(feature = "unredacted-debug")]
+    return Redacted(format!("{:?}", message));
+
+    #[cfg(not(feature = "unredacted-debug"))]
+    Redacted(format!("<{} bytes>", message.len()))
+}
+
+#[cfg(all(test, not(feature = "unredacted-debug")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redact_token_shows_length() {
+        assert_eq!(
+            format!("{:?}", redact_token("my-secret")),
+            "<redacted 9 bytes>"
+        );
+    }
+
+    #[test]
+    fn redact_invoice_keeps_hrp() {
+        let invoice = "lnbcrt500u1pjqq9a8sp5secret";
+        let redacted = format!("{:?}", redact_invoice(invoice));
+
+        assert!(redacted.starts_with("lnbcrt500u…<sha256:"));
+        assert!(!redacted.contains("secret"));
+
+        // The same invoice is always redacted to the same value
+        assert_eq!(redacted, format!("{:?}", redact_invoice(invoice)));
+        assert_ne!(
+            redacted,
+            format!("{:?}", redact_invoice("lnbcrt500u1other"))
+        );
+    }
+
+    #[test]
+    fn redact_address_keeps_first_and_last_chars() {
+        let address = "bcrt1q8v5sxlq8xg3fm6stqn7l9yy9hj2jdr2f4gvc5w";
+        assert_eq!(format!("{:?}", redact_address(address)), "bcrt…vc5w");
+        assert_eq!(format!("{:?}", redact_address("short")), "<redacted>");
+    }
+}
@@ -0,0 +1,152 @@
+//! Amounts of satoshis in the output of RPC-commands
+//!
+//! The LSPS-spec encodes every amount as a string because JSON-parsers
+//! that store numbers as doubles lose precision above `2^53 - 1`. That
+//! protects the wire format but it makes the output of admin and client
+//! RPC-commands awkward to use from `jq` or a dashboard.
+//!
+//! A [`SatAmountJson`] is serialized as a JSON number if every consumer
+//! can represent it exactly and as a string otherwise:
+//!
+//! ```json
+//! 9007199254740991
+//! "9007199254740992"
+//! ```
+//!
+//! Never use it in an LSPS-message. Those must use [`SatAmount`].
+use serde::{Serialize, Serializer};
+
+use crate::lsps0::common_schemas::SatAmount;
+
+/// The largest integer a double represents exactly, `2^53 - 1`
+pub const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Serializes an amount of satoshis as a number if it is at most [`MAX_SAFE_INTEGER`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub struct SatAmountJson(pub u64);
+
+impl SatAmountJson {
+    pub fn sat_value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for SatAmountJson {
+    fn from(sat: u64) -> Self {
+        Self(sat)
+    }
+}
+
+impl From<SatAmount> for SatAmountJson {
+    fn from(amount: SatAmount) -> Self {
+        Self(amount.sat_value())
+    }
+}
+
+impl Serialize for SatAmountJson {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.0 <= MAX_SAFE_INTEGER {
+            serializer.serialize_u64(self.0)
+        } else {
+            serializer.serialize_str(&self.0.to_string())
+        }
+    }
+}
+
+/// Use as `#[serde(serialize_with = "sat_amount_json::serialize")]`
+///
+/// Works for fields of type `u64` and [`SatAmount`]
+pub fn serialize<T, S>(amount: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Copy + Into<SatAmountJson>,
+    S: Serializer,
+{
+    (*amount).into().serialize(serializer)
+}
+
+/// Use as `#[serde(serialize_with = "sat_amount_json::serialize_option")]`
+pub fn serialize_option<T, S>(amount: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Copy + Into<SatAmountJson>,
+    S: Serializer,
+{
+    amount.map(Into::into).serialize(serializer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn serialize_as_number_up_to_max_safe_integer() {
+        assert_eq!(MAX_SAFE_INTEGER, 9_007_199_254_740_991);
+        for sat in [0, 1, 100_000, 2_100_000_000_000_000, MAX_SAFE_INTEGER] {
+            assert_eq!(
+                serde_json::to_value(SatAmountJson(sat)).unwrap(),
+                json!(sat)
+            );
+        }
+        assert_eq!(
+            serde_json::to_string(&SatAmountJson(MAX_SAFE_INTEGER)).unwrap(),
+            "9007199254740991"
+        );
+    }
+
+    #[test]
+    fn serialize_as_string_above_max_safe_integer() {
+        assert_eq!(
+            serde_json::to_string(&SatAmountJson(MAX_SAFE_INTEGER + 1)).unwrap(),
+            "\"9007199254740992\""
+        );
+        assert_eq!(
+            serde_json::to_string(&SatAmountJson(MAX_SAFE_INTEGER + 2)).unwrap(),
+            "\"9007199254740993\""
+        );
+        assert_eq!(
+            serde_json::to_string(&SatAmountJson(u64::MAX)).unwrap(),
+            "\"18446744073709551615\""
+        );
+    }
+
+    #[test]
+    fn serialize_fields() {
+        #[derive(Serialize)]
+        struct Output {
+            #[serde(serialize_with = "serialize")]
+            capacity_sat: u64,
+            #[serde(serialize_with = "serialize")]
+            fee_total_sat: SatAmount,
+            #[serde(serialize_with = "serialize_option")]
+            budget_sat: Option<u64>,
+            #[serde(serialize_with = "serialize_option")]
+            refund_sat: Option<SatAmount>,
+        }
+
+        let output = Output {
+            capacity_sat: MAX_SAFE_INTEGER + 1,
+            fee_total_sat: SatAmount::new(1_000),
+            budget_sat: None,
+            refund_sat: Some(SatAmount::new(MAX_SAFE_INTEGER)),
+        };
+        assert_eq!(
+            serde_json::to_value(output).unwrap(),
+            json!({
+                "capacity_sat" : "9007199254740992",
+                "fee_total_sat" : 1_000,
+                "budget_sat" : null,
+                "refund_sat" : 9_007_199_254_740_991u64,
+            })
+        );
+
+        // The wire format is unchanged
+        assert_eq!(
+            serde_json::to_value(SatAmount::new(1_000)).unwrap(),
+            json!("1000")
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Caches the `lsps1.get_info`-response of every LSP
+//!
+//! Creating and awaiting an order both need the info of the LSP.
+//! The cache saves a round-trip to the LSP on every call. Callers that
+//! request the info of the same peer at the same time share a single
+//! request.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps1::schema::Lsps1GetInfoResponse;
+
+/// How long the info is cached unless the user configured otherwise
+pub(crate) const DEFAULT_INFO_TTL: Duration = Duration::from_secs(300);
+
+struct CachedInfo {
+    info: Lsps1GetInfoResponse,
+    /// When the request was sent
+    fetched_at: Instant,
+}
+
+/// Holds the info of a single peer
+///
+/// The lock is held while the info is fetched. Concurrent callers wait
+/// for the request in flight instead of sending their own.
+type InfoSlot = Arc<tokio::sync::Mutex<Option<CachedInfo>>>;
+
+pub(crate) struct InfoCache {
+    ttl: Duration,
+    slots: Mutex<HashMap<PublicKey, InfoSlot>>,
+}
+
+impl InfoCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached info of `peer_id` or fetches it
+    ///
+    /// `fetch` is only awaited if the info isn't cached, is older than the
+    /// TTL or `force_refresh` is set. Errors are not cached.
+    ///
+    /// A forced refresh that waited for a request that was sent after the
+    /// refresh was requested uses that response.
+    pub(crate) async fn get_or_fetch<F>(
+        &self,
+        peer_id: &PublicKey,
+        force_refresh: bool,
+        fetch: F,
+    ) -> Result<Lsps1GetInfoResponse>
+    where
+        F: Future<Output = Result<Lsps1GetInfoResponse>>,
+    {
+        let requested_at = Instant::now();
+        let slot = self.slot(peer_id);
+        let mut cached = slot.lock().await;
+
+        if let Some(entry) = cached.as_ref() {
+            let is_fresh = entry.fetched_at.elapsed() < self.ttl;
+            let is_coalesced = entry.fetched_at > requested_at;
+            if (is_fresh && !force_refresh) || is_coalesced {
+                return Ok(entry.info.clone());
+            }
+        }
+
+        let fetched_at = Instant::now();
+        let info = fetch.await?;
+        *cached = Some(CachedInfo {
+            info: info.clone(),
+            fetched_at,
+        });
+        Ok(info)
+    }
+
+    /// Forgets the info of `peer_id`
+    ///
+    /// A request that is in flight completes but its response
+    /// isn't cached.
+    pub(crate) fn invalidate(&self, peer_id: &PublicKey) -> bool {
+        self.slots.lock().unwrap().remove(peer_id).is_some()
+    }
+
+    fn slot(&self, peer_id: &PublicKey) -> InfoSlot {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry(*peer_id)
+            .or_default()
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    fn peer_id() -> PublicKey {
+        PublicKey::from_hex("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap()
+    }
+
+    /// Counts the requests that were sent to the LSP
+    #[derive(Default)]
+    struct FakeLsp {
+        requests: AtomicUsize,
+    }
+
+    impl FakeLsp {
+        async fn get_info(&self, delay: Duration) -> Result<Lsps1GetInfoResponse> {
+            let request = self.requests.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(delay).await;
+            let info = serde_json::from_value(json!({
+                "options" : {
+                    "min_required_channel_confirmations": 0,
+                    "min_funding_confirms_within_blocks": 6,
+                    "min_onchain_payment_confirmations": null,
+                    "supports_zero_channel_reserve": false,
+                    "min_onchain_payment_size_sat": null,
+                    "max_channel_expiry_blocks": 20160,
+                    "min_initial_client_balance_sat": "0",
+                    "max_initial_client_balance_sat": "0",
+                    "min_initial_lsp_balance_sat": "100000",
+                    "max_initial_lsp_balance_sat": "10000000",
+                    "min_channel_balance_sat": "100000",
+                    "max_channel_balance_sat": "10000000"
+                },
+                "website" : format!("https://lsp.example/{}", request)
+            }))?;
+            Ok(info)
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn info_is_fetched_again_after_ttl() {
+        let cache = InfoCache::new(Duration::from_millis(200));
+        let lsp = FakeLsp::default();
+        let no_delay = Duration::ZERO;
+
+        let first = cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        let second = cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        assert_eq!(lsp.requests(), 1);
+        assert_eq!(first.website, second.website);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let third = cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        assert_eq!(lsp.requests(), 2);
+        assert_ne!(first.website, third.website);
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_a_request() {
+        let cache = InfoCache::new(Duration::from_secs(300));
+        let lsp = FakeLsp::default();
+        let delay = Duration::from_millis(50);
+
+        let (a, b, c) = tokio::join!(
+            cache.get_or_fetch(&peer_id(), false, lsp.get_info(delay)),
+            cache.get_or_fetch(&peer_id(), false, lsp.get_info(delay)),
+            cache.get_or_fetch(&peer_id(), false, lsp.get_info(delay)),
+        );
+        assert_eq!(lsp.requests(), 1);
+        assert_eq!(a.unwrap().website, b.unwrap().website);
+        assert!(c.is_ok());
+    }
+
+    #[tokio::test]
+    async fn force_refresh_bypasses_cache() {
+        let cache = InfoCache::new(Duration::from_secs(300));
+        let lsp = FakeLsp::default();
+        let no_delay = Duration::ZERO;
+
+        let first = cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        let refreshed = cache
+            .get_or_fetch(&peer_id(), true, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        assert_eq!(lsp.requests(), 2);
+        assert_ne!(first.website, refreshed.website);
+
+        // Later calls see the refreshed info
+        let cached = cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        assert_eq!(lsp.requests(), 2);
+        assert_eq!(cached.website, refreshed.website);
+    }
+
+    #[tokio::test]
+    async fn invalidated_and_failed_info_is_not_cached() {
+        let cache = InfoCache::new(Duration::from_secs(300));
+        let lsp = FakeLsp::default();
+        let no_delay = Duration::ZERO;
+
+        let err = cache
+            .get_or_fetch(&peer_id(), false, async { Err(anyhow!("Time-out")) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Time-out");
+
+        cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        assert_eq!(lsp.requests(), 1);
+
+        // The peer disconnects
+        assert!(cache.invalidate(&peer_id()));
+        cache
+            .get_or_fetch(&peer_id(), false, lsp.get_info(no_delay))
+            .await
+            .unwrap();
+        assert_eq!(lsp.requests(), 2);
+    }
+}
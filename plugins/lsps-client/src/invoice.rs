@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+
+pub(crate) use cln_lsps::invoice::normalize_bolt11;
+use cln_lsps::invoice::LIGHTNING_URI_SCHEME;
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, MsatAmount, SatAmount};
+use lsp_primitives::lsps1::schema::PaymentState;
+
+/// Returns true if the client has already paid for the order
+///
+/// The invoice of such an order should not be displayed again
+pub(crate) fn is_already_paid(state: &PaymentState) -> bool {
+    match state {
+        PaymentState::ExpectPayment => false,
+        PaymentState::Hold | PaymentState::Paid | PaymentState::Refunded => true,
+    }
+}
+
+/// Creates a BIP21-style `lightning:`-uri
+///
+/// The invoice is uppercased so it can be encoded in the compact
+/// alphanumeric mode of a QR-code
+pub(crate) fn lightning_uri(bolt11: &str) -> String {
+    format!("{}{}", LIGHTNING_URI_SCHEME, bolt11.to_ascii_uppercase())
+}
+
+/// Computes when the invoice expires and fails if it already did
+pub(crate) fn invoice_expires_at(created_at: u64, expiry: u64, now: i64) -> Result<IsoDatetime> {
+    let expires_at = created_at
+        .checked_add(expiry)
+        .and_then(|x| i64::try_from(x).ok())
+        .context("Invalid invoice expiry")?;
+    let expires_at_datetime = IsoDatetime::from_unix_timestamp(expires_at)?;
+
+    if expires_at <= now {
+        return Err(anyhow!("Invoice expired at {:?}", expires_at_datetime));
+    }
+    Ok(expires_at_datetime)
+}
+
+/// Fails if the invoice doesn't request the `order_total_sat` of the order
+pub(crate) fn check_invoice_amount(
+    amount_msat: Option<u64>,
+    order_total_sat: SatAmount,
+) -> Result<()> {
+    let expected = MsatAmount::from_sat(order_total_sat).context("Invalid order_total_sat")?;
+    match amount_msat {
+        Some(amount_msat) if amount_msat == expected.msat_value() => Ok(()),
+        Some(amount_msat) => Err(anyhow!(
+            "Invoice requests {} msat but the order costs {}",
+            amount_msat,
+            expected
+        )),
+        None => Err(anyhow!("Invoice doesn't specify an amount")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INVOICE: &str = "lnbcrt1m1pjtest";
+
+    #[test]
+    fn only_unpaid_orders_show_an_invoice() {
+        assert!(!is_already_paid(&PaymentState::ExpectPayment));
+        assert!(is_already_paid(&PaymentState::Hold));
+        assert!(is_already_paid(&PaymentState::Paid));
+        assert!(is_already_paid(&PaymentState::Refunded));
+    }
+
+    #[test]
+    fn create_lightning_uri() {
+        assert_eq!(lightning_uri(INVOICE), "lightning:LNBCRT1M1PJTEST");
+    }
+
+    #[test]
+    fn expired_invoices_are_rejected() {
+        let expires_at = invoice_expires_at(1_000, 3_600, 4_599).unwrap();
+        assert_eq!(expires_at.unix_timestamp(), 4_600);
+
+        assert!(invoice_expires_at(1_000, 3_600, 4_600).is_err());
+        assert!(invoice_expires_at(u64::MAX, 1, 0).is_err());
+    }
+
+    #[test]
+    fn invoice_amount_should_match_order() {
+        let order_total_sat = SatAmount::new(1_234);
+        assert!(check_invoice_amount(Some(1_234_000), order_total_sat).is_ok());
+        assert!(check_invoice_amount(Some(1_234_001), order_total_sat).is_err());
+        assert!(check_invoice_amount(None, order_total_sat).is_err());
+    }
+}
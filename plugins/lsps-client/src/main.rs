@@ -1,46 +1,167 @@
+mod info_cache;
+mod invoice;
+mod options;
 mod plugin_rpc;
+mod receipt;
+mod waits;
 
 use anyhow::{anyhow, Context, Result};
+use cln_lsps::cln_rpc::model::requests::{DecodepayRequest, ListpeerchannelsRequest};
+use cln_lsps::cln_rpc::primitives::PublicKey as RpcPublicKey;
 use cln_lsps::cln_rpc::ClnRpc;
+use cln_plugin::messages::NotificationTopic;
 use cln_plugin::{Builder, Error, Plugin};
 use tokio;
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use lsp_primitives::json_rpc::{DefaultError, JsonRpcId, JsonRpcMethod, JsonRpcResponse, NoParams};
-use lsp_primitives::lsps0::common_schemas::{Network, NetworkCheckable, PublicKey};
+use lsp_primitives::json_rpc::{
+    generate_random_rpc_id, DefaultError, JsonRpcId, JsonRpcMessageKind, JsonRpcMethod,
+    JsonRpcResponse, NoParams,
+};
+use lsp_primitives::lsps0::common_schemas::{
+    IsoDatetime, Network, NetworkCheckable, PublicKey, SatAmount,
+};
+use lsp_primitives::lsps0::message_id::is_lsps_message;
 use lsp_primitives::lsps1;
+use lsp_primitives::lsps1::schema::{Lsps1Options, OrderId};
 use lsp_primitives::methods;
+use lsp_primitives::methods::{ListprotocolsResponse, ProtocolInfo};
+use lsp_primitives::sat_amount_json::SatAmountJson;
 
-use cln_lsps::client::{LspClient, RequestId, LSPS_MESSAGE_ID, LSPS_MESSAGE_ID_U16};
-use cln_lsps::cln_rpc_client::ClnRpcLspClient;
+use cln_lsps::channel_ready::{complete_ready_orders, ready_peer_id, ReadyChannel};
+use cln_lsps::client::{
+    is_method_not_found, LspClient, LspClientExt, RequestId, ResponseValidation,
+    LSPS_MESSAGE_ID_U16,
+};
+use cln_lsps::cln_rpc_client::{ClnRpcLspClient, SharedClnRpc, DEFAULT_RESPONSE_TIMEOUT};
 use cln_lsps::custom_msg_hook::RpcCustomMsgMessage;
+use cln_lsps::deadline::{Deadline, Stage};
+use cln_lsps::liquidity::{plan_liquidity, FeeHistory};
+use cln_lsps::pins::{OrderExport, OrderPins, PinnedOrder};
+use cln_lsps::tracker::OrderTracker;
 use cln_lsps::transport::RequestResponseMatcher as RRM;
+use cln_lsps::updates::FinalStates;
+
+use crate::info_cache::InfoCache;
+use crate::waits::{StopReason, WaitRegistry};
 
 type RequestResponseMatcher = RRM<RequestId, serde_json::Value>;
 
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const AWAIT_ORDER_DEFAULT_TIMEOUT_SECONDS: u64 = 600;
+const AWAIT_ORDER_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Orders paid by an external wallet are polled this often
+const EXTERNAL_PAYMENT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Orders are compacted this often if `lsps-client-order-retention-days` is set
+const ORDER_COMPACTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Notification sent after every poll of `lsps1-await-order`
+const LSPS1_ORDER_PROGRESS: &str = "lsps1_order_progress";
+
+/// Notification sent when an LSP changes the payment details of an order
+const LSPS1_LSP_INCONSISTENT: &str = "lsps1_lsp_inconsistent";
+
+/// Notification sent once when an order is completed or failed
+const LSPS1_ORDER_UPDATE: &str = "lsps1_order_update";
+
+/// Notification sent once when our node sees the channel of an order become ready
+const LSPS1_CHANNEL_READY: &str = "lsps1_channel_ready";
+
+/// Notification sent once when the LSP reports the payment of an order
+/// that is paid by an external wallet
+const LSPS1_PAYMENT_RECEIVED: &str = "lsps1_payment_received";
 
 #[derive(Clone)]
 struct PluginState {
     matcher: Arc<Mutex<RequestResponseMatcher>>,
+    /// The connection to lightningd that is used by all rpc-methods
+    rpc: SharedClnRpc,
+    waits: Arc<WaitRegistry>,
+    pins: Arc<OrderPins>,
+    final_states: Arc<FinalStates>,
+    info_cache: Arc<InfoCache>,
+    /// The orders that are paid by an external wallet
+    tracker: Arc<OrderTracker>,
 }
 
 impl PluginState {
-    fn new() -> Self {
+    fn new(rpc: ClnRpc, info_ttl: Duration) -> Self {
         Self {
             matcher: Arc::new(Mutex::new(RequestResponseMatcher::new())),
+            rpc: Arc::new(tokio::sync::Mutex::new(rpc)),
+            waits: Arc::new(WaitRegistry::default()),
+            pins: Arc::new(OrderPins::default()),
+            final_states: Arc::new(FinalStates::default()),
+            info_cache: Arc::new(InfoCache::new(info_ttl)),
+            tracker: Arc::new(OrderTracker::default()),
         }
     }
+
+    /// Creates a client that reuses the connection to lightningd
+    ///
+    /// Connecting to lightningd for every call of an rpc-method adds
+    /// a round-trip to the socket before the request can be sent.
+    fn lsp_client(&self, timeout: Duration) -> ClnRpcLspClient {
+        let mut client = ClnRpcLspClient::with_shared_rpc(self.matcher.clone(), self.rpc.clone());
+        client.set_timeout(timeout);
+        client
+    }
+
+    /// Returns the `lsps1.get_info`-response of `peer_id`
+    ///
+    /// The response is cached for `lsps-client-info-ttl-seconds`.
+    /// Use `force_refresh` to ignore the cached response.
+    ///
+    /// Waiting for the request of another caller counts towards `deadline`.
+    async fn get_info_cached(
+        &self,
+        client: &mut dyn LspClient,
+        peer_id: &PublicKey,
+        force_refresh: bool,
+        deadline: &Deadline,
+    ) -> Result<lsps1::schema::Lsps1GetInfoResponse> {
+        let fetch = client.lsps1_get_info_with_deadline(peer_id, deadline);
+        deadline
+            .run(
+                Stage::GetInfo,
+                self.info_cache.get_or_fetch(peer_id, force_refresh, fetch),
+            )
+            .await?
+            .map_err(|err| explain_info_error(peer_id, err))
+    }
 }
 
-async fn create_lsp_client_from_plugin(plugin: Plugin<PluginState>) -> Result<impl LspClient> {
-    // Create an LSPClient
-    let matcher = plugin.state().matcher.clone();
-    let rpc_file = plugin.configuration().rpc_file;
-    let rpc = ClnRpc::new(rpc_file.clone()).await?;
-    return Ok(ClnRpcLspClient::new(matcher, rpc));
+/// Explains why `lsps1.get_info` failed
+///
+/// Some LSPs list protocol 1 but don't implement `lsps1.get_info`.
+/// E.g: because they only offer LSPS2 or are misconfigured.
+fn explain_info_error(peer_id: &PublicKey, err: anyhow::Error) -> anyhow::Error {
+    if is_method_not_found(&err) {
+        anyhow!(
+            "Peer {} lists protocol 1 but does not answer lsps1.get_info: {}",
+            peer_id.to_hex(),
+            err
+        )
+    } else {
+        err
+    }
+}
+
+fn create_lsp_client_from_plugin(plugin: &Plugin<PluginState>) -> Result<ClnRpcLspClient> {
+    let timeout_ms = plugin.option(&options::lsps0_response_timeout_ms())?;
+    let timeout = Duration::from_millis(u64::try_from(timeout_ms)?);
+    let mut client = plugin.state().lsp_client(timeout);
+    if plugin.option(&options::lsps_client_strict_responses())? {
+        client.set_response_validation(ResponseValidation::Strict);
+    }
+    Ok(client)
 }
 
 #[tokio::main]
@@ -48,13 +169,35 @@ async fn main() -> Result<()> {
     log::info!("Configure plugin 'lsps0-client'");
     let configured_plugin =
         match Builder::<PluginState, _, _>::new(tokio::io::stdin(), tokio::io::stdout())
+            .option(options::lsps0_response_timeout_ms())
+            .option(options::lsps_client_info_ttl_seconds())
+            .option(options::lsps_client_strict_responses())
+            .option(options::lsps_client_order_retention_days())
             .rpcmethod_from_builder(crate::plugin_rpc::lsps0_list_servers_method())
             .rpcmethod_from_builder(crate::plugin_rpc::lsps0_list_protocols_method())
             .rpcmethod_from_builder(crate::plugin_rpc::lsps0_send_request())
             .rpcmethod_from_builder(crate::plugin_rpc::lsps1_get_info())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_refresh_info())
             .rpcmethod_from_builder(crate::plugin_rpc::lsps1_create_order())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_plan_liquidity())
             .rpcmethod_from_builder(crate::plugin_rpc::lsps1_get_order())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_get_invoice())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_await_order())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_abort_wait())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_list_orders())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_verify_receipt())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_export_orders())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_import_orders())
+            .notification(NotificationTopic::new(LSPS1_ORDER_PROGRESS))
+            .notification(NotificationTopic::new(LSPS1_LSP_INCONSISTENT))
+            .notification(NotificationTopic::new(LSPS1_ORDER_UPDATE))
+            .notification(NotificationTopic::new(LSPS1_CHANNEL_READY))
+            .notification(NotificationTopic::new(LSPS1_PAYMENT_RECEIVED))
             .hook("custommsg", handle_custom_msg)
+            .subscribe("disconnect", handle_disconnect)
+            .subscribe("channel_opened", handle_channel_opened)
+            .subscribe("channel_state_changed", handle_channel_state_changed)
+            .subscribe("shutdown", handle_shutdown)
             .custommessages(vec![LSPS_MESSAGE_ID_U16])
             .dynamic()
             .configure()
@@ -64,30 +207,234 @@ async fn main() -> Result<()> {
             None => return Ok(()),
         };
 
-    let plugin = configured_plugin.start(PluginState::new()).await?;
+    // Load the pins before we handle the first order
+    let info_ttl_seconds = configured_plugin.option(&options::lsps_client_info_ttl_seconds())?;
+    let info_ttl = Duration::from_secs(u64::try_from(info_ttl_seconds)?);
+    let order_retention = configured_plugin
+        .option(&options::lsps_client_order_retention_days())?
+        .map(|days| {
+            u64::try_from(days)
+                .map(|days| Duration::from_secs(days * 24 * 3600))
+                .context("Invalid lsps-client-order-retention-days")
+        })
+        .transpose()?;
+    let rpc = ClnRpc::new(configured_plugin.configuration().rpc_file).await?;
+    let state = PluginState::new(rpc, info_ttl);
+    let pinned = state.pins.load(&mut *state.rpc.lock().await).await?;
+    log::info!("Loaded {} pinned orders", pinned);
+
+    let plugin = configured_plugin.start(state).await?;
+    // Our node doesn't see the payments of these orders after a restart either
+    for order in plugin.state().pins.list() {
+        if order.external_payment && !order.is_final() && !order.channel_ready {
+            watch_external_payment(&plugin, order.peer_id, &order.order_id);
+        }
+    }
+    if let Some(order_retention) = order_retention {
+        tokio::spawn(compact_orders_periodically(plugin.clone(), order_retention));
+    }
     plugin.join().await?;
     return Ok(());
 }
 
+/// Compacts the orders every [`ORDER_COMPACTION_INTERVAL`]
+async fn compact_orders_periodically(plugin: Plugin<PluginState>, retention: Duration) {
+    let mut interval = tokio::time::interval(ORDER_COMPACTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        match compact_orders(plugin.state(), retention).await {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {} orders after the retention period", removed),
+            Err(err) => log::warn!("Failed to compact orders: {:?}", err),
+        }
+    }
+}
+
+/// Removes the completed and failed orders that are older than `retention`
+///
+/// Returns the number of orders that were removed
+async fn compact_orders(state: &PluginState, retention: Duration) -> Result<usize> {
+    let retention_seconds = i64::try_from(retention.as_secs())?;
+    let created_before =
+        IsoDatetime::from_unix_timestamp(IsoDatetime::now().unix_timestamp() - retention_seconds)?;
+    let removed = state.pins.compact(created_before);
+
+    let mut rpc = state.rpc.lock().await;
+    for order in &removed {
+        if let Err(err) = order.delete(&mut rpc).await {
+            log::warn!("Failed to delete order {}: {:?}", order.order_id, err);
+        }
+    }
+    Ok(removed.len())
+}
+
+/// Notification handler for `shutdown`
+///
+/// lightningd stops reading our responses shortly after it sends
+/// the notification. We respond to all pending `lsps1-await-order`-calls
+/// before the plugin stops.
+async fn handle_shutdown(plugin: Plugin<PluginState>, _value: serde_json::Value) -> Result<()> {
+    log::info!("Received shutdown notification");
+    plugin.state().waits.shutdown();
+    plugin.shutdown()
+}
+
+/// Notification handler for `disconnect`
+///
+/// The options of an LSP might have changed by the time it reconnects.
+/// The cached `lsps1.get_info`-response is dropped.
+async fn handle_disconnect(plugin: Plugin<PluginState>, value: serde_json::Value) -> Result<()> {
+    // Newer versions of Core Lightning wrap the notification data
+    // in an object named after the topic
+    let data = value.get("disconnect").unwrap_or(&value);
+    let peer_id = match data["id"].as_str().map(PublicKey::from_hex) {
+        Some(Ok(peer_id)) => peer_id,
+        _ => {
+            log::warn!("Error in parsing disconnect notification: {}", value);
+            return Ok(());
+        }
+    };
+
+    if plugin.state().info_cache.invalidate(&peer_id) {
+        log::debug!("Dropped cached info of peer {}", peer_id.to_hex());
+    }
+    Ok(())
+}
+
+/// Notification handler for `channel_opened`
+async fn handle_channel_opened(
+    plugin: Plugin<PluginState>,
+    value: serde_json::Value,
+) -> Result<()> {
+    handle_channel_ready(plugin, "channel_opened", value).await
+}
+
+/// Notification handler for `channel_state_changed`
+async fn handle_channel_state_changed(
+    plugin: Plugin<PluginState>,
+    value: serde_json::Value,
+) -> Result<()> {
+    handle_channel_ready(plugin, "channel_state_changed", value).await
+}
+
+/// Completes the orders whose channel became ready
+///
+/// All ready channels to the peer are correlated with the orders
+/// bought from it. A [`LSPS1_CHANNEL_READY`]-notification is sent for
+/// every order that is completed.
+async fn handle_channel_ready(
+    plugin: Plugin<PluginState>,
+    topic: &str,
+    value: serde_json::Value,
+) -> Result<()> {
+    let peer_id = match ready_peer_id(topic, &value) {
+        Ok(Some(peer_id)) => peer_id,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            log::warn!("Error in parsing {} notification: {:?}", topic, err);
+            return Ok(());
+        }
+    };
+    // Channels to peers we never ordered from are none of our business
+    if !plugin
+        .state()
+        .pins
+        .list()
+        .iter()
+        .any(|o| o.peer_id == peer_id)
+    {
+        return Ok(());
+    }
+
+    let mut rpc = plugin.state().rpc.lock().await;
+    let rpc_peer_id =
+        RpcPublicKey::from_slice(&peer_id.inner().serialize()).context("Invalid peer_id")?;
+    let response = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: Some(rpc_peer_id),
+        })
+        .await
+        .context("Failed to call 'listpeerchannels'")?;
+
+    let now = IsoDatetime::now();
+    let mut channels = vec![];
+    for channel in response.channels.unwrap_or_default() {
+        let channel = serde_json::to_value(channel)?;
+        match ReadyChannel::from_peer_channel(&peer_id, &channel, now) {
+            Ok(Some(channel)) => channels.push(channel),
+            Ok(None) => {}
+            Err(err) => log::debug!("Ignoring channel of peer {}: {:?}", peer_id.to_hex(), err),
+        }
+    }
+
+    for ready in complete_ready_orders(&plugin.state().pins, &channels) {
+        plugin
+            .state()
+            .tracker
+            .forget(&ready.order.peer_id, &ready.order.order_id);
+        if !ready.warnings.is_empty() {
+            log::warn!(
+                "Matched order {} to channel {} by capacity: {:?}",
+                ready.order.order_id,
+                ready.notification["funding_outpoint"],
+                ready.warnings
+            );
+        }
+        if let Err(err) = ready.order.store(&mut rpc).await {
+            log::warn!(
+                "Failed to store completed order {}: {:?}",
+                ready.order.order_id,
+                err
+            );
+        }
+        if let Err(err) = plugin
+            .send_custom_notification(LSPS1_CHANNEL_READY.to_string(), ready.notification)
+            .await
+        {
+            log::debug!("Failed to send {}: {:?}", LSPS1_CHANNEL_READY, err);
+        }
+    }
+    Ok(())
+}
+
+/// Hook handler for `custommsg`
+///
+/// The hook always continues. Other plugins, such as the LSP-server that
+/// runs on the same node, might be interested in the same message.
 async fn handle_custom_msg(
     plugin: Plugin<PluginState>,
     notification: serde_json::Value,
-) -> Result<serde_json::Value>
-where
-{
+) -> Result<serde_json::Value> {
+    if let Err(err) = process_custom_msg(&plugin, notification) {
+        log::debug!("Ignoring custom message: {:?}", err);
+    }
+    Ok(serde_json::json!({"result" : "continue"}))
+}
+
+/// Passes a response to the request that is waiting for it
+///
+/// Requests are ignored. The LSP-server handles them if it is installed.
+fn process_custom_msg(plugin: &Plugin<PluginState>, notification: serde_json::Value) -> Result<()> {
     log::debug!("Process incoming custom msg");
     let rpc_message = serde_json::from_value::<RpcCustomMsgMessage>(notification)?;
     let raw_message = rpc_message.to_raw()?;
 
     // Ignore the message if the BOLT_8_MSG id doesn't match
     // This message is not related to LSPS
-    if raw_message.bolt_8_msg_id() != LSPS_MESSAGE_ID {
-        return Ok(serde_json::json!({"result" : "continue"}));
+    if !is_lsps_message(u16::from_be_bytes(raw_message.bolt_8_msg_id())) {
+        return Ok(());
     }
 
     // Parse the JSONRpc-Response message
     let response_msg: serde_json::Value = serde_json::from_slice(raw_message.msg())
         .with_context(|| "Failed to parse custommsg as json")?;
+    if JsonRpcMessageKind::of(&response_msg) == JsonRpcMessageKind::Request {
+        log::debug!(
+            "Ignoring JSON-RPC request from peer {:?}",
+            raw_message.peer_id()
+        );
+        return Ok(());
+    }
     let json_rpc_id = response_msg.get("id");
     let json_rpc_id = match json_rpc_id {
         None => JsonRpcId::None,
@@ -98,8 +445,17 @@ where
 
     // Match the message with outgoing requests
     let mut matcher = plugin.state().matcher.lock().unwrap();
-    matcher.process_response(&request_id, response_msg);
-    return Ok(serde_json::json!({"result" : "continue"}));
+    if !matcher.process_response(&request_id, response_msg) {
+        if matcher.is_abandoned(&request_id) {
+            log::debug!(
+                "Ignoring late response to abandoned request {:?}",
+                request_id
+            );
+        } else {
+            log::warn!("Received unmatched response {:?}", request_id);
+        }
+    }
+    Ok(())
 }
 
 async fn list_lsp_servers(
@@ -109,7 +465,7 @@ async fn list_lsp_servers(
     log::debug!("Listing lsp-servers");
 
     // Create an LSP-client from the plugin-state
-    let mut client = create_lsp_client_from_plugin(plugin).await?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
 
     // Get all LSP-servers from the client
     let result = client.list_lsps().await?;
@@ -130,34 +486,59 @@ async fn list_protocols(
     log::debug!("Listing protocols for peer {:?}", request);
 
     // Create an LSP-client from the plugin-state
-    let mut client = create_lsp_client_from_plugin(plugin).await?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
     log::debug!("Created client");
 
     // Parse the users request
-    let request: plugin_rpc::ListProtocolsRequest = serde_json::from_value(request)?;
+    let request: plugin_rpc::ListProtocolsRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::ListProtocolsRequest::PARAMS)?;
     log::debug!("plugin_rpc_request created {:?}", request);
     let pubkey: PublicKey = PublicKey::from_hex(&request.peer_id)?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
 
     // Make the request to the LSP-server and return the result
     let lsp_protocol_list = client
-        .request(&pubkey, methods::LSPS0_LIST_PROTOCOLS, NoParams)
+        .request_validated_with_deadline(
+            &pubkey,
+            methods::LSPS0_LIST_PROTOCOLS,
+            NoParams,
+            &deadline,
+        )
         .await?;
     log::debug!("ProtocolList Request {:?}", lsp_protocol_list);
 
     match lsp_protocol_list {
-        JsonRpcResponse::Ok(response) => Ok(json!(response.result)),
-        JsonRpcResponse::Error(err) => Err(anyhow!("{:?}", err)),
+        JsonRpcResponse::Ok(response) => Ok(describe_protocols(response.result)),
+        JsonRpcResponse::Error(err) => Err(anyhow!("{}", err.error)),
     }
 }
 
+/// Adds human readable names to the protocols returned by the server
+///
+/// The list returned by the server is preserved under `raw`
+fn describe_protocols(response: ListprotocolsResponse) -> serde_json::Value {
+    let described: Vec<ProtocolInfo> = response
+        .protocols
+        .iter()
+        .map(|number| ProtocolInfo::from_number(*number))
+        .collect();
+    // Fields the server added next to the protocols are passed on as is
+    json!({
+        "protocols" : described,
+        "raw" : response.protocols,
+        "extra_fields" : response.extra_fields,
+    })
+}
+
 async fn lsps0_send_request(
     plugin: Plugin<PluginState>,
     request: serde_json::Value,
 ) -> Result<serde_json::value::Value, Error> {
-    let mut client = create_lsp_client_from_plugin(plugin).await?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
 
     // Parsing the request
-    let request: plugin_rpc::Lsps0SendRequest = serde_json::from_value(request)?;
+    let request: plugin_rpc::Lsps0SendRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps0SendRequest::PARAMS)?;
 
     pub type Method<'a> = JsonRpcMethod<'a, serde_json::Value, serde_json::Value, DefaultError>;
     let method = Method::new(&request.method);
@@ -165,9 +546,21 @@ async fn lsps0_send_request(
     let peer_id: PublicKey = PublicKey::from_hex(&request.peer_id)?;
 
     let params: serde_json::Value = serde_json::from_str(&request.params)?;
-    let response = client.request(&peer_id, method, params).await?;
+    if let Some(timeout_ms) = request.timeout_ms {
+        client.set_timeout(Duration::from_millis(timeout_ms));
+    }
+    let retries = request.retries.unwrap_or(0);
+    let (response, attempts) = client
+        .request_with_retries(&peer_id, method, params, retries)
+        .await?;
 
-    Ok(serde_json::to_value(response)?)
+    let mut response = serde_json::to_value(response)?;
+    response["metadata"] = json!({
+        "attempts": attempts.attempts,
+        "elapsed_ms": attempts.elapsed.as_millis() as u64,
+        "answered_id": attempts.answered_id,
+    });
+    Ok(response)
 }
 
 async fn lsps1_get_info(
@@ -175,25 +568,38 @@ async fn lsps1_get_info(
     request: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     log::info!("Retrieve lsps1.get_info");
-    let request: plugin_rpc::Lsps1GetInfoRequest = serde_json::from_value(request)?;
+    let request: plugin_rpc::Lsps1GetInfoRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1GetInfoRequest::PARAMS)?;
     let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
 
     // Create an LSP-client from the plugin-state
-    let mut client = create_lsp_client_from_plugin(plugin).await?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
 
-    // Make the request to the LSP-server and return the result
-    let response = client
-        .request(
-            &pubkey,
-            methods::LSPS1_GETINFO,
-            lsps1::schema::Lsps1InfoRequest {},
-        )
+    let info = plugin
+        .state()
+        .get_info_cached(&mut client, &pubkey, false, &deadline)
         .await?;
+    Ok(json!(info))
+}
 
-    match response {
-        JsonRpcResponse::Ok(response) => Ok(json!(response.result)),
-        JsonRpcResponse::Error(err) => Err(anyhow!("{:?}", err)),
-    }
+/// Fetches the `lsps1.get_info`-response of an LSP and replaces the
+/// cached response
+async fn lsps1_refresh_info(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1RefreshInfoRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1RefreshInfoRequest::PARAMS)?;
+    let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
+
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
+    let info = plugin
+        .state()
+        .get_info_cached(&mut client, &pubkey, true, &deadline)
+        .await?;
+    Ok(json!(info))
 }
 
 async fn lsps1_create_order(
@@ -201,14 +607,27 @@ async fn lsps1_create_order(
     request: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     let network = str_to_network(&plugin.configuration().network)?;
-    let mut client = create_lsp_client_from_plugin(plugin).await?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
 
-    let request: plugin_rpc::Lsps1CreateOrderRequest = serde_json::from_value(request)?;
+    let request: plugin_rpc::Lsps1CreateOrderRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1CreateOrderRequest::PARAMS)?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
+    let external_payment = request.external_payment.unwrap_or(false);
 
     // Check the network
     request.refund_onchain_address.require_network(&network)?;
 
     let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    let defaulted = defaulted_order_params(&request);
+
+    // A payment to an LSP on another network is lost
+    let info = plugin
+        .state()
+        .get_info_cached(&mut client, &pubkey, false, &deadline)
+        .await?;
+    info.require_network(&network)
+        .context("Refusing to order a channel")?;
+    check_client_balance(request.client_balance_sat, &info.options)?;
 
     let create_order_request = lsps1::builders::Lsps1CreateOrderRequestBuilder::new()
         .lsp_balance_sat(request.lsp_balance_sat)
@@ -218,24 +637,228 @@ async fn lsps1_create_order(
         .token(request.token)
         .refund_onchain_address(request.refund_onchain_address)
         .announce_channel(request.announce_channel)
+        .channel_type(request.channel_type)
         .build()?;
 
+    // Report every option the order violates instead of only the first
+    // one the LSP would return
+    let violations = info.options.order_violations(&create_order_request);
+    if !violations.is_empty() {
+        let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+        return Err(anyhow!(
+            "The LSP doesn't accept the order: {}. Call lsps1-refresh-info if the options of the LSP changed",
+            violations.join("; ")
+        ));
+    }
+
     // Make the request to the LSP-server and return the result
     let response = client
-        .request(&pubkey, methods::LSPS1_CREATE_ORDER, create_order_request)
+        .request_validated_with_deadline(
+            &pubkey,
+            methods::LSPS1_CREATE_ORDER,
+            create_order_request,
+            &deadline,
+        )
         .await?;
 
     match response {
-        JsonRpcResponse::Ok(ok) => return Ok(json!(ok.result)),
-        JsonRpcResponse::Error(err) => {
-            return Err(anyhow!(
-                "Code {}-{} \t {}",
-                err.error.code,
-                err.error.message,
-                err.error.data.unwrap_or_default().to_string()
-            ))
+        JsonRpcResponse::Ok(ok) => {
+            let warnings = check_order_pin(&plugin, &pubkey, &ok.result).await;
+            if external_payment {
+                track_external_payment(&plugin, &pubkey, &ok.result).await;
+            }
+            let mut response = with_warnings(json!(ok.result), warnings);
+            if let Some(object) = response.as_object_mut() {
+                object.insert("defaulted".to_string(), json!(defaulted));
+                if external_payment {
+                    object.insert(
+                        "external_payment".to_string(),
+                        describe_external_payment(&ok.result.payment),
+                    );
+                }
+            }
+            return Ok(response);
+        }
+        JsonRpcResponse::Error(err) => return Err(anyhow!("{}", err.error)),
+    }
+}
+
+/// Plans the orders that buy `target_inbound_sat` for the lowest fee
+///
+/// LSPS1 has no way to price an order without creating it. The fees are
+/// estimated from the previous orders of the LSP instead. The result says
+/// how much the estimate can be trusted.
+async fn lsps1_plan_liquidity(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1PlanLiquidityRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1PlanLiquidityRequest::PARAMS)?;
+    let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
+
+    let info = plugin
+        .state()
+        .get_info_cached(&mut client, &pubkey, false, &deadline)
+        .await?;
+
+    let orders: Vec<PinnedOrder> = plugin
+        .state()
+        .pins
+        .list()
+        .into_iter()
+        .filter(|order| order.peer_id == pubkey)
+        .collect();
+    let history = FeeHistory::from_orders(&orders);
+    if history.is_empty() {
+        return Err(anyhow!(
+            "No previous orders of {} to estimate its fees from. Order a channel using lsps1-create-order first",
+            request.peer_id
+        ));
+    }
+
+    let mut confidences = BTreeMap::new();
+    let plan = plan_liquidity(request.target_inbound_sat, &info, |lsp_balance_sat| {
+        let estimate = history
+            .estimate(lsp_balance_sat)
+            .context("Failed to estimate the fee")?;
+        confidences.insert(lsp_balance_sat, estimate.confidence);
+        Ok(estimate.fee_total_sat)
+    })?;
+    let confidence = plan
+        .orders
+        .iter()
+        .filter_map(|order| confidences.get(&order.lsp_balance_sat))
+        .max();
+
+    Ok(json!({
+        "orders" : plan.orders,
+        "inbound_sat" : SatAmountJson::from(plan.inbound_sat),
+        "fee_total_sat" : SatAmountJson::from(plan.fee_total_sat),
+        "quote_source" : "estimate",
+        "confidence" : confidence,
+    }))
+}
+
+/// Lists the optional params of `lsps1-create-order` that were omitted
+///
+/// These are filled in with a default value. E.g.: an omitted
+/// `client_balance_sat` orders a channel without a client balance.
+fn defaulted_order_params(request: &plugin_rpc::Lsps1CreateOrderRequest) -> Vec<&'static str> {
+    let mut defaulted = Vec::new();
+    if request.client_balance_sat.is_none() {
+        defaulted.push("client_balance_sat");
+    }
+    defaulted
+}
+
+/// Describes the invoice a user pays from an external wallet
+fn describe_external_payment(payment: &lsps1::schema::Payment) -> serde_json::Value {
+    let bolt11 = invoice::normalize_bolt11(&payment.bolt11_invoice)
+        .unwrap_or_else(|_| payment.bolt11_invoice.clone());
+    json!({
+        "bolt11" : bolt11,
+        "uri" : invoice::lightning_uri(&bolt11),
+        "order_total_sat" : SatAmountJson::from(payment.order_total_sat),
+        "payment_state" : payment.state,
+    })
+}
+
+/// Remembers that an order is paid by an external wallet and starts polling it
+///
+/// Our node never sees the payment. The order is polled until the LSP
+/// reports it as final or our node sees its channel become ready.
+async fn track_external_payment(
+    plugin: &Plugin<PluginState>,
+    peer_id: &PublicKey,
+    order: &lsps1::schema::Lsps1GetOrderResponse,
+) {
+    let order_id = order.order_id.to_string();
+    if let Some(marked) = plugin
+        .state()
+        .pins
+        .mark_external_payment(peer_id, &order_id)
+    {
+        let mut rpc = plugin.state().rpc.lock().await;
+        if let Err(err) = marked.store(&mut rpc).await {
+            log::warn!("Failed to store pin of order {}: {:?}", order_id, err);
+        }
+    }
+    watch_external_payment(plugin, *peer_id, &order_id);
+}
+
+/// Polls an order every [`EXTERNAL_PAYMENT_POLL_INTERVAL`] in the background
+///
+/// Every response is handled like the response of `lsps1-get-order`. The
+/// polling stops once the [`OrderTracker`] no longer watches the order.
+fn watch_external_payment(plugin: &Plugin<PluginState>, peer_id: PublicKey, order_id: &str) {
+    let order_id: OrderId = match order_id.parse() {
+        Ok(order_id) => order_id,
+        Err(err) => {
+            log::warn!("Can't watch order {}: {:?}", order_id, err);
+            return;
+        }
+    };
+    if !plugin
+        .state()
+        .tracker
+        .watch(&peer_id, &order_id.to_string())
+    {
+        return;
+    }
+
+    let plugin = plugin.clone();
+    tokio::spawn(async move {
+        let key = order_id.to_string();
+        log::info!("Watching the payment of order {}", key);
+        while plugin.state().tracker.is_watched(&peer_id, &key) {
+            tokio::time::sleep(EXTERNAL_PAYMENT_POLL_INTERVAL).await;
+            let order = match create_lsp_client_from_plugin(&plugin) {
+                Ok(mut client) => client.lsps1_get_order(&peer_id, order_id).await,
+                Err(err) => Err(err),
+            };
+            // The LSP might be offline for a while. We try again later
+            let order = match order {
+                Ok(order) => order,
+                Err(err) => {
+                    log::debug!("Failed to poll order {}: {:?}", key, err);
+                    continue;
+                }
+            };
+            if !check_order_pin(&plugin, &peer_id, &order).await.is_empty() {
+                log::warn!(
+                    "Stopped watching order {}: the LSP changed its payment details",
+                    key
+                );
+                plugin.state().tracker.forget(&peer_id, &key);
+                break;
+            }
+            report_payment_received(&plugin, &peer_id, &order).await;
+            report_final_state(&plugin, &peer_id, &order).await;
         }
+        log::info!("Stopped watching the payment of order {}", key);
+    });
+}
+
+/// Fails if the user asked for a client balance the LSP never provides
+///
+/// Many LSPs advertise `max_initial_client_balance_sat = 0`. The LSP
+/// would refuse the order with an `option_mismatch`.
+fn check_client_balance(
+    client_balance_sat: Option<SatAmount>,
+    options: &Lsps1Options,
+) -> Result<()> {
+    let requested = client_balance_sat.map(|x| x.sat_value()).unwrap_or(0);
+    if requested > 0 && options.max_initial_client_balance_sat.sat_value() == 0 {
+        return Err(anyhow!(
+            "The LSP doesn't accept the order: it doesn't provide a client balance \
+             (max_initial_client_balance_sat is 0) but {} sat was requested. \
+             Omit client_balance_sat or set it to 0",
+            requested
+        ));
     }
+    Ok(())
 }
 
 async fn lsps1_get_order(
@@ -243,28 +866,538 @@ async fn lsps1_get_order(
     request: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     // Create a client that for sending messages
-    let mut client = create_lsp_client_from_plugin(plugin).await?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
 
     // Parse the request and pubkey
-    let request: plugin_rpc::Lsps1GetOrderRequest = serde_json::from_value(request)?;
+    let request: plugin_rpc::Lsps1GetOrderRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1GetOrderRequest::PARAMS)?;
     let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    // The LSP would reject an order_id that isn't a lowercase uuid
+    let order_id: OrderId = request.order_id.parse()?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
 
     let get_order_request = lsps1::builders::Lsps1GetOrderRequestBuilder::new()
-        .order_id(request.order_id)
+        .order_id(order_id)
         .build()?;
 
     let response = client
-        .request(&pubkey, methods::LSPS1_GET_ORDER, get_order_request)
+        .request_validated_with_deadline(
+            &pubkey,
+            methods::LSPS1_GET_ORDER,
+            get_order_request,
+            &deadline,
+        )
         .await?;
 
     match response {
-        JsonRpcResponse::Ok(ok) => return Ok(json!(ok.result)),
-        JsonRpcResponse::Error(err) => {
-            return Err(anyhow!("{} : {}", err.error.code, err.error.message))
+        JsonRpcResponse::Ok(ok) => {
+            let warnings = check_order_pin(&plugin, &pubkey, &ok.result).await;
+            report_payment_received(&plugin, &pubkey, &ok.result).await;
+            report_final_state(&plugin, &pubkey, &ok.result).await;
+            return Ok(with_warnings(json!(ok.result), warnings));
         }
+        JsonRpcResponse::Error(err) => return Err(anyhow!("{}", err.error)),
     }
 }
 
+/// Returns the invoice of an order that hasn't been paid yet
+///
+/// The invoice is decoded by our own node to verify it hasn't expired
+/// and requests the `order_total_sat` of the order.
+async fn lsps1_get_invoice(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1GetInvoiceRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1GetInvoiceRequest::PARAMS)?;
+    let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    let order_id: OrderId = request.order_id.parse()?;
+    let deadline = Deadline::from_timeout_ms(request.timeout_ms);
+
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
+
+    let get_order_request = lsps1::builders::Lsps1GetOrderRequestBuilder::new()
+        .order_id(order_id)
+        .build()?;
+
+    let response = client
+        .request_validated_with_deadline(
+            &pubkey,
+            methods::LSPS1_GET_ORDER,
+            get_order_request,
+            &deadline,
+        )
+        .await?;
+    let order = match response {
+        JsonRpcResponse::Ok(ok) => ok.result,
+        JsonRpcResponse::Error(err) => return Err(anyhow!("{}", err.error)),
+    };
+
+    // Never hand out an invoice of an order the LSP has changed
+    let warnings = check_order_pin(&plugin, &pubkey, &order).await;
+    if !warnings.is_empty() {
+        return Err(anyhow!(
+            "Refusing to pay order {}: the LSP changed its payment details",
+            order.order_id
+        ));
+    }
+    let payment = order.payment;
+
+    if invoice::is_already_paid(&payment.state) {
+        return Ok(json!({
+            "already_paid" : true,
+            "payment_state" : payment.state,
+        }));
+    }
+
+    let bolt11 = invoice::normalize_bolt11(&payment.bolt11_invoice)?;
+    let decoded = plugin
+        .state()
+        .rpc
+        .lock()
+        .await
+        .call_typed(&DecodepayRequest {
+            bolt11: bolt11.clone(),
+            description: None,
+        })
+        .await
+        .context("Failed to decode the invoice of the order")?;
+
+    let expires_at = invoice::invoice_expires_at(
+        decoded.created_at,
+        decoded.expiry,
+        IsoDatetime::now().unix_timestamp(),
+    )?;
+    invoice::check_invoice_amount(
+        decoded.amount_msat.map(|x| x.msat()),
+        payment.order_total_sat,
+    )?;
+
+    let uri = request
+        .uri
+        .unwrap_or(false)
+        .then(|| invoice::lightning_uri(&bolt11));
+
+    Ok(json!({
+        "bolt11" : bolt11,
+        "amount_sat" : SatAmountJson::from(payment.order_total_sat),
+        "expires_at" : expires_at,
+        "already_paid" : false,
+        "payment_state" : payment.state,
+        "uri" : uri,
+    }))
+}
+
+/// Polls `lsps1.get_order` until the order is no longer `CREATED`
+///
+/// If the server supports it, we use a long-poll. Otherwise, we
+/// poll the order every [`AWAIT_ORDER_POLL_INTERVAL`]. If the LSP
+/// is temporarily unavailable we retry after the time it asked for.
+///
+/// A [`LSPS1_ORDER_PROGRESS`]-notification is sent after every poll.
+/// The wait can be stopped using `lsps1-abort-wait` or by a shutdown.
+/// In that case the latest known state of the order is returned.
+///
+/// Fails if the LSP changed the payment details of the order.
+async fn lsps1_await_order(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1AwaitOrderRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1AwaitOrderRequest::PARAMS)?;
+    let pubkey = PublicKey::from_hex(&request.peer_id)?;
+    let order_id: OrderId = request.order_id.parse()?;
+    let timeout = Duration::from_secs(
+        request
+            .timeout_seconds
+            .unwrap_or(AWAIT_ORDER_DEFAULT_TIMEOUT_SECONDS),
+    );
+    let start = Instant::now();
+    let deadline = start + timeout;
+
+    // Waits without an abort_token can still be stopped by a shutdown
+    let token = match &request.abort_token {
+        Some(token) => token.clone(),
+        None => match generate_random_rpc_id() {
+            JsonRpcId::String(id) => id,
+            id => format!("{:?}", id),
+        },
+    };
+    let mut wait = plugin.state().waits.register(token)?;
+    let mut client = create_lsp_client_from_plugin(&plugin)?;
+
+    let mut latest_order: Option<lsps1::schema::Lsps1GetOrderResponse> = None;
+    let mut polls: u64 = 0;
+
+    let outcome: Result<WaitOutcome> = async {
+        // Check if the server supports long-polling
+        let info = match wait
+            .run(
+                plugin
+                    .state()
+                    .get_info_cached(&mut client, &pubkey, false, &Deadline::none()),
+            )
+            .await
+        {
+            Ok(info) => info?,
+            Err(reason) => return Ok(WaitOutcome::Stopped(reason)),
+        };
+        let use_long_poll = info.lsp_extensions().supports_long_poll();
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait_for_change_seconds = if use_long_poll && !remaining.is_zero() {
+                let seconds = remaining
+                    .as_secs()
+                    .clamp(1, lsps1::schema::MAX_WAIT_FOR_CHANGE_SECONDS);
+                // Give the server some time to respond after the wait
+                client.set_timeout(Duration::from_secs(seconds) + DEFAULT_RESPONSE_TIMEOUT);
+                Some(seconds)
+            } else {
+                None
+            };
+
+            let get_order_request = lsps1::builders::Lsps1GetOrderRequestBuilder::new()
+                .order_id(order_id)
+                .wait_for_change_seconds(wait_for_change_seconds)
+                .build()?;
+
+            let response = match wait
+                .run(client.request_validated(&pubkey, methods::LSPS1_GET_ORDER, get_order_request))
+                .await
+            {
+                Ok(response) => response?,
+                Err(reason) => return Ok(WaitOutcome::Stopped(reason)),
+            };
+            let order = match response {
+                JsonRpcResponse::Ok(ok) => ok.result,
+                // The LSP asked us to come back later. E.g: during maintenance
+                JsonRpcResponse::Error(err) if err.error.is_retryable() && !remaining.is_zero() => {
+                    let retry_after = err.error.retry_after().unwrap_or(AWAIT_ORDER_POLL_INTERVAL);
+                    log::debug!(
+                        "LSP can't serve order {} right now. Retrying in {:?}",
+                        request.order_id,
+                        retry_after
+                    );
+                    let sleep = tokio::time::sleep(remaining.min(retry_after));
+                    if let Err(reason) = wait.run(sleep).await {
+                        return Ok(WaitOutcome::Stopped(reason));
+                    }
+                    continue;
+                }
+                JsonRpcResponse::Error(err) => return Err(anyhow!("{}", err.error)),
+            };
+
+            polls += 1;
+            log::debug!(
+                "Polled order {} ({} polls): {:?}",
+                request.order_id,
+                polls,
+                order.order_state
+            );
+            let progress = json!({
+                "abort_token" : request.abort_token,
+                "peer_id" : request.peer_id,
+                "order_id" : request.order_id,
+                "poll" : polls,
+                "order_state" : order.order_state,
+                "payment_state" : order.payment.state,
+                "has_channel" : order.channel.is_some(),
+                "observed_at" : IsoDatetime::now(),
+                "elapsed_ms" : start.elapsed().as_millis() as u64,
+            });
+            if let Err(err) = plugin
+                .send_custom_notification(LSPS1_ORDER_PROGRESS.to_string(), progress)
+                .await
+            {
+                log::debug!("Failed to send {}: {:?}", LSPS1_ORDER_PROGRESS, err);
+            }
+
+            // Never keep waiting on an order the LSP has changed
+            if !check_order_pin(&plugin, &pubkey, &order).await.is_empty() {
+                return Err(anyhow!(
+                    "Refusing to wait for order {}: the LSP changed its payment details",
+                    order.order_id
+                ));
+            }
+            report_payment_received(&plugin, &pubkey, &order).await;
+            report_final_state(&plugin, &pubkey, &order).await;
+            let is_changed = order.order_state != lsps1::schema::OrderState::Created;
+            latest_order = Some(order);
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if is_changed {
+                return Ok(WaitOutcome::Finished("order_changed"));
+            } else if remaining.is_zero() {
+                return Ok(WaitOutcome::Finished("timeout"));
+            }
+
+            if !use_long_poll {
+                let sleep = tokio::time::sleep(remaining.min(AWAIT_ORDER_POLL_INTERVAL));
+                if let Err(reason) = wait.run(sleep).await {
+                    return Ok(WaitOutcome::Stopped(reason));
+                }
+            }
+        }
+    }
+    .await;
+
+    let (waited, stop_reason) = match outcome? {
+        WaitOutcome::Finished(stop_reason) => (true, stop_reason),
+        WaitOutcome::Stopped(reason) => {
+            log::debug!(
+                "Stopped waiting for order {}: {:?}",
+                request.order_id,
+                reason
+            );
+            (false, reason.as_str())
+        }
+    };
+
+    Ok(json!({
+        "order" : latest_order,
+        "waited" : waited,
+        "stop_reason" : stop_reason,
+        "polls" : polls,
+        "abort_token" : request.abort_token,
+    }))
+}
+
+enum WaitOutcome {
+    /// The order changed or the wait timed out
+    Finished(&'static str),
+    /// The wait was stopped before it finished
+    Stopped(StopReason),
+}
+
+/// Aborts a running `lsps1-await-order`
+async fn lsps1_abort_wait(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1AbortWaitRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1AbortWaitRequest::PARAMS)?;
+    if plugin.state().waits.abort(&request.abort_token) {
+        Ok(json!({ "aborted" : true }))
+    } else {
+        Err(anyhow!(
+            "No active wait with abort_token '{}'",
+            request.abort_token
+        ))
+    }
+}
+
+/// Lists all orders pinned by the plugin
+async fn lsps1_list_orders(
+    plugin: Plugin<PluginState>,
+    _request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let orders: Vec<_> = plugin
+        .state()
+        .pins
+        .list()
+        .into_iter()
+        .map(|order| {
+            json!({
+                "peer_id" : order.peer_id,
+                "order_id" : order.order_id,
+                "fee_total_sat" : SatAmountJson::from(order.fee_total_sat()),
+                "order_total_sat" : SatAmountJson::from(order.order_total_sat()),
+                "changed_fields" : order.changed_fields,
+                "order_state" : order.order_state,
+                "funding_outpoint" : order.funding_outpoint,
+                "channel_ready" : order.channel_ready,
+                "warnings" : order.warnings(),
+            })
+        })
+        .collect();
+    Ok(json!({ "orders" : orders }))
+}
+
+/// Verifies that the receipt of an order was signed by the LSP
+///
+/// The receipt doesn't have to be verified by the node that purchased
+/// the channel. Anyone who knows the node id of the LSP can verify it.
+async fn lsps1_verify_receipt(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1VerifyReceiptRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1VerifyReceiptRequest::PARAMS)?;
+    let pubkey = PublicKey::from_hex(&request.peer_id)?;
+
+    let payload = receipt::parse_receipt(&request.receipt, request.order_id.as_deref())?;
+    let checkmessage = receipt::checkmessage_request(&request.receipt, &pubkey)?;
+    let checked = plugin
+        .state()
+        .rpc
+        .lock()
+        .await
+        .call_typed(&checkmessage)
+        .await
+        .context("Failed to check the signature of the receipt")?;
+
+    Ok(json!({
+        "verified" : receipt::is_signed_by(checked.verified, &checked.pubkey, &pubkey),
+        "payload" : payload,
+    }))
+}
+
+/// Writes all orders to a file
+///
+/// The file can be restored on another node using `lsps1-import-orders`
+async fn lsps1_export_orders(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1ExportOrdersRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1ExportOrdersRequest::PARAMS)?;
+    let export = plugin.state().pins.export();
+    tokio::fs::write(&request.path, export.to_json()?)
+        .await
+        .with_context(|| format!("Failed to write orders to '{}'", request.path))?;
+
+    Ok(json!({
+        "path" : request.path,
+        "schema_version" : export.schema_version,
+        "orders" : export.len(),
+    }))
+}
+
+/// Restores the orders of a file written by `lsps1-export-orders`
+///
+/// Orders that are already known keep their local state. The
+/// differences are returned as conflicts.
+async fn lsps1_import_orders(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let request: plugin_rpc::Lsps1ImportOrdersRequest =
+        plugin_rpc::parse_params(request, plugin_rpc::Lsps1ImportOrdersRequest::PARAMS)?;
+    let content = tokio::fs::read_to_string(&request.path)
+        .await
+        .with_context(|| format!("Failed to read orders from '{}'", request.path))?;
+    let export = OrderExport::from_json(&content)?;
+    let report = plugin.state().pins.import(export);
+
+    let mut rpc = plugin.state().rpc.lock().await;
+    for order in &report.imported {
+        if let Err(err) = order.store(&mut rpc).await {
+            log::warn!(
+                "Failed to store imported order {}: {:?}",
+                order.order_id,
+                err
+            );
+        }
+    }
+
+    Ok(json!({
+        "imported" : report.imported.len(),
+        "unchanged" : report.unchanged,
+        "conflicts" : report.conflicts,
+    }))
+}
+
+/// Compares the payment details of `order` against the pinned ones
+///
+/// Stores the pin in the datastore if it was created, flagged or if the
+/// details that identify the channel of the order changed. Sends a
+/// [`LSPS1_LSP_INCONSISTENT`]-notification if the LSP changed the
+/// payment details and returns the warnings of the order.
+async fn check_order_pin(
+    plugin: &Plugin<PluginState>,
+    peer_id: &PublicKey,
+    order: &lsps1::schema::Lsps1GetOrderResponse,
+) -> Vec<&'static str> {
+    let order_id = order.order_id.to_string();
+    let check = plugin
+        .state()
+        .pins
+        .check(peer_id, &order_id, &order.payment);
+    // Remember what identifies the channel of the order
+    let tracked = plugin.state().pins.track(peer_id, order);
+
+    let to_store = match tracked {
+        Some(tracked) => Some(tracked),
+        None => check.needs_store.then(|| check.order.clone()),
+    };
+    if let Some(to_store) = to_store {
+        let mut rpc = plugin.state().rpc.lock().await;
+        if let Err(err) = to_store.store(&mut rpc).await {
+            log::warn!("Failed to store pin of order {}: {:?}", order_id, err);
+        }
+    }
+
+    if !check.changed_fields.is_empty() {
+        log::warn!(
+            "LSP {} changed {:?} of order {}",
+            peer_id.to_hex(),
+            check.changed_fields,
+            order_id
+        );
+        let notification = json!({
+            "peer_id" : peer_id,
+            "order_id" : order_id,
+            "changed_fields" : check.changed_fields,
+        });
+        if let Err(err) = plugin
+            .send_custom_notification(LSPS1_LSP_INCONSISTENT.to_string(), notification)
+            .await
+        {
+            log::debug!("Failed to send {}: {:?}", LSPS1_LSP_INCONSISTENT, err);
+        }
+    }
+
+    check.order.warnings()
+}
+
+/// Sends a [`LSPS1_ORDER_UPDATE`]-notification if the order reached
+/// its final state for the first time
+async fn report_final_state(
+    plugin: &Plugin<PluginState>,
+    peer_id: &PublicKey,
+    order: &lsps1::schema::Lsps1GetOrderResponse,
+) {
+    let Some(update) = plugin.state().final_states.take_update(peer_id, order) else {
+        return;
+    };
+    if let Err(err) = plugin
+        .send_custom_notification(LSPS1_ORDER_UPDATE.to_string(), update)
+        .await
+    {
+        log::debug!("Failed to send {}: {:?}", LSPS1_ORDER_UPDATE, err);
+    }
+}
+
+/// Sends a [`LSPS1_PAYMENT_RECEIVED`]-notification if the LSP reports
+/// the payment of an externally paid order for the first time
+async fn report_payment_received(
+    plugin: &Plugin<PluginState>,
+    peer_id: &PublicKey,
+    order: &lsps1::schema::Lsps1GetOrderResponse,
+) {
+    let Some(update) = plugin.state().tracker.observe(peer_id, order) else {
+        return;
+    };
+    if let Err(err) = plugin
+        .send_custom_notification(LSPS1_PAYMENT_RECEIVED.to_string(), update)
+        .await
+    {
+        log::debug!("Failed to send {}: {:?}", LSPS1_PAYMENT_RECEIVED, err);
+    }
+}
+
+/// Adds the warnings of an order to an rpc-response
+fn with_warnings(
+    mut response: serde_json::Value,
+    warnings: Vec<&'static str>,
+) -> serde_json::Value {
+    if let Some(object) = response.as_object_mut() {
+        object.insert("warnings".to_string(), json!(warnings));
+    }
+    response
+}
+
 fn str_to_network(network: &str) -> Result<Network> {
     match network {
         "bitcoin" => Ok(Network::Bitcoin),
@@ -274,3 +1407,136 @@ fn str_to_network(network: &str) -> Result<Network> {
         _ => Err(anyhow!("Unknown network '{}", network)),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cln_lsps::client::LspClientError;
+    use lsp_primitives::json_rpc::ErrorData;
+
+    #[test]
+    fn raw_protocols_are_preserved() {
+        let response: ListprotocolsResponse =
+            serde_json::from_value(json!({ "protocols" : [5, 0, 42, 1], "name" : "Example LSP" }))
+                .unwrap();
+        let result = describe_protocols(response);
+        assert_eq!(result["raw"], json!([5, 0, 42, 1]));
+        assert_eq!(result["extra_fields"], json!({ "name" : "Example LSP" }));
+
+        let names: Vec<&str> = result["protocols"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["LSPS5", "LSPS0", "LSPS42", "LSPS1"]);
+        assert_eq!(result["protocols"][1]["description"], "Transport layer");
+        assert_eq!(result["protocols"][2]["description"], "Unknown protocol");
+    }
+
+    #[test]
+    fn missing_get_info_is_explained() {
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let err = anyhow::Error::new(LspClientError::ErrorResponse {
+            method: "lsps1.get_info".to_string(),
+            error: ErrorData::method_not_found("lsps1.get_info"),
+        });
+        let err = explain_info_error(&peer_id, err);
+        assert_eq!(
+            err.to_string(),
+            "Peer 0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798 lists protocol 1 \
+             but does not answer lsps1.get_info: LSP-server responded to lsps1.get_info with \
+             method_not_found: Code -32601 - Method not found \t {\"method\":\"lsps1.get_info\"}"
+        );
+
+        // Other errors are returned as is
+        let err = explain_info_error(&peer_id, anyhow!("Time-out"));
+        assert_eq!(err.to_string(), "Time-out");
+    }
+
+    fn create_order_request(
+        client_balance_sat: Option<u64>,
+    ) -> plugin_rpc::Lsps1CreateOrderRequest {
+        let mut params = json!({
+            "peer_id" : "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "lsp_balance_sat" : "100000",
+            "channel_expiry_blocks" : 144,
+        });
+        if let Some(client_balance_sat) = client_balance_sat {
+            params["client_balance_sat"] = json!(client_balance_sat.to_string());
+        }
+        plugin_rpc::parse_params(params, plugin_rpc::Lsps1CreateOrderRequest::PARAMS).unwrap()
+    }
+
+    fn options(max_initial_client_balance_sat: u64) -> Lsps1Options {
+        serde_json::from_value(json!({
+            "min_required_channel_confirmations": 0,
+            "min_funding_confirms_within_blocks": 1,
+            "min_onchain_payment_confirmations": null,
+            "supports_zero_channel_reserve": true,
+            "min_onchain_payment_size_sat": null,
+            "max_channel_expiry_blocks": 20160,
+            "min_initial_client_balance_sat": "0",
+            "max_initial_client_balance_sat": max_initial_client_balance_sat.to_string(),
+            "min_initial_lsp_balance_sat": "0",
+            "max_initial_lsp_balance_sat": "1000000",
+            "min_channel_balance_sat": "0",
+            "max_channel_balance_sat": "1000000"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn omitted_client_balance_is_defaulted() {
+        let request = create_order_request(None);
+        assert_eq!(defaulted_order_params(&request), vec!["client_balance_sat"]);
+        assert!(check_client_balance(request.client_balance_sat, &options(0)).is_ok());
+
+        // An explicit zero isn't a default
+        let request = create_order_request(Some(0));
+        assert!(defaulted_order_params(&request).is_empty());
+        assert!(check_client_balance(request.client_balance_sat, &options(0)).is_ok());
+    }
+
+    #[test]
+    fn client_balance_is_refused_if_lsp_provides_none() {
+        let request = create_order_request(Some(50_000));
+        assert!(defaulted_order_params(&request).is_empty());
+
+        let err = check_client_balance(request.client_balance_sat, &options(0)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("max_initial_client_balance_sat is 0"));
+        assert!(err.to_string().contains("50000 sat was requested"));
+
+        // The range is checked against the options of the LSP later
+        assert!(check_client_balance(request.client_balance_sat, &options(100_000)).is_ok());
+    }
+
+    #[test]
+    fn external_payment_shows_the_invoice() {
+        let request = create_order_request(None);
+        assert_eq!(request.external_payment, None);
+
+        let payment: lsps1::schema::Payment = serde_json::from_value(json!({
+            "state" : "EXPECT_PAYMENT",
+            "fee_total_sat" : "1000",
+            "order_total_sat" : "101000",
+            "bolt11_invoice" : "lightning:LNBCRT1M1PJTEST",
+            "onchain_address" : null,
+            "min_onchain_payment_confirmations" : null,
+            "min_fee_for_0conf" : null,
+            "onchain_payment" : null
+        }))
+        .unwrap();
+        let described = describe_external_payment(&payment);
+        assert_eq!(described["bolt11"], "lnbcrt1m1pjtest");
+        assert_eq!(described["uri"], "lightning:LNBCRT1M1PJTEST");
+        assert_eq!(described["order_total_sat"], 101_000);
+        assert_eq!(described["payment_state"], "EXPECT_PAYMENT");
+    }
+}
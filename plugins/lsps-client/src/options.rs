@@ -0,0 +1,43 @@
+use cln_plugin::options;
+
+use cln_lsps::cln_rpc_client::DEFAULT_RESPONSE_TIMEOUT;
+
+use crate::info_cache::DEFAULT_INFO_TTL;
+
+pub(crate) const LSPS0_RESPONSE_TIMEOUT_MS: &str = "lsps0-response-timeout-ms";
+
+pub fn lsps0_response_timeout_ms() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS0_RESPONSE_TIMEOUT_MS,
+        DEFAULT_RESPONSE_TIMEOUT.as_millis() as i64,
+        "Time in milliseconds the client waits for the LSP-server to respond",
+    )
+}
+
+pub(crate) const LSPS_CLIENT_INFO_TTL_SECONDS: &str = "lsps-client-info-ttl-seconds";
+
+pub fn lsps_client_info_ttl_seconds() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS_CLIENT_INFO_TTL_SECONDS,
+        DEFAULT_INFO_TTL.as_secs() as i64,
+        "Time in seconds the client caches the lsps1.get_info-response of an LSP",
+    )
+}
+
+pub(crate) const LSPS_CLIENT_STRICT_RESPONSES: &str = "lsps-client-strict-responses";
+
+pub fn lsps_client_strict_responses() -> options::FlagConfigOption<'static> {
+    options::FlagConfigOption::new_flag(
+        LSPS_CLIENT_STRICT_RESPONSES,
+        "If set responses of an LSP-server that contain unknown or malformed properties are rejected",
+    )
+}
+
+pub(crate) const LSPS_CLIENT_ORDER_RETENTION_DAYS: &str = "lsps-client-order-retention-days";
+
+pub fn lsps_client_order_retention_days() -> options::IntegerConfigOption<'static> {
+    options::IntegerConfigOption::new_i64_no_default(
+        LSPS_CLIENT_ORDER_RETENTION_DAYS,
+        "If set completed and failed orders are removed this many days after they were created. Orders are kept forever by default",
+    )
+}
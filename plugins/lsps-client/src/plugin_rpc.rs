@@ -6,13 +6,27 @@
 /// - core-Lightning RPC (scope of this file)
 /// - RPC between LSP-client and LSP-server (not the scope of this file)
 ///
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 use lsp_primitives::lsps0::common_schemas::{OnchainAddress, SatAmount};
+use lsp_primitives::lsps1::receipt::OrderReceipt;
+use lsp_primitives::lsps1::schema::ChannelTypeFeature;
+use lsp_primitives::redact::{redact_address, redact_message, redact_token};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ListProtocolsRequest {
     pub peer_id: String,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+}
+
+impl ListProtocolsRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "timeout_ms"];
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -23,9 +37,26 @@ pub struct ListProtocolsResponse {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Lsps1GetInfoRequest {
     pub peer_id: String,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+}
+
+impl Lsps1GetInfoRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "timeout_ms"];
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lsps1RefreshInfoRequest {
+    pub peer_id: String,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+}
+
+impl Lsps1RefreshInfoRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "timeout_ms"];
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Lsps1CreateOrderRequest {
     pub peer_id: String,
     pub lsp_balance_sat: SatAmount,
@@ -35,19 +66,217 @@ pub struct Lsps1CreateOrderRequest {
     pub token: Option<String>,
     pub refund_onchain_address: Option<OnchainAddress>,
     pub announce_channel: Option<bool>,
+    /// Requires an LSP that supports the `channel_type` extension
+    pub channel_type: Option<Vec<ChannelTypeFeature>>,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+    /// The invoice is paid by another wallet. The order is polled
+    /// until the LSP reports the payment
+    pub external_payment: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Lsps1CreateOrderRequest {
+    pub const PARAMS: &'static [&'static str] = &[
+        "peer_id",
+        "lsp_balance_sat",
+        "channel_expiry_blocks",
+        "client_balance_sat",
+        "funding_confirms_within_blocks",
+        "token",
+        "refund_onchain_address",
+        "announce_channel",
+        "channel_type",
+        "timeout_ms",
+        "external_payment",
+    ];
+}
+
+impl fmt::Debug for Lsps1CreateOrderRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps1CreateOrderRequest")
+            .field("peer_id", &self.peer_id)
+            .field("lsp_balance_sat", &self.lsp_balance_sat)
+            .field("client_balance_sat", &self.client_balance_sat)
+            .field(
+                "funding_confirms_within_blocks",
+                &self.funding_confirms_within_blocks,
+            )
+            .field("channel_expiry_blocks", &self.channel_expiry_blocks)
+            .field("token", &self.token.as_deref().map(redact_token))
+            .field(
+                "refund_onchain_address",
+                &self
+                    .refund_onchain_address
+                    .as_ref()
+                    .map(|a| redact_address(&a.to_string())),
+            )
+            .field("announce_channel", &self.announce_channel)
+            .field("channel_type", &self.channel_type)
+            .field("timeout_ms", &self.timeout_ms)
+            .field("external_payment", &self.external_payment)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1PlanLiquidityRequest {
+    pub peer_id: String,
+    pub target_inbound_sat: SatAmount,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+}
+
+impl Lsps1PlanLiquidityRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "target_inbound_sat", "timeout_ms"];
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Lsps0SendRequest {
     pub peer_id: String,
     pub method: String,
     pub params: String,
+    /// Overrides the `lsps0-response-timeout-ms` option for each attempt
+    pub timeout_ms: Option<u64>,
+    /// The number of times the request is re-sent after a time-out
+    pub retries: Option<u32>,
+}
+
+impl Lsps0SendRequest {
+    pub const PARAMS: &'static [&'static str] =
+        &["peer_id", "method", "params", "timeout_ms", "retries"];
+}
+
+/// The params might contain a token. Only the length is shown
+impl fmt::Debug for Lsps0SendRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps0SendRequest")
+            .field("peer_id", &self.peer_id)
+            .field("method", &self.method)
+            .field("params", &redact_message(&self.params))
+            .field("timeout_ms", &self.timeout_ms)
+            .field("retries", &self.retries)
+            .finish()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Lsps1GetOrderRequest {
     pub peer_id: String,
     pub order_id: String,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+}
+
+impl Lsps1GetOrderRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "order_id", "timeout_ms"];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1GetInvoiceRequest {
+    pub peer_id: String,
+    pub order_id: String,
+    /// Also return the invoice as a `lightning:`-uri
+    pub uri: Option<bool>,
+    /// Fails the call once it takes longer than this
+    pub timeout_ms: Option<u64>,
+}
+
+impl Lsps1GetInvoiceRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "order_id", "uri", "timeout_ms"];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1AwaitOrderRequest {
+    pub peer_id: String,
+    pub order_id: String,
+    pub timeout_seconds: Option<u64>,
+    /// Allows to abort the wait using `lsps1-abort-wait`
+    pub abort_token: Option<String>,
+}
+
+impl Lsps1AwaitOrderRequest {
+    pub const PARAMS: &'static [&'static str] =
+        &["peer_id", "order_id", "timeout_seconds", "abort_token"];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1AbortWaitRequest {
+    pub abort_token: String,
+}
+
+impl Lsps1AbortWaitRequest {
+    pub const PARAMS: &'static [&'static str] = &["abort_token"];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1VerifyReceiptRequest {
+    pub peer_id: String,
+    /// The `receipt` of an order as returned by `lsps1-get-order`
+    pub receipt: OrderReceipt,
+    /// Fails if the receipt belongs to another order
+    pub order_id: Option<String>,
+}
+
+impl Lsps1VerifyReceiptRequest {
+    pub const PARAMS: &'static [&'static str] = &["peer_id", "receipt", "order_id"];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1ExportOrdersRequest {
+    /// The file the orders are written to
+    pub path: String,
+}
+
+impl Lsps1ExportOrdersRequest {
+    pub const PARAMS: &'static [&'static str] = &["path"];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lsps1ImportOrdersRequest {
+    /// A file written by `lsps1-export-orders`
+    pub path: String,
+}
+
+impl Lsps1ImportOrdersRequest {
+    pub const PARAMS: &'static [&'static str] = &["path"];
+}
+
+/// Parses the params of an rpcmethod
+///
+/// `lightning-cli` passes params as a JSON-array unless `-k` is used.
+/// The array is matched against `field_order` which should be the order
+/// documented in the `usage` of the rpcmethod. Trailing optional params
+/// can be omitted.
+pub fn parse_params<T: DeserializeOwned>(params: Value, field_order: &[&str]) -> Result<T> {
+    let params = match params {
+        Value::Array(values) => {
+            if values.len() > field_order.len() {
+                return Err(anyhow!(
+                    "Too many positional params: expected at most {} ({}) but got {}",
+                    field_order.len(),
+                    field_order.join(" "),
+                    values.len()
+                ));
+            }
+
+            let num_values = values.len();
+            let object: Map<String, Value> = field_order
+                .iter()
+                .zip(values)
+                .filter(|(_, value)| !value.is_null())
+                .map(|(field, value)| (field.to_string(), value))
+                .collect();
+            return serde_json::from_value(Value::Object(object)).with_context(|| {
+                format!(
+                    "Invalid positional params: got {} of ({})",
+                    num_values,
+                    field_order.join(" ")
+                )
+            });
+        }
+        params => params,
+    };
+    Ok(serde_json::from_value(params)?)
 }
 
 type RpcMethodBuilder = cln_plugin::RpcMethodBuilder<crate::PluginState>;
@@ -60,29 +289,174 @@ pub fn lsps0_list_servers_method() -> RpcMethodBuilder {
 pub fn lsps0_list_protocols_method() -> RpcMethodBuilder {
     RpcMethodBuilder::new("lsps0-list-protocols", crate::list_protocols)
         .description("List all lsps-servers that have publicly announced themselves")
-        .usage("peer_id")
+        .usage("peer_id [timeout_ms]")
 }
 
 pub fn lsps0_send_request() -> RpcMethodBuilder {
     RpcMethodBuilder::new("lsps0-send-request", crate::lsps0_send_request)
-        .usage("For devs: Send request to an LSP-server")
-        .usage("peer_id method [params]")
+        .description("For devs: Send request to an LSP-server")
+        .usage("peer_id method [params] [timeout_ms] [retries]")
 }
 
 pub fn lsps1_get_info() -> RpcMethodBuilder {
     RpcMethodBuilder::new("lsps1-get-info", crate::lsps1_get_info)
         .description("Get info and pricing to purchase a channel from an LSP")
-        .usage("peer_id")
+        .usage("peer_id [timeout_ms]")
+}
+
+pub fn lsps1_refresh_info() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-refresh-info", crate::lsps1_refresh_info)
+        .description("Get info and pricing from an LSP without using the cached response")
+        .usage("peer_id [timeout_ms]")
 }
 
 pub fn lsps1_create_order() -> RpcMethodBuilder {
     RpcMethodBuilder::new("lsps1-create-order", crate::lsps1_create_order)
         .description("Order a channel from an LSP")
-        .usage("peer_id lsp_balance_sat channel_expiry_blocks [client_balance_sat] [confirms_within_blocks] [token] [refund_onchain_address] [announce_channel]")
+        .usage("peer_id lsp_balance_sat channel_expiry_blocks [client_balance_sat] [funding_confirms_within_blocks] [token] [refund_onchain_address] [announce_channel] [channel_type] [timeout_ms] [external_payment]")
+}
+
+pub fn lsps1_plan_liquidity() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-plan-liquidity", crate::lsps1_plan_liquidity)
+        .description("Estimate if inbound liquidity is cheaper in one or several orders")
+        .usage("peer_id target_inbound_sat [timeout_ms]")
 }
 
 pub fn lsps1_get_order() -> RpcMethodBuilder {
     RpcMethodBuilder::new("lsps1-get-order", crate::lsps1_get_order)
         .description("Request info about an order")
-        .usage("peer_id order_id")
+        .usage("peer_id order_id [timeout_ms]")
+}
+
+pub fn lsps1_get_invoice() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-get-invoice", crate::lsps1_get_invoice)
+        .description("Get the invoice to pay for an order")
+        .usage("peer_id order_id [uri] [timeout_ms]")
+}
+
+pub fn lsps1_await_order() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-await-order", crate::lsps1_await_order)
+        .description("Wait until an order is completed or failed")
+        .usage("peer_id order_id [timeout_seconds] [abort_token]")
+}
+
+pub fn lsps1_abort_wait() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-abort-wait", crate::lsps1_abort_wait)
+        .description("Abort a running lsps1-await-order")
+        .usage("abort_token")
+}
+
+pub fn lsps1_list_orders() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-list-orders", crate::lsps1_list_orders)
+        .description("List all orders pinned by the plugin")
+}
+
+pub fn lsps1_verify_receipt() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-verify-receipt", crate::lsps1_verify_receipt)
+        .description("Verify that an LSP signed the receipt of an order")
+        .usage("peer_id receipt [order_id]")
+}
+
+pub fn lsps1_export_orders() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-export-orders", crate::lsps1_export_orders)
+        .description("Write all orders to a file to back up the wallet")
+        .usage("path")
+}
+
+pub fn lsps1_import_orders() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-import-orders", crate::lsps1_import_orders)
+        .description("Restore the orders of a file written by lsps1-export-orders")
+        .usage("path")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn parse_named_params() {
+        let params = json!({"peer_id" : "peer", "order_id" : "order"});
+        let request: Lsps1GetOrderRequest =
+            parse_params(params, Lsps1GetOrderRequest::PARAMS).unwrap();
+        assert_eq!(request.peer_id, "peer");
+        assert_eq!(request.order_id, "order");
+    }
+
+    #[test]
+    fn parse_positional_params() {
+        let params = json!(["peer", "order"]);
+        let request: Lsps1GetOrderRequest =
+            parse_params(params, Lsps1GetOrderRequest::PARAMS).unwrap();
+        assert_eq!(request.peer_id, "peer");
+        assert_eq!(request.order_id, "order");
+    }
+
+    #[test]
+    fn optional_positional_params_can_be_omitted() {
+        let params = json!(["peer", "order"]);
+        let request: Lsps1AwaitOrderRequest =
+            parse_params(params, Lsps1AwaitOrderRequest::PARAMS).unwrap();
+        assert_eq!(request.timeout_seconds, None);
+        assert_eq!(request.abort_token, None);
+
+        let params = json!(["peer", "order", null, "my-token"]);
+        let request: Lsps1AwaitOrderRequest =
+            parse_params(params, Lsps1AwaitOrderRequest::PARAMS).unwrap();
+        assert_eq!(request.timeout_seconds, None);
+        assert_eq!(request.abort_token.as_deref(), Some("my-token"));
+    }
+
+    #[test]
+    fn too_few_positional_params() {
+        let params = json!(["peer"]);
+        let err =
+            parse_params::<Lsps1GetOrderRequest>(params, Lsps1GetOrderRequest::PARAMS).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid positional params: got 1 of (peer_id order_id timeout_ms)"
+        );
+        assert!(format!("{:#}", err).contains("missing field `order_id`"));
+    }
+
+    #[test]
+    fn too_many_positional_params() {
+        let params = json!(["peer", "order", 1000, "unexpected"]);
+        let err =
+            parse_params::<Lsps1GetOrderRequest>(params, Lsps1GetOrderRequest::PARAMS).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Too many positional params: expected at most 3 (peer_id order_id timeout_ms) but got 4"
+        );
+    }
+
+    #[test]
+    fn params_match_struct_fields() {
+        // The positional order must cover every field of the request
+        let request = Lsps1CreateOrderRequest {
+            peer_id: String::new(),
+            lsp_balance_sat: SatAmount::new(0),
+            client_balance_sat: None,
+            funding_confirms_within_blocks: None,
+            channel_expiry_blocks: 0,
+            token: None,
+            refund_onchain_address: None,
+            announce_channel: None,
+            channel_type: None,
+            timeout_ms: None,
+            external_payment: None,
+        };
+        let value = serde_json::to_value(request).unwrap();
+        let mut fields: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        let mut params = Lsps1CreateOrderRequest::PARAMS.to_vec();
+        fields.sort();
+        params.sort();
+        assert_eq!(fields, params);
+    }
 }
@@ -0,0 +1,119 @@
+//! Verifies the receipts that an LSP issues for completed orders
+//!
+//! The signature is checked by our own node using `checkmessage`.
+use anyhow::{anyhow, Context, Result};
+use cln_lsps::cln_rpc::model::requests::CheckmessageRequest;
+use cln_lsps::cln_rpc::primitives::PublicKey as RpcPublicKey;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps1::receipt::{OrderReceipt, ReceiptPayload};
+
+/// Parses the payload of a receipt
+///
+/// Fails if the payload isn't canonical or if it belongs to another
+/// order than `order_id`.
+pub(crate) fn parse_receipt(
+    receipt: &OrderReceipt,
+    order_id: Option<&str>,
+) -> Result<ReceiptPayload> {
+    let payload = ReceiptPayload::from_canonical_json(&receipt.payload)?;
+    if let Some(order_id) = order_id {
+        if !payload.order_id.to_string().eq_ignore_ascii_case(order_id) {
+            return Err(anyhow!(
+                "The receipt belongs to order {} instead of {}",
+                payload.order_id,
+                order_id
+            ));
+        }
+    }
+    Ok(payload)
+}
+
+/// Asks our node to check the signature against the node key of the LSP
+pub(crate) fn checkmessage_request(
+    receipt: &OrderReceipt,
+    lsp_id: &PublicKey,
+) -> Result<CheckmessageRequest> {
+    let pubkey =
+        RpcPublicKey::from_slice(&lsp_id.inner().serialize()).context("Invalid peer_id")?;
+    Ok(CheckmessageRequest {
+        message: receipt.payload.clone(),
+        zbase: receipt.zbase_signature.clone(),
+        pubkey: Some(pubkey),
+    })
+}
+
+/// Returns true if `checkmessage` verified a signature by the LSP
+pub(crate) fn is_signed_by(verified: bool, signer: &RpcPublicKey, lsp_id: &PublicKey) -> bool {
+    verified && signer.serialize() == lsp_id.inner().serialize()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::hex;
+
+    const LSP_ID: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const OTHER_ID: &str = "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170";
+    const ORDER_ID: &str = "bb4b5d0a-8334-49d8-9463-90a6d413af7c";
+
+    fn receipt() -> OrderReceipt {
+        OrderReceipt {
+            payload: concat!(
+                r#"{"capacity":"1000000","#,
+                r#""client_node_id":"0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798","#,
+                r#""completed_at":"2023-11-14T22:13:20.000Z","#,
+                r#""fee_total_sat":"1510","#,
+                r#""funding_outpoint":"4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1","#,
+                r#""order_id":"bb4b5d0a-8334-49d8-9463-90a6d413af7c"}"#
+            )
+            .to_string(),
+            zbase_signature: "d6tqaeuonjhi98mmont9m4wag7gg4krg1f4txonug3h31e9h6p6k6".to_string(),
+        }
+    }
+
+    #[test]
+    fn receipt_of_order_is_parsed() {
+        let payload = parse_receipt(&receipt(), Some(ORDER_ID)).unwrap();
+        assert_eq!(payload.order_id.to_string(), ORDER_ID);
+        assert_eq!(payload.fee_total_sat.sat_value(), 1_510);
+
+        let payload = parse_receipt(&receipt(), None).unwrap();
+        assert_eq!(payload.capacity.sat_value(), 1_000_000);
+    }
+
+    #[test]
+    fn receipt_of_other_order_is_rejected() {
+        let err =
+            parse_receipt(&receipt(), Some("5cdc8a2d-4c2d-4d43-9b4c-c5f0a1d1e1b8")).unwrap_err();
+        assert!(err.to_string().contains("belongs to order"));
+    }
+
+    #[test]
+    fn tampered_receipt_is_rejected() {
+        let mut tampered = receipt();
+        tampered.payload = tampered.payload.replace("\"1510\"", "\"1500\" ");
+        assert!(parse_receipt(&tampered, None).is_err());
+    }
+
+    #[test]
+    fn signature_is_checked_against_the_lsp() {
+        let lsp_id = PublicKey::from_hex(LSP_ID).unwrap();
+        let request = checkmessage_request(&receipt(), &lsp_id).unwrap();
+        assert_eq!(request.message, receipt().payload);
+        assert_eq!(request.zbase, receipt().zbase_signature);
+        assert_eq!(
+            hex::encode(request.pubkey.unwrap().serialize()),
+            LSP_ID.to_string()
+        );
+
+        let lsp_key =
+            RpcPublicKey::from_slice(&hex::decode("public-key", LSP_ID).unwrap()).unwrap();
+        let other_key =
+            RpcPublicKey::from_slice(&hex::decode("public-key", OTHER_ID).unwrap()).unwrap();
+        assert!(is_signed_by(true, &lsp_key, &lsp_id));
+        assert!(!is_signed_by(false, &lsp_key, &lsp_id));
+        assert!(!is_signed_by(true, &other_key, &lsp_id));
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::oneshot;
+
+/// Explains why a wait was stopped before it completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StopReason {
+    /// The wait was aborted using `lsps1-abort-wait`
+    Aborted,
+    /// The plugin is shutting down
+    Shutdown,
+}
+
+impl StopReason {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Aborted => "aborted",
+            Self::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Keeps track of all active waits so they can be stopped
+///
+/// Every wait is identified by an `abort_token`. A wait is
+/// removed from the registry once its [`Wait`] is dropped.
+#[derive(Default)]
+pub(crate) struct WaitRegistry {
+    inner: Mutex<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    is_shutdown: bool,
+    waits: HashMap<String, oneshot::Sender<StopReason>>,
+}
+
+/// A wait that can be aborted or stopped by a shutdown
+pub(crate) struct Wait {
+    registry: Arc<WaitRegistry>,
+    token: String,
+    receiver: oneshot::Receiver<StopReason>,
+}
+
+impl WaitRegistry {
+    /// Registers a new wait
+    ///
+    /// Fails if the token is already in use or the plugin is shutting down
+    pub(crate) fn register(self: &Arc<Self>, token: String) -> Result<Wait> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.is_shutdown {
+            return Err(anyhow!("The plugin is shutting down"));
+        }
+        if inner.waits.contains_key(&token) {
+            return Err(anyhow!("abort_token '{}' is already in use", token));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        inner.waits.insert(token.clone(), sender);
+        Ok(Wait {
+            registry: self.clone(),
+            token,
+            receiver,
+        })
+    }
+
+    /// Aborts the wait identified by `token`
+    ///
+    /// Returns false if there is no such wait
+    pub(crate) fn abort(&self, token: &str) -> bool {
+        let sender = self.inner.lock().unwrap().waits.remove(token);
+        match sender {
+            Some(sender) => sender.send(StopReason::Aborted).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stops all waits and refuses new ones
+    pub(crate) fn shutdown(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.is_shutdown = true;
+        for (_, sender) in inner.waits.drain() {
+            // Fails if the wait has already finished. That's fine
+            let _ = sender.send(StopReason::Shutdown);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().waits.len()
+    }
+}
+
+impl Wait {
+    /// Runs `future` unless the wait is stopped first
+    pub(crate) async fn run<F: Future>(&mut self, future: F) -> Result<F::Output, StopReason> {
+        tokio::select! {
+            output = future => Ok(output),
+            reason = &mut self.receiver => {
+                // The sender is only dropped after a stop-reason has been sent
+                Err(reason.unwrap_or(StopReason::Shutdown))
+            }
+        }
+    }
+}
+
+impl Drop for Wait {
+    fn drop(&mut self) {
+        self.registry
+            .inner
+            .lock()
+            .unwrap()
+            .waits
+            .remove(&self.token);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// Waits forever unless it is stopped
+    async fn wait_forever(registry: Arc<WaitRegistry>, token: &str) -> Result<(), StopReason> {
+        let mut wait = registry.register(token.to_string()).unwrap();
+        wait.run(std::future::pending::<()>()).await
+    }
+
+    #[tokio::test]
+    async fn abort_mid_wait() {
+        let registry = Arc::new(WaitRegistry::default());
+
+        let waiter = tokio::spawn(wait_forever(registry.clone(), "token-a"));
+        while registry.len() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        assert!(!registry.abort("unknown-token"));
+        assert!(registry.abort("token-a"));
+
+        let result = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Err(StopReason::Aborted));
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn shutdown_mid_wait() {
+        let registry = Arc::new(WaitRegistry::default());
+
+        let waiter_a = tokio::spawn(wait_forever(registry.clone(), "token-a"));
+        let waiter_b = tokio::spawn(wait_forever(registry.clone(), "token-b"));
+        while registry.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        registry.shutdown();
+
+        for waiter in [waiter_a, waiter_b] {
+            let result = tokio::time::timeout(Duration::from_secs(1), waiter)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(result, Err(StopReason::Shutdown));
+        }
+        assert_eq!(registry.len(), 0);
+
+        // No new waits are accepted after a shutdown
+        assert!(registry.register("token-c".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn completed_wait_is_removed() {
+        let registry = Arc::new(WaitRegistry::default());
+
+        let mut wait = registry.register("token-a".to_string()).unwrap();
+        assert!(registry.register("token-a".to_string()).is_err());
+        assert_eq!(wait.run(async { 42 }).await, Ok(42));
+
+        drop(wait);
+        assert_eq!(registry.len(), 0);
+        assert!(!registry.abort("token-a"));
+    }
+}
@@ -0,0 +1 @@
+DELETE FROM lsps1_order_state_enum WHERE id = 4;
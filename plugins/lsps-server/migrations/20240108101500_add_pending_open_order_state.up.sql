@@ -0,0 +1,7 @@
+-- An order is PENDING_OPEN if the client has paid but was
+-- offline when we attempted to open the channel.
+-- The channel is opened once the client reconnects.
+INSERT INTO lsps1_order_state_enum
+  (id, order_state)
+VALUES
+  (4, "PENDING_OPEN");
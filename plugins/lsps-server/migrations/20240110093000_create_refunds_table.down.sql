@@ -0,0 +1 @@
+DROP TABLE lsps1_refunds;
@@ -0,0 +1,10 @@
+-- Refunds for orders that failed after the client paid
+-- There is at most one refund per order
+CREATE TABLE lsps1_refunds (
+  order_id INTEGER PRIMARY KEY NOT NULL,
+  txid TEXT NOT NULL,
+  amount_sat INTEGER NOT NULL,
+  address TEXT NOT NULL,
+  broadcast_at INTEGER NOT NULL,			-- timestamp: seconds since UNIX epoch in UTC
+  FOREIGN KEY (order_id) references lsps1_order(id)
+);
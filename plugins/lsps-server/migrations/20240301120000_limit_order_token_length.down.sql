@@ -0,0 +1,2 @@
+DROP TRIGGER lsps1_order_token_length_insert;
+DROP TRIGGER lsps1_order_token_length_update;
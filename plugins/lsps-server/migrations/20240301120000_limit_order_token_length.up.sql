@@ -0,0 +1,16 @@
+-- The token is provided by the client and echoed back in every response.
+-- The server validates it before storing it. These triggers guarantee that
+-- no oversized token ends up in the database.
+CREATE TRIGGER lsps1_order_token_length_insert
+BEFORE INSERT ON lsps1_order
+WHEN length(CAST(NEW.token AS BLOB)) > 512
+BEGIN
+  SELECT RAISE(ABORT, 'lsps1_order.token exceeds 512 bytes');
+END;
+
+CREATE TRIGGER lsps1_order_token_length_update
+BEFORE UPDATE OF token ON lsps1_order
+WHEN length(CAST(NEW.token AS BLOB)) > 512
+BEGIN
+  SELECT RAISE(ABORT, 'lsps1_order.token exceeds 512 bytes');
+END;
@@ -0,0 +1,5 @@
+-- Before CHANNEL_OPENING an order was COMPLETED once the funding
+-- transaction was broadcast
+UPDATE lsps1_order_state SET order_state_enum_id = 2 WHERE order_state_enum_id = 5;
+ALTER TABLE lsps1_order_state DROP COLUMN failure_reason;
+DELETE FROM lsps1_order_state_enum WHERE id = 5;
@@ -0,0 +1,9 @@
+-- An order is CHANNEL_OPENING if the funding transaction has been
+-- broadcast but the channel hasn't reached CHANNELD_NORMAL yet.
+INSERT INTO lsps1_order_state_enum
+  (id, order_state)
+VALUES
+  (5, "CHANNEL_OPENING");
+
+-- Explains why an order moved to FAILED
+ALTER TABLE lsps1_order_state ADD COLUMN failure_reason TEXT;
@@ -0,0 +1 @@
+DROP TABLE lsp_server_instance;
@@ -0,0 +1,8 @@
+-- Only a single instance of the server can use the database.
+-- The running instance refreshes heartbeat_at periodically.
+CREATE TABLE lsp_server_instance (
+  id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+  instance_id TEXT NOT NULL,
+  started_at INTEGER NOT NULL,		-- timestamp: seconds since UNIX epoch in UTC
+  heartbeat_at INTEGER NOT NULL		-- timestamp: seconds since UNIX epoch in UTC
+);
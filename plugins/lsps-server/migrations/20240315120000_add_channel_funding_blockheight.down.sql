@@ -0,0 +1 @@
+ALTER TABLE lsps1_channel DROP COLUMN funding_blockheight;
@@ -0,0 +1,4 @@
+-- The block in which the funding transaction confirmed.
+-- The lease of the channel starts at this height.
+-- It is NULL if the channel isn't confirmed or the height is unknown
+ALTER TABLE lsps1_channel ADD COLUMN funding_blockheight INTEGER;
@@ -0,0 +1,2 @@
+DROP INDEX lsps1_order_log_order_id_index;
+DROP TABLE lsps1_order_log;
@@ -0,0 +1,12 @@
+-- Audit log of the events that happened to an order
+CREATE TABLE lsps1_order_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  order_id INTEGER NOT NULL,
+  created_at INTEGER NOT NULL,		-- timestamp: seconds since UNIX epoch in UTC
+  severity TEXT NOT NULL,
+  event_code TEXT NOT NULL,
+  details TEXT NOT NULL,			-- json
+  FOREIGN KEY (order_id) references lsps1_order(id)
+);
+
+CREATE INDEX lsps1_order_log_order_id_index ON lsps1_order_log(order_id);
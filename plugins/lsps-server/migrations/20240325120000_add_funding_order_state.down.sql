@@ -0,0 +1,3 @@
+-- Before FUNDING an order was CREATED while its channel was opened
+UPDATE lsps1_order_state SET order_state_enum_id = 1 WHERE order_state_enum_id = 6;
+DELETE FROM lsps1_order_state_enum WHERE id = 6;
@@ -0,0 +1,7 @@
+-- An order is FUNDING while the worker opens its channel.
+-- Orders that are FUNDING when the plugin starts are reconciled
+-- against the channels of lightningd.
+INSERT INTO lsps1_order_state_enum
+  (id, order_state)
+VALUES
+  (6, "FUNDING");
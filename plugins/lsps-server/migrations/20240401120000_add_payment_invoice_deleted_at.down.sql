@@ -0,0 +1 @@
+ALTER TABLE lsps1_payment_state DROP COLUMN invoice_deleted_at;
@@ -0,0 +1,4 @@
+-- When the invoice of an unpaid order was deleted from lightningd.
+-- The row that records the deletion repeats the previous payment_state.
+-- It is NULL if the invoice still exists
+ALTER TABLE lsps1_payment_state ADD COLUMN invoice_deleted_at INTEGER;
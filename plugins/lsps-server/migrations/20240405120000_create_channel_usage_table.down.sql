@@ -0,0 +1,2 @@
+DROP INDEX lsps1_channel_usage_order_id_index;
+DROP TABLE lsps1_channel_usage;
@@ -0,0 +1,17 @@
+-- Periodic samples of the usage of leased channels
+-- The forwarded amounts and counts are those since the previous sample
+CREATE TABLE lsps1_channel_usage (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  order_id INTEGER NOT NULL,
+  sampled_at INTEGER NOT NULL,		-- timestamp: seconds since UNIX epoch in UTC
+  lsp_balance_msat INTEGER NOT NULL,
+  client_balance_msat INTEGER NOT NULL,
+  forwarded_in_count INTEGER NOT NULL,	-- forwarded from the client
+  forwarded_in_msat INTEGER NOT NULL,
+  forwarded_out_count INTEGER NOT NULL,	-- forwarded to the client
+  forwarded_out_msat INTEGER NOT NULL,
+  htlc_count INTEGER NOT NULL,		-- pending when the sample was taken
+  FOREIGN KEY (order_id) references lsps1_order(id)
+);
+
+CREATE INDEX lsps1_channel_usage_order_id_index ON lsps1_channel_usage(order_id);
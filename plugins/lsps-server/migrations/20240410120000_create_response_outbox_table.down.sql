@@ -0,0 +1,2 @@
+DROP INDEX lsps0_response_outbox_peer_id_index;
+DROP TABLE lsps0_response_outbox;
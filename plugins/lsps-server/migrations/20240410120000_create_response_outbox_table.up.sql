@@ -0,0 +1,11 @@
+-- Responses that couldn't be sent to a peer
+-- They are retried until they are delivered or expire
+CREATE TABLE lsps0_response_outbox (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  peer_id TEXT NOT NULL,
+  payload TEXT NOT NULL,		-- the serialized JSON-RPC response
+  created_at INTEGER NOT NULL,		-- timestamp: seconds since UNIX epoch in UTC
+  attempts INTEGER NOT NULL		-- includes the attempt that created the entry
+);
+
+CREATE INDEX lsps0_response_outbox_peer_id_index ON lsps0_response_outbox(peer_id);
@@ -0,0 +1,3 @@
+DROP VIEW v_lsps1_channels;
+DROP VIEW v_lsps1_payments_latest;
+DROP VIEW v_lsps1_orders_latest;
@@ -0,0 +1,3 @@
+ALTER TABLE lsps1_order DROP COLUMN client_remote_address;
+ALTER TABLE lsps1_order DROP COLUMN client_addresses;
+ALTER TABLE lsps1_order DROP COLUMN client_alias;
@@ -0,0 +1,10 @@
+-- What the node knew about the client when the order was created.
+-- This is a snapshot for the operator and isn't updated afterwards.
+-- A column is NULL if the information wasn't available
+
+-- The alias the client announced in gossip
+ALTER TABLE lsps1_order ADD COLUMN client_alias TEXT;
+-- A JSON-array of the addresses the client announced in gossip
+ALTER TABLE lsps1_order ADD COLUMN client_addresses TEXT;
+-- The address of the connection the order was created on
+ALTER TABLE lsps1_order ADD COLUMN client_remote_address TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE lsps1_order DROP COLUMN receipt_zbase_signature;
+ALTER TABLE lsps1_order DROP COLUMN receipt_payload;
@@ -0,0 +1,8 @@
+-- The receipt that is issued once the order is completed.
+-- The LSP signs the payload with its node key using `signmessage`.
+-- Both columns are NULL until the receipt is issued
+
+-- The canonical JSON that was signed
+ALTER TABLE lsps1_order ADD COLUMN receipt_payload TEXT;
+-- The zbase32-encoded signature of the payload
+ALTER TABLE lsps1_order ADD COLUMN receipt_zbase_signature TEXT;
@@ -0,0 +1 @@
+DROP TABLE lsps1_funding_reservation;
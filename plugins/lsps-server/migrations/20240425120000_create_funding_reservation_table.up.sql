@@ -0,0 +1,10 @@
+-- The wallet inputs that are reserved to fund the channel of an order
+-- The inputs are reserved once the order is paid and released once the
+-- channel is funded or the order fails
+CREATE TABLE lsps1_funding_reservation (
+  order_id INTEGER PRIMARY KEY,
+  psbt TEXT NOT NULL,			-- the psbt returned by `fundpsbt`
+  utxos TEXT NOT NULL,			-- comma-separated outpoints. E.g: txid:outnum
+  reserved_at INTEGER NOT NULL,		-- timestamp: seconds since UNIX epoch in UTC
+  FOREIGN KEY (order_id) references lsps1_order(id)
+);
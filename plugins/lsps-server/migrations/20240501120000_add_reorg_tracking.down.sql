@@ -0,0 +1,4 @@
+DROP TABLE lsps1_block;
+-- Before AWAITING_CONFIRMATION an order stayed COMPLETED after a reorg
+UPDATE lsps1_order_state SET order_state_enum_id = 2 WHERE order_state_enum_id = 7;
+DELETE FROM lsps1_order_state_enum WHERE id = 7;
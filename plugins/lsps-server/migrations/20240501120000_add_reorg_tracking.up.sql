@@ -0,0 +1,14 @@
+-- An order is AWAITING_CONFIRMATION if a reorg unconfirmed the funding
+-- transaction of its channel after it was COMPLETED.
+-- It is COMPLETED again once the funding transaction confirms.
+INSERT INTO lsps1_order_state_enum
+  (id, order_state)
+VALUES
+  (7, "AWAITING_CONFIRMATION");
+
+-- The most recent blocks seen by the plugin.
+-- A block that replaces a known block at the same height reveals a reorg
+CREATE TABLE lsps1_block (
+  height INTEGER PRIMARY KEY NOT NULL,
+  hash TEXT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE lsps1_order DROP COLUMN channel_type;
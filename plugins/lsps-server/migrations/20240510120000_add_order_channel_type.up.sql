@@ -0,0 +1,4 @@
+-- The channel type the client requested using the `channel_type` extension.
+-- A JSON-array of features. E.g: ["anchors","zeroconf"]
+-- NULL if the client didn't request a channel type
+ALTER TABLE lsps1_order ADD COLUMN channel_type TEXT;
@@ -0,0 +1,6 @@
+ALTER TABLE lsps1_payment_details DROP COLUMN latest_generation;
+ALTER TABLE lsps1_payment_details DROP COLUMN latest_payment_state;
+
+ALTER TABLE lsps1_order DROP COLUMN latest_generation;
+ALTER TABLE lsps1_order DROP COLUMN latest_failure_reason;
+ALTER TABLE lsps1_order DROP COLUMN latest_order_state;
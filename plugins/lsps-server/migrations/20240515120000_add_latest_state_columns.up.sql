@@ -0,0 +1,29 @@
+-- The latest state of every order and payment
+-- These columns are updated in the same transaction that appends a row to
+-- lsps1_order_state or lsps1_payment_state. Reads use these columns and
+-- don't have to find the latest generation. The state tables keep the
+-- history of every order for auditing.
+ALTER TABLE lsps1_order ADD COLUMN latest_order_state INTEGER;
+ALTER TABLE lsps1_order ADD COLUMN latest_failure_reason TEXT;
+ALTER TABLE lsps1_order ADD COLUMN latest_generation INTEGER;
+
+ALTER TABLE lsps1_payment_details ADD COLUMN latest_payment_state INTEGER;
+ALTER TABLE lsps1_payment_details ADD COLUMN latest_generation INTEGER;
+
+UPDATE lsps1_order
+SET (latest_order_state, latest_failure_reason, latest_generation) = (
+  SELECT os.order_state_enum_id, os.failure_reason, os.generation
+  FROM lsps1_order_state AS os
+  WHERE os.order_id = lsps1_order.id
+  ORDER BY os.generation DESC
+  LIMIT 1
+);
+
+UPDATE lsps1_payment_details
+SET (latest_payment_state, latest_generation) = (
+  SELECT ps.payment_state, ps.generation
+  FROM lsps1_payment_state AS ps
+  WHERE ps.payment_details_id = lsps1_payment_details.id
+  ORDER BY ps.generation DESC
+  LIMIT 1
+);
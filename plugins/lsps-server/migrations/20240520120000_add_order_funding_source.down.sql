@@ -0,0 +1 @@
+ALTER TABLE lsps1_order DROP COLUMN funding_source;
@@ -0,0 +1,4 @@
+-- The label of the funding source whose wallet outputs fund the channel.
+-- See `lsps1-funding-sources-file`
+-- NULL if any output of the wallet can fund the channel
+ALTER TABLE lsps1_order ADD COLUMN funding_source TEXT;
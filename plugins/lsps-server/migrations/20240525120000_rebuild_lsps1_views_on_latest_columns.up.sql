@@ -0,0 +1,53 @@
+-- Rebuild the views on the latest_* columns of lsps1_order and
+-- lsps1_payment_details. The queries of the server read the same
+-- columns, so the views and the server agree on the latest state.
+-- The columns of the views don't change.
+DROP VIEW v_lsps1_payments_latest;
+DROP VIEW v_lsps1_orders_latest;
+
+-- The latest state of every order
+CREATE VIEW v_lsps1_orders_latest AS
+SELECT
+  o.uuid,
+  o.client_node_id,
+  o.lsp_balance_sat,
+  o.client_balance_sat,
+  o.funding_confirms_within_blocks,
+  o.required_channel_confirmations,
+  o.channel_expiry_blocks,
+  o.token,
+  o.refund_onchain_address,
+  o.announce_channel,
+  o.created_at,				-- timestamp: seconds since UNIX epoch in UTC
+  o.expires_at,				-- timestamp: seconds since UNIX epoch in UTC
+  o.latest_order_state AS order_state,
+  ose.order_state AS order_state_name,	-- e.g: CREATED or COMPLETED
+  o.latest_failure_reason AS failure_reason,
+  o.latest_generation AS generation,
+  os.created_at AS state_changed_at	-- timestamp: seconds since UNIX epoch in UTC
+FROM lsps1_order AS o
+JOIN lsps1_order_state AS os
+  ON os.order_id = o.id AND os.generation = o.latest_generation
+JOIN lsps1_order_state_enum AS ose ON ose.id = o.latest_order_state;
+
+-- The payment details and the latest payment state of every order
+CREATE VIEW v_lsps1_payments_latest AS
+SELECT
+  o.uuid AS order_uuid,
+  pd.fee_total_sat,
+  pd.order_total_sat,
+  pd.bolt11_invoice,
+  pd.bolt11_invoice_label,
+  pd.onchain_address,
+  pd.onchain_block_confirmations_required,
+  pd.minimum_fee_for_0conf,
+  pd.latest_payment_state AS payment_state,
+  pse.payment_state AS payment_state_name,	-- e.g: EXPECT_PAYMENT or PAID
+  pd.latest_generation AS generation,
+  ps.created_at AS state_changed_at,		-- timestamp: seconds since UNIX epoch in UTC
+  ps.invoice_deleted_at				-- timestamp: seconds since UNIX epoch in UTC
+FROM lsps1_payment_details AS pd
+JOIN lsps1_order AS o ON o.id = pd.order_id
+JOIN lsps1_payment_state AS ps
+  ON ps.payment_details_id = pd.id AND ps.generation = pd.latest_generation
+JOIN lsps1_payment_state_enum AS pse ON pse.id = pd.latest_payment_state;
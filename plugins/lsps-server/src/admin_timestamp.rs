@@ -0,0 +1,149 @@
+//! Timestamps in the output of the admin RPC-commands
+//!
+//! Scripts shouldn't have to care which struct a timestamp came from.
+//! Every timestamp in an admin output is rendered as
+//!
+//! ```json
+//! { "iso" : "2023-11-14T22:13:20.000Z", "unix" : 1700000000 }
+//! ```
+//!
+//! Callers that pass `epoch_only` get the unix timestamp only. The
+//! responses to LSPS-clients use the format of the spec and don't use
+//! this module.
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use serde_json::Value;
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+/// Serializes an [`IsoDatetime`] as `iso` and `unix`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct AdminTimestamp(pub(crate) IsoDatetime);
+
+impl From<IsoDatetime> for AdminTimestamp {
+    fn from(datetime: IsoDatetime) -> Self {
+        Self(datetime)
+    }
+}
+
+impl Serialize for AdminTimestamp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut timestamp = serializer.serialize_struct("AdminTimestamp", 2)?;
+        timestamp.serialize_field("iso", &self.0)?;
+        timestamp.serialize_field("unix", &self.0.unix_timestamp())?;
+        timestamp.end()
+    }
+}
+
+/// Use as `#[serde(serialize_with = "admin_timestamp::serialize")]`
+pub(crate) fn serialize<S>(datetime: &IsoDatetime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    AdminTimestamp(*datetime).serialize(serializer)
+}
+
+/// Use as `#[serde(serialize_with = "admin_timestamp::serialize_option")]`
+pub(crate) fn serialize_option<S>(
+    datetime: &Option<IsoDatetime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    datetime.map(AdminTimestamp).serialize(serializer)
+}
+
+/// Replaces every timestamp in `value` by its unix timestamp if `epoch_only` is set
+///
+/// A timestamp is an object that has exactly the fields `iso` and `unix`.
+pub(crate) fn render(value: Value, epoch_only: Option<bool>) -> Value {
+    if epoch_only.unwrap_or(false) {
+        to_epoch_only(value)
+    } else {
+        value
+    }
+}
+
+fn to_epoch_only(value: Value) -> Value {
+    match value {
+        Value::Object(mut object) => {
+            let is_timestamp = object.len() == 2
+                && object.get("iso").is_some_and(Value::is_string)
+                && object.get("unix").is_some_and(Value::is_i64);
+            if is_timestamp {
+                return object.remove("unix").unwrap_or_default();
+            }
+            for field in object.values_mut() {
+                *field = to_epoch_only(field.take());
+            }
+            Value::Object(object)
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(to_epoch_only).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    fn at(timestamp: i64) -> IsoDatetime {
+        IsoDatetime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    #[derive(Serialize)]
+    struct Output {
+        #[serde(serialize_with = "serialize")]
+        created_at: IsoDatetime,
+        #[serde(serialize_with = "serialize_option")]
+        expires_at: Option<IsoDatetime>,
+        #[serde(serialize_with = "serialize_option")]
+        closed_at: Option<IsoDatetime>,
+    }
+
+    #[test]
+    fn serialize_iso_and_unix() {
+        let output = Output {
+            created_at: at(1_700_000_000),
+            expires_at: Some(at(1_700_086_400)),
+            closed_at: None,
+        };
+        assert_eq!(
+            serde_json::to_value(output).unwrap(),
+            json!({
+                "created_at" : { "iso" : "2023-11-14T22:13:20.000Z", "unix" : 1_700_000_000 },
+                "expires_at" : { "iso" : "2023-11-15T22:13:20.000Z", "unix" : 1_700_086_400 },
+                "closed_at" : null,
+            })
+        );
+    }
+
+    #[test]
+    fn render_epoch_only() {
+        let value = json!({
+            "created_at" : AdminTimestamp(at(1_700_000_000)),
+            "entries" : [
+                { "sampled_at" : AdminTimestamp(at(1_700_000_600)), "count" : 1 },
+            ],
+            // Objects with other fields are left untouched
+            "details" : { "iso" : "2023-11-14T22:13:20.000Z", "unix" : 1, "extra" : true },
+        });
+
+        assert_eq!(render(value.clone(), None), value);
+        assert_eq!(render(value.clone(), Some(false)), value);
+        assert_eq!(
+            render(value, Some(true)),
+            json!({
+                "created_at" : 1_700_000_000,
+                "entries" : [{ "sampled_at" : 1_700_000_600, "count" : 1 }],
+                "details" : { "iso" : "2023-11-14T22:13:20.000Z", "unix" : 1, "extra" : true },
+            })
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Locates the funding output in the transaction prepared by `txprepare`
+//!
+//! lightningd may reorder the outputs of the transaction and can add
+//! change at any index. The index of the funding output becomes part of
+//! the funding outpoint that is stored with the order. It is read from
+//! the prepared PSBT rather than from the outputs we requested.
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine as _;
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount};
+
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+
+/// Returns the index of the output that pays `amount` to `funding_address`
+///
+/// `psbt` is base64-encoded. Fails unless exactly one output matches.
+pub(crate) fn find_funding_outnum(
+    psbt: &str,
+    funding_address: &str,
+    amount: SatAmount,
+) -> Result<u32> {
+    let psbt = base64::engine::general_purpose::STANDARD
+        .decode(psbt)
+        .context("The PSBT of the funding transaction isn't valid base64")?;
+    let psbt = Psbt::deserialize(&psbt).context("Failed to parse the funding transaction")?;
+    let script_pubkey = Address::<NetworkUnchecked>::from_str(funding_address)
+        .with_context(|| format!("Invalid funding address '{}'", funding_address))?
+        .assume_checked()
+        .script_pubkey();
+    let amount = Amount::from_sat(amount.sat_value());
+
+    let matches: Vec<usize> = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.script_pubkey == script_pubkey && output.value == amount)
+        .map(|(outnum, _)| outnum)
+        .collect();
+
+    match matches.as_slice() {
+        [outnum] => u32::try_from(*outnum).context("The funding output has an invalid index"),
+        [] => bail!(
+            "No output of the funding transaction pays {} to {}",
+            amount,
+            funding_address
+        ),
+        _ => bail!(
+            "{} outputs of the funding transaction pay {} to {}",
+            matches.len(),
+            amount,
+            funding_address
+        ),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Transaction, TxIn, TxOut};
+
+    /// The P2WSH address of `OP_TRUE` on regtest
+    pub(crate) const FUNDING_ADDRESS: &str =
+        "bcrt1qft5p2uhsdcdc3l2ua4ap5qqfg4pjaqlp250x7us7a8qqhrxrxfsqseac85";
+
+    /// The P2WSH address of `OP_2` on regtest
+    pub(crate) const CHANGE_ADDRESS: &str =
+        "bcrt1q3sjhfzfqv0uetl0h267wql6xcxj3j0j5e4fgxlkerceqprx0gxkq8swv49";
+
+    /// A base64-encoded PSBT with an output per `(address, amount_sat)`
+    pub(crate) fn prepared_psbt(outputs: &[(&str, u64)]) -> String {
+        let output = outputs
+            .iter()
+            .map(|(address, amount_sat)| TxOut {
+                value: Amount::from_sat(*amount_sat),
+                script_pubkey: Address::<NetworkUnchecked>::from_str(address)
+                    .unwrap()
+                    .assume_checked()
+                    .script_pubkey(),
+            })
+            .collect();
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output,
+        };
+        let psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        base64::engine::general_purpose::STANDARD.encode(psbt.serialize())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::{prepared_psbt, CHANGE_ADDRESS, FUNDING_ADDRESS};
+    use super::*;
+
+    fn sat(value: u64) -> SatAmount {
+        SatAmount::new(value)
+    }
+
+    #[test]
+    fn funding_output_after_change() {
+        let psbt = prepared_psbt(&[(CHANGE_ADDRESS, 250_000), (FUNDING_ADDRESS, 100_000)]);
+        assert_eq!(
+            find_funding_outnum(&psbt, FUNDING_ADDRESS, sat(100_000)).unwrap(),
+            1
+        );
+
+        let psbt = prepared_psbt(&[(FUNDING_ADDRESS, 100_000), (CHANGE_ADDRESS, 250_000)]);
+        assert_eq!(
+            find_funding_outnum(&psbt, FUNDING_ADDRESS, sat(100_000)).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn change_of_the_same_amount_is_not_the_funding_output() {
+        let psbt = prepared_psbt(&[(CHANGE_ADDRESS, 100_000), (FUNDING_ADDRESS, 100_000)]);
+        assert_eq!(
+            find_funding_outnum(&psbt, FUNDING_ADDRESS, sat(100_000)).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn ambiguous_or_missing_funding_output_fails() {
+        let psbt = prepared_psbt(&[(FUNDING_ADDRESS, 100_000), (FUNDING_ADDRESS, 100_000)]);
+        let err = find_funding_outnum(&psbt, FUNDING_ADDRESS, sat(100_000)).unwrap_err();
+        assert!(err.to_string().starts_with("2 outputs"), "{}", err);
+
+        // The address matches but the amount doesn't
+        let psbt = prepared_psbt(&[(CHANGE_ADDRESS, 250_000), (FUNDING_ADDRESS, 99_999)]);
+        let err = find_funding_outnum(&psbt, FUNDING_ADDRESS, sat(100_000)).unwrap_err();
+        assert!(err.to_string().starts_with("No output"), "{}", err);
+
+        let psbt = prepared_psbt(&[(CHANGE_ADDRESS, 100_000)]);
+        assert!(find_funding_outnum(&psbt, FUNDING_ADDRESS, sat(100_000)).is_err());
+    }
+
+    #[test]
+    fn invalid_psbt_fails() {
+        assert!(find_funding_outnum("not base64!", FUNDING_ADDRESS, sat(1)).is_err());
+        // A transaction without outputs
+        assert!(
+            find_funding_outnum("cHNidP8BAAoCAAAAAAAAAAAAAA==", FUNDING_ADDRESS, sat(1)).is_err()
+        );
+        let psbt = prepared_psbt(&[(FUNDING_ADDRESS, 100_000)]);
+        assert!(find_funding_outnum(&psbt, "bcrt1qfundingaddress", sat(100_000)).is_err());
+    }
+}
@@ -1,28 +1,384 @@
+pub(crate) mod funding_output;
+
 use anyhow::{anyhow, Context, Result};
-use cln_rpc::model::requests::{TxdiscardRequest, TxprepareRequest, TxsendRequest};
+use cln_rpc::model::requests::{
+    ListfundsRequest, TxdiscardRequest, TxprepareRequest, TxsendRequest,
+};
+use cln_rpc::model::responses::FeeratesPerkwEstimates;
 use cln_rpc::primitives as rpc_primitives;
-use cln_rpc::ClnRpc;
 use lsp_primitives::lsps0::common_schemas::{
-    FeeRate, IsoDatetime, PublicKey, SatAmount, TransactionId,
+    FeeRate, MsatAmount, Network, NetworkCheckable, OnchainAddress, Outpoint, PublicKey, SatAmount,
+    TransactionId,
 };
+use lsp_primitives::lsps1::schema::{ChannelTypeFeature, Lsps1Options};
 use std::str::FromStr;
 use std::time::Duration;
 
+use crate::channel_open::funding_output::find_funding_outnum;
+use crate::cln::public_key::to_rpc_public_key;
+use crate::cln::rpc_api::ClnRpcApi;
 use crate::cln::rpc_model::{
     FundChannelCancelRequest, FundChannelCompleteRequest, FundChannelCompleteResponse,
-    FundChannelStartRequest, FundChannelStartResponse,
+    FundChannelStartRequest, FundChannelStartResponse, FundpsbtRequest, FundpsbtResponse,
+    UnreserveinputsRequest, UtxopsbtRequest,
 };
-use crate::db::schema::Lsps1Channel;
+use crate::clock::Clock;
+use crate::db::schema::{Lsps1Channel, Lsps1FundingReservation, Lsps1Order};
+use crate::lsps1::fee_calc::calculate_onchain_feerate;
+use crate::lsps1::funding_source::UtxoFilter;
 
+#[derive(Debug, Clone)]
 pub struct ChannelDetails {
     pub(crate) peer_id: PublicKey,
     pub(crate) amount: SatAmount,
     pub(crate) feerate: Option<FeeRate>,
     pub(crate) announce: Option<bool>,
     pub(crate) close_to: Option<String>,
-    pub(crate) push_msat: Option<SatAmount>,
+    pub(crate) push_msat: Option<MsatAmount>,
     pub(crate) mindepth: Option<u16>,
     pub(crate) reserve: Option<SatAmount>,
+    /// The features of the channel the client requested
+    ///
+    /// lightningd picks the channel type if `None`
+    pub(crate) channel_type: Option<Vec<ChannelTypeFeature>>,
+    /// The wallet inputs that fund the channel
+    ///
+    /// lightningd selects the inputs if there is no reservation
+    pub(crate) reserved_inputs: Option<Lsps1FundingReservation>,
+    /// Restricts the wallet inputs to the outputs of a funding source
+    ///
+    /// lightningd never selects the inputs of such a channel. The open
+    /// fails if the reserved inputs are gone
+    pub(crate) utxo_filter: Option<UtxoFilter>,
+}
+
+/// `option_static_remotekey` is part of every channel type that is requested
+pub(crate) const STATIC_REMOTEKEY_FEATURE_BIT: u32 = 12;
+
+/// The weight of a funding transaction without its inputs
+///
+/// This is the version, locktime, segwit marker and the P2WSH funding output
+pub(crate) const FUNDING_TX_STARTWEIGHT: u32 = 214;
+
+/// The configuration of the server that affects the channels it opens
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConfig {
+    pub(crate) min_required_channel_confirmations: u16,
+    /// Receives the funds of the LSP when the channel is closed
+    pub(crate) close_to: Option<OnchainAddress>,
+}
+
+impl ServerConfig {
+    pub(crate) fn new(options: &Lsps1Options, close_to: Option<OnchainAddress>) -> Self {
+        Self {
+            min_required_channel_confirmations: options.min_required_channel_confirmations,
+            close_to,
+        }
+    }
+}
+
+/// Parses the address that receives the funds of the LSP when a channel closes
+///
+/// Funds sent to an address of another network are lost. Such an
+/// address is refused.
+pub(crate) fn parse_close_to(address: &str, network: &Network) -> Result<OnchainAddress> {
+    let address = OnchainAddress::from_str(address)
+        .with_context(|| format!("Invalid close_to address '{}'", address))?;
+    address
+        .require_network(network)
+        .context("Invalid close_to address")?;
+    Ok(address)
+}
+
+/// The fee estimates of lightningd when the channel is opened
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FeerateSnapshot {
+    pub(crate) estimates: Vec<FeeratesPerkwEstimates>,
+}
+
+impl FeerateSnapshot {
+    /// The feerate that confirms within `blocks`
+    ///
+    /// This is the feerate that was used to compute the fee of the order.
+    /// Returns `None` if there are no estimates. lightningd picks a feerate
+    /// in that case.
+    pub(crate) fn feerate_for(&self, blocks: u16) -> Option<FeeRate> {
+        calculate_onchain_feerate(blocks, &self.estimates)
+            .map(|perkw| FeeRate::from_sats_per_kwu(u64::from(perkw)))
+    }
+}
+
+/// The order can't be translated into a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChannelDetailsError {
+    /// Both the lsp_balance and client_balance are zero
+    ZeroCapacity,
+    CapacityOverflow,
+    PushOverflow,
+}
+
+impl std::fmt::Display for ChannelDetailsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroCapacity => write!(f, "The channel has no capacity"),
+            Self::CapacityOverflow => write!(f, "Overflow when computing channel capacity"),
+            Self::PushOverflow => write!(f, "Overflow when converting client_balance to msat"),
+        }
+    }
+}
+
+impl std::error::Error for ChannelDetailsError {}
+
+impl ChannelDetails {
+    /// Translates an order into the parameters of `fundchannel_start`
+    ///
+    /// - The capacity is `lsp_balance_sat + client_balance_sat`. The
+    ///   `client_balance_sat` is pushed to the client.
+    /// - The channel is only announced if the client asked for it.
+    /// - The channel is ready after the confirmations requested by the
+    ///   client or the configured minimum, whichever is higher. An announced
+    ///   channel needs a short_channel_id and waits for at least 1 confirmation.
+    /// - The funding transaction confirms within `funding_confirms_within_blocks`
+    ///   if fee estimates are available.
+    /// - The client doesn't have to keep a reserve.
+    pub(crate) fn from_order(
+        order: &Lsps1Order,
+        config: &ServerConfig,
+        feerates: &FeerateSnapshot,
+    ) -> Result<Self, ChannelDetailsError> {
+        let amount = order
+            .client_balance_sat
+            .checked_add(&order.lsp_balance_sat)
+            .ok_or(ChannelDetailsError::CapacityOverflow)?;
+        if amount.sat_value() == 0 {
+            return Err(ChannelDetailsError::ZeroCapacity);
+        }
+
+        let push_msat = match order.client_balance_sat.sat_value() {
+            0 => None,
+            _ => Some(
+                MsatAmount::from_sat(order.client_balance_sat)
+                    .ok_or(ChannelDetailsError::PushOverflow)?,
+            ),
+        };
+
+        let mut mindepth = config
+            .min_required_channel_confirmations
+            .max(order.required_channel_confirmations);
+        if order.announce_channel {
+            mindepth = mindepth.max(1);
+        }
+
+        Ok(Self {
+            peer_id: order.client_node_id,
+            amount,
+            feerate: feerates.feerate_for(order.funding_confirms_within_blocks),
+            announce: Some(order.announce_channel),
+            close_to: config.close_to.as_ref().map(|x| x.to_string()),
+            push_msat,
+            mindepth: Some(mindepth),
+            reserve: Some(SatAmount::new(0)),
+            channel_type: None,
+            reserved_inputs: None,
+            utxo_filter: None,
+        })
+    }
+
+    /// The request to `fundchannel_start` that opens the channel to `id`
+    fn fundchannel_start_request(&self, id: rpc_primitives::PublicKey) -> FundChannelStartRequest {
+        FundChannelStartRequest {
+            id,
+            amount: rpc_primitives::Amount::from_sat(self.amount.sat_value()),
+            feerate: to_rpc_feerate(&self.feerate),
+            announce: self.announce,
+            close_to: self.close_to.clone(),
+            push_msat: self
+                .push_msat
+                .map(|x| rpc_primitives::Amount::from_msat(x.msat_value())),
+            mindepth: self.mindepth,
+            reserve: self
+                .reserve
+                .map(|x| rpc_primitives::Amount::from_sat(x.sat_value())),
+            channel_type: self.channel_type.as_deref().map(to_rpc_channel_type),
+        }
+    }
+}
+
+/// Reserves wallet inputs that can fund the channel
+///
+/// The inputs are selected by `fundpsbt` at the feerate of the channel.
+/// If the channel has a [`UtxoFilter`] only the outputs of the funding
+/// source are used. See [`select_filtered_inputs`].
+/// Other wallet activity can't spend them until they are released using
+/// [`release_inputs`] or handed over to the funding transaction.
+pub(crate) async fn reserve_inputs(
+    rpc: &mut dyn ClnRpcApi,
+    clock: &dyn Clock,
+    channel_details: &ChannelDetails,
+) -> Result<Lsps1FundingReservation> {
+    let response = match &channel_details.utxo_filter {
+        Some(filter) => select_filtered_inputs(rpc, channel_details, filter).await?,
+        None => {
+            let request = FundpsbtRequest {
+                satoshi: rpc_primitives::Amount::from_sat(channel_details.amount.sat_value()),
+                feerate: to_rpc_feerate(&channel_details.feerate)
+                    .unwrap_or(rpc_primitives::Feerate::Normal),
+                startweight: FUNDING_TX_STARTWEIGHT,
+                minconf: None,
+                reserve: None,
+            };
+            rpc.fundpsbt(&request)
+                .await
+                .context("Failed to call 'fundpsbt'")?
+        }
+    };
+
+    let utxos = response
+        .reservations
+        .iter()
+        .map(|r| {
+            Ok(Outpoint {
+                txid: TransactionId::from_str(&r.txid)?,
+                outnum: r.vout,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("Invalid reservation of funding inputs")?;
+
+    Ok(Lsps1FundingReservation {
+        psbt: response.psbt,
+        utxos,
+        reserved_at: clock.now(),
+    })
+}
+
+/// Reserves outputs that match `filter` using `utxopsbt`
+///
+/// `fundpsbt` can't be limited to a set of outputs. `utxopsbt` spends
+/// exactly the outputs it is given. The largest outputs are used first.
+/// The first attempt uses the fewest outputs that cover the channel. An
+/// output is added whenever lightningd can't afford the funding
+/// transaction, e.g.: because of its fee.
+async fn select_filtered_inputs(
+    rpc: &mut dyn ClnRpcApi,
+    channel_details: &ChannelDetails,
+    filter: &UtxoFilter,
+) -> Result<FundpsbtResponse> {
+    let funds = rpc
+        .listfunds(&ListfundsRequest { spent: None })
+        .await
+        .context("Failed to call 'listfunds'")?;
+    let mut candidates: Vec<_> = filter.spendable(&funds.outputs).collect();
+    candidates.sort_by_key(|output| std::cmp::Reverse(output.amount_msat.msat()));
+
+    let needed_msat = channel_details.amount.sat_value().saturating_mul(1000);
+    let mut covered_msat: u64 = 0;
+    let fewest = candidates
+        .iter()
+        .position(|output| {
+            covered_msat = covered_msat.saturating_add(output.amount_msat.msat());
+            covered_msat >= needed_msat
+        })
+        .with_context(|| {
+            format!(
+                "The funding source has {} sat available, {} sat needed",
+                covered_msat / 1000,
+                channel_details.amount.sat_value()
+            )
+        })?;
+
+    let mut last_err = None;
+    for count in (fewest + 1)..=candidates.len() {
+        let request = UtxopsbtRequest {
+            satoshi: rpc_primitives::Amount::from_sat(channel_details.amount.sat_value()),
+            feerate: to_rpc_feerate(&channel_details.feerate)
+                .unwrap_or(rpc_primitives::Feerate::Normal),
+            startweight: FUNDING_TX_STARTWEIGHT,
+            utxos: candidates[..count]
+                .iter()
+                .map(|output| format!("{}:{}", output.txid, output.output))
+                .collect(),
+            reserve: None,
+        };
+        match rpc.utxopsbt(&request).await {
+            Ok(response) => return Ok(response),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| anyhow!("No outputs to fund the channel"))
+        .context("Failed to call 'utxopsbt'"))
+}
+
+/// Releases the inputs of a reservation
+pub(crate) async fn release_inputs(
+    rpc: &mut dyn ClnRpcApi,
+    reservation: &Lsps1FundingReservation,
+) -> Result<()> {
+    let request = UnreserveinputsRequest {
+        psbt: reservation.psbt.clone(),
+        reserve: None,
+    };
+    rpc.unreserveinputs(&request)
+        .await
+        .context("Failed to call 'unreserveinputs'")?;
+    Ok(())
+}
+
+/// Releases the reserved inputs so `txprepare` can spend them
+///
+/// `txprepare` reserves the inputs of the funding transaction itself and
+/// `txdiscard` releases them again. Returns the inputs `txprepare` must
+/// use. Returns `None` if one of the inputs was no longer reserved. It
+/// might have been spent in the mean time and lightningd selects the
+/// inputs instead.
+async fn handover_reserved_inputs(
+    rpc: &mut dyn ClnRpcApi,
+    reservation: &Lsps1FundingReservation,
+) -> Option<Vec<rpc_primitives::Outpoint>> {
+    let request = UnreserveinputsRequest {
+        psbt: reservation.psbt.clone(),
+        reserve: None,
+    };
+    let response = match rpc.unreserveinputs(&request).await {
+        Ok(response) => response,
+        Err(err) => {
+            log::warn!("Failed to release the reserved inputs: {:?}", err);
+            return None;
+        }
+    };
+    if response.reservations.iter().any(|r| !r.was_reserved) {
+        log::warn!(
+            "The reservation expired. lightningd selects the inputs of the funding transaction"
+        );
+        return None;
+    }
+
+    reservation
+        .utxos
+        .iter()
+        .map(|utxo| serde_json::from_value(serde_json::Value::String(utxo.to_string())))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| log::warn!("Invalid reserved input: {:?}", e))
+        .ok()
+}
+
+fn to_rpc_feerate(feerate: &Option<FeeRate>) -> Option<rpc_primitives::Feerate> {
+    feerate
+        .clone()
+        .map(|x| rpc_primitives::Feerate::PerKw(x.to_sats_per_kwu().try_into().unwrap()))
+}
+
+/// The feature bits of a channel type in ascending order
+fn to_rpc_channel_type(features: &[ChannelTypeFeature]) -> Vec<u32> {
+    let mut bits: Vec<u32> = features
+        .iter()
+        .map(|feature| feature.feature_bit() as u32)
+        .collect();
+    bits.push(STATIC_REMOTEKEY_FEATURE_BIT);
+    bits.sort_unstable();
+    bits.dedup();
+    bits
 }
 
 #[derive(Debug, Default, Clone)]
@@ -30,6 +386,8 @@ struct ChannelOpenErrorData {
     peer_id: Option<PublicKey>,
     funding_address: Option<String>,
     txid: Option<String>,
+    /// The reservation that wasn't handed over to `txprepare` yet
+    reservation: Option<Lsps1FundingReservation>,
 }
 
 #[derive(Debug)]
@@ -73,19 +431,23 @@ impl ChannelOpenErrorData {
 /// and must ensure it will not continue to open the channel after
 /// providing the refund.
 ///
+/// If `channel_details` has reserved inputs they fund the channel. The
+/// reservation is released if the channel open fails.
+///
 /// Note, that the `timeout` parameter is a lower-bound. The underlying implementation calls
 /// `fundchannel_start`, `tx_prepare`, `fundchannel_complete` and `tx_send`. Each of those
 /// methods is called with this time-out prameter
 pub async fn fundchannel_fallible(
-    rpc: &mut ClnRpc,
+    rpc: &mut dyn ClnRpcApi,
+    clock: &dyn Clock,
     channel_details: &ChannelDetails,
     timeout: Duration,
 ) -> Result<Lsps1Channel> {
-    let rpc_id = rpc_primitives::PublicKey::from_str(&channel_details.peer_id.to_hex())
-        .context("Invalid peer_id")?;
+    let rpc_id = to_rpc_public_key(&channel_details.peer_id).context("Invalid peer_id")?;
 
     let result =
-        fundchannel_without_publishing_funding_transaction(rpc, channel_details, timeout).await;
+        fundchannel_without_publishing_funding_transaction(rpc, clock, channel_details, timeout)
+            .await;
 
     match result {
         Ok(channelopen_response) => {
@@ -93,7 +455,7 @@ pub async fn fundchannel_fallible(
             let txsend = TxsendRequest {
                 txid: channelopen_response.funding_txid.to_string(),
             };
-            let _ = rpc.call_typed(&txsend).await?;
+            let _ = rpc.txsend(&txsend).await?;
             Ok(channelopen_response)
         }
         Err(channel_open_error) => {
@@ -103,16 +465,24 @@ pub async fn fundchannel_fallible(
             );
             log::warn!("Error: {:?}", channel_open_error.error);
 
+            // Releases the inputs that `txprepare` reserved
             if let Some(txid) = channel_open_error.data.txid {
                 log::debug!("Discard funding transaction {:?}", txid);
                 let txdiscard_request = TxdiscardRequest { txid };
-                let _ = rpc.call_typed(&txdiscard_request).await;
+                let _ = rpc.txdiscard(&txdiscard_request).await;
             };
 
+            if let Some(reservation) = channel_open_error.data.reservation {
+                log::debug!("Releasing the reserved inputs {:?}", reservation.utxos);
+                if let Err(err) = release_inputs(rpc, &reservation).await {
+                    log::warn!("Failed to release the reserved inputs: {:?}", err);
+                }
+            }
+
             if let Some(peer_id) = channel_open_error.data.peer_id {
                 log::debug!("Cancelling channel open to peer {:?}", peer_id);
                 let fundchannel_cancel_request = FundChannelCancelRequest { id: rpc_id };
-                let _ = rpc.call_typed(&fundchannel_cancel_request).await;
+                let _ = rpc.fundchannel_cancel(&fundchannel_cancel_request).await;
             } else {
                 log::debug!("Channel open was never initiated successfully");
             }
@@ -124,7 +494,8 @@ pub async fn fundchannel_fallible(
 
 ///
 async fn fundchannel_without_publishing_funding_transaction(
-    rpc: &mut ClnRpc,
+    rpc: &mut dyn ClnRpcApi,
+    clock: &dyn Clock,
     channel_details: &ChannelDetails,
     timeout: Duration,
 ) -> Result<Lsps1Channel, ChannelOpenError> {
@@ -134,35 +505,22 @@ async fn fundchannel_without_publishing_funding_transaction(
 
     // We need to capture details about our channel and reserved utxo's
     // to clean-up on failure
-    let mut error_data = ChannelOpenErrorData::default();
+    let mut error_data = ChannelOpenErrorData {
+        reservation: channel_details.reserved_inputs.clone(),
+        ..Default::default()
+    };
 
-    let rpc_id = rpc_primitives::PublicKey::from_str(&channel_details.peer_id.to_hex())
+    let rpc_id = to_rpc_public_key(&channel_details.peer_id)
         .map_err(|_| error_data.wrap(anyhow!("peer_id is not a valid ECDSA public key").into()))?;
     let amount = rpc_primitives::Amount::from_sat(channel_details.amount.sat_value());
 
-    let feerate = channel_details
-        .feerate
-        .clone()
-        .map(|x| rpc_primitives::Feerate::PerKw(x.to_sats_per_kwu().try_into().unwrap()));
+    let feerate = to_rpc_feerate(&channel_details.feerate);
 
     // Do fundchannel_request
-    let fundchannel_request: FundChannelStartRequest = FundChannelStartRequest {
-        id: rpc_id,
-        amount,
-        feerate: feerate,
-        announce: channel_details.announce,
-        close_to: channel_details.close_to.clone(),
-        push_msat: channel_details
-            .push_msat
-            .map(|x| rpc_primitives::Amount::from_sat(x.sat_value())),
-        mindepth: channel_details.mindepth,
-        reserve: channel_details
-            .reserve
-            .map(|x| rpc_primitives::Amount::from_sat(x.sat_value())),
-    };
+    let fundchannel_request = channel_details.fundchannel_start_request(rpc_id);
 
     // Do fundchannel start and wrap it in a timeout
-    let future = rpc.call_typed(&fundchannel_request);
+    let future = rpc.fundchannel_start(&fundchannel_request);
     let timeout = timeout_time
         .checked_duration_since(std::time::Instant::now())
         .ok_or(error_data.wrap(anyhow!("Timeout in channel open").into()))?;
@@ -174,38 +532,56 @@ async fn fundchannel_without_publishing_funding_transaction(
     let fundchannel_response: FundChannelStartResponse = tokio::time::timeout(timeout, future)
         .await
         .map_err(|_| error_data.wrap(anyhow!("Time-out in RPC-command: fundchannel_start").into()))?
-        .map_err(|e| error_data.wrap(Box::new(e)))?;
+        .map_err(|e| error_data.wrap(e.into()))?;
 
     let funding_address = fundchannel_response.funding_address.clone();
     error_data.peer_id = Some(channel_details.peer_id.clone());
     error_data.funding_address = Some(funding_address.clone());
 
     // Create the funding transaction
-    // It is an unsigned psbt
+    // It is an unsigned psbt that pays the feerate used to price the order
     let address = funding_address;
+    let utxos = match error_data.reservation.take() {
+        Some(reservation) => handover_reserved_inputs(rpc, &reservation).await,
+        None => None,
+    };
+    // lightningd would select inputs of other funding sources
+    if utxos.is_none() && channel_details.utxo_filter.is_some() {
+        return Err(error_data
+            .wrap(anyhow!("The reserved inputs of the funding source are not available").into()));
+    }
     log::debug!("Constructing the funding transaction");
     let txprepare_request: TxprepareRequest = TxprepareRequest {
         outputs: vec![rpc_primitives::OutputDesc {
             address: address,
             amount: amount,
         }],
-        feerate: None,
+        feerate,
         minconf: None,
-        utxos: None,
+        utxos,
     };
     let timeout = timeout_time
         .checked_duration_since(std::time::Instant::now())
         .ok_or(error_data.wrap(anyhow!("Timeout in channel open").into()))?;
     log::debug!("Call txprepare with a timeout of {} sec", timeout.as_secs());
     let txprepare_response: cln_rpc::model::responses::TxprepareResponse =
-        tokio::time::timeout(timeout, rpc.call_typed(&txprepare_request))
+        tokio::time::timeout(timeout, rpc.txprepare(&txprepare_request))
             .await
             .map_err(|_| error_data.wrap(anyhow!("Time-out in RPC-command: txprepare").into()))?
-            .map_err(|e| error_data.wrap(Box::new(e)))?;
+            .map_err(|e| error_data.wrap(e.into()))?;
 
     error_data.txid = Some(txprepare_response.txid.clone());
-    let funding_txid =
-        TransactionId::from_str(&txprepare_response.txid).map_err(|e| error_data.wrap(e.into()))?;
+    let funding_txid = TransactionId::from_str(&txprepare_response.txid)
+        .map_err(|e| error_data.wrap(Box::new(e)))?;
+
+    // The outpoint is stored with the order. It must point at the
+    // output lightningd created rather than the one we requested
+    let outnum = find_funding_outnum(
+        &txprepare_response.psbt,
+        &fundchannel_response.funding_address,
+        channel_details.amount,
+    )
+    .map_err(|e| error_data.wrap(e.into()))?;
 
     // Get the commitment transaction from the peer
     log::debug!("Securing the commitment transaction from peer");
@@ -220,13 +596,13 @@ async fn fundchannel_without_publishing_funding_transaction(
         "Call 'fundchannel_complete with a timeout of {} sec",
         timeout.as_secs()
     );
-    let fundchannelcomplete_response: FundChannelCompleteResponse =
-        tokio::time::timeout(timeout, rpc.call_typed(&fundchannelcomplete_request))
-            .await
-            .map_err(|_| {
-                error_data.wrap(anyhow!("Time-out in RPC-command: fundchannel_complete").into())
-            })?
-            .map_err(|e| error_data.wrap(Box::new(e)))?;
+    let fundchannelcomplete_response: FundChannelCompleteResponse = tokio::time::timeout(
+        timeout,
+        rpc.fundchannel_complete(&fundchannelcomplete_request),
+    )
+    .await
+    .map_err(|_| error_data.wrap(anyhow!("Time-out in RPC-command: fundchannel_complete").into()))?
+    .map_err(|e| error_data.wrap(e.into()))?;
 
     if !fundchannelcomplete_response.commitments_secured {
         return Err(error_data.wrap(
@@ -237,21 +613,196 @@ async fn fundchannel_without_publishing_funding_transaction(
         ));
     }
 
-    let outnum = txprepare_request
-        .outputs
-        .iter()
-        .position(|x| x.address == fundchannel_response.funding_address)
-        .ok_or_else(|| {
-            error_data.wrap(anyhow!("Failed to find matching output in funding transaction").into())
-        })?
-        .try_into()
-        .map_err(|e| {
-            error_data.wrap(anyhow!("Error in type-conversion of outnum: {}", e).into())
-        })?;
-
     Ok(Lsps1Channel {
         funding_txid,
         outnum: outnum,
-        funded_at: IsoDatetime::now(),
+        funded_at: clock.now(),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::sqlite::test::create_test_order;
+
+    fn config(min_required_channel_confirmations: u16) -> ServerConfig {
+        ServerConfig {
+            min_required_channel_confirmations,
+            close_to: None,
+        }
+    }
+
+    fn estimate(blockcount: u32, feerate: u32) -> FeeratesPerkwEstimates {
+        FeeratesPerkwEstimates {
+            blockcount: Some(blockcount),
+            feerate: Some(feerate),
+            smoothed_feerate: Some(feerate),
+        }
+    }
+
+    #[test]
+    fn capacity_and_push() {
+        let mut order = create_test_order();
+        order.lsp_balance_sat = SatAmount::new(100_000);
+        order.client_balance_sat = SatAmount::new(20_000);
+
+        let details =
+            ChannelDetails::from_order(&order, &config(0), &FeerateSnapshot::default()).unwrap();
+        assert_eq!(details.peer_id, order.client_node_id);
+        assert_eq!(details.amount, SatAmount::new(120_000));
+        assert_eq!(details.push_msat, Some(MsatAmount::new(20_000_000)));
+        assert_eq!(details.reserve, Some(SatAmount::new(0)));
+        assert_eq!(details.close_to, None);
+
+        // Nothing is pushed if the client doesn't buy outbound liquidity
+        order.client_balance_sat = SatAmount::new(0);
+        let details =
+            ChannelDetails::from_order(&order, &config(0), &FeerateSnapshot::default()).unwrap();
+        assert_eq!(details.amount, SatAmount::new(100_000));
+        assert_eq!(details.push_msat, None);
+    }
+
+    #[test]
+    fn impossible_amounts_are_rejected() {
+        let feerates = FeerateSnapshot::default();
+        let mut order = create_test_order();
+
+        order.lsp_balance_sat = SatAmount::new(u64::MAX);
+        order.client_balance_sat = SatAmount::new(1);
+        let err = ChannelDetails::from_order(&order, &config(0), &feerates).unwrap_err();
+        assert_eq!(err, ChannelDetailsError::CapacityOverflow);
+
+        order.lsp_balance_sat = SatAmount::new(0);
+        order.client_balance_sat = SatAmount::new(u64::MAX / 1000 + 1);
+        let err = ChannelDetails::from_order(&order, &config(0), &feerates).unwrap_err();
+        assert_eq!(err, ChannelDetailsError::PushOverflow);
+
+        order.client_balance_sat = SatAmount::new(u64::MAX / 1000);
+        assert!(ChannelDetails::from_order(&order, &config(0), &feerates).is_ok());
+
+        order.client_balance_sat = SatAmount::new(0);
+        let err = ChannelDetails::from_order(&order, &config(0), &feerates).unwrap_err();
+        assert_eq!(err, ChannelDetailsError::ZeroCapacity);
+    }
+
+    #[test]
+    fn mindepth_rules() {
+        let feerates = FeerateSnapshot::default();
+        let mut order = create_test_order();
+        let mindepth = |order: &Lsps1Order, min: u16| {
+            ChannelDetails::from_order(order, &config(min), &feerates)
+                .unwrap()
+                .mindepth
+        };
+
+        // The highest of the configured and requested confirmations is used
+        order.required_channel_confirmations = 3;
+        assert_eq!(mindepth(&order, 6), Some(6));
+        order.required_channel_confirmations = 10;
+        assert_eq!(mindepth(&order, 6), Some(10));
+
+        // Unannounced channels can be zero-conf
+        order.required_channel_confirmations = 0;
+        order.announce_channel = false;
+        let details = ChannelDetails::from_order(&order, &config(0), &feerates).unwrap();
+        assert_eq!(details.mindepth, Some(0));
+        assert_eq!(details.announce, Some(false));
+
+        // Announced channels need at least one confirmation
+        order.announce_channel = true;
+        let details = ChannelDetails::from_order(&order, &config(0), &feerates).unwrap();
+        assert_eq!(details.mindepth, Some(1));
+        assert_eq!(details.announce, Some(true));
+        assert_eq!(mindepth(&order, 6), Some(6));
+    }
+
+    const REGTEST_ADDRESS: &str = "bcrt1qkm08480v79rzjp7tx2pjrly423ncv85k65nsmu";
+    const MAINNET_ADDRESS: &str = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+
+    #[test]
+    fn close_to_of_other_network_is_refused() {
+        let address = parse_close_to(REGTEST_ADDRESS, &Network::Regtest).unwrap();
+        assert_eq!(address.to_string(), REGTEST_ADDRESS);
+
+        let err = parse_close_to(MAINNET_ADDRESS, &Network::Regtest).unwrap_err();
+        assert!(format!("{:#}", err).contains("Network mismatch"));
+
+        assert!(parse_close_to("not-an-address", &Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn channel_closes_to_configured_address() {
+        let order = create_test_order();
+        let config = ServerConfig {
+            min_required_channel_confirmations: 0,
+            close_to: Some(parse_close_to(REGTEST_ADDRESS, &Network::Regtest).unwrap()),
+        };
+
+        let details =
+            ChannelDetails::from_order(&order, &config, &FeerateSnapshot::default()).unwrap();
+        assert_eq!(details.close_to.as_deref(), Some(REGTEST_ADDRESS));
+    }
+
+    #[test]
+    fn feerate_matches_funding_target() {
+        let feerates = FeerateSnapshot {
+            estimates: vec![estimate(2, 10_000), estimate(6, 5_000), estimate(12, 2_000)],
+        };
+        let mut order = create_test_order();
+        let feerate = |order: &Lsps1Order| {
+            ChannelDetails::from_order(order, &config(0), &feerates)
+                .unwrap()
+                .feerate
+                .map(|f| f.to_sats_per_kwu())
+        };
+
+        order.funding_confirms_within_blocks = 6;
+        assert_eq!(feerate(&order), Some(5_000));
+        order.funding_confirms_within_blocks = 144;
+        assert_eq!(feerate(&order), Some(2_000));
+
+        // The highest feerate is used if no estimate is fast enough
+        order.funding_confirms_within_blocks = 1;
+        assert_eq!(feerate(&order), Some(10_000));
+
+        // lightningd picks a feerate if there are no estimates
+        let details =
+            ChannelDetails::from_order(&order, &config(0), &FeerateSnapshot::default()).unwrap();
+        assert!(details.feerate.is_none());
+    }
+
+    #[test]
+    fn fundchannel_start_requests_channel_type() {
+        use ChannelTypeFeature::{Anchors, ScidAlias, Zeroconf};
+
+        let order = create_test_order();
+        let mut details =
+            ChannelDetails::from_order(&order, &config(0), &FeerateSnapshot::default()).unwrap();
+        let id = to_rpc_public_key(&details.peer_id).unwrap();
+
+        // lightningd picks the channel type if the client didn't request one
+        let request = serde_json::to_value(details.fundchannel_start_request(id)).unwrap();
+        assert!(request.get("channel_type").is_none());
+
+        let combinations: Vec<(Vec<ChannelTypeFeature>, Vec<u32>)> = vec![
+            (vec![], vec![12]),
+            (vec![Anchors], vec![12, 22]),
+            (vec![ScidAlias], vec![12, 46]),
+            (vec![Zeroconf], vec![12, 50]),
+            (vec![Anchors, ScidAlias], vec![12, 22, 46]),
+            (vec![Anchors, Zeroconf], vec![12, 22, 50]),
+            (vec![Zeroconf, ScidAlias], vec![12, 46, 50]),
+            (vec![Zeroconf, ScidAlias, Anchors], vec![12, 22, 46, 50]),
+        ];
+        for (features, bits) in combinations {
+            details.channel_type = Some(features.clone());
+            let request = serde_json::to_value(details.fundchannel_start_request(id)).unwrap();
+            assert_eq!(
+                request["channel_type"],
+                serde_json::json!(bits),
+                "{:?}",
+                features
+            );
+        }
+    }
+}
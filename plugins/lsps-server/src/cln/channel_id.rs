@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+
+use lsp_primitives::hex;
+use lsp_primitives::lsps0::common_schemas::TransactionId;
+
+/// Derives the `channel_id` of a channel from its funding outpoint
+///
+/// BOLT 2 defines the `channel_id` as the funding txid in internal byte
+/// order with the last two bytes XOR-ed with the output index.
+/// Core Lightning displays the txid in reversed byte order.
+pub(crate) fn derive_channel_id(funding_txid: &TransactionId, outnum: u32) -> Result<String> {
+    let outnum = u16::try_from(outnum).context("Funding output index exceeds 16 bits")?;
+
+    let mut channel_id = hex::decode("txid", &funding_txid.to_string())?;
+    channel_id.reverse();
+
+    let [high, low] = outnum.to_be_bytes();
+    channel_id[30] ^= high;
+    channel_id[31] ^= low;
+
+    Ok(hex::encode(channel_id))
+}
+
+/// Returns the block in which the funding transaction confirmed
+///
+/// Core Lightning formats a `short_channel_id` as `BLOCKxTXINDEXxOUTNUM`
+pub(crate) fn short_channel_id_blockheight(short_channel_id: &str) -> Result<u32> {
+    let mut parts = short_channel_id.split('x');
+    let blockheight = parts.next().context("Empty short_channel_id")?;
+    if parts.count() != 2 {
+        anyhow::bail!("Invalid short_channel_id: {}", short_channel_id);
+    }
+    blockheight
+        .parse()
+        .with_context(|| format!("Invalid short_channel_id: {}", short_channel_id))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::str::FromStr;
+
+    fn txid() -> TransactionId {
+        TransactionId::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+            .unwrap()
+    }
+
+    #[test]
+    fn channel_id_is_reversed_txid_for_first_output() {
+        let channel_id = derive_channel_id(&txid(), 0).unwrap();
+        assert_eq!(
+            channel_id,
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a"
+        );
+    }
+
+    #[test]
+    fn output_index_is_xored_into_last_bytes() {
+        let channel_id = derive_channel_id(&txid(), 0x0102).unwrap();
+        assert_eq!(
+            channel_id,
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5f48"
+        );
+    }
+
+    #[test]
+    fn output_index_must_fit_in_16_bits() {
+        assert!(derive_channel_id(&txid(), 1 << 16).is_err());
+    }
+
+    #[test]
+    fn blockheight_from_short_channel_id() {
+        assert_eq!(short_channel_id_blockheight("103x1x0").unwrap(), 103);
+        assert!(short_channel_id_blockheight("103x1").is_err());
+        assert!(short_channel_id_blockheight("ax1x0").is_err());
+        assert!(short_channel_id_blockheight("").is_err());
+    }
+}
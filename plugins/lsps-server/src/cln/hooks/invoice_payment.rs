@@ -75,7 +75,6 @@ pub(crate) struct Payment {
     pub(crate) label: String,
     #[allow(dead_code)]
     pub(crate) preimage: String,
-    #[allow(dead_code)]
     pub(crate) msat: AmountMsat,
 }
 
@@ -1,2 +1,7 @@
+pub(crate) mod channel_id;
 pub(crate) mod hooks;
+pub(crate) mod notifications;
+pub(crate) mod public_key;
+pub(crate) mod reconnecting_rpc;
+pub(crate) mod rpc_api;
 pub(crate) mod rpc_model;
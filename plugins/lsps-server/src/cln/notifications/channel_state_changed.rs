@@ -0,0 +1,91 @@
+use serde::Deserialize;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChannelStateChangedNotification {
+    pub(crate) peer_id: PublicKey,
+    pub(crate) channel_id: String,
+    #[allow(dead_code)]
+    pub(crate) short_channel_id: Option<String>,
+    #[allow(dead_code)]
+    pub(crate) old_state: Option<String>,
+    pub(crate) new_state: String,
+    pub(crate) cause: String,
+    pub(crate) message: Option<String>,
+}
+
+impl ChannelStateChangedNotification {
+    pub(crate) fn from_value(value: serde_json::Value) -> serde_json::Result<Self> {
+        match value.get("channel_state_changed") {
+            Some(inner) => serde_json::from_value(inner.clone()),
+            None => serde_json::from_value(value),
+        }
+    }
+
+    /// The channel can be used to make payments
+    pub(crate) fn is_ready(&self) -> bool {
+        self.new_state == "CHANNELD_NORMAL"
+    }
+
+    /// The channel is being closed or is closed
+    ///
+    /// A channel that reaches any of these states before it was
+    /// ready will never become usable.
+    pub(crate) fn is_aborted(&self) -> bool {
+        matches!(
+            self.new_state.as_str(),
+            "CHANNELD_SHUTTING_DOWN"
+                | "CLOSINGD_SIGEXCHANGE"
+                | "CLOSINGD_COMPLETE"
+                | "AWAITING_UNILATERAL"
+                | "FUNDING_SPEND_SEEN"
+                | "ONCHAIN"
+                | "CLOSED"
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn deserialize_channel_state_changed_notification() {
+        let value = serde_json::json!({
+            "channel_state_changed" : {
+                "peer_id" : "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                "channel_id" : "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a",
+                "short_channel_id" : "103x1x0",
+                "timestamp" : "2023-01-05T18:27:12.145Z",
+                "old_state" : "CHANNELD_AWAITING_LOCKIN",
+                "new_state" : "CHANNELD_NORMAL",
+                "cause" : "remote",
+                "message" : "Lockin complete"
+            }
+        });
+
+        let notification = ChannelStateChangedNotification::from_value(value).unwrap();
+        assert!(notification.is_ready());
+        assert!(!notification.is_aborted());
+        assert_eq!(notification.short_channel_id.as_deref(), Some("103x1x0"));
+    }
+
+    #[test]
+    fn short_channel_id_and_old_state_are_optional() {
+        // Neither is known when the channel is created
+        let value = serde_json::json!({
+            "peer_id" : "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "channel_id" : "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a",
+            "timestamp" : "2023-01-05T18:27:12.145Z",
+            "new_state" : "CHANNELD_AWAITING_LOCKIN",
+            "cause" : "user",
+            "message" : "new channel opened"
+        });
+
+        let notification = ChannelStateChangedNotification::from_value(value).unwrap();
+        assert!(!notification.is_ready());
+        assert!(!notification.is_aborted());
+    }
+}
@@ -0,0 +1,55 @@
+use serde::Deserialize;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ConnectNotification {
+    pub(crate) id: PublicKey,
+    #[allow(dead_code)]
+    pub(crate) direction: String,
+}
+
+impl ConnectNotification {
+    pub(crate) fn from_value(value: serde_json::Value) -> serde_json::Result<Self> {
+        // Newer versions of Core Lightning wrap the notification data
+        // in an object named after the topic
+        match value.get("connect") {
+            Some(inner) => serde_json::from_value(inner.clone()),
+            None => serde_json::from_value(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn deserialize_connect_notification() {
+        let id = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+        // Old behavior
+        // The fields are at the top-level
+        let value = serde_json::json!({
+            "id" : id,
+            "direction" : "in",
+            "address" : {"type" : "ipv4", "address" : "127.0.0.1", "port" : 9735 }
+        });
+        let notification = ConnectNotification::from_value(value).unwrap();
+        assert_eq!(notification.id.to_hex(), id);
+
+        // New behavior
+        // The fields are wrapped in a `connect`-object
+        let value = serde_json::json!({
+            "connect" : {
+                "id" : id,
+                "direction" : "out",
+                "address" : {"type" : "ipv4", "address" : "127.0.0.1", "port" : 9735 }
+            }
+        });
+        let notification = ConnectNotification::from_value(value).unwrap();
+        assert_eq!(notification.id.to_hex(), id);
+        assert_eq!(notification.direction, "out");
+    }
+}
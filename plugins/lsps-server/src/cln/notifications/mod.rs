@@ -0,0 +1,2 @@
+pub(crate) mod channel_state_changed;
+pub(crate) mod connect;
@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use cln_rpc::primitives as rpc_primitives;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+/// Converts a [`PublicKey`] to the type used by `cln_rpc`
+///
+/// Both crates use a different version of `secp256k1`. The conversion
+/// uses the compressed serialization and avoids a round-trip through hex.
+pub(crate) fn to_rpc_public_key(public_key: &PublicKey) -> Result<rpc_primitives::PublicKey> {
+    rpc_primitives::PublicKey::from_slice(&public_key.serialize())
+        .map_err(|e| anyhow!("Invalid public-key: {}", e))
+}
+
+/// Returns true if both keys represent the same node
+pub(crate) fn is_same_public_key(
+    public_key: &PublicKey,
+    rpc_public_key: &rpc_primitives::PublicKey,
+) -> bool {
+    public_key.serialize() == rpc_public_key.serialize()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    const PUBKEY_HEX: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const OTHER_PUBKEY_HEX: &str =
+        "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    #[test]
+    fn compare_public_key_with_rpc_public_key() {
+        let public_key = PublicKey::from_hex(&PUBKEY_HEX.to_uppercase()).unwrap();
+        let rpc_public_key = rpc_primitives::PublicKey::from_str(PUBKEY_HEX).unwrap();
+        let other_rpc_public_key = rpc_primitives::PublicKey::from_str(OTHER_PUBKEY_HEX).unwrap();
+
+        assert!(is_same_public_key(&public_key, &rpc_public_key));
+        assert!(!is_same_public_key(&public_key, &other_rpc_public_key));
+    }
+
+    #[test]
+    fn convert_public_key_to_rpc_and_back() {
+        let public_key = PublicKey::from_hex(PUBKEY_HEX).unwrap();
+        let rpc_public_key = to_rpc_public_key(&public_key).unwrap();
+
+        assert_eq!(rpc_public_key.to_string(), PUBKEY_HEX);
+        assert!(is_same_public_key(&public_key, &rpc_public_key));
+    }
+}
@@ -0,0 +1,495 @@
+//! A connection to lightningd that survives a restart of its rpc-socket
+//!
+//! Background tasks keep their connection for as long as they run. Once
+//! lightningd closes the socket every call on a [`ClnRpc`] fails. A
+//! [`ReconnectingRpc`] drops a connection that failed and connects again
+//! on the next call. Calls are never retried because they might not be
+//! idempotent, e.g.: `txsend`.
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::{
+    DelinvoiceRequest, FeeratesRequest, GetinfoRequest, InvoiceRequest, ListforwardsRequest,
+    ListfundsRequest, ListnodesRequest, ListpeerchannelsRequest, ListpeersRequest,
+    SendcustommsgRequest, SignmessageRequest, TxdiscardRequest, TxprepareRequest, TxsendRequest,
+    WithdrawRequest,
+};
+use cln_rpc::model::responses::{
+    DelinvoiceResponse, FeeratesResponse, GetinfoResponse, InvoiceResponse, ListforwardsResponse,
+    ListfundsResponse, ListnodesResponse, ListpeerchannelsResponse, ListpeersResponse,
+    SendcustommsgResponse, SignmessageResponse, TxdiscardResponse, TxprepareResponse,
+    TxsendResponse, WithdrawResponse,
+};
+use cln_rpc::{ClnRpc, RpcError};
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::cln::rpc_model::{
+    FundChannelCancelRequest, FundChannelCancelResponse, FundChannelCompleteRequest,
+    FundChannelCompleteResponse, FundChannelStartRequest, FundChannelStartResponse,
+    FundpsbtRequest, FundpsbtResponse, GetchaininfoRequest, GetchaininfoResponse,
+    UnreserveinputsRequest, UnreserveinputsResponse, UtxopsbtRequest,
+};
+use crate::metrics::Metrics;
+
+/// The delay before the second attempt to connect
+pub(crate) const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// The delay doubles after every failed attempt up to this maximum
+pub(crate) const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// The number of attempts to connect before a call fails
+pub(crate) const MAX_CONNECT_ATTEMPTS: u32 = 6;
+
+/// Creates new connections to lightningd
+#[async_trait::async_trait]
+pub(crate) trait Connect: Send + Sync {
+    type Rpc: ClnRpcApi;
+
+    async fn connect(&self) -> Result<Self::Rpc>;
+}
+
+/// Connects to the rpc-socket of lightningd
+pub(crate) struct SocketConnector {
+    rpc_path: PathBuf,
+}
+
+impl SocketConnector {
+    pub(crate) fn new(rpc_path: impl Into<PathBuf>) -> Self {
+        Self {
+            rpc_path: rpc_path.into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Connect for SocketConnector {
+    type Rpc = ClnRpc;
+
+    async fn connect(&self) -> Result<ClnRpc> {
+        ClnRpc::new(&self.rpc_path)
+            .await
+            .with_context(|| format!("Failed to connect to {:?}", self.rpc_path))
+    }
+}
+
+/// Returns true if the call failed because the connection broke
+///
+/// Errors returned by lightningd have an error code. Errors of the
+/// socket don't.
+pub(crate) fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|cause| match cause.downcast_ref::<RpcError>() {
+            Some(err) => err.code.is_none(),
+            None => cause.is::<std::io::Error>(),
+        })
+}
+
+/// A [`ClnRpcApi`] that connects lazily and reconnects after a connection error
+///
+/// Reconnects are reported in the task health of `metrics` under `name`.
+pub(crate) struct ReconnectingRpc<C: Connect = SocketConnector> {
+    name: &'static str,
+    connector: C,
+    metrics: Arc<Metrics>,
+    rpc: Option<C::Rpc>,
+    has_connected: bool,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl ReconnectingRpc<SocketConnector> {
+    pub(crate) fn new(
+        name: &'static str,
+        rpc_path: impl Into<PathBuf>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self::with_connector(name, SocketConnector::new(rpc_path), metrics)
+    }
+}
+
+impl<C: Connect> ReconnectingRpc<C> {
+    pub(crate) fn with_connector(name: &'static str, connector: C, metrics: Arc<Metrics>) -> Self {
+        Self {
+            name,
+            connector,
+            metrics,
+            rpc: None,
+            has_connected: false,
+            initial_delay: RECONNECT_INITIAL_DELAY,
+            max_delay: RECONNECT_MAX_DELAY,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_backoff(mut self, initial_delay: Duration, max_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Returns the current connection or connects with exponential backoff
+    async fn connection(&mut self) -> Result<&mut C::Rpc> {
+        if self.rpc.is_none() {
+            let mut delay = self.initial_delay;
+            let mut attempt = 1;
+            let rpc = loop {
+                match self.connector.connect().await {
+                    Ok(rpc) => break rpc,
+                    Err(err) if attempt >= MAX_CONNECT_ATTEMPTS => {
+                        return Err(err.context(format!(
+                            "Task '{}' failed to connect to lightningd {} times",
+                            self.name, attempt
+                        )))
+                    }
+                    Err(err) => log::debug!(
+                        "Task '{}' failed to connect to lightningd (attempt {}): {:?}",
+                        self.name,
+                        attempt,
+                        err
+                    ),
+                }
+                tokio::time::sleep(delay).await;
+                delay = std::cmp::min(delay * 2, self.max_delay);
+                attempt += 1;
+            };
+
+            if self.has_connected {
+                log::info!("Task '{}' reconnected to lightningd", self.name);
+                self.metrics.tasks.reconnected(self.name);
+            }
+            self.has_connected = true;
+            self.rpc = Some(rpc);
+        }
+        self.rpc
+            .as_mut()
+            .context("The connection to lightningd is missing")
+    }
+
+    /// Drops the connection if `result` is a connection error
+    fn inspect<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(err) = &result {
+            if is_connection_error(err) {
+                log::warn!(
+                    "Task '{}' lost its connection to lightningd: {:?}",
+                    self.name,
+                    err
+                );
+                self.rpc = None;
+            }
+        }
+        result
+    }
+}
+
+macro_rules! reconnecting_call {
+    ($self:ident, $method:ident, $request:ident) => {{
+        let result = $self.connection().await?.$method($request).await;
+        $self.inspect(result)
+    }};
+}
+
+#[async_trait::async_trait]
+impl<C: Connect> ClnRpcApi for ReconnectingRpc<C> {
+    async fn getinfo(&mut self, request: &GetinfoRequest) -> Result<GetinfoResponse> {
+        reconnecting_call!(self, getinfo, request)
+    }
+
+    async fn getchaininfo(
+        &mut self,
+        request: &GetchaininfoRequest,
+    ) -> Result<GetchaininfoResponse> {
+        reconnecting_call!(self, getchaininfo, request)
+    }
+
+    async fn invoice(&mut self, request: &InvoiceRequest) -> Result<InvoiceResponse> {
+        reconnecting_call!(self, invoice, request)
+    }
+
+    async fn delinvoice(&mut self, request: &DelinvoiceRequest) -> Result<DelinvoiceResponse> {
+        reconnecting_call!(self, delinvoice, request)
+    }
+
+    async fn listpeers(&mut self, request: &ListpeersRequest) -> Result<ListpeersResponse> {
+        reconnecting_call!(self, listpeers, request)
+    }
+
+    async fn listnodes(&mut self, request: &ListnodesRequest) -> Result<ListnodesResponse> {
+        reconnecting_call!(self, listnodes, request)
+    }
+
+    async fn listpeerchannels(
+        &mut self,
+        request: &ListpeerchannelsRequest,
+    ) -> Result<ListpeerchannelsResponse> {
+        reconnecting_call!(self, listpeerchannels, request)
+    }
+
+    async fn listforwards(
+        &mut self,
+        request: &ListforwardsRequest,
+    ) -> Result<ListforwardsResponse> {
+        reconnecting_call!(self, listforwards, request)
+    }
+
+    async fn feerates(&mut self, request: &FeeratesRequest) -> Result<FeeratesResponse> {
+        reconnecting_call!(self, feerates, request)
+    }
+
+    async fn listfunds(&mut self, request: &ListfundsRequest) -> Result<ListfundsResponse> {
+        reconnecting_call!(self, listfunds, request)
+    }
+
+    async fn fundchannel_start(
+        &mut self,
+        request: &FundChannelStartRequest,
+    ) -> Result<FundChannelStartResponse> {
+        reconnecting_call!(self, fundchannel_start, request)
+    }
+
+    async fn fundchannel_complete(
+        &mut self,
+        request: &FundChannelCompleteRequest,
+    ) -> Result<FundChannelCompleteResponse> {
+        reconnecting_call!(self, fundchannel_complete, request)
+    }
+
+    async fn fundchannel_cancel(
+        &mut self,
+        request: &FundChannelCancelRequest,
+    ) -> Result<FundChannelCancelResponse> {
+        reconnecting_call!(self, fundchannel_cancel, request)
+    }
+
+    async fn txprepare(&mut self, request: &TxprepareRequest) -> Result<TxprepareResponse> {
+        reconnecting_call!(self, txprepare, request)
+    }
+
+    async fn txsend(&mut self, request: &TxsendRequest) -> Result<TxsendResponse> {
+        reconnecting_call!(self, txsend, request)
+    }
+
+    async fn txdiscard(&mut self, request: &TxdiscardRequest) -> Result<TxdiscardResponse> {
+        reconnecting_call!(self, txdiscard, request)
+    }
+
+    async fn fundpsbt(&mut self, request: &FundpsbtRequest) -> Result<FundpsbtResponse> {
+        reconnecting_call!(self, fundpsbt, request)
+    }
+
+    async fn utxopsbt(&mut self, request: &UtxopsbtRequest) -> Result<FundpsbtResponse> {
+        reconnecting_call!(self, utxopsbt, request)
+    }
+
+    async fn unreserveinputs(
+        &mut self,
+        request: &UnreserveinputsRequest,
+    ) -> Result<UnreserveinputsResponse> {
+        reconnecting_call!(self, unreserveinputs, request)
+    }
+
+    async fn sendcustommsg(
+        &mut self,
+        request: &SendcustommsgRequest,
+    ) -> Result<SendcustommsgResponse> {
+        reconnecting_call!(self, sendcustommsg, request)
+    }
+
+    async fn withdraw(&mut self, request: &WithdrawRequest) -> Result<WithdrawResponse> {
+        reconnecting_call!(self, withdraw, request)
+    }
+
+    async fn signmessage(&mut self, request: &SignmessageRequest) -> Result<SignmessageResponse> {
+        reconnecting_call!(self, signmessage, request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use anyhow::anyhow;
+    use cln_rpc::model::requests::{DelinvoiceStatus, FeeratesStyle};
+    use serde_json::json;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::schema::{InvoiceLabel, Lsps1OrderState};
+    use crate::db::sqlite::queries::GetOrderQuery;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::lsps1::order_expiry::expire_unpaid_orders;
+    use crate::state::test_support::test_state;
+
+    /// Hands out the same [`FakeClnRpc`] after `failures` failed attempts
+    ///
+    /// Clones share their counters
+    #[derive(Clone)]
+    struct FakeConnector {
+        rpc: FakeClnRpc,
+        failures: Arc<AtomicU32>,
+        connects: Arc<AtomicU32>,
+    }
+
+    impl FakeConnector {
+        fn new(rpc: &FakeClnRpc, failures: u32) -> Self {
+            Self {
+                rpc: rpc.clone(),
+                failures: Arc::new(AtomicU32::new(failures)),
+                connects: Arc::new(AtomicU32::new(0)),
+            }
+        }
+
+        fn connects(&self) -> u32 {
+            self.connects.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Connect for FakeConnector {
+        type Rpc = FakeClnRpc;
+
+        async fn connect(&self) -> Result<FakeClnRpc> {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            let failed = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |f| f.checked_sub(1));
+            if failed.is_ok() {
+                return Err(anyhow!("Connection refused"));
+            }
+            Ok(self.rpc.clone())
+        }
+    }
+
+    fn reconnecting_rpc(
+        connector: &FakeConnector,
+        metrics: &Arc<Metrics>,
+    ) -> ReconnectingRpc<FakeConnector> {
+        ReconnectingRpc::with_connector("test", connector.clone(), metrics.clone())
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(4))
+    }
+
+    fn feerates_response() -> serde_json::Value {
+        json!({
+            "perkw" : {
+                "min_acceptable" : 253,
+                "max_acceptable" : 100_000,
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn connection_error_reconnects_on_next_call() {
+        let metrics = Arc::new(Metrics::default());
+        let fake = FakeClnRpc::default();
+        fake.respond("feerates", feerates_response())
+            .disconnect("feerates")
+            .respond("feerates", feerates_response());
+        let connector = FakeConnector::new(&fake, 0);
+        let mut rpc = reconnecting_rpc(&connector, &metrics);
+
+        let request = FeeratesRequest {
+            style: FeeratesStyle::PERKW,
+        };
+        rpc.feerates(&request).await.unwrap();
+        let err = rpc.feerates(&request).await.unwrap_err();
+        assert!(is_connection_error(&err));
+        assert_eq!(connector.connects(), 1);
+
+        rpc.feerates(&request).await.unwrap();
+        assert_eq!(connector.connects(), 2);
+        assert_eq!(metrics.tasks.to_json()["test"]["reconnects"], 1);
+    }
+
+    #[tokio::test]
+    async fn rpc_errors_keep_the_connection() {
+        let metrics = Arc::new(Metrics::default());
+        let fake = FakeClnRpc::default();
+        fake.fail("delinvoice", "Unknown invoice");
+        let connector = FakeConnector::new(&fake, 0);
+        let mut rpc = reconnecting_rpc(&connector, &metrics);
+
+        let err = rpc
+            .delinvoice(&DelinvoiceRequest {
+                label: "lsps1-order".to_string(),
+                status: DelinvoiceStatus::UNPAID,
+                desconly: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(!is_connection_error(&err));
+        assert!(rpc.rpc.is_some());
+    }
+
+    #[tokio::test]
+    async fn connect_is_retried_with_backoff() {
+        let metrics = Arc::new(Metrics::default());
+        let fake = FakeClnRpc::default();
+        fake.respond("feerates", feerates_response());
+        let request = FeeratesRequest {
+            style: FeeratesStyle::PERKW,
+        };
+
+        // lightningd comes back before we give up
+        let connector = FakeConnector::new(&fake, MAX_CONNECT_ATTEMPTS - 1);
+        let mut rpc = reconnecting_rpc(&connector, &metrics);
+        rpc.feerates(&request).await.unwrap();
+        assert_eq!(connector.connects(), MAX_CONNECT_ATTEMPTS);
+
+        // The first connection isn't counted as a reconnect
+        assert!(metrics.tasks.to_json().get("test").is_none());
+
+        // lightningd doesn't come back
+        let connector = FakeConnector::new(&fake, MAX_CONNECT_ATTEMPTS);
+        let mut rpc = reconnecting_rpc(&connector, &metrics);
+        let err = rpc.feerates(&request).await.unwrap_err();
+        assert!(err.to_string().contains("failed to connect"));
+    }
+
+    #[tokio::test]
+    async fn task_completes_its_work_after_reconnect() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+
+        // An order that expired unpaid
+        let mut query = create_order_query();
+        query.order.expires_at =
+            IsoDatetime::from_unix_timestamp(IsoDatetime::now().unix_timestamp() - 3600).unwrap();
+        let uuid = query.order.uuid;
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        // lightningd restarts while the invoice is deleted
+        let fake = FakeClnRpc::default();
+        fake.disconnect("delinvoice").respond(
+            "delinvoice",
+            json!({
+                "label" : InvoiceLabel::for_order(&uuid).unwrap().to_string(),
+                "payment_hash" : "00".repeat(32),
+                "status" : "unpaid",
+                "expires_at" : 1_700_000_000,
+                "created_index" : 1,
+            }),
+        );
+        let connector = FakeConnector::new(&fake, 1);
+        let mut rpc = reconnecting_rpc(&connector, &state.metrics);
+
+        assert_eq!(expire_unpaid_orders(&state, &mut rpc).await.unwrap(), 0);
+        assert_eq!(expire_unpaid_orders(&state, &mut rpc).await.unwrap(), 1);
+        assert_eq!(fake.called_methods(), vec!["delinvoice", "delinvoice"]);
+        assert_eq!(state.metrics.tasks.to_json()["test"]["reconnects"], 1);
+
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+    }
+}
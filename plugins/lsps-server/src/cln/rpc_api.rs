@@ -0,0 +1,521 @@
+use anyhow::Result;
+use cln_rpc::model::requests::{
+    DelinvoiceRequest, FeeratesRequest, GetinfoRequest, InvoiceRequest, ListforwardsRequest,
+    ListfundsRequest, ListnodesRequest, ListpeerchannelsRequest, ListpeersRequest,
+    SendcustommsgRequest, SignmessageRequest, TxdiscardRequest, TxprepareRequest, TxsendRequest,
+    WithdrawRequest,
+};
+use cln_rpc::model::responses::{
+    DelinvoiceResponse, FeeratesResponse, GetinfoResponse, InvoiceResponse, ListforwardsResponse,
+    ListfundsResponse, ListnodesResponse, ListpeerchannelsResponse, ListpeersResponse,
+    SendcustommsgResponse, SignmessageResponse, TxdiscardResponse, TxprepareResponse,
+    TxsendResponse, WithdrawResponse,
+};
+use cln_rpc::ClnRpc;
+
+use crate::cln::rpc_model::{
+    FundChannelCancelRequest, FundChannelCancelResponse, FundChannelCompleteRequest,
+    FundChannelCompleteResponse, FundChannelStartRequest, FundChannelStartResponse,
+    FundpsbtRequest, FundpsbtResponse, GetchaininfoRequest, GetchaininfoResponse,
+    ListconfigsRequest, ListconfigsResponse, UnreserveinputsRequest, UnreserveinputsResponse,
+    UtxopsbtRequest,
+};
+
+/// The rpc-methods of lightningd that are used by the handlers
+///
+/// The handlers only depend on this trait instead of on [`ClnRpc`].
+/// This allows tests to script the responses of lightningd using the
+/// `FakeClnRpc` in `test_support`.
+#[async_trait::async_trait]
+pub trait ClnRpcApi: Send {
+    async fn getinfo(&mut self, request: &GetinfoRequest) -> Result<GetinfoResponse>;
+
+    async fn getchaininfo(&mut self, request: &GetchaininfoRequest)
+        -> Result<GetchaininfoResponse>;
+
+    async fn invoice(&mut self, request: &InvoiceRequest) -> Result<InvoiceResponse>;
+
+    async fn delinvoice(&mut self, request: &DelinvoiceRequest) -> Result<DelinvoiceResponse>;
+
+    async fn listpeers(&mut self, request: &ListpeersRequest) -> Result<ListpeersResponse>;
+
+    async fn listnodes(&mut self, request: &ListnodesRequest) -> Result<ListnodesResponse>;
+
+    async fn listpeerchannels(
+        &mut self,
+        request: &ListpeerchannelsRequest,
+    ) -> Result<ListpeerchannelsResponse>;
+
+    async fn listforwards(&mut self, request: &ListforwardsRequest)
+        -> Result<ListforwardsResponse>;
+
+    async fn feerates(&mut self, request: &FeeratesRequest) -> Result<FeeratesResponse>;
+
+    async fn listfunds(&mut self, request: &ListfundsRequest) -> Result<ListfundsResponse>;
+
+    async fn fundchannel_start(
+        &mut self,
+        request: &FundChannelStartRequest,
+    ) -> Result<FundChannelStartResponse>;
+
+    async fn fundchannel_complete(
+        &mut self,
+        request: &FundChannelCompleteRequest,
+    ) -> Result<FundChannelCompleteResponse>;
+
+    async fn fundchannel_cancel(
+        &mut self,
+        request: &FundChannelCancelRequest,
+    ) -> Result<FundChannelCancelResponse>;
+
+    async fn txprepare(&mut self, request: &TxprepareRequest) -> Result<TxprepareResponse>;
+
+    async fn txsend(&mut self, request: &TxsendRequest) -> Result<TxsendResponse>;
+
+    async fn txdiscard(&mut self, request: &TxdiscardRequest) -> Result<TxdiscardResponse>;
+
+    async fn fundpsbt(&mut self, request: &FundpsbtRequest) -> Result<FundpsbtResponse>;
+
+    async fn utxopsbt(&mut self, request: &UtxopsbtRequest) -> Result<FundpsbtResponse>;
+
+    async fn unreserveinputs(
+        &mut self,
+        request: &UnreserveinputsRequest,
+    ) -> Result<UnreserveinputsResponse>;
+
+    async fn sendcustommsg(
+        &mut self,
+        request: &SendcustommsgRequest,
+    ) -> Result<SendcustommsgResponse>;
+
+    async fn withdraw(&mut self, request: &WithdrawRequest) -> Result<WithdrawResponse>;
+
+    async fn signmessage(&mut self, request: &SignmessageRequest) -> Result<SignmessageResponse>;
+
+    async fn listconfigs(&mut self, request: &ListconfigsRequest) -> Result<ListconfigsResponse>;
+}
+
+#[async_trait::async_trait]
+impl ClnRpcApi for ClnRpc {
+    async fn getinfo(&mut self, request: &GetinfoRequest) -> Result<GetinfoResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn getchaininfo(
+        &mut self,
+        request: &GetchaininfoRequest,
+    ) -> Result<GetchaininfoResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn invoice(&mut self, request: &InvoiceRequest) -> Result<InvoiceResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn delinvoice(&mut self, request: &DelinvoiceRequest) -> Result<DelinvoiceResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn listpeers(&mut self, request: &ListpeersRequest) -> Result<ListpeersResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn listnodes(&mut self, request: &ListnodesRequest) -> Result<ListnodesResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn listpeerchannels(
+        &mut self,
+        request: &ListpeerchannelsRequest,
+    ) -> Result<ListpeerchannelsResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn listforwards(
+        &mut self,
+        request: &ListforwardsRequest,
+    ) -> Result<ListforwardsResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn feerates(&mut self, request: &FeeratesRequest) -> Result<FeeratesResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn listfunds(&mut self, request: &ListfundsRequest) -> Result<ListfundsResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn fundchannel_start(
+        &mut self,
+        request: &FundChannelStartRequest,
+    ) -> Result<FundChannelStartResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn fundchannel_complete(
+        &mut self,
+        request: &FundChannelCompleteRequest,
+    ) -> Result<FundChannelCompleteResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn fundchannel_cancel(
+        &mut self,
+        request: &FundChannelCancelRequest,
+    ) -> Result<FundChannelCancelResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn txprepare(&mut self, request: &TxprepareRequest) -> Result<TxprepareResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn txsend(&mut self, request: &TxsendRequest) -> Result<TxsendResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn txdiscard(&mut self, request: &TxdiscardRequest) -> Result<TxdiscardResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn fundpsbt(&mut self, request: &FundpsbtRequest) -> Result<FundpsbtResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn utxopsbt(&mut self, request: &UtxopsbtRequest) -> Result<FundpsbtResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn unreserveinputs(
+        &mut self,
+        request: &UnreserveinputsRequest,
+    ) -> Result<UnreserveinputsResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn sendcustommsg(
+        &mut self,
+        request: &SendcustommsgRequest,
+    ) -> Result<SendcustommsgResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn withdraw(&mut self, request: &WithdrawRequest) -> Result<WithdrawResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn signmessage(&mut self, request: &SignmessageRequest) -> Result<SignmessageResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+
+    async fn listconfigs(&mut self, request: &ListconfigsRequest) -> Result<ListconfigsResponse> {
+        Ok(self.call_typed(request).await?)
+    }
+}
+
+/// Opens connections to lightningd
+///
+/// A request is handled on its own connection. Tests connect to a
+/// `FakeClnRpc` instead.
+#[async_trait::async_trait]
+pub trait RpcConnector: Send + Sync {
+    async fn connect(&self) -> Result<Box<dyn ClnRpcApi>>;
+}
+
+/// Connects to the rpc-file of lightningd
+pub(crate) struct RpcFileConnector {
+    rpc_file: String,
+}
+
+impl RpcFileConnector {
+    pub(crate) fn new(rpc_file: String) -> Self {
+        Self { rpc_file }
+    }
+}
+
+#[async_trait::async_trait]
+impl RpcConnector for RpcFileConnector {
+    async fn connect(&self) -> Result<Box<dyn ClnRpcApi>> {
+        Ok(Box::new(ClnRpc::new(&self.rpc_file).await?))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::{anyhow, Context};
+    use cln_rpc::RpcError;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+
+    use crate::channel_open::funding_output::test_support::{prepared_psbt, FUNDING_ADDRESS};
+
+    enum Scripted {
+        Response(Value),
+        Error(String),
+        /// lightningd closed the rpc-socket
+        Disconnected,
+    }
+
+    #[derive(Default)]
+    struct FakeState {
+        responses: HashMap<&'static str, VecDeque<Scripted>>,
+        calls: Vec<(&'static str, Value)>,
+    }
+
+    /// A [`ClnRpcApi`] that answers with scripted responses
+    ///
+    /// Responses are queued per method and returned in order. A call to a
+    /// method without a queued response fails. Clones share their script,
+    /// so a test can keep a clone to inspect the calls that were made.
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeClnRpc {
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    impl FakeClnRpc {
+        /// Queues a successful response to `method`
+        pub(crate) fn respond(&self, method: &'static str, response: Value) -> &Self {
+            self.queue(method, Scripted::Response(response))
+        }
+
+        /// Queues an rpc-error in response to `method`
+        pub(crate) fn fail(&self, method: &'static str, message: &str) -> &Self {
+            self.queue(method, Scripted::Error(message.to_string()))
+        }
+
+        /// Queues a connection error in response to `method`
+        ///
+        /// This is what a [`ClnRpc`] returns once lightningd closed the socket
+        pub(crate) fn disconnect(&self, method: &'static str) -> &Self {
+            self.queue(method, Scripted::Disconnected)
+        }
+
+        fn queue(&self, method: &'static str, response: Scripted) -> &Self {
+            self.state
+                .lock()
+                .unwrap()
+                .responses
+                .entry(method)
+                .or_default()
+                .push_back(response);
+            self
+        }
+
+        /// Returns the names of all methods that were called, in order
+        pub(crate) fn called_methods(&self) -> Vec<&'static str> {
+            let state = self.state.lock().unwrap();
+            state.calls.iter().map(|(method, _)| *method).collect()
+        }
+
+        /// Returns the params of the first call to `method`
+        pub(crate) fn params_of(&self, method: &str) -> Option<Value> {
+            let state = self.state.lock().unwrap();
+            state
+                .calls
+                .iter()
+                .find(|(m, _)| *m == method)
+                .map(|(_, params)| params.clone())
+        }
+
+        /// Queues the responses of a successful channel open of `capacity_sat`
+        ///
+        /// The funding transaction pays [`FUNDING_ADDRESS`] in its first output
+        pub(crate) fn script_fundchannel(&self, capacity_sat: u64) -> &Self {
+            self.respond(
+                "fundchannel_start",
+                json!({ "funding_address" : FUNDING_ADDRESS }),
+            )
+            .respond(
+                "txprepare",
+                json!({
+                    "psbt" : prepared_psbt(&[(FUNDING_ADDRESS, capacity_sat)]),
+                    "txid" : "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70",
+                    "unsigned_tx" : "0200000000000000000000",
+                }),
+            )
+            .respond(
+                "fundchannel_complete",
+                json!({
+                    "channel_id" : "00".repeat(32),
+                    "commitments_secured" : true,
+                }),
+            )
+            .respond(
+                "txsend",
+                json!({
+                    "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+                    "tx" : "0200000000000000000000",
+                    "txid" : "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70",
+                }),
+            )
+        }
+
+        fn call<P: Serialize, R: DeserializeOwned>(
+            &self,
+            method: &'static str,
+            params: &P,
+        ) -> Result<R> {
+            let mut state = self.state.lock().unwrap();
+            state.calls.push((method, serde_json::to_value(params)?));
+            let response = state
+                .responses
+                .get_mut(method)
+                .and_then(|queue| queue.pop_front())
+                .with_context(|| format!("No scripted response for '{}'", method))?;
+
+            let response = match response {
+                Scripted::Response(response) => response,
+                Scripted::Error(message) => return Err(anyhow!("{}", message)),
+                Scripted::Disconnected => {
+                    return Err(anyhow::Error::new(RpcError {
+                        code: None,
+                        message: "Error reading response: Connection reset by peer".to_string(),
+                        data: None,
+                    }))
+                }
+            };
+            serde_json::from_value(response)
+                .with_context(|| format!("Invalid scripted response for '{}'", method))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ClnRpcApi for FakeClnRpc {
+        async fn getinfo(&mut self, request: &GetinfoRequest) -> Result<GetinfoResponse> {
+            self.call("getinfo", request)
+        }
+
+        async fn getchaininfo(
+            &mut self,
+            request: &GetchaininfoRequest,
+        ) -> Result<GetchaininfoResponse> {
+            self.call("getchaininfo", request)
+        }
+
+        async fn invoice(&mut self, request: &InvoiceRequest) -> Result<InvoiceResponse> {
+            self.call("invoice", request)
+        }
+
+        async fn delinvoice(&mut self, request: &DelinvoiceRequest) -> Result<DelinvoiceResponse> {
+            self.call("delinvoice", request)
+        }
+
+        async fn listpeers(&mut self, request: &ListpeersRequest) -> Result<ListpeersResponse> {
+            self.call("listpeers", request)
+        }
+
+        async fn listnodes(&mut self, request: &ListnodesRequest) -> Result<ListnodesResponse> {
+            self.call("listnodes", request)
+        }
+
+        async fn listpeerchannels(
+            &mut self,
+            request: &ListpeerchannelsRequest,
+        ) -> Result<ListpeerchannelsResponse> {
+            self.call("listpeerchannels", request)
+        }
+
+        async fn listforwards(
+            &mut self,
+            request: &ListforwardsRequest,
+        ) -> Result<ListforwardsResponse> {
+            self.call("listforwards", request)
+        }
+
+        async fn feerates(&mut self, request: &FeeratesRequest) -> Result<FeeratesResponse> {
+            self.call("feerates", request)
+        }
+
+        async fn listfunds(&mut self, request: &ListfundsRequest) -> Result<ListfundsResponse> {
+            self.call("listfunds", request)
+        }
+
+        async fn fundchannel_start(
+            &mut self,
+            request: &FundChannelStartRequest,
+        ) -> Result<FundChannelStartResponse> {
+            self.call("fundchannel_start", request)
+        }
+
+        async fn fundchannel_complete(
+            &mut self,
+            request: &FundChannelCompleteRequest,
+        ) -> Result<FundChannelCompleteResponse> {
+            self.call("fundchannel_complete", request)
+        }
+
+        async fn fundchannel_cancel(
+            &mut self,
+            request: &FundChannelCancelRequest,
+        ) -> Result<FundChannelCancelResponse> {
+            self.call("fundchannel_cancel", request)
+        }
+
+        async fn txprepare(&mut self, request: &TxprepareRequest) -> Result<TxprepareResponse> {
+            self.call("txprepare", request)
+        }
+
+        async fn txsend(&mut self, request: &TxsendRequest) -> Result<TxsendResponse> {
+            self.call("txsend", request)
+        }
+
+        async fn txdiscard(&mut self, request: &TxdiscardRequest) -> Result<TxdiscardResponse> {
+            self.call("txdiscard", request)
+        }
+
+        async fn fundpsbt(&mut self, request: &FundpsbtRequest) -> Result<FundpsbtResponse> {
+            self.call("fundpsbt", request)
+        }
+
+        async fn utxopsbt(&mut self, request: &UtxopsbtRequest) -> Result<FundpsbtResponse> {
+            self.call("utxopsbt", request)
+        }
+
+        async fn unreserveinputs(
+            &mut self,
+            request: &UnreserveinputsRequest,
+        ) -> Result<UnreserveinputsResponse> {
+            self.call("unreserveinputs", request)
+        }
+
+        async fn sendcustommsg(
+            &mut self,
+            request: &SendcustommsgRequest,
+        ) -> Result<SendcustommsgResponse> {
+            self.call("sendcustommsg", request)
+        }
+
+        async fn withdraw(&mut self, request: &WithdrawRequest) -> Result<WithdrawResponse> {
+            self.call("withdraw", request)
+        }
+
+        async fn signmessage(
+            &mut self,
+            request: &SignmessageRequest,
+        ) -> Result<SignmessageResponse> {
+            self.call("signmessage", request)
+        }
+
+        async fn listconfigs(
+            &mut self,
+            request: &ListconfigsRequest,
+        ) -> Result<ListconfigsResponse> {
+            self.call("listconfigs", request)
+        }
+    }
+
+    /// Every connection shares the script of the fake
+    #[async_trait::async_trait]
+    impl RpcConnector for FakeClnRpc {
+        async fn connect(&self) -> Result<Box<dyn ClnRpcApi>> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+}
@@ -7,6 +7,7 @@ use cln_rpc::TypedRequest;
 /// I'll delete this file once a sustainable approach is available in core lightning.
 // TODO:    Remove this file once a sustainable solution is available in cln
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FundChannelStartRequest {
@@ -24,6 +25,9 @@ pub struct FundChannelStartRequest {
     pub mindepth: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reserve: Option<rpc_primitives::Amount>,
+    /// The feature bits of the channel type. E.g: `[12, 22]` for anchors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_type: Option<Vec<u32>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -78,3 +82,139 @@ impl TypedRequest for FundChannelCancelRequest {
         "fundchannel_cancel"
     }
 }
+
+/// `getchaininfo` is provided by the bitcoin backend plugin
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetchaininfoRequest {
+    pub last_height: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetchaininfoResponse {
+    pub blockcount: u32,
+}
+
+impl TypedRequest for GetchaininfoRequest {
+    type Response = GetchaininfoResponse;
+
+    fn method(&self) -> &str {
+        "getchaininfo"
+    }
+}
+
+/// `fundpsbt` selects wallet inputs that fund `satoshi` and reserves them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FundpsbtRequest {
+    pub satoshi: rpc_primitives::Amount,
+    pub feerate: rpc_primitives::Feerate,
+    /// The weight of the transaction without its inputs
+    pub startweight: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minconf: Option<u32>,
+    /// The number of blocks the inputs are reserved for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FundpsbtResponse {
+    pub psbt: String,
+    pub feerate_per_kw: u32,
+    pub estimated_final_weight: u32,
+    #[serde(default)]
+    pub reservations: Vec<InputReservation>,
+}
+
+impl TypedRequest for FundpsbtRequest {
+    type Response = FundpsbtResponse;
+
+    fn method(&self) -> &str {
+        "fundpsbt"
+    }
+}
+
+/// `utxopsbt` funds `satoshi` using exactly the given `utxos` and reserves them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxopsbtRequest {
+    pub satoshi: rpc_primitives::Amount,
+    pub feerate: rpc_primitives::Feerate,
+    /// The weight of the transaction without its inputs
+    pub startweight: u32,
+    /// The inputs formatted as `txid:vout`
+    pub utxos: Vec<String>,
+    /// The number of blocks the inputs are reserved for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve: Option<u32>,
+}
+
+impl TypedRequest for UtxopsbtRequest {
+    type Response = FundpsbtResponse;
+
+    fn method(&self) -> &str {
+        "utxopsbt"
+    }
+}
+
+/// Releases the reservation of the inputs of `psbt`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreserveinputsRequest {
+    pub psbt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreserveinputsResponse {
+    #[serde(default)]
+    pub reservations: Vec<InputReservation>,
+}
+
+impl TypedRequest for UnreserveinputsRequest {
+    type Response = UnreserveinputsResponse;
+
+    fn method(&self) -> &str {
+        "unreserveinputs"
+    }
+}
+
+/// The reservation of a wallet input as reported by lightningd
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputReservation {
+    pub txid: String,
+    pub vout: u32,
+    pub was_reserved: bool,
+    pub reserved: bool,
+    pub reserved_to_block: Option<u32>,
+}
+
+/// The `listconfigs` of cln-rpc doesn't include the options of plugins
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ListconfigsRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListconfigsResponse {
+    pub configs: HashMap<String, ListconfigsConfig>,
+}
+
+/// The value of an option. Only the field that matches its type is set
+///
+/// A flag that is set is reported as `set`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ListconfigsConfig {
+    pub value_str: Option<String>,
+    pub value_int: Option<i64>,
+    pub value_bool: Option<bool>,
+    pub set: Option<bool>,
+    pub source: Option<String>,
+}
+
+impl TypedRequest for ListconfigsRequest {
+    type Response = ListconfigsResponse;
+
+    fn method(&self) -> &str {
+        "listconfigs"
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Instant;
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+/// A source of time
+///
+/// Code that depends on the current time should use the [`Clock`]
+/// in the `PluginState` rather than calling `IsoDatetime::now()`.
+/// This allows tests to control time using a `MockClock`.
+pub(crate) trait Clock: Send + Sync {
+    /// The current wall-clock time
+    fn now(&self) -> IsoDatetime;
+
+    /// A monotonic time that can be used to measure durations
+    fn instant(&self) -> Instant;
+}
+
+/// Uses the time of the system
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> IsoDatetime {
+        IsoDatetime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// A clock that only moves when it is advanced manually
+    pub(crate) struct MockClock {
+        time: Mutex<(IsoDatetime, Instant)>,
+    }
+
+    impl MockClock {
+        pub(crate) fn new(now: IsoDatetime) -> Self {
+            Self {
+                time: Mutex::new((now, Instant::now())),
+            }
+        }
+
+        pub(crate) fn advance(&self, duration: Duration) {
+            let mut time = self.time.lock().unwrap();
+            let datetime = time.0.datetime + duration;
+            time.0 = IsoDatetime::from_primitive_date_time(datetime);
+            time.1 += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> IsoDatetime {
+            self.time.lock().unwrap().0
+        }
+
+        fn instant(&self) -> Instant {
+            self.time.lock().unwrap().1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::MockClock;
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let start = IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap();
+        let clock = MockClock::new(start);
+        let start_instant = clock.instant();
+
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.instant(), start_instant);
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(clock.now().unix_timestamp(), 1_700_003_600);
+        assert_eq!(clock.instant() - start_instant, Duration::from_secs(3600));
+    }
+}
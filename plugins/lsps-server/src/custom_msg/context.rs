@@ -1,18 +1,22 @@
-use anyhow::{Context, Result};
+use std::sync::Arc;
 
-use cln_plugin::Plugin;
-use cln_rpc::ClnRpc;
+use anyhow::{Context, Result};
 
 use lsp_primitives::json_rpc::JsonRpcRequest;
-use lsp_primitives::lsps0::common_schemas::{Network, PublicKey};
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+use crate::cln::rpc_api::{ClnRpcApi, RpcConnector};
+use crate::custom_msg::router::HandlerOptions;
 
 pub struct CustomMsgContext<PluginState>
 where
     PluginState: Send + Clone,
 {
-    pub network: Network,
-    pub plugin: Plugin<PluginState>,
-    pub cln_rpc: ClnRpc,
+    pub state: PluginState,
+    pub(crate) options: HandlerOptions,
+    /// Opens additional connections to lightningd
+    pub connector: Arc<dyn RpcConnector>,
+    pub cln_rpc: Box<dyn ClnRpcApi>,
     pub peer_id: PublicKey,
     pub request: JsonRpcRequest<serde_json::Value>,
     pub(crate) _private: (),
@@ -22,9 +26,10 @@ pub struct CustomMsgContextBuilder<PluginState>
 where
     PluginState: Send + Clone,
 {
-    network: Option<Network>,
-    plugin: Option<Plugin<PluginState>>,
-    cln_rpc: Option<ClnRpc>,
+    state: Option<PluginState>,
+    options: Option<HandlerOptions>,
+    connector: Option<Arc<dyn RpcConnector>>,
+    cln_rpc: Option<Box<dyn ClnRpcApi>>,
     peer_id: Option<PublicKey>,
     request: Option<JsonRpcRequest<serde_json::Value>>,
 }
@@ -35,25 +40,31 @@ where
 {
     pub fn new() -> Self {
         Self {
-            network: None,
-            plugin: None,
+            state: None,
+            options: None,
+            connector: None,
             cln_rpc: None,
             peer_id: None,
             request: None,
         }
     }
 
-    pub fn network(mut self, network: Network) -> Self {
-        self.network = Some(network);
+    pub fn state(mut self, state: PluginState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub(crate) fn options(mut self, options: HandlerOptions) -> Self {
+        self.options = Some(options);
         self
     }
 
-    pub fn plugin(mut self, plugin: Plugin<PluginState>) -> Self {
-        self.plugin = Some(plugin);
+    pub fn connector(mut self, connector: Arc<dyn RpcConnector>) -> Self {
+        self.connector = Some(connector);
         self
     }
 
-    pub fn cln_rpc(mut self, cln_rpc: ClnRpc) -> Self {
+    pub fn cln_rpc(mut self, cln_rpc: Box<dyn ClnRpcApi>) -> Self {
         self.cln_rpc = Some(cln_rpc);
         self
     }
@@ -69,15 +80,17 @@ where
     }
 
     pub fn build(self) -> Result<CustomMsgContext<PluginState>> {
-        let network = self.network.context("Missing value for 'network'")?;
-        let plugin = self.plugin.context("Missing value for 'plugin'")?;
+        let state = self.state.context("Missing value for 'state'")?;
+        let options = self.options.context("Missing value for 'options'")?;
+        let connector = self.connector.context("Missing value for 'connector'")?;
         let cln_rpc = self.cln_rpc.context("Missing value for 'cln_rpc'")?;
         let peer_id = self.peer_id.context("Missing value for 'peer_id'")?;
         let request = self.request.context("Missing value for 'request'")?;
 
         Ok(CustomMsgContext {
-            network,
-            plugin,
+            state,
+            options,
+            connector,
             cln_rpc,
             peer_id,
             request,
@@ -0,0 +1,220 @@
+use serde_json::json;
+use uuid::Uuid;
+
+use lsp_primitives::json_rpc::error::codes;
+use lsp_primitives::json_rpc::{ErrorData, LspsErrorReason};
+use lsp_primitives::lsps0::parameter_validation::ParamValidationError;
+use lsp_primitives::lsps1::util::Lsps1OptionMismatchError;
+
+use crate::db::sqlite::quarantine::{CorruptRow, Quarantine};
+use crate::lsps1::fee_calc::DomainError;
+
+/// The error returned by a handler of a request from a peer
+///
+/// A [`HandlerError::Client`]-error is sent to the peer as is. The details
+/// of a [`HandlerError::Internal`]-error are only logged. The peer gets an
+/// `incident_id` which appears next to the details in our logs.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The request can't be served. E.g: the params are invalid
+    Client(ErrorData),
+    /// Something went wrong on our side. E.g: a database query failed
+    Internal(anyhow::Error),
+}
+
+impl HandlerError {
+    pub fn internal(err: impl Into<anyhow::Error>) -> Self {
+        Self::Internal(err.into())
+    }
+
+    /// Quarantines the row if the error is a [`CorruptRow`]
+    ///
+    /// The peer can't fix the row by changing the request. It gets a
+    /// `temporarily_unavailable` instead of an incident id. Other
+    /// errors are returned unchanged.
+    pub(crate) fn quarantine_corrupt_row(self, quarantine: &Quarantine) -> Self {
+        match self {
+            Self::Internal(err) => match err.downcast_ref::<CorruptRow>() {
+                Some(row) => {
+                    quarantine.record(std::slice::from_ref(row));
+                    Self::Client(ErrorData::temporarily_unavailable())
+                }
+                None => Self::Internal(err),
+            },
+            error => error,
+        }
+    }
+
+    /// The `reason` that is sent to the peer
+    pub fn reason(&self) -> Option<LspsErrorReason> {
+        match self {
+            Self::Client(error) => error.reason(),
+            Self::Internal(_) => Some(LspsErrorReason::Internal),
+        }
+    }
+
+    /// Converts the error into the error that is sent to the peer
+    ///
+    /// An internal error is logged together with a new incident id.
+    /// Only the incident id is sent to the peer.
+    pub fn into_error_data(self) -> ErrorData {
+        match self {
+            Self::Client(error) => error,
+            Self::Internal(err) => {
+                let incident_id = Uuid::new_v4();
+                log::error!("Internal error (incident_id={}): {:?}", incident_id, err);
+                ErrorData::new(
+                    codes::INTERNAL_ERROR_CODE,
+                    codes::INTERNAL_ERROR_MSG,
+                    Some(json!({ "incident_id" : incident_id.to_string() })),
+                )
+                .with_reason(LspsErrorReason::Internal)
+            }
+        }
+    }
+}
+
+impl From<ErrorData> for HandlerError {
+    fn from(error: ErrorData) -> Self {
+        Self::Client(error)
+    }
+}
+
+impl From<ParamValidationError> for HandlerError {
+    fn from(error: ParamValidationError) -> Self {
+        Self::Client(error.into())
+    }
+}
+
+impl From<Lsps1OptionMismatchError> for HandlerError {
+    fn from(error: Lsps1OptionMismatchError) -> Self {
+        Self::Client(error.into())
+    }
+}
+
+/// The client sees the same `option_mismatch` as for an order that
+/// doesn't satisfy the options
+impl From<DomainError> for HandlerError {
+    fn from(error: DomainError) -> Self {
+        Self::Client(ErrorData::option_mismatch(json!({
+            "property" : error.property,
+            "message" : error.message,
+        })))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::sync::{Mutex, OnceLock};
+
+    use log::{LevelFilter, Log, Metadata, Record};
+
+    /// A logger that keeps all messages in memory
+    #[derive(Default)]
+    pub(crate) struct CapturedLogs {
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl CapturedLogs {
+        /// Returns all messages that contain `needle`
+        pub(crate) fn containing(&self, needle: &str) -> Vec<String> {
+            let messages = self.messages.lock().unwrap();
+            messages
+                .iter()
+                .filter(|m| m.contains(needle))
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl Log for CapturedLogs {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let message = format!("{} {}", record.level(), record.args());
+            self.messages.lock().unwrap().push(message);
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Installs the [`CapturedLogs`] as logger of the test binary
+    ///
+    /// Tests run in parallel, so the logs contain the messages of all tests
+    pub(crate) fn capture_logs() -> &'static CapturedLogs {
+        static LOGS: OnceLock<CapturedLogs> = OnceLock::new();
+        let mut installed = false;
+        let logs = LOGS.get_or_init(|| {
+            installed = true;
+            CapturedLogs::default()
+        });
+        if installed {
+            log::set_logger(logs).expect("Another logger is installed");
+            log::set_max_level(LevelFilter::Debug);
+        }
+        logs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::capture_logs;
+    use super::*;
+
+    #[test]
+    fn client_error_is_sent_as_is() {
+        let error = HandlerError::from(ErrorData::not_found());
+        assert_eq!(error.reason(), Some(LspsErrorReason::NotFound));
+
+        let error = error.into_error_data();
+        assert_eq!(error.code, codes::NOT_FOUND_CODE);
+    }
+
+    #[test]
+    fn internal_error_only_sends_incident_id() {
+        let logs = capture_logs();
+        let error = HandlerError::internal(anyhow::anyhow!("secret detail"));
+        assert_eq!(error.reason(), Some(LspsErrorReason::Internal));
+
+        let error = error.into_error_data();
+        assert_eq!(error.code, codes::INTERNAL_ERROR_CODE);
+        assert_eq!(error.reason(), Some(LspsErrorReason::Internal));
+
+        let data = error.data.unwrap();
+        let incident_id = data["incident_id"].as_str().unwrap();
+        assert!(!data.to_string().contains("secret detail"));
+
+        let logged = logs.containing(incident_id);
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("ERROR"));
+        assert!(logged[0].contains("secret detail"));
+    }
+
+    #[test]
+    fn corrupt_row_is_quarantined() {
+        let quarantine = Quarantine::default();
+        let row = CorruptRow {
+            table: "lsps1_order",
+            rowid: 7,
+            error: "Stored value -1 is not a valid IsoDatetime".to_string(),
+        };
+        let err = anyhow::Error::new(row.clone()).context("Failed to get order");
+
+        let error = HandlerError::internal(err).quarantine_corrupt_row(&quarantine);
+        assert_eq!(
+            error.reason(),
+            Some(LspsErrorReason::TemporarilyUnavailable)
+        );
+        assert_eq!(quarantine.list(), vec![row]);
+
+        let error = HandlerError::internal(anyhow::anyhow!("Database is locked"))
+            .quarantine_corrupt_row(&quarantine);
+        assert_eq!(error.reason(), Some(LspsErrorReason::Internal));
+
+        let error = HandlerError::from(ErrorData::not_found()).quarantine_corrupt_row(&quarantine);
+        assert_eq!(error.reason(), Some(LspsErrorReason::NotFound));
+        assert_eq!(quarantine.list().len(), 1);
+    }
+}
@@ -1,2 +1,6 @@
 pub mod context;
+pub mod error;
+pub mod outbox;
+pub mod peer_queue;
+pub mod router;
 pub mod util;
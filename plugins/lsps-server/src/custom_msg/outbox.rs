@@ -0,0 +1,293 @@
+//! Retries responses that failed to reach a peer
+//!
+//! `sendcustommsg` fails if the peer disconnected before we could
+//! respond. Such a response is stored in the outbox and sent again when
+//! the peer reconnects or when the outbox is retried periodically.
+//! Responses that can't be delivered within the TTL are dropped.
+//!
+//! A response that is retried by two tasks at the same time might be
+//! delivered twice. A client ignores responses to requests it isn't
+//! waiting for.
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use lsp_primitives::json_rpc::JsonRpcResponse;
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, PublicKey};
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::custom_msg::util::send_payload;
+use crate::db::sqlite::queries::{
+    CreateOutboxEntryQuery, DeleteExpiredOutboxEntriesQuery, DeleteOutboxEntryQuery,
+    GetOutboxEntriesQuery, RecordOutboxAttemptQuery,
+};
+use crate::state::PluginState;
+
+/// How often we retry the responses in the outbox
+pub(crate) const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a response is retried unless the operator configured otherwise
+pub(crate) const DEFAULT_RESPONSE_RETRY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Sends a response to the peer
+///
+/// If sending fails the response is stored in the outbox. An error is
+/// only returned if the response couldn't be stored either.
+pub(crate) async fn send_or_enqueue<O, E>(
+    state: &PluginState,
+    cln_rpc: &mut dyn ClnRpcApi,
+    peer_id: PublicKey,
+    response: JsonRpcResponse<O, E>,
+) -> Result<()>
+where
+    O: Serialize,
+    E: Serialize,
+{
+    let payload = serde_json::to_string(&response)?;
+    send_or_enqueue_payload(state, cln_rpc, peer_id, &payload).await
+}
+
+/// Sends a serialized response to the peer
+///
+/// See [`send_or_enqueue`]
+pub(crate) async fn send_or_enqueue_payload(
+    state: &PluginState,
+    cln_rpc: &mut dyn ClnRpcApi,
+    peer_id: PublicKey,
+    payload: &str,
+) -> Result<()> {
+    let err = match send_payload(cln_rpc, peer_id, payload.as_bytes()).await {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+
+    let mut tx = state.database.begin().await?;
+    let id = CreateOutboxEntryQuery {
+        peer_id,
+        payload,
+        created_at: state.clock.now(),
+    }
+    .execute(&mut tx)
+    .await
+    .context("Failed to store response in the outbox")?;
+    tx.commit().await?;
+
+    log::info!(
+        "Failed to send response to peer {:?}. Stored it as outbox entry {}: {:?}",
+        peer_id,
+        id,
+        err
+    );
+    Ok(())
+}
+
+/// Sends the responses in the outbox
+///
+/// Responses that are older than the TTL are dropped first. If `peer_id`
+/// is set only the responses to that peer are sent.
+///
+/// Returns the number of responses that were delivered
+pub(crate) async fn deliver_outbox(
+    state: &PluginState,
+    cln_rpc: &mut dyn ClnRpcApi,
+    peer_id: Option<PublicKey>,
+) -> Result<usize> {
+    let now = state.clock.now();
+    let ttl = i64::try_from(state.response_retry_ttl.as_secs())?;
+    let created_before = IsoDatetime::from_unix_timestamp(now.unix_timestamp() - ttl)?;
+
+    let mut tx = state.database.begin().await?;
+    let dropped = DeleteExpiredOutboxEntriesQuery { created_before }
+        .execute(&mut tx)
+        .await?;
+    let query = match peer_id {
+        Some(peer_id) => GetOutboxEntriesQuery::by_peer_id(peer_id),
+        None => GetOutboxEntriesQuery::all(),
+    };
+    let entries = query.execute(&mut tx).await?;
+    tx.commit().await?;
+
+    if dropped > 0 {
+        log::warn!(
+            "Dropped {} responses that couldn't be delivered within {:?}",
+            dropped,
+            state.response_retry_ttl
+        );
+    }
+
+    let mut delivered = 0;
+    for entry in entries {
+        let result = send_payload(cln_rpc, entry.peer_id, entry.payload.as_bytes()).await;
+
+        let mut tx = state.database.begin().await?;
+        match result {
+            Ok(()) => {
+                DeleteOutboxEntryQuery { id: entry.id }
+                    .execute(&mut tx)
+                    .await?;
+                delivered += 1;
+            }
+            Err(err) => {
+                log::debug!(
+                    "Failed to deliver outbox entry {} after {} attempts: {:?}",
+                    entry.id,
+                    entry.attempts + 1,
+                    err
+                );
+                RecordOutboxAttemptQuery { id: entry.id }
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+    }
+    Ok(delivered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use lsp_primitives::hex;
+    use lsp_primitives::json_rpc::{DefaultError, JsonRpcId};
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+    use crate::db::schema::Lsps0OutboxEntry;
+    use crate::db::sqlite::test::get_temp_db;
+    use crate::state::test_support::test_state;
+
+    const PEER_ID: &str = "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170";
+
+    fn peer_id() -> PublicKey {
+        PublicKey::from_hex(PEER_ID).unwrap()
+    }
+
+    fn response() -> JsonRpcResponse<serde_json::Value, DefaultError> {
+        JsonRpcResponse::success(JsonRpcId::String("abc".to_string()), json!({}))
+    }
+
+    fn sent() -> serde_json::Value {
+        json!({ "status" : "Message sent to connectd for delivery" })
+    }
+
+    async fn get_entries(state: &PluginState) -> Vec<Lsps0OutboxEntry> {
+        let mut tx = state.database.begin().await.unwrap();
+        let entries = GetOutboxEntriesQuery::all().execute(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        entries
+    }
+
+    #[tokio::test]
+    async fn sent_response_bypasses_outbox() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("sendcustommsg", sent());
+
+        send_or_enqueue(&state, &mut rpc, peer_id(), response())
+            .await
+            .unwrap();
+        assert!(get_entries(&state).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_response_is_delivered_after_reconnect() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+
+        // The peer disconnects before we respond
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("sendcustommsg", "Peer is not connected");
+        send_or_enqueue(&state, &mut rpc, peer_id(), response())
+            .await
+            .unwrap();
+
+        let entries = get_entries(&state).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].peer_id, peer_id());
+        assert_eq!(entries[0].attempts, 1);
+
+        // The periodic retry fails while the peer is offline
+        rpc.fail("sendcustommsg", "Peer is not connected");
+        let delivered = deliver_outbox(&state, &mut rpc, None).await.unwrap();
+        assert_eq!(delivered, 0);
+        assert_eq!(get_entries(&state).await[0].attempts, 2);
+
+        // The peer reconnects
+        rpc.respond("sendcustommsg", sent());
+        let delivered = deliver_outbox(&state, &mut rpc, Some(peer_id()))
+            .await
+            .unwrap();
+        assert_eq!(delivered, 1);
+        assert!(get_entries(&state).await.is_empty());
+
+        // The stored payload is sent as is
+        let payload = serde_json::to_vec(&response()).unwrap();
+        let params = rpc.params_of("sendcustommsg").unwrap();
+        assert!(params["msg"]
+            .as_str()
+            .unwrap()
+            .ends_with(&hex::encode(payload)));
+    }
+
+    #[tokio::test]
+    async fn reconnect_only_delivers_to_that_peer() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("sendcustommsg", "Peer is not connected");
+        send_or_enqueue(&state, &mut rpc, peer_id(), response())
+            .await
+            .unwrap();
+
+        let other_peer = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let delivered = deliver_outbox(&state, &mut rpc, Some(other_peer))
+            .await
+            .unwrap();
+        assert_eq!(delivered, 0);
+        assert_eq!(rpc.called_methods(), vec!["sendcustommsg"]);
+        assert_eq!(get_entries(&state).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn response_is_dropped_after_ttl() {
+        let (db, _) = get_temp_db().await;
+        let mut state = test_state(db);
+        let clock = Arc::new(MockClock::new(IsoDatetime::now()));
+        state.clock = clock.clone();
+        state.response_retry_ttl = Duration::from_secs(10 * 60);
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("sendcustommsg", "Peer is not connected");
+        send_or_enqueue(&state, &mut rpc, peer_id(), response())
+            .await
+            .unwrap();
+
+        // Within the TTL the response is retried
+        clock.advance(Duration::from_secs(9 * 60));
+        rpc.fail("sendcustommsg", "Peer is not connected");
+        deliver_outbox(&state, &mut rpc, None).await.unwrap();
+        assert_eq!(get_entries(&state).await.len(), 1);
+
+        // Afterwards it is dropped without sending it
+        clock.advance(Duration::from_secs(2 * 60));
+        rpc.respond("sendcustommsg", sent());
+        let delivered = deliver_outbox(&state, &mut rpc, Some(peer_id()))
+            .await
+            .unwrap();
+        assert_eq!(delivered, 0);
+        assert!(get_entries(&state).await.is_empty());
+        assert_eq!(rpc.called_methods().len(), 2);
+    }
+}
@@ -0,0 +1,244 @@
+//! Handles the requests of a peer in the order they arrived
+//!
+//! lightningd can call the `custommsg`-hook concurrently. A peer that
+//! sends `lsps1.get_info` immediately followed by `lsps1.create_order`
+//! could see them handled in the opposite order. If
+//! `lsps0-per-peer-queue-depth` is set every peer gets a queue that is
+//! processed by its own worker. Requests of different peers are still
+//! handled in parallel.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+/// Peers whose queue is full are asked to retry after this long
+pub(crate) const PEER_QUEUE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// The handling of a single request
+pub(crate) type PeerJob = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// The queue of the peer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Too many requests of the peer are waiting")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// A bounded FIFO-queue of requests per peer
+///
+/// A peer has a queue while one of its requests is being handled. The
+/// worker of the queue stops once the queue is empty.
+pub(crate) struct PeerQueues {
+    /// The number of requests of a peer that can wait
+    depth: usize,
+    // The requests that wait while another request of the peer is handled
+    queues: Mutex<HashMap<PublicKey, VecDeque<PeerJob>>>,
+    rejected: AtomicU64,
+}
+
+impl PeerQueues {
+    pub(crate) fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            queues: Mutex::new(HashMap::new()),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Handles `job` after all jobs that were submitted before for `peer_id`
+    ///
+    /// The job is started right away if the peer has no other job.
+    /// Fails if `depth` jobs of the peer are waiting already.
+    pub(crate) fn submit(
+        self: &Arc<Self>,
+        peer_id: PublicKey,
+        job: PeerJob,
+    ) -> Result<(), QueueFull> {
+        let mut queues = self.queues.lock().unwrap();
+        match queues.get_mut(&peer_id) {
+            Some(queue) if queue.len() >= self.depth => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(QueueFull)
+            }
+            Some(queue) => {
+                queue.push_back(job);
+                Ok(())
+            }
+            None => {
+                queues.insert(peer_id, VecDeque::new());
+                tokio::spawn(self.clone().work(peer_id, job));
+                Ok(())
+            }
+        }
+    }
+
+    async fn work(self: Arc<Self>, peer_id: PublicKey, mut job: PeerJob) {
+        loop {
+            // A panic must not stop the other requests of the peer
+            if let Err(err) = tokio::spawn(job).await {
+                log::error!(
+                    "Handling a request of {} panicked: {}",
+                    peer_id.to_hex(),
+                    err
+                );
+            }
+
+            let next = {
+                let mut queues = self.queues.lock().unwrap();
+                let next = queues.get_mut(&peer_id).and_then(|queue| queue.pop_front());
+                if next.is_none() {
+                    queues.remove(&peer_id);
+                }
+                next
+            };
+            match next {
+                Some(next) => job = next,
+                None => return,
+            }
+        }
+    }
+
+    /// The number of requests that are waiting per peer
+    pub(crate) fn queue_depths(&self) -> HashMap<PublicKey, usize> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer_id, queue)| (*peer_id, queue.len()))
+            .collect()
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let depths = self.queue_depths();
+        let peers: serde_json::Map<String, serde_json::Value> = depths
+            .iter()
+            .map(|(peer_id, depth)| (peer_id.to_hex(), json!(depth)))
+            .collect();
+        json!({
+            "max_depth" : self.depth,
+            "waiting" : depths.values().sum::<usize>(),
+            "peers" : peers,
+            "rejected" : self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::sync::{mpsc, oneshot};
+
+    fn peer(index: u8) -> PublicKey {
+        let keys = [
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        ];
+        PublicKey::from_hex(keys[usize::from(index)]).unwrap()
+    }
+
+    /// A job that sleeps for `delay` and reports `id` when it is done
+    fn job(id: u8, delay_ms: u64, done: &mpsc::UnboundedSender<u8>) -> PeerJob {
+        let done = done.clone();
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            done.send(id).unwrap();
+        })
+    }
+
+    #[tokio::test]
+    async fn requests_of_a_peer_are_handled_in_arrival_order() {
+        let queues = Arc::new(PeerQueues::new(10));
+        let (done, mut finished) = mpsc::unbounded_channel();
+
+        // Later requests are faster. They still wait for the earlier ones
+        for id in 0..5 {
+            queues
+                .submit(peer(0), job(id, 50 - 10 * u64::from(id), &done))
+                .unwrap();
+        }
+        // Requests of other peers don't wait
+        queues.submit(peer(1), job(100, 0, &done)).unwrap();
+
+        let mut order = vec![];
+        for _ in 0..6 {
+            order.push(finished.recv().await.unwrap());
+        }
+        assert_eq!(order, vec![100, 0, 1, 2, 3, 4]);
+
+        // The queues are removed once they are empty
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(queues.queue_depths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn full_queue_rejects_requests() {
+        let queues = Arc::new(PeerQueues::new(2));
+        let (done, mut finished) = mpsc::unbounded_channel();
+
+        // The first request blocks the queue until it is released
+        let (release, released) = oneshot::channel::<()>();
+        let blocking_done = done.clone();
+        queues
+            .submit(
+                peer(0),
+                Box::pin(async move {
+                    released.await.unwrap();
+                    blocking_done.send(0).unwrap();
+                }),
+            )
+            .unwrap();
+        queues.submit(peer(0), job(1, 0, &done)).unwrap();
+        queues.submit(peer(0), job(2, 0, &done)).unwrap();
+        assert_eq!(queues.submit(peer(0), job(3, 0, &done)), Err(QueueFull));
+
+        // Other peers have their own queue
+        queues.submit(peer(1), job(10, 0, &done)).unwrap();
+        assert_eq!(finished.recv().await, Some(10));
+
+        let metrics = queues.to_json();
+        assert_eq!(metrics["max_depth"], 2);
+        assert_eq!(metrics["waiting"], 2);
+        assert_eq!(metrics["peers"][peer(0).to_hex()], 2);
+        assert_eq!(metrics["rejected"], 1);
+
+        release.send(()).unwrap();
+        for expected in 0..3 {
+            assert_eq!(finished.recv().await, Some(expected));
+        }
+
+        // The peer can send requests again
+        queues.submit(peer(0), job(4, 0, &done)).unwrap();
+        assert_eq!(finished.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn panicking_request_does_not_stop_the_queue() {
+        let queues = Arc::new(PeerQueues::new(2));
+        let (done, mut finished) = mpsc::unbounded_channel();
+
+        queues
+            .submit(
+                peer(0),
+                Box::pin(async {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    panic!("Handler failed");
+                }),
+            )
+            .unwrap();
+        queues.submit(peer(0), job(1, 0, &done)).unwrap();
+        assert_eq!(finished.recv().await, Some(1));
+    }
+}
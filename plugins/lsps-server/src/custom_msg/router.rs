@@ -0,0 +1,540 @@
+//! Answers the LSPS-messages of peers
+//!
+//! The [`Router`] parses a message, dispatches it to the handler of its
+//! method and serializes the responses. It doesn't send anything. The
+//! caller sends the returned [`OutboundMessage`]s using `sendcustommsg`.
+//!
+//! The responses to canonical requests are pinned by the files in
+//! `tests/fixtures/router`. Run the tests with `UPDATE_GOLDEN_FILES=1`
+//! to write the responses of the current implementation.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Result;
+use cln_plugin::Plugin;
+use serde::Serialize;
+
+use lsp_primitives::json_rpc::{
+    DefaultError, ErrorData, JsonRpcId, JsonRpcMessageKind, JsonRpcRequest, JsonRpcResponse,
+};
+use lsp_primitives::lsps0::common_schemas::{Network, PublicKey};
+use lsp_primitives::lsps0::schema::ListprotocolsResponse;
+use lsp_primitives::methods;
+use lsp_primitives::methods::JsonRpcMethodEnum;
+
+use crate::cln::rpc_api::{RpcConnector, RpcFileConnector};
+use crate::custom_msg::context::{CustomMsgContext, CustomMsgContextBuilder};
+use crate::custom_msg::error::HandlerError;
+use crate::custom_msg::peer_queue::PEER_QUEUE_RETRY_AFTER;
+use crate::lsps1::hooks::{
+    do_lsps1_create_order, do_lsps1_get_info, do_lsps1_get_order, CreateOrderSettings,
+};
+use crate::network::parse_network;
+use crate::options;
+use crate::state::PluginState;
+
+/// A serialized JSON-RPC message for a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OutboundMessage {
+    pub(crate) peer_id: PublicKey,
+    pub(crate) payload: String,
+}
+
+/// The plugin options that are used to answer requests
+#[derive(Debug, Clone)]
+pub(crate) struct HandlerOptions {
+    /// The network of lightningd
+    pub(crate) network: Network,
+    /// The value of `lsps1-enable`
+    pub(crate) lsps1_enabled: bool,
+    /// The value of `lsps1-hide-info-when-unhealthy`
+    pub(crate) hide_info_when_unhealthy: bool,
+    pub(crate) create_order: CreateOrderSettings,
+}
+
+impl HandlerOptions {
+    /// Reads the options from the configured plugin options
+    pub(crate) fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        Ok(Self {
+            network: parse_network(&plugin.configuration().network)?,
+            lsps1_enabled: plugin.option(&options::lsps1_enable())?,
+            hide_info_when_unhealthy: plugin.option(&options::lsps1_hide_info_when_unhealthy())?,
+            create_order: CreateOrderSettings::from_plugin(plugin)?,
+        })
+    }
+}
+
+/// The responses that are produced once the handler is done
+pub(crate) type PendingResponses = Pin<Box<dyn Future<Output = Vec<OutboundMessage>> + Send>>;
+
+/// The outcome of [`Router::dispatch`]
+pub(crate) enum Dispatched {
+    /// The responses can be sent immediately
+    Ready(Vec<OutboundMessage>),
+    /// The handler might wait for a long time
+    ///
+    /// `lsps1.get_order` can wait until the order changes. The caller
+    /// should await it in a separate task
+    Pending(PendingResponses),
+}
+
+#[derive(Clone)]
+pub(crate) struct Router {
+    state: PluginState,
+    options: HandlerOptions,
+    connector: Arc<dyn RpcConnector>,
+}
+
+impl Router {
+    pub(crate) fn new(
+        state: PluginState,
+        options: HandlerOptions,
+        connector: Arc<dyn RpcConnector>,
+    ) -> Self {
+        Self {
+            state,
+            options,
+            connector,
+        }
+    }
+
+    /// Creates a router that connects to the rpc-file of lightningd
+    pub(crate) fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        Ok(Self::new(
+            plugin.state().clone(),
+            HandlerOptions::from_plugin(plugin)?,
+            Arc::new(RpcFileConnector::new(plugin.configuration().rpc_file)),
+        ))
+    }
+
+    /// Returns the responses to the message `msg` of `peer_id`
+    ///
+    /// Waits until the handler is done. See [`Router::dispatch`]
+    pub(crate) async fn handle_raw(&self, peer_id: PublicKey, msg: &[u8]) -> Vec<OutboundMessage> {
+        match self.dispatch(peer_id, msg).await {
+            Dispatched::Ready(messages) => messages,
+            Dispatched::Pending(responses) => responses.await,
+        }
+    }
+
+    /// Handles the message `msg` of `peer_id`
+    ///
+    /// A message that isn't a valid JSON-RPC request is answered with
+    /// an error. Responses are ignored.
+    pub(crate) async fn dispatch(&self, peer_id: PublicKey, msg: &[u8]) -> Dispatched {
+        // BOLT-8 messages are already limited in length.
+        // By consequence, we don't need to check the length of these messages
+
+        // We'll expect that all incoming messages are JSON-RPC Requests.
+        // Here we parse the JSON and receive a `serde_json::Value`-struct
+        // This will succeed as long as the `raw_message` is valid json
+        // If it isn't we'll respond to our peer with a parse_error
+        let json_msg: Result<serde_json::Value, _> = serde_json::from_slice(msg);
+        let json_msg = match json_msg {
+            Ok(ok) => ok,
+            Err(_) => {
+                let error = ErrorData::parse_error("Invalid JSON");
+                return Dispatched::Ready(error_response(peer_id, JsonRpcId::None, error));
+            }
+        };
+
+        // Responses are handled by the LSP-client. Replying to them would
+        // start an endless exchange of errors with a peer that is an LSP too
+        if JsonRpcMessageKind::of(&json_msg) == JsonRpcMessageKind::Response {
+            log::debug!("Ignoring JSON-RPC response from peer {:?}", peer_id);
+            return Dispatched::Ready(vec![]);
+        }
+
+        // Let's try to read the id of the JSON-rpc request
+        // If the json doesn't include an id, we'll respond to the peer and tell
+        // them they've sent an invalid message.
+        let id: Option<&serde_json::Value> = json_msg.get("id");
+        let id = match id.map(|value| serde_json::from_value::<JsonRpcId>(value.clone())) {
+            Some(Ok(id)) => id,
+            Some(Err(_)) => {
+                let error = ErrorData::invalid_request("Invalid field `id`");
+                return Dispatched::Ready(error_response(peer_id, JsonRpcId::None, error));
+            }
+            None => {
+                let error = ErrorData::invalid_request("Missing field `id`");
+                return Dispatched::Ready(error_response(peer_id, JsonRpcId::None, error));
+            }
+        };
+
+        // We'll parse to `JsonRpcRequest<serde_json::Value>`.
+        // Here we ensure it is a valid JsonRpcRequest. (id-field, jsonrpc="2.0")
+        // However, we don't parse the params yet
+        let json_rpc_request =
+            serde_json::from_value::<JsonRpcRequest<serde_json::Value>>(json_msg);
+        let json_rpc_request = match json_rpc_request {
+            Ok(request) => request,
+            Err(parse_error) => {
+                let error = ErrorData::invalid_request(format!(
+                    "Invalid JSON-RPC request. {}",
+                    parse_error
+                ));
+                return Dispatched::Ready(error_response(peer_id, id, error));
+            }
+        };
+
+        // Let's check if we know the method in the JSON-rpc request.
+        // We return a user to the error of the JsonRpcMethod isn't known
+        let method_str = json_rpc_request.method.clone();
+        let method = match JsonRpcMethodEnum::from_method_name(&method_str) {
+            Ok(m) => m,
+            Err(_) => {
+                log::debug!(
+                    "Invalid rpc-method '{}' from peer '{:?}'",
+                    method_str,
+                    &peer_id
+                );
+                let error = ErrorData::method_not_found(&method_str);
+                return Dispatched::Ready(error_response(peer_id, id, error));
+            }
+        };
+
+        // We can't respond if lightningd is unreachable
+        let cln_rpc = match self.connector.connect().await {
+            Ok(cln_rpc) => cln_rpc,
+            Err(err) => {
+                log::warn!("Failed to connect to lightningd: {:?}", err);
+                return Dispatched::Ready(vec![]);
+            }
+        };
+        let context = CustomMsgContextBuilder::new()
+            .state(self.state.clone())
+            .options(self.options.clone())
+            .connector(self.connector.clone())
+            .request(json_rpc_request)
+            .peer_id(peer_id)
+            .cln_rpc(cln_rpc)
+            .build();
+        let mut context = match context {
+            Ok(context) => context,
+            Err(err) => {
+                log::warn!("Failed to create the context of a request: {:?}", err);
+                return Dispatched::Ready(vec![]);
+            }
+        };
+
+        type JRM = JsonRpcMethodEnum;
+        let result = match method {
+            JRM::Lsps0ListProtocols(m) => do_list_protocols(m, &mut context)
+                .await
+                .map(|x| serde_json::to_value(x).unwrap()),
+            JRM::Lsps1Info(m) => do_lsps1_get_info(m, &mut context)
+                .await
+                .map(|x| serde_json::to_value(x.as_ref()).unwrap()),
+            JRM::Lsps1CreateOrder(m) => do_lsps1_create_order(m, &mut context)
+                .await
+                .map(|x| serde_json::to_value(x).unwrap()),
+            JRM::Lsps1GetOrder(m) => {
+                // lsps1.get_order might wait until the order changes
+                return Dispatched::Pending(Box::pin(async move {
+                    let result = do_lsps1_get_order(m, &mut context)
+                        .await
+                        .map(|x| serde_json::to_value(x).unwrap());
+                    result_response(peer_id, id, result)
+                }));
+            }
+        };
+
+        Dispatched::Ready(result_response(peer_id, id, result))
+    }
+
+    /// Tells a peer whose queue is full to retry its request later
+    ///
+    /// The message hasn't been validated yet. Invalid requests and
+    /// responses are dropped.
+    pub(crate) fn reject_busy(&self, peer_id: PublicKey, msg: &[u8]) -> Vec<OutboundMessage> {
+        let json_msg: serde_json::Value = match serde_json::from_slice(msg) {
+            Ok(json_msg) => json_msg,
+            Err(_) => return vec![],
+        };
+        if JsonRpcMessageKind::of(&json_msg) == JsonRpcMessageKind::Response {
+            return vec![];
+        }
+        let id = match json_msg
+            .get("id")
+            .and_then(|id| serde_json::from_value::<JsonRpcId>(id.clone()).ok())
+        {
+            Some(id) => id,
+            None => return vec![],
+        };
+
+        let error = ErrorData::temporarily_unavailable()
+            .with_message("Too many pending requests")
+            .with_retry_after(PEER_QUEUE_RETRY_AFTER);
+        error_response(peer_id, id, error)
+    }
+}
+
+/// Serializes the response to `peer_id`
+fn outbound<O, E>(peer_id: PublicKey, response: JsonRpcResponse<O, E>) -> Vec<OutboundMessage>
+where
+    O: Serialize,
+    E: Serialize,
+{
+    match serde_json::to_string(&response) {
+        Ok(payload) => vec![OutboundMessage { peer_id, payload }],
+        Err(err) => {
+            log::warn!("Failed to serialize response: {:?}", err);
+            vec![]
+        }
+    }
+}
+
+fn error_response(peer_id: PublicKey, id: JsonRpcId, error: ErrorData) -> Vec<OutboundMessage> {
+    outbound(
+        peer_id,
+        JsonRpcResponse::<(), DefaultError>::error(id, error),
+    )
+}
+
+/// The response with the result of a handler
+fn result_response(
+    peer_id: PublicKey,
+    id: JsonRpcId,
+    result: Result<serde_json::Value, HandlerError>,
+) -> Vec<OutboundMessage> {
+    match result {
+        Ok(result) => outbound(
+            peer_id,
+            JsonRpcResponse::<_, DefaultError>::success(id, result),
+        ),
+        Err(err) => {
+            // Internal errors are logged with their incident id
+            if let HandlerError::Client(error) = &err {
+                log::warn!("Error {:?}", error);
+            }
+            error_response(peer_id, id, err.into_error_data())
+        }
+    }
+}
+
+async fn do_list_protocols(
+    method: methods::Lsps0ListProtocols,
+    context: &mut CustomMsgContext<PluginState>,
+) -> Result<ListprotocolsResponse, HandlerError> {
+    method.into_typed_request(context.request.clone())?;
+
+    let lsps1_enabled =
+        context.options.lsps1_enabled && context.state.is_lsps1_allowed_for(&context.peer_id);
+
+    let protocols = if lsps1_enabled { vec![0, 1] } else { vec![0] };
+
+    // The server doesn't advertise any fields that aren't part of the spec
+    Ok(ListprotocolsResponse {
+        protocols,
+        extra_fields: Default::default(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::{Path, PathBuf};
+
+    use serde_json::{json, Value};
+
+    use lsp_primitives::json_rpc::error::codes;
+    use lsp_primitives::lsps1::util::ExpiryMode;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::sqlite::test::get_temp_db;
+    use crate::lsps1::channel_type::AllowedChannelTypes;
+    use crate::lsps1::fee_calc::StandardFeeCalculator;
+    use crate::lsps1::refund_address::AcceptedRefundAddressTypes;
+    use crate::state::test_support::test_state;
+
+    const PEER_ID: &str = "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170";
+
+    fn peer_id() -> PublicKey {
+        PublicKey::from_hex(PEER_ID).unwrap()
+    }
+
+    /// The options of a regtest server without extensions
+    fn test_options() -> HandlerOptions {
+        HandlerOptions {
+            network: Network::Regtest,
+            lsps1_enabled: true,
+            hide_info_when_unhealthy: false,
+            create_order: CreateOrderSettings {
+                order_lifetime: 3600,
+                fee_calc: StandardFeeCalculator::from_options(1_000, 500, 100).unwrap(),
+                confirmation_policy: None,
+                expiry_mode: ExpiryMode::Reject,
+                extensions_enabled: false,
+                allowed_channel_types: AllowedChannelTypes::default(),
+                accepted_refund_address_types: AcceptedRefundAddressTypes::default(),
+            },
+        }
+    }
+
+    async fn test_router(rpc: &FakeClnRpc) -> Router {
+        let (db, _) = get_temp_db().await;
+        Router::new(test_state(db), test_options(), Arc::new(rpc.clone()))
+    }
+
+    fn parse_payloads(messages: &[OutboundMessage]) -> Vec<Value> {
+        messages
+            .iter()
+            .map(|message| serde_json::from_str(&message.payload).unwrap())
+            .collect()
+    }
+
+    /// Compares the responses to every `<case>.request.json` in
+    /// `tests/fixtures/router` with `<case>.response.json`
+    ///
+    /// The payloads are compared as JSON. The order of the fields in an
+    /// object is not part of the protocol.
+    #[tokio::test]
+    async fn golden_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/router");
+        let update = std::env::var_os("UPDATE_GOLDEN_FILES").is_some();
+
+        let mut cases: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".request.json"))
+            .collect();
+        cases.sort();
+        assert!(!cases.is_empty(), "No requests in {}", dir.display());
+
+        let rpc = FakeClnRpc::default();
+        let router = test_router(&rpc).await;
+        let mut mismatches = Vec::new();
+        for request_path in cases {
+            let file_name = request_path.file_name().unwrap().to_string_lossy();
+            let case = file_name.trim_end_matches(".request.json").to_string();
+            let response_path = dir.join(format!("{}.response.json", case));
+
+            let request = std::fs::read(&request_path).unwrap();
+            let messages = router.handle_raw(peer_id(), &request).await;
+            assert!(messages.iter().all(|message| message.peer_id == peer_id()));
+            let actual = Value::Array(parse_payloads(&messages));
+
+            if update {
+                let mut contents = serde_json::to_string_pretty(&actual).unwrap();
+                contents.push('\n');
+                std::fs::write(&response_path, contents).unwrap();
+                continue;
+            }
+
+            let expected = std::fs::read_to_string(&response_path)
+                .unwrap_or_else(|_| panic!("Missing {}", response_path.display()));
+            let expected: Value = serde_json::from_str(&expected).unwrap();
+            if actual != expected {
+                mismatches.push(format!(
+                    "{}:\n  expected: {}\n  actual:   {}",
+                    case, expected, actual
+                ));
+            }
+        }
+        assert!(
+            mismatches.is_empty(),
+            "Responses differ from the golden files:\n{}",
+            mismatches.join("\n")
+        );
+
+        // None of the requests reaches lightningd
+        assert!(rpc.called_methods().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lsps1_is_not_listed_while_disabled() {
+        let rpc = FakeClnRpc::default();
+        let mut router = test_router(&rpc).await;
+        router.options.lsps1_enabled = false;
+
+        let request = json!({
+            "jsonrpc" : "2.0",
+            "id" : "list",
+            "method" : "lsps0.list_protocols",
+            "params" : {},
+        });
+        let messages = router
+            .handle_raw(peer_id(), request.to_string().as_bytes())
+            .await;
+        assert_eq!(
+            parse_payloads(&messages),
+            vec![json!({ "jsonrpc" : "2.0", "id" : "list", "result" : { "protocols" : [0] } })]
+        );
+
+        let request = json!({
+            "jsonrpc" : "2.0",
+            "id" : "info",
+            "method" : "lsps1.get_info",
+            "params" : {},
+        });
+        let messages = router
+            .handle_raw(peer_id(), request.to_string().as_bytes())
+            .await;
+        let payload = &parse_payloads(&messages)[0];
+        assert_eq!(payload["error"]["code"], codes::METHOD_NOT_FOUND_CODE);
+    }
+
+    #[tokio::test]
+    async fn get_order_is_pending() {
+        let rpc = FakeClnRpc::default();
+        let router = test_router(&rpc).await;
+
+        let request = json!({
+            "jsonrpc" : "2.0",
+            "id" : "order",
+            "method" : "lsps1.get_order",
+            "params" : { "order_id" : "bb4b5d0a-8334-49d8-9463-90a6d413af7c" },
+        });
+        let Dispatched::Pending(responses) = router
+            .dispatch(peer_id(), request.to_string().as_bytes())
+            .await
+        else {
+            panic!("lsps1.get_order should be handled in a separate task");
+        };
+        let messages = tokio::spawn(responses).await.unwrap();
+        let payload = &parse_payloads(&messages)[0];
+        assert_eq!(payload["id"], "order");
+        assert_eq!(payload["error"]["code"], codes::NOT_FOUND_CODE);
+    }
+
+    #[tokio::test]
+    async fn busy_peer_is_asked_to_retry() {
+        let rpc = FakeClnRpc::default();
+        let router = test_router(&rpc).await;
+
+        let request = json!({
+            "jsonrpc" : "2.0",
+            "id" : "busy",
+            "method" : "lsps1.get_info",
+            "params" : {},
+        });
+        let messages = router.reject_busy(peer_id(), request.to_string().as_bytes());
+        assert_eq!(
+            parse_payloads(&messages),
+            vec![json!({
+                "jsonrpc" : "2.0",
+                "id" : "busy",
+                "error" : {
+                    "code" : 503,
+                    "message" : "Temporarily unavailable",
+                    "data" : {
+                        "message" : "Too many pending requests",
+                        "reason" : "temporarily_unavailable",
+                        "retry_after_seconds" : 1,
+                    },
+                },
+            })]
+        );
+
+        // Messages we wouldn't answer anyway are dropped
+        assert!(router.reject_busy(peer_id(), b"{").is_empty());
+        assert!(router
+            .reject_busy(peer_id(), br#"{"jsonrpc":"2.0","id":"a","result":{}}"#)
+            .is_empty());
+        assert!(router
+            .reject_busy(peer_id(), br#"{"jsonrpc":"2.0","method":"lsps1.get_info"}"#)
+            .is_empty());
+    }
+}
@@ -1,32 +1,26 @@
 use anyhow::Result;
 
 use cln_rpc::model::requests::SendcustommsgRequest;
-use cln_rpc::ClnRpc;
 
 use cln_lsps::client::LSPS_MESSAGE_ID;
 use cln_lsps::custom_msg_hook::RawCustomMsgMessage;
-use lsp_primitives::json_rpc::JsonRpcResponse;
 use lsp_primitives::lsps0::common_schemas::PublicKey;
 
-use serde::Serialize;
+use crate::cln::rpc_api::ClnRpcApi;
 
-pub async fn send_response<O, E>(
-    cln_rpc: &mut ClnRpc,
+/// Sends a serialized response to the peer
+pub async fn send_payload(
+    cln_rpc: &mut dyn ClnRpcApi,
     peer_id: PublicKey,
-    response: JsonRpcResponse<O, E>,
-) -> Result<()>
-where
-    O: Serialize,
-    E: Serialize,
-{
-    let data: Vec<u8> = serde_json::to_vec(&response)?;
+    data: &[u8],
+) -> Result<()> {
     let bolt8_msg_id = LSPS_MESSAGE_ID;
-    let raw_msg = RawCustomMsgMessage::create(peer_id.clone(), &bolt8_msg_id, &data)?;
+    let raw_msg = RawCustomMsgMessage::create(peer_id.clone(), &bolt8_msg_id, data)?;
     let rpc_msg = raw_msg.to_rpc()?;
     log::debug!(
-        "Sending response to peer={:?} data={}",
+        "Sending response to peer={:?} of {} bytes",
         rpc_msg.peer_id,
-        rpc_msg.payload
+        data.len()
     );
 
     let send_custom_msg_request = SendcustommsgRequest {
@@ -34,6 +28,6 @@ where
         msg: rpc_msg.payload,
     };
 
-    let _result = cln_rpc.call_typed(&send_custom_msg_request).await?;
+    let _result = cln_rpc.sendcustommsg(&send_custom_msg_request).await?;
     Ok(())
 }
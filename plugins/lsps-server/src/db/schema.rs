@@ -1,10 +1,147 @@
+use anyhow::{anyhow, Context, Result};
 use lsp_primitives::lsps0::common_schemas::{
-    FeeRate, IsoDatetime, PublicKey, SatAmount, TransactionId,
+    FeeRate, IsoDatetime, MsatAmount, OnchainAddress, Outpoint, PublicKey, SatAmount, TransactionId,
 };
-use lsp_primitives::lsps1::schema::{OrderState, PaymentState};
+use lsp_primitives::lsps1::schema::{Channel, OrderState, Payment, PaymentState};
+use lsp_primitives::redact::{redact_address, redact_invoice, redact_token};
+use std::fmt;
+use std::str::FromStr;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+use crate::lsps1::lease_report::SECONDS_PER_BLOCK;
+
+/// The state of an order as tracked by the LSP-server
+///
+/// This is a superset of the LSPS1 [`OrderState`]. The `PendingOpen`,
+/// `Funding`, `ChannelOpening` and `AwaitingConfirmation` states are
+/// internal to the server.
+///
+/// - `PendingOpen` is used when the client has paid but the channel hasn't
+///   been opened yet. The order is queued for the background worker or waits
+///   until the client reconnects if they were offline.
+/// - `Funding` is used while the worker opens the channel. An order that is
+///   `Funding` at start-up is reconciled against the channels of lightningd.
+/// - `ChannelOpening` is used when the funding transaction has been
+///   broadcast. The order completes once the channel reaches `CHANNELD_NORMAL`.
+/// - `AwaitingConfirmation` is used when a reorg unconfirmed the funding
+///   transaction of a `Completed` order. The order completes again once the
+///   funding transaction confirms.
+///
+/// The client sees these states as `CREATED`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lsps1OrderState {
+    Created,
+    PendingOpen,
+    Funding,
+    ChannelOpening,
+    AwaitingConfirmation,
+    Completed,
+    Failed,
+}
+
+/// Explains why an order moved to [`Lsps1OrderState::Failed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lsps1FailureReason {
+    /// We failed to open the channel. E.g: the peer rejected it
+    ChannelOpenFailed,
+    /// The order expired before it was paid or before the client reconnected
+    OrderExpired,
+    /// The channel was closed or the funding transaction was double-spent
+    /// before the channel became usable
+    ChannelAborted,
+}
+
+impl Lsps1FailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ChannelOpenFailed => "channel_open_failed",
+            Self::OrderExpired => "order_expired",
+            Self::ChannelAborted => "channel_aborted",
+        }
+    }
+}
+
+impl std::str::FromStr for Lsps1FailureReason {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "channel_open_failed" => Ok(Self::ChannelOpenFailed),
+            "order_expired" => Ok(Self::OrderExpired),
+            "channel_aborted" => Ok(Self::ChannelAborted),
+            _ => Err(anyhow::anyhow!("Unknown failure reason: {}", value)),
+        }
+    }
+}
+
+impl From<Lsps1OrderState> for OrderState {
+    fn from(state: Lsps1OrderState) -> Self {
+        match state {
+            Lsps1OrderState::Created => OrderState::Created,
+            Lsps1OrderState::PendingOpen => OrderState::Created,
+            Lsps1OrderState::Funding => OrderState::Created,
+            Lsps1OrderState::ChannelOpening => OrderState::Created,
+            Lsps1OrderState::AwaitingConfirmation => OrderState::Created,
+            Lsps1OrderState::Completed => OrderState::Completed,
+            Lsps1OrderState::Failed => OrderState::Failed,
+        }
+    }
+}
+
+/// The generation of the state of an order
+///
+/// Every change of the order state increments the generation. An update
+/// only succeeds at the generation it read, so a concurrent change is
+/// detected. The type can't be mixed up with a [`PaymentGeneration`]:
+///
+/// ```compile_fail
+/// fn pay(order: &Lsps1Order, payment: &Lsps1PaymentDetails) -> UpdatePaymentStateQuery {
+///     UpdatePaymentStateQuery {
+///         state: PaymentState::Paid,
+///         changed_at: IsoDatetime::now(),
+///         // expected `PaymentGeneration`, found `OrderGeneration`
+///         generation: order.generation,
+///         label: payment.bolt11_invoice_label.clone(),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct OrderGeneration(pub(crate) u64);
+
+impl OrderGeneration {
+    /// The generation after the next change of the order state
+    pub(crate) fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for OrderGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The generation of the state of a payment
+///
+/// See [`OrderGeneration`]. Orders and payments count their generations
+/// independently.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct PaymentGeneration(pub(crate) u64);
+
+impl PaymentGeneration {
+    /// The generation after the next change of the payment state
+    pub(crate) fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for PaymentGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone)]
 pub struct Lsps1Order {
     pub(crate) uuid: Uuid,
     pub(crate) client_node_id: PublicKey,
@@ -18,22 +155,186 @@ pub struct Lsps1Order {
     pub(crate) announce_channel: bool,
     pub(crate) created_at: IsoDatetime,
     pub(crate) expires_at: IsoDatetime,
-    pub(crate) order_state: OrderState,
-    pub(crate) generation: u64,
+    pub(crate) order_state: Lsps1OrderState,
+    pub(crate) failure_reason: Option<Lsps1FailureReason>,
+    pub(crate) generation: OrderGeneration,
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Debug for Lsps1Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps1Order")
+            .field("uuid", &self.uuid)
+            .field("client_node_id", &self.client_node_id)
+            .field("lsp_balance_sat", &self.lsp_balance_sat)
+            .field("client_balance_sat", &self.client_balance_sat)
+            .field(
+                "funding_confirms_within_blocks",
+                &self.funding_confirms_within_blocks,
+            )
+            .field(
+                "required_channel_confirmations",
+                &self.required_channel_confirmations,
+            )
+            .field("channel_expiry_blocks", &self.channel_expiry_blocks)
+            .field("token", &self.token.as_deref().map(redact_token))
+            .field(
+                "refund_onchain_address",
+                &self.refund_onchain_address.as_deref().map(redact_address),
+            )
+            .field("announce_channel", &self.announce_channel)
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .field("order_state", &self.order_state)
+            .field("failure_reason", &self.failure_reason)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+/// The label of a bolt11-invoice created by lightningd
+///
+/// lightningd refuses labels that are too long. Labels are also passed
+/// around in json, logs and sql-queries. We only allow a conservative
+/// charset so a label never has to be escaped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceLabel(String);
+
+impl InvoiceLabel {
+    pub const MAX_LENGTH: usize = 128;
+
+    const ORDER_PREFIX: &'static str = "lsps1_";
+
+    pub fn new(label: impl Into<String>) -> Result<Self> {
+        let label = label.into();
+        if label.is_empty() {
+            return Err(anyhow!("Invoice label is empty"));
+        }
+        if label.len() > Self::MAX_LENGTH {
+            return Err(anyhow!(
+                "Invoice label has {} characters but at most {} are allowed",
+                label.len(),
+                Self::MAX_LENGTH
+            ));
+        }
+        if let Some(c) = label.chars().find(|c| !Self::is_allowed_char(*c)) {
+            return Err(anyhow!("Invoice label contains invalid character {:?}", c));
+        }
+        Ok(Self(label))
+    }
+
+    /// The label of the invoice that pays for an LSPS1-order
+    pub fn for_order(order_uuid: &Uuid) -> Result<Self> {
+        Self::new(format!("{}{}", Self::ORDER_PREFIX, order_uuid))
+    }
+
+    /// Returns the order if `label` was created by [`InvoiceLabel::for_order`]
+    ///
+    /// Only the exact spelling is accepted. A label with an upper-case or
+    /// braced uuid was not created by us and belongs to another invoice.
+    pub fn parse_order_uuid(label: &str) -> Option<Uuid> {
+        let uuid = Uuid::parse_str(label.strip_prefix(Self::ORDER_PREFIX)?).ok()?;
+        let canonical = Self::for_order(&uuid).ok()?;
+        (canonical.as_str() == label).then_some(uuid)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn is_allowed_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')
+    }
+}
+
+impl TryFrom<String> for InvoiceLabel {
+    type Error = anyhow::Error;
+
+    fn try_from(label: String) -> Result<Self> {
+        Self::new(label)
+    }
+}
+
+impl fmt::Display for InvoiceLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone)]
 pub struct Lsps1PaymentDetails {
     pub(crate) order_uuid: Uuid,
     pub(crate) fee_total_sat: SatAmount,
     pub(crate) order_total_sat: SatAmount,
     pub(crate) bolt11_invoice: String,
-    pub(crate) bolt11_invoice_label: String,
+    pub(crate) bolt11_invoice_label: InvoiceLabel,
     pub(crate) onchain_address: Option<String>,
     pub(crate) onchain_block_confirmations_required: Option<u16>,
     pub(crate) minimum_fee_for_0conf: Option<FeeRate>,
     pub(crate) state: PaymentState,
-    pub(crate) generation: u64,
+    pub(crate) generation: PaymentGeneration,
+}
+
+impl fmt::Debug for Lsps1PaymentDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps1PaymentDetails")
+            .field("order_uuid", &self.order_uuid)
+            .field("fee_total_sat", &self.fee_total_sat)
+            .field("order_total_sat", &self.order_total_sat)
+            .field("bolt11_invoice", &redact_invoice(&self.bolt11_invoice))
+            .field("bolt11_invoice_label", &self.bolt11_invoice_label)
+            .field(
+                "onchain_address",
+                &self.onchain_address.as_deref().map(redact_address),
+            )
+            .field(
+                "onchain_block_confirmations_required",
+                &self.onchain_block_confirmations_required,
+            )
+            .field("minimum_fee_for_0conf", &self.minimum_fee_for_0conf)
+            .field("state", &self.state)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl Lsps1PaymentDetails {
+    /// Converts the payment into the `payment` of an LSPS1 order
+    ///
+    /// The server doesn't track on-chain payments. The `onchain_payment`
+    /// is always `None`.
+    pub(crate) fn into_spec_payment(self) -> Result<Payment> {
+        let onchain_address = self
+            .onchain_address
+            .as_deref()
+            .map(OnchainAddress::from_str)
+            .transpose()
+            .with_context(|| format!("Invalid onchain_address for order {}", self.order_uuid))?;
+
+        Ok(Payment {
+            state: self.state,
+            fee_total_sat: self.fee_total_sat,
+            order_total_sat: self.order_total_sat,
+            bolt11_invoice: self.bolt11_invoice,
+            onchain_address,
+            min_onchain_payment_confirmations: self.onchain_block_confirmations_required,
+            min_fee_for_0conf: self.minimum_fee_for_0conf,
+            onchain_payment: None,
+        })
+    }
+}
+
+/// What the node knew about the client when the order was created
+///
+/// This is a snapshot for the operator and isn't updated afterwards.
+/// Fields are empty if the information wasn't available.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lsps1ClientNode {
+    /// The alias the client announced in gossip
+    pub(crate) alias: Option<String>,
+    /// The addresses the client announced in gossip. E.g: `203.0.113.7:9735`
+    pub(crate) addresses: Vec<String>,
+    /// The address of the connection the order was created on
+    pub(crate) remote_address: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -42,3 +343,394 @@ pub struct Lsps1Channel {
     pub(crate) outnum: u32,
     pub(crate) funded_at: IsoDatetime,
 }
+
+impl Lsps1Channel {
+    /// Converts the channel into the `channel` of an LSPS1 order
+    ///
+    /// The lease lasts `channel_expiry_blocks` from the moment the channel
+    /// was funded. `expires_at` is an estimate based on 10 minute blocks.
+    pub(crate) fn into_spec_channel(self, channel_expiry_blocks: u32) -> Result<Channel> {
+        let lease_seconds = i64::from(channel_expiry_blocks) * SECONDS_PER_BLOCK;
+        let expires_at =
+            IsoDatetime::from_unix_timestamp(self.funded_at.unix_timestamp() + lease_seconds)?;
+
+        Ok(Channel {
+            funded_at: self.funded_at,
+            funding_outpoint: Outpoint {
+                txid: self.funding_txid,
+                outnum: self.outnum,
+            },
+            expires_at,
+            // Only known to lightningd. See `lsps1::channel_usability`
+            scid_alias: None,
+            usable: None,
+        })
+    }
+}
+
+/// The wallet inputs that are reserved to fund the channel of an order
+///
+/// Other wallet activity, e.g.: a `withdraw`, can't spend reserved inputs.
+/// lightningd drops the reservation by itself after a number of blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lsps1FundingReservation {
+    /// The psbt returned by `fundpsbt`. It is passed to `unreserveinputs`
+    pub(crate) psbt: String,
+    pub(crate) utxos: Vec<Outpoint>,
+    pub(crate) reserved_at: IsoDatetime,
+}
+
+#[derive(Clone)]
+pub struct Lsps1Refund {
+    pub(crate) txid: TransactionId,
+    pub(crate) amount_sat: SatAmount,
+    pub(crate) address: String,
+    pub(crate) broadcast_at: IsoDatetime,
+}
+
+impl fmt::Debug for Lsps1Refund {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lsps1Refund")
+            .field("txid", &self.txid)
+            .field("amount_sat", &self.amount_sat)
+            .field("address", &redact_address(&self.address))
+            .field("broadcast_at", &self.broadcast_at)
+            .finish()
+    }
+}
+
+/// The severity of an entry in the audit log of an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderLogSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl OrderLogSeverity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl std::str::FromStr for OrderLogSeverity {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "info" => Ok(Self::Info),
+            "warning" => Ok(Self::Warning),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow::anyhow!("Unknown severity: {}", value)),
+        }
+    }
+}
+
+/// The events that are recorded in the audit log of an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderLogEvent {
+    /// The order was created. The details contain the fee parameters
+    OrderCreated,
+    /// The invoice was paid and the order is queued to be opened
+    PaymentAccepted,
+    /// The payment was rejected because the amount is too low
+    PaymentRejected,
+    /// The channel can't be opened because the client is offline
+    PeerOffline,
+    ChannelOpenStarted,
+    ChannelOpenFailed,
+    /// The funding transaction was broadcast
+    ChannelOpened,
+    /// The order expired before it was paid or before the client reconnected
+    OrderExpired,
+    /// The payment was refunded to the `refund_onchain_address`
+    RefundBroadcast,
+    /// The payment must be refunded by the operator
+    RefundRequired,
+    /// The operator used `lsps1-admin-retry-open`
+    AdminRetryOpen,
+    /// The operator used `lsps1-admin-fulfill-order`
+    AdminFulfillOrder,
+    /// A signed receipt was issued for the completed order
+    ReceiptIssued,
+    /// Wallet inputs were reserved to fund the channel
+    InputsReserved,
+    /// The reserved wallet inputs were released or used to fund the channel
+    InputsReleased,
+    /// A reorg unconfirmed the funding transaction of the completed order
+    FundingUnconfirmed,
+    /// The funding transaction confirmed again after a reorg
+    FundingReconfirmed,
+}
+
+impl OrderLogEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OrderCreated => "order_created",
+            Self::PaymentAccepted => "payment_accepted",
+            Self::PaymentRejected => "payment_rejected",
+            Self::PeerOffline => "peer_offline",
+            Self::ChannelOpenStarted => "channel_open_started",
+            Self::ChannelOpenFailed => "channel_open_failed",
+            Self::ChannelOpened => "channel_opened",
+            Self::OrderExpired => "order_expired",
+            Self::RefundBroadcast => "refund_broadcast",
+            Self::RefundRequired => "refund_required",
+            Self::AdminRetryOpen => "admin_retry_open",
+            Self::AdminFulfillOrder => "admin_fulfill_order",
+            Self::ReceiptIssued => "receipt_issued",
+            Self::InputsReserved => "inputs_reserved",
+            Self::InputsReleased => "inputs_released",
+            Self::FundingUnconfirmed => "funding_unconfirmed",
+            Self::FundingReconfirmed => "funding_reconfirmed",
+        }
+    }
+}
+
+impl std::str::FromStr for OrderLogEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "order_created" => Ok(Self::OrderCreated),
+            "payment_accepted" => Ok(Self::PaymentAccepted),
+            "payment_rejected" => Ok(Self::PaymentRejected),
+            "peer_offline" => Ok(Self::PeerOffline),
+            "channel_open_started" => Ok(Self::ChannelOpenStarted),
+            "channel_open_failed" => Ok(Self::ChannelOpenFailed),
+            "channel_opened" => Ok(Self::ChannelOpened),
+            "order_expired" => Ok(Self::OrderExpired),
+            "refund_broadcast" => Ok(Self::RefundBroadcast),
+            "refund_required" => Ok(Self::RefundRequired),
+            "admin_retry_open" => Ok(Self::AdminRetryOpen),
+            "admin_fulfill_order" => Ok(Self::AdminFulfillOrder),
+            "receipt_issued" => Ok(Self::ReceiptIssued),
+            "inputs_reserved" => Ok(Self::InputsReserved),
+            "inputs_released" => Ok(Self::InputsReleased),
+            "funding_unconfirmed" => Ok(Self::FundingUnconfirmed),
+            "funding_reconfirmed" => Ok(Self::FundingReconfirmed),
+            _ => Err(anyhow::anyhow!("Unknown order log event: {}", value)),
+        }
+    }
+}
+
+/// An entry in the audit log of an order
+#[derive(Debug, Clone)]
+pub struct Lsps1OrderLogEntry {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) created_at: IsoDatetime,
+    pub(crate) severity: OrderLogSeverity,
+    pub(crate) event: OrderLogEvent,
+    pub(crate) details: serde_json::Value,
+}
+
+/// A sample of the usage of a leased channel
+///
+/// The forwarded payments are those since the previous sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lsps1ChannelUsageSample {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) sampled_at: IsoDatetime,
+    pub(crate) lsp_balance_msat: MsatAmount,
+    pub(crate) client_balance_msat: MsatAmount,
+    /// Payments forwarded from the client
+    pub(crate) forwarded_in_count: u64,
+    pub(crate) forwarded_in_msat: MsatAmount,
+    /// Payments forwarded to the client
+    pub(crate) forwarded_out_count: u64,
+    pub(crate) forwarded_out_msat: MsatAmount,
+    /// HTLCs that were pending when the sample was taken
+    pub(crate) htlc_count: u32,
+}
+
+/// A response that couldn't be sent to a peer
+///
+/// The `payload` is the serialized JSON-RPC response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lsps0OutboxEntry {
+    pub(crate) id: i64,
+    pub(crate) peer_id: PublicKey,
+    pub(crate) payload: String,
+    pub(crate) created_at: IsoDatetime,
+    pub(crate) attempts: u32,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::sqlite::test::{create_test_order, create_test_payment};
+
+    #[test]
+    fn order_label_is_valid() {
+        let uuid = Uuid::new_v4();
+        let label = InvoiceLabel::for_order(&uuid).unwrap();
+        assert_eq!(label.as_str(), format!("lsps1_{}", uuid));
+        assert_eq!(InvoiceLabel::parse_order_uuid(label.as_str()), Some(uuid));
+    }
+
+    #[test]
+    fn parse_order_label_is_strict() {
+        let uuid = Uuid::new_v4();
+        let upper = uuid.to_string().to_uppercase();
+        assert_eq!(
+            InvoiceLabel::parse_order_uuid(&format!("lsps1_{}", upper)),
+            None
+        );
+        assert_eq!(
+            InvoiceLabel::parse_order_uuid(&format!("lsps1_{{{}}}", uuid)),
+            None
+        );
+        assert_eq!(
+            InvoiceLabel::parse_order_uuid(&format!("lsps1_{}x", uuid)),
+            None
+        );
+        assert_eq!(
+            InvoiceLabel::parse_order_uuid(&format!("lsps2_{}", uuid)),
+            None
+        );
+        assert_eq!(InvoiceLabel::parse_order_uuid(&uuid.to_string()), None);
+        assert_eq!(InvoiceLabel::parse_order_uuid("lsps1_"), None);
+        assert_eq!(InvoiceLabel::parse_order_uuid("invoice-for-coffee"), None);
+    }
+
+    #[test]
+    fn label_at_the_length_limit() {
+        let uuid = Uuid::new_v4().to_string();
+        let prefix = "a".repeat(InvoiceLabel::MAX_LENGTH - uuid.len());
+
+        let label = format!("{}{}", prefix, uuid);
+        assert_eq!(label.len(), InvoiceLabel::MAX_LENGTH);
+        assert!(InvoiceLabel::new(label.clone()).is_ok());
+        assert!(InvoiceLabel::new(format!("a{}", label)).is_err());
+    }
+
+    #[test]
+    fn label_with_invalid_characters_is_rejected() {
+        assert!(InvoiceLabel::new("").is_err());
+        assert!(InvoiceLabel::new("lsps1_\nabc").is_err());
+        assert!(InvoiceLabel::new("lsps1_\"abc").is_err());
+        assert!(InvoiceLabel::new("lsps1_'abc").is_err());
+        assert!(InvoiceLabel::new("lsps1 abc").is_err());
+        assert!(InvoiceLabel::new("lsps1_é").is_err());
+        assert!(InvoiceLabel::new("test.order:1-a_B").is_ok());
+    }
+
+    #[test]
+    fn payment_without_optional_fields() {
+        let order = create_test_order();
+        let payment = create_test_payment(&order);
+        let invoice = payment.bolt11_invoice.clone();
+
+        let payment = serde_json::to_value(payment.into_spec_payment().unwrap()).unwrap();
+        assert_eq!(
+            payment,
+            serde_json::json!({
+                "state" : "EXPECT_PAYMENT",
+                "fee_total_sat" : "500",
+                "order_total_sat" : "500",
+                "bolt11_invoice" : invoice,
+                "onchain_address" : null,
+                "min_onchain_payment_confirmations" : null,
+                "min_fee_for_0conf" : null,
+                "onchain_payment" : null,
+            })
+        );
+    }
+
+    #[test]
+    fn payment_with_all_optional_fields() {
+        let order = create_test_order();
+        let mut payment = create_test_payment(&order);
+        payment.onchain_address = Some("bcrt1qkm08480v79rzjp7tx2pjrly423ncv85k65nsmu".to_string());
+        payment.onchain_block_confirmations_required = Some(0);
+        payment.minimum_fee_for_0conf = Some(FeeRate::from_sats_per_kwu(253));
+        payment.state = PaymentState::Paid;
+
+        let payment = payment.into_spec_payment().unwrap();
+        assert_eq!(payment.state, PaymentState::Paid);
+        assert_eq!(
+            payment.onchain_address.unwrap().to_string(),
+            "bcrt1qkm08480v79rzjp7tx2pjrly423ncv85k65nsmu"
+        );
+        // Zero confirmations is not the same as no requirement
+        assert_eq!(payment.min_onchain_payment_confirmations, Some(0));
+        assert_eq!(payment.min_fee_for_0conf.unwrap().to_sats_per_kwu(), 253);
+        // On-chain payments are not tracked
+        assert!(payment.onchain_payment.is_none());
+    }
+
+    #[test]
+    fn payment_with_only_onchain_confirmations() {
+        let order = create_test_order();
+        let mut payment = create_test_payment(&order);
+        payment.onchain_block_confirmations_required = Some(3);
+
+        let payment = payment.into_spec_payment().unwrap();
+        assert!(payment.onchain_address.is_none());
+        assert_eq!(payment.min_onchain_payment_confirmations, Some(3));
+        assert!(payment.min_fee_for_0conf.is_none());
+        assert!(payment.onchain_payment.is_none());
+    }
+
+    #[test]
+    fn payment_with_invalid_onchain_address_fails() {
+        let order = create_test_order();
+        let mut payment = create_test_payment(&order);
+        payment.onchain_address = Some("not-an-address".to_string());
+
+        let err = payment.into_spec_payment().unwrap_err();
+        assert!(err.to_string().contains("onchain_address"), "{:?}", err);
+    }
+
+    #[test]
+    fn channel_lease_expires_after_channel_expiry_blocks() {
+        let funded_at = IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap();
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(&"ab".repeat(32)).unwrap(),
+            outnum: 1,
+            funded_at,
+        };
+
+        let spec = channel.clone().into_spec_channel(144).unwrap();
+        assert_eq!(spec.funded_at, funded_at);
+        assert_eq!(spec.funding_outpoint.txid, channel.funding_txid);
+        assert_eq!(spec.funding_outpoint.outnum, 1);
+        assert_eq!(spec.expires_at.unix_timestamp(), 1_700_000_000 + 144 * 600);
+
+        // A lease of zero blocks expires when the channel is funded
+        let spec = channel.into_spec_channel(0).unwrap();
+        assert_eq!(spec.expires_at, funded_at);
+    }
+
+    #[test]
+    #[cfg(not(feature = "unredacted-debug"))]
+    fn debug_output_is_redacted() {
+        let mut order = create_test_order();
+        order.token = Some("my-secret-token".to_string());
+        order.refund_onchain_address =
+            Some("bcrt1q8v5sxlq8xg3fm6stqn7l9yy9hj2jdr2f4gvc5w".to_string());
+
+        let debug = format!("{:?}", order);
+        assert!(!debug.contains("my-secret-token"), "{}", debug);
+        assert!(!debug.contains("bcrt1q8v5sxlq8xg3fm6stqn7l9yy9hj2jdr2f4gvc5w"));
+        assert!(debug.contains("bcrt…vc5w"), "{}", debug);
+
+        let payment = create_test_payment(&order);
+        let debug = format!("{:?}", payment);
+        assert!(!debug.contains(&payment.bolt11_invoice), "{}", debug);
+    }
+
+    #[test]
+    fn generations_count_changes() {
+        assert_eq!(OrderGeneration::default(), OrderGeneration(0));
+        assert_eq!(OrderGeneration(4).next(), OrderGeneration(5));
+        assert!(OrderGeneration(4) < OrderGeneration(4).next());
+        assert_eq!(PaymentGeneration(0).next().next(), PaymentGeneration(2));
+        assert_eq!(OrderGeneration(7).to_string(), "7");
+        assert_eq!(PaymentGeneration(3).to_string(), "3");
+    }
+}
@@ -1,6 +1,94 @@
-use anyhow::{anyhow, Context, Result};
+//! Converts values to and from the integers that sqlite stores
+//!
+//! Sqlite integers are signed 64-bit. A value that can't be stored fails
+//! with [`ConversionError::RangeViolation`]. A stored integer that isn't a
+//! valid value fails with [`ConversionError::DataCorruption`]. The plugin
+//! never writes such an integer. It is the result of a manual edit or a
+//! corrupt database. Queries that return many rows quarantine these rows.
+//! See [`crate::db::sqlite::quarantine`].
+//!
+//! Timestamps that are only displayed can be read using
+//! [`saturating_timestamp`].
+use anyhow::Result;
 use lsp_primitives::lsps0::common_schemas::{FeeRate, IsoDatetime, MsatAmount, SatAmount};
-use lsp_primitives::lsps1::schema::{OrderState, PaymentState};
+use lsp_primitives::lsps1::schema::PaymentState;
+
+use crate::db::schema::{Lsps1OrderState, OrderGeneration, PaymentGeneration};
+
+/// The last second that can be represented as an [`IsoDatetime`]
+///
+/// 9999-12-31T23:59:59Z
+pub(crate) const MAX_TIMESTAMP: i64 = 253_402_300_799;
+
+/// A value that can't be converted to or from a sqlite integer
+///
+/// It is returned wrapped in an [`anyhow::Error`] and can be
+/// retrieved using `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ConversionError {
+    /// A stored integer isn't a valid value. E.g: a negative amount
+    DataCorruption {
+        /// The type that was read. E.g: `SatAmount`
+        what: &'static str,
+        value: i64,
+    },
+    /// A value doesn't fit in a sqlite integer
+    RangeViolation {
+        /// The type that was written. E.g: `OrderGeneration`
+        what: &'static str,
+        value: String,
+    },
+}
+
+impl ConversionError {
+    fn corrupt(what: &'static str, value: i64) -> anyhow::Error {
+        Self::DataCorruption { what, value }.into()
+    }
+
+    fn out_of_range(what: &'static str, value: impl std::fmt::Display) -> anyhow::Error {
+        Self::RangeViolation {
+            what,
+            value: value.to_string(),
+        }
+        .into()
+    }
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DataCorruption { what, value } => {
+                write!(f, "Stored value {} is not a valid {}", value, what)
+            }
+            Self::RangeViolation { what, value } => {
+                write!(f, "{} {} doesn't fit in a sqlite integer", what, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Returns true if `err` is caused by a stored value that isn't valid
+pub(crate) fn is_data_corruption(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<ConversionError>(),
+            Some(ConversionError::DataCorruption { .. })
+        )
+    })
+}
+
+/// Reads a timestamp that is only displayed
+///
+/// Values before the epoch are read as the epoch. Values beyond
+/// [`MAX_TIMESTAMP`] are read as [`MAX_TIMESTAMP`]. Timestamps that
+/// decide what happens to an order (e.g: `expires_at`) must be read
+/// strictly. A corrupt order could expire at the wrong time otherwise.
+pub(crate) fn saturating_timestamp(value: i64) -> IsoDatetime {
+    IsoDatetime::from_unix_timestamp(value.clamp(0, MAX_TIMESTAMP))
+        .expect("Timestamps between the epoch and MAX_TIMESTAMP are valid")
+}
 
 pub trait IntoSqliteInteger {
     fn into_sqlite_integer(&self) -> Result<i64>;
@@ -13,89 +101,105 @@ where
     fn from_sqlite_integer(value: i64) -> Result<Self>;
 }
 
-impl IntoSqliteInteger for u8 {
-    fn into_sqlite_integer(&self) -> Result<i64> {
-        i64::try_from(*self).context(format!("Failed to fit {} in i64", self))
-    }
-}
-
-impl FromSqliteInteger for u8 {
-    fn from_sqlite_integer(value: i64) -> Result<Self> {
-        u8::try_from(value).context(format!("Failed to fit {} in i64", value))
-    }
-}
+/// Implements the conversions of an unsigned integer type
+macro_rules! unsigned_sqlite_integer {
+    ($type:ty) => {
+        impl IntoSqliteInteger for $type {
+            fn into_sqlite_integer(&self) -> Result<i64> {
+                i64::try_from(*self)
+                    .map_err(|_| ConversionError::out_of_range(stringify!($type), self))
+            }
+        }
 
-impl IntoSqliteInteger for u16 {
-    fn into_sqlite_integer(&self) -> Result<i64> {
-        i64::try_from(*self).context(format!("Failed to fit {} in i64", self))
-    }
+        impl FromSqliteInteger for $type {
+            fn from_sqlite_integer(value: i64) -> Result<Self> {
+                <$type>::try_from(value)
+                    .map_err(|_| ConversionError::corrupt(stringify!($type), value))
+            }
+        }
+    };
 }
 
-impl FromSqliteInteger for u16 {
-    fn from_sqlite_integer(value: i64) -> Result<Self> {
-        u16::try_from(value).context(format!("Failed to fit {} in i64", value))
-    }
-}
+unsigned_sqlite_integer!(u8);
+unsigned_sqlite_integer!(u16);
+unsigned_sqlite_integer!(u32);
+unsigned_sqlite_integer!(u64);
 
-impl IntoSqliteInteger for u32 {
+impl IntoSqliteInteger for OrderGeneration {
     fn into_sqlite_integer(&self) -> Result<i64> {
-        i64::try_from(*self).context(format!("Failed to fit {} in u32", self))
+        i64::try_from(self.0).map_err(|_| ConversionError::out_of_range("OrderGeneration", self))
     }
 }
 
-impl FromSqliteInteger for u32 {
+impl FromSqliteInteger for OrderGeneration {
     fn from_sqlite_integer(value: i64) -> Result<Self> {
-        u32::try_from(value).context(format!("Failed to fit {} in u32", value))
+        let generation =
+            u64::try_from(value).map_err(|_| ConversionError::corrupt("OrderGeneration", value))?;
+        Ok(Self(generation))
     }
 }
 
-impl IntoSqliteInteger for u64 {
+impl IntoSqliteInteger for PaymentGeneration {
     fn into_sqlite_integer(&self) -> Result<i64> {
-        i64::try_from(*self).context(format!("Failed to fit {} in i64", self))
+        i64::try_from(self.0).map_err(|_| ConversionError::out_of_range("PaymentGeneration", self))
     }
 }
 
-impl FromSqliteInteger for u64 {
+impl FromSqliteInteger for PaymentGeneration {
     fn from_sqlite_integer(value: i64) -> Result<Self> {
-        u64::try_from(value).context(format!("Failed to fit {} in i64", value))
+        let generation = u64::try_from(value)
+            .map_err(|_| ConversionError::corrupt("PaymentGeneration", value))?;
+        Ok(Self(generation))
     }
 }
 
 impl IntoSqliteInteger for SatAmount {
     fn into_sqlite_integer(&self) -> Result<i64> {
-        i64::try_from(self.sat_value()).context(format!("Failed fit {} in i64", self))
+        i64::try_from(self.sat_value())
+            .map_err(|_| ConversionError::out_of_range("SatAmount", self))
     }
 }
 
 impl FromSqliteInteger for SatAmount {
     fn from_sqlite_integer(value: i64) -> Result<Self> {
-        let vu64 = u64::try_from(value).context("Failed to represent negative SatAmount")?;
-        Ok(SatAmount::new(vu64))
+        let sat = u64::try_from(value).map_err(|_| ConversionError::corrupt("SatAmount", value))?;
+        Ok(SatAmount::new(sat))
     }
 }
 
 impl IntoSqliteInteger for MsatAmount {
     fn into_sqlite_integer(&self) -> Result<i64> {
-        i64::try_from(self.msat_value()).context(format!("Failed fit {} in i64", self))
+        i64::try_from(self.msat_value())
+            .map_err(|_| ConversionError::out_of_range("MsatAmount", self))
     }
 }
 
 impl FromSqliteInteger for MsatAmount {
     fn from_sqlite_integer(value: i64) -> Result<Self> {
-        let vu64 = u64::try_from(value).context("Failed to represent negative SatAmount")?;
-        Ok(MsatAmount::new(vu64))
+        let msat =
+            u64::try_from(value).map_err(|_| ConversionError::corrupt("MsatAmount", value))?;
+        Ok(MsatAmount::new(msat))
     }
 }
 
+/// Timestamps before the epoch are never written
 impl IntoSqliteInteger for IsoDatetime {
     fn into_sqlite_integer(&self) -> Result<i64> {
-        Ok(self.datetime().unix_timestamp())
+        let timestamp = self.unix_timestamp();
+        if timestamp < 0 {
+            return Err(ConversionError::out_of_range("IsoDatetime", timestamp));
+        }
+        Ok(timestamp)
     }
 }
 
 impl FromSqliteInteger for IsoDatetime {
     fn from_sqlite_integer(value: i64) -> Result<Self> {
+        if !(0..=MAX_TIMESTAMP).contains(&value) {
+            return Err(ConversionError::corrupt("IsoDatetime", value));
+        }
         IsoDatetime::from_unix_timestamp(value)
+            .map_err(|_| ConversionError::corrupt("IsoDatetime", value))
     }
 }
 
@@ -112,23 +216,31 @@ impl FromSqliteInteger for FeeRate {
     }
 }
 
-impl IntoSqliteInteger for OrderState {
+impl IntoSqliteInteger for Lsps1OrderState {
     fn into_sqlite_integer(&self) -> Result<i64> {
         Ok(match self {
-            OrderState::Created => 1,
-            OrderState::Completed => 2,
-            OrderState::Failed => 3,
+            Lsps1OrderState::Created => 1,
+            Lsps1OrderState::Completed => 2,
+            Lsps1OrderState::Failed => 3,
+            Lsps1OrderState::PendingOpen => 4,
+            Lsps1OrderState::ChannelOpening => 5,
+            Lsps1OrderState::Funding => 6,
+            Lsps1OrderState::AwaitingConfirmation => 7,
         })
     }
 }
 
-impl FromSqliteInteger for OrderState {
+impl FromSqliteInteger for Lsps1OrderState {
     fn from_sqlite_integer(value: i64) -> Result<Self> {
         match &value {
-            1 => Ok(OrderState::Created),
-            2 => Ok(OrderState::Completed),
-            3 => Ok(OrderState::Failed),
-            _ => Err(anyhow!("Unknown order state: {}", value)),
+            1 => Ok(Lsps1OrderState::Created),
+            2 => Ok(Lsps1OrderState::Completed),
+            3 => Ok(Lsps1OrderState::Failed),
+            4 => Ok(Lsps1OrderState::PendingOpen),
+            5 => Ok(Lsps1OrderState::ChannelOpening),
+            6 => Ok(Lsps1OrderState::Funding),
+            7 => Ok(Lsps1OrderState::AwaitingConfirmation),
+            _ => Err(ConversionError::corrupt("Lsps1OrderState", value)),
         }
     }
 }
@@ -151,7 +263,137 @@ impl FromSqliteInteger for PaymentState {
             2 => Ok(PaymentState::Hold),
             3 => Ok(PaymentState::Paid),
             4 => Ok(PaymentState::Refunded),
-            _ => Err(anyhow!("Unknown payment state: {}", value)),
+            _ => Err(ConversionError::corrupt("PaymentState", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conversion_error(err: anyhow::Error) -> ConversionError {
+        err.downcast::<ConversionError>().unwrap()
+    }
+
+    #[test]
+    fn unsigned_integers_at_the_boundaries() {
+        assert_eq!(u64::from_sqlite_integer(0).unwrap(), 0);
+        assert_eq!(u64::from_sqlite_integer(i64::MAX).unwrap(), i64::MAX as u64);
+        assert_eq!(
+            conversion_error(u64::from_sqlite_integer(-1).unwrap_err()),
+            ConversionError::DataCorruption {
+                what: "u64",
+                value: -1
+            }
+        );
+        assert!(u32::from_sqlite_integer(i64::MAX).is_err());
+
+        assert_eq!((i64::MAX as u64).into_sqlite_integer().unwrap(), i64::MAX);
+        let err = (i64::MAX as u64 + 1).into_sqlite_integer().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "u64 9223372036854775808 doesn't fit in a sqlite integer"
+        );
+        assert!(!is_data_corruption(&err));
+    }
+
+    #[test]
+    fn amounts_and_generations_at_the_boundaries() {
+        assert_eq!(
+            SatAmount::from_sqlite_integer(i64::MAX).unwrap(),
+            SatAmount::new(i64::MAX as u64)
+        );
+        assert_eq!(
+            MsatAmount::from_sqlite_integer(0).unwrap(),
+            MsatAmount::new(0)
+        );
+        assert_eq!(
+            OrderGeneration::from_sqlite_integer(i64::MAX).unwrap(),
+            OrderGeneration(i64::MAX as u64)
+        );
+        assert!(is_data_corruption(
+            &SatAmount::from_sqlite_integer(-1).unwrap_err()
+        ));
+        assert!(is_data_corruption(
+            &MsatAmount::from_sqlite_integer(-1).unwrap_err()
+        ));
+        assert!(is_data_corruption(
+            &PaymentGeneration::from_sqlite_integer(-1).unwrap_err()
+        ));
+
+        let too_large = i64::MAX as u64 + 1;
+        let err = SatAmount::new(too_large).into_sqlite_integer().unwrap_err();
+        assert!(matches!(
+            conversion_error(err),
+            ConversionError::RangeViolation {
+                what: "SatAmount",
+                ..
+            }
+        ));
+        let err = OrderGeneration(too_large)
+            .into_sqlite_integer()
+            .unwrap_err();
+        assert!(matches!(
+            conversion_error(err),
+            ConversionError::RangeViolation {
+                what: "OrderGeneration",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn timestamps_at_the_boundaries() {
+        let epoch = IsoDatetime::from_sqlite_integer(0).unwrap();
+        assert_eq!(epoch.into_sqlite_integer().unwrap(), 0);
+        let max = IsoDatetime::from_sqlite_integer(MAX_TIMESTAMP).unwrap();
+        assert_eq!(max.into_sqlite_integer().unwrap(), MAX_TIMESTAMP);
+
+        for value in [-1, MAX_TIMESTAMP + 1, i64::MAX] {
+            let err = IsoDatetime::from_sqlite_integer(value).unwrap_err();
+            assert_eq!(
+                conversion_error(err),
+                ConversionError::DataCorruption {
+                    what: "IsoDatetime",
+                    value
+                }
+            );
+        }
+
+        let before_epoch = IsoDatetime::from_unix_timestamp(-1).unwrap();
+        assert!(!is_data_corruption(
+            &before_epoch.into_sqlite_integer().unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn displayed_timestamps_saturate() {
+        assert_eq!(saturating_timestamp(-1), saturating_timestamp(0));
+        assert_eq!(saturating_timestamp(i64::MIN).unix_timestamp(), 0);
+        assert_eq!(
+            saturating_timestamp(i64::MAX).unix_timestamp(),
+            MAX_TIMESTAMP
+        );
+        assert_eq!(
+            saturating_timestamp(1_700_000_000).unix_timestamp(),
+            1_700_000_000
+        );
+    }
+
+    #[test]
+    fn unknown_states_are_corrupt() {
+        for value in [0, 8, i64::MAX] {
+            assert!(is_data_corruption(
+                &Lsps1OrderState::from_sqlite_integer(value).unwrap_err()
+            ));
         }
+        let err = PaymentState::from_sqlite_integer(5).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Stored value 5 is not a valid PaymentState"
+        );
+        // The error is still recognized when context is added
+        assert!(is_data_corruption(&err.context("Failed to read payment")));
     }
 }
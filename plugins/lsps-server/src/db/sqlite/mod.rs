@@ -1,12 +1,39 @@
 mod conversion;
 mod schema;
 use async_trait::async_trait;
+pub(crate) mod quarantine;
 pub(crate) mod queries;
+pub(crate) mod url;
+pub(crate) mod views;
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 
-use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqlitePool};
-use sqlx::Transaction;
+use sqlx::migrate::MigrateError;
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqliteLockingMode,
+    SqlitePool,
+};
+use sqlx::{Connection, Executor, Transaction};
+use uuid::Uuid;
+
+/// The number of times the migrations are attempted
+const MIGRATION_ATTEMPTS: u32 = 5;
+const MIGRATION_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a connection waits for a lock before sqlite returns SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long [`retry_on_busy`] retries an operation
+const BUSY_RETRY_DEADLINE: Duration = Duration::from_secs(10);
+const BUSY_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const BUSY_RETRY_MAX_BACKOFF: Duration = Duration::from_millis(500);
 
 #[derive(Clone)]
 pub struct Database {
@@ -18,6 +45,32 @@ pub trait Query<T> {
     async fn execute(self, executor: &mut Transaction<'static, Sqlite>) -> Result<T>;
 }
 
+/// The future that runs the body of [`Database::with_tx`]
+pub(crate) type TxFuture<'c, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'c>>;
+
+/// The transaction passed to the body of [`Database::with_tx`]
+///
+/// It dereferences to the underlying transaction. The lifetime `'a` allows
+/// the body to borrow data of the caller.
+pub(crate) struct ScopedTransaction<'a> {
+    tx: Transaction<'static, Sqlite>,
+    _borrowed: PhantomData<&'a ()>,
+}
+
+impl Deref for ScopedTransaction<'_> {
+    type Target = Transaction<'static, Sqlite>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl DerefMut for ScopedTransaction<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tx
+    }
+}
+
 impl Database {
     pub async fn connect_with_options(options: SqliteConnectOptions) -> Result<Self> {
         let pool = SqlitePool::connect_with(options).await?;
@@ -27,20 +80,275 @@ impl Database {
     pub async fn begin(&self) -> Result<Transaction<'static, Sqlite>> {
         Ok(self.pool.begin().await?)
     }
+
+    /// Runs `f` in a transaction that is named `name` in the logs
+    ///
+    /// The transaction is committed if `f` succeeds. It is rolled back if
+    /// `f` fails or if the returned future is dropped before it completes,
+    /// e.g. because a handler timed out. The outcome and the elapsed time
+    /// are logged, so a rollback never goes unnoticed.
+    pub(crate) async fn with_tx<'a, T, F>(&self, name: &str, f: F) -> Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut ScopedTransaction<'a>) -> TxFuture<'c, T>,
+    {
+        let outcome = TxOutcome::start(name);
+        let result = async {
+            let mut tx = ScopedTransaction {
+                tx: self.begin().await?,
+                _borrowed: PhantomData,
+            };
+            match f(&mut tx).await {
+                Ok(value) => {
+                    tx.tx.commit().await?;
+                    Ok(value)
+                }
+                Err(err) => {
+                    if let Err(rollback_err) = tx.tx.rollback().await {
+                        log::warn!(
+                            "Failed to roll back transaction '{}': {:?}",
+                            name,
+                            rollback_err
+                        );
+                    }
+                    Err(err)
+                }
+            }
+        }
+        .await;
+        outcome.finish(&result);
+        result
+    }
+
+    /// Closes all connections. Every later call to [`Database::begin`] fails
+    #[cfg(test)]
+    pub async fn close(&self) {
+        self.pool.close().await
+    }
+}
+
+/// Logs the outcome of a transaction that is run by [`Database::with_tx`]
+///
+/// If the guard is dropped before [`TxOutcome::finish`] was called the
+/// transaction was cancelled. Dropping the transaction rolls it back.
+struct TxOutcome<'n> {
+    name: &'n str,
+    start: Instant,
+    finished: bool,
+}
+
+impl<'n> TxOutcome<'n> {
+    fn start(name: &'n str) -> Self {
+        Self {
+            name,
+            start: Instant::now(),
+            finished: false,
+        }
+    }
+
+    fn finish<T>(mut self, result: &Result<T>) {
+        self.finished = true;
+        let elapsed = self.start.elapsed();
+        match result {
+            Ok(_) => log::debug!("Committed transaction '{}' in {:?}", self.name, elapsed),
+            Err(err) => log::warn!(
+                "Rolled back transaction '{}' after {:?}: {:?}",
+                self.name,
+                elapsed,
+                err
+            ),
+        }
+    }
+}
+
+impl Drop for TxOutcome<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            log::warn!(
+                "Rolled back transaction '{}' after {:?}: it was cancelled",
+                self.name,
+                self.start.elapsed()
+            );
+        }
+    }
+}
+
+/// Parses the connection string of the database
+///
+/// The database uses WAL-mode. Readers don't block the writer and the
+/// writer doesn't block readers. A connection waits up to [`BUSY_TIMEOUT`]
+/// for a lock held by another connection.
+pub fn connect_options(connection_string: &str) -> Result<SqliteConnectOptions> {
+    Ok(SqliteConnectOptions::from_str(connection_string)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(BUSY_TIMEOUT))
+}
+
+/// Runs `operation` again if it failed because the database was locked
+///
+/// Concurrent hooks can hold a lock for longer than the busy timeout.
+/// A transaction also fails immediately if another connection wrote to
+/// the database after the transaction started reading. Running such a
+/// transaction again from the start succeeds.
+///
+/// `operation` must run a complete transaction. The failed attempt was
+/// rolled back, so nothing is stored twice. The retries use a jittered
+/// backoff and stop after [`BUSY_RETRY_DEADLINE`].
+pub(crate) async fn retry_on_busy<T, F, Fut>(operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    retry_on_busy_until(BUSY_RETRY_DEADLINE, operation).await
+}
+
+async fn retry_on_busy_until<T, F, Fut>(deadline: Duration, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let mut backoff = BUSY_RETRY_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let err = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        let elapsed = start.elapsed();
+        if !is_busy_error(&err) || elapsed >= deadline {
+            return Err(err);
+        }
+
+        let sleep = jittered(backoff).min(deadline - elapsed);
+        log::debug!(
+            "Database is locked. Attempt {} failed, retrying in {:?}: {:?}",
+            attempt,
+            sleep,
+            err
+        );
+        tokio::time::sleep(sleep).await;
+        backoff = (backoff * 2).min(BUSY_RETRY_MAX_BACKOFF);
+        attempt += 1;
+    }
+}
+
+/// Returns a random duration between half of `backoff` and `backoff`
+///
+/// Operations that failed at the same time don't retry at the same time
+fn jittered(backoff: Duration) -> Duration {
+    let half_ms = (backoff.as_millis() / 2) as u64;
+    let jitter_ms = (Uuid::new_v4().as_u128() % u128::from(half_ms + 1)) as u64;
+    Duration::from_millis(half_ms + jitter_ms)
+}
+
+/// Runs the migration scripts
+///
+/// Another instance might be migrating the same database. The migrator
+/// reads the applied migrations before it applies the missing ones, so two
+/// migrators would apply the same script twice. We hold an exclusive lock on
+/// the database while migrating. A migrator that can't get the lock retries
+/// with backoff and skips the migrations the other instance has applied.
+pub async fn run_migrations(options: &SqliteConnectOptions) -> Result<()> {
+    let mut backoff = MIGRATION_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let result = async {
+            // In exclusive locking mode the lock is kept until the connection is closed
+            let options = options.clone().locking_mode(SqliteLockingMode::Exclusive);
+            let mut connection = SqliteConnection::connect_with(&options).await?;
+            connection.execute("BEGIN EXCLUSIVE; COMMIT;").await?;
+            sqlx::migrate!().run(&mut connection).await?;
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < MIGRATION_ATTEMPTS && is_concurrent_migration_error(&err) => {
+                log::info!(
+                    "Migration attempt {} failed, retrying in {:?}: {:?}",
+                    attempt,
+                    backoff,
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The version of the most recent migration script shipped with the plugin
+pub(crate) fn latest_migration_version() -> Option<i64> {
+    sqlx::migrate!()
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+}
+
+/// Errors that can be caused by another process holding the lock (SQLITE_BUSY or SQLITE_LOCKED)
+fn is_concurrent_migration_error(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<MigrateError>() {
+        Some(MigrateError::Execute(err)) => is_busy_sqlx_error(err),
+        Some(_) => false,
+        None => is_busy_error(err),
+    }
+}
+
+/// Errors that are caused by another connection holding the lock
+///
+/// The error might have been wrapped using `context`
+fn is_busy_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<sqlx::Error>())
+        .any(is_busy_sqlx_error)
+}
+
+/// SQLITE_BUSY or SQLITE_LOCKED
+fn is_busy_sqlx_error(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => db_err
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .map(|code| {
+                // Extended result codes keep the primary code in the lowest byte
+                let primary_code = code & 0xff;
+                primary_code == 5 || primary_code == 6
+            })
+            .unwrap_or(false),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
 
     use super::*;
 
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::{anyhow, Context};
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
     use uuid::Uuid;
 
     use lsp_primitives::lsps0::common_schemas::{IsoDatetime, PublicKey, SatAmount};
-    use lsp_primitives::lsps1::schema::{OrderState, PaymentState};
+    use lsp_primitives::lsps1::schema::{PaymentState, MAX_TOKEN_LENGTH};
 
-    use crate::db::schema::{Lsps1Order, Lsps1PaymentDetails};
-    use crate::db::sqlite::queries::{GetOrderQuery, Lsps1CreateOrderQuery};
+    use crate::custom_msg::error::test_support::capture_logs;
+    use crate::db::schema::{
+        InvoiceLabel, Lsps1FailureReason, Lsps1Order, Lsps1OrderState, Lsps1PaymentDetails,
+        OrderGeneration, PaymentGeneration,
+    };
+    use crate::db::sqlite::conversion::IntoSqliteInteger;
+    use crate::db::sqlite::queries::{
+        GetOrderQuery, GetPaymentDetailsQuery, GetRecentOrdersQuery, Lsps1CreateOrderQuery,
+        UpdateOrderStateQuery, UpdatePaymentStateQuery,
+    };
 
     pub async fn get_db() -> Database {
         let options = SqliteConnectOptions::default()
@@ -49,6 +357,43 @@ mod test {
         Database::connect_with_options(options).await.unwrap()
     }
 
+    /// Returns two handles to a new database file
+    pub async fn get_temp_db() -> (Database, Database) {
+        let path = std::env::temp_dir().join(format!("lsp_server_test_{}.db", Uuid::new_v4()));
+        let options = SqliteConnectOptions::default()
+            .filename(path)
+            .create_if_missing(true);
+        run_migrations(&options).await.unwrap();
+
+        let db_1 = Database::connect_with_options(options.clone())
+            .await
+            .unwrap();
+        let db_2 = Database::connect_with_options(options).await.unwrap();
+        (db_1, db_2)
+    }
+
+    #[tokio::test]
+    async fn migrations_can_run_in_parallel() {
+        let path = std::env::temp_dir().join(format!("lsp_server_test_{}.db", Uuid::new_v4()));
+        let options = SqliteConnectOptions::default()
+            .filename(path)
+            .create_if_missing(true);
+
+        let results = tokio::join!(
+            run_migrations(&options),
+            run_migrations(&options),
+            run_migrations(&options),
+            run_migrations(&options)
+        );
+        results.0.unwrap();
+        results.1.unwrap();
+        results.2.unwrap();
+        results.3.unwrap();
+
+        // Running them again is a no-op
+        run_migrations(&options).await.unwrap();
+    }
+
     pub fn create_test_order() -> Lsps1Order {
         // Create the order_uuid
         let uuid = Uuid::new_v4();
@@ -73,8 +418,9 @@ mod test {
             token: None,
             channel_expiry_blocks: 6 * 24 * 30,
             announce_channel: false,
-            order_state: OrderState::Created,
-            generation: 0,
+            order_state: Lsps1OrderState::Created,
+            failure_reason: None,
+            generation: OrderGeneration(0),
         }
     }
 
@@ -84,12 +430,12 @@ mod test {
             fee_total_sat: SatAmount::new(500),
             order_total_sat: SatAmount::new(500),
             bolt11_invoice: format!("bolt11_invoice.{}", order.uuid),
-            bolt11_invoice_label: format!("test.order.{}", order.uuid),
+            bolt11_invoice_label: InvoiceLabel::for_order(&order.uuid).unwrap(),
             onchain_address: None,
             minimum_fee_for_0conf: None,
             onchain_block_confirmations_required: None,
             state: PaymentState::ExpectPayment,
-            generation: 0,
+            generation: PaymentGeneration(0),
         }
     }
 
@@ -146,4 +492,551 @@ mod test {
         assert_eq!(order.channel_expiry_blocks, 6 * 24 * 30);
         assert_eq!(order.announce_channel, false);
     }
+
+    async fn create_order_with_token(db: &Database, token: Option<String>) -> Result<Uuid> {
+        let mut query = create_order_query();
+        query.order.token = token;
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await?;
+        query.execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(uuid)
+    }
+
+    async fn get_token(db: &Database, uuid: Uuid) -> Option<String> {
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery { order_id: uuid }
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        order.token
+    }
+
+    #[tokio::test]
+    async fn test_absent_and_empty_token_are_distinct() {
+        let db = get_db().await;
+
+        let uuid = create_order_with_token(&db, None).await.unwrap();
+        assert_eq!(get_token(&db, uuid).await, None);
+
+        let uuid = create_order_with_token(&db, Some("".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(get_token(&db, uuid).await, Some("".to_string()));
+
+        let uuid = create_order_with_token(&db, Some("token".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(get_token(&db, uuid).await, Some("token".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_token_is_rejected() {
+        let db = get_db().await;
+
+        let token = "a".repeat(MAX_TOKEN_LENGTH);
+        create_order_with_token(&db, Some(token)).await.unwrap();
+
+        let token = "a".repeat(MAX_TOKEN_LENGTH + 1);
+        let result = create_order_with_token(&db, Some(token)).await;
+        assert!(result.is_err());
+    }
+
+    /// Returns two handles to a new database that is configured like in production
+    ///
+    /// The first handle gives up quickly if the database is locked
+    async fn get_contended_db() -> (Database, Database) {
+        let path = std::env::temp_dir().join(format!("lsp_server_test_{}.db", Uuid::new_v4()));
+        let options = connect_options(&format!("sqlite://{}", path.display())).unwrap();
+        run_migrations(&options).await.unwrap();
+
+        let impatient = options.clone().busy_timeout(Duration::from_millis(10));
+        let db_1 = Database::connect_with_options(impatient).await.unwrap();
+        let db_2 = Database::connect_with_options(options).await.unwrap();
+        (db_1, db_2)
+    }
+
+    /// Writes to the database and holds the lock until `release` is called
+    ///
+    /// Returns once the lock is held
+    async fn hold_write_lock(db: Database) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let (locked_sender, locked) = oneshot::channel();
+        let (release, released) = oneshot::channel::<()>();
+        let writer = tokio::spawn(async move {
+            let mut tx = db.begin().await.unwrap();
+            create_order_query().execute(&mut tx).await.unwrap();
+            locked_sender.send(()).unwrap();
+            let _ = released.await;
+            tx.commit().await.unwrap();
+        });
+        locked.await.unwrap();
+        (release, writer)
+    }
+
+    /// Moves the order to `PendingOpen` in a single transaction
+    async fn move_to_pending_open(db: &Database, uuid: Uuid, attempts: &AtomicUsize) -> Result<()> {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        let mut tx = db.begin().await?;
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await?
+            .context("Order not found")?;
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: order.generation,
+            failure_reason: None,
+            changed_at: IsoDatetime::now(),
+        }
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn count_order_states(db: &Database, uuid: Uuid) -> i64 {
+        let mut tx = db.begin().await.unwrap();
+        let count = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM lsps1_order_state AS os
+             JOIN lsps1_order AS o ON o.id = os.order_id
+             WHERE o.uuid = ?1",
+        )
+        .bind(uuid.to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        count
+    }
+
+    #[tokio::test]
+    async fn locked_operation_is_retried_until_it_succeeds() {
+        let (db, other_db) = get_contended_db().await;
+        let uuid = create_order_with_token(&db, None).await.unwrap();
+
+        // Another connection holds the lock for longer than the busy timeout
+        let (release, writer) = hold_write_lock(other_db).await;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            release.send(()).unwrap();
+        });
+
+        let attempts = &AtomicUsize::new(0);
+        let db_ref = &db;
+        retry_on_busy(move || move_to_pending_open(db_ref, uuid, attempts))
+            .await
+            .unwrap();
+        writer.await.unwrap();
+        assert!(attempts.load(Ordering::SeqCst) > 1);
+
+        // The failed attempts didn't store a state
+        assert_eq!(count_order_states(&db, uuid).await, 2);
+        let order_state = {
+            let mut tx = db.begin().await.unwrap();
+            let order = GetOrderQuery::by_uuid(uuid)
+                .execute(&mut tx)
+                .await
+                .unwrap()
+                .unwrap();
+            tx.commit().await.unwrap();
+            order.order_state
+        };
+        assert_eq!(order_state, Lsps1OrderState::PendingOpen);
+    }
+
+    #[tokio::test]
+    async fn retry_stops_at_deadline() {
+        let (db, other_db) = get_contended_db().await;
+        let uuid = create_order_with_token(&db, None).await.unwrap();
+        let (release, writer) = hold_write_lock(other_db).await;
+
+        let attempts = &AtomicUsize::new(0);
+        let db_ref = &db;
+        let err = retry_on_busy_until(Duration::from_millis(200), move || {
+            move_to_pending_open(db_ref, uuid, attempts)
+        })
+        .await
+        .unwrap_err();
+        assert!(is_busy_error(&err), "Unexpected error: {:?}", err);
+        assert!(attempts.load(Ordering::SeqCst) > 1);
+
+        release.send(()).unwrap();
+        writer.await.unwrap();
+        assert_eq!(count_order_states(&db, uuid).await, 1);
+    }
+
+    /// Moves the order and its payment to their next state in a single transaction
+    async fn transition(db: &Database, uuid: Uuid) -> Result<()> {
+        let mut tx = db.begin().await?;
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await?
+            .context("Order not found")?;
+        let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await?
+            .context("Payment not found")?;
+
+        let (order_state, payment_state) = match order.generation.0 % 2 {
+            0 => (Lsps1OrderState::PendingOpen, PaymentState::Hold),
+            _ => (Lsps1OrderState::ChannelOpening, PaymentState::Paid),
+        };
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: order_state,
+            generation: order.generation,
+            failure_reason: None,
+            changed_at: IsoDatetime::now(),
+        }
+        .execute(&mut tx)
+        .await?;
+        UpdatePaymentStateQuery {
+            state: payment_state,
+            generation: payment.generation,
+            label: payment.bolt11_invoice_label,
+            changed_at: IsoDatetime::now(),
+        }
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Reads the order and its payment
+    async fn get_order_and_payment(db: &Database, uuid: Uuid) -> (Lsps1Order, Lsps1PaymentDetails) {
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        (order, payment)
+    }
+
+    /// The order state, order generation, payment state and payment
+    /// generation of the most recent rows in the state history
+    async fn latest_history(db: &Database, uuid: Uuid) -> (i64, i64, i64, i64) {
+        let mut tx = db.begin().await.unwrap();
+        let latest = sqlx::query_as(
+            "SELECT
+                os.order_state_enum_id, os.generation, ps.payment_state, ps.generation
+             FROM lsps1_order AS o
+             JOIN lsps1_order_state AS os ON os.order_id = o.id
+             JOIN lsps1_payment_details AS pd ON pd.order_id = o.id
+             JOIN lsps1_payment_state AS ps ON ps.payment_details_id = pd.id
+             WHERE o.uuid = ?1
+             ORDER BY os.generation DESC, ps.generation DESC
+             LIMIT 1",
+        )
+        .bind(uuid.to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        latest
+    }
+
+    #[tokio::test]
+    async fn latest_state_matches_history_under_concurrent_transitions() {
+        let (db, other_db) = get_contended_db().await;
+        let uuid = create_order_with_token(&db, None).await.unwrap();
+
+        // Tasks on both handles race to move the same order
+        let tasks: Vec<_> = [db.clone(), other_db.clone(), db.clone(), other_db]
+            .into_iter()
+            .map(|db| {
+                tokio::spawn(async move {
+                    for _ in 0..5 {
+                        loop {
+                            match transition(&db, uuid).await {
+                                Ok(()) => break,
+                                Err(err)
+                                    if is_busy_error(&err)
+                                        || err.to_string().contains("modified concurrently") =>
+                                {
+                                    tokio::task::yield_now().await
+                                }
+                                Err(err) => panic!("Unexpected error: {:?}", err),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let (order, payment) = get_order_and_payment(&db, uuid).await;
+        assert_eq!(order.generation, OrderGeneration(20));
+        assert_eq!(payment.generation, PaymentGeneration(20));
+        assert_eq!(order.order_state, Lsps1OrderState::ChannelOpening);
+        assert_eq!(payment.state, PaymentState::Paid);
+        // Every transition is kept in the history
+        assert_eq!(count_order_states(&db, uuid).await, 21);
+        assert_eq!(
+            latest_history(&db, uuid).await,
+            (
+                order.order_state.into_sqlite_integer().unwrap(),
+                order.generation.into_sqlite_integer().unwrap(),
+                payment.state.into_sqlite_integer().unwrap(),
+                payment.generation.into_sqlite_integer().unwrap(),
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_do_not_use_the_state_history() {
+        let (db, _) = get_temp_db().await;
+        let uuid = create_order_with_token(&db, None).await.unwrap();
+        transition(&db, uuid).await.unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        sqlx::query("DELETE FROM lsps1_order_state")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM lsps1_payment_state")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(order.order_state, Lsps1OrderState::PendingOpen);
+        assert_eq!(order.generation, OrderGeneration(1));
+
+        let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state, PaymentState::Hold);
+        assert_eq!(payment.generation, PaymentGeneration(1));
+
+        let payment = GetPaymentDetailsQuery::by_label(payment.bolt11_invoice_label)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.order_uuid, uuid);
+        assert_eq!(payment.state, PaymentState::Hold);
+
+        let orders = GetRecentOrdersQuery::last(1)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(orders[0].uuid, uuid);
+        assert_eq!(orders[0].order_state, Lsps1OrderState::PendingOpen);
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn migration_backfills_the_latest_state() {
+        const LATEST_STATE_MIGRATION: i64 = 20240515120000;
+
+        let path = std::env::temp_dir().join(format!("lsp_server_test_{}.db", Uuid::new_v4()));
+        let options = SqliteConnectOptions::default()
+            .filename(path)
+            .create_if_missing(true);
+
+        // A database that was created before the latest state was stored on the order
+        let mut migrator = sqlx::migrate!();
+        migrator.migrations = migrator
+            .migrations
+            .iter()
+            .filter(|migration| migration.version < LATEST_STATE_MIGRATION)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into();
+        let mut connection = SqliteConnection::connect_with(&options).await.unwrap();
+        migrator.run(&mut connection).await.unwrap();
+
+        let uuid = Uuid::new_v4();
+        let label = InvoiceLabel::for_order(&uuid).unwrap();
+        sqlx::query(
+            "INSERT INTO lsps1_order (
+                id, uuid, client_node_id, lsp_balance_sat, client_balance_sat,
+                funding_confirms_within_blocks, required_channel_confirmations,
+                channel_expiry_blocks, announce_channel, created_at, expires_at
+            ) VALUES (1, ?1, ?2, 100000, 0, 6, 0, 1000, 0, 1700000000, 1700003600)",
+        )
+        .bind(uuid.to_string())
+        .bind(create_test_order().client_node_id.to_hex())
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        // The rows of the history aren't ordered by generation
+        sqlx::query(
+            "INSERT INTO lsps1_order_state
+                (order_id, order_state_enum_id, created_at, generation, failure_reason)
+            VALUES
+                (1, 1, 1700000000, 0, NULL),
+                (1, 3, 1700003700, 2, 'order_expired'),
+                (1, 4, 1700000100, 1, NULL)",
+        )
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO lsps1_payment_details (
+                id, order_id, fee_total_sat, order_total_sat,
+                bolt11_invoice, bolt11_invoice_label
+            ) VALUES (1, 1, 500, 500, 'bolt11_invoice', ?1)",
+        )
+        .bind(label.as_str())
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO lsps1_payment_state
+                (payment_details_id, payment_state, created_at, generation)
+            VALUES
+                (1, 1, 1700000000, 0),
+                (1, 3, 1700000100, 1)",
+        )
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        connection.close().await.unwrap();
+
+        run_migrations(&options).await.unwrap();
+        let db = Database::connect_with_options(options).await.unwrap();
+
+        let (order, payment) = get_order_and_payment(&db, uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(order.failure_reason, Some(Lsps1FailureReason::OrderExpired));
+        assert_eq!(order.generation, OrderGeneration(2));
+        assert_eq!(payment.state, PaymentState::Paid);
+        assert_eq!(payment.generation, PaymentGeneration(1));
+
+        // The views read the backfilled columns
+        let mut tx = db.begin().await.unwrap();
+        let viewed: (String, i64, i64) = sqlx::query_as(
+            "SELECT order_state_name, generation, state_changed_at
+            FROM v_lsps1_orders_latest WHERE uuid = ?1",
+        )
+        .bind(uuid.to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(viewed, ("FAILED".to_string(), 2, 1700003700));
+        let viewed: (String, i64, i64) = sqlx::query_as(
+            "SELECT payment_state_name, generation, state_changed_at
+            FROM v_lsps1_payments_latest WHERE order_uuid = ?1",
+        )
+        .bind(uuid.to_string())
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(viewed, ("PAID".to_string(), 1, 1700000100));
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let attempts = &AtomicUsize::new(0);
+        let err = retry_on_busy(move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(anyhow!("Order not found"))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Order not found");
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    async fn order_exists(db: &Database, uuid: Uuid) -> bool {
+        db.with_tx("test_get_order", move |tx| {
+            Box::pin(async move { GetOrderQuery::by_uuid(uuid).execute(tx).await })
+        })
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    #[tokio::test]
+    async fn successful_transaction_is_committed() {
+        let logs = capture_logs();
+        let (db, _) = get_temp_db().await;
+        let query = &create_order_query();
+        let name = format!("create_order_{}", query.order.uuid);
+
+        db.with_tx(&name, move |tx| {
+            Box::pin(async move { query.execute(tx).await })
+        })
+        .await
+        .unwrap();
+
+        assert!(order_exists(&db, query.order.uuid).await);
+        let logged = logs.containing(&format!("transaction '{}'", name));
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("DEBUG Committed"));
+    }
+
+    #[tokio::test]
+    async fn failed_transaction_leaves_no_partial_rows() {
+        let logs = capture_logs();
+        let (db, _) = get_temp_db().await;
+        let query = &create_order_query();
+        let name = format!("create_order_{}", query.order.uuid);
+
+        let err = db
+            .with_tx(&name, move |tx| {
+                Box::pin(async move {
+                    query.execute(tx).await?;
+                    Err::<(), _>(anyhow!("Invoice disappeared")).context("Failed to store order")
+                })
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to store order");
+
+        assert!(!order_exists(&db, query.order.uuid).await);
+        let logged = logs.containing(&format!("transaction '{}'", name));
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("WARN Rolled back"));
+        // The log includes the chain of the error
+        assert!(logged[0].contains("Failed to store order"));
+        assert!(logged[0].contains("Invoice disappeared"));
+    }
+
+    #[tokio::test]
+    async fn cancelled_transaction_is_rolled_back() {
+        let logs = capture_logs();
+        let (db, _) = get_temp_db().await;
+        let query = &create_order_query();
+        let name = format!("create_order_{}", query.order.uuid);
+
+        let result = tokio::time::timeout(
+            Duration::from_millis(100),
+            db.with_tx(&name, move |tx| {
+                Box::pin(async move {
+                    query.execute(tx).await?;
+                    std::future::pending::<()>().await;
+                    Ok(())
+                })
+            }),
+        )
+        .await;
+        assert!(result.is_err());
+
+        assert!(!order_exists(&db, query.order.uuid).await);
+        let logged = logs.containing(&format!("transaction '{}'", name));
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("WARN Rolled back"));
+        assert!(logged[0].contains("cancelled"));
+    }
 }
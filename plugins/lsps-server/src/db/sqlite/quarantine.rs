@@ -0,0 +1,224 @@
+//! Quarantines rows that can't be converted
+//!
+//! A single corrupt row (e.g: an invalid uuid written by a manual edit)
+//! shouldn't make every listing fail. Queries that return many rows convert
+//! each row on its own. The rows that fail are skipped and reported as
+//! [`CorruptRow`]s next to the healthy rows.
+//!
+//! Queries that return a single row stay strict. They fail with the
+//! [`CorruptRow`]. A handler that serves a peer records it in the
+//! [`Quarantine`] instead of failing with an internal error.
+//! See [`crate::custom_msg::error::HandlerError::quarantine_corrupt_row`].
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use cln_plugin::Plugin;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::db::sqlite::queries::{GetActiveLeasesQuery, GetRecentOrdersQuery};
+use crate::state::PluginState;
+
+/// A row that couldn't be converted
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct CorruptRow {
+    pub(crate) table: &'static str,
+    pub(crate) rowid: i64,
+    pub(crate) error: String,
+}
+
+impl std::fmt::Display for CorruptRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to convert row {} of '{}': {}",
+            self.rowid, self.table, self.error
+        )
+    }
+}
+
+impl std::error::Error for CorruptRow {}
+
+/// The converted rows of a query and the rows that failed to convert
+#[derive(Debug)]
+pub(crate) struct Quarantined<T> {
+    pub(crate) rows: Vec<T>,
+    pub(crate) corrupt_rows: Vec<CorruptRow>,
+}
+
+impl<T> Quarantined<T> {
+    /// Converts every row of `table` and quarantines the ones that fail
+    pub(crate) fn convert<R, I, F, C>(table: &'static str, rows: I, rowid: F, convert: C) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        F: Fn(&R) -> i64,
+        C: Fn(R) -> Result<T>,
+    {
+        let mut result = Self {
+            rows: Vec::new(),
+            corrupt_rows: Vec::new(),
+        };
+        for row in rows {
+            let id = rowid(&row);
+            match convert(row) {
+                Ok(value) => result.rows.push(value),
+                Err(err) => result.corrupt_rows.push(CorruptRow {
+                    table,
+                    rowid: id,
+                    error: format!("{:#}", err),
+                }),
+            }
+        }
+        result
+    }
+
+    /// Fails with the first [`CorruptRow`] if any row is corrupt
+    pub(crate) fn into_strict(self) -> Result<Vec<T>> {
+        match self.corrupt_rows.into_iter().next() {
+            Some(row) => Err(row.into()),
+            None => Ok(self.rows),
+        }
+    }
+}
+
+/// The corrupt rows the plugin has seen since it started
+#[derive(Default)]
+pub(crate) struct Quarantine {
+    rows: Mutex<BTreeMap<(&'static str, i64), CorruptRow>>,
+}
+
+impl Quarantine {
+    /// Remembers `corrupt_rows`
+    ///
+    /// A row is only logged the first time it is seen
+    pub(crate) fn record(&self, corrupt_rows: &[CorruptRow]) {
+        let mut rows = self.rows.lock().unwrap();
+        for row in corrupt_rows {
+            let key = (row.table, row.rowid);
+            if !rows.contains_key(&key) {
+                log::warn!(
+                    "Quarantined row {} of '{}': {}",
+                    row.rowid,
+                    row.table,
+                    row.error
+                );
+                rows.insert(key, row.clone());
+            }
+        }
+    }
+
+    /// Replaces the known rows with the result of a full scan
+    ///
+    /// Rows that were repaired are forgotten
+    pub(crate) fn record_scan(&self, corrupt_rows: &[CorruptRow]) {
+        {
+            let mut rows = self.rows.lock().unwrap();
+            rows.retain(|_, known| corrupt_rows.contains(known));
+        }
+        self.record(corrupt_rows)
+    }
+
+    /// The quarantined rows ordered by table and rowid
+    pub(crate) fn list(&self) -> Vec<CorruptRow> {
+        self.rows.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Handles `lsps1-admin-quarantine-list`
+///
+/// Scans all orders and leases before listing the quarantined rows
+pub(crate) async fn lsps1_admin_quarantine_list(
+    plugin: Plugin<PluginState>,
+    _request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let state = plugin.state();
+    let corrupt_rows = scan_corrupt_rows(state).await?;
+    state.quarantine.record_scan(&corrupt_rows);
+    Ok(json!({ "corrupt_rows": state.quarantine.list() }))
+}
+
+async fn scan_corrupt_rows(state: &PluginState) -> Result<Vec<CorruptRow>> {
+    let mut tx = state.database.begin().await?;
+    let orders = GetRecentOrdersQuery::last(u32::MAX)
+        .execute_quarantined(&mut tx)
+        .await?;
+    let leases = GetActiveLeasesQuery::at_blockheight(0)
+        .execute_quarantined(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let mut corrupt_rows = orders.corrupt_rows;
+    corrupt_rows.extend(leases.corrupt_rows);
+    Ok(corrupt_rows)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::state::test_support::test_state;
+
+    fn corrupt_row(rowid: i64, error: &str) -> CorruptRow {
+        CorruptRow {
+            table: "lsps1_order",
+            rowid,
+            error: error.to_string(),
+        }
+    }
+
+    #[test]
+    fn convert_quarantines_failing_rows() {
+        let result = Quarantined::convert(
+            "lsps1_order",
+            vec![(1, "1"), (2, "two"), (3, "3")],
+            |row| row.0,
+            |row| Ok(row.1.parse::<u32>()?),
+        );
+        assert_eq!(result.rows, vec![1, 3]);
+        assert_eq!(result.corrupt_rows.len(), 1);
+        assert_eq!(result.corrupt_rows[0].rowid, 2);
+
+        let err = result.into_strict().unwrap_err();
+        assert!(err.to_string().contains("row 2 of 'lsps1_order'"));
+        assert_eq!(err.downcast_ref::<CorruptRow>().unwrap().rowid, 2);
+    }
+
+    #[test]
+    fn scan_forgets_repaired_rows() {
+        let quarantine = Quarantine::default();
+        quarantine.record(&[corrupt_row(2, "bad uuid"), corrupt_row(1, "bad key")]);
+        // Seeing a row again doesn't replace it
+        quarantine.record(&[corrupt_row(2, "other error")]);
+        assert_eq!(
+            quarantine.list(),
+            vec![corrupt_row(1, "bad key"), corrupt_row(2, "bad uuid")]
+        );
+
+        quarantine.record_scan(&[corrupt_row(2, "bad uuid")]);
+        assert_eq!(quarantine.list(), vec![corrupt_row(2, "bad uuid")]);
+    }
+
+    #[tokio::test]
+    async fn scan_finds_corrupt_orders() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+        let healthy = create_order_query();
+        healthy.execute(&mut tx).await.unwrap();
+        let corrupt = create_order_query();
+        let corrupt_uuid = corrupt.order.uuid;
+        corrupt.execute(&mut tx).await.unwrap();
+        sqlx::query("UPDATE lsps1_order SET client_node_id = 'not-a-node-id' WHERE uuid = ?1")
+            .bind(corrupt_uuid.to_string())
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let state = test_state(db);
+        let corrupt_rows = scan_corrupt_rows(&state).await.unwrap();
+        assert_eq!(corrupt_rows.len(), 1);
+        assert_eq!(corrupt_rows[0].table, "lsps1_order");
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+/// A block that was seen by the plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChainBlock {
+    pub(crate) height: u32,
+    pub(crate) hash: String,
+}
+
+/// Finds the block with the highest height
+pub struct GetChainTipQuery;
+
+impl GetChainTipQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<ChainBlock>> {
+        let row =
+            sqlx::query!(r#"SELECT height, hash FROM lsps1_block ORDER BY height DESC LIMIT 1"#)
+                .fetch_optional(&mut **tx)
+                .await
+                .context("Failed to execute query")?;
+
+        row.map(|row| {
+            Ok(ChainBlock {
+                height: u32::try_from(row.height)?,
+                hash: row.hash,
+            })
+        })
+        .transpose()
+    }
+}
+
+/// Finds the block at `height`
+pub struct GetBlockQuery {
+    pub(crate) height: u32,
+}
+
+impl GetBlockQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<ChainBlock>> {
+        let height = i64::from(self.height);
+        let row = sqlx::query!(
+            r#"SELECT height, hash FROM lsps1_block WHERE height = ?1"#,
+            height
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        row.map(|row| {
+            Ok(ChainBlock {
+                height: u32::try_from(row.height)?,
+                hash: row.hash,
+            })
+        })
+        .transpose()
+    }
+}
+
+/// Stores `block` as the new chain tip
+///
+/// Blocks at or above the height of `block` were reorged out and are
+/// removed. Only the `keep` most recent blocks are kept.
+pub struct RecordBlockQuery {
+    pub(crate) block: ChainBlock,
+    pub(crate) keep: u32,
+}
+
+impl RecordBlockQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let height = i64::from(self.block.height);
+        let oldest = height - i64::from(self.keep);
+
+        sqlx::query!(
+            r#"DELETE FROM lsps1_block WHERE height >= ?1 OR height <= ?2"#,
+            height,
+            oldest
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        sqlx::query!(
+            r#"INSERT INTO lsps1_block (height, hash) VALUES (?1, ?2)"#,
+            height,
+            self.block.hash
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::test::get_temp_db;
+
+    fn block(height: u32, hash: &str) -> ChainBlock {
+        ChainBlock {
+            height,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn record_blocks_and_reorgs() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+        assert_eq!(GetChainTipQuery.execute(&mut tx).await.unwrap(), None);
+
+        for (height, hash) in [(100, "a"), (101, "b"), (102, "c"), (103, "d")] {
+            RecordBlockQuery {
+                block: block(height, hash),
+                keep: 3,
+            }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            GetChainTipQuery.execute(&mut tx).await.unwrap(),
+            Some(block(103, "d"))
+        );
+        // Only the 3 most recent blocks are kept
+        let forgotten = GetBlockQuery { height: 100 }.execute(&mut tx).await;
+        assert_eq!(forgotten.unwrap(), None);
+
+        // A block at a known height replaces the blocks above it
+        RecordBlockQuery {
+            block: block(102, "c2"),
+            keep: 3,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        assert_eq!(
+            GetChainTipQuery.execute(&mut tx).await.unwrap(),
+            Some(block(102, "c2"))
+        );
+        assert_eq!(
+            GetBlockQuery { height: 101 }
+                .execute(&mut tx)
+                .await
+                .unwrap(),
+            Some(block(101, "b"))
+        );
+        assert_eq!(
+            GetBlockQuery { height: 103 }
+                .execute(&mut tx)
+                .await
+                .unwrap(),
+            None
+        );
+        tx.commit().await.unwrap();
+    }
+}
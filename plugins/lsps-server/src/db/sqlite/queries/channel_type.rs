@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Context, Result};
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps1::schema::ChannelTypeFeature;
+
+/// Stores the channel type the client requested for an order
+pub struct SetChannelTypeQuery {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) channel_type: Vec<ChannelTypeFeature>,
+}
+
+impl SetChannelTypeQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let uuid = self.order_uuid.to_string();
+        let channel_type = serde_json::to_string(&self.channel_type)?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE lsps1_order
+            SET channel_type = ?1
+            WHERE uuid = ?2
+            "#,
+            channel_type,
+            uuid
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => Ok(()),
+            _ => Err(anyhow!(
+                "Failed to find order '{}' and could not store channel type",
+                self.order_uuid
+            )),
+        }
+    }
+}
+
+/// Returns the channel type the client requested for an order
+///
+/// Returns `None` if the order doesn't exist or if the client
+/// didn't request a channel type
+pub struct GetChannelTypeQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetChannelTypeQuery {
+    pub(crate) fn by_uuid(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<Vec<ChannelTypeFeature>>> {
+        let uuid = self.order_uuid.to_string();
+        let result = sqlx::query!(
+            r#"
+            SELECT channel_type
+            FROM lsps1_order
+            WHERE uuid = ?1
+            "#,
+            uuid
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        result
+            .and_then(|row| row.channel_type)
+            .map(|channel_type| {
+                serde_json::from_str(&channel_type).context("Invalid channel type in database")
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn channel_type_is_stored_with_the_order() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        // Orders are created without a channel type
+        let stored = GetChannelTypeQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, None);
+
+        let channel_type = vec![ChannelTypeFeature::Anchors, ChannelTypeFeature::Zeroconf];
+        SetChannelTypeQuery {
+            order_uuid: uuid,
+            channel_type: channel_type.clone(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let stored = GetChannelTypeQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, Some(channel_type.clone()));
+
+        // Unknown orders have no channel type
+        let unknown = Uuid::new_v4();
+        let result = SetChannelTypeQuery {
+            order_uuid: unknown,
+            channel_type,
+        }
+        .execute(&mut tx)
+        .await;
+        assert!(result.is_err());
+        tx.commit().await.unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Result};
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+/// Claims the database for a single instance of the server
+///
+/// The claim succeeds if the database isn't claimed yet, if it is claimed
+/// by the same instance or if the heartbeat of the other instance is older
+/// than `stale_before`. Executing the query again refreshes the heartbeat.
+pub(crate) struct ClaimInstanceQuery {
+    instance_id: String,
+    now: IsoDatetime,
+    stale_before: IsoDatetime,
+    force: bool,
+}
+
+/// The instance that currently holds the claim
+#[derive(Debug, Clone)]
+pub(crate) struct InstanceClaim {
+    pub(crate) instance_id: String,
+    pub(crate) heartbeat_at: IsoDatetime,
+}
+
+impl ClaimInstanceQuery {
+    pub(crate) fn new(instance_id: String, now: IsoDatetime, stale_before: IsoDatetime) -> Self {
+        Self {
+            instance_id,
+            now,
+            stale_before,
+            force: false,
+        }
+    }
+
+    /// Take over the claim even if the other instance is still alive
+    pub(crate) fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Returns the claim of the other instance if the database is in use
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Option<InstanceClaim>> {
+        let now = self.now.unix_timestamp();
+        let stale_before = self.stale_before.unix_timestamp();
+
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO lsp_server_instance (id, instance_id, started_at, heartbeat_at)
+            VALUES (1, ?1, ?2, ?2)
+            ON CONFLICT(id) DO UPDATE SET
+                started_at = CASE
+                    WHEN lsp_server_instance.instance_id = excluded.instance_id
+                    THEN lsp_server_instance.started_at
+                    ELSE excluded.started_at END,
+                instance_id = excluded.instance_id,
+                heartbeat_at = excluded.heartbeat_at
+            WHERE lsp_server_instance.instance_id = excluded.instance_id
+                OR lsp_server_instance.heartbeat_at < ?3
+                OR ?4
+            "#,
+            self.instance_id,
+            now,
+            stale_before,
+            self.force
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        if result.rows_affected() == 1 {
+            return Ok(None);
+        }
+
+        let claim = sqlx::query!(
+            r#"SELECT instance_id, heartbeat_at FROM lsp_server_instance WHERE id = 1"#
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(Some(InstanceClaim {
+            instance_id: claim.instance_id,
+            heartbeat_at: IsoDatetime::from_unix_timestamp(claim.heartbeat_at)
+                .map_err(|e| anyhow!("Invalid heartbeat_at in lsp_server_instance: {}", e))?,
+        }))
+    }
+}
+
+/// Gives up the claim so another instance can start immediately
+pub(crate) struct ReleaseInstanceQuery {
+    instance_id: String,
+}
+
+impl ReleaseInstanceQuery {
+    pub(crate) fn new(instance_id: String) -> Self {
+        Self { instance_id }
+    }
+
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<()> {
+        sqlx::query!(
+            r#"DELETE FROM lsp_server_instance WHERE instance_id = ?1"#,
+            self.instance_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
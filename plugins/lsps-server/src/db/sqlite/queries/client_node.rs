@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Context, Result};
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::Lsps1ClientNode;
+use crate::db::sqlite::schema::Lsps1ClientNode as Lsps1ClientNodeSqlite;
+
+/// Stores what the node knew about the client of an order
+pub struct SetClientNodeQuery {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) client_node: Lsps1ClientNode,
+}
+
+impl SetClientNodeQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let uuid = self.order_uuid.to_string();
+        let node = Lsps1ClientNodeSqlite::try_from(&self.client_node)?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE lsps1_order
+            SET client_alias = ?1, client_addresses = ?2, client_remote_address = ?3
+            WHERE uuid = ?4
+            "#,
+            node.client_alias,
+            node.client_addresses,
+            node.client_remote_address,
+            uuid
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => Ok(()),
+            _ => Err(anyhow!(
+                "Failed to find order '{}' and could not store client node",
+                self.order_uuid
+            )),
+        }
+    }
+}
+
+/// Returns what the node knew about the client of an order
+///
+/// Returns `None` if the order doesn't exist
+pub struct GetClientNodeQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetClientNodeQuery {
+    pub(crate) fn by_uuid(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<Lsps1ClientNode>> {
+        let uuid = self.order_uuid.to_string();
+        let result = sqlx::query_as!(
+            Lsps1ClientNodeSqlite,
+            r#"
+            SELECT client_alias, client_addresses, client_remote_address
+            FROM lsps1_order
+            WHERE uuid = ?1
+            "#,
+            uuid
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        result.as_ref().map(Lsps1ClientNode::try_from).transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn client_node_is_stored_with_the_order() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        // Orders are created without a snapshot
+        let node = GetClientNodeQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(node, Some(Lsps1ClientNode::default()));
+
+        let client_node = Lsps1ClientNode {
+            alias: Some("SLEEPYDRAGON".to_string()),
+            addresses: vec![
+                "203.0.113.7:9735".to_string(),
+                "[2001:db8::7]:9735".to_string(),
+            ],
+            remote_address: Some("198.51.100.3:41234".to_string()),
+        };
+        SetClientNodeQuery {
+            order_uuid: uuid,
+            client_node: client_node.clone(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let node = GetClientNodeQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(node, Some(client_node.clone()));
+
+        // Unknown orders have no client node
+        let unknown = Uuid::new_v4();
+        let node = GetClientNodeQuery::by_uuid(unknown)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(node.is_none());
+        let result = SetClientNodeQuery {
+            order_uuid: unknown,
+            client_node,
+        }
+        .execute(&mut tx)
+        .await;
+        assert!(result.is_err());
+        tx.commit().await.unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use crate::db::schema::Lsps1Channel;
+use crate::db::sqlite::queries::GetChannelQuery;
 use crate::db::sqlite::schema::Lsps1Channel as Lsps1ChannelSqlite;
 use anyhow::{anyhow, Result};
 use sqlx::{Sqlite, Transaction};
@@ -16,13 +17,18 @@ impl CreateChannelQuery {
 }
 
 impl CreateChannelQuery {
-    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+    /// Stores the channel that was opened for an order
+    ///
+    /// Each order has at most one channel. The query is idempotent:
+    /// storing the same funding outpoint twice succeeds and returns `false`.
+    /// Storing a different outpoint for the same order is an error.
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<bool> {
         let uuid_str = self.order_id.to_string();
         let channel: Lsps1ChannelSqlite = Lsps1ChannelSqlite::try_from(&self.channel)?;
 
         let result = sqlx::query!(
             r#"
-             INSERT INTO lsps1_channel (order_id, funding_txid, outnum, funded_at)
+             INSERT OR IGNORE INTO lsps1_channel (order_id, funding_txid, outnum, funded_at)
              SELECT o.id, ?2, ?3, ?4 FROM lsps1_order as o
              where o.uuid = ?1;
              "#,
@@ -35,11 +41,8 @@ impl CreateChannelQuery {
         .await?;
 
         match result.rows_affected() {
-            0 => Err(anyhow!(
-                "Failed to find order '{}' and could not create channel",
-                self.order_id.to_string()
-            )),
-            1 => Ok(()),
+            0 => self.check_existing_channel(tx).await,
+            1 => Ok(true),
             _ => Err(anyhow!(
                 "Error in updating state. Query affected {} rows",
                 result.rows_affected()
@@ -48,6 +51,37 @@ impl CreateChannelQuery {
     }
 }
 
+impl CreateChannelQuery {
+    // Called when nothing was inserted. Either the order doesn't exist
+    // or a channel was already stored for the order
+    async fn check_existing_channel(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<bool> {
+        let existing = GetChannelQuery::by_order_id(self.order_id)
+            .execute(tx)
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to find order '{}' and could not create channel",
+                    self.order_id
+                )
+            })?;
+
+        if existing.funding_txid == self.channel.funding_txid
+            && existing.outnum == self.channel.outnum
+        {
+            Ok(false)
+        } else {
+            Err(anyhow!(
+                "Order '{}' already has a channel with outpoint {}:{}. Refusing to store {}:{}",
+                self.order_id,
+                existing.funding_txid,
+                existing.outnum,
+                self.channel.funding_txid,
+                self.channel.outnum
+            ))
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -98,4 +132,77 @@ mod test {
         assert_eq!(channel.funding_txid, returned_channel.funding_txid);
         assert_eq!(channel.outnum, returned_channel.outnum);
     }
+
+    #[tokio::test]
+    async fn store_channel_twice() {
+        let db = get_db().await;
+
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.pool.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            )
+            .unwrap(),
+            outnum: 0,
+            funded_at: IsoDatetime::now(),
+        };
+
+        let inserted = CreateChannelQuery::new(uuid, channel.clone())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(inserted);
+
+        // Retrying with the same outpoint succeeds but doesn't insert
+        let inserted = CreateChannelQuery::new(uuid, channel.clone())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(!inserted);
+
+        // A different outpoint for the same order is an error
+        let other_channel = Lsps1Channel {
+            outnum: 1,
+            ..channel.clone()
+        };
+        let result = CreateChannelQuery::new(uuid, other_channel)
+            .execute(&mut tx)
+            .await;
+        assert!(result.is_err());
+
+        // The original channel is kept
+        let returned_channel = GetChannelQuery::by_order_id(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(returned_channel.outnum, 0);
+    }
+
+    #[tokio::test]
+    async fn store_channel_for_unknown_order() {
+        let db = get_db().await;
+
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            )
+            .unwrap(),
+            outnum: 0,
+            funded_at: IsoDatetime::now(),
+        };
+
+        let mut tx = db.pool.begin().await.unwrap();
+        let result = CreateChannelQuery::new(Uuid::new_v4(), channel)
+            .execute(&mut tx)
+            .await;
+        tx.rollback().await.unwrap();
+        assert!(result.is_err());
+    }
 }
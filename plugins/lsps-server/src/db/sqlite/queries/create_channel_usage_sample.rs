@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Result};
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::Lsps1ChannelUsageSample;
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+
+/// Stores a sample of the usage of a leased channel
+pub struct CreateChannelUsageSampleQuery<'a> {
+    pub(crate) sample: &'a Lsps1ChannelUsageSample,
+}
+
+impl<'a> CreateChannelUsageSampleQuery<'a> {
+    pub(crate) fn new(sample: &'a Lsps1ChannelUsageSample) -> Self {
+        Self { sample }
+    }
+
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let sample = self.sample;
+        let order_uuid = sample.order_uuid.to_string();
+        let sampled_at = sample.sampled_at.into_sqlite_integer()?;
+        let lsp_balance_msat = sample.lsp_balance_msat.into_sqlite_integer()?;
+        let client_balance_msat = sample.client_balance_msat.into_sqlite_integer()?;
+        let forwarded_in_count = sample.forwarded_in_count.into_sqlite_integer()?;
+        let forwarded_in_msat = sample.forwarded_in_msat.into_sqlite_integer()?;
+        let forwarded_out_count = sample.forwarded_out_count.into_sqlite_integer()?;
+        let forwarded_out_msat = sample.forwarded_out_msat.into_sqlite_integer()?;
+        let htlc_count = sample.htlc_count.into_sqlite_integer()?;
+
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO lsps1_channel_usage (
+                order_id, sampled_at, lsp_balance_msat, client_balance_msat,
+                forwarded_in_count, forwarded_in_msat,
+                forwarded_out_count, forwarded_out_msat, htlc_count
+            )
+            SELECT o.id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 FROM lsps1_order AS o
+            WHERE o.uuid = ?1;
+            "#,
+            order_uuid,
+            sampled_at,
+            lsp_balance_msat,
+            client_balance_msat,
+            forwarded_in_count,
+            forwarded_in_msat,
+            forwarded_out_count,
+            forwarded_out_msat,
+            htlc_count
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => Ok(()),
+            0 => Err(anyhow!(
+                "Failed to find order '{}' and could not store channel usage",
+                sample.order_uuid
+            )),
+            n => Err(anyhow!(
+                "Error in storing channel usage. Query affected {} rows",
+                n
+            )),
+        }
+    }
+}
@@ -3,8 +3,6 @@ use anyhow::anyhow;
 use sqlx::Sqlite;
 use sqlx::Transaction;
 
-use lsp_primitives::lsps0::common_schemas::IsoDatetime;
-
 use crate::db::schema::{Lsps1Order, Lsps1PaymentDetails};
 use crate::db::sqlite::schema::{
     Lsps1Order as Lsps1OrderSqlite, Lsps1PaymentDetails as Lsps1PaymentDetailsSqlite,
@@ -22,7 +20,8 @@ impl Lsps1CreateOrderQuery {
     ) -> Result<(), anyhow::Error> {
         // Convert all data to sqlite types
         // All integer types are i64, OnchainAddresses become String, ...
-        let now = IsoDatetime::now().unix_timestamp();
+        // The initial states are as old as the order
+        let now = self.order.created_at.unix_timestamp();
         let order = Lsps1OrderSqlite::try_from(&self.order)?;
         let payment = Lsps1PaymentDetailsSqlite::try_from(&self.payment)?;
 
@@ -32,6 +31,7 @@ impl Lsps1CreateOrderQuery {
         }
 
         // Create the entry in the lsps1_order table
+        // The latest state is stored with the order and in the state history
         let order_id = sqlx::query_as!(
             IdType,
             r#"
@@ -47,9 +47,12 @@ impl Lsps1CreateOrderQuery {
               refund_onchain_address,
               announce_channel,
               created_at,
-              expires_at
+              expires_at,
+              latest_order_state,
+              latest_failure_reason,
+              latest_generation
             ) VALUES (
-                ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12
+                ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15
             )
             RETURNING id;"#,
             order.uuid,
@@ -63,7 +66,10 @@ impl Lsps1CreateOrderQuery {
             order.refund_onchain_address,
             order.announce_channel,
             order.created_at,
-            order.expires_at
+            order.expires_at,
+            order.order_state,
+            order.failure_reason,
+            order.generation
         )
         .fetch_optional(&mut **tx)
         .await?
@@ -81,10 +87,12 @@ impl Lsps1CreateOrderQuery {
                bolt11_invoice_label,
                onchain_address,
                onchain_block_confirmations_required,
-               minimum_fee_for_0conf
+               minimum_fee_for_0conf,
+               latest_payment_state,
+               latest_generation
             ) VALUES 
             (
-              ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+              ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
             RETURNING id;
             "#,
             order_id.id,
@@ -94,7 +102,9 @@ impl Lsps1CreateOrderQuery {
             payment.bolt11_invoice_label,
             payment.onchain_address,
             payment.onchain_block_confirmations_required,
-            payment.minimum_fee_for_0conf
+            payment.minimum_fee_for_0conf,
+            payment.state,
+            payment.generation
         )
         .fetch_one(&mut **tx)
         .await?;
@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::Lsps1OrderLogEntry;
+
+/// Appends an entry to the audit log of an order
+pub struct CreateOrderLogEntryQuery<'a> {
+    pub(crate) entry: &'a Lsps1OrderLogEntry,
+}
+
+impl<'a> CreateOrderLogEntryQuery<'a> {
+    pub(crate) fn new(entry: &'a Lsps1OrderLogEntry) -> Self {
+        Self { entry }
+    }
+
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let order_uuid = self.entry.order_uuid.to_string();
+        let created_at = self.entry.created_at.unix_timestamp();
+        let severity = self.entry.severity.as_str();
+        let event_code = self.entry.event.as_str();
+        let details = serde_json::to_string(&self.entry.details)?;
+
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO lsps1_order_log (order_id, created_at, severity, event_code, details)
+            SELECT o.id, ?2, ?3, ?4, ?5 FROM lsps1_order AS o
+            WHERE o.uuid = ?1;
+            "#,
+            order_uuid,
+            created_at,
+            severity,
+            event_code,
+            details
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => Ok(()),
+            0 => Err(anyhow!(
+                "Failed to find order '{}' and could not store log entry",
+                self.entry.order_uuid
+            )),
+            n => Err(anyhow!(
+                "Error in storing log entry. Query affected {} rows",
+                n
+            )),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, PublicKey};
+
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+
+/// Stores a response that couldn't be sent to a peer
+///
+/// The entry counts as one attempt. Returns the id of the entry
+pub struct CreateOutboxEntryQuery<'a> {
+    pub(crate) peer_id: PublicKey,
+    pub(crate) payload: &'a str,
+    pub(crate) created_at: IsoDatetime,
+}
+
+impl<'a> CreateOutboxEntryQuery<'a> {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<i64> {
+        let peer_id = self.peer_id.to_hex();
+        let created_at = self.created_at.into_sqlite_integer()?;
+
+        struct IdType {
+            pub id: i64,
+        }
+
+        let entry = sqlx::query_as!(
+            IdType,
+            r#"
+            INSERT INTO lsps0_response_outbox (peer_id, payload, created_at, attempts)
+            VALUES (?1, ?2, ?3, 1)
+            RETURNING id;
+            "#,
+            peer_id,
+            self.payload,
+            created_at
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+        Ok(entry.id)
+    }
+}
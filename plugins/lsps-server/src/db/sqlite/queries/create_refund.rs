@@ -0,0 +1,113 @@
+use crate::db::schema::Lsps1Refund;
+use crate::db::sqlite::schema::Lsps1Refund as Lsps1RefundSqlite;
+use anyhow::{anyhow, Result};
+use sqlx::{Sqlite, Transaction};
+use uuid::Uuid;
+
+/// Stores the refund that was broadcast for an order
+pub struct CreateRefundQuery {
+    pub(crate) order_id: Uuid,
+    pub(crate) refund: Lsps1Refund,
+}
+
+impl CreateRefundQuery {
+    pub fn new(order_id: Uuid, refund: Lsps1Refund) -> Self {
+        Self { order_id, refund }
+    }
+}
+
+impl CreateRefundQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let uuid_str = self.order_id.to_string();
+        let refund: Lsps1RefundSqlite = Lsps1RefundSqlite::try_from(&self.refund)?;
+
+        let result = sqlx::query!(
+            r#"
+             INSERT INTO lsps1_refunds (order_id, txid, amount_sat, address, broadcast_at)
+             SELECT o.id, ?2, ?3, ?4, ?5 FROM lsps1_order as o
+             where o.uuid = ?1;
+             "#,
+            uuid_str,
+            refund.txid,
+            refund.amount_sat,
+            refund.address,
+            refund.broadcast_at
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(anyhow!(
+                "Failed to find order '{}' and could not create refund",
+                self.order_id
+            )),
+            1 => Ok(()),
+            _ => Err(anyhow!(
+                "Error in creating refund. Query affected {} rows",
+                result.rows_affected()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use lsp_primitives::lsps0::schema::{IsoDatetime, SatAmount, TransactionId};
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::db::sqlite::queries::GetRefundQuery;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn store_refund_in_database() {
+        // Create a database connection
+        let db = get_db().await;
+
+        // Create the order query
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        // Execute the query
+        let mut tx = db.pool.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        // Orders without a refund return None
+        let returned_refund = GetRefundQuery::by_order_id(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(returned_refund.is_none());
+
+        // Store the refund in the database
+        let refund = Lsps1Refund {
+            txid: TransactionId::from_str(
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            )
+            .unwrap(),
+            amount_sat: SatAmount::new(500),
+            address: "bcrt1qxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzx".to_string(),
+            broadcast_at: IsoDatetime::now(),
+        };
+        CreateRefundQuery::new(uuid, refund.clone())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        let returned_refund = GetRefundQuery::by_order_id(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+
+        tx.commit().await.unwrap();
+        assert_eq!(refund.txid, returned_refund.txid);
+        assert_eq!(refund.amount_sat, returned_refund.amount_sat);
+        assert_eq!(refund.address, returned_refund.address);
+        assert_eq!(
+            refund.broadcast_at.unix_timestamp(),
+            returned_refund.broadcast_at.unix_timestamp()
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, Outpoint};
+
+use crate::db::schema::{Lsps1FundingReservation, Lsps1OrderState};
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+
+/// Stores the inputs that were reserved to fund the channel of an order
+///
+/// An order has at most one reservation. Returns false if the order
+/// already has a reservation or if the order doesn't exist.
+pub struct CreateFundingReservationQuery {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) reservation: Lsps1FundingReservation,
+}
+
+impl CreateFundingReservationQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<bool> {
+        let uuid = self.order_uuid.to_string();
+        let utxos = format_utxos(&self.reservation.utxos);
+        let reserved_at = self.reservation.reserved_at.unix_timestamp();
+        let result = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO lsps1_funding_reservation (order_id, psbt, utxos, reserved_at)
+            SELECT o.id, ?2, ?3, ?4 FROM lsps1_order AS o
+            WHERE o.uuid = ?1;
+            "#,
+            uuid,
+            self.reservation.psbt,
+            utxos,
+            reserved_at
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// Returns the reservation of an order
+///
+/// Returns `None` if the order doesn't exist or has no reservation
+pub struct GetFundingReservationQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetFundingReservationQuery {
+    pub(crate) fn by_uuid(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<Lsps1FundingReservation>> {
+        let uuid = self.order_uuid.to_string();
+        let row = sqlx::query!(
+            r#"
+            SELECT r.psbt, r.utxos, r.reserved_at
+            FROM lsps1_funding_reservation AS r
+            JOIN lsps1_order AS o ON o.id = r.order_id
+            WHERE o.uuid = ?1
+            "#,
+            uuid
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        row.map(|row| {
+            Ok(Lsps1FundingReservation {
+                psbt: row.psbt,
+                utxos: parse_utxos(&row.utxos)?,
+                reserved_at: IsoDatetime::from_unix_timestamp(row.reserved_at)?,
+            })
+        })
+        .transpose()
+    }
+}
+
+/// Forgets the reservation of an order
+///
+/// Returns false if the order had no reservation
+pub struct DeleteFundingReservationQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl DeleteFundingReservationQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<bool> {
+        let uuid = self.order_uuid.to_string();
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM lsps1_funding_reservation
+            WHERE order_id = (SELECT id FROM lsps1_order WHERE uuid = ?1)
+            "#,
+            uuid
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// Finds the reservations of orders that won't fund a channel anymore
+///
+/// An order only needs its reservation while it is `PendingOpen` or
+/// `Funding`. Other reservations were left behind when the plugin stopped
+/// and must be released.
+pub struct GetStaleFundingReservationsQuery;
+
+impl GetStaleFundingReservationsQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<(Uuid, Lsps1FundingReservation)>> {
+        let pending_open = Lsps1OrderState::PendingOpen.into_sqlite_integer()?;
+        let funding = Lsps1OrderState::Funding.into_sqlite_integer()?;
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                ord.uuid, r.psbt, r.utxos, r.reserved_at
+            FROM lsps1_funding_reservation AS r
+            JOIN lsps1_order AS ord ON ord.id = r.order_id
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND os.order_state_enum_id NOT IN (?1, ?2)
+            ORDER BY r.reserved_at;"#,
+            pending_open,
+            funding
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        rows.into_iter()
+            .map(|row| {
+                let reservation = Lsps1FundingReservation {
+                    psbt: row.psbt,
+                    utxos: parse_utxos(&row.utxos)?,
+                    reserved_at: IsoDatetime::from_unix_timestamp(row.reserved_at)?,
+                };
+                Ok((Uuid::from_str(&row.uuid)?, reservation))
+            })
+            .collect()
+    }
+}
+
+fn format_utxos(utxos: &[Outpoint]) -> String {
+    utxos
+        .iter()
+        .map(|utxo| utxo.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_utxos(utxos: &str) -> Result<Vec<Outpoint>> {
+    utxos
+        .split(',')
+        .filter(|utxo| !utxo.is_empty())
+        .map(|utxo| Ok(Outpoint::from_str(utxo)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::UpdateOrderStateQuery;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn reservation(psbt: &str) -> Lsps1FundingReservation {
+        Lsps1FundingReservation {
+            psbt: psbt.to_string(),
+            utxos: vec![
+                Outpoint::from_str(&format!("{}:0", TXID)).unwrap(),
+                Outpoint::from_str(&format!("{}:3", TXID)).unwrap(),
+            ],
+            reserved_at: IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn order_has_one_reservation() {
+        let (db, _) = get_temp_db().await;
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let first = CreateFundingReservationQuery {
+            order_uuid: uuid,
+            reservation: reservation("first"),
+        };
+        assert!(first.execute(&mut tx).await.unwrap());
+        let second = CreateFundingReservationQuery {
+            order_uuid: uuid,
+            reservation: reservation("second"),
+        };
+        assert!(!second.execute(&mut tx).await.unwrap());
+
+        let stored = GetFundingReservationQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, Some(reservation("first")));
+
+        let delete = DeleteFundingReservationQuery { order_uuid: uuid };
+        assert!(delete.execute(&mut tx).await.unwrap());
+        assert!(!delete.execute(&mut tx).await.unwrap());
+        let stored = GetFundingReservationQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, None);
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reservation_of_failed_order_is_stale() {
+        let (db, _) = get_temp_db().await;
+        let pending_query = create_order_query();
+        let pending_uuid = pending_query.order.uuid;
+        let failed_query = create_order_query();
+        let failed_uuid = failed_query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        pending_query.execute(&mut tx).await.unwrap();
+        failed_query.execute(&mut tx).await.unwrap();
+        for (uuid, state) in [
+            (pending_uuid, Lsps1OrderState::PendingOpen),
+            (failed_uuid, Lsps1OrderState::Failed),
+        ] {
+            UpdateOrderStateQuery {
+                order_uuid: uuid,
+                state,
+                generation: OrderGeneration(0),
+                changed_at: IsoDatetime::now(),
+                failure_reason: None,
+            }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+            CreateFundingReservationQuery {
+                order_uuid: uuid,
+                reservation: reservation("psbt"),
+            }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+
+        let stale = GetStaleFundingReservationsQuery
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(stale, vec![(failed_uuid, reservation("psbt"))]);
+    }
+}
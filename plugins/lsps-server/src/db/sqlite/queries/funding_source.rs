@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::Lsps1OrderState;
+use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
+
+/// Stores the label of the funding source that funds the channel of an order
+pub struct SetFundingSourceQuery {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) funding_source: String,
+}
+
+impl SetFundingSourceQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let uuid = self.order_uuid.to_string();
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE lsps1_order
+            SET funding_source = ?1
+            WHERE uuid = ?2
+            "#,
+            self.funding_source,
+            uuid
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => Ok(()),
+            _ => Err(anyhow!(
+                "Failed to find order '{}' and could not store funding source",
+                self.order_uuid
+            )),
+        }
+    }
+}
+
+/// Returns the label of the funding source of an order
+///
+/// Returns `None` if the order doesn't exist or if any output of the
+/// wallet can fund its channel
+pub struct GetFundingSourceQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetFundingSourceQuery {
+    pub(crate) fn by_uuid(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<Option<String>> {
+        let uuid = self.order_uuid.to_string();
+        let result = sqlx::query!(
+            r#"
+            SELECT funding_source
+            FROM lsps1_order
+            WHERE uuid = ?1
+            "#,
+            uuid
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        Ok(result.and_then(|row| row.funding_source))
+    }
+}
+
+/// Sums the capacity of the orders that wait for a channel per funding source
+///
+/// These orders are `CREATED`, `PENDING_OPEN` or `FUNDING` in their latest
+/// state. The funds of their channel haven't left the wallet yet. Orders
+/// without a funding source are not included.
+pub struct GetCommittedLiquidityQuery {
+    pub(crate) funding_source: Option<String>,
+}
+
+impl GetCommittedLiquidityQuery {
+    pub fn all() -> Self {
+        Self {
+            funding_source: None,
+        }
+    }
+
+    pub fn by_source(funding_source: String) -> Self {
+        Self {
+            funding_source: Some(funding_source),
+        }
+    }
+}
+
+impl GetCommittedLiquidityQuery {
+    /// Returns the committed capacity in sat by label
+    ///
+    /// Sources without waiting orders are absent
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<BTreeMap<String, u64>> {
+        let created = Lsps1OrderState::Created.into_sqlite_integer()?;
+        let pending_open = Lsps1OrderState::PendingOpen.into_sqlite_integer()?;
+        let funding = Lsps1OrderState::Funding.into_sqlite_integer()?;
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                funding_source AS "funding_source!",
+                SUM(lsp_balance_sat + client_balance_sat) AS "committed_sat!: i64"
+            FROM lsps1_order
+            WHERE funding_source IS NOT NULL
+            AND (?1 IS NULL OR funding_source = ?1)
+            AND latest_order_state IN (?2, ?3, ?4)
+            GROUP BY funding_source
+            ORDER BY funding_source;"#,
+            self.funding_source,
+            created,
+            pending_open,
+            funding
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to sum the committed liquidity")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok((
+                    row.funding_source,
+                    u64::from_sqlite_integer(row.committed_sat)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, SatAmount};
+
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::UpdateOrderStateQuery;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    /// Creates an order of `capacity_sat` funded by `funding_source` that moves to `state`
+    async fn create_order(
+        tx: &mut Transaction<'static, Sqlite>,
+        funding_source: Option<&str>,
+        capacity_sat: u64,
+        state: Lsps1OrderState,
+    ) -> Uuid {
+        let mut query = create_order_query();
+        query.order.lsp_balance_sat = SatAmount::new(capacity_sat);
+        query.order.client_balance_sat = SatAmount::new(0);
+        let order_uuid = query.order.uuid;
+        query.execute(tx).await.unwrap();
+
+        if let Some(funding_source) = funding_source {
+            SetFundingSourceQuery {
+                order_uuid,
+                funding_source: funding_source.to_string(),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+        if state != Lsps1OrderState::Created {
+            UpdateOrderStateQuery {
+                order_uuid,
+                state,
+                generation: OrderGeneration(0),
+                changed_at: IsoDatetime::now(),
+                failure_reason: None,
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+        order_uuid
+    }
+
+    #[tokio::test]
+    async fn funding_source_is_stored_with_the_order() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        let without = create_order(&mut tx, None, 100_000, Lsps1OrderState::Created).await;
+        let with = create_order(&mut tx, Some("cold"), 100_000, Lsps1OrderState::Created).await;
+
+        let stored = GetFundingSourceQuery::by_uuid(without)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, None);
+        let stored = GetFundingSourceQuery::by_uuid(with)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored.as_deref(), Some("cold"));
+
+        let result = SetFundingSourceQuery {
+            order_uuid: Uuid::new_v4(),
+            funding_source: "cold".to_string(),
+        }
+        .execute(&mut tx)
+        .await;
+        assert!(result.is_err());
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn committed_liquidity_is_summed_per_source() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        create_order(&mut tx, Some("cold"), 100_000, Lsps1OrderState::Created).await;
+        create_order(&mut tx, Some("cold"), 200_000, Lsps1OrderState::PendingOpen).await;
+        create_order(&mut tx, Some("cold"), 400_000, Lsps1OrderState::Funding).await;
+        // The funds of these orders left the wallet or are available again
+        create_order(
+            &mut tx,
+            Some("cold"),
+            800_000,
+            Lsps1OrderState::ChannelOpening,
+        )
+        .await;
+        create_order(&mut tx, Some("cold"), 800_000, Lsps1OrderState::Completed).await;
+        create_order(&mut tx, Some("cold"), 800_000, Lsps1OrderState::Failed).await;
+        create_order(&mut tx, Some("hot"), 50_000, Lsps1OrderState::PendingOpen).await;
+        create_order(&mut tx, None, 1_000_000, Lsps1OrderState::PendingOpen).await;
+
+        let committed = GetCommittedLiquidityQuery::all()
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            committed,
+            BTreeMap::from([("cold".to_string(), 700_000), ("hot".to_string(), 50_000)])
+        );
+
+        let hot = GetCommittedLiquidityQuery::by_source("hot".to_string())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(hot, BTreeMap::from([("hot".to_string(), 50_000)]));
+
+        let unused = GetCommittedLiquidityQuery::by_source("partner".to_string())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(unused.is_empty());
+        tx.commit().await.unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::{PublicKey, SatAmount};
+
+use crate::db::schema::{Lsps1Channel, Lsps1OrderState};
+use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
+use crate::db::sqlite::quarantine::Quarantined;
+use crate::db::sqlite::schema::Lsps1Channel as Lsps1ChannelSqlite;
+
+/// A completed order for which the channel must be kept open
+#[derive(Debug, Clone)]
+pub(crate) struct ActiveLease {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) client_node_id: PublicKey,
+    pub(crate) lsp_balance_sat: SatAmount,
+    pub(crate) client_balance_sat: SatAmount,
+    pub(crate) channel_expiry_blocks: u32,
+    pub(crate) channel: Lsps1Channel,
+    /// `None` if the funding transaction confirmed before the height was tracked
+    pub(crate) funding_blockheight: Option<u32>,
+    /// The label of the funding source that funded the channel
+    pub(crate) funding_source: Option<String>,
+}
+
+/// Finds all completed orders whose lease hasn't expired at `blockheight`
+///
+/// Leases for which the funding height is unknown are always included.
+pub struct GetActiveLeasesQuery {
+    pub(crate) blockheight: u32,
+}
+
+impl GetActiveLeasesQuery {
+    pub fn at_blockheight(blockheight: u32) -> Self {
+        Self { blockheight }
+    }
+}
+
+impl GetActiveLeasesQuery {
+    /// Fails if any of the leases is corrupt
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<ActiveLease>> {
+        self.execute_quarantined(tx).await?.into_strict()
+    }
+
+    /// Skips the leases that are corrupt and reports them separately
+    pub(crate) async fn execute_quarantined(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Quarantined<ActiveLease>> {
+        let completed = Lsps1OrderState::Completed.into_sqlite_integer()?;
+        let blockheight = i64::from(self.blockheight);
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                ord.id, ord.uuid, ord.client_node_id, ord.lsp_balance_sat,
+                ord.client_balance_sat, ord.channel_expiry_blocks, ord.funding_source,
+                c.funding_txid, c.outnum, c.funded_at, c.funding_blockheight
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            JOIN lsps1_channel AS c ON ord.id = c.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND os.order_state_enum_id = ?1
+            AND (
+                c.funding_blockheight IS NULL
+                OR c.funding_blockheight + ord.channel_expiry_blocks > ?2
+            )
+            ORDER BY ord.created_at, ord.id;"#,
+            completed,
+            blockheight
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        Ok(Quarantined::convert(
+            "lsps1_order",
+            rows,
+            |row| row.id,
+            |row| {
+                let channel = Lsps1ChannelSqlite {
+                    funding_txid: row.funding_txid,
+                    outnum: row.outnum,
+                    funded_at: row.funded_at,
+                };
+                Ok(ActiveLease {
+                    order_uuid: Uuid::from_str(&row.uuid)?,
+                    client_node_id: PublicKey::from_hex(&row.client_node_id)?,
+                    lsp_balance_sat: SatAmount::from_sqlite_integer(row.lsp_balance_sat)?,
+                    client_balance_sat: SatAmount::from_sqlite_integer(row.client_balance_sat)?,
+                    channel_expiry_blocks: u32::try_from(row.channel_expiry_blocks)?,
+                    channel: Lsps1Channel::try_from(&channel)?,
+                    funding_blockheight: row.funding_blockheight.map(u32::try_from).transpose()?,
+                    funding_source: row.funding_source,
+                })
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, TransactionId};
+
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::{
+        CreateChannelQuery, SetFundingBlockheightQuery, UpdateOrderStateQuery,
+    };
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    async fn create_lease(
+        tx: &mut Transaction<'static, Sqlite>,
+        state: Lsps1OrderState,
+        funding_blockheight: Option<u32>,
+    ) -> Uuid {
+        let query = create_order_query();
+        let order_uuid = query.order.uuid;
+        query.execute(tx).await.unwrap();
+
+        let txid = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(&txid).unwrap(),
+            outnum: 0,
+            funded_at: IsoDatetime::now(),
+        };
+        CreateChannelQuery::new(order_uuid, channel)
+            .execute(tx)
+            .await
+            .unwrap();
+        if let Some(funding_blockheight) = funding_blockheight {
+            let stored = SetFundingBlockheightQuery {
+                order_uuid,
+                funding_blockheight,
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+            assert!(stored);
+        }
+        UpdateOrderStateQuery {
+            order_uuid,
+            state,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(tx)
+        .await
+        .unwrap();
+        order_uuid
+    }
+
+    #[tokio::test]
+    async fn find_active_leases() {
+        let (db, _) = get_temp_db().await;
+        // The test order has channel_expiry_blocks = 4320
+        let mut tx = db.begin().await.unwrap();
+        let active = create_lease(&mut tx, Lsps1OrderState::Completed, Some(1_000)).await;
+        let unknown = create_lease(&mut tx, Lsps1OrderState::Completed, None).await;
+        let expired = create_lease(&mut tx, Lsps1OrderState::Completed, Some(100)).await;
+        create_lease(&mut tx, Lsps1OrderState::ChannelOpening, None).await;
+
+        let leases = GetActiveLeasesQuery::at_blockheight(5_000)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        let found: Vec<_> = leases.iter().map(|l| l.order_uuid).collect();
+        assert_eq!(found, vec![active, unknown]);
+        assert_eq!(leases[0].funding_blockheight, Some(1_000));
+        assert_eq!(leases[1].funding_blockheight, None);
+
+        // The lease of `expired` ends at block 4420
+        let leases = GetActiveLeasesQuery::at_blockheight(4_419)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(leases.iter().any(|l| l.order_uuid == expired));
+
+        // The funding height is only stored once
+        let stored = SetFundingBlockheightQuery {
+            order_uuid: active,
+            funding_blockheight: 2_000,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        assert!(!stored);
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_leases_are_quarantined() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+        let healthy = create_lease(&mut tx, Lsps1OrderState::Completed, Some(1_000)).await;
+        let corrupt = create_lease(&mut tx, Lsps1OrderState::Completed, Some(1_000)).await;
+        sqlx::query("UPDATE lsps1_order SET lsp_balance_sat = -1 WHERE uuid = ?1")
+            .bind(corrupt.to_string())
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let query = GetActiveLeasesQuery::at_blockheight(2_000);
+        let result = query.execute_quarantined(&mut tx).await.unwrap();
+        let found: Vec<_> = result.rows.iter().map(|l| l.order_uuid).collect();
+        assert_eq!(found, vec![healthy]);
+        assert_eq!(result.corrupt_rows.len(), 1);
+
+        assert!(query.execute(&mut tx).await.is_err());
+        tx.commit().await.unwrap();
+    }
+}
@@ -25,7 +25,7 @@ impl GetChannelQuery {
     ) -> Result<Option<Lsps1Channel>, anyhow::Error> {
         let order_str = self.order_uuid.to_string();
 
-        let channel = sqlx::query_as!(
+        let channels = sqlx::query_as!(
             Lsps1ChannelSqlite,
             r#"
              SELECT c.funding_txid, c.outnum, c.funded_at FROM lsps1_channel as c
@@ -35,13 +35,20 @@ impl GetChannelQuery {
               "#,
             order_str
         )
-        .fetch_optional(&mut **tx)
+        .fetch_all(&mut **tx)
         .await
         .map_err(|e| anyhow!("db.get_channel Failed: {}", e))?;
 
-        match channel {
-            None => Ok(None),
-            Some(channel) => Ok(Some(Lsps1Channel::try_from(&channel)?)),
+        // An order has at most one channel. Databases created before
+        // this was enforced might violate this
+        match channels.as_slice() {
+            [] => Ok(None),
+            [channel] => Ok(Some(Lsps1Channel::try_from(channel)?)),
+            _ => Err(anyhow!(
+                "db.get_channel Failed: Found {} channels for order {}",
+                channels.len(),
+                self.order_uuid
+            )),
         }
     }
 }
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+use crate::db::schema::{Lsps1Channel, Lsps1OrderState, OrderGeneration};
+use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
+use crate::db::sqlite::schema::Lsps1Channel as Lsps1ChannelSqlite;
+
+/// An order for which the funding transaction has been broadcast
+#[derive(Debug, Clone)]
+pub(crate) struct ChannelOpeningOrder {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) client_node_id: PublicKey,
+    pub(crate) channel: Lsps1Channel,
+    /// The generation of the `ChannelOpening`-state
+    pub(crate) generation: OrderGeneration,
+}
+
+/// Finds all orders for which the latest state is `ChannelOpening`
+/// together with the funding outpoint of their channel
+pub struct GetChannelOpeningOrdersQuery {
+    pub(crate) peer_id: Option<PublicKey>,
+}
+
+impl GetChannelOpeningOrdersQuery {
+    pub fn all() -> Self {
+        Self { peer_id: None }
+    }
+
+    pub fn by_peer_id(peer_id: PublicKey) -> Self {
+        Self {
+            peer_id: Some(peer_id),
+        }
+    }
+}
+
+impl GetChannelOpeningOrdersQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<ChannelOpeningOrder>> {
+        let channel_opening = Lsps1OrderState::ChannelOpening.into_sqlite_integer()?;
+        let peer_id = self.peer_id.as_ref().map(|p| p.to_hex());
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                ord.uuid, ord.client_node_id, os.generation,
+                c.funding_txid, c.outnum, c.funded_at
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            JOIN lsps1_channel AS c ON ord.id = c.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND os.order_state_enum_id = ?1
+            AND (?2 IS NULL OR ord.client_node_id = ?2)
+            ORDER BY ord.created_at;"#,
+            channel_opening,
+            peer_id
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        rows.into_iter()
+            .map(|row| {
+                let channel = Lsps1ChannelSqlite {
+                    funding_txid: row.funding_txid,
+                    outnum: row.outnum,
+                    funded_at: row.funded_at,
+                };
+                Ok(ChannelOpeningOrder {
+                    order_uuid: Uuid::from_str(&row.uuid)?,
+                    client_node_id: PublicKey::from_hex(&row.client_node_id)?,
+                    channel: Lsps1Channel::try_from(&channel)?,
+                    generation: OrderGeneration::from_sqlite_integer(row.generation)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, TransactionId};
+
+    use crate::db::sqlite::queries::{CreateChannelQuery, UpdateOrderStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn find_channel_opening_orders_by_peer() {
+        let db = get_db().await;
+
+        // Use a peer that isn't used by other tests
+        let peer_id = PublicKey::from_hex(
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        )
+        .unwrap();
+
+        let mut opening_query = create_order_query();
+        opening_query.order.client_node_id = peer_id;
+        let opening_uuid = opening_query.order.uuid;
+
+        let mut created_query = create_order_query();
+        created_query.order.client_node_id = peer_id;
+
+        let mut tx = db.begin().await.unwrap();
+        opening_query.execute(&mut tx).await.unwrap();
+        created_query.execute(&mut tx).await.unwrap();
+
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            )
+            .unwrap(),
+            outnum: 1,
+            funded_at: IsoDatetime::now(),
+        };
+        CreateChannelQuery::new(opening_uuid, channel.clone())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: opening_uuid,
+            state: Lsps1OrderState::ChannelOpening,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let orders = GetChannelOpeningOrdersQuery::by_peer_id(peer_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].order_uuid, opening_uuid);
+        assert_eq!(orders[0].client_node_id, peer_id);
+        assert_eq!(orders[0].channel.funding_txid, channel.funding_txid);
+        assert_eq!(orders[0].channel.outnum, 1);
+        assert_eq!(orders[0].generation, OrderGeneration(1));
+
+        // A completed order is no longer opening
+        UpdateOrderStateQuery {
+            order_uuid: opening_uuid,
+            state: Lsps1OrderState::Completed,
+            generation: orders[0].generation,
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let orders = GetChannelOpeningOrdersQuery::by_peer_id(peer_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(orders.is_empty());
+    }
+}
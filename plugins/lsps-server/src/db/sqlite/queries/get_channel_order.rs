@@ -0,0 +1,92 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use sqlx::{Sqlite, Transaction};
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::TransactionId;
+
+/// Finds the order for which the channel with the funding outpoint was stored
+pub(crate) struct GetChannelOrderQuery {
+    funding_txid: TransactionId,
+    outnum: u32,
+}
+
+impl GetChannelOrderQuery {
+    pub(crate) fn by_funding_outpoint(funding_txid: TransactionId, outnum: u32) -> Self {
+        Self {
+            funding_txid,
+            outnum,
+        }
+    }
+
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Option<Uuid>> {
+        let funding_txid = self.funding_txid.to_string();
+
+        let row = sqlx::query!(
+            r#"
+            SELECT o.uuid FROM lsps1_channel AS c
+            JOIN lsps1_order AS o ON c.order_id = o.id
+            WHERE c.funding_txid = ?1 AND c.outnum = ?2
+            "#,
+            funding_txid,
+            self.outnum
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        row.map(|row| Uuid::from_str(&row.uuid).context("Invalid uuid"))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::db::schema::Lsps1Channel;
+    use crate::db::sqlite::queries::CreateChannelQuery;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    #[tokio::test]
+    async fn find_order_by_funding_outpoint() {
+        let (db, _) = get_temp_db().await;
+        let query = create_order_query();
+        let order_uuid = query.order.uuid;
+        let funding_txid = TransactionId::from_str(
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        )
+        .unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        let channel = Lsps1Channel {
+            funding_txid: funding_txid.clone(),
+            outnum: 1,
+            funded_at: IsoDatetime::now(),
+        };
+        CreateChannelQuery::new(order_uuid, channel)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        let found = GetChannelOrderQuery::by_funding_outpoint(funding_txid.clone(), 1)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(found, Some(order_uuid));
+
+        let other_output = GetChannelOrderQuery::by_funding_outpoint(funding_txid, 0)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(other_output, None);
+        tx.commit().await.unwrap();
+    }
+}
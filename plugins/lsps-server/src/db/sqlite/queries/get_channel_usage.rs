@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, MsatAmount};
+
+use crate::db::schema::Lsps1ChannelUsageSample;
+use crate::db::sqlite::conversion::FromSqliteInteger;
+
+/// Finds the usage samples of the channel of an order
+///
+/// The samples are returned from oldest to newest
+pub struct GetChannelUsageQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetChannelUsageQuery {
+    pub fn by_order_id(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+}
+
+impl GetChannelUsageQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<Lsps1ChannelUsageSample>> {
+        let order_uuid = self.order_uuid.to_string();
+
+        let rows = sqlx::query!(
+            r#"SELECT u.sampled_at, u.lsp_balance_msat, u.client_balance_msat,
+                u.forwarded_in_count, u.forwarded_in_msat,
+                u.forwarded_out_count, u.forwarded_out_msat, u.htlc_count
+            FROM lsps1_channel_usage AS u
+            JOIN lsps1_order AS o ON u.order_id = o.id
+            WHERE o.uuid = ?1
+            ORDER BY u.sampled_at, u.id;"#,
+            order_uuid
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Lsps1ChannelUsageSample {
+                    order_uuid: self.order_uuid,
+                    sampled_at: IsoDatetime::from_sqlite_integer(row.sampled_at)?,
+                    lsp_balance_msat: MsatAmount::from_sqlite_integer(row.lsp_balance_msat)?,
+                    client_balance_msat: MsatAmount::from_sqlite_integer(row.client_balance_msat)?,
+                    forwarded_in_count: u64::from_sqlite_integer(row.forwarded_in_count)?,
+                    forwarded_in_msat: MsatAmount::from_sqlite_integer(row.forwarded_in_msat)?,
+                    forwarded_out_count: u64::from_sqlite_integer(row.forwarded_out_count)?,
+                    forwarded_out_msat: MsatAmount::from_sqlite_integer(row.forwarded_out_msat)?,
+                    htlc_count: u32::from_sqlite_integer(row.htlc_count)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Returns when the channel of an order was sampled last
+pub struct GetLastChannelUsageAtQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetLastChannelUsageAtQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Option<IsoDatetime>> {
+        let order_uuid = self.order_uuid.to_string();
+
+        let sampled_at = sqlx::query_scalar!(
+            r#"SELECT MAX(u.sampled_at) AS "sampled_at?"
+            FROM lsps1_channel_usage AS u
+            JOIN lsps1_order AS o ON u.order_id = o.id
+            WHERE o.uuid = ?1;"#,
+            order_uuid
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        sampled_at.map(IsoDatetime::from_sqlite_integer).transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::queries::CreateChannelUsageSampleQuery;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    fn sample(order_uuid: Uuid, sampled_at: i64) -> Lsps1ChannelUsageSample {
+        Lsps1ChannelUsageSample {
+            order_uuid,
+            sampled_at: IsoDatetime::from_unix_timestamp(sampled_at).unwrap(),
+            lsp_balance_msat: MsatAmount::new(900_000_000),
+            client_balance_msat: MsatAmount::new(100_000_000),
+            forwarded_in_count: 1,
+            forwarded_in_msat: MsatAmount::new(5_000),
+            forwarded_out_count: 2,
+            forwarded_out_msat: MsatAmount::new(100_000_000),
+            htlc_count: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn store_and_read_channel_usage() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let order_uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let later = sample(order_uuid, 1_700_003_600);
+        let earlier = sample(order_uuid, 1_700_000_000);
+        for sample in [&later, &earlier] {
+            CreateChannelUsageSampleQuery::new(sample)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+        }
+
+        let last_sampled_at = GetLastChannelUsageAtQuery { order_uuid }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(last_sampled_at, Some(later.sampled_at));
+
+        // A sample of an unknown order isn't stored
+        let unknown = sample(Uuid::new_v4(), 1_700_000_000);
+        assert!(CreateChannelUsageSampleQuery::new(&unknown)
+            .execute(&mut tx)
+            .await
+            .is_err());
+
+        let samples = GetChannelUsageQuery::by_order_id(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(samples, vec![earlier, later]);
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+use lsp_primitives::lsps1::schema::PaymentState;
+
+use crate::db::schema::{Lsps1Order, Lsps1OrderState};
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+use crate::db::sqlite::schema::Lsps1Order as Lsps1OrderSqlite;
+
+/// Finds all orders that expired before they were paid
+///
+/// The latest state of these orders is `Created` and the latest
+/// state of their payment is `ExpectPayment`. Only orders that expired
+/// before `expired_before` are returned.
+pub struct GetExpiredUnpaidOrdersQuery {
+    pub(crate) expired_before: IsoDatetime,
+}
+
+impl GetExpiredUnpaidOrdersQuery {
+    pub async fn execute(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<Vec<Lsps1Order>> {
+        let created = Lsps1OrderState::Created.into_sqlite_integer()?;
+        let expect_payment = PaymentState::ExpectPayment.into_sqlite_integer()?;
+        let expired_before = self.expired_before.into_sqlite_integer()?;
+
+        let result = sqlx::query_as!(
+            Lsps1OrderSqlite,
+            r#"SELECT
+                uuid, client_node_id, lsp_balance_sat,
+                client_balance_sat, funding_confirms_within_blocks,
+                required_channel_confirmations, channel_expiry_blocks,
+                token, refund_onchain_address, announce_channel,
+                ord.created_at, expires_at, os.order_state_enum_id as order_state,
+                os.failure_reason, os.generation
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            JOIN lsps1_payment_details AS pd ON ord.id = pd.order_id
+            JOIN lsps1_payment_state AS ps ON pd.id = ps.payment_details_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND ps.generation = (
+                SELECT MAX(generation) FROM lsps1_payment_state WHERE payment_details_id = pd.id
+            )
+            AND os.order_state_enum_id = ?1
+            AND ps.payment_state = ?2
+            AND ord.expires_at < ?3
+            ORDER BY ord.expires_at;"#,
+            created,
+            expect_payment,
+            expired_before
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        result.iter().map(Lsps1Order::try_from).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::{UpdateOrderStateQuery, UpdatePaymentStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    #[tokio::test]
+    async fn find_expired_unpaid_orders() {
+        let (db, _) = get_temp_db().await;
+        let now = IsoDatetime::now();
+        let expired = IsoDatetime::from_unix_timestamp(now.unix_timestamp() - 60).unwrap();
+        let valid = IsoDatetime::from_unix_timestamp(now.unix_timestamp() + 60).unwrap();
+
+        let mut tx = db.pool.begin().await.unwrap();
+        let mut uuids = vec![];
+        let mut payments = vec![];
+        for expires_at in [expired, expired, valid] {
+            let mut query = create_order_query();
+            query.order.expires_at = expires_at;
+            uuids.push(query.order.uuid);
+            payments.push(query.payment.clone());
+            query.execute(&mut tx).await.unwrap();
+        }
+
+        // The second order was paid before it expired
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            generation: payments[1].generation,
+            label: payments[1].bolt11_invoice_label.clone(),
+            changed_at: now,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuids[1],
+            state: Lsps1OrderState::PendingOpen,
+            generation: OrderGeneration(0),
+            changed_at: now,
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let orders = GetExpiredUnpaidOrdersQuery {
+            expired_before: now,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].uuid, uuids[0]);
+        assert_eq!(orders[0].order_state, Lsps1OrderState::Created);
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::{Lsps1Channel, Lsps1OrderState, OrderGeneration};
+use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
+use crate::db::sqlite::schema::Lsps1Channel as Lsps1ChannelSqlite;
+
+/// An order whose funding transaction was broadcast
+#[derive(Debug, Clone)]
+pub(crate) struct FundedOrder {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) channel: Lsps1Channel,
+    /// `None` if the funding transaction isn't confirmed or the height is unknown
+    pub(crate) funding_blockheight: Option<u32>,
+    /// The generation of the current state
+    pub(crate) generation: OrderGeneration,
+}
+
+/// Finds the orders whose funding transaction might be affected by a reorg
+pub struct GetFundedOrdersQuery {
+    pub(crate) state: Lsps1OrderState,
+    pub(crate) funded_since: Option<u32>,
+}
+
+impl GetFundedOrdersQuery {
+    /// The completed orders whose funding transaction confirmed at or above `blockheight`
+    pub fn completed_since(blockheight: u32) -> Self {
+        Self {
+            state: Lsps1OrderState::Completed,
+            funded_since: Some(blockheight),
+        }
+    }
+
+    /// The orders whose funding transaction was unconfirmed by a reorg
+    pub fn awaiting_confirmation() -> Self {
+        Self {
+            state: Lsps1OrderState::AwaitingConfirmation,
+            funded_since: None,
+        }
+    }
+}
+
+impl GetFundedOrdersQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<FundedOrder>> {
+        let state = self.state.into_sqlite_integer()?;
+        let funded_since = self.funded_since.map(i64::from);
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                ord.uuid, os.generation,
+                c.funding_txid, c.outnum, c.funded_at, c.funding_blockheight
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            JOIN lsps1_channel AS c ON ord.id = c.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND os.order_state_enum_id = ?1
+            AND (?2 IS NULL OR c.funding_blockheight >= ?2)
+            ORDER BY ord.created_at, ord.id;"#,
+            state,
+            funded_since
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        rows.into_iter()
+            .map(|row| {
+                let channel = Lsps1ChannelSqlite {
+                    funding_txid: row.funding_txid,
+                    outnum: row.outnum,
+                    funded_at: row.funded_at,
+                };
+                Ok(FundedOrder {
+                    order_uuid: Uuid::from_str(&row.uuid)?,
+                    channel: Lsps1Channel::try_from(&channel)?,
+                    funding_blockheight: row.funding_blockheight.map(u32::try_from).transpose()?,
+                    generation: OrderGeneration::from_sqlite_integer(row.generation)?,
+                })
+            })
+            .collect()
+    }
+}
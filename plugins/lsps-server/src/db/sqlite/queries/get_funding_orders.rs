@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::{Lsps1Order, Lsps1OrderState};
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+use crate::db::sqlite::schema::Lsps1Order as Lsps1OrderSqlite;
+
+/// Finds all orders for which the latest state is `Funding`
+///
+/// The worker moves an order out of `Funding` once it knows whether the
+/// channel was opened. Orders that are still `Funding` at start-up were
+/// interrupted and must be reconciled.
+pub struct GetFundingOrdersQuery;
+
+impl GetFundingOrdersQuery {
+    pub async fn execute(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<Vec<Lsps1Order>> {
+        let funding = Lsps1OrderState::Funding.into_sqlite_integer()?;
+
+        let result = sqlx::query_as!(
+            Lsps1OrderSqlite,
+            r#"SELECT
+                uuid, client_node_id, lsp_balance_sat,
+                client_balance_sat, funding_confirms_within_blocks,
+                required_channel_confirmations, channel_expiry_blocks,
+                token, refund_onchain_address, announce_channel,
+                ord.created_at, expires_at, os.order_state_enum_id as order_state,
+                os.failure_reason, generation
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND os.order_state_enum_id = ?1
+            ORDER BY ord.created_at;"#,
+            funding
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        result.iter().map(Lsps1Order::try_from).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::UpdateOrderStateQuery;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    #[tokio::test]
+    async fn find_funding_orders() {
+        let (db, _) = get_temp_db().await;
+
+        let funding_query = create_order_query();
+        let funding_uuid = funding_query.order.uuid;
+        let created_query = create_order_query();
+
+        let mut tx = db.begin().await.unwrap();
+        funding_query.execute(&mut tx).await.unwrap();
+        created_query.execute(&mut tx).await.unwrap();
+
+        UpdateOrderStateQuery {
+            order_uuid: funding_uuid,
+            state: Lsps1OrderState::Funding,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let orders = GetFundingOrdersQuery.execute(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].uuid, funding_uuid);
+        assert_eq!(orders[0].order_state, Lsps1OrderState::Funding);
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+/// Finds the version of the most recent migration that was applied
+///
+/// Returns `None` if no migration was applied
+pub(crate) struct GetMigrationVersionQuery;
+
+impl GetMigrationVersionQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<Option<i64>> {
+        let row = sqlx::query!(
+            r#"SELECT MAX(version) AS "version: i64" FROM _sqlx_migrations WHERE success = 1"#
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        Ok(row.version)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::latest_migration_version;
+    use crate::db::sqlite::test::get_temp_db;
+
+    #[tokio::test]
+    async fn migrated_database_has_latest_version() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+        let version = GetMigrationVersionQuery.execute(&mut tx).await.unwrap();
+        assert_eq!(version, latest_migration_version());
+    }
+}
@@ -4,8 +4,14 @@ use uuid::Uuid;
 use sqlx::{Sqlite, Transaction};
 
 use crate::db::schema::Lsps1Order;
+use crate::db::sqlite::quarantine::Quarantined;
 use crate::db::sqlite::schema::Lsps1Order as Lsps1OrderSqlite;
 
+/// Finds an order in its latest state
+///
+/// The state is read from the order. The state history isn't joined.
+/// Fails with a [`crate::db::sqlite::quarantine::CorruptRow`] if the
+/// order can't be converted.
 pub struct GetOrderQuery {
     pub(crate) order_id: Uuid,
 }
@@ -22,29 +28,48 @@ impl GetOrderQuery {
         tx: &'b mut Transaction<'static, Sqlite>,
     ) -> Result<Option<Lsps1Order>> {
         let uuid_string = self.order_id.to_string();
-        let result = sqlx::query_as!(
-            Lsps1OrderSqlite,
+        let result = sqlx::query!(
             r#"SELECT
-                uuid, client_node_id, lsp_balance_sat,
-                client_balance_sat, funding_confirms_within_blocks, 
+                id, uuid, client_node_id, lsp_balance_sat,
+                client_balance_sat, funding_confirms_within_blocks,
                 required_channel_confirmations, channel_expiry_blocks,
                 token, refund_onchain_address, announce_channel,
-                ord.created_at, expires_at, os.order_state_enum_id as order_state,
-                generation
-            FROM lsps1_order AS ord
-            JOIN lsps1_order_state AS os ON ord.id = os.order_id
-            WHERE uuid = ?
-            ORDER BY os.generation DESC
-            LIMIT 1;"#,
+                created_at, expires_at,
+                latest_order_state AS "order_state!",
+                latest_failure_reason AS failure_reason,
+                latest_generation AS "generation!"
+            FROM lsps1_order
+            WHERE uuid = ?;"#,
             uuid_string
         )
         .fetch_optional(&mut **tx)
         .await
         .context("Failed to execute query")?;
 
-        match result {
-            Some(r) => Ok(Some(Lsps1Order::try_from(&r)?)),
-            None => Ok(None),
-        }
+        let order = Quarantined::convert(
+            "lsps1_order",
+            result,
+            |row| row.id,
+            |row| {
+                Lsps1Order::try_from(&Lsps1OrderSqlite {
+                    uuid: row.uuid,
+                    client_node_id: row.client_node_id,
+                    lsp_balance_sat: row.lsp_balance_sat,
+                    client_balance_sat: row.client_balance_sat,
+                    funding_confirms_within_blocks: row.funding_confirms_within_blocks,
+                    required_channel_confirmations: row.required_channel_confirmations,
+                    channel_expiry_blocks: row.channel_expiry_blocks,
+                    token: row.token,
+                    refund_onchain_address: row.refund_onchain_address,
+                    announce_channel: row.announce_channel,
+                    created_at: row.created_at,
+                    expires_at: row.expires_at,
+                    order_state: row.order_state,
+                    failure_reason: row.failure_reason,
+                    generation: row.generation,
+                })
+            },
+        );
+        Ok(order.into_strict()?.pop())
     }
 }
@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+use crate::db::schema::Lsps1OrderLogEntry;
+
+/// Finds the audit log of an order
+///
+/// The entries are returned in the order they were written
+pub struct GetOrderLogQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetOrderLogQuery {
+    pub fn by_uuid(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+}
+
+impl GetOrderLogQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<Lsps1OrderLogEntry>> {
+        let order_uuid = self.order_uuid.to_string();
+
+        let rows = sqlx::query!(
+            r#"SELECT l.created_at, l.severity, l.event_code, l.details
+            FROM lsps1_order_log AS l
+            JOIN lsps1_order AS o ON l.order_id = o.id
+            WHERE o.uuid = ?1
+            ORDER BY l.id;"#,
+            order_uuid
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Lsps1OrderLogEntry {
+                    order_uuid: self.order_uuid,
+                    created_at: IsoDatetime::from_unix_timestamp(row.created_at)?,
+                    severity: FromStr::from_str(&row.severity)?,
+                    event: FromStr::from_str(&row.event_code)?,
+                    details: serde_json::from_str(&row.details)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::db::schema::{OrderLogEvent, OrderLogSeverity};
+    use crate::db::sqlite::queries::CreateOrderLogEntryQuery;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn store_and_read_order_log() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let events = [
+            (OrderLogSeverity::Info, OrderLogEvent::OrderCreated),
+            (OrderLogSeverity::Warning, OrderLogEvent::PeerOffline),
+            (OrderLogSeverity::Info, OrderLogEvent::ChannelOpenStarted),
+        ];
+        for (index, (severity, event)) in events.iter().enumerate() {
+            let entry = Lsps1OrderLogEntry {
+                order_uuid: uuid,
+                // All entries have the same timestamp
+                created_at: IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap(),
+                severity: *severity,
+                event: *event,
+                details: json!({ "index" : index }),
+            };
+            CreateOrderLogEntryQuery::new(&entry)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+        }
+
+        // Unknown orders don't have a log
+        let unknown = Lsps1OrderLogEntry {
+            order_uuid: Uuid::new_v4(),
+            created_at: IsoDatetime::now(),
+            severity: OrderLogSeverity::Info,
+            event: OrderLogEvent::OrderCreated,
+            details: json!({}),
+        };
+        assert!(CreateOrderLogEntryQuery::new(&unknown)
+            .execute(&mut tx)
+            .await
+            .is_err());
+
+        let log = GetOrderLogQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let read: Vec<_> = log.iter().map(|e| (e.severity, e.event)).collect();
+        assert_eq!(read, events);
+        assert_eq!(log[1].details, json!({ "index" : 1 }));
+        assert_eq!(log[2].created_at.unix_timestamp(), 1_700_000_000);
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use serde::Serialize;
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+use lsp_primitives::lsps1::schema::PaymentState;
+use lsp_primitives::sat_amount_json;
+
+use crate::db::schema::{Lsps1FailureReason, Lsps1OrderState};
+use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
+
+/// The orders that were created in a time window
+///
+/// Every order is counted in its latest state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct OrderStats {
+    pub(crate) created: u64,
+    pub(crate) pending_open: u64,
+    pub(crate) funding: u64,
+    pub(crate) channel_opening: u64,
+    pub(crate) awaiting_confirmation: u64,
+    pub(crate) completed: u64,
+    pub(crate) failed: u64,
+    /// The fees of the orders whose payment is `PAID`
+    ///
+    /// Payments that are held or refunded are not included
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) revenue_sat: u64,
+}
+
+impl OrderStats {
+    pub(crate) fn total(&self) -> u64 {
+        self.created
+            + self.pending_open
+            + self.funding
+            + self.channel_opening
+            + self.awaiting_confirmation
+            + self.completed
+            + self.failed
+    }
+
+    fn count_mut(&mut self, state: Lsps1OrderState) -> &mut u64 {
+        match state {
+            Lsps1OrderState::Created => &mut self.created,
+            Lsps1OrderState::PendingOpen => &mut self.pending_open,
+            Lsps1OrderState::Funding => &mut self.funding,
+            Lsps1OrderState::ChannelOpening => &mut self.channel_opening,
+            Lsps1OrderState::AwaitingConfirmation => &mut self.awaiting_confirmation,
+            Lsps1OrderState::Completed => &mut self.completed,
+            Lsps1OrderState::Failed => &mut self.failed,
+        }
+    }
+}
+
+/// Counts the orders created between `from` and `to` and sums their revenue
+///
+/// Both ends of the window are included.
+pub struct GetOrderStatsQuery {
+    pub(crate) from: IsoDatetime,
+    pub(crate) to: IsoDatetime,
+}
+
+impl GetOrderStatsQuery {
+    pub fn between(from: IsoDatetime, to: IsoDatetime) -> Self {
+        Self { from, to }
+    }
+}
+
+impl GetOrderStatsQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<OrderStats> {
+        let from = self.from.into_sqlite_integer()?;
+        let to = self.to.into_sqlite_integer()?;
+        let paid = PaymentState::Paid.into_sqlite_integer()?;
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                os.order_state_enum_id AS order_state,
+                COUNT(*) AS "order_count!: i64"
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND ord.created_at BETWEEN ?1 AND ?2
+            GROUP BY os.order_state_enum_id;"#,
+            from,
+            to
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to count orders")?;
+
+        let mut stats = OrderStats::default();
+        for row in rows {
+            let state = Lsps1OrderState::from_sqlite_integer(row.order_state)?;
+            *stats.count_mut(state) = u64::from_sqlite_integer(row.order_count)?;
+        }
+
+        let revenue = sqlx::query!(
+            r#"SELECT
+                COALESCE(SUM(pd.fee_total_sat), 0) AS "revenue_sat!: i64"
+            FROM lsps1_order AS ord
+            JOIN lsps1_payment_details AS pd ON ord.id = pd.order_id
+            JOIN lsps1_payment_state AS ps ON pd.id = ps.payment_details_id
+            WHERE ps.generation = (
+                SELECT MAX(generation) FROM lsps1_payment_state
+                WHERE payment_details_id = pd.id
+            )
+            AND ps.payment_state = ?3
+            AND ord.created_at BETWEEN ?1 AND ?2;"#,
+            from,
+            to,
+            paid
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .context("Failed to sum revenue")?;
+        stats.revenue_sat = u64::from_sqlite_integer(revenue.revenue_sat)?;
+
+        Ok(stats)
+    }
+}
+
+/// The most recent order that moved to `FAILED`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OrderFailure {
+    pub(crate) order_uuid: Uuid,
+    /// `None` for orders that failed before the reason was tracked
+    pub(crate) failure_reason: Option<Lsps1FailureReason>,
+    pub(crate) failed_at: IsoDatetime,
+}
+
+/// Finds the order that failed most recently
+pub struct GetLastOrderFailureQuery;
+
+impl GetLastOrderFailureQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Option<OrderFailure>> {
+        let failed = Lsps1OrderState::Failed.into_sqlite_integer()?;
+
+        let row = sqlx::query!(
+            r#"SELECT ord.uuid, os.failure_reason, os.created_at
+            FROM lsps1_order_state AS os
+            JOIN lsps1_order AS ord ON ord.id = os.order_id
+            WHERE os.order_state_enum_id = ?1
+            ORDER BY os.created_at DESC, os.id DESC
+            LIMIT 1;"#,
+            failed
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to find the last failed order")?;
+
+        row.map(|row| {
+            Ok(OrderFailure {
+                order_uuid: Uuid::from_str(&row.uuid)?,
+                failure_reason: row
+                    .failure_reason
+                    .as_deref()
+                    .map(Lsps1FailureReason::from_str)
+                    .transpose()?,
+                failed_at: IsoDatetime::from_sqlite_integer(row.created_at)?,
+            })
+        })
+        .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::schema::{OrderGeneration, PaymentGeneration};
+    use crate::db::sqlite::queries::{UpdateOrderStateQuery, UpdatePaymentStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    const NOW: i64 = 1_700_000_000;
+    const HOUR: i64 = 3_600;
+
+    fn at(timestamp: i64) -> IsoDatetime {
+        IsoDatetime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    /// Creates an order at `created_at` that moves to `state`
+    async fn create_order(
+        tx: &mut Transaction<'static, Sqlite>,
+        created_at: i64,
+        state: Lsps1OrderState,
+        paid: bool,
+    ) -> Uuid {
+        let mut query = create_order_query();
+        query.order.created_at = at(created_at);
+        let order_uuid = query.order.uuid;
+        let label = query.payment.bolt11_invoice_label.clone();
+        query.execute(tx).await.unwrap();
+
+        if paid {
+            UpdatePaymentStateQuery {
+                state: PaymentState::Paid,
+                generation: PaymentGeneration(0),
+                label,
+                changed_at: at(created_at),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+        if state != Lsps1OrderState::Created {
+            UpdateOrderStateQuery {
+                order_uuid,
+                state,
+                generation: OrderGeneration(0),
+                changed_at: at(created_at + 60),
+                failure_reason: (state == Lsps1OrderState::Failed)
+                    .then_some(Lsps1FailureReason::OrderExpired),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+        order_uuid
+    }
+
+    #[tokio::test]
+    async fn count_orders_and_revenue_per_window() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        // Within the last day. The test payment has a fee of 500 sat
+        create_order(&mut tx, NOW - HOUR, Lsps1OrderState::Completed, true).await;
+        create_order(&mut tx, NOW - 2 * HOUR, Lsps1OrderState::PendingOpen, true).await;
+        create_order(&mut tx, NOW - 3 * HOUR, Lsps1OrderState::Created, false).await;
+        // Within the last week
+        create_order(&mut tx, NOW - 48 * HOUR, Lsps1OrderState::Completed, true).await;
+        create_order(&mut tx, NOW - 72 * HOUR, Lsps1OrderState::Failed, false).await;
+        // Older than a week
+        create_order(&mut tx, NOW - 200 * HOUR, Lsps1OrderState::Completed, true).await;
+
+        let day = GetOrderStatsQuery::between(at(NOW - 24 * HOUR), at(NOW))
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            day,
+            OrderStats {
+                created: 1,
+                pending_open: 1,
+                completed: 1,
+                revenue_sat: 1_000,
+                ..Default::default()
+            }
+        );
+        assert_eq!(day.total(), 3);
+
+        let week = GetOrderStatsQuery::between(at(NOW - 7 * 24 * HOUR), at(NOW))
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            week,
+            OrderStats {
+                created: 1,
+                pending_open: 1,
+                completed: 2,
+                failed: 1,
+                revenue_sat: 1_500,
+                ..Default::default()
+            }
+        );
+        assert_eq!(week.total(), 5);
+
+        // Both ends of the window are included
+        let edges = GetOrderStatsQuery::between(at(NOW - 3 * HOUR), at(NOW - HOUR))
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(edges.total(), 3);
+
+        let empty = GetOrderStatsQuery::between(at(NOW), at(NOW + HOUR))
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(empty, OrderStats::default());
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn find_the_last_failure() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+        assert_eq!(
+            GetLastOrderFailureQuery.execute(&mut tx).await.unwrap(),
+            None
+        );
+
+        create_order(&mut tx, NOW - 72 * HOUR, Lsps1OrderState::Failed, false).await;
+        let last = create_order(&mut tx, NOW - 48 * HOUR, Lsps1OrderState::Failed, false).await;
+        create_order(&mut tx, NOW - HOUR, Lsps1OrderState::Completed, true).await;
+
+        let failure = GetLastOrderFailureQuery.execute(&mut tx).await.unwrap();
+        assert_eq!(
+            failure,
+            Some(OrderFailure {
+                order_uuid: last,
+                failure_reason: Some(Lsps1FailureReason::OrderExpired),
+                failed_at: at(NOW - 48 * HOUR + 60),
+            })
+        );
+        tx.commit().await.unwrap();
+    }
+}
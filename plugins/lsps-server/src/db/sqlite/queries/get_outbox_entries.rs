@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, PublicKey};
+
+use crate::db::schema::Lsps0OutboxEntry;
+use crate::db::sqlite::conversion::FromSqliteInteger;
+
+/// Finds the responses that still have to be sent
+///
+/// The entries are returned from oldest to newest
+pub struct GetOutboxEntriesQuery {
+    pub(crate) peer_id: Option<PublicKey>,
+}
+
+impl GetOutboxEntriesQuery {
+    pub fn all() -> Self {
+        Self { peer_id: None }
+    }
+
+    pub fn by_peer_id(peer_id: PublicKey) -> Self {
+        Self {
+            peer_id: Some(peer_id),
+        }
+    }
+
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<Lsps0OutboxEntry>> {
+        let peer_id = self.peer_id.as_ref().map(|p| p.to_hex());
+
+        let rows = sqlx::query!(
+            r#"SELECT id, peer_id, payload, created_at, attempts
+            FROM lsps0_response_outbox
+            WHERE (?1 IS NULL OR peer_id = ?1)
+            ORDER BY id;"#,
+            peer_id
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Lsps0OutboxEntry {
+                    id: row.id,
+                    peer_id: PublicKey::from_hex(&row.peer_id)?,
+                    payload: row.payload,
+                    created_at: IsoDatetime::from_sqlite_integer(row.created_at)?,
+                    attempts: u32::from_sqlite_integer(row.attempts)?,
+                })
+            })
+            .collect()
+    }
+}
@@ -3,12 +3,18 @@ use uuid::Uuid;
 
 use sqlx::{Sqlite, Transaction};
 
-use crate::db::schema::Lsps1PaymentDetails;
+use crate::db::schema::{InvoiceLabel, Lsps1PaymentDetails};
+use crate::db::sqlite::quarantine::Quarantined;
 use crate::db::sqlite::schema::Lsps1PaymentDetails as Lsps1PaymentDetailsSqlite;
 
+/// Finds the payment details of an order in their latest state
+///
+/// The state is read from the payment details. The state history isn't joined.
+/// Fails with a [`crate::db::sqlite::quarantine::CorruptRow`] if the
+/// payment details can't be converted.
 pub enum GetPaymentDetailsQuery {
     ByUuid(Uuid),
-    ByLabel(String),
+    ByLabel(InvoiceLabel),
 }
 
 impl GetPaymentDetailsQuery {
@@ -17,7 +23,7 @@ impl GetPaymentDetailsQuery {
     }
 
     #[allow(dead_code)]
-    pub fn by_label(label: String) -> Self {
+    pub fn by_label(label: InvoiceLabel) -> Self {
         Self::ByLabel(label)
     }
 }
@@ -39,27 +45,22 @@ impl GetPaymentDetailsQuery {
     ) -> Result<Option<Lsps1PaymentDetails>> {
         let uuid_string = uuid.to_string();
 
-        let result = sqlx::query_as!(
-            Lsps1PaymentDetailsSqlite,
+        let result = sqlx::query!(
             r#"SELECT
-               o.uuid as order_uuid,
-               p.fee_total_sat,
-               p.order_total_sat,
-               p.bolt11_invoice,
-               p.bolt11_invoice_label,
-               p.onchain_address,
-               p.onchain_block_confirmations_required,
-               p.minimum_fee_for_0conf,
-               ps.payment_state as state,
-               ps.generation
-               FROM lsps1_payment_details as p
-               JOIN lsps1_order as o
-               ON o.id = p.order_id
-               JOIN lsps1_payment_state as ps
-               ON ps.payment_details_id = p.id
-               WHERE o.uuid = ?1
-               ORDER BY ps.generation DESC
-               LIMIT 1;
+               pd.id,
+               o.uuid AS order_uuid,
+               pd.fee_total_sat,
+               pd.order_total_sat,
+               pd.bolt11_invoice,
+               pd.bolt11_invoice_label,
+               pd.onchain_address,
+               pd.onchain_block_confirmations_required,
+               pd.minimum_fee_for_0conf,
+               pd.latest_payment_state AS "state!",
+               pd.latest_generation AS "generation!"
+               FROM lsps1_payment_details AS pd
+               JOIN lsps1_order AS o ON o.id = pd.order_id
+               WHERE o.uuid = ?1;
                "#,
             uuid_string
         )
@@ -67,45 +68,152 @@ impl GetPaymentDetailsQuery {
         .await
         .context("Failed to execute query")?;
 
-        match result {
-            Some(r) => Ok(Some(Lsps1PaymentDetails::try_from(&r)?)),
-            None => Ok(None),
-        }
+        let payment_details = Quarantined::convert(
+            "lsps1_payment_details",
+            result,
+            |row| row.id,
+            |row| {
+                Lsps1PaymentDetails::try_from(&Lsps1PaymentDetailsSqlite {
+                    order_uuid: row.order_uuid,
+                    fee_total_sat: row.fee_total_sat,
+                    order_total_sat: row.order_total_sat,
+                    bolt11_invoice: row.bolt11_invoice,
+                    bolt11_invoice_label: row.bolt11_invoice_label,
+                    onchain_address: row.onchain_address,
+                    onchain_block_confirmations_required: row.onchain_block_confirmations_required,
+                    minimum_fee_for_0conf: row.minimum_fee_for_0conf,
+                    state: row.state,
+                    generation: row.generation,
+                })
+            },
+        );
+        Ok(payment_details.into_strict()?.pop())
     }
 
     pub(crate) async fn execute_by_label(
-        label: &str,
+        label: &InvoiceLabel,
         tx: &mut Transaction<'_, Sqlite>,
     ) -> Result<Option<Lsps1PaymentDetails>> {
         log::debug!("Get payment by label={}", label);
 
-        let payment_details = sqlx::query_as!(
-            Lsps1PaymentDetailsSqlite,
+        let label = label.as_str();
+        let result = sqlx::query!(
             r#"
-            SELECT 
-                od.uuid as order_uuid,
-                fee_total_sat, order_total_sat, bolt11_invoice, 
-                bolt11_invoice_label, minimum_fee_for_0conf, 
-                onchain_address, onchain_block_confirmations_required,
-                ps.payment_state as state,
-                ps.generation
+            SELECT
+                pd.id,
+                o.uuid AS order_uuid,
+                pd.fee_total_sat, pd.order_total_sat, pd.bolt11_invoice,
+                pd.bolt11_invoice_label, pd.minimum_fee_for_0conf,
+                pd.onchain_address, pd.onchain_block_confirmations_required,
+                pd.latest_payment_state AS "state!",
+                pd.latest_generation AS "generation!"
             FROM lsps1_payment_details AS pd
-            JOIN lsps1_payment_state AS ps
-            ON pd.id = ps.payment_details_id
-            JOIN lsps1_order as od
-            ON od.id = pd.order_id
+            JOIN lsps1_order AS o ON o.id = pd.order_id
             WHERE pd.bolt11_invoice_label = ?1
-            ORDER by ps.generation DESC
-            LIMIT 1
             "#,
             label
         )
         .fetch_optional(&mut **tx)
         .await?;
 
-        match payment_details {
-            Some(r) => Ok(Some(Lsps1PaymentDetails::try_from(&r)?)),
-            None => Ok(None),
+        let payment_details = Quarantined::convert(
+            "lsps1_payment_details",
+            result,
+            |row| row.id,
+            |row| {
+                Lsps1PaymentDetails::try_from(&Lsps1PaymentDetailsSqlite {
+                    order_uuid: row.order_uuid,
+                    fee_total_sat: row.fee_total_sat,
+                    order_total_sat: row.order_total_sat,
+                    bolt11_invoice: row.bolt11_invoice,
+                    bolt11_invoice_label: row.bolt11_invoice_label,
+                    onchain_address: row.onchain_address,
+                    onchain_block_confirmations_required: row.onchain_block_confirmations_required,
+                    minimum_fee_for_0conf: row.minimum_fee_for_0conf,
+                    state: row.state,
+                    generation: row.generation,
+                })
+            },
+        );
+        Ok(payment_details.into_strict()?.pop())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::{FeeRate, SatAmount};
+
+    use crate::db::sqlite::quarantine::CorruptRow;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    #[tokio::test]
+    async fn payment_details_round_trip() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        let mut query = create_order_query();
+        query.payment.fee_total_sat = SatAmount::new(500);
+        query.payment.order_total_sat = SatAmount::new(100_500);
+        query.payment.onchain_address = Some("bcrt1qtest".to_string());
+        query.payment.onchain_block_confirmations_required = Some(6);
+        query.payment.minimum_fee_for_0conf = Some(FeeRate::from_sats_per_kwu(253));
+        let uuid = query.order.uuid;
+        let label = query.payment.bolt11_invoice_label.clone();
+        query.execute(&mut tx).await.unwrap();
+
+        let by_uuid = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let by_label = GetPaymentDetailsQuery::by_label(label)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        for payment in [by_uuid, by_label] {
+            assert_eq!(payment.fee_total_sat, SatAmount::new(500));
+            assert_eq!(payment.order_total_sat, SatAmount::new(100_500));
+            assert_eq!(payment.onchain_block_confirmations_required, Some(6));
+            assert_eq!(
+                payment.minimum_fee_for_0conf.map(|f| f.to_sats_per_kwu()),
+                Some(253)
+            );
         }
+        tx.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_payment_details_fail_with_the_row() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+        let label = query.payment.bolt11_invoice_label.clone();
+        query.execute(&mut tx).await.unwrap();
+        sqlx::query("UPDATE lsps1_payment_details SET fee_total_sat = -1")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let err = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .err()
+            .unwrap();
+        let row = err.downcast_ref::<CorruptRow>().unwrap();
+        assert_eq!(row.table, "lsps1_payment_details");
+        assert_eq!(row.error, "Stored value -1 is not a valid SatAmount");
+
+        let err = GetPaymentDetailsQuery::by_label(label)
+            .execute(&mut tx)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<CorruptRow>().is_some());
+        tx.commit().await.unwrap();
     }
 }
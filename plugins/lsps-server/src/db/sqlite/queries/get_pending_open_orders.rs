@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps1::schema::PaymentState;
+
+use crate::db::schema::{Lsps1Order, Lsps1OrderState};
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+use crate::db::sqlite::schema::Lsps1Order as Lsps1OrderSqlite;
+
+/// Finds all orders for which the latest state is `PendingOpen`
+///
+/// These orders have been paid but the channel hasn't been opened
+/// yet because the client was offline.
+///
+/// The orders are sorted by the time they were paid. Channels are
+/// opened in the order clients paid for them.
+pub struct GetPendingOpenOrdersQuery {
+    pub(crate) peer_id: Option<PublicKey>,
+}
+
+impl GetPendingOpenOrdersQuery {
+    pub fn all() -> Self {
+        Self { peer_id: None }
+    }
+
+    pub fn by_peer_id(peer_id: PublicKey) -> Self {
+        Self {
+            peer_id: Some(peer_id),
+        }
+    }
+}
+
+impl GetPendingOpenOrdersQuery {
+    pub async fn execute(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<Vec<Lsps1Order>> {
+        let pending_open = Lsps1OrderState::PendingOpen.into_sqlite_integer()?;
+        let peer_id = self.peer_id.as_ref().map(|p| p.to_hex());
+        let paid = PaymentState::Paid.into_sqlite_integer()?;
+
+        let result = sqlx::query_as!(
+            Lsps1OrderSqlite,
+            r#"SELECT
+                uuid, client_node_id, lsp_balance_sat,
+                client_balance_sat, funding_confirms_within_blocks,
+                required_channel_confirmations, channel_expiry_blocks,
+                token, refund_onchain_address, announce_channel,
+                ord.created_at, expires_at, os.order_state_enum_id as order_state,
+                os.failure_reason, generation
+            FROM lsps1_order AS ord
+            JOIN lsps1_order_state AS os ON ord.id = os.order_id
+            WHERE os.generation = (
+                SELECT MAX(generation) FROM lsps1_order_state WHERE order_id = ord.id
+            )
+            AND os.order_state_enum_id = ?1
+            AND (?2 IS NULL OR ord.client_node_id = ?2)
+            ORDER BY COALESCE(
+                (SELECT MIN(ps.created_at)
+                    FROM lsps1_payment_details AS pd
+                    JOIN lsps1_payment_state AS ps ON ps.payment_details_id = pd.id
+                    WHERE pd.order_id = ord.id AND ps.payment_state = ?3),
+                ord.created_at
+            ), ord.created_at;"#,
+            pending_open,
+            peer_id,
+            paid
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        result.iter().map(Lsps1Order::try_from).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::UpdateOrderStateQuery;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn find_pending_open_orders_by_peer() {
+        let db = get_db().await;
+
+        // Use a peer that isn't used by other tests
+        let peer_id = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        let mut pending_query = create_order_query();
+        pending_query.order.client_node_id = peer_id;
+        let pending_uuid = pending_query.order.uuid;
+
+        let mut created_query = create_order_query();
+        created_query.order.client_node_id = peer_id;
+
+        let mut tx = db.pool.begin().await.unwrap();
+        pending_query.execute(&mut tx).await.unwrap();
+        created_query.execute(&mut tx).await.unwrap();
+
+        UpdateOrderStateQuery {
+            order_uuid: pending_uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let orders = GetPendingOpenOrdersQuery::by_peer_id(peer_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].uuid, pending_uuid);
+        assert_eq!(orders[0].order_state, Lsps1OrderState::PendingOpen);
+
+        // Once the channel is opened the order is no longer pending
+        UpdateOrderStateQuery {
+            order_uuid: pending_uuid,
+            state: Lsps1OrderState::Completed,
+            generation: orders[0].generation,
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let orders = GetPendingOpenOrdersQuery::by_peer_id(peer_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(orders.is_empty());
+    }
+}
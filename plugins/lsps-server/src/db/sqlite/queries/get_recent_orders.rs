@@ -0,0 +1,216 @@
+use anyhow::{Context, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+use crate::db::schema::Lsps1Order;
+use crate::db::sqlite::quarantine::Quarantined;
+use crate::db::sqlite::schema::Lsps1Order as Lsps1OrderSqlite;
+
+/// Finds the most recently created orders in their latest state
+///
+/// The newest order comes first.
+pub struct GetRecentOrdersQuery {
+    pub(crate) limit: u32,
+}
+
+impl GetRecentOrdersQuery {
+    pub fn last(limit: u32) -> Self {
+        Self { limit }
+    }
+}
+
+impl GetRecentOrdersQuery {
+    /// Fails if any of the orders is corrupt
+    pub async fn execute(&self, tx: &mut Transaction<'static, Sqlite>) -> Result<Vec<Lsps1Order>> {
+        self.execute_quarantined(tx).await?.into_strict()
+    }
+
+    /// Skips the orders that are corrupt and reports them separately
+    pub(crate) async fn execute_quarantined(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Quarantined<Lsps1Order>> {
+        let limit = i64::from(self.limit);
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                ord.id, uuid, client_node_id, lsp_balance_sat,
+                client_balance_sat, funding_confirms_within_blocks,
+                required_channel_confirmations, channel_expiry_blocks,
+                token, refund_onchain_address, announce_channel,
+                ord.created_at, expires_at,
+                latest_order_state AS "order_state!",
+                latest_failure_reason AS failure_reason,
+                latest_generation AS "generation!"
+            FROM lsps1_order AS ord
+            ORDER BY ord.created_at DESC, ord.id DESC
+            LIMIT ?1;"#,
+            limit
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        Ok(Quarantined::convert(
+            "lsps1_order",
+            rows,
+            |row| row.id,
+            |row| {
+                Lsps1Order::try_from(&Lsps1OrderSqlite {
+                    uuid: row.uuid,
+                    client_node_id: row.client_node_id,
+                    lsp_balance_sat: row.lsp_balance_sat,
+                    client_balance_sat: row.client_balance_sat,
+                    funding_confirms_within_blocks: row.funding_confirms_within_blocks,
+                    required_channel_confirmations: row.required_channel_confirmations,
+                    channel_expiry_blocks: row.channel_expiry_blocks,
+                    token: row.token,
+                    refund_onchain_address: row.refund_onchain_address,
+                    announce_channel: row.announce_channel,
+                    created_at: row.created_at,
+                    expires_at: row.expires_at,
+                    order_state: row.order_state,
+                    failure_reason: row.failure_reason,
+                    generation: row.generation,
+                })
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    #[tokio::test]
+    async fn find_recent_orders() {
+        let (db, _) = get_temp_db().await;
+
+        let mut uuids = Vec::new();
+        let mut tx = db.begin().await.unwrap();
+        for _ in 0..3 {
+            let query = create_order_query();
+            uuids.push(query.order.uuid);
+            query.execute(&mut tx).await.unwrap();
+        }
+
+        let orders = GetRecentOrdersQuery::last(2)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let found: Vec<_> = orders.iter().map(|o| o.uuid).collect();
+        assert_eq!(found, vec![uuids[2], uuids[1]]);
+    }
+
+    #[tokio::test]
+    async fn corrupt_orders_are_quarantined() {
+        let (db, _) = get_temp_db().await;
+
+        let mut uuids = Vec::new();
+        let mut tx = db.begin().await.unwrap();
+        for _ in 0..3 {
+            let query = create_order_query();
+            uuids.push(query.order.uuid);
+            query.execute(&mut tx).await.unwrap();
+        }
+        sqlx::query("UPDATE lsps1_order SET uuid = 'not-a-uuid' WHERE uuid = ?1")
+            .bind(uuids[1].to_string())
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let query = GetRecentOrdersQuery::last(3);
+        let result = query.execute_quarantined(&mut tx).await.unwrap();
+        let found: Vec<_> = result.rows.iter().map(|o| o.uuid).collect();
+        assert_eq!(found, vec![uuids[2], uuids[0]]);
+        assert_eq!(result.corrupt_rows.len(), 1);
+        assert_eq!(result.corrupt_rows[0].table, "lsps1_order");
+
+        // A strict query fails on the corrupt order
+        assert!(query.execute(&mut tx).await.is_err());
+        tx.commit().await.unwrap();
+    }
+
+    /// How a stored integer is read
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Read {
+        Valid,
+        Corrupt,
+        /// Sqlite stores `i64::MAX + 1` as a real. It is either read
+        /// as `i64::MAX` or rejected, but never fails the query.
+        Either,
+    }
+
+    #[tokio::test]
+    async fn boundary_values_are_read_or_quarantined() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        let max = i64::MAX.to_string();
+        let max = max.as_str();
+        let beyond_max = (i64::MAX as u64 + 1).to_string();
+        let beyond_max = beyond_max.as_str();
+        let cases = [
+            ("created_at", "0", Read::Valid),
+            ("created_at", "-1", Read::Corrupt),
+            ("created_at", max, Read::Corrupt),
+            ("created_at", beyond_max, Read::Corrupt),
+            ("expires_at", "0", Read::Valid),
+            ("expires_at", max, Read::Corrupt),
+            ("expires_at", beyond_max, Read::Corrupt),
+            ("lsp_balance_sat", "0", Read::Valid),
+            ("lsp_balance_sat", "-1", Read::Corrupt),
+            ("lsp_balance_sat", max, Read::Valid),
+            ("lsp_balance_sat", beyond_max, Read::Either),
+            ("client_balance_sat", "0", Read::Valid),
+            ("client_balance_sat", max, Read::Valid),
+            ("client_balance_sat", beyond_max, Read::Either),
+            ("funding_confirms_within_blocks", "0", Read::Valid),
+            ("funding_confirms_within_blocks", max, Read::Corrupt),
+            ("required_channel_confirmations", beyond_max, Read::Corrupt),
+            ("channel_expiry_blocks", "0", Read::Valid),
+            ("channel_expiry_blocks", max, Read::Corrupt),
+            ("channel_expiry_blocks", beyond_max, Read::Corrupt),
+            ("latest_generation", "0", Read::Valid),
+            ("latest_generation", "-1", Read::Corrupt),
+            ("latest_generation", max, Read::Valid),
+            ("latest_generation", beyond_max, Read::Either),
+        ];
+
+        let mut uuids = Vec::new();
+        for (column, value, _) in cases {
+            let query = create_order_query();
+            let uuid = query.order.uuid;
+            query.execute(&mut tx).await.unwrap();
+            let sql = format!(
+                "UPDATE lsps1_order SET {} = {} WHERE uuid = ?1",
+                column, value
+            );
+            sqlx::query(&sql)
+                .bind(uuid.to_string())
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            uuids.push(uuid);
+        }
+
+        let limit = u32::try_from(cases.len()).unwrap();
+        let result = GetRecentOrdersQuery::last(limit)
+            .execute_quarantined(&mut tx)
+            .await
+            .unwrap();
+        for ((column, value, expected), uuid) in cases.iter().zip(uuids) {
+            let found = result.rows.iter().any(|order| order.uuid == uuid);
+            match expected {
+                Read::Valid => assert!(found, "{} = {} should be read", column, value),
+                Read::Corrupt => assert!(!found, "{} = {} should be quarantined", column, value),
+                Read::Either => {}
+            }
+        }
+        assert_eq!(result.rows.len() + result.corrupt_rows.len(), cases.len());
+        tx.commit().await.unwrap();
+    }
+}
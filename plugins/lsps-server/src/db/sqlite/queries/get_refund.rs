@@ -0,0 +1,46 @@
+use anyhow::{anyhow, Result};
+
+use sqlx::Sqlite;
+use sqlx::Transaction;
+
+use crate::db::schema::Lsps1Refund;
+use crate::db::sqlite::schema::Lsps1Refund as Lsps1RefundSqlite;
+use uuid::Uuid;
+
+pub(crate) struct GetRefundQuery {
+    order_uuid: Uuid,
+}
+
+impl GetRefundQuery {
+    pub(crate) fn by_order_id(uuid: Uuid) -> Self {
+        GetRefundQuery { order_uuid: uuid }
+    }
+}
+
+impl GetRefundQuery {
+    pub async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Option<Lsps1Refund>, anyhow::Error> {
+        let order_str = self.order_uuid.to_string();
+
+        let refund = sqlx::query_as!(
+            Lsps1RefundSqlite,
+            r#"
+             SELECT r.txid, r.amount_sat, r.address, r.broadcast_at FROM lsps1_refunds as r
+             JOIN lsps1_order as od
+              ON r.order_id = od.id
+              WHERE od.uuid = ?1
+              "#,
+            order_str
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| anyhow!("db.get_refund Failed: {}", e))?;
+
+        match refund {
+            None => Ok(None),
+            Some(refund) => Ok(Some(Lsps1Refund::try_from(&refund)?)),
+        }
+    }
+}
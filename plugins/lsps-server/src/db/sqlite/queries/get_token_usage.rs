@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+
+use serde::Serialize;
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+use lsp_primitives::lsps1::schema::PaymentState;
+use lsp_primitives::sat_amount_json;
+
+use crate::admin_timestamp;
+use crate::db::schema::Lsps1OrderState;
+use crate::db::sqlite::conversion::{saturating_timestamp, FromSqliteInteger, IntoSqliteInteger};
+
+/// The orders that were created with a token
+///
+/// Every order is counted in its latest state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct TokenUsage {
+    pub(crate) token: String,
+    pub(crate) order_count: u64,
+    pub(crate) completed_count: u64,
+    pub(crate) failed_count: u64,
+    /// The fees of the orders whose payment is `PAID`
+    ///
+    /// Payments that are held or refunded are not included
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) revenue_sat: u64,
+    #[serde(serialize_with = "admin_timestamp::serialize")]
+    pub(crate) first_used_at: IsoDatetime,
+    #[serde(serialize_with = "admin_timestamp::serialize")]
+    pub(crate) last_used_at: IsoDatetime,
+}
+
+/// Summarizes the orders per token
+///
+/// Orders without a token are not included.
+pub struct GetTokenUsageQuery {
+    pub(crate) token: Option<String>,
+}
+
+impl GetTokenUsageQuery {
+    pub fn all() -> Self {
+        Self { token: None }
+    }
+
+    pub fn by_token(token: String) -> Self {
+        Self { token: Some(token) }
+    }
+}
+
+impl GetTokenUsageQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'static, Sqlite>,
+    ) -> Result<Vec<TokenUsage>> {
+        let completed = Lsps1OrderState::Completed.into_sqlite_integer()?;
+        let failed = Lsps1OrderState::Failed.into_sqlite_integer()?;
+        let paid = PaymentState::Paid.into_sqlite_integer()?;
+
+        let rows = sqlx::query!(
+            r#"SELECT
+                ord.token AS "token!",
+                COUNT(*) AS "order_count!: i64",
+                SUM(ord.latest_order_state = ?2) AS "completed_count!: i64",
+                SUM(ord.latest_order_state = ?3) AS "failed_count!: i64",
+                SUM(
+                    CASE WHEN pd.latest_payment_state = ?4 THEN pd.fee_total_sat ELSE 0 END
+                ) AS "revenue_sat!: i64",
+                MIN(ord.created_at) AS "first_used_at!: i64",
+                MAX(ord.created_at) AS "last_used_at!: i64"
+            FROM lsps1_order AS ord
+            JOIN lsps1_payment_details AS pd ON ord.id = pd.order_id
+            WHERE ord.token IS NOT NULL
+            AND (?1 IS NULL OR ord.token = ?1)
+            GROUP BY ord.token
+            ORDER BY ord.token;"#,
+            self.token,
+            completed,
+            failed,
+            paid
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to summarize token usage")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(TokenUsage {
+                    token: row.token,
+                    order_count: u64::from_sqlite_integer(row.order_count)?,
+                    completed_count: u64::from_sqlite_integer(row.completed_count)?,
+                    failed_count: u64::from_sqlite_integer(row.failed_count)?,
+                    revenue_sat: u64::from_sqlite_integer(row.revenue_sat)?,
+                    // A corrupt timestamp shouldn't hide the usage of a token
+                    first_used_at: saturating_timestamp(row.first_used_at),
+                    last_used_at: saturating_timestamp(row.last_used_at),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::schema::{Lsps1FailureReason, OrderGeneration, PaymentGeneration};
+    use crate::db::sqlite::queries::{UpdateOrderStateQuery, UpdatePaymentStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    const NOW: i64 = 1_700_000_000;
+    const HOUR: i64 = 3_600;
+
+    fn at(timestamp: i64) -> IsoDatetime {
+        IsoDatetime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    /// Creates an order with `token` at `created_at` that moves to `state`
+    async fn create_order(
+        tx: &mut Transaction<'static, Sqlite>,
+        token: Option<&str>,
+        created_at: i64,
+        state: Lsps1OrderState,
+        paid: bool,
+    ) {
+        let mut query = create_order_query();
+        query.order.token = token.map(str::to_string);
+        query.order.created_at = at(created_at);
+        let order_uuid = query.order.uuid;
+        let label = query.payment.bolt11_invoice_label.clone();
+        query.execute(tx).await.unwrap();
+
+        if paid {
+            UpdatePaymentStateQuery {
+                state: PaymentState::Paid,
+                generation: PaymentGeneration(0),
+                label,
+                changed_at: at(created_at),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+        if state != Lsps1OrderState::Created {
+            UpdateOrderStateQuery {
+                order_uuid,
+                state,
+                generation: OrderGeneration(0),
+                changed_at: at(created_at + 60),
+                failure_reason: (state == Lsps1OrderState::Failed)
+                    .then_some(Lsps1FailureReason::ChannelOpenFailed),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn summarize_orders_per_token() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        // The test payment has a fee of 500 sat
+        create_order(
+            &mut tx,
+            Some("spring"),
+            NOW - 3 * HOUR,
+            Lsps1OrderState::Completed,
+            true,
+        )
+        .await;
+        create_order(
+            &mut tx,
+            Some("spring"),
+            NOW - HOUR,
+            Lsps1OrderState::Completed,
+            true,
+        )
+        .await;
+        create_order(
+            &mut tx,
+            Some("spring"),
+            NOW - 2 * HOUR,
+            Lsps1OrderState::Failed,
+            false,
+        )
+        .await;
+        create_order(
+            &mut tx,
+            Some("spring"),
+            NOW,
+            Lsps1OrderState::Created,
+            false,
+        )
+        .await;
+        create_order(
+            &mut tx,
+            Some("autumn"),
+            NOW - HOUR,
+            Lsps1OrderState::PendingOpen,
+            true,
+        )
+        .await;
+        create_order(&mut tx, None, NOW, Lsps1OrderState::Completed, true).await;
+
+        let usage = GetTokenUsageQuery::all().execute(&mut tx).await.unwrap();
+        assert_eq!(
+            usage,
+            vec![
+                TokenUsage {
+                    token: "autumn".to_string(),
+                    order_count: 1,
+                    completed_count: 0,
+                    failed_count: 0,
+                    revenue_sat: 500,
+                    first_used_at: at(NOW - HOUR),
+                    last_used_at: at(NOW - HOUR),
+                },
+                TokenUsage {
+                    token: "spring".to_string(),
+                    order_count: 4,
+                    completed_count: 2,
+                    failed_count: 1,
+                    revenue_sat: 1_000,
+                    first_used_at: at(NOW - 3 * HOUR),
+                    last_used_at: at(NOW),
+                },
+            ]
+        );
+
+        let spring = GetTokenUsageQuery::by_token("spring".to_string())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(spring, usage[1..]);
+
+        let unknown = GetTokenUsageQuery::by_token("winter".to_string())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(unknown.is_empty());
+        tx.commit().await.unwrap();
+    }
+}
@@ -1,15 +1,84 @@
+mod block;
+mod channel_type;
+mod claim_instance;
+mod client_node;
 mod create_channel;
+mod create_channel_usage_sample;
 mod create_order;
+mod create_order_log_entry;
+mod create_outbox_entry;
+mod create_refund;
+mod funding_reservation;
+mod funding_source;
+mod get_active_leases;
 mod get_channel;
+mod get_channel_opening_orders;
+mod get_channel_order;
+mod get_channel_usage;
+mod get_expired_unpaid_orders;
+mod get_funded_orders;
+mod get_funding_orders;
+mod get_migration_version;
 mod get_order;
+mod get_order_log;
+mod get_order_stats;
+mod get_outbox_entries;
 mod get_payment_details;
+mod get_pending_open_orders;
+mod get_recent_orders;
+mod get_refund;
+mod get_token_usage;
+mod receipt;
+mod record_invoice_deleted;
+mod set_funding_blockheight;
 mod update_order_state;
+mod update_outbox_entry;
 mod update_payment_state;
 
+pub(crate) use block::{ChainBlock, GetBlockQuery, GetChainTipQuery, RecordBlockQuery};
+pub(crate) use channel_type::{GetChannelTypeQuery, SetChannelTypeQuery};
+pub(crate) use claim_instance::{ClaimInstanceQuery, ReleaseInstanceQuery};
+pub(crate) use client_node::{GetClientNodeQuery, SetClientNodeQuery};
 pub(crate) use create_channel::CreateChannelQuery;
+pub(crate) use create_channel_usage_sample::CreateChannelUsageSampleQuery;
 pub(crate) use create_order::Lsps1CreateOrderQuery;
+pub(crate) use create_order_log_entry::CreateOrderLogEntryQuery;
+pub(crate) use create_outbox_entry::CreateOutboxEntryQuery;
+pub(crate) use create_refund::CreateRefundQuery;
+pub(crate) use funding_reservation::{
+    CreateFundingReservationQuery, DeleteFundingReservationQuery, GetFundingReservationQuery,
+    GetStaleFundingReservationsQuery,
+};
+pub(crate) use funding_source::{
+    GetCommittedLiquidityQuery, GetFundingSourceQuery, SetFundingSourceQuery,
+};
+pub(crate) use get_active_leases::{ActiveLease, GetActiveLeasesQuery};
 pub(crate) use get_channel::GetChannelQuery;
+pub(crate) use get_channel_opening_orders::{ChannelOpeningOrder, GetChannelOpeningOrdersQuery};
+pub(crate) use get_channel_order::GetChannelOrderQuery;
+pub(crate) use get_channel_usage::{GetChannelUsageQuery, GetLastChannelUsageAtQuery};
+pub(crate) use get_expired_unpaid_orders::GetExpiredUnpaidOrdersQuery;
+pub(crate) use get_funded_orders::{FundedOrder, GetFundedOrdersQuery};
+pub(crate) use get_funding_orders::GetFundingOrdersQuery;
+pub(crate) use get_migration_version::GetMigrationVersionQuery;
 pub(crate) use get_order::GetOrderQuery;
+pub(crate) use get_order_log::GetOrderLogQuery;
+pub(crate) use get_order_stats::{
+    GetLastOrderFailureQuery, GetOrderStatsQuery, OrderFailure, OrderStats,
+};
+pub(crate) use get_outbox_entries::GetOutboxEntriesQuery;
 pub(crate) use get_payment_details::GetPaymentDetailsQuery;
+pub(crate) use get_pending_open_orders::GetPendingOpenOrdersQuery;
+pub(crate) use get_recent_orders::GetRecentOrdersQuery;
+pub(crate) use get_refund::GetRefundQuery;
+pub(crate) use get_token_usage::{GetTokenUsageQuery, TokenUsage};
+pub(crate) use receipt::{GetReceiptQuery, SetReceiptQuery};
+pub(crate) use record_invoice_deleted::{GetInvoiceDeletedAtQuery, RecordInvoiceDeletedQuery};
+pub(crate) use set_funding_blockheight::{
+    ClearFundingBlockheightQuery, SetFundingBlockheightQuery,
+};
 pub(crate) use update_order_state::UpdateOrderStateQuery;
+pub(crate) use update_outbox_entry::{
+    DeleteExpiredOutboxEntriesQuery, DeleteOutboxEntryQuery, RecordOutboxAttemptQuery,
+};
 pub(crate) use update_payment_state::UpdatePaymentStateQuery;
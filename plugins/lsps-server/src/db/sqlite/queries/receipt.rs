@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use uuid::Uuid;
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps1::receipt::OrderReceipt;
+
+/// Stores the receipt of a completed order
+///
+/// A receipt is issued only once. Returns false if the order already
+/// has a receipt or if the order doesn't exist.
+pub struct SetReceiptQuery {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) receipt: OrderReceipt,
+}
+
+impl SetReceiptQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<bool> {
+        let uuid = self.order_uuid.to_string();
+        let result = sqlx::query!(
+            r#"
+            UPDATE lsps1_order
+            SET receipt_payload = ?1, receipt_zbase_signature = ?2
+            WHERE uuid = ?3 AND receipt_payload IS NULL
+            "#,
+            self.receipt.payload,
+            self.receipt.zbase_signature,
+            uuid
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// Returns the receipt of an order
+///
+/// Returns `None` if the order doesn't exist or has no receipt yet
+pub struct GetReceiptQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl GetReceiptQuery {
+    pub(crate) fn by_uuid(order_uuid: Uuid) -> Self {
+        Self { order_uuid }
+    }
+
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<OrderReceipt>> {
+        let uuid = self.order_uuid.to_string();
+        let result = sqlx::query!(
+            r#"
+            SELECT receipt_payload, receipt_zbase_signature
+            FROM lsps1_order
+            WHERE uuid = ?1
+            "#,
+            uuid
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to execute query")?;
+
+        Ok(result.and_then(|row| {
+            Some(OrderReceipt {
+                payload: row.receipt_payload?,
+                zbase_signature: row.receipt_zbase_signature?,
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    fn receipt(payload: &str) -> OrderReceipt {
+        OrderReceipt {
+            payload: payload.to_string(),
+            zbase_signature: "d6tqaeuonjhi98mmont9m4wag7gg4krg1f4txonug3h31e9h6p6k6".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn receipt_is_issued_once() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let stored = GetReceiptQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert!(stored.is_none());
+
+        let first = SetReceiptQuery {
+            order_uuid: uuid,
+            receipt: receipt("first"),
+        };
+        assert!(first.execute(&mut tx).await.unwrap());
+
+        // The receipt that was issued first is kept
+        let second = SetReceiptQuery {
+            order_uuid: uuid,
+            receipt: receipt("second"),
+        };
+        assert!(!second.execute(&mut tx).await.unwrap());
+        let stored = GetReceiptQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(stored, Some(receipt("first")));
+        tx.commit().await.unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+use crate::db::schema::{InvoiceLabel, PaymentGeneration};
+use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
+
+/// Records that the invoice of a payment was deleted from lightningd
+///
+/// Appends a row to the payment state history which repeats the
+/// current state. The update only succeeds if `generation` is the
+/// current generation of the payment.
+pub struct RecordInvoiceDeletedQuery {
+    pub(crate) label: InvoiceLabel,
+    pub(crate) generation: PaymentGeneration,
+    pub(crate) deleted_at: IsoDatetime,
+}
+
+impl RecordInvoiceDeletedQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let deleted_at = self.deleted_at.into_sqlite_integer()?;
+        let generation = self.generation.into_sqlite_integer()?;
+        let new_generation = self.generation.next().into_sqlite_integer()?;
+        let label = self.label.as_str();
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE lsps1_payment_details
+            SET latest_generation = ?1
+            WHERE bolt11_invoice_label = ?2 AND latest_generation = ?3
+            "#,
+            new_generation,
+            label,
+            generation
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        if result.rows_affected() != 1 {
+            return Err(anyhow!(
+                "Payment {} was modified concurrently. It is no longer at generation {}",
+                self.label,
+                self.generation
+            ));
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO lsps1_payment_state
+                (payment_details_id, payment_state, created_at, generation, invoice_deleted_at)
+            SELECT pd.id, pd.latest_payment_state, ?1, ?2, ?1
+                FROM lsps1_payment_details AS pd
+                WHERE pd.bolt11_invoice_label = ?3
+            "#,
+            deleted_at,
+            new_generation,
+            label
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Returns when the invoice of a payment was deleted from lightningd
+pub struct GetInvoiceDeletedAtQuery {
+    pub(crate) label: InvoiceLabel,
+}
+
+impl GetInvoiceDeletedAtQuery {
+    pub(crate) async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<Option<IsoDatetime>> {
+        let label = self.label.as_str();
+        let deleted_at = sqlx::query_scalar!(
+            r#"
+            SELECT MAX(ps.invoice_deleted_at) AS "deleted_at?"
+            FROM lsps1_payment_state AS ps
+            JOIN lsps1_payment_details AS pd ON ps.payment_details_id = pd.id
+            WHERE pd.bolt11_invoice_label = ?1
+            "#,
+            label
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+
+        deleted_at.map(IsoDatetime::from_sqlite_integer).transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps1::schema::PaymentState;
+
+    use crate::db::sqlite::queries::GetPaymentDetailsQuery;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+
+    #[tokio::test]
+    async fn deletion_is_appended_to_the_history() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let payment = query.payment.clone();
+        let label = payment.bolt11_invoice_label.clone();
+
+        let mut tx = db.pool.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let deleted_at = GetInvoiceDeletedAtQuery {
+            label: label.clone(),
+        };
+        assert!(deleted_at.execute(&mut tx).await.unwrap().is_none());
+
+        let record = RecordInvoiceDeletedQuery {
+            label: label.clone(),
+            generation: payment.generation,
+            deleted_at: IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap(),
+        };
+        record.execute(&mut tx).await.unwrap();
+
+        // The deletion can only be recorded once per generation
+        assert!(record.execute(&mut tx).await.is_err());
+
+        let details = GetPaymentDetailsQuery::by_label(label)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let deleted_at = deleted_at.execute(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(details.state, PaymentState::ExpectPayment);
+        assert_eq!(details.generation, payment.generation.next());
+        assert_eq!(deleted_at.unwrap().unix_timestamp(), 1_700_000_000);
+    }
+}
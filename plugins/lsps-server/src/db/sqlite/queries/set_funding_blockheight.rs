@@ -0,0 +1,61 @@
+use anyhow::Result;
+
+use sqlx::{Sqlite, Transaction};
+use uuid::Uuid;
+
+/// Stores the block in which the funding transaction of an order confirmed
+///
+/// A height that is already stored is never overwritten.
+/// Returns `true` if the height was stored.
+pub struct SetFundingBlockheightQuery {
+    pub(crate) order_uuid: Uuid,
+    pub(crate) funding_blockheight: u32,
+}
+
+impl SetFundingBlockheightQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<bool> {
+        let order_uuid = self.order_uuid.to_string();
+        let funding_blockheight = i64::from(self.funding_blockheight);
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE lsps1_channel
+            SET funding_blockheight = ?2
+            WHERE funding_blockheight IS NULL
+            AND order_id = (SELECT id FROM lsps1_order WHERE uuid = ?1)
+            "#,
+            order_uuid,
+            funding_blockheight
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// Forgets the block in which the funding transaction of an order confirmed
+///
+/// Used when a reorg unconfirms the funding transaction
+pub struct ClearFundingBlockheightQuery {
+    pub(crate) order_uuid: Uuid,
+}
+
+impl ClearFundingBlockheightQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let order_uuid = self.order_uuid.to_string();
+
+        sqlx::query!(
+            r#"
+            UPDATE lsps1_channel
+            SET funding_blockheight = NULL
+            WHERE order_id = (SELECT id FROM lsps1_order WHERE uuid = ?1)
+            "#,
+            order_uuid
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+}
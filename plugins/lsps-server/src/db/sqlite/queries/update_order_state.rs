@@ -4,54 +4,96 @@ use sqlx::sqlite::SqliteQueryResult;
 use sqlx::{Sqlite, Transaction};
 
 use lsp_primitives::lsps0::common_schemas::IsoDatetime;
-use lsp_primitives::lsps1::schema::OrderState;
 use uuid::Uuid;
 
+use crate::db::schema::{Lsps1FailureReason, Lsps1OrderState, OrderGeneration};
 use crate::db::sqlite::conversion::IntoSqliteInteger;
 
+/// Moves an order to a new state
+///
+/// The update only succeeds if `generation` is the current generation of
+/// the order. This ensures that two tasks can't both move an order out of
+/// the same state.
+///
+/// The latest state is stored on the order and the new state is appended
+/// to `lsps1_order_state`. This query is the only one that changes the
+/// state of an existing order. It keeps both in sync.
 pub struct UpdateOrderStateQuery {
     pub(crate) order_uuid: Uuid,
-    pub(crate) state: OrderState,
+    pub(crate) state: Lsps1OrderState,
+    pub(crate) generation: OrderGeneration,
+    /// Should only be set when the order moves to `Failed`
+    pub(crate) failure_reason: Option<Lsps1FailureReason>,
+    pub(crate) changed_at: IsoDatetime,
 }
 
 impl UpdateOrderStateQuery {
     pub(crate) async fn execute<'b>(&self, tx: &'b mut Transaction<'_, Sqlite>) -> Result<()> {
         log::debug!(
-            "Update order_state order={} to {:?}",
+            "Update order_state order={} to {:?} at generation {}",
             self.order_uuid,
             self.state,
+            self.generation
         );
         let state = self.state.into_sqlite_integer()?;
-        let created_at = IsoDatetime::now().into_sqlite_integer()?;
+        let generation = self.generation.into_sqlite_integer()?;
+        let new_generation = self.generation.next().into_sqlite_integer()?;
+        let created_at = self.changed_at.into_sqlite_integer()?;
         let order_uuid = self.order_uuid.to_string();
+        let failure_reason = self.failure_reason.map(|r| r.as_str());
 
         let result: SqliteQueryResult = sqlx::query!(
+            r#"
+            UPDATE lsps1_order
+            SET latest_order_state = ?1,
+                latest_failure_reason = ?2,
+                latest_generation = ?3
+            WHERE uuid = ?4 AND latest_generation = ?5
+            "#,
+            state,
+            failure_reason,
+            new_generation,
+            order_uuid,
+            generation
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => {}
+            0 => {
+                return Err(anyhow!(
+                    "Order {} was modified concurrently. It is no longer at generation {}",
+                    self.order_uuid,
+                    self.generation
+                ))
+            }
+            n => {
+                return Err(anyhow!(
+                    "Error in updating state. Query affected {} rows",
+                    n
+                ))
+            }
+        }
+
+        sqlx::query!(
             r#"
             INSERT INTO lsps1_order_state
-                (order_id, order_state_enum_id, created_at, generation)
-            SELECT o.id, ?1, ?2, os.generation+1
+                (order_id, order_state_enum_id, created_at, generation, failure_reason)
+            SELECT o.id, ?1, ?2, ?3, ?4
                 FROM lsps1_order as o
-                JOIN lsps1_order_state as os
-                ON o.id = os.order_id
-                WHERE o.uuid = ?3
-                ORDER BY os.generation DESC
-                LIMIT 1
+                WHERE o.uuid = ?5
             "#,
             state,
             created_at,
+            new_generation,
+            failure_reason,
             order_uuid
         )
         .execute(&mut **tx)
         .await?;
 
-        if result.rows_affected() == 1 {
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "Error in updating state. Query affected {} rows",
-                result.rows_affected()
-            ))
-        }
+        Ok(())
     }
 }
 
@@ -78,7 +120,10 @@ mod test {
         println!("Attempting to update the payment state");
         let query = UpdateOrderStateQuery {
             order_uuid: uuid,
-            state: OrderState::Completed,
+            state: Lsps1OrderState::Completed,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
         };
 
         query.execute(&mut tx).await.unwrap();
@@ -93,7 +138,7 @@ mod test {
 
         assert_eq!(
             order.order_state,
-            OrderState::Completed,
+            Lsps1OrderState::Completed,
             "Failed to update state"
         );
 
@@ -101,7 +146,10 @@ mod test {
         let mut tx = db.pool.begin().await.unwrap();
         UpdateOrderStateQuery {
             order_uuid: uuid,
-            state: OrderState::Failed,
+            state: Lsps1OrderState::Failed,
+            generation: order.generation,
+            changed_at: IsoDatetime::now(),
+            failure_reason: Some(Lsps1FailureReason::ChannelAborted),
         }
         .execute(&mut tx)
         .await
@@ -115,10 +163,53 @@ mod test {
 
         assert_eq!(
             order.order_state,
-            OrderState::Failed,
+            Lsps1OrderState::Failed,
             "Failed to update state"
         );
+        assert_eq!(
+            order.failure_reason,
+            Some(Lsps1FailureReason::ChannelAborted)
+        );
 
         tx.commit().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn reject_update_at_stale_generation() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.pool.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let update = |state| UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        };
+        update(Lsps1OrderState::PendingOpen)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        // A second task that read the order at generation 0 can't move it
+        let err = update(Lsps1OrderState::Created)
+            .execute(&mut tx)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("modified concurrently"));
+
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(order.order_state, Lsps1OrderState::PendingOpen);
+        assert_eq!(order.generation, OrderGeneration(1));
+    }
 }
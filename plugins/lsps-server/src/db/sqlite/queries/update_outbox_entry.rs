@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use sqlx::{Sqlite, Transaction};
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+use crate::db::sqlite::conversion::IntoSqliteInteger;
+
+/// Counts another failed attempt to send a response
+pub struct RecordOutboxAttemptQuery {
+    pub(crate) id: i64,
+}
+
+impl RecordOutboxAttemptQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let result = sqlx::query!(
+            r#"UPDATE lsps0_response_outbox SET attempts = attempts + 1 WHERE id = ?1;"#,
+            self.id
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => Ok(()),
+            n => Err(anyhow!(
+                "Failed to record attempt for outbox entry {}. Query affected {} rows",
+                self.id,
+                n
+            )),
+        }
+    }
+}
+
+/// Removes a response that was delivered
+///
+/// Another task might have delivered the response already.
+/// Returns false if the entry didn't exist anymore
+pub struct DeleteOutboxEntryQuery {
+    pub(crate) id: i64,
+}
+
+impl DeleteOutboxEntryQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"DELETE FROM lsps0_response_outbox WHERE id = ?1;"#,
+            self.id
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Removes the responses that were created before `created_before`
+///
+/// Returns the number of responses that were removed
+pub struct DeleteExpiredOutboxEntriesQuery {
+    pub(crate) created_before: IsoDatetime,
+}
+
+impl DeleteExpiredOutboxEntriesQuery {
+    pub(crate) async fn execute(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<u64> {
+        let created_before = self.created_before.into_sqlite_integer()?;
+        let result = sqlx::query!(
+            r#"DELETE FROM lsps0_response_outbox WHERE created_at < ?1;"#,
+            created_before
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
@@ -6,12 +6,21 @@ use sqlx::{Sqlite, Transaction};
 use lsp_primitives::lsps0::common_schemas::IsoDatetime;
 use lsp_primitives::lsps1::schema::PaymentState;
 
+use crate::db::schema::{InvoiceLabel, PaymentGeneration};
 use crate::db::sqlite::conversion::IntoSqliteInteger;
 
+/// Moves a payment to a new state
+///
+/// The update only succeeds if `generation` is the current generation
+/// of the payment.
+///
+/// The latest state is stored on the payment details and the new state
+/// is appended to `lsps1_payment_state`.
 pub struct UpdatePaymentStateQuery {
     pub(crate) state: PaymentState,
-    pub(crate) generation: u64,
-    pub(crate) label: String,
+    pub(crate) generation: PaymentGeneration,
+    pub(crate) label: InvoiceLabel,
+    pub(crate) changed_at: IsoDatetime,
 }
 
 impl UpdatePaymentStateQuery {
@@ -23,34 +32,60 @@ impl UpdatePaymentStateQuery {
             self.generation
         );
         let state = self.state.into_sqlite_integer()?;
-        let created_at = IsoDatetime::now().into_sqlite_integer()?;
+        let created_at = self.changed_at.into_sqlite_integer()?;
         let generation = self.generation.into_sqlite_integer()?;
-        let new_generation = generation + 1;
+        let new_generation = self.generation.next().into_sqlite_integer()?;
+        let label = self.label.as_str();
 
         let result: SqliteQueryResult = sqlx::query!(
             r#"
-            INSERT INTO lsps1_payment_state 
+            UPDATE lsps1_payment_details
+            SET latest_payment_state = ?1,
+                latest_generation = ?2
+            WHERE bolt11_invoice_label = ?3 AND latest_generation = ?4
+            "#,
+            state,
+            new_generation,
+            label,
+            generation
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        match result.rows_affected() {
+            1 => {}
+            0 => {
+                return Err(anyhow!(
+                    "Payment {} was modified concurrently. It is no longer at generation {}",
+                    self.label,
+                    self.generation
+                ))
+            }
+            n => {
+                return Err(anyhow!(
+                    "Error in updating state. Query affected {} rows",
+                    n
+                ))
+            }
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO lsps1_payment_state
                 (payment_details_id, payment_state, created_at, generation)
-            SELECT id, ?1, ?2, ?3
-                FROM lsps1_payment_details
-                WHERE bolt11_invoice_label = ?4
+            SELECT pd.id, ?1, ?2, ?3
+                FROM lsps1_payment_details as pd
+                WHERE pd.bolt11_invoice_label = ?4
             "#,
             state,
             created_at,
             new_generation,
-            self.label
+            label
         )
         .execute(&mut **tx)
         .await?;
 
-        if result.rows_affected() == 1 {
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "Error in updating state. Query affected {} rows",
-                result.rows_affected()
-            ))
-        }
+        Ok(())
     }
 }
 
@@ -80,12 +115,13 @@ mod test {
             generation: initial_payment.generation,
             label: initial_payment.bolt11_invoice_label.clone(),
             state: PaymentState::Hold,
+            changed_at: IsoDatetime::now(),
         };
 
         query.execute(&mut tx).await.unwrap();
 
         let result_label =
-            GetPaymentDetailsQuery::by_label(initial_payment.bolt11_invoice_label.to_string())
+            GetPaymentDetailsQuery::by_label(initial_payment.bolt11_invoice_label.clone())
                 .execute(&mut tx)
                 .await
                 .unwrap()
@@ -109,5 +145,39 @@ mod test {
             PaymentState::Hold,
             "Bad state using label"
         );
+        assert_eq!(result_uuid.generation, initial_payment.generation.next());
+    }
+
+    #[tokio::test]
+    async fn reject_update_at_stale_generation() {
+        let db = get_db().await;
+        let query = create_order_query();
+        let payment = query.payment.clone();
+
+        let mut tx = db.pool.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+
+        let update = |state| UpdatePaymentStateQuery {
+            generation: payment.generation,
+            label: payment.bolt11_invoice_label.clone(),
+            state,
+            changed_at: IsoDatetime::now(),
+        };
+        update(PaymentState::Paid).execute(&mut tx).await.unwrap();
+
+        let err = update(PaymentState::Refunded)
+            .execute(&mut tx)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("modified concurrently"));
+
+        let result = GetPaymentDetailsQuery::by_label(payment.bolt11_invoice_label.clone())
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(result.state, PaymentState::Paid);
     }
 }
@@ -3,14 +3,16 @@ use std::str::FromStr;
 use uuid::Uuid;
 
 use crate::db::schema::{
-    Lsps1Channel as Lsps1ChannelBase, Lsps1Order as Lsps1OrderBase,
-    Lsps1PaymentDetails as Lsps1PaymentDetailsBase,
+    InvoiceLabel, Lsps1Channel as Lsps1ChannelBase, Lsps1ClientNode as Lsps1ClientNodeBase,
+    Lsps1FailureReason, Lsps1Order as Lsps1OrderBase, Lsps1OrderState,
+    Lsps1PaymentDetails as Lsps1PaymentDetailsBase, Lsps1Refund as Lsps1RefundBase,
+    OrderGeneration, PaymentGeneration,
 };
 use crate::db::sqlite::conversion::{FromSqliteInteger, IntoSqliteInteger};
 use lsp_primitives::lsps0::common_schemas::{
     FeeRate, IsoDatetime, PublicKey, SatAmount, TransactionId,
 };
-use lsp_primitives::lsps1::schema::{OrderState, PaymentState};
+use lsp_primitives::lsps1::schema::PaymentState;
 
 #[derive(sqlx::FromRow)]
 pub struct Lsps1Order {
@@ -27,6 +29,7 @@ pub struct Lsps1Order {
     pub(crate) created_at: i64,
     pub(crate) expires_at: i64,
     pub(crate) order_state: i64,
+    pub(crate) failure_reason: Option<String>,
     pub(crate) generation: i64,
 }
 
@@ -51,27 +54,44 @@ pub struct Lsps1Channel {
     pub(crate) funded_at: i64,
 }
 
+/// The `client_*`-columns of `lsps1_order`
+///
+/// `client_addresses` is a JSON-array
+#[derive(sqlx::FromRow)]
+pub struct Lsps1ClientNode {
+    pub(crate) client_alias: Option<String>,
+    pub(crate) client_addresses: Option<String>,
+    pub(crate) client_remote_address: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Lsps1Refund {
+    pub(crate) txid: String,
+    pub(crate) amount_sat: i64,
+    pub(crate) address: String,
+    pub(crate) broadcast_at: i64,
+}
+
 impl TryFrom<&Lsps1PaymentDetailsBase> for Lsps1PaymentDetails {
     type Error = anyhow::Error;
 
     fn try_from(payment: &Lsps1PaymentDetailsBase) -> Result<Self, Self::Error> {
         let min_0conf = payment
             .minimum_fee_for_0conf
-            .clone()
-            .map(|f| f.to_sats_per_kwu())
-            .map(|f| i64::try_from(f))
+            .as_ref()
+            .map(|f| f.into_sqlite_integer())
             .transpose()?;
         let block_conf = payment
             .onchain_block_confirmations_required
-            .map(|n| i64::try_from(n))
+            .map(|n| n.into_sqlite_integer())
             .transpose()?;
 
         Ok(Self {
             order_uuid: payment.order_uuid.to_string(),
-            fee_total_sat: i64::try_from(payment.fee_total_sat.sat_value())?,
-            order_total_sat: i64::try_from(payment.order_total_sat.sat_value())?,
+            fee_total_sat: payment.fee_total_sat.into_sqlite_integer()?,
+            order_total_sat: payment.order_total_sat.into_sqlite_integer()?,
             bolt11_invoice: payment.bolt11_invoice.clone(),
-            bolt11_invoice_label: payment.bolt11_invoice_label.clone(),
+            bolt11_invoice_label: payment.bolt11_invoice_label.to_string(),
             onchain_address: payment.onchain_address.clone(),
             onchain_block_confirmations_required: block_conf,
             minimum_fee_for_0conf: min_0conf,
@@ -91,7 +111,7 @@ impl TryFrom<&Lsps1PaymentDetails> for Lsps1PaymentDetailsBase {
             .transpose()?;
 
         let minimum_fee_for_0conf = payment
-            .onchain_block_confirmations_required
+            .minimum_fee_for_0conf
             .map(|p| FeeRate::from_sqlite_integer(p))
             .transpose()?;
 
@@ -99,14 +119,14 @@ impl TryFrom<&Lsps1PaymentDetails> for Lsps1PaymentDetailsBase {
             order_uuid: Uuid::parse_str(&payment.order_uuid)
                 .context("order_uuid is not a valid uuid")?,
             fee_total_sat: SatAmount::from_sqlite_integer(payment.fee_total_sat)?,
-            order_total_sat: SatAmount::from_sqlite_integer(payment.fee_total_sat)?,
+            order_total_sat: SatAmount::from_sqlite_integer(payment.order_total_sat)?,
             bolt11_invoice: payment.bolt11_invoice.clone(),
-            bolt11_invoice_label: payment.bolt11_invoice_label.clone(),
+            bolt11_invoice_label: InvoiceLabel::new(payment.bolt11_invoice_label.clone())?,
             onchain_address: payment.onchain_address.clone(),
             onchain_block_confirmations_required,
             minimum_fee_for_0conf,
             state: PaymentState::from_sqlite_integer(payment.state)?,
-            generation: u64::from_sqlite_integer(payment.generation)?,
+            generation: PaymentGeneration::from_sqlite_integer(payment.generation)?,
         })
     }
 }
@@ -118,18 +138,27 @@ impl TryFrom<&Lsps1Order> for Lsps1OrderBase {
         Ok(Self {
             uuid: Uuid::parse_str(&order.uuid)?,
             client_node_id: PublicKey::from_hex(&order.client_node_id)?,
-            lsp_balance_sat: SatAmount::new(u64::try_from(order.lsp_balance_sat)?),
-            client_balance_sat: SatAmount::new(u64::try_from(order.client_balance_sat)?),
-            funding_confirms_within_blocks: u16::try_from(order.funding_confirms_within_blocks)?,
-            required_channel_confirmations: u16::try_from(order.required_channel_confirmations)?,
-            channel_expiry_blocks: u32::try_from(order.channel_expiry_blocks)?,
+            lsp_balance_sat: SatAmount::from_sqlite_integer(order.lsp_balance_sat)?,
+            client_balance_sat: SatAmount::from_sqlite_integer(order.client_balance_sat)?,
+            funding_confirms_within_blocks: u16::from_sqlite_integer(
+                order.funding_confirms_within_blocks,
+            )?,
+            required_channel_confirmations: u16::from_sqlite_integer(
+                order.required_channel_confirmations,
+            )?,
+            channel_expiry_blocks: u32::from_sqlite_integer(order.channel_expiry_blocks)?,
             token: order.token.clone(),
             refund_onchain_address: order.refund_onchain_address.clone(),
             announce_channel: order.announce_channel,
-            created_at: IsoDatetime::from_unix_timestamp(order.created_at)?,
-            expires_at: IsoDatetime::from_unix_timestamp(order.expires_at)?,
-            order_state: OrderState::from_sqlite_integer(order.order_state)?,
-            generation: u64::from_sqlite_integer(order.generation)?,
+            created_at: IsoDatetime::from_sqlite_integer(order.created_at)?,
+            expires_at: IsoDatetime::from_sqlite_integer(order.expires_at)?,
+            order_state: Lsps1OrderState::from_sqlite_integer(order.order_state)?,
+            failure_reason: order
+                .failure_reason
+                .as_deref()
+                .map(Lsps1FailureReason::from_str)
+                .transpose()?,
+            generation: OrderGeneration::from_sqlite_integer(order.generation)?,
         })
     }
 }
@@ -141,17 +170,22 @@ impl TryFrom<&Lsps1OrderBase> for Lsps1Order {
         Ok(Self {
             uuid: order.uuid.to_string(),
             client_node_id: order.client_node_id.to_hex(),
-            lsp_balance_sat: i64::try_from(order.lsp_balance_sat.sat_value())?,
-            client_balance_sat: i64::try_from(order.client_balance_sat.sat_value())?,
-            funding_confirms_within_blocks: i64::try_from(order.funding_confirms_within_blocks)?,
-            required_channel_confirmations: i64::try_from(order.required_channel_confirmations)?,
-            channel_expiry_blocks: i64::try_from(order.channel_expiry_blocks)?,
+            lsp_balance_sat: order.lsp_balance_sat.into_sqlite_integer()?,
+            client_balance_sat: order.client_balance_sat.into_sqlite_integer()?,
+            funding_confirms_within_blocks: order
+                .funding_confirms_within_blocks
+                .into_sqlite_integer()?,
+            required_channel_confirmations: order
+                .required_channel_confirmations
+                .into_sqlite_integer()?,
+            channel_expiry_blocks: order.channel_expiry_blocks.into_sqlite_integer()?,
             token: order.token.clone(),
             refund_onchain_address: order.refund_onchain_address.clone(),
             announce_channel: order.announce_channel,
-            created_at: order.created_at.unix_timestamp(),
-            expires_at: order.expires_at.unix_timestamp(),
+            created_at: order.created_at.into_sqlite_integer()?,
+            expires_at: order.expires_at.into_sqlite_integer()?,
             order_state: order.order_state.into_sqlite_integer()?,
+            failure_reason: order.failure_reason.map(|r| r.as_str().to_string()),
             generation: order.generation.into_sqlite_integer()?,
         })
     }
@@ -180,3 +214,64 @@ impl TryFrom<&Lsps1ChannelBase> for Lsps1Channel {
         })
     }
 }
+
+impl TryFrom<&Lsps1ClientNode> for Lsps1ClientNodeBase {
+    type Error = anyhow::Error;
+
+    fn try_from(node: &Lsps1ClientNode) -> Result<Self, Self::Error> {
+        let addresses = match &node.client_addresses {
+            Some(addresses) => {
+                serde_json::from_str(addresses).context("Failed to parse client_addresses")?
+            }
+            None => Vec::new(),
+        };
+        Ok(Self {
+            alias: node.client_alias.clone(),
+            addresses,
+            remote_address: node.client_remote_address.clone(),
+        })
+    }
+}
+
+impl TryFrom<&Lsps1ClientNodeBase> for Lsps1ClientNode {
+    type Error = anyhow::Error;
+
+    fn try_from(node: &Lsps1ClientNodeBase) -> Result<Self, Self::Error> {
+        let client_addresses = if node.addresses.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&node.addresses)?)
+        };
+        Ok(Self {
+            client_alias: node.alias.clone(),
+            client_addresses,
+            client_remote_address: node.remote_address.clone(),
+        })
+    }
+}
+
+impl TryFrom<&Lsps1Refund> for Lsps1RefundBase {
+    type Error = anyhow::Error;
+
+    fn try_from(refund: &Lsps1Refund) -> Result<Self, Self::Error> {
+        Ok(Self {
+            txid: TransactionId::from_str(&refund.txid)?,
+            amount_sat: SatAmount::from_sqlite_integer(refund.amount_sat)?,
+            address: refund.address.clone(),
+            broadcast_at: IsoDatetime::from_sqlite_integer(refund.broadcast_at)?,
+        })
+    }
+}
+
+impl TryFrom<&Lsps1RefundBase> for Lsps1Refund {
+    type Error = anyhow::Error;
+
+    fn try_from(refund: &Lsps1RefundBase) -> Result<Self, Self::Error> {
+        Ok(Self {
+            txid: refund.txid.to_string(),
+            amount_sat: refund.amount_sat.into_sqlite_integer()?,
+            address: refund.address.clone(),
+            broadcast_at: refund.broadcast_at.into_sqlite_integer()?,
+        })
+    }
+}
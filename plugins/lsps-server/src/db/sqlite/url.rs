@@ -0,0 +1,315 @@
+//! Resolves the value of `lsp-server-database-url`
+//!
+//! sqlx reads `sqlite://home/user/lsp.db` as a path relative to the
+//! working directory of lightningd. The database would silently be
+//! created in an unexpected place. The value is resolved to an absolute
+//! path before we connect.
+//!
+//! Accepted forms are
+//! - `sqlite:///home/user/lsp.db` or `sqlite://home/user/lsp.db`: an absolute path
+//! - `sqlite:lsp.db`: a path relative to the lightning-dir unless it is absolute
+//! - `lsp.db`: a bare path. Relative to the lightning-dir unless it is absolute
+//!
+//! The query parameter `create-dirs` creates the directory of the
+//! database if it is missing. Other query parameters are passed to sqlx.
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use uuid::Uuid;
+
+/// The database that is used if `lsp-server-database-url` isn't set
+pub(crate) const DEFAULT_DATABASE_FILE: &str = "lsp_server.db";
+
+const SUPPORTED_BACKENDS: &str = "sqlite";
+const CREATE_DIRS: &str = "create-dirs";
+
+/// The location of the database after resolving the configured url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DatabaseUrl {
+    /// The absolute path of the database file
+    pub(crate) path: PathBuf,
+    /// The query parameters that are passed to sqlx
+    params: Vec<String>,
+}
+
+impl DatabaseUrl {
+    /// Parses `url` without touching the file system
+    ///
+    /// Relative paths are resolved against `lightning_dir`. Returns if
+    /// the directory of the database may be created.
+    fn parse(url: &str, lightning_dir: &Path) -> Result<(Self, bool)> {
+        let url = url.trim();
+        let (location, query) = match url.split_once('?') {
+            Some((location, query)) => (location, Some(query)),
+            None => (url, None),
+        };
+
+        let path = match location.strip_prefix("sqlite:") {
+            // A url with an authority always refers to an absolute path
+            Some(path) if path.starts_with("//") => {
+                format!("/{}", path.trim_start_matches('/'))
+            }
+            Some(path) => path.to_string(),
+            None => {
+                if let Some(scheme) = scheme_of(location) {
+                    bail!(
+                        "Unsupported database backend '{}'. Supported backends: {}",
+                        scheme,
+                        SUPPORTED_BACKENDS
+                    );
+                }
+                location.to_string()
+            }
+        };
+
+        ensure!(
+            path != ":memory:",
+            "An in-memory database loses all orders when the plugin stops"
+        );
+        // A trailing slash is tolerated. The path must still name a file
+        let path = path.trim_end_matches('/');
+        ensure!(!path.is_empty(), "The database url doesn't contain a path");
+
+        let path = normalize(&lightning_dir.join(path));
+        ensure!(
+            path.file_name().is_some(),
+            "The database url '{}' doesn't name a file",
+            url
+        );
+
+        let mut create_dirs = false;
+        let mut params = vec![];
+        for param in query.unwrap_or_default().split('&') {
+            match param.split_once('=') {
+                _ if param.is_empty() => {}
+                None if param == CREATE_DIRS => create_dirs = true,
+                Some((CREATE_DIRS, "true" | "1")) => create_dirs = true,
+                Some((CREATE_DIRS, "false" | "0")) => create_dirs = false,
+                Some((CREATE_DIRS, value)) => {
+                    bail!("Invalid value '{}' for '{}'", value, CREATE_DIRS)
+                }
+                _ => params.push(param.to_string()),
+            }
+        }
+
+        Ok((Self { path, params }, create_dirs))
+    }
+
+    /// The connection string that is passed to sqlx
+    pub(crate) fn connection_string(&self) -> Result<String> {
+        let path = self
+            .path
+            .to_str()
+            .ok_or_else(|| anyhow!("The database path must be valid UTF-8"))?;
+        match self.params.is_empty() {
+            true => Ok(format!("sqlite://{}", path)),
+            false => Ok(format!("sqlite://{}?{}", path, self.params.join("&"))),
+        }
+    }
+}
+
+/// Resolves `url` to the absolute path of the database
+///
+/// Relative paths are resolved against `lightning_dir`. Fails if the
+/// directory of the database doesn't exist or isn't writable. The
+/// directory is created if `url` contains the `create-dirs` parameter.
+pub(crate) fn resolve_database_url(url: &str, lightning_dir: &Path) -> Result<DatabaseUrl> {
+    ensure!(
+        lightning_dir.is_absolute(),
+        "The lightning-dir '{}' must be an absolute path",
+        lightning_dir.display()
+    );
+    let (mut database_url, create_dirs) = DatabaseUrl::parse(url, lightning_dir)?;
+
+    // `parse` guarantees that the path has a file name and a parent
+    let file_name = database_url.path.file_name().unwrap().to_owned();
+    let directory = database_url.path.parent().unwrap().to_path_buf();
+    if !directory.exists() {
+        ensure!(
+            create_dirs,
+            "The directory '{}' of the database doesn't exist. Create it or add '?{}' to the database url",
+            directory.display(),
+            CREATE_DIRS
+        );
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("Failed to create directory '{}'", directory.display()))?;
+    }
+    ensure!(
+        directory.is_dir(),
+        "'{}' is not a directory",
+        directory.display()
+    );
+
+    // Resolve symlinks in the directory. The database itself might not exist yet
+    let directory = fs::canonicalize(&directory)
+        .with_context(|| format!("Failed to resolve directory '{}'", directory.display()))?;
+    database_url.path = directory.join(file_name);
+    ensure!(
+        !database_url.path.is_dir(),
+        "The database '{}' is a directory",
+        database_url.path.display()
+    );
+    check_writable(&directory)?;
+
+    Ok(database_url)
+}
+
+/// sqlite creates the journal next to the database. The directory must
+/// be writable even if the database exists already
+fn check_writable(directory: &Path) -> Result<()> {
+    let probe = directory.join(format!(".lsp_server_write_check_{}", Uuid::new_v4()));
+    fs::File::create(&probe)
+        .with_context(|| format!("The directory '{}' is not writable", directory.display()))?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Returns the scheme if `location` looks like a url
+fn scheme_of(location: &str) -> Option<&str> {
+    let (scheme, _) = location.split_once("://")?;
+    let mut chars = scheme.chars();
+    let is_scheme = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    is_scheme.then_some(scheme)
+}
+
+/// Removes `.` and `..` from an absolute path
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(url: &str) -> Result<(DatabaseUrl, bool)> {
+        DatabaseUrl::parse(url, Path::new("/home/user/.lightning/regtest"))
+    }
+
+    fn parsed_path(url: &str) -> PathBuf {
+        parse(url).unwrap().0.path
+    }
+
+    /// A new directory that is removed when the test ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("lsp_server_url_{}", Uuid::new_v4()));
+            fs::create_dir(&path).unwrap();
+            Self(fs::canonicalize(path).unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parse_accepted_forms() {
+        let expected = PathBuf::from("/home/user/data/lsp.db");
+        assert_eq!(parsed_path("sqlite:///home/user/data/lsp.db"), expected);
+        assert_eq!(parsed_path("sqlite://home/user/data/lsp.db"), expected);
+        assert_eq!(parsed_path("sqlite:/home/user/data/lsp.db"), expected);
+        assert_eq!(parsed_path("/home/user/data/lsp.db"), expected);
+        assert_eq!(parsed_path(" sqlite:///home/user/data/lsp.db/ "), expected);
+
+        // Relative paths are relative to the lightning-dir
+        let expected = PathBuf::from("/home/user/.lightning/regtest/lsp.db");
+        assert_eq!(parsed_path("sqlite:lsp.db"), expected);
+        assert_eq!(parsed_path("lsp.db"), expected);
+        assert_eq!(parsed_path("./data/../lsp.db"), expected);
+        assert_eq!(
+            parsed_path(DEFAULT_DATABASE_FILE).file_name().unwrap(),
+            DEFAULT_DATABASE_FILE
+        );
+    }
+
+    #[test]
+    fn parse_query_parameters() {
+        let (url, create_dirs) = parse("lsp.db").unwrap();
+        assert!(!create_dirs);
+        assert_eq!(
+            url.connection_string().unwrap(),
+            "sqlite:///home/user/.lightning/regtest/lsp.db"
+        );
+
+        let (url, create_dirs) = parse("sqlite:///data/lsp.db?create-dirs&mode=rwc").unwrap();
+        assert!(create_dirs);
+        assert_eq!(
+            url.connection_string().unwrap(),
+            "sqlite:///data/lsp.db?mode=rwc"
+        );
+
+        assert!(parse("lsp.db?create-dirs=true").unwrap().1);
+        assert!(!parse("lsp.db?create-dirs=0").unwrap().1);
+        assert!(parse("lsp.db?create-dirs=yes").is_err());
+    }
+
+    #[test]
+    fn reject_invalid_urls() {
+        let err = parse("postgres://user@localhost/lsp").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported database backend 'postgres'. Supported backends: sqlite"
+        );
+        assert!(parse("mysql://localhost/lsp").is_err());
+        assert!(parse("sqlite::memory:").is_err());
+        assert!(parse("sqlite://").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("/").is_err());
+    }
+
+    #[test]
+    fn resolve_requires_an_existing_directory() {
+        let dir = TempDir::new();
+
+        let url = resolve_database_url("lsp.db", &dir.0).unwrap();
+        assert_eq!(url.path, dir.0.join("lsp.db"));
+
+        let err = resolve_database_url("missing/lsp.db", &dir.0).unwrap_err();
+        assert!(err.to_string().contains("doesn't exist"));
+
+        let url = resolve_database_url("missing/lsp.db?create-dirs", &dir.0).unwrap();
+        assert_eq!(url.path, dir.0.join("missing").join("lsp.db"));
+        assert!(dir.0.join("missing").is_dir());
+
+        // The path of the database can't be a directory
+        assert!(resolve_database_url("missing", &dir.0).is_err());
+        // The lightning-dir must be absolute
+        assert!(resolve_database_url("lsp.db", Path::new("relative")).is_err());
+        // No probe files are left behind
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_requires_a_writable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let readonly = dir.0.join("readonly");
+        fs::create_dir(&readonly).unwrap();
+        fs::set_permissions(&readonly, fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Permissions don't apply to root
+        if fs::File::create(readonly.join("probe")).is_ok() {
+            return;
+        }
+        let err = resolve_database_url("readonly/lsp.db", &dir.0).unwrap_err();
+        assert!(err.to_string().contains("not writable"));
+    }
+}
@@ -0,0 +1,401 @@
+//! Read-only views of the LSPS1-tables
+//!
+//! Other plugins on the same node (e.g: dashboards or accounting) can
+//! read the orders without linking this crate. The state of an order is
+//! stored as generations. A view has a single row per order that
+//! contains the latest generation.
+//!
+//! The views read the `latest_*`-columns of the order and the payment
+//! details, like [`GetOrderQuery`] and [`GetPaymentDetailsQuery`] do.
+//! A view and the server always agree on the latest state.
+//!
+//! [`GetOrderQuery`]: crate::db::sqlite::queries::GetOrderQuery
+//! [`GetPaymentDetailsQuery`]: crate::db::sqlite::queries::GetPaymentDetailsQuery
+use anyhow::Result;
+use cln_plugin::Plugin;
+use serde_json::json;
+
+use crate::state::PluginState;
+
+/// The version of the migration that last changed a view
+///
+/// The columns of a view only change together with this version
+pub(crate) const VIEWS_SCHEMA_VERSION: i64 = 20240525120000;
+
+/// A view that can be read by other plugins
+pub(crate) struct ViewDefinition {
+    pub(crate) name: &'static str,
+    pub(crate) description: &'static str,
+    pub(crate) columns: &'static [&'static str],
+}
+
+pub(crate) const LSPS1_VIEWS: [ViewDefinition; 3] = [
+    ViewDefinition {
+        name: "v_lsps1_orders_latest",
+        description: "The latest state of every order",
+        columns: &[
+            "uuid",
+            "client_node_id",
+            "lsp_balance_sat",
+            "client_balance_sat",
+            "funding_confirms_within_blocks",
+            "required_channel_confirmations",
+            "channel_expiry_blocks",
+            "token",
+            "refund_onchain_address",
+            "announce_channel",
+            "created_at",
+            "expires_at",
+            "order_state",
+            "order_state_name",
+            "failure_reason",
+            "generation",
+            "state_changed_at",
+        ],
+    },
+    ViewDefinition {
+        name: "v_lsps1_payments_latest",
+        description: "The payment details and the latest payment state of every order",
+        columns: &[
+            "order_uuid",
+            "fee_total_sat",
+            "order_total_sat",
+            "bolt11_invoice",
+            "bolt11_invoice_label",
+            "onchain_address",
+            "onchain_block_confirmations_required",
+            "minimum_fee_for_0conf",
+            "payment_state",
+            "payment_state_name",
+            "generation",
+            "state_changed_at",
+            "invoice_deleted_at",
+        ],
+    },
+    ViewDefinition {
+        name: "v_lsps1_channels",
+        description: "The channel of every order that has been funded",
+        columns: &[
+            "order_uuid",
+            "client_node_id",
+            "funding_txid",
+            "outnum",
+            "funded_at",
+            "funding_blockheight",
+        ],
+    },
+];
+
+/// Handles `lsps1-db-schema-version`
+pub(crate) async fn lsps1_db_schema_version(
+    _plugin: Plugin<PluginState>,
+    _request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    Ok(describe_views())
+}
+
+fn describe_views() -> serde_json::Value {
+    let views: Vec<serde_json::Value> = LSPS1_VIEWS
+        .iter()
+        .map(|view| {
+            json!({
+                "name" : view.name,
+                "description" : view.description,
+                "columns" : view.columns,
+            })
+        })
+        .collect();
+    json!({
+        "schema_version" : VIEWS_SCHEMA_VERSION,
+        "views" : views,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+    use lsp_primitives::lsps1::schema::PaymentState;
+    use sqlx::{Sqlite, Transaction};
+    use uuid::Uuid;
+
+    use crate::db::schema::{
+        Lsps1FailureReason, Lsps1Order, Lsps1OrderState, Lsps1PaymentDetails, OrderGeneration,
+        PaymentGeneration,
+    };
+    use crate::db::sqlite::queries::{
+        GetOrderQuery, GetPaymentDetailsQuery, UpdateOrderStateQuery, UpdatePaymentStateQuery,
+    };
+    use crate::db::sqlite::schema::{
+        Lsps1Order as Lsps1OrderSqlite, Lsps1PaymentDetails as Lsps1PaymentDetailsSqlite,
+    };
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+
+    /// The join that was used by `GetOrderQuery` before the views existed
+    const ORDER_JOIN: &str = r#"
+        SELECT
+            uuid, client_node_id, lsp_balance_sat,
+            client_balance_sat, funding_confirms_within_blocks,
+            required_channel_confirmations, channel_expiry_blocks,
+            token, refund_onchain_address, announce_channel,
+            ord.created_at, expires_at, os.order_state_enum_id as order_state,
+            os.failure_reason, generation
+        FROM lsps1_order AS ord
+        JOIN lsps1_order_state AS os ON ord.id = os.order_id
+        WHERE uuid = ?
+        ORDER BY os.generation DESC
+        LIMIT 1"#;
+
+    /// The join that was used by `GetPaymentDetailsQuery` before the views existed
+    const PAYMENT_JOIN: &str = r#"
+        SELECT
+            o.uuid as order_uuid,
+            p.fee_total_sat, p.order_total_sat, p.bolt11_invoice,
+            p.bolt11_invoice_label, p.onchain_address,
+            p.onchain_block_confirmations_required, p.minimum_fee_for_0conf,
+            ps.payment_state as state,
+            ps.generation
+        FROM lsps1_payment_details as p
+        JOIN lsps1_order as o ON o.id = p.order_id
+        JOIN lsps1_payment_state as ps ON ps.payment_details_id = p.id
+        WHERE o.uuid = ?1
+        ORDER BY ps.generation DESC
+        LIMIT 1"#;
+
+    /// Reads the fields of `ORDER_JOIN` from `v_lsps1_orders_latest`
+    const ORDER_VIEW: &str = r#"
+        SELECT
+            uuid, client_node_id, lsp_balance_sat,
+            client_balance_sat, funding_confirms_within_blocks,
+            required_channel_confirmations, channel_expiry_blocks,
+            token, refund_onchain_address, announce_channel,
+            created_at, expires_at, order_state,
+            failure_reason, generation
+        FROM v_lsps1_orders_latest
+        WHERE uuid = ?"#;
+
+    /// Reads the fields of `PAYMENT_JOIN` from `v_lsps1_payments_latest`
+    const PAYMENT_VIEW: &str = r#"
+        SELECT
+            order_uuid,
+            fee_total_sat, order_total_sat, bolt11_invoice,
+            bolt11_invoice_label, onchain_address,
+            onchain_block_confirmations_required, minimum_fee_for_0conf,
+            payment_state as state,
+            generation
+        FROM v_lsps1_payments_latest
+        WHERE order_uuid = ?1"#;
+
+    async fn create_order(tx: &mut Transaction<'static, Sqlite>) -> Lsps1Order {
+        let query = create_order_query();
+        let order = query.order.clone();
+        query.execute(tx).await.unwrap();
+        order
+    }
+
+    async fn move_order(
+        tx: &mut Transaction<'static, Sqlite>,
+        order: &Lsps1Order,
+        states: &[Lsps1OrderState],
+    ) {
+        for (generation, state) in states.iter().enumerate() {
+            let failure_reason =
+                (*state == Lsps1OrderState::Failed).then_some(Lsps1FailureReason::OrderExpired);
+            UpdateOrderStateQuery {
+                order_uuid: order.uuid,
+                state: *state,
+                generation: OrderGeneration(generation as u64),
+                failure_reason,
+                changed_at: IsoDatetime::now(),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    async fn move_payment(
+        tx: &mut Transaction<'static, Sqlite>,
+        order: &Lsps1Order,
+        states: &[PaymentState],
+    ) {
+        let payment = GetPaymentDetailsQuery::by_uuid(order.uuid)
+            .execute(tx)
+            .await
+            .unwrap()
+            .unwrap();
+        for (generation, state) in states.iter().enumerate() {
+            UpdatePaymentStateQuery {
+                state: state.clone(),
+                generation: PaymentGeneration(generation as u64),
+                label: payment.bolt11_invoice_label.clone(),
+                changed_at: IsoDatetime::now(),
+            }
+            .execute(tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    /// The fields of an order that are stored per generation
+    type OrderFields = (
+        Uuid,
+        Lsps1OrderState,
+        Option<Lsps1FailureReason>,
+        OrderGeneration,
+        i64,
+        i64,
+    );
+
+    fn order_fields(order: &Lsps1Order) -> OrderFields {
+        (
+            order.uuid,
+            order.order_state,
+            order.failure_reason,
+            order.generation,
+            order.created_at.unix_timestamp(),
+            order.expires_at.unix_timestamp(),
+        )
+    }
+
+    fn payment_fields(
+        payment: &Lsps1PaymentDetails,
+    ) -> (Uuid, PaymentState, PaymentGeneration, String, String) {
+        (
+            payment.order_uuid,
+            payment.state.clone(),
+            payment.generation,
+            payment.bolt11_invoice.clone(),
+            payment.bolt11_invoice_label.to_string(),
+        )
+    }
+
+    async fn assert_views_match_joins(tx: &mut Transaction<'static, Sqlite>, uuid: Uuid) {
+        let joined: Lsps1OrderSqlite = sqlx::query_as(ORDER_JOIN)
+            .bind(uuid.to_string())
+            .fetch_one(&mut **tx)
+            .await
+            .unwrap();
+        let joined = Lsps1Order::try_from(&joined).unwrap();
+        let viewed = GetOrderQuery::by_uuid(uuid)
+            .execute(tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(order_fields(&viewed), order_fields(&joined));
+
+        let in_view: Lsps1OrderSqlite = sqlx::query_as(ORDER_VIEW)
+            .bind(uuid.to_string())
+            .fetch_one(&mut **tx)
+            .await
+            .unwrap();
+        let in_view = Lsps1Order::try_from(&in_view).unwrap();
+        assert_eq!(order_fields(&in_view), order_fields(&viewed));
+
+        let joined: Lsps1PaymentDetailsSqlite = sqlx::query_as(PAYMENT_JOIN)
+            .bind(uuid.to_string())
+            .fetch_one(&mut **tx)
+            .await
+            .unwrap();
+        let joined = Lsps1PaymentDetails::try_from(&joined).unwrap();
+        let by_uuid = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let by_label = GetPaymentDetailsQuery::by_label(joined.bolt11_invoice_label.clone())
+            .execute(tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment_fields(&by_uuid), payment_fields(&joined));
+        assert_eq!(payment_fields(&by_label), payment_fields(&joined));
+
+        let in_view: Lsps1PaymentDetailsSqlite = sqlx::query_as(PAYMENT_VIEW)
+            .bind(uuid.to_string())
+            .fetch_one(&mut **tx)
+            .await
+            .unwrap();
+        let in_view = Lsps1PaymentDetails::try_from(&in_view).unwrap();
+        assert_eq!(payment_fields(&in_view), payment_fields(&by_uuid));
+    }
+
+    #[tokio::test]
+    async fn views_have_the_documented_columns() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        for view in LSPS1_VIEWS.iter() {
+            let columns: Vec<String> =
+                sqlx::query_scalar("SELECT name FROM pragma_table_info(?1) ORDER BY cid")
+                    .bind(view.name)
+                    .fetch_all(&mut *tx)
+                    .await
+                    .unwrap();
+            assert_eq!(columns, view.columns, "Columns of {}", view.name);
+        }
+        tx.commit().await.unwrap();
+
+        let described = describe_views();
+        assert_eq!(described["schema_version"], VIEWS_SCHEMA_VERSION);
+        assert_eq!(described["views"][0]["name"], "v_lsps1_orders_latest");
+    }
+
+    #[tokio::test]
+    async fn views_match_joins_for_multiple_generations() {
+        let (db, _) = get_temp_db().await;
+        let mut tx = db.begin().await.unwrap();
+
+        // A completed order went through a few generations
+        let completed = create_order(&mut tx).await;
+        move_order(
+            &mut tx,
+            &completed,
+            &[
+                Lsps1OrderState::Funding,
+                Lsps1OrderState::ChannelOpening,
+                Lsps1OrderState::Completed,
+            ],
+        )
+        .await;
+        move_payment(
+            &mut tx,
+            &completed,
+            &[PaymentState::Hold, PaymentState::Paid],
+        )
+        .await;
+
+        // A failed order was refunded
+        let failed = create_order(&mut tx).await;
+        move_order(&mut tx, &failed, &[Lsps1OrderState::Failed]).await;
+        move_payment(&mut tx, &failed, &[PaymentState::Refunded]).await;
+
+        // A new order only has a single generation
+        let created = create_order(&mut tx).await;
+
+        for order in [&completed, &failed, &created] {
+            assert_views_match_joins(&mut tx, order.uuid).await;
+        }
+
+        // Every order has exactly one row in the views
+        let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM v_lsps1_orders_latest")
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        let payments: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM v_lsps1_payments_latest")
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        assert_eq!((orders, payments), (3, 3));
+
+        let order = GetOrderQuery::by_uuid(completed.uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(order.order_state, Lsps1OrderState::Completed);
+        assert_eq!(order.generation, OrderGeneration(3));
+        tx.commit().await.unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! Caches the fee estimates of lightningd
+//!
+//! Pricing an order and opening its channel both need fee estimates.
+//! Calling `feerates` for every order adds latency. A background task
+//! refreshes the cache every `lsps1-feerate-refresh-seconds` and whenever
+//! a block is found.
+//!
+//! Estimates older than [`MAX_FEERATE_AGE`] are not used. The reader
+//! fetches the estimates itself in that case. This happens if the
+//! background task can't reach lightningd.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::{FeeratesRequest, FeeratesStyle};
+use cln_rpc::model::responses::FeeratesPerkwEstimates;
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::Clock;
+use crate::lsps1::fee_calc::calculate_onchain_feerate;
+
+/// The cache is refreshed this often if the option isn't set
+pub(crate) const DEFAULT_FEERATE_REFRESH_SECONDS: i64 = 30;
+
+/// Cached estimates older than this are fetched again
+pub(crate) const MAX_FEERATE_AGE: Duration = Duration::from_secs(180);
+
+/// The fee estimates of lightningd at a single moment
+#[derive(Debug, Clone)]
+pub(crate) struct Feerates {
+    /// The estimated feerate for every confirmation target
+    pub(crate) estimates: Vec<FeeratesPerkwEstimates>,
+    /// The lowest feerate the mempool of bitcoind accepts
+    pub(crate) min_acceptable_perkw: u32,
+    pub(crate) fetched_at: Instant,
+}
+
+impl Feerates {
+    /// Calls `feerates`
+    pub(crate) async fn fetch(rpc: &mut dyn ClnRpcApi, fetched_at: Instant) -> Result<Self> {
+        let request = FeeratesRequest {
+            style: FeeratesStyle::PERKW,
+        };
+        let perkw = rpc
+            .feerates(&request)
+            .await?
+            .perkw
+            .context("Failed to retrieve feerates")?;
+        Ok(Self {
+            estimates: perkw.estimates.context("Failed to retrieve feerates")?,
+            min_acceptable_perkw: perkw.min_acceptable,
+            fetched_at,
+        })
+    }
+
+    /// The feerate in sat per 1000 weight units that confirms within `blocks`
+    ///
+    /// Returns `None` if there are no estimates
+    pub(crate) fn perkw_within(&self, blocks: u16) -> Option<u32> {
+        calculate_onchain_feerate(blocks, &self.estimates)
+    }
+
+    fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.fetched_at)
+    }
+}
+
+/// The most recent fee estimates
+#[derive(Default)]
+pub(crate) struct FeerateCache {
+    latest: Mutex<Option<Arc<Feerates>>>,
+}
+
+impl FeerateCache {
+    /// Replaces the cached estimates unless they are newer than `feerates`
+    pub(crate) fn store(&self, feerates: Feerates) -> Arc<Feerates> {
+        let mut latest = self.latest.lock().unwrap();
+        match latest.as_ref() {
+            Some(cached) if cached.fetched_at > feerates.fetched_at => cached.clone(),
+            _ => {
+                let feerates = Arc::new(feerates);
+                *latest = Some(feerates.clone());
+                feerates
+            }
+        }
+    }
+
+    /// The cached estimates if they aren't older than [`MAX_FEERATE_AGE`]
+    pub(crate) fn fresh(&self, now: Instant) -> Option<Arc<Feerates>> {
+        self.latest
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|feerates| feerates.age(now) <= MAX_FEERATE_AGE)
+            .cloned()
+    }
+
+    /// Fetches the estimates and stores them in the cache
+    pub(crate) async fn refresh(
+        &self,
+        rpc: &mut dyn ClnRpcApi,
+        clock: &dyn Clock,
+    ) -> Result<Arc<Feerates>> {
+        let feerates = Feerates::fetch(rpc, clock.instant()).await?;
+        Ok(self.store(feerates))
+    }
+
+    /// Returns the cached estimates or fetches them if they are stale
+    pub(crate) async fn get(
+        &self,
+        rpc: &mut dyn ClnRpcApi,
+        clock: &dyn Clock,
+    ) -> Result<Arc<Feerates>> {
+        match self.fresh(clock.instant()) {
+            Some(feerates) => Ok(feerates),
+            None => {
+                log::debug!("Cached feerates are stale. Fetching them");
+                self.refresh(rpc, clock).await
+            }
+        }
+    }
+}
+
+/// Handles a `block_added` notification
+///
+/// The estimates change when a block is found
+pub(crate) async fn refresh_on_block_added(
+    cache: &FeerateCache,
+    rpc: &mut dyn ClnRpcApi,
+    clock: &dyn Clock,
+    notification: &serde_json::Value,
+) -> Result<()> {
+    // Older versions of lightningd name the object `block`
+    let height = notification
+        .get("block_added")
+        .or_else(|| notification.get("block"))
+        .and_then(|block| block.get("height"))
+        .and_then(|height| height.as_u64());
+    log::debug!("Refreshing feerates after block {:?}", height);
+    cache.refresh(rpc, clock).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+
+    fn feerates(slow_perkw: u32) -> serde_json::Value {
+        json!({
+            "perkw" : {
+                "min_acceptable" : 253,
+                "max_acceptable" : 100_000,
+                "estimates" : [
+                    { "blockcount" : 2, "feerate" : 2_000, "smoothed_feerate" : 2_000 },
+                    { "blockcount" : 6, "feerate" : slow_perkw, "smoothed_feerate" : slow_perkw },
+                ]
+            }
+        })
+    }
+
+    fn clock() -> MockClock {
+        MockClock::new(IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap())
+    }
+
+    #[tokio::test]
+    async fn stale_feerates_are_fetched_again() {
+        let clock = clock();
+        let cache = FeerateCache::default();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates(1_000))
+            .respond("feerates", feerates(500));
+
+        // The first reader fills the cache
+        let first = cache.get(&mut rpc, &clock).await.unwrap();
+        assert_eq!(first.perkw_within(6), Some(1_000));
+        assert_eq!(first.perkw_within(2), Some(2_000));
+        assert_eq!(first.min_acceptable_perkw, 253);
+
+        // Until the estimates are stale they are used as is
+        clock.advance(MAX_FEERATE_AGE);
+        let cached = cache.get(&mut rpc, &clock).await.unwrap();
+        assert_eq!(cached.perkw_within(6), Some(1_000));
+        assert_eq!(rpc.called_methods(), vec!["feerates"]);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.fresh(clock.instant()).is_none());
+        let fetched = cache.get(&mut rpc, &clock).await.unwrap();
+        assert_eq!(fetched.perkw_within(6), Some(500));
+        assert_eq!(rpc.called_methods(), vec!["feerates", "feerates"]);
+    }
+
+    #[tokio::test]
+    async fn block_added_refreshes_the_cache() {
+        let clock = clock();
+        let cache = FeerateCache::default();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates(1_000))
+            .respond("feerates", feerates(700))
+            .fail("feerates", "Connection refused");
+
+        let notification =
+            json!({ "block_added" : { "hash" : "00".repeat(32), "height" : 800_000 } });
+        refresh_on_block_added(&cache, &mut rpc, &clock, &notification)
+            .await
+            .unwrap();
+        let now = clock.instant();
+        assert_eq!(cache.fresh(now).unwrap().perkw_within(6), Some(1_000));
+
+        // The estimates are replaced even if the cached ones are fresh
+        clock.advance(Duration::from_secs(10));
+        let notification = json!({ "block" : { "hash" : "00".repeat(32), "height" : 800_001 } });
+        refresh_on_block_added(&cache, &mut rpc, &clock, &notification)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.fresh(clock.instant()).unwrap().perkw_within(6),
+            Some(700)
+        );
+
+        // A failed refresh keeps the previous estimates
+        assert!(
+            refresh_on_block_added(&cache, &mut rpc, &clock, &notification)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            cache.fresh(clock.instant()).unwrap().perkw_within(6),
+            Some(700)
+        );
+    }
+
+    #[test]
+    fn older_estimates_dont_replace_newer_ones() {
+        let now = Instant::now();
+        let cache = FeerateCache::default();
+        let feerates = |perkw: u32, fetched_at: Instant| Feerates {
+            estimates: vec![FeeratesPerkwEstimates {
+                blockcount: Some(6),
+                feerate: Some(perkw),
+                smoothed_feerate: Some(perkw),
+            }],
+            min_acceptable_perkw: 253,
+            fetched_at,
+        };
+
+        cache.store(feerates(1_000, now + Duration::from_secs(5)));
+        let stored = cache.store(feerates(2_000, now));
+        assert_eq!(stored.perkw_within(6), Some(1_000));
+    }
+}
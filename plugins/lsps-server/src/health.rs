@@ -0,0 +1,335 @@
+//! Stops selling channels while lightningd or bitcoind is unhealthy
+//!
+//! A background task inspects `getinfo` and `listfunds` periodically. If
+//! lightningd or bitcoind is still syncing, lightningd lags behind the
+//! chain or the wallet can't be read, the LSP could take payments for
+//! channels it can't open. While the node is unhealthy `lsps1.create_order`
+//! is refused. Existing orders keep working.
+//!
+//! A single bad or good check doesn't change the state. This prevents
+//! flapping when a check happens to run while a block is being processed.
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::{GetinfoRequest, ListfundsRequest};
+use cln_rpc::model::responses::GetinfoResponse;
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::cln::rpc_model::GetchaininfoRequest;
+
+/// The time between two health checks
+pub(crate) const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The number of consecutive failed checks before the node is unhealthy
+const UNHEALTHY_AFTER: u32 = 2;
+
+/// The number of consecutive successful checks before the node recovers
+const HEALTHY_AFTER: u32 = 3;
+
+/// The state of the node as seen by a single health check
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NodeSnapshot {
+    pub(crate) blockheight: u32,
+    /// The number of blocks bitcoind knows about. `None` if unknown
+    pub(crate) chain_blockcount: Option<u32>,
+    pub(crate) warning_bitcoind_sync: Option<String>,
+    pub(crate) warning_lightningd_sync: Option<String>,
+    /// Why the wallet can't be read. `None` if it is available
+    pub(crate) wallet_error: Option<String>,
+}
+
+impl NodeSnapshot {
+    pub(crate) fn from_getinfo(
+        getinfo: &GetinfoResponse,
+        chain_blockcount: Option<u32>,
+        wallet_error: Option<String>,
+    ) -> Self {
+        Self {
+            blockheight: getinfo.blockheight,
+            chain_blockcount,
+            warning_bitcoind_sync: getinfo.warning_bitcoind_sync.clone(),
+            warning_lightningd_sync: getinfo.warning_lightningd_sync.clone(),
+            wallet_error,
+        }
+    }
+
+    /// Explains why the node can't sell channels, if it can't
+    pub(crate) fn problem(&self, max_block_lag: u32) -> Option<String> {
+        if let Some(warning) = &self.warning_bitcoind_sync {
+            return Some(format!("bitcoind is syncing: {}", warning));
+        }
+        if let Some(warning) = &self.warning_lightningd_sync {
+            return Some(format!("lightningd is syncing: {}", warning));
+        }
+        if let Some(err) = &self.wallet_error {
+            return Some(format!("the wallet is unavailable: {}", err));
+        }
+
+        let lag = self
+            .chain_blockcount
+            .map(|count| count.saturating_sub(self.blockheight))
+            .unwrap_or(0);
+        if lag > max_block_lag {
+            return Some(format!(
+                "lightningd is {} blocks behind bitcoind (at most {} allowed)",
+                lag, max_block_lag
+            ));
+        }
+        None
+    }
+}
+
+#[derive(Debug)]
+struct GateState {
+    healthy: bool,
+    /// The number of consecutive checks that disagree with `healthy`
+    streak: u32,
+    /// The problem found by the most recent failed check
+    problem: Option<String>,
+}
+
+/// Tracks if the node is healthy enough to sell channels
+#[derive(Debug)]
+pub(crate) struct HealthGate {
+    max_block_lag: u32,
+    state: Mutex<GateState>,
+}
+
+impl HealthGate {
+    /// The node is assumed to be healthy until the checks say otherwise
+    pub(crate) fn new(max_block_lag: u32) -> Self {
+        Self {
+            max_block_lag,
+            state: Mutex::new(GateState {
+                healthy: true,
+                streak: 0,
+                problem: None,
+            }),
+        }
+    }
+
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.state.lock().unwrap().healthy
+    }
+
+    /// Records the result of a health check
+    ///
+    /// A check that failed to reach lightningd counts as unhealthy.
+    /// Returns true if the node became healthy or unhealthy.
+    pub(crate) fn record(&self, snapshot: Result<NodeSnapshot>) -> bool {
+        let problem = match snapshot {
+            Ok(snapshot) => snapshot.problem(self.max_block_lag),
+            Err(err) => Some(format!("Failed to check lightningd: {:#}", err)),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let check_healthy = problem.is_none();
+        if problem.is_some() {
+            state.problem = problem;
+        }
+
+        if check_healthy == state.healthy {
+            state.streak = 0;
+            return false;
+        }
+
+        state.streak += 1;
+        let required = if state.healthy {
+            UNHEALTHY_AFTER
+        } else {
+            HEALTHY_AFTER
+        };
+        if state.streak < required {
+            return false;
+        }
+
+        state.healthy = check_healthy;
+        state.streak = 0;
+        if state.healthy {
+            log::info!("The node is healthy again. Orders are accepted");
+            state.problem = None;
+        } else {
+            log::warn!(
+                "The node is unhealthy. Orders are refused: {}",
+                state.problem.as_deref().unwrap_or_default()
+            );
+        }
+        true
+    }
+
+    /// The problem found by the most recent failed check
+    ///
+    /// `None` once the node is healthy again
+    pub(crate) fn problem(&self) -> Option<String> {
+        self.state.lock().unwrap().problem.clone()
+    }
+}
+
+/// Takes a [`NodeSnapshot`] of lightningd
+///
+/// `getchaininfo` is provided by the bitcoin backend plugin. If it isn't
+/// available the lag between lightningd and bitcoind is not checked.
+/// The wallet is available if `listfunds` succeeds.
+pub(crate) async fn take_snapshot(rpc: &mut dyn ClnRpcApi) -> Result<NodeSnapshot> {
+    let getinfo = rpc
+        .getinfo(&GetinfoRequest {})
+        .await
+        .context("Failed to call 'getinfo'")?;
+
+    let request = GetchaininfoRequest {
+        last_height: getinfo.blockheight,
+    };
+    let chain_blockcount = match rpc.getchaininfo(&request).await {
+        Ok(response) => Some(response.blockcount),
+        Err(err) => {
+            log::debug!("Failed to call 'getchaininfo': {:?}", err);
+            None
+        }
+    };
+
+    let wallet_error = check_wallet(rpc).await;
+
+    Ok(NodeSnapshot::from_getinfo(
+        &getinfo,
+        chain_blockcount,
+        wallet_error,
+    ))
+}
+
+/// Returns why the wallet can't be read, if it can't
+async fn check_wallet(rpc: &mut dyn ClnRpcApi) -> Option<String> {
+    rpc.listfunds(&ListfundsRequest { spent: None })
+        .await
+        .context("Failed to call 'listfunds'")
+        .err()
+        .map(|err| format!("{:#}", err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+
+    fn synced(blockheight: u32) -> Result<NodeSnapshot> {
+        Ok(NodeSnapshot {
+            blockheight,
+            chain_blockcount: Some(blockheight),
+            ..Default::default()
+        })
+    }
+
+    fn lagging(blockheight: u32, lag: u32) -> Result<NodeSnapshot> {
+        Ok(NodeSnapshot {
+            blockheight,
+            chain_blockcount: Some(blockheight + lag),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn snapshot_problems() {
+        assert_eq!(synced(800_000).unwrap().problem(3), None);
+        assert_eq!(lagging(800_000, 3).unwrap().problem(3), None);
+        assert!(lagging(800_000, 4).unwrap().problem(3).is_some());
+
+        let snapshot = NodeSnapshot {
+            blockheight: 800_000,
+            warning_bitcoind_sync: Some("Bitcoind is not up-to-date with network.".into()),
+            ..Default::default()
+        };
+        assert!(snapshot
+            .problem(3)
+            .unwrap()
+            .starts_with("bitcoind is syncing"));
+
+        let snapshot = NodeSnapshot {
+            blockheight: 800_000,
+            warning_lightningd_sync: Some("Still loading latest blocks from bitcoind.".into()),
+            ..Default::default()
+        };
+        assert!(snapshot
+            .problem(3)
+            .unwrap()
+            .starts_with("lightningd is syncing"));
+    }
+
+    #[test]
+    fn gate_closes_on_sync_lag_and_recovers() {
+        let gate = HealthGate::new(3);
+        assert!(gate.is_healthy());
+
+        // A single lagging check doesn't close the gate
+        assert!(!gate.record(lagging(800_000, 10)));
+        assert!(gate.is_healthy());
+        assert!(gate.record(lagging(800_001, 9)));
+        assert!(!gate.is_healthy());
+        assert!(gate.problem().unwrap().contains("9 blocks behind"));
+
+        // The gate opens after enough healthy checks
+        assert!(!gate.record(synced(800_010)));
+        assert!(!gate.record(synced(800_010)));
+        assert!(!gate.is_healthy());
+        assert!(gate.record(synced(800_011)));
+        assert!(gate.is_healthy());
+        assert_eq!(gate.problem(), None);
+    }
+
+    #[test]
+    fn gate_closes_while_the_wallet_is_unavailable() {
+        let gate = HealthGate::new(3);
+        let wallet_down = || {
+            Ok(NodeSnapshot {
+                wallet_error: Some("Failed to call 'listfunds': Database locked".into()),
+                ..synced(800_000).unwrap()
+            })
+        };
+
+        assert!(!gate.record(wallet_down()));
+        assert!(gate.record(wallet_down()));
+        assert!(!gate.is_healthy());
+        assert!(gate
+            .problem()
+            .unwrap()
+            .starts_with("the wallet is unavailable"));
+    }
+
+    #[tokio::test]
+    async fn wallet_is_checked_with_listfunds() {
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listfunds", json!({ "outputs" : [], "channels" : [] }))
+            .fail("listfunds", "Database locked");
+
+        assert_eq!(check_wallet(&mut rpc).await, None);
+        let err = check_wallet(&mut rpc).await.unwrap();
+        assert!(err.contains("Database locked"), "{}", err);
+        assert_eq!(rpc.called_methods(), vec!["listfunds", "listfunds"]);
+    }
+
+    #[test]
+    fn flapping_checks_dont_change_the_state() {
+        let gate = HealthGate::new(3);
+
+        // Alternating results never reach the threshold
+        for height in 800_000..800_010 {
+            assert!(!gate.record(lagging(height, 10)));
+            assert!(!gate.record(synced(height)));
+        }
+        assert!(gate.is_healthy());
+
+        // The same holds for a gate that is closed
+        gate.record(Err(anyhow!("Connection refused")));
+        gate.record(Err(anyhow!("Connection refused")));
+        assert!(!gate.is_healthy());
+        for height in 800_000..800_010 {
+            assert!(!gate.record(synced(height)));
+            assert!(!gate.record(synced(height)));
+            assert!(!gate.record(lagging(height, 10)));
+        }
+        assert!(!gate.is_healthy());
+    }
+}
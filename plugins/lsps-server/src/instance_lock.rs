@@ -0,0 +1,207 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+use crate::clock::Clock;
+use crate::db::sqlite::queries::{ClaimInstanceQuery, ReleaseInstanceQuery};
+use crate::db::sqlite::Database;
+
+/// How often the running instance refreshes its heartbeat
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A claim is abandoned if the heartbeat wasn't refreshed for this long
+pub(crate) const STALE_AFTER: Duration = Duration::from_secs(90);
+
+/// The instance stops serving after this many failed heartbeats in a row.
+/// By then another instance could take over the stale claim
+pub(crate) const MAX_HEARTBEAT_FAILURES: u32 =
+    (STALE_AFTER.as_secs() / HEARTBEAT_INTERVAL.as_secs()) as u32;
+
+/// Another instance of the server holds the claim on the database
+#[derive(Debug, Clone)]
+pub(crate) struct ClaimedByOther {
+    instance_id: String,
+    seconds_since_heartbeat: i64,
+}
+
+impl std::fmt::Display for ClaimedByOther {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The database is used by another instance of the lsps-server ({}) which was alive {} seconds ago. \
+             Make sure every node uses its own database or set 'lsp-server-force-start' if the other instance is gone",
+            self.instance_id, self.seconds_since_heartbeat
+        )
+    }
+}
+
+impl std::error::Error for ClaimedByOther {}
+
+/// Ensures only a single instance of the server writes to the database
+///
+/// Order updates and the checks on the configured limits assume there is
+/// a single writer. Two nodes that share a database would break this.
+pub(crate) struct InstanceLock {
+    database: Database,
+    instance_id: String,
+}
+
+impl InstanceLock {
+    /// Claims the database or fails if another instance is alive
+    ///
+    /// The `instance_id` should be derived from the node id. A node that
+    /// restarts after a crash can reclaim the database immediately.
+    /// If `force` is set the claim of the other instance is taken over
+    pub(crate) async fn acquire(
+        database: Database,
+        instance_id: String,
+        clock: &dyn Clock,
+        force: bool,
+    ) -> Result<Self> {
+        let lock = Self {
+            database,
+            instance_id,
+        };
+        lock.claim(clock, force).await?;
+        Ok(lock)
+    }
+
+    /// Refreshes the heartbeat
+    ///
+    /// Fails with [`ClaimedByOther`] if another instance has taken over the database
+    pub(crate) async fn heartbeat(&self, clock: &dyn Clock) -> Result<()> {
+        self.claim(clock, false).await
+    }
+
+    /// Allows another instance to start immediately
+    pub(crate) async fn release(&self) -> Result<()> {
+        let mut tx = self.database.begin().await?;
+        ReleaseInstanceQuery::new(self.instance_id.clone())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn claim(&self, clock: &dyn Clock, force: bool) -> Result<()> {
+        let now = clock.now();
+        let stale_before =
+            IsoDatetime::from_unix_timestamp(now.unix_timestamp() - STALE_AFTER.as_secs() as i64)?;
+
+        let mut tx = self.database.begin().await?;
+        let other = ClaimInstanceQuery::new(self.instance_id.clone(), now, stale_before)
+            .force(force)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        match other {
+            None => Ok(()),
+            Some(other) => Err(ClaimedByOther {
+                instance_id: other.instance_id,
+                seconds_since_heartbeat: now.unix_timestamp() - other.heartbeat_at.unix_timestamp(),
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::clock::test_support::MockClock;
+    use crate::db::sqlite::test::get_temp_db;
+
+    fn node_1() -> String {
+        String::from("026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170")
+    }
+
+    fn node_2() -> String {
+        String::from("03b9f9c4a5a7e0e5ef2a1e14b0a9c0c2c9fbb2a0b0f3f8b6d0c8a2c3d4e5f6a7b8")
+    }
+
+    #[tokio::test]
+    async fn second_instance_refuses_to_start() {
+        let clock = MockClock::new(IsoDatetime::now());
+        let (db_1, db_2) = get_temp_db().await;
+
+        let lock_1 = InstanceLock::acquire(db_1, node_1(), &clock, false)
+            .await
+            .unwrap();
+        let err = InstanceLock::acquire(db_2.clone(), node_2(), &clock, false)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<ClaimedByOther>());
+        assert!(err.to_string().contains("lsp-server-force-start"));
+
+        // The first instance keeps its claim
+        clock.advance(HEARTBEAT_INTERVAL);
+        lock_1.heartbeat(&clock).await.unwrap();
+        assert!(InstanceLock::acquire(db_2, node_2(), &clock, false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_or_released_claims_can_be_taken_over() {
+        let clock = MockClock::new(IsoDatetime::now());
+        let (db_1, db_2) = get_temp_db().await;
+
+        // A released claim can be taken immediately
+        let lock_1 = InstanceLock::acquire(db_1.clone(), node_1(), &clock, false)
+            .await
+            .unwrap();
+        lock_1.release().await.unwrap();
+        let lock_2 = InstanceLock::acquire(db_2, node_2(), &clock, false)
+            .await
+            .unwrap();
+
+        // A claim without heartbeat becomes stale
+        clock.advance(STALE_AFTER + Duration::from_secs(1));
+        let lock_1 = InstanceLock::acquire(db_1, node_1(), &clock, false)
+            .await
+            .unwrap();
+
+        // The instance that lost its claim is told so
+        let err = lock_2.heartbeat(&clock).await.err().unwrap();
+        assert!(err.is::<ClaimedByOther>());
+        lock_1.heartbeat(&clock).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn force_start_takes_over_claim() {
+        let clock = MockClock::new(IsoDatetime::now());
+        let (db_1, db_2) = get_temp_db().await;
+
+        let lock_1 = InstanceLock::acquire(db_1, node_1(), &clock, false)
+            .await
+            .unwrap();
+        let lock_2 = InstanceLock::acquire(db_2, node_2(), &clock, true)
+            .await
+            .unwrap();
+
+        assert!(lock_1.heartbeat(&clock).await.is_err());
+        lock_2.heartbeat(&clock).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn restarted_node_reclaims_immediately() {
+        let clock = MockClock::new(IsoDatetime::now());
+        let (db_1, db_2) = get_temp_db().await;
+
+        // The first run crashed without releasing its claim
+        let _crashed = InstanceLock::acquire(db_1, node_1(), &clock, false)
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        let lock = InstanceLock::acquire(db_2, node_1(), &clock, false)
+            .await
+            .unwrap();
+        lock.heartbeat(&clock).await.unwrap();
+    }
+}
@@ -0,0 +1,597 @@
+//! Manual overrides for the operator of the LSP-server
+//!
+//! Opening a channel can fail repeatedly. E.g: because fee-rates spike
+//! or the wallet has no confirmed utxos. The operator can retry to open
+//! the channel or open it out-of-band and mark the order as fulfilled.
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::ListpeerchannelsRequest;
+use cln_rpc::model::responses::{ListpeerchannelsChannels, ListpeerchannelsChannelsState};
+use cln_rpc::ClnRpc;
+use serde_json::json;
+use sqlx::{Sqlite, Transaction};
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::{PublicKey, TransactionId};
+use lsp_primitives::lsps1::schema::{OrderState, PaymentState};
+
+use crate::cln::public_key::is_same_public_key;
+use crate::clock::Clock;
+use crate::db::schema::{
+    Lsps1Channel, Lsps1Order, Lsps1OrderState, Lsps1PaymentDetails, OrderLogEvent,
+};
+use crate::db::sqlite::queries::{
+    CreateChannelQuery, GetChannelQuery, GetOrderQuery, GetPaymentDetailsQuery,
+    SetFundingBlockheightQuery, UpdateOrderStateQuery, UpdatePaymentStateQuery,
+};
+use crate::db::sqlite::Database;
+use crate::lsps1::funding_reservation::release_funding_inputs;
+use crate::lsps1::order_watcher::OrderWatcher;
+use crate::lsps1::receipt::try_issue_receipt;
+use crate::plugin_rpc::{Lsps1AdminFulfillOrderRequest, Lsps1AdminRetryOpenRequest};
+use crate::state::PluginState;
+
+/// Handles `lsps1-admin-fulfill-order`
+pub(crate) async fn lsps1_admin_fulfill_order(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminFulfillOrderRequest = serde_json::from_value(request)?;
+    let order_uuid = Uuid::parse_str(&request.order_id).context("Invalid order_id")?;
+    let funding_txid =
+        TransactionId::from_str(&request.funding_txid).context("Invalid funding_txid")?;
+
+    let db = &plugin.state().database;
+    let mut tx = db.begin().await?;
+    let (order, _) = get_unfunded_order(&mut tx, order_uuid).await?;
+    tx.commit().await?;
+
+    let rpc_path = plugin.configuration().rpc_file;
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let response = rpc
+        .call_typed(&ListpeerchannelsRequest { id: None })
+        .await
+        .context("Failed to call 'listpeerchannels'")?;
+    let channels = response.channels.unwrap_or_default();
+
+    let peer_channel = find_funding_channel(
+        &channels,
+        &order.client_node_id,
+        &funding_txid,
+        request.outnum,
+    )?;
+    let is_ready = peer_channel.state == Some(ListpeerchannelsChannelsState::CHANNELD_NORMAL);
+    let funding_blockheight = peer_channel.short_channel_id.map(|scid| scid.block());
+
+    let channel = Lsps1Channel {
+        funding_txid,
+        outnum: request.outnum,
+        funded_at: plugin.state().clock.now(),
+    };
+    let order_state = fulfill_order(
+        db,
+        &plugin.state().order_watcher,
+        plugin.state().clock.as_ref(),
+        order_uuid,
+        channel,
+        funding_blockheight,
+        is_ready,
+    )
+    .await?;
+
+    log::info!(
+        "Order {} was fulfilled manually by {}:{}",
+        order_uuid,
+        request.funding_txid,
+        request.outnum
+    );
+    plugin.state().order_log.info(
+        order_uuid,
+        OrderLogEvent::AdminFulfillOrder,
+        json!({
+            "funding_outpoint" : format!("{}:{}", request.funding_txid, request.outnum),
+            "funding_blockheight" : funding_blockheight,
+            "channel_ready" : is_ready,
+        }),
+    );
+    // The channel was funded out-of-band
+    if let Err(err) = release_funding_inputs(plugin.state(), &mut rpc, order_uuid).await {
+        log::warn!(
+            "Failed to release the reservation of order {}: {:?}",
+            order_uuid,
+            err
+        );
+    }
+    if order_state == Lsps1OrderState::Completed {
+        try_issue_receipt(plugin.state(), &mut rpc, order_uuid).await;
+    }
+    Ok(json!({
+        "order_id" : order_uuid,
+        "order_state" : OrderState::from(order_state),
+        "funding_outpoint" : format!("{}:{}", request.funding_txid, request.outnum),
+    }))
+}
+
+/// Handles `lsps1-admin-retry-open`
+pub(crate) async fn lsps1_admin_retry_open(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminRetryOpenRequest = serde_json::from_value(request)?;
+    let order_uuid = Uuid::parse_str(&request.order_id).context("Invalid order_id")?;
+
+    let queued = retry_open(plugin.state(), order_uuid).await?;
+    log::info!("Retrying to open channel for order {}", order_uuid);
+
+    Ok(json!({
+        "order_id" : order_uuid,
+        "order_state" : OrderState::from(Lsps1OrderState::PendingOpen),
+        "queued" : queued,
+    }))
+}
+
+/// Moves the order back to `PendingOpen` and adds it to the [`OpenQueue`]
+///
+/// The channel is opened by the worker of the queue. This ensures a retry
+/// can't open a channel while the worker is opening one for the same order.
+/// Returns false if the order was already queued.
+///
+/// [`OpenQueue`]: crate::lsps1::open_queue::OpenQueue
+pub(crate) async fn retry_open(state: &PluginState, order_uuid: Uuid) -> Result<bool> {
+    let mut tx = state.database.begin().await?;
+    let (order, _) = get_unfunded_order(&mut tx, order_uuid).await?;
+    if order.order_state == Lsps1OrderState::Failed {
+        // Fails if the order changed since we read it
+        UpdateOrderStateQuery {
+            order_uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: order.generation,
+            changed_at: state.clock.now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .with_context(|| format!("Failed to retry order {}", order_uuid))?;
+    }
+    tx.commit().await?;
+    state.order_watcher.notify(order_uuid);
+    state
+        .order_log
+        .info(order_uuid, OrderLogEvent::AdminRetryOpen, json!({}));
+
+    Ok(state.open_queue.enqueue(order_uuid))
+}
+
+/// Handles `lsps1-admin-reload-peer-lists`
+pub(crate) async fn lsps1_admin_reload_peer_lists(
+    plugin: Plugin<PluginState>,
+    _request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let sizes = plugin
+        .state()
+        .peer_policy
+        .reload()
+        .context("Failed to reload peer lists. The previous lists are used")?;
+    log::info!("Reloaded peer lists: {:?}", sizes);
+    Ok(json!({
+        "allowlist" : sizes.allowlist,
+        "denylist" : sizes.denylist,
+    }))
+}
+
+/// Returns the order if it has been paid but no channel has been opened yet
+///
+/// Only orders that are `PendingOpen` or `Failed` qualify. The worker is
+/// opening the channel of an order that is `Funding`.
+async fn get_unfunded_order(
+    tx: &mut Transaction<'static, Sqlite>,
+    order_uuid: Uuid,
+) -> Result<(Lsps1Order, Lsps1PaymentDetails)> {
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(tx)
+        .await
+        .context("Failed to execute 'get_order'-query on database")?
+        .with_context(|| format!("Unknown order {}", order_uuid))?;
+
+    let payment_details = GetPaymentDetailsQuery::by_uuid(order_uuid)
+        .execute(tx)
+        .await
+        .context("Failed to execute 'get_payment_details'-query on database")?
+        .context("Failed to find payment that corresponds to order")?;
+
+    if !matches!(
+        payment_details.state,
+        PaymentState::Hold | PaymentState::Paid
+    ) {
+        return Err(anyhow!(
+            "Order {} has not been paid. Payment state is {:?}",
+            order_uuid,
+            payment_details.state
+        ));
+    }
+
+    if !matches!(
+        order.order_state,
+        Lsps1OrderState::PendingOpen | Lsps1OrderState::Failed
+    ) {
+        return Err(anyhow!(
+            "Order {} is in state {:?}",
+            order_uuid,
+            order.order_state
+        ));
+    }
+
+    let channel = GetChannelQuery::by_order_id(order_uuid).execute(tx).await?;
+    if let Some(channel) = channel {
+        return Err(anyhow!(
+            "Order {} is already funded by {}:{}",
+            order_uuid,
+            channel.funding_txid,
+            channel.outnum
+        ));
+    }
+
+    Ok((order, payment_details))
+}
+
+/// Finds the channel funded by `funding_txid:outnum` and verifies it is
+/// a channel with the client
+fn find_funding_channel<'a>(
+    channels: &'a [ListpeerchannelsChannels],
+    client_node_id: &PublicKey,
+    funding_txid: &TransactionId,
+    outnum: u32,
+) -> Result<&'a ListpeerchannelsChannels> {
+    let funding_txid = funding_txid.to_string();
+    let channel = channels
+        .iter()
+        .find(|c| {
+            c.funding_txid.as_deref() == Some(funding_txid.as_str())
+                && c.funding_outnum == Some(outnum)
+        })
+        .with_context(|| format!("Unknown channel with outpoint {}:{}", funding_txid, outnum))?;
+
+    let is_client = channel
+        .peer_id
+        .as_ref()
+        .map(|peer_id| is_same_public_key(client_node_id, peer_id))
+        .unwrap_or(false);
+    if !is_client {
+        return Err(anyhow!(
+            "Channel {}:{} is not a channel with client {:?}",
+            funding_txid,
+            outnum,
+            client_node_id
+        ));
+    }
+
+    Ok(channel)
+}
+
+/// Stores `channel` for the order and marks the payment as `Paid`
+///
+/// The order completes immediately if the channel is ready.
+/// Otherwise, it completes once the channel reaches `CHANNELD_NORMAL`.
+async fn fulfill_order(
+    db: &Database,
+    order_watcher: &OrderWatcher,
+    clock: &dyn Clock,
+    order_uuid: Uuid,
+    channel: Lsps1Channel,
+    funding_blockheight: Option<u32>,
+    is_ready: bool,
+) -> Result<Lsps1OrderState> {
+    let mut tx = db.begin().await?;
+    let (order, payment_details) = get_unfunded_order(&mut tx, order_uuid).await?;
+
+    let inserted = CreateChannelQuery::new(order_uuid, channel)
+        .execute(&mut tx)
+        .await?;
+    if !inserted {
+        return Err(anyhow!("Failed to store channel for order {}", order_uuid));
+    }
+
+    if let Some(funding_blockheight) = funding_blockheight {
+        SetFundingBlockheightQuery {
+            order_uuid,
+            funding_blockheight,
+        }
+        .execute(&mut tx)
+        .await?;
+    }
+
+    if payment_details.state != PaymentState::Paid {
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            changed_at: clock.now(),
+            generation: payment_details.generation,
+            label: payment_details.bolt11_invoice_label,
+        }
+        .execute(&mut tx)
+        .await?;
+    }
+
+    let state = if is_ready {
+        Lsps1OrderState::Completed
+    } else {
+        Lsps1OrderState::ChannelOpening
+    };
+    UpdateOrderStateQuery {
+        order_uuid,
+        state,
+        generation: order.generation,
+        changed_at: clock.now(),
+        failure_reason: None,
+    }
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    order_watcher.notify(order_uuid);
+    Ok(state)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::clock::SystemClock;
+    use crate::db::schema::{OrderGeneration, PaymentGeneration};
+    use crate::db::sqlite::test::{create_order_query, get_db};
+    use crate::state::test_support::test_state;
+
+    const FUNDING_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    const OTHER_PEER: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    fn peer_channel(peer_id: &str, funding_txid: &str, outnum: u32) -> ListpeerchannelsChannels {
+        serde_json::from_value(json!({
+            "peer_id" : peer_id,
+            "funding_txid" : funding_txid,
+            "funding_outnum" : outnum,
+            "state" : "CHANNELD_NORMAL",
+        }))
+        .unwrap()
+    }
+
+    /// Creates an order in `order_state` and sets the payment to `payment_state`
+    async fn create_order_in_state(
+        db: &Database,
+        order_state: Lsps1OrderState,
+        payment_state: PaymentState,
+    ) -> Uuid {
+        let query = create_order_query();
+        let order_uuid = query.order.uuid;
+        let label = query.payment.bolt11_invoice_label.clone();
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdatePaymentStateQuery {
+            state: payment_state,
+            changed_at: IsoDatetime::now(),
+            generation: PaymentGeneration(0),
+            label,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        if order_state != Lsps1OrderState::Created {
+            UpdateOrderStateQuery {
+                order_uuid,
+                state: order_state,
+                generation: OrderGeneration(0),
+                changed_at: IsoDatetime::now(),
+                failure_reason: None,
+            }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        order_uuid
+    }
+
+    /// Creates an order that is waiting for its channel
+    async fn create_order(db: &Database, payment_state: PaymentState) -> Uuid {
+        create_order_in_state(db, Lsps1OrderState::PendingOpen, payment_state).await
+    }
+
+    async fn get_order(db: &Database, order_uuid: Uuid) -> Lsps1Order {
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        order
+    }
+
+    fn create_channel() -> Lsps1Channel {
+        Lsps1Channel {
+            funding_txid: TransactionId::from_str(FUNDING_TXID).unwrap(),
+            outnum: 0,
+            funded_at: IsoDatetime::now(),
+        }
+    }
+
+    #[test]
+    fn find_channel_with_client() {
+        let client = create_order_query().order.client_node_id;
+        let client_hex = client.to_hex();
+        let txid = TransactionId::from_str(FUNDING_TXID).unwrap();
+
+        let channels = vec![
+            peer_channel(OTHER_PEER, FUNDING_TXID, 0),
+            peer_channel(&client_hex, FUNDING_TXID, 1),
+        ];
+
+        let channel = find_funding_channel(&channels, &client, &txid, 1).unwrap();
+        assert_eq!(channel.funding_outnum, Some(1));
+    }
+
+    #[test]
+    fn reject_channel_with_wrong_peer() {
+        let client = create_order_query().order.client_node_id;
+        let txid = TransactionId::from_str(FUNDING_TXID).unwrap();
+        let channels = vec![peer_channel(OTHER_PEER, FUNDING_TXID, 0)];
+
+        let err = find_funding_channel(&channels, &client, &txid, 0).unwrap_err();
+        assert!(err.to_string().contains("is not a channel with client"));
+    }
+
+    #[test]
+    fn reject_unknown_outpoint() {
+        let client = create_order_query().order.client_node_id;
+        let txid = TransactionId::from_str(FUNDING_TXID).unwrap();
+        let channels = vec![peer_channel(&client.to_hex(), FUNDING_TXID, 0)];
+
+        let err = find_funding_channel(&channels, &client, &txid, 1).unwrap_err();
+        assert!(err.to_string().contains("Unknown channel"));
+    }
+
+    #[tokio::test]
+    async fn reject_unpaid_order() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let order_uuid = create_order(&db, PaymentState::ExpectPayment).await;
+
+        let err = fulfill_order(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            order_uuid,
+            create_channel(),
+            None,
+            true,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("has not been paid"));
+    }
+
+    #[tokio::test]
+    async fn fulfill_paid_order() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let order_uuid = create_order(&db, PaymentState::Hold).await;
+
+        let state = fulfill_order(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            order_uuid,
+            create_channel(),
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state, Lsps1OrderState::Completed);
+
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let channel = GetChannelQuery::by_order_id(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(order.order_state, Lsps1OrderState::Completed);
+        assert_eq!(payment.state, PaymentState::Paid);
+        assert_eq!(channel.funding_txid.to_string(), FUNDING_TXID);
+
+        // An order can only be fulfilled once
+        let err = fulfill_order(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            order_uuid,
+            create_channel(),
+            None,
+            true,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("is in state"));
+    }
+
+    #[tokio::test]
+    async fn fulfilled_order_waits_for_channel() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let order_uuid = create_order(&db, PaymentState::Paid).await;
+
+        let state = fulfill_order(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            order_uuid,
+            create_channel(),
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state, Lsps1OrderState::ChannelOpening);
+    }
+
+    #[tokio::test]
+    async fn reject_order_that_is_being_opened() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        // The worker moves an order to `Funding` while it opens the channel
+        let order_uuid =
+            create_order_in_state(&db, Lsps1OrderState::Funding, PaymentState::Paid).await;
+
+        let err = fulfill_order(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            order_uuid,
+            create_channel(),
+            None,
+            true,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("is in state"));
+
+        let state = test_state(db.clone());
+        let err = retry_open(&state, order_uuid).await.unwrap_err();
+        assert!(err.to_string().contains("is in state"));
+        assert!(!state.open_queue.is_in_flight(&order_uuid));
+    }
+
+    #[tokio::test]
+    async fn retry_queues_failed_order() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let order_uuid =
+            create_order_in_state(&db, Lsps1OrderState::Failed, PaymentState::Paid).await;
+
+        assert!(retry_open(&state, order_uuid).await.unwrap());
+        assert!(state.open_queue.is_in_flight(&order_uuid));
+
+        let order = get_order(&db, order_uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::PendingOpen);
+        assert_eq!(order.failure_reason, None);
+
+        // A second retry doesn't queue the order twice
+        assert!(!retry_open(&state, order_uuid).await.unwrap());
+    }
+}
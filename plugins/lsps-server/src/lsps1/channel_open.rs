@@ -0,0 +1,1559 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::{ListpeerchannelsRequest, ListpeersRequest};
+use cln_rpc::primitives::ChannelSide;
+
+use serde_json::json;
+use sqlx::{Sqlite, Transaction};
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::{PublicKey, TransactionId};
+
+use crate::channel_open::{fundchannel_fallible, ChannelDetails, FeerateSnapshot, ServerConfig};
+use crate::cln::public_key::{is_same_public_key, to_rpc_public_key};
+use crate::cln::reconnecting_rpc::ReconnectingRpc;
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::Clock;
+use crate::db::schema::{
+    Lsps1Channel, Lsps1FailureReason, Lsps1Order, Lsps1OrderState, OrderLogEvent,
+};
+use crate::db::sqlite::queries::UpdateOrderStateQuery;
+use crate::db::sqlite::queries::{
+    ChannelOpeningOrder, CreateChannelQuery, GetChannelOrderQuery, GetChannelTypeQuery,
+    GetFundingOrdersQuery, GetFundingSourceQuery, GetOrderQuery, GetPendingOpenOrdersQuery,
+};
+use crate::db::sqlite::Database;
+use crate::lsps1::funding_reservation::{
+    funding_inputs_used, release_funding_inputs, reserve_funding_inputs,
+    reserve_funding_inputs_within_budget,
+};
+use crate::lsps1::hooks::check_channel_opening_order;
+use crate::lsps1::open_queue::OpenQueue;
+use crate::lsps1::order_expiry::is_past_payment_grace;
+use crate::lsps1::refund::refund_order;
+use crate::state::PluginState;
+
+/// Returns true if we currently have a connection to `peer_id`
+pub(crate) async fn is_peer_connected(
+    rpc: &mut dyn ClnRpcApi,
+    peer_id: &PublicKey,
+) -> Result<bool> {
+    let id = to_rpc_public_key(peer_id).context("Invalid peer_id")?;
+    let request = ListpeersRequest {
+        id: Some(id),
+        level: None,
+    };
+    let response = rpc
+        .listpeers(&request)
+        .await
+        .context("Failed to call 'listpeers'")?;
+
+    Ok(response
+        .peers
+        .iter()
+        .any(|p| p.connected && is_same_public_key(peer_id, &p.id)))
+}
+
+/// Translates `order` into the channel the client purchased
+///
+/// The channel has the type the client requested when it created the order.
+/// It is funded by the outputs of the funding source of the order.
+async fn channel_details_for_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order: &Lsps1Order,
+) -> Result<ChannelDetails> {
+    let config = state
+        .lsps1_info()
+        .map(|info| ServerConfig::new(&info.options, state.close_to.clone()))
+        .context("LSPS1 is not configured")?;
+
+    // Without estimates lightningd picks the feerate
+    let feerates = match state.feerates.get(rpc, state.clock.as_ref()).await {
+        Ok(cached) => FeerateSnapshot {
+            estimates: cached.estimates.clone(),
+        },
+        Err(err) => {
+            log::warn!("Failed to retrieve feerates: {:?}", err);
+            FeerateSnapshot::default()
+        }
+    };
+
+    let mut tx = state.database.begin().await?;
+    let channel_type = GetChannelTypeQuery::by_uuid(order.uuid)
+        .execute(&mut tx)
+        .await?;
+    let funding_source = GetFundingSourceQuery::by_uuid(order.uuid)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let mut channel_details = ChannelDetails::from_order(order, &config, &feerates)?;
+    channel_details.channel_type = channel_type;
+    if let Some(label) = funding_source {
+        let source = state.funding_sources.get(&label).with_context(|| {
+            format!(
+                "Funding source '{}' of order {} is not configured",
+                label, order.uuid
+            )
+        })?;
+        channel_details.utxo_filter = Some(source.filter.clone());
+    }
+    Ok(channel_details)
+}
+
+/// Attempts to open the channel that the client purchased in `order`
+///
+/// The channel is funded by the inputs that were reserved for the order.
+/// Fails if the wallet can't fund the channel.
+pub(crate) async fn open_channel_for_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order: &Lsps1Order,
+) -> Result<Lsps1Channel> {
+    let timeout = std::time::Duration::from_secs(60);
+
+    let mut channel_details = channel_details_for_order(state, rpc, order).await?;
+    channel_details.reserved_inputs =
+        reserve_funding_inputs_within_budget(state, rpc, order.uuid, &channel_details).await?;
+
+    log::debug!("Atempting to open channel for order {}", order.uuid);
+    let result = fundchannel_fallible(rpc, state.clock.as_ref(), &channel_details, timeout).await;
+
+    if let Some(reservation) = &channel_details.reserved_inputs {
+        if let Err(err) = funding_inputs_used(state, order.uuid, reservation, result.is_ok()).await
+        {
+            log::warn!(
+                "Failed to forget the reservation of order {}: {:?}",
+                order.uuid,
+                err
+            );
+        }
+    }
+    result
+}
+
+/// Reserves the inputs of an order whose client is offline
+///
+/// Other wallet activity can't spend the inputs while the order waits
+/// for the client to reconnect.
+async fn reserve_inputs_while_offline(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order: &Lsps1Order,
+) {
+    match channel_details_for_order(state, rpc, order).await {
+        Ok(channel_details) => {
+            reserve_funding_inputs(state, rpc, order.uuid, &channel_details).await;
+        }
+        Err(err) => log::warn!(
+            "Failed to reserve inputs for order {}: {:?}",
+            order.uuid,
+            err
+        ),
+    }
+}
+
+/// Queues the paid orders whose channel hasn't been opened yet
+///
+/// This is used at start-up to recover the queue and when a client reconnects.
+/// If `peer_id` is `None` all pending orders are queued.
+/// Returns the number of orders that were added to the queue.
+pub(crate) async fn enqueue_pending_orders(
+    db: &Database,
+    open_queue: &OpenQueue,
+    peer_id: Option<PublicKey>,
+) -> Result<usize> {
+    let query = match peer_id {
+        Some(peer_id) => GetPendingOpenOrdersQuery::by_peer_id(peer_id),
+        None => GetPendingOpenOrdersQuery::all(),
+    };
+
+    let mut tx = db.begin().await?;
+    let orders = query
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_pending_open_orders'-query on database")?;
+    tx.commit().await?;
+
+    Ok(orders
+        .iter()
+        .filter(|order| open_queue.enqueue(order.uuid))
+        .count())
+}
+
+/// Opens the channel for an order taken from the [`OpenQueue`]
+///
+/// Orders that are no longer `PendingOpen` have been handled in the mean time
+/// and are skipped.
+pub(crate) async fn process_queued_order(
+    plugin: Plugin<PluginState>,
+    order_uuid: Uuid,
+) -> Result<()> {
+    let db = &plugin.state().database;
+    let mut tx = db.begin().await?;
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order'-query on database")?
+        .context("Failed to find queued order")?;
+    tx.commit().await?;
+
+    if order.order_state != Lsps1OrderState::PendingOpen {
+        log::debug!(
+            "Skipping queued order {} in state {:?}",
+            order.uuid,
+            order.order_state
+        );
+        return Ok(());
+    }
+
+    // lightningd might be restarting its rpc-socket. Connecting is retried
+    // so the order doesn't wait for the next start-up to be opened
+    let rpc_path = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("open_worker", rpc_path, plugin.state().metrics.clone());
+    open_pending_order(plugin.state(), &mut rpc, &order).await
+}
+
+/// Fails a paid order if the client didn't come back before it expired
+///
+/// An order that was paid within `payment_grace` after it expired can
+/// still be opened until the grace period has elapsed.
+///
+/// Returns true if the order has expired. The client is owed a refund
+/// which must be broadcast using [`refund_order`] once `tx` is committed.
+pub(crate) async fn fail_expired_order(
+    tx: &mut Transaction<'static, Sqlite>,
+    clock: &dyn Clock,
+    payment_grace: Duration,
+    order: &Lsps1Order,
+) -> Result<bool> {
+    if !is_past_payment_grace(&order.expires_at, payment_grace, &clock.now()) {
+        return Ok(false);
+    }
+
+    log::warn!(
+        "Order {} expired before peer {:?} reconnected. The payment will be refunded",
+        order.uuid,
+        order.client_node_id
+    );
+    UpdateOrderStateQuery {
+        order_uuid: order.uuid,
+        state: Lsps1OrderState::Failed,
+        generation: order.generation,
+        changed_at: clock.now(),
+        failure_reason: Some(Lsps1FailureReason::OrderExpired),
+    }
+    .execute(tx)
+    .await?;
+
+    Ok(true)
+}
+
+/// Releases the reserved inputs of an order that won't fund a channel
+async fn release_reservation(state: &PluginState, rpc: &mut dyn ClnRpcApi, order_uuid: Uuid) {
+    if let Err(err) = release_funding_inputs(state, rpc, order_uuid).await {
+        log::warn!(
+            "Failed to release the reservation of order {}: {:?}",
+            order_uuid,
+            err
+        );
+    }
+}
+
+/// Refunds an order that has failed
+///
+/// A failed refund is logged. The operator can see in the order log
+/// which orders are still owed a refund.
+async fn refund_failed_order(state: &PluginState, rpc: &mut dyn ClnRpcApi, order_uuid: Uuid) {
+    if let Err(err) = refund_order(state, rpc, order_uuid).await {
+        log::warn!("Failed to refund order {}: {:?}", order_uuid, err);
+    }
+}
+
+pub(crate) async fn open_pending_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order: &Lsps1Order,
+) -> Result<()> {
+    let db = &state.database;
+    let order_log = &state.order_log;
+    let mut tx = db.begin().await?;
+
+    let clock = state.clock.as_ref();
+    if fail_expired_order(&mut tx, clock, state.payment_grace, order).await? {
+        tx.commit().await?;
+        state.order_watcher.notify(order.uuid);
+        order_log.error(
+            order.uuid,
+            OrderLogEvent::OrderExpired,
+            json!({ "expires_at" : order.expires_at }),
+        );
+        release_reservation(state, rpc, order.uuid).await;
+        refund_failed_order(state, rpc, order.uuid).await;
+        return Ok(());
+    }
+
+    if !is_peer_connected(rpc, &order.client_node_id).await? {
+        log::info!(
+            "Peer {:?} is offline. Order {} will be opened when they reconnect",
+            order.client_node_id,
+            order.uuid
+        );
+        tx.commit().await?;
+        order_log.warn(order.uuid, OrderLogEvent::PeerOffline, json!({}));
+        reserve_inputs_while_offline(state, rpc, order).await;
+        return Ok(());
+    }
+
+    // Move the order to `Funding` before opening the channel.
+    // The update fails if another task changed the order since we read it.
+    // This ensures only one task opens a channel for the order
+    UpdateOrderStateQuery {
+        order_uuid: order.uuid,
+        state: Lsps1OrderState::Funding,
+        generation: order.generation,
+        changed_at: state.clock.now(),
+        failure_reason: None,
+    }
+    .execute(&mut tx)
+    .await
+    .with_context(|| format!("Failed to claim order {} for opening", order.uuid))?;
+    tx.commit().await?;
+    let generation = order.generation.next();
+
+    log::info!(
+        "Peer {:?} is online. Opening channel for order {}",
+        order.client_node_id,
+        order.uuid
+    );
+    order_log.info(order.uuid, OrderLogEvent::ChannelOpenStarted, json!({}));
+    let channel_result = open_channel_for_order(state, rpc, order).await;
+
+    let mut opening_order = None;
+    match channel_result {
+        Ok(channel) => {
+            log::info!(
+                "Successfully opened channel for pending order {}",
+                order.uuid
+            );
+            order_log.info(
+                order.uuid,
+                OrderLogEvent::ChannelOpened,
+                json!({
+                    "funding_outpoint" : format!("{}:{}", channel.funding_txid, channel.outnum)
+                }),
+            );
+            let create_channel = CreateChannelQuery::new(order.uuid, channel.clone());
+            // The order completes once the channel reaches CHANNELD_NORMAL
+            let update_order_state = UpdateOrderStateQuery {
+                order_uuid: order.uuid,
+                state: Lsps1OrderState::ChannelOpening,
+                generation,
+                changed_at: state.clock.now(),
+                failure_reason: None,
+            };
+            db.with_tx("store_channel", move |tx| {
+                Box::pin(async move {
+                    if !create_channel.execute(tx).await? {
+                        log::debug!("Channel for order {} was already stored", order.uuid);
+                    }
+                    update_order_state.execute(tx).await
+                })
+            })
+            .await?;
+
+            opening_order = Some(ChannelOpeningOrder {
+                order_uuid: order.uuid,
+                client_node_id: order.client_node_id,
+                channel,
+                generation: generation.next(),
+            });
+        }
+        Err(err) => {
+            log::warn!("Failed to open channel for order {}: {}", order.uuid, err);
+
+            // The client might have gone offline again.
+            // In that case we'll retry when they reconnect
+            let is_connected = is_peer_connected(rpc, &order.client_node_id)
+                .await
+                .unwrap_or(false);
+
+            // The order is retried if the client went offline
+            let details = json!({
+                "error" : err.to_string(),
+                "peer_connected" : is_connected,
+            });
+            if is_connected {
+                order_log.error(order.uuid, OrderLogEvent::ChannelOpenFailed, details);
+            } else {
+                order_log.warn(order.uuid, OrderLogEvent::ChannelOpenFailed, details);
+            }
+
+            let (order_state, failure_reason) = if is_connected {
+                (
+                    Lsps1OrderState::Failed,
+                    Some(Lsps1FailureReason::ChannelOpenFailed),
+                )
+            } else {
+                (Lsps1OrderState::PendingOpen, None)
+            };
+
+            let update_order_state = UpdateOrderStateQuery {
+                order_uuid: order.uuid,
+                state: order_state,
+                generation,
+                changed_at: state.clock.now(),
+                failure_reason,
+            };
+            db.with_tx("store_channel_open_failure", move |tx| {
+                Box::pin(async move { update_order_state.execute(tx).await })
+            })
+            .await?;
+
+            if is_connected {
+                log::warn!("Order {} failed. The payment will be refunded", order.uuid);
+                refund_failed_order(state, rpc, order.uuid).await;
+            }
+        }
+    }
+
+    state.order_watcher.notify(order.uuid);
+
+    // A zero-conf channel can reach CHANNELD_NORMAL before the order is in
+    // `ChannelOpening`. The notification for that change was ignored
+    if let Some(opening_order) = opening_order {
+        if let Err(err) = check_channel_opening_order(state, rpc, &opening_order).await {
+            log::warn!(
+                "Failed to check the channel state of order {}: {:?}",
+                order.uuid,
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Recovers the orders that were `Funding` when the plugin stopped
+///
+/// The channel might have been opened before the order was updated.
+/// A matching channel is stored and the order moves to `ChannelOpening`.
+/// Otherwise, the order returns to `PendingOpen` and is opened again.
+/// This must run before [`enqueue_pending_orders`] at start-up.
+pub(crate) async fn reconcile_funding_orders(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+) -> Result<()> {
+    let orders = state
+        .database
+        .with_tx("get_funding_orders", |tx| {
+            Box::pin(async move {
+                GetFundingOrdersQuery
+                    .execute(tx)
+                    .await
+                    .context("Failed to execute 'get_funding_orders'-query on database")
+            })
+        })
+        .await?;
+
+    for order in orders {
+        if let Err(err) = reconcile_funding_order(state, rpc, &order).await {
+            log::warn!("Failed to reconcile order {}: {:?}", order.uuid, err);
+        }
+    }
+    Ok(())
+}
+
+async fn reconcile_funding_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order: &Lsps1Order,
+) -> Result<()> {
+    let channel =
+        find_unrecorded_channel(&state.database, state.clock.as_ref(), rpc, order).await?;
+
+    let opening_order = state
+        .database
+        .with_tx("reconcile_funding_order", move |tx| {
+            Box::pin(async move {
+                match channel {
+                    Some(channel) => {
+                        log::info!(
+                            "Found channel {}:{} for order {} that was interrupted while funding",
+                            channel.funding_txid,
+                            channel.outnum,
+                            order.uuid
+                        );
+                        CreateChannelQuery::new(order.uuid, channel.clone())
+                            .execute(tx)
+                            .await?;
+                        UpdateOrderStateQuery {
+                            order_uuid: order.uuid,
+                            state: Lsps1OrderState::ChannelOpening,
+                            generation: order.generation,
+                            changed_at: state.clock.now(),
+                            failure_reason: None,
+                        }
+                        .execute(tx)
+                        .await?;
+                        Ok(Some(ChannelOpeningOrder {
+                            order_uuid: order.uuid,
+                            client_node_id: order.client_node_id,
+                            channel,
+                            generation: order.generation.next(),
+                        }))
+                    }
+                    None => {
+                        log::info!(
+                            "No channel was opened for order {} that was interrupted while funding. It is opened again",
+                            order.uuid
+                        );
+                        UpdateOrderStateQuery {
+                            order_uuid: order.uuid,
+                            state: Lsps1OrderState::PendingOpen,
+                            generation: order.generation,
+                            changed_at: state.clock.now(),
+                            failure_reason: None,
+                        }
+                        .execute(tx)
+                        .await?;
+                        Ok(None)
+                    }
+                }
+            })
+        })
+        .await?;
+    state.order_watcher.notify(order.uuid);
+
+    if let Some(opening_order) = opening_order {
+        let channel = &opening_order.channel;
+        state.order_log.info(
+            order.uuid,
+            OrderLogEvent::ChannelOpened,
+            json!({
+                "funding_outpoint" : format!("{}:{}", channel.funding_txid, channel.outnum),
+                "reconciled" : true,
+            }),
+        );
+        check_channel_opening_order(state, rpc, &opening_order).await?;
+    }
+    Ok(())
+}
+
+/// Finds a channel with the client that we funded with the capacity
+/// of `order` and that isn't stored for any order
+async fn find_unrecorded_channel(
+    db: &Database,
+    clock: &dyn Clock,
+    rpc: &mut dyn ClnRpcApi,
+    order: &Lsps1Order,
+) -> Result<Option<Lsps1Channel>> {
+    let capacity_msat = order
+        .client_balance_sat
+        .checked_add(&order.lsp_balance_sat)
+        .and_then(|capacity| capacity.sat_value().checked_mul(1000))
+        .context("Overflow when computing channel capacity")?;
+
+    let request = ListpeerchannelsRequest {
+        id: Some(to_rpc_public_key(&order.client_node_id)?),
+    };
+    let response = rpc
+        .listpeerchannels(&request)
+        .await
+        .context("Failed to call 'listpeerchannels'")?;
+
+    let mut tx = db.begin().await?;
+    for channel in response.channels.unwrap_or_default() {
+        if channel.opener != Some(ChannelSide::LOCAL)
+            || channel.total_msat.map(|amount| amount.msat()) != Some(capacity_msat)
+        {
+            continue;
+        }
+        let (funding_txid, outnum) = match (channel.funding_txid, channel.funding_outnum) {
+            (Some(funding_txid), Some(outnum)) => (TransactionId::from_str(&funding_txid)?, outnum),
+            _ => continue,
+        };
+
+        let recorded_for = GetChannelOrderQuery::by_funding_outpoint(funding_txid.clone(), outnum)
+            .execute(&mut tx)
+            .await?;
+        if recorded_for.is_none() {
+            tx.commit().await?;
+            return Ok(Some(Lsps1Channel {
+                funding_txid,
+                outnum,
+                funded_at: clock.now(),
+            }));
+        }
+    }
+    tx.commit().await?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+    use lsp_primitives::lsps1::schema::PaymentState;
+
+    use serde_json::json;
+
+    use crate::channel_open::funding_output::test_support::{
+        prepared_psbt, CHANGE_ADDRESS, FUNDING_ADDRESS,
+    };
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+    use crate::db::schema::{Lsps1FundingReservation, Lsps1PaymentDetails, OrderGeneration};
+    use crate::db::sqlite::queries::{
+        GetChannelQuery, GetFundingReservationQuery, GetPaymentDetailsQuery, SetFundingSourceQuery,
+        UpdatePaymentStateQuery,
+    };
+    use crate::db::sqlite::test::{create_order_query, get_db, get_temp_db};
+    use crate::lsps1::funding_source::test_support::funding_sources;
+    use crate::state::test_support::test_state;
+
+    /// Inserts a paid order that waits for its channel
+    async fn insert_pending_order(db: &Database) -> Lsps1Order {
+        insert_pending_order_with_refund_address(db, None).await
+    }
+
+    async fn insert_pending_order_with_refund_address(
+        db: &Database,
+        refund_address: Option<&str>,
+    ) -> Lsps1Order {
+        insert_order_paid_at(db, refund_address, IsoDatetime::now()).await
+    }
+
+    async fn insert_order_paid_at(
+        db: &Database,
+        refund_address: Option<&str>,
+        paid_at: IsoDatetime,
+    ) -> Lsps1Order {
+        let mut query = create_order_query();
+        let expires_at = IsoDatetime::now().unix_timestamp() + 3600;
+        query.order.expires_at = IsoDatetime::from_unix_timestamp(expires_at).unwrap();
+        query.order.refund_onchain_address = refund_address.map(|a| a.to_string());
+        let uuid = query.order.uuid;
+        let payment = query.payment.clone();
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            changed_at: paid_at,
+            generation: payment.generation,
+            label: payment.bolt11_invoice_label,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        order
+    }
+
+    fn peer_is_online(rpc: &FakeClnRpc, order: &Lsps1Order) {
+        rpc.respond(
+            "listpeers",
+            json!({ "peers" : [{ "id" : order.client_node_id.to_hex(), "connected" : true }] }),
+        );
+    }
+
+    const REFUND_ADDRESS: &str = "bcrt1qxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzx";
+
+    fn withdraw() -> serde_json::Value {
+        json!({
+            "tx" : "0200000000000000000000",
+            "txid" : "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+        })
+    }
+
+    fn feerates() -> serde_json::Value {
+        json!({
+            "perkw" : {
+                "min_acceptable" : 253,
+                "max_acceptable" : 100_000,
+                "estimates" : [{ "blockcount" : 6, "feerate" : 1_000, "smoothed_feerate" : 1_000 }]
+            }
+        })
+    }
+
+    async fn get_order_and_payment(db: &Database, uuid: Uuid) -> (Lsps1Order, Lsps1PaymentDetails) {
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        (order, payment)
+    }
+
+    const RESERVED_UTXO: &str =
+        "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1";
+
+    fn fundpsbt() -> serde_json::Value {
+        json!({
+            "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+            "feerate_per_kw" : 1_000,
+            "estimated_final_weight" : 652,
+            "reservations" : [{
+                "txid" : RESERVED_UTXO.split(':').next().unwrap(),
+                "vout" : 1,
+                "was_reserved" : false,
+                "reserved" : true,
+                "reserved_to_block" : 175,
+            }],
+        })
+    }
+
+    /// The wallet has a single confirmed output of `amount_sat`
+    fn listfunds(amount_sat: u64) -> serde_json::Value {
+        json!({
+            "outputs" : [{
+                "txid" : RESERVED_UTXO.split(':').next().unwrap(),
+                "output" : 1,
+                "amount_msat" : amount_sat * 1000,
+                "scriptpubkey" : "0014b6de7a9decf14629207cb32832fc9554678619f4",
+                "status" : "confirmed",
+                "reserved" : false,
+            }],
+            "channels" : [],
+        })
+    }
+
+    /// The response to `unreserveinputs` for the input reserved by [`fundpsbt`]
+    fn unreserveinputs(was_reserved: bool) -> serde_json::Value {
+        json!({
+            "reservations" : [{
+                "txid" : RESERVED_UTXO.split(':').next().unwrap(),
+                "vout" : 1,
+                "was_reserved" : was_reserved,
+                "reserved" : false,
+                "reserved_to_block" : null,
+            }],
+        })
+    }
+
+    async fn get_reservation(db: &Database, uuid: Uuid) -> Option<Lsps1FundingReservation> {
+        let mut tx = db.begin().await.unwrap();
+        let reservation = GetFundingReservationQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        reservation
+    }
+
+    /// The channel opened by [`FakeClnRpc::script_fundchannel`] as listed by lightningd
+    fn funded_channel(state: &str) -> serde_json::Value {
+        json!({
+            "channels" : [{
+                "funding_txid" : "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70",
+                "funding_outnum" : 0,
+                "state" : state,
+            }]
+        })
+    }
+
+    #[tokio::test]
+    async fn pending_order_opens_channel() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000))
+            .respond("fundpsbt", fundpsbt())
+            .respond("unreserveinputs", unreserveinputs(true));
+        rpc.script_fundchannel(100_000);
+        rpc.respond(
+            "listpeerchannels",
+            funded_channel("CHANNELD_AWAITING_LOCKIN"),
+        );
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+        assert_eq!(
+            rpc.called_methods(),
+            vec![
+                "listpeers",
+                "feerates",
+                "listfunds",
+                "fundpsbt",
+                "fundchannel_start",
+                "unreserveinputs",
+                "txprepare",
+                "fundchannel_complete",
+                "txsend",
+                "listpeerchannels"
+            ]
+        );
+
+        // The channel is opened as described by the order
+        let fundchannel = rpc.params_of("fundchannel_start").unwrap();
+        assert_eq!(fundchannel["id"], order.client_node_id.to_hex());
+        assert_eq!(fundchannel["amount"], "100000000msat");
+        assert_eq!(fundchannel["announce"], false);
+        assert!(fundchannel.get("push_msat").is_none());
+
+        // The funding transaction pays the feerate that was used to price the order
+        // and spends the reserved inputs
+        let txprepare = rpc.params_of("txprepare").unwrap();
+        assert_eq!(txprepare["feerate"], "1000perkw");
+        assert_eq!(txprepare["utxos"], json!([RESERVED_UTXO]));
+        assert_eq!(rpc.params_of("fundpsbt").unwrap()["feerate"], "1000perkw");
+        assert!(get_reservation(&db, order.uuid).await.is_none());
+
+        let (order, payment) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::ChannelOpening);
+        assert_eq!(payment.state, PaymentState::Paid);
+
+        let mut tx = db.begin().await.unwrap();
+        let channel = GetChannelQuery::by_order_id(order.uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert!(channel.is_some());
+    }
+
+    const OTHER_UTXO: &str = "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70:0";
+
+    /// The wallet holds `cold_sat` in [`RESERVED_UTXO`] and `hot_sat` in [`OTHER_UTXO`]
+    fn listfunds_of_sources(cold_sat: u64, hot_sat: u64) -> serde_json::Value {
+        let output = |outpoint: &str, amount_sat: u64| {
+            let (txid, vout) = outpoint.split_once(':').unwrap();
+            json!({
+                "txid" : txid,
+                "output" : vout.parse::<u32>().unwrap(),
+                "amount_msat" : amount_sat * 1000,
+                "scriptpubkey" : "0014b6de7a9decf14629207cb32832fc9554678619f4",
+                "status" : "confirmed",
+                "reserved" : false,
+            })
+        };
+        json!({
+            "outputs" : [output(OTHER_UTXO, hot_sat), output(RESERVED_UTXO, cold_sat)],
+            "channels" : [],
+        })
+    }
+
+    /// A state whose `cold` source owns [`RESERVED_UTXO`] and `hot` source owns [`OTHER_UTXO`]
+    async fn state_with_cold_order(db: &Database) -> (PluginState, Lsps1Order) {
+        let state = test_state(db.clone()).with_funding_sources(funding_sources(
+            json!({
+                "cold" : { "outpoints" : [RESERVED_UTXO] },
+                "hot" : { "outpoints" : [OTHER_UTXO] },
+            }),
+            Some("hot"),
+        ));
+        let order = insert_pending_order(db).await;
+        let mut tx = db.begin().await.unwrap();
+        SetFundingSourceQuery {
+            order_uuid: order.uuid,
+            funding_source: "cold".to_string(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        (state, order)
+    }
+
+    #[tokio::test]
+    async fn channel_is_funded_by_its_funding_source() {
+        let (db, _) = get_temp_db().await;
+        let (state, order) = state_with_cold_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds_of_sources(200_000, 5_000_000))
+            .respond("listfunds", listfunds_of_sources(200_000, 5_000_000))
+            .respond("utxopsbt", fundpsbt())
+            .respond("unreserveinputs", unreserveinputs(true));
+        rpc.script_fundchannel(100_000);
+
+        open_channel_for_order(&state, &mut rpc, &order)
+            .await
+            .unwrap();
+
+        // lightningd never picks inputs of the wallet
+        assert!(!rpc.called_methods().contains(&"fundpsbt"));
+        let utxopsbt = rpc.params_of("utxopsbt").unwrap();
+        assert_eq!(utxopsbt["utxos"], json!([RESERVED_UTXO]));
+        assert_eq!(utxopsbt["satoshi"], "100000000msat");
+        let txprepare = rpc.params_of("txprepare").unwrap();
+        assert_eq!(txprepare["utxos"], json!([RESERVED_UTXO]));
+    }
+
+    #[tokio::test]
+    async fn funding_source_without_funds_fails() {
+        let (db, _) = get_temp_db().await;
+        let (state, order) = state_with_cold_order(&db).await;
+
+        // The hot source could fund the channel
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds_of_sources(50_000, 5_000_000));
+
+        let err = open_channel_for_order(&state, &mut rpc, &order)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("50000 sat available"), "{:?}", err);
+        assert_eq!(rpc.called_methods(), vec!["feerates", "listfunds"]);
+        assert!(get_reservation(&db, order.uuid).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn zero_conf_channel_completes_order() {
+        // Other tests open a channel with the same funding outpoint
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order(&db).await;
+
+        // The channel is ready before the order is in `ChannelOpening`
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000));
+        rpc.script_fundchannel(100_000);
+        rpc.respond("listpeerchannels", funded_channel("CHANNELD_NORMAL"));
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        let (order, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Completed);
+    }
+
+    #[tokio::test]
+    async fn failed_fundchannel_start_refunds_online_peer() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order_with_refund_address(&db, Some(REFUND_ADDRESS)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000))
+            .respond("fundpsbt", fundpsbt())
+            .fail("fundchannel_start", "Peer rejected the channel")
+            .respond("unreserveinputs", unreserveinputs(true));
+        // The peer is still online after the failure
+        peer_is_online(&rpc, &order);
+        rpc.respond("withdraw", withdraw());
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        // The channel open was never started. There is nothing to cancel
+        // but the reserved inputs are released
+        assert_eq!(
+            rpc.called_methods(),
+            vec![
+                "listpeers",
+                "feerates",
+                "listfunds",
+                "fundpsbt",
+                "fundchannel_start",
+                "unreserveinputs",
+                "listpeers",
+                "withdraw"
+            ]
+        );
+        assert!(get_reservation(&db, order.uuid).await.is_none());
+        assert_eq!(
+            rpc.params_of("withdraw").unwrap()["destination"],
+            REFUND_ADDRESS
+        );
+
+        let (order, payment) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(
+            order.failure_reason,
+            Some(Lsps1FailureReason::ChannelOpenFailed)
+        );
+        assert_eq!(payment.state, PaymentState::Refunded);
+    }
+
+    #[tokio::test]
+    async fn pending_order_fails_after_expiry() {
+        let db = get_db().await;
+        let clock = MockClock::new(IsoDatetime::now());
+
+        // The order expires in one hour
+        let mut query = create_order_query();
+        let expires_at = clock.now().unix_timestamp() + 3600;
+        query.order.expires_at = IsoDatetime::from_unix_timestamp(expires_at).unwrap();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The order hasn't expired yet
+        let grace = Duration::from_secs(120);
+        let expired = fail_expired_order(&mut tx, &clock, grace, &order)
+            .await
+            .unwrap();
+        assert!(!expired);
+
+        // The order expired but can still be opened during the grace period
+        clock.advance(Duration::from_secs(3601));
+        let expired = fail_expired_order(&mut tx, &clock, grace, &order)
+            .await
+            .unwrap();
+        assert!(!expired);
+
+        // Travel past the grace period
+        clock.advance(grace);
+        let expired = fail_expired_order(&mut tx, &clock, grace, &order)
+            .await
+            .unwrap();
+        assert!(expired);
+
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(order.failure_reason, Some(Lsps1FailureReason::OrderExpired));
+    }
+
+    #[tokio::test]
+    async fn expired_order_is_refunded() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let mut order = insert_pending_order_with_refund_address(&db, Some(REFUND_ADDRESS)).await;
+        order.expires_at = IsoDatetime::from_unix_timestamp(0).unwrap();
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("withdraw", withdraw());
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+        assert_eq!(rpc.called_methods(), vec!["withdraw"]);
+
+        let (order, payment) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(order.failure_reason, Some(Lsps1FailureReason::OrderExpired));
+        assert_eq!(payment.state, PaymentState::Refunded);
+    }
+
+    #[tokio::test]
+    async fn expired_order_without_refund_address_keeps_payment() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let mut order = insert_pending_order(&db).await;
+        order.expires_at = IsoDatetime::from_unix_timestamp(0).unwrap();
+
+        let mut rpc = FakeClnRpc::default();
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+        assert!(rpc.called_methods().is_empty());
+
+        // The operator must refund the payment
+        let (order, payment) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(payment.state, PaymentState::Paid);
+    }
+
+    #[tokio::test]
+    async fn stale_order_is_not_opened_twice() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000));
+        rpc.script_fundchannel(100_000);
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        // A second task that read the order while it was `PendingOpen`
+        // can't claim it anymore
+        let mut second_rpc = FakeClnRpc::default();
+        peer_is_online(&second_rpc, &order);
+        let err = open_pending_order(&state, &mut second_rpc, &order)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Failed to claim order"));
+        assert_eq!(second_rpc.called_methods(), vec!["listpeers"]);
+
+        let (order, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::ChannelOpening);
+    }
+
+    #[tokio::test]
+    async fn failed_open_discards_funding_transaction_of_reserved_inputs() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order_with_refund_address(&db, Some(REFUND_ADDRESS)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000))
+            .respond("fundpsbt", fundpsbt())
+            .respond(
+                "fundchannel_start",
+                json!({ "funding_address" : FUNDING_ADDRESS }),
+            )
+            .respond("unreserveinputs", unreserveinputs(true))
+            .respond(
+                "txprepare",
+                json!({
+                    "psbt" : prepared_psbt(&[(FUNDING_ADDRESS, 100_000)]),
+                    "txid" : "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70",
+                    "unsigned_tx" : "0200000000000000000000",
+                }),
+            )
+            .fail("fundchannel_complete", "Peer disconnected")
+            .respond("txdiscard", json!({ "unsigned_tx" : "00", "txid" : "00" }))
+            .respond(
+                "fundchannel_cancel",
+                json!({ "cancelled" : "Channel open canceled" }),
+            );
+        peer_is_online(&rpc, &order);
+        rpc.respond("withdraw", withdraw());
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        // The reserved inputs were handed over to `txprepare`.
+        // `txdiscard` releases them and they aren't released twice
+        assert_eq!(
+            rpc.called_methods(),
+            vec![
+                "listpeers",
+                "feerates",
+                "listfunds",
+                "fundpsbt",
+                "fundchannel_start",
+                "unreserveinputs",
+                "txprepare",
+                "fundchannel_complete",
+                "txdiscard",
+                "fundchannel_cancel",
+                "listpeers",
+                "withdraw"
+            ]
+        );
+        assert_eq!(
+            rpc.params_of("txprepare").unwrap()["utxos"],
+            json!([RESERVED_UTXO])
+        );
+        assert!(get_reservation(&db, order.uuid).await.is_none());
+
+        let (order, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+    }
+
+    #[tokio::test]
+    async fn expired_reservation_falls_back_to_automatic_selection() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order(&db).await;
+
+        // lightningd dropped the reservation. The input might have been spent
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000))
+            .respond("fundpsbt", fundpsbt())
+            .respond("unreserveinputs", unreserveinputs(false));
+        rpc.script_fundchannel(100_000);
+        rpc.respond(
+            "listpeerchannels",
+            funded_channel("CHANNELD_AWAITING_LOCKIN"),
+        );
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        let txprepare = rpc.params_of("txprepare").unwrap();
+        assert!(txprepare.get("utxos").map_or(true, |utxos| utxos.is_null()));
+        let (order, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::ChannelOpening);
+    }
+
+    /// Queues the responses of a channel open whose funding transaction
+    /// has the outputs `outputs`
+    fn script_fundchannel_with_outputs(rpc: &FakeClnRpc, outputs: &[(&str, u64)]) {
+        rpc.respond(
+            "fundchannel_start",
+            json!({ "funding_address" : FUNDING_ADDRESS }),
+        )
+        .respond("unreserveinputs", unreserveinputs(true))
+        .respond(
+            "txprepare",
+            json!({
+                "psbt" : prepared_psbt(outputs),
+                "txid" : "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70",
+                "unsigned_tx" : "0200000000000000000000",
+            }),
+        );
+    }
+
+    #[tokio::test]
+    async fn funding_outnum_is_read_from_the_prepared_transaction() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order(&db).await;
+
+        // lightningd put the change first
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000))
+            .respond("fundpsbt", fundpsbt());
+        script_fundchannel_with_outputs(
+            &rpc,
+            &[(CHANGE_ADDRESS, 100_000), (FUNDING_ADDRESS, 100_000)],
+        );
+        rpc.respond(
+            "fundchannel_complete",
+            json!({ "channel_id" : "00".repeat(32), "commitments_secured" : true }),
+        )
+        .respond(
+            "txsend",
+            json!({
+                "psbt" : prepared_psbt(&[(FUNDING_ADDRESS, 100_000)]),
+                "tx" : "0200000000000000000000",
+                "txid" : "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70",
+            }),
+        )
+        .respond("listpeerchannels", json!({ "channels" : [] }));
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        let channel = GetChannelQuery::by_order_id(order.uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(channel.outnum, 1);
+    }
+
+    #[tokio::test]
+    async fn ambiguous_funding_output_aborts_the_open() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_pending_order_with_refund_address(&db, Some(REFUND_ADDRESS)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        peer_is_online(&rpc, &order);
+        rpc.respond("feerates", feerates())
+            .respond("listfunds", listfunds(1_000_000))
+            .respond("fundpsbt", fundpsbt());
+        script_fundchannel_with_outputs(
+            &rpc,
+            &[(FUNDING_ADDRESS, 100_000), (FUNDING_ADDRESS, 100_000)],
+        );
+        rpc.respond("txdiscard", json!({ "unsigned_tx" : "00", "txid" : "00" }))
+            .respond(
+                "fundchannel_cancel",
+                json!({ "cancelled" : "Channel open canceled" }),
+            );
+        peer_is_online(&rpc, &order);
+        rpc.respond("withdraw", withdraw());
+
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        // The peer never signs a commitment for an output we can't identify
+        let methods = rpc.called_methods();
+        assert!(!methods.contains(&"fundchannel_complete"));
+        assert!(methods.contains(&"txdiscard"));
+        assert!(methods.contains(&"fundchannel_cancel"));
+
+        let mut tx = db.begin().await.unwrap();
+        let channel = GetChannelQuery::by_order_id(order.uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert!(channel.is_none());
+    }
+
+    #[tokio::test]
+    async fn inputs_stay_reserved_while_peer_is_offline() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut order = insert_pending_order_with_refund_address(&db, Some(REFUND_ADDRESS)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeers", json!({ "peers" : [] }))
+            .respond("feerates", feerates())
+            .respond("fundpsbt", fundpsbt());
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        let reservation = get_reservation(&db, order.uuid).await.unwrap();
+        assert_eq!(reservation.utxos[0].to_string(), RESERVED_UTXO);
+        let (stored, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(stored.order_state, Lsps1OrderState::PendingOpen);
+
+        // The reservation is released when the order expires
+        order.expires_at = IsoDatetime::from_unix_timestamp(0).unwrap();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("unreserveinputs", unreserveinputs(true))
+            .respond("withdraw", withdraw());
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        assert_eq!(rpc.called_methods(), vec!["unreserveinputs", "withdraw"]);
+        assert_eq!(
+            rpc.params_of("unreserveinputs").unwrap()["psbt"],
+            reservation.psbt
+        );
+        assert!(get_reservation(&db, order.uuid).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_opens_stay_within_the_funds_of_the_wallet() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+
+        // The order that was created last was paid first
+        let now = IsoDatetime::now().unix_timestamp();
+        let mut orders = Vec::new();
+        for minutes_ago in 0..5 {
+            let paid_at = IsoDatetime::from_unix_timestamp(now - 60 * minutes_ago).unwrap();
+            orders.push(insert_order_paid_at(&db, None, paid_at).await);
+        }
+        orders.reverse();
+
+        // The wallet can fund two channels of 100_000 sat. lightningd
+        // lists the inputs of a channel as reserved once it is funded
+        let rpc = FakeClnRpc::default();
+        for available_sat in [250_000, 150_000, 50_000, 50_000, 50_000] {
+            rpc.respond("listfunds", listfunds(available_sat));
+        }
+        for _ in 0..8 {
+            peer_is_online(&rpc, &orders[0]);
+        }
+        rpc.script_fundchannel(100_000);
+        rpc.script_fundchannel(100_000);
+
+        let queued = enqueue_pending_orders(&db, &state.open_queue, None)
+            .await
+            .unwrap();
+        assert_eq!(queued, 5);
+        assert_eq!(state.open_queue.queued(), 5);
+
+        let (done_sender, mut done_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let worker_state = state.clone();
+        let worker_rpc = rpc.clone();
+        tokio::spawn(state.open_queue.clone().run(2, move |order_uuid| {
+            let state = worker_state.clone();
+            let mut rpc = worker_rpc.clone();
+            let done_sender = done_sender.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            async move {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                let (order, _) = get_order_and_payment(&state.database, order_uuid).await;
+                let result = open_pending_order(&state, &mut rpc, &order).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done_sender.send((order_uuid, max_running.load(Ordering::SeqCst)))?;
+                result
+            }
+        }));
+
+        let mut max_running = 0;
+        for _ in 0..orders.len() {
+            let (_, now_max) = done_receiver.recv().await.unwrap();
+            max_running = max_running.max(now_max);
+        }
+        assert!(
+            max_running <= 2,
+            "{} channels were opened at once",
+            max_running
+        );
+
+        // The orders that were paid first get the funds. The others fail
+        // before a channel open is started
+        for (index, order) in orders.iter().enumerate() {
+            let (order, payment) = get_order_and_payment(&db, order.uuid).await;
+            if index < 2 {
+                assert_eq!(order.order_state, Lsps1OrderState::ChannelOpening);
+            } else {
+                assert_eq!(order.order_state, Lsps1OrderState::Failed);
+                assert_eq!(
+                    order.failure_reason,
+                    Some(Lsps1FailureReason::ChannelOpenFailed)
+                );
+                assert_eq!(payment.state, PaymentState::Paid);
+            }
+        }
+        let fundchannel_starts = rpc
+            .called_methods()
+            .into_iter()
+            .filter(|method| *method == "fundchannel_start")
+            .count();
+        assert_eq!(fundchannel_starts, 2);
+    }
+
+    /// Moves a pending order to `Funding` as if the plugin stopped while opening its channel
+    async fn insert_funding_order(db: &Database) -> Lsps1Order {
+        let order = insert_pending_order(db).await;
+        let mut tx = db.begin().await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: order.uuid,
+            state: Lsps1OrderState::Funding,
+            generation: order.generation,
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        order
+    }
+
+    #[tokio::test]
+    async fn funding_order_records_channel_at_startup() {
+        // Other tests open a channel with the same funding outpoint
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_funding_order(&db).await;
+
+        // Only the channel we funded with the capacity of the order matches
+        let txid = "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70";
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            json!({
+                "channels" : [
+                    { "funding_txid" : txid, "funding_outnum" : 1, "opener" : "remote", "total_msat" : 100_000_000 },
+                    { "funding_txid" : txid, "funding_outnum" : 2, "opener" : "local", "total_msat" : 50_000_000 },
+                    { "funding_txid" : txid, "funding_outnum" : 0, "opener" : "local", "total_msat" : 100_000_000 },
+                ]
+            }),
+        );
+        rpc.respond(
+            "listpeerchannels",
+            funded_channel("CHANNELD_AWAITING_LOCKIN"),
+        );
+
+        reconcile_funding_orders(&state, &mut rpc).await.unwrap();
+
+        let (order, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::ChannelOpening);
+
+        let mut tx = db.begin().await.unwrap();
+        let channel = GetChannelQuery::by_order_id(order.uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(channel.funding_txid.to_string(), txid);
+        assert_eq!(channel.outnum, 0);
+    }
+
+    #[tokio::test]
+    async fn funding_order_without_channel_is_opened_again() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let order = insert_funding_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeerchannels", json!({ "channels" : [] }));
+
+        reconcile_funding_orders(&state, &mut rpc).await.unwrap();
+
+        let (order, _) = get_order_and_payment(&db, order.uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::PendingOpen);
+
+        let queued = enqueue_pending_orders(&db, &state.open_queue, None)
+            .await
+            .unwrap();
+        assert_eq!(queued, 1);
+        assert!(state.open_queue.is_in_flight(&order.uuid));
+    }
+
+    #[tokio::test]
+    async fn pending_orders_are_recovered_at_startup() {
+        let db = get_db().await;
+        let queue = OpenQueue::default();
+
+        let query = create_order_query();
+        let peer_id = query.order.client_node_id;
+        let pending_uuid = query.order.uuid;
+        let created_uuid = create_order_query().order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: pending_uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        // Orders that are already queued are not counted twice
+        enqueue_pending_orders(&db, &queue, Some(peer_id))
+            .await
+            .unwrap();
+        let queued = enqueue_pending_orders(&db, &queue, Some(peer_id))
+            .await
+            .unwrap();
+        assert_eq!(queued, 0);
+
+        assert!(queue.is_in_flight(&pending_uuid));
+        assert!(!queue.is_in_flight(&created_uuid));
+    }
+}
@@ -0,0 +1,262 @@
+//! The `channel_type` extension of `lsps1.create_order`
+//!
+//! A client can request the features of the channel it purchases. The
+//! operator lists the features a client may request in
+//! `lsps1-allowed-channel-types`. A feature is only accepted if the
+//! client's node signals support for it.
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::ListpeersRequest;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps0::features::has_feature_bit;
+use lsp_primitives::lsps1::schema::{ChannelTypeFeature, Lsps1CreateOrderRequest};
+use lsp_primitives::lsps1::util::Lsps1OptionMismatchError;
+
+use crate::cln::public_key::{is_same_public_key, to_rpc_public_key};
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::{options, PluginState};
+
+const PROPERTY: &str = "channel_type";
+
+/// The channel type features a client may request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AllowedChannelTypes {
+    features: Vec<ChannelTypeFeature>,
+}
+
+impl AllowedChannelTypes {
+    /// Parses the value of the `lsps1-allowed-channel-types` option
+    ///
+    /// E.g: `anchors,scid_alias,zeroconf`
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        let mut features = value
+            .split(',')
+            .map(|entry| entry.trim().parse::<ChannelTypeFeature>())
+            .collect::<Result<Vec<_>, _>>()?;
+        features.sort();
+        features.dedup();
+        Ok(Self { features })
+    }
+
+    /// Creates the allowlist from the value of the plugin option
+    ///
+    /// Clients can't request any feature if the option isn't set
+    pub(crate) fn from_option(value: Option<String>) -> Result<Self> {
+        value
+            .map(|value| Self::parse(&value))
+            .transpose()
+            .with_context(|| {
+                format!(
+                    "Invalid value for option '{}'",
+                    options::LSPS1_ALLOWED_CHANNEL_TYPES
+                )
+            })
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Creates the allowlist from the configured plugin options
+    pub(crate) fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        Self::from_option(plugin.option(&options::lsps1_allowed_channel_types())?)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Checks if the server can open a channel of the requested type
+    ///
+    /// `peer_features` are the hex-encoded init features of the client.
+    /// A zero-conf channel can't wait for confirmations and can't be
+    /// announced. Returns the requested features in ascending order.
+    pub(crate) fn validate(
+        &self,
+        order: &Lsps1CreateOrderRequest,
+        requested: &[ChannelTypeFeature],
+        peer_features: Option<&str>,
+    ) -> Result<Vec<ChannelTypeFeature>, Lsps1OptionMismatchError> {
+        let mut features = requested.to_vec();
+        features.sort();
+        features.dedup();
+
+        for feature in &features {
+            if !self.features.contains(feature) {
+                return Err(mismatch(format!(
+                    "The LSP doesn't open {} channels",
+                    feature
+                )));
+            }
+            if !peer_supports(peer_features, *feature) {
+                return Err(mismatch(format!("Your node doesn't support {}", feature)));
+            }
+        }
+
+        if features.contains(&ChannelTypeFeature::Zeroconf) {
+            if order.required_channel_confirmations != 0 {
+                return Err(mismatch(
+                    "A zeroconf channel requires required_channel_confirmations=0".to_string(),
+                ));
+            }
+            if order.announce_channel {
+                return Err(mismatch(
+                    "A zeroconf channel can't be announced".to_string(),
+                ));
+            }
+        }
+
+        Ok(features)
+    }
+}
+
+fn mismatch(message: String) -> Lsps1OptionMismatchError {
+    Lsps1OptionMismatchError::new(PROPERTY.to_string(), message)
+}
+
+/// A node supports a feature if it sets the even or the odd bit
+fn peer_supports(peer_features: Option<&str>, feature: ChannelTypeFeature) -> bool {
+    let peer_features = match peer_features {
+        Some(peer_features) => peer_features,
+        None => return false,
+    };
+    let bit = feature.feature_bit();
+    [bit, bit + 1]
+        .into_iter()
+        .any(|bit| has_feature_bit(peer_features, bit).unwrap_or(false))
+}
+
+/// Returns the features `peer_id` sent when it connected
+///
+/// Returns `None` if the peer isn't connected
+pub(crate) async fn lookup_peer_features(
+    rpc: &mut dyn ClnRpcApi,
+    peer_id: &PublicKey,
+) -> Result<Option<String>> {
+    let request = ListpeersRequest {
+        id: Some(to_rpc_public_key(peer_id).context("Invalid peer_id")?),
+        level: None,
+    };
+    let response = rpc
+        .listpeers(&request)
+        .await
+        .context("Failed to call 'listpeers'")?;
+
+    Ok(response
+        .peers
+        .into_iter()
+        .find(|p| p.connected && is_same_public_key(peer_id, &p.id))
+        .and_then(|p| p.features))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use lsp_primitives::lsps0::features::set_feature_bit;
+
+    use ChannelTypeFeature::{Anchors, ScidAlias, Zeroconf};
+
+    fn order() -> Lsps1CreateOrderRequest {
+        serde_json::from_value(json!({
+            "lsp_balance_sat" : "100000",
+            "client_balance_sat" : "0",
+            "funding_confirms_within_blocks" : 6,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks" : 1000,
+            "token" : null,
+            "refund_onchain_address" : null,
+            "announce_channel" : false,
+        }))
+        .unwrap()
+    }
+
+    /// The features of a node that sets `bits`
+    fn peer_features(bits: &[usize]) -> String {
+        bits.iter()
+            .try_fold(String::new(), |features, bit| {
+                set_feature_bit(&features, *bit)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_allowed_channel_types() {
+        let allowed = AllowedChannelTypes::parse("zeroconf, anchors,zeroconf").unwrap();
+        assert_eq!(allowed.features, vec![Anchors, Zeroconf]);
+
+        assert!(AllowedChannelTypes::parse("anchors,static_remotekey").is_err());
+        assert!(AllowedChannelTypes::parse("").is_err());
+        assert!(AllowedChannelTypes::from_option(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn accept_allowed_features_the_peer_supports() {
+        let allowed = AllowedChannelTypes::parse("anchors,scid_alias,zeroconf").unwrap();
+        // The peer supports anchors (odd), scid_alias (even) and zeroconf (odd)
+        let features = peer_features(&[23, 46, 51]);
+
+        let accepted = allowed
+            .validate(&order(), &[Zeroconf, Anchors, Zeroconf], Some(&features))
+            .unwrap();
+        assert_eq!(accepted, vec![Anchors, Zeroconf]);
+
+        let accepted = allowed.validate(&order(), &[], None).unwrap();
+        assert!(accepted.is_empty());
+    }
+
+    #[test]
+    fn reject_features_the_server_does_not_allow() {
+        let allowed = AllowedChannelTypes::parse("anchors").unwrap();
+        let features = peer_features(&[22, 46]);
+
+        let err = allowed
+            .validate(&order(), &[Anchors, ScidAlias], Some(&features))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The LSP doesn't open scid_alias channels (channel_type)"
+        );
+
+        let err = AllowedChannelTypes::default()
+            .validate(&order(), &[Anchors], Some(&features))
+            .unwrap_err();
+        assert!(err.to_string().ends_with("(channel_type)"));
+    }
+
+    #[test]
+    fn reject_features_the_peer_does_not_support() {
+        let allowed = AllowedChannelTypes::parse("anchors,zeroconf").unwrap();
+
+        let err = allowed
+            .validate(&order(), &[Zeroconf], Some(&peer_features(&[22])))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Your node doesn't support zeroconf (channel_type)"
+        );
+
+        // The features of a peer that isn't connected are unknown
+        assert!(allowed.validate(&order(), &[Anchors], None).is_err());
+    }
+
+    #[test]
+    fn zeroconf_channels_are_unconfirmed_and_unannounced() {
+        let allowed = AllowedChannelTypes::parse("zeroconf").unwrap();
+        let features = peer_features(&[50]);
+
+        let mut order = order();
+        order.required_channel_confirmations = 1;
+        let err = allowed
+            .validate(&order, &[Zeroconf], Some(&features))
+            .unwrap_err();
+        assert!(err.to_string().contains("required_channel_confirmations"));
+
+        order.required_channel_confirmations = 0;
+        order.announce_channel = true;
+        let err = allowed
+            .validate(&order, &[Zeroconf], Some(&features))
+            .unwrap_err();
+        assert!(err.to_string().contains("announced"));
+    }
+}
@@ -0,0 +1,328 @@
+//! Tells a client whether it can use the channel of its order
+//!
+//! A zero-conf channel can forward payments as soon as it is opened. The
+//! client routes through it using the scid alias until the funding
+//! transaction confirms. The order only becomes `COMPLETED` later, so
+//! `lsps1.get_order` reports the alias and the state of the channel.
+//!
+//! Clients poll `lsps1.get_order`. The channels of a peer are read using
+//! `listpeerchannels` at most once per [`CHANNEL_USABILITY_TTL`].
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::ListpeerchannelsRequest;
+use cln_rpc::model::responses::ListpeerchannelsChannels;
+
+use lsp_primitives::lsps0::common_schemas::{Outpoint, PublicKey, ShortChannelId};
+use lsp_primitives::lsps1::schema::Channel;
+
+use crate::cln::public_key::to_rpc_public_key;
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::Clock;
+
+/// The channels of a peer are read again after this long
+pub(crate) const CHANNEL_USABILITY_TTL: Duration = Duration::from_secs(5);
+
+/// The channel states in which payments can be forwarded
+const USABLE_STATES: [&str; 2] = ["CHANNELD_NORMAL", "CHANNELD_AWAITING_SPLICE"];
+
+/// What a client needs to know to start using a channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChannelUsability {
+    /// The alias lightningd assigned to the channel
+    pub(crate) scid_alias: Option<ShortChannelId>,
+    pub(crate) usable: bool,
+}
+
+impl ChannelUsability {
+    fn from_channel(channel: &ListpeerchannelsChannels) -> Result<Self> {
+        let scid_alias = channel
+            .alias
+            .as_ref()
+            .and_then(|alias| alias.local.as_ref())
+            .map(|scid| ShortChannelId::from_str(&scid.to_string()))
+            .transpose()
+            .context("Invalid scid alias")?;
+        let state = match &channel.state {
+            Some(state) => serde_json::to_value(state)?
+                .as_str()
+                .context("Channel state should be a string")?
+                .to_string(),
+            None => String::new(),
+        };
+
+        Ok(Self {
+            scid_alias,
+            usable: USABLE_STATES.contains(&state.as_str()),
+        })
+    }
+
+    /// Adds the extension fields to the `channel` of an order
+    pub(crate) fn apply_to(&self, channel: &mut Channel) {
+        channel.scid_alias = self.scid_alias;
+        channel.usable = Some(self.usable);
+    }
+}
+
+/// The channels of a peer as read by `listpeerchannels`
+struct PeerChannels {
+    channels: Vec<(Outpoint, ChannelUsability)>,
+    fetched_at: Instant,
+}
+
+/// Caches the channels of every peer that polls its order
+#[derive(Default)]
+pub(crate) struct ChannelUsabilityCache {
+    peers: Mutex<HashMap<PublicKey, Arc<PeerChannels>>>,
+}
+
+impl ChannelUsabilityCache {
+    /// Describes the channel with `funding_outpoint` to `peer_id`
+    ///
+    /// Returns `None` if lightningd doesn't know the channel
+    pub(crate) async fn get(
+        &self,
+        rpc: &mut dyn ClnRpcApi,
+        clock: &dyn Clock,
+        peer_id: &PublicKey,
+        funding_outpoint: &Outpoint,
+    ) -> Result<Option<ChannelUsability>> {
+        let peer_channels = match self.fresh(peer_id, clock.instant()) {
+            Some(peer_channels) => peer_channels,
+            None => self.refresh(rpc, clock, peer_id).await?,
+        };
+
+        Ok(peer_channels
+            .channels
+            .iter()
+            .find(|(outpoint, _)| outpoint == funding_outpoint)
+            .map(|(_, usability)| usability.clone()))
+    }
+
+    fn fresh(&self, peer_id: &PublicKey, now: Instant) -> Option<Arc<PeerChannels>> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .filter(|cached| {
+                now.saturating_duration_since(cached.fetched_at) <= CHANNEL_USABILITY_TTL
+            })
+            .cloned()
+    }
+
+    async fn refresh(
+        &self,
+        rpc: &mut dyn ClnRpcApi,
+        clock: &dyn Clock,
+        peer_id: &PublicKey,
+    ) -> Result<Arc<PeerChannels>> {
+        let request = ListpeerchannelsRequest {
+            id: Some(to_rpc_public_key(peer_id)?),
+        };
+        let response = rpc
+            .listpeerchannels(&request)
+            .await
+            .context("Failed to call 'listpeerchannels'")?;
+
+        let mut channels = Vec::new();
+        for channel in response.channels.unwrap_or_default() {
+            let outpoint = match (&channel.funding_txid, channel.funding_outnum) {
+                (Some(txid), Some(outnum)) => Outpoint {
+                    txid: txid.parse()?,
+                    outnum,
+                },
+                _ => continue,
+            };
+            channels.push((outpoint, ChannelUsability::from_channel(&channel)?));
+        }
+
+        let now = clock.instant();
+        let peer_channels = Arc::new(PeerChannels {
+            channels,
+            fetched_at: now,
+        });
+        let mut peers = self.peers.lock().unwrap();
+        // Peers that stopped polling are dropped
+        peers.retain(|_, cached| {
+            now.saturating_duration_since(cached.fetched_at) <= CHANNEL_USABILITY_TTL
+        });
+        peers.insert(*peer_id, peer_channels.clone());
+        Ok(peer_channels)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use serde_json::json;
+
+    /// A `listpeerchannels` response with a single channel
+    pub(crate) fn listpeerchannels(
+        funding_txid: &str,
+        outnum: u32,
+        state: &str,
+        scid_alias: Option<&str>,
+    ) -> serde_json::Value {
+        let mut channel = json!({
+            "funding_txid" : funding_txid,
+            "funding_outnum" : outnum,
+            "state" : state,
+        });
+        if let Some(scid_alias) = scid_alias {
+            channel["alias"] = json!({ "local" : scid_alias, "remote" : "7x8x9" });
+        }
+        json!({ "channels" : [channel] })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::listpeerchannels;
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+
+    const FUNDING_TXID: &str = "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae";
+    const PEER_ID: &str = "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170";
+
+    fn peer_id() -> PublicKey {
+        PublicKey::from_hex(PEER_ID).unwrap()
+    }
+
+    fn outpoint(outnum: u32) -> Outpoint {
+        Outpoint {
+            txid: FUNDING_TXID.parse().unwrap(),
+            outnum,
+        }
+    }
+
+    fn clock() -> MockClock {
+        MockClock::new(IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap())
+    }
+
+    #[tokio::test]
+    async fn zero_conf_channel_is_usable_with_its_alias() {
+        let clock = clock();
+        let cache = ChannelUsabilityCache::default();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 1, "CHANNELD_NORMAL", Some("8573349x2947x1")),
+        );
+
+        let usability = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            usability.scid_alias.map(|scid| scid.to_string()).as_deref(),
+            Some("8573349x2947x1")
+        );
+        assert!(usability.usable);
+        assert_eq!(
+            rpc.params_of("listpeerchannels").unwrap(),
+            serde_json::json!({ "id" : PEER_ID })
+        );
+
+        // Another output of the same transaction is a different channel
+        let other = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .unwrap();
+        assert!(other.is_none());
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_channel_is_not_usable() {
+        let clock = clock();
+        let cache = ChannelUsabilityCache::default();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 0, "CHANNELD_AWAITING_LOCKIN", None),
+        );
+
+        let usability = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .unwrap();
+        assert_eq!(
+            usability,
+            Some(ChannelUsability {
+                scid_alias: None,
+                usable: false,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn channels_are_cached_briefly() {
+        let clock = clock();
+        let cache = ChannelUsabilityCache::default();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 0, "CHANNELD_AWAITING_LOCKIN", None),
+        )
+        .respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 0, "CHANNELD_NORMAL", Some("8573349x2947x1")),
+        );
+
+        let first = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!first.usable);
+
+        clock.advance(CHANNEL_USABILITY_TTL);
+        let cached = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!cached.usable);
+        assert_eq!(rpc.called_methods(), vec!["listpeerchannels"]);
+
+        clock.advance(Duration::from_secs(1));
+        let fetched = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(fetched.usable);
+        assert_eq!(
+            rpc.called_methods(),
+            vec!["listpeerchannels", "listpeerchannels"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_lookup_is_not_cached() {
+        let clock = clock();
+        let cache = ChannelUsabilityCache::default();
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("listpeerchannels", "Connection refused").respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 0, "CHANNELD_NORMAL", None),
+        );
+
+        assert!(cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .is_err());
+        let usability = cache
+            .get(&mut rpc, &clock, &peer_id(), &outpoint(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(usability.usable);
+    }
+}
@@ -0,0 +1,504 @@
+//! Samples the usage of leased channels
+//!
+//! An LSP can use the usage of a channel to decide whether the lease
+//! should be renewed at a discount or the channel should be closed once
+//! the lease ends. Sampling is disabled unless `lsps1-usage-sampling-minutes`
+//! is set.
+//!
+//! A sample stores the balances of the channel and the payments that were
+//! forwarded through it since the previous sample.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::{ListforwardsRequest, ListforwardsStatus, ListpeerchannelsRequest};
+use cln_rpc::model::responses::{ListforwardsForwards, ListpeerchannelsChannels};
+use cln_rpc::primitives::ShortChannelId;
+use serde::Serialize;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, MsatAmount};
+
+use crate::admin_timestamp::{self, AdminTimestamp};
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::Lsps1ChannelUsageSample;
+use crate::db::sqlite::queries::{
+    ActiveLease, CreateChannelUsageSampleQuery, GetActiveLeasesQuery, GetChannelUsageQuery,
+    GetLastChannelUsageAtQuery, GetOrderQuery,
+};
+use crate::plugin_rpc::Lsps1AdminChannelUsageRequest;
+use crate::state::PluginState;
+
+/// Aggregates the usage samples of a channel
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub(crate) struct ChannelUsageSummary {
+    pub(crate) sample_count: usize,
+    #[serde(serialize_with = "admin_timestamp::serialize_option")]
+    pub(crate) first_sampled_at: Option<IsoDatetime>,
+    #[serde(serialize_with = "admin_timestamp::serialize_option")]
+    pub(crate) last_sampled_at: Option<IsoDatetime>,
+    /// The average share of the channel balance on the client's side
+    ///
+    /// A channel that is used to receive payments fills up on the
+    /// client's side. `None` if no sample has a balance.
+    pub(crate) utilization_percent: Option<u64>,
+    pub(crate) forwarded_in_count: u64,
+    pub(crate) forwarded_in_msat: u64,
+    pub(crate) forwarded_out_count: u64,
+    pub(crate) forwarded_out_msat: u64,
+    pub(crate) max_htlc_count: u32,
+}
+
+pub(crate) fn summarize_channel_usage(
+    samples: &[Lsps1ChannelUsageSample],
+) -> Result<ChannelUsageSummary> {
+    let mut utilization_sum: u128 = 0;
+    let mut utilization_count: u128 = 0;
+    let mut forwarded_in_count: u64 = 0;
+    let mut forwarded_in_msat: u64 = 0;
+    let mut forwarded_out_count: u64 = 0;
+    let mut forwarded_out_msat: u64 = 0;
+
+    for sample in samples {
+        let client_msat = u128::from(sample.client_balance_msat.msat_value());
+        let total_msat = client_msat + u128::from(sample.lsp_balance_msat.msat_value());
+        if total_msat > 0 {
+            utilization_sum += client_msat * 100 / total_msat;
+            utilization_count += 1;
+        }
+
+        forwarded_in_count = forwarded_in_count
+            .checked_add(sample.forwarded_in_count)
+            .context("Forwarded count overflows")?;
+        forwarded_in_msat = forwarded_in_msat
+            .checked_add(sample.forwarded_in_msat.msat_value())
+            .context("Forwarded amount overflows")?;
+        forwarded_out_count = forwarded_out_count
+            .checked_add(sample.forwarded_out_count)
+            .context("Forwarded count overflows")?;
+        forwarded_out_msat = forwarded_out_msat
+            .checked_add(sample.forwarded_out_msat.msat_value())
+            .context("Forwarded amount overflows")?;
+    }
+
+    let utilization_percent = utilization_sum
+        .checked_div(utilization_count)
+        .map(u64::try_from)
+        .transpose()?;
+
+    Ok(ChannelUsageSummary {
+        sample_count: samples.len(),
+        first_sampled_at: samples.iter().map(|s| s.sampled_at).reduce(min_datetime),
+        last_sampled_at: samples.iter().map(|s| s.sampled_at).reduce(max_datetime),
+        utilization_percent,
+        forwarded_in_count,
+        forwarded_in_msat,
+        forwarded_out_count,
+        forwarded_out_msat,
+        max_htlc_count: samples.iter().map(|s| s.htlc_count).max().unwrap_or(0),
+    })
+}
+
+fn min_datetime(a: IsoDatetime, b: IsoDatetime) -> IsoDatetime {
+    if b < a {
+        b
+    } else {
+        a
+    }
+}
+
+fn max_datetime(a: IsoDatetime, b: IsoDatetime) -> IsoDatetime {
+    if b > a {
+        b
+    } else {
+        a
+    }
+}
+
+/// Finds the channel of every lease
+///
+/// A channel is identified by its funding outpoint. Leases whose channel
+/// is no longer known to lightningd are skipped.
+pub(crate) fn find_lease_channels<'a>(
+    leases: &[ActiveLease],
+    channels: &'a [ListpeerchannelsChannels],
+) -> HashMap<Uuid, &'a ListpeerchannelsChannels> {
+    leases
+        .iter()
+        .filter_map(|lease| {
+            let funding_txid = lease.channel.funding_txid.to_string();
+            let channel = channels.iter().find(|c| {
+                c.funding_txid.as_deref() == Some(funding_txid.as_str())
+                    && c.funding_outnum == Some(lease.channel.outnum)
+            })?;
+            Some((lease.order_uuid, channel))
+        })
+        .collect()
+}
+
+/// Samples the usage of all active leases
+///
+/// A lease that was sampled less than `interval` ago is skipped. This
+/// keeps restarts from sampling the same channel over and over.
+/// Returns the number of samples that were stored.
+pub(crate) async fn sample_channel_usage(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    blockheight: u32,
+    interval: Duration,
+) -> Result<usize> {
+    let now = state.clock.now();
+    let mut tx = state.database.begin().await?;
+    let leases = GetActiveLeasesQuery::at_blockheight(blockheight)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_active_leases'-query on database")?;
+
+    let mut due = Vec::with_capacity(leases.len());
+    for lease in leases {
+        let last_sampled_at = GetLastChannelUsageAtQuery {
+            order_uuid: lease.order_uuid,
+        }
+        .execute(&mut tx)
+        .await?;
+        let is_due = last_sampled_at
+            .map(|at| now.unix_timestamp() - at.unix_timestamp() >= interval.as_secs() as i64)
+            .unwrap_or(true);
+        if is_due {
+            due.push((lease, last_sampled_at));
+        }
+    }
+    tx.commit().await?;
+
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let response = rpc
+        .listpeerchannels(&ListpeerchannelsRequest { id: None })
+        .await
+        .context("Failed to call 'listpeerchannels'")?;
+    let channels = response.channels.unwrap_or_default();
+    let leases: Vec<ActiveLease> = due.iter().map(|(lease, _)| lease.clone()).collect();
+    let lease_channels = find_lease_channels(&leases, &channels);
+
+    let mut samples = Vec::with_capacity(due.len());
+    for (lease, last_sampled_at) in due {
+        let Some(channel) = lease_channels.get(&lease.order_uuid) else {
+            log::debug!(
+                "Channel of order {} is not known to lightningd. Not sampling it",
+                lease.order_uuid
+            );
+            continue;
+        };
+
+        let since = last_sampled_at.unwrap_or(lease.channel.funded_at);
+        match sample_channel(rpc, lease.order_uuid, channel, &since, &now).await {
+            Ok(sample) => samples.push(sample),
+            Err(err) => log::warn!(
+                "Failed to sample the channel of order {}: {:?}",
+                lease.order_uuid,
+                err
+            ),
+        }
+    }
+
+    let mut tx = state.database.begin().await?;
+    for sample in &samples {
+        CreateChannelUsageSampleQuery::new(sample)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(samples.len())
+}
+
+async fn sample_channel(
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+    channel: &ListpeerchannelsChannels,
+    since: &IsoDatetime,
+    now: &IsoDatetime,
+) -> Result<Lsps1ChannelUsageSample> {
+    let total_msat = channel.total_msat.map(|a| a.msat()).unwrap_or(0);
+    let lsp_balance_msat = channel.to_us_msat.map(|a| a.msat()).unwrap_or(0);
+    let client_balance_msat = total_msat.saturating_sub(lsp_balance_msat);
+    let htlc_count = channel.htlcs.as_ref().map(|h| h.len()).unwrap_or(0);
+
+    // An unconfirmed channel can't have forwarded payments
+    let (forwarded_in, forwarded_out) = match channel.short_channel_id {
+        Some(scid) => {
+            let forwarded_in = list_settled_forwards(rpc, Some(scid), None).await?;
+            let forwarded_out = list_settled_forwards(rpc, None, Some(scid)).await?;
+            (
+                sum_forwards(&forwarded_in, since, now, |f| Some(f.in_msat.msat())),
+                sum_forwards(&forwarded_out, since, now, |f| f.out_msat.map(|a| a.msat())),
+            )
+        }
+        None => ((0, 0), (0, 0)),
+    };
+
+    Ok(Lsps1ChannelUsageSample {
+        order_uuid,
+        sampled_at: *now,
+        lsp_balance_msat: MsatAmount::new(lsp_balance_msat),
+        client_balance_msat: MsatAmount::new(client_balance_msat),
+        forwarded_in_count: forwarded_in.0,
+        forwarded_in_msat: MsatAmount::new(forwarded_in.1),
+        forwarded_out_count: forwarded_out.0,
+        forwarded_out_msat: MsatAmount::new(forwarded_out.1),
+        htlc_count: u32::try_from(htlc_count)?,
+    })
+}
+
+async fn list_settled_forwards(
+    rpc: &mut dyn ClnRpcApi,
+    in_channel: Option<ShortChannelId>,
+    out_channel: Option<ShortChannelId>,
+) -> Result<Vec<ListforwardsForwards>> {
+    let request = ListforwardsRequest {
+        status: Some(ListforwardsStatus::SETTLED),
+        in_channel,
+        out_channel,
+        index: None,
+        start: None,
+        limit: None,
+    };
+    let response = rpc
+        .listforwards(&request)
+        .await
+        .context("Failed to call 'listforwards'")?;
+    Ok(response.forwards)
+}
+
+/// Counts the forwards that were resolved after `since` and up to `now`
+///
+/// Returns the number of forwards and their total amount in msat
+pub(crate) fn sum_forwards(
+    forwards: &[ListforwardsForwards],
+    since: &IsoDatetime,
+    now: &IsoDatetime,
+    amount_msat: impl Fn(&ListforwardsForwards) -> Option<u64>,
+) -> (u64, u64) {
+    let since = since.unix_timestamp() as f64;
+    let now = now.unix_timestamp() as f64;
+    forwards
+        .iter()
+        .filter(|f| {
+            let resolved_time = f.resolved_time.unwrap_or(f.received_time);
+            resolved_time > since && resolved_time <= now
+        })
+        .filter_map(|f| amount_msat(f))
+        .fold((0, 0), |(count, total), msat| {
+            (count + 1, total.saturating_add(msat))
+        })
+}
+
+/// Handles `lsps1-admin-channel-usage`
+pub(crate) async fn lsps1_admin_channel_usage(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminChannelUsageRequest = serde_json::from_value(request)?;
+    let order_uuid = Uuid::from_str(&request.order_id).context("Invalid order_id")?;
+
+    let mut tx = plugin.state().database.begin().await?;
+    GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order'-query on database")?
+        .with_context(|| format!("Unknown order {}", order_uuid))?;
+    let samples = GetChannelUsageQuery::by_order_id(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_channel_usage'-query on database")?;
+    tx.commit().await?;
+
+    let summary = summarize_channel_usage(&samples)?;
+    let samples: Vec<_> = samples
+        .into_iter()
+        .map(|sample| {
+            serde_json::json!({
+                "sampled_at" : AdminTimestamp(sample.sampled_at),
+                "lsp_balance_msat" : sample.lsp_balance_msat.msat_value(),
+                "client_balance_msat" : sample.client_balance_msat.msat_value(),
+                "forwarded_in_count" : sample.forwarded_in_count,
+                "forwarded_in_msat" : sample.forwarded_in_msat.msat_value(),
+                "forwarded_out_count" : sample.forwarded_out_count,
+                "forwarded_out_msat" : sample.forwarded_out_msat.msat_value(),
+                "htlc_count" : sample.htlc_count,
+            })
+        })
+        .collect();
+
+    let usage = serde_json::json!({
+        "order_id" : order_uuid,
+        "summary" : summary,
+        "samples" : samples,
+    });
+    Ok(admin_timestamp::render(usage, request.epoch_only))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use lsp_primitives::lsps0::common_schemas::{SatAmount, TransactionId};
+
+    use crate::db::schema::Lsps1Channel;
+    use crate::db::sqlite::test::create_test_order;
+
+    const FUNDING_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn at(timestamp: i64) -> IsoDatetime {
+        IsoDatetime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    fn sample(
+        sampled_at: i64,
+        lsp_balance_msat: u64,
+        client_balance_msat: u64,
+        forwarded_out_msat: u64,
+    ) -> Lsps1ChannelUsageSample {
+        Lsps1ChannelUsageSample {
+            order_uuid: Uuid::new_v4(),
+            sampled_at: at(sampled_at),
+            lsp_balance_msat: MsatAmount::new(lsp_balance_msat),
+            client_balance_msat: MsatAmount::new(client_balance_msat),
+            forwarded_in_count: 1,
+            forwarded_in_msat: MsatAmount::new(1_000),
+            forwarded_out_count: 2,
+            forwarded_out_msat: MsatAmount::new(forwarded_out_msat),
+            htlc_count: (sampled_at % 10) as u32,
+        }
+    }
+
+    fn lease(outnum: u32) -> ActiveLease {
+        let order = create_test_order();
+        ActiveLease {
+            order_uuid: Uuid::new_v4(),
+            client_node_id: order.client_node_id,
+            lsp_balance_sat: SatAmount::new(1_000_000),
+            client_balance_sat: SatAmount::new(0),
+            channel_expiry_blocks: 4_320,
+            channel: Lsps1Channel {
+                funding_txid: TransactionId::from_str(FUNDING_TXID).unwrap(),
+                outnum,
+                funded_at: IsoDatetime::now(),
+            },
+            funding_blockheight: Some(100),
+            funding_source: None,
+        }
+    }
+
+    fn peer_channel(outnum: u32, short_channel_id: &str) -> ListpeerchannelsChannels {
+        serde_json::from_value(json!({
+            "funding_txid" : FUNDING_TXID,
+            "funding_outnum" : outnum,
+            "short_channel_id" : short_channel_id,
+            "state" : "CHANNELD_NORMAL",
+        }))
+        .unwrap()
+    }
+
+    fn forward(resolved_time: Option<f64>, in_msat: u64, out_msat: u64) -> ListforwardsForwards {
+        serde_json::from_value(json!({
+            "in_channel" : "100x1x0",
+            "in_msat" : in_msat,
+            "out_channel" : "100x2x1",
+            "out_msat" : out_msat,
+            "fee_msat" : in_msat - out_msat,
+            "status" : "settled",
+            "received_time" : 1_700_000_000.5,
+            "resolved_time" : resolved_time,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn summarize_samples() {
+        let samples = vec![
+            // 10% on the client's side
+            sample(1_700_000_003, 900_000_000, 100_000_000, 50_000_000),
+            // 50% on the client's side
+            sample(1_700_003_607, 500_000_000, 500_000_000, 400_000_000),
+            // Without balance the sample doesn't count for the utilization
+            sample(1_700_007_201, 0, 0, 0),
+        ];
+
+        let summary = summarize_channel_usage(&samples).unwrap();
+        assert_eq!(
+            summary,
+            ChannelUsageSummary {
+                sample_count: 3,
+                first_sampled_at: Some(at(1_700_000_003)),
+                last_sampled_at: Some(at(1_700_007_201)),
+                utilization_percent: Some(30),
+                forwarded_in_count: 3,
+                forwarded_in_msat: 3_000,
+                forwarded_out_count: 6,
+                forwarded_out_msat: 450_000_000,
+                max_htlc_count: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn summarize_without_samples() {
+        let summary = summarize_channel_usage(&[]).unwrap();
+        assert_eq!(summary.sample_count, 0);
+        assert_eq!(summary.first_sampled_at, None);
+        assert_eq!(summary.utilization_percent, None);
+        assert_eq!(summary.forwarded_out_msat, 0);
+        assert_eq!(summary.max_htlc_count, 0);
+    }
+
+    #[test]
+    fn leases_are_mapped_by_funding_outpoint() {
+        let leases = vec![lease(0), lease(1), lease(2)];
+        let channels = vec![peer_channel(1, "100x1x1"), peer_channel(0, "100x1x0")];
+
+        let lease_channels = find_lease_channels(&leases, &channels);
+        assert_eq!(lease_channels.len(), 2);
+
+        let scid = |order_uuid: &Uuid| {
+            lease_channels[order_uuid]
+                .short_channel_id
+                .map(|scid| scid.to_string())
+        };
+        assert_eq!(scid(&leases[0].order_uuid), Some("100x1x0".to_string()));
+        assert_eq!(scid(&leases[1].order_uuid), Some("100x1x1".to_string()));
+
+        // The channel of the last lease has disappeared
+        assert!(!lease_channels.contains_key(&leases[2].order_uuid));
+    }
+
+    #[test]
+    fn only_forwards_since_last_sample_are_counted() {
+        let forwards = vec![
+            // Counted by the previous sample
+            forward(Some(1_700_000_000.0), 2_000, 1_000),
+            forward(Some(1_700_000_100.5), 20_000, 10_000),
+            forward(Some(1_700_000_200.0), 200_000, 100_000),
+            // Counted by the next sample
+            forward(Some(1_700_000_300.5), 2_000_000, 1_000_000),
+            // Falls back to the `received_time`
+            forward(None, 20_000_000, 10_000_000),
+        ];
+
+        let (count, msat) = sum_forwards(&forwards, &at(1_700_000_000), &at(1_700_000_300), |f| {
+            f.out_msat.map(|a| a.msat())
+        });
+        assert_eq!((count, msat), (3, 10_110_000));
+
+        let (count, msat) = sum_forwards(&forwards, &at(1_700_000_000), &at(1_700_000_300), |f| {
+            Some(f.in_msat.msat())
+        });
+        assert_eq!((count, msat), (3, 20_220_000));
+    }
+}
@@ -0,0 +1,184 @@
+//! A snapshot of the client's node at the time an order was created
+//!
+//! Operators use the snapshot to recognize a client after it changed
+//! its alias or stopped announcing its addresses. The lookup is best
+//! effort. A node without public channels has no alias and an order is
+//! never rejected because the lookup failed.
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::{ListnodesRequest, ListpeersRequest};
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+use crate::cln::public_key::{is_same_public_key, to_rpc_public_key};
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::Lsps1ClientNode;
+
+/// The lookup must not delay the response to `lsps1.create_order`
+pub(crate) const CLIENT_NODE_LOOKUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Collects what our node knows about `peer_id`
+///
+/// Returns an empty snapshot if `rpc` is `None`, the lookup fails
+/// or takes longer than [`CLIENT_NODE_LOOKUP_TIMEOUT`].
+pub(crate) async fn lookup_client_node(
+    rpc: Option<&mut dyn ClnRpcApi>,
+    peer_id: &PublicKey,
+) -> Lsps1ClientNode {
+    let rpc = match rpc {
+        Some(rpc) => rpc,
+        None => return Lsps1ClientNode::default(),
+    };
+
+    let lookup = async {
+        let mut client_node = Lsps1ClientNode::default();
+        match lookup_gossip(rpc, peer_id).await {
+            Ok(Some((alias, addresses))) => {
+                client_node.alias = alias;
+                client_node.addresses = addresses;
+            }
+            Ok(None) => log::debug!("No node_announcement of {} is known", peer_id),
+            Err(err) => log::debug!("Failed to look up node {}: {:?}", peer_id, err),
+        }
+        match lookup_remote_address(rpc, peer_id).await {
+            Ok(remote_address) => client_node.remote_address = remote_address,
+            Err(err) => log::debug!("Failed to look up peer {}: {:?}", peer_id, err),
+        }
+        client_node
+    };
+
+    match tokio::time::timeout(CLIENT_NODE_LOOKUP_TIMEOUT, lookup).await {
+        Ok(client_node) => client_node,
+        Err(_) => {
+            log::debug!("Looking up node {} timed out", peer_id);
+            Lsps1ClientNode::default()
+        }
+    }
+}
+
+/// Returns the alias and addresses in the node_announcement of `peer_id`
+async fn lookup_gossip(
+    rpc: &mut dyn ClnRpcApi,
+    peer_id: &PublicKey,
+) -> Result<Option<(Option<String>, Vec<String>)>> {
+    let request = ListnodesRequest {
+        id: Some(to_rpc_public_key(peer_id).context("Invalid peer_id")?),
+    };
+    let response = rpc
+        .listnodes(&request)
+        .await
+        .context("Failed to call 'listnodes'")?;
+
+    let node = match response
+        .nodes
+        .into_iter()
+        .find(|n| is_same_public_key(peer_id, &n.nodeid))
+    {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+
+    let addresses = node
+        .addresses
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|a| a.address.map(|address| format_address(&address, a.port)))
+        .collect();
+    Ok(Some((node.alias, addresses)))
+}
+
+/// Returns the address the peer used to connect to our node
+async fn lookup_remote_address(
+    rpc: &mut dyn ClnRpcApi,
+    peer_id: &PublicKey,
+) -> Result<Option<String>> {
+    let request = ListpeersRequest {
+        id: Some(to_rpc_public_key(peer_id).context("Invalid peer_id")?),
+        level: None,
+    };
+    let response = rpc
+        .listpeers(&request)
+        .await
+        .context("Failed to call 'listpeers'")?;
+
+    Ok(response
+        .peers
+        .into_iter()
+        .find(|p| is_same_public_key(peer_id, &p.id))
+        .and_then(|p| p.netaddr)
+        .and_then(|netaddr| netaddr.into_iter().next()))
+}
+
+fn format_address(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+
+    const CLIENT_NODE_ID: &str =
+        "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn peer_id() -> PublicKey {
+        PublicKey::from_hex(CLIENT_NODE_ID).unwrap()
+    }
+
+    #[tokio::test]
+    async fn announced_node_is_recorded() {
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listnodes",
+            json!({ "nodes" : [{
+                "nodeid" : CLIENT_NODE_ID,
+                "alias" : "SLEEPYDRAGON",
+                "addresses" : [
+                    { "type" : "ipv4", "address" : "203.0.113.7", "port" : 9735 },
+                    { "type" : "ipv6", "address" : "2001:db8::7", "port" : 9735 }
+                ]
+            }]}),
+        )
+        .respond(
+            "listpeers",
+            json!({ "peers" : [{
+                "id" : CLIENT_NODE_ID,
+                "connected" : true,
+                "netaddr" : ["198.51.100.3:41234"]
+            }]}),
+        );
+
+        let client_node = lookup_client_node(Some(&mut rpc), &peer_id()).await;
+        assert_eq!(client_node.alias.as_deref(), Some("SLEEPYDRAGON"));
+        assert_eq!(
+            client_node.addresses,
+            vec!["203.0.113.7:9735", "[2001:db8::7]:9735"]
+        );
+        assert_eq!(
+            client_node.remote_address.as_deref(),
+            Some("198.51.100.3:41234")
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_is_best_effort() {
+        // A private node that has no node_announcement
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listnodes", json!({ "nodes" : [] }))
+            .fail("listpeers", "Connection refused");
+        let client_node = lookup_client_node(Some(&mut rpc), &peer_id()).await;
+        assert_eq!(client_node, Lsps1ClientNode::default());
+        assert_eq!(rpc.called_methods(), vec!["listnodes", "listpeers"]);
+
+        let client_node = lookup_client_node(None, &peer_id()).await;
+        assert_eq!(client_node, Lsps1ClientNode::default());
+    }
+}
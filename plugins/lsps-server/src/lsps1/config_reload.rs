@@ -0,0 +1,409 @@
+//! Reloads the options of LSPS1 without restarting the plugin
+//!
+//! lightningd can only change options of a plugin that are dynamic. The
+//! others change once the operator restarts the plugin. Instead, the
+//! operator can run `lsps1-reload-config`. It reads the options using
+//! `listconfigs` and validates them like at startup. The response to
+//! `lsps1.get_info` is replaced only if every option is valid. Channels
+//! that are opened afterwards use the new options.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{anyhow, Context, Result};
+use cln_plugin::options::{ConfigOption, OptionType, Value as OptionValue, ValueType};
+use cln_plugin::Plugin;
+use cln_rpc::ClnRpc;
+use lsp_primitives::lsps0::common_schemas::Network;
+use lsp_primitives::methods::Lsps1GetInfoResponse;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::cln::rpc_model::{ListconfigsConfig, ListconfigsRequest, ListconfigsResponse};
+use crate::lsps1::state::{get_state, OptionSource};
+use crate::network::parse_network;
+use crate::state::PluginState;
+
+/// The options as listed by `listconfigs`
+pub(crate) struct ListedConfigs {
+    values: HashMap<String, OptionValue>,
+    network: Network,
+}
+
+impl ListedConfigs {
+    pub(crate) fn new(response: ListconfigsResponse, network: Network) -> Self {
+        let values = response
+            .configs
+            .into_iter()
+            .filter_map(|(name, config)| Some((name, listed_value(config)?)))
+            .collect();
+        Self { values, network }
+    }
+}
+
+/// Options that take several values are skipped
+fn listed_value(config: ListconfigsConfig) -> Option<OptionValue> {
+    config
+        .value_str
+        .map(OptionValue::String)
+        .or(config.value_int.map(OptionValue::Integer))
+        .or(config.value_bool.or(config.set).map(OptionValue::Boolean))
+}
+
+/// Whether `value` can be read as an option of type `value_type`
+fn has_type(value: &OptionValue, value_type: ValueType) -> bool {
+    match value_type {
+        ValueType::String => matches!(value, OptionValue::String(_)),
+        ValueType::Integer => matches!(value, OptionValue::Integer(_)),
+        ValueType::Boolean | ValueType::Flag => matches!(value, OptionValue::Boolean(_)),
+    }
+}
+
+impl OptionSource for ListedConfigs {
+    fn option<'a, V: OptionType<'a>>(&self, opt: &ConfigOption<'a, V>) -> Result<V::OutputValue> {
+        // lightningd lists the options that use their default.
+        // An option that is missing is treated the same
+        let value = match self.values.get(opt.name) {
+            // `from_value` panics if the value has another type
+            Some(value) if !has_type(value, V::get_value_type()) => {
+                return Err(anyhow!(
+                    "Option '{}' is listed with a value of the wrong type",
+                    opt.name
+                ))
+            }
+            Some(value) => Some(value.clone()),
+            None => V::convert_default(&opt.default),
+        };
+        Ok(V::from_value(&value))
+    }
+
+    fn network(&self) -> Result<Network> {
+        Ok(self.network)
+    }
+}
+
+/// A field of the response to `lsps1.get_info` that was changed by a reload
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ConfigChange {
+    /// The path of the field. E.g: `options.max_channel_balance_sat`
+    pub(crate) field: String,
+    /// `null` if the field was absent
+    pub(crate) old: Value,
+    /// `null` if the field was removed
+    pub(crate) new: Value,
+}
+
+/// Maps the path of every field of `info` to its value
+///
+/// The field `enabled` tells whether LSPS1 is enabled
+fn info_fields(info: Option<&Lsps1GetInfoResponse>) -> Result<BTreeMap<String, Value>> {
+    let mut fields = BTreeMap::new();
+    fields.insert("enabled".to_string(), json!(info.is_some()));
+    if let Some(info) = info {
+        flatten("", serde_json::to_value(info)?, &mut fields);
+    }
+    Ok(fields)
+}
+
+fn flatten(path: &str, value: Value, fields: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                let path = match path {
+                    "" => name,
+                    _ => format!("{}.{}", path, name),
+                };
+                flatten(&path, value, fields);
+            }
+        }
+        value => {
+            fields.insert(path.to_string(), value);
+        }
+    }
+}
+
+/// Lists the fields that differ, sorted by their path
+fn diff_fields(old: BTreeMap<String, Value>, new: BTreeMap<String, Value>) -> Vec<ConfigChange> {
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let old = old.get(path).cloned().unwrap_or(Value::Null);
+            let new = new.get(path).cloned().unwrap_or(Value::Null);
+            if old == new {
+                return None;
+            }
+            Some(ConfigChange {
+                field: path.clone(),
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+/// Reads and validates the options of LSPS1 and replaces the info
+///
+/// If an option is invalid the error is returned and the info is kept
+pub(crate) async fn reload_config(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    network: Network,
+) -> Result<Vec<ConfigChange>> {
+    let response = rpc
+        .listconfigs(&ListconfigsRequest::default())
+        .await
+        .context("Failed to call 'listconfigs'")?;
+    let configs = ListedConfigs::new(response, network);
+    let info =
+        get_state(&configs).context("Invalid configuration. The previous configuration is used")?;
+
+    let new_fields = info_fields(info.as_ref())?;
+    let previous = state.replace_lsps1_info(info);
+    let old_fields = info_fields(previous.as_deref())?;
+    Ok(diff_fields(old_fields, new_fields))
+}
+
+/// Handles `lsps1-reload-config`
+pub(crate) async fn lsps1_reload_config(
+    plugin: Plugin<PluginState>,
+    _request: Value,
+) -> Result<Value> {
+    let network = parse_network(&plugin.configuration().network)?;
+    let mut rpc = ClnRpc::new(plugin.configuration().rpc_file).await?;
+
+    // The response only shows the outer context of an error
+    let changes = reload_config(plugin.state(), &mut rpc, network)
+        .await
+        .map_err(|err| anyhow!("{:#}", err))?;
+    for change in &changes {
+        log::info!(
+            "Reloaded '{}': {} -> {}",
+            change.field,
+            change.old,
+            change.new
+        );
+    }
+    Ok(json!({ "changes" : changes }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::sqlite::test::get_db;
+    use crate::options;
+    use crate::state::test_support::test_state;
+
+    /// The `listconfigs` of a node that sells channels of up to `max_channel_balance_sat`
+    fn listconfigs(max_channel_balance_sat: &str) -> Value {
+        json!({
+            "configs" : {
+                "network" : { "value_str" : "regtest", "source" : "cmdline" },
+                "lsps1-enable" : { "set" : true, "source" : "config", "plugin" : "/lsps-server" },
+                "lsps1-min-required-channel-confirmations" : { "value_int" : 0, "source" : "config" },
+                "lsps1-max-channel-expiry-blocks" : { "value_int" : 20160, "source" : "config" },
+                "lsps1-min-initial-client-balance-sat" : { "value_str" : "0", "source" : "config" },
+                "lsps1-max-initial-client-balance-sat" : { "value_str" : "0", "source" : "config" },
+                "lsps1-min-initial-lsp-balance-sat" : { "value_str" : "0", "source" : "config" },
+                "lsps1-max-initial-lsp-balance-sat" : { "value_str" : max_channel_balance_sat, "source" : "config" },
+                "lsps1-min-channel-balance-sat" : { "value_str" : "0", "source" : "config" },
+                "lsps1-max-channel-balance-sat" : { "value_str" : max_channel_balance_sat, "source" : "config" },
+                "lsps1-max-fee-percent" : { "value_int" : 10, "source" : "default" },
+                "important-plugin" : { "values_str" : ["/a", "/b"], "sources" : ["cmdline", "cmdline"] },
+            }
+        })
+    }
+
+    fn listed_configs(response: Value) -> ListedConfigs {
+        ListedConfigs::new(serde_json::from_value(response).unwrap(), Network::Regtest)
+    }
+
+    #[test]
+    fn listed_options_have_the_type_of_the_option() {
+        let configs = listed_configs(listconfigs("1000000"));
+
+        assert!(configs.option(&options::lsps1_enable()).unwrap());
+        assert!(!configs.option(&options::lsps1_clamp_expiry()).unwrap());
+        assert_eq!(
+            configs
+                .option(&options::lsps1_max_channel_expiry_blocks())
+                .unwrap(),
+            20160
+        );
+        assert_eq!(
+            configs
+                .option(&options::lsps1_max_channel_balance_sat())
+                .unwrap()
+                .as_deref(),
+            Some("1000000")
+        );
+        assert_eq!(
+            configs.option(&options::lsps1_info_website()).unwrap(),
+            None
+        );
+
+        // Options that are not listed use their default
+        assert_eq!(
+            configs
+                .option(&options::lsps1_fee_computation_base_fee_sat())
+                .unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn mistyped_options_are_rejected() {
+        let mut response = listconfigs("1000000");
+        response["configs"]["lsps1-max-channel-expiry-blocks"] = json!({ "value_str" : "20160" });
+        response["configs"]["lsps1-enable"] = json!({ "value_int" : 1 });
+        let configs = listed_configs(response);
+
+        let err = configs
+            .option(&options::lsps1_max_channel_expiry_blocks())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("lsps1-max-channel-expiry-blocks"),
+            "{}",
+            err
+        );
+        assert!(configs.option(&options::lsps1_enable()).is_err());
+        assert!(get_state(&configs).is_err());
+    }
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let old = BTreeMap::from([
+            ("enabled".to_string(), json!(true)),
+            (
+                "options.max_channel_balance_sat".to_string(),
+                json!("1000000"),
+            ),
+            ("website".to_string(), json!("https://example.com")),
+        ]);
+        let new = BTreeMap::from([
+            ("enabled".to_string(), json!(true)),
+            (
+                "options.max_channel_balance_sat".to_string(),
+                json!("2000000"),
+            ),
+            ("network".to_string(), json!("regtest")),
+        ]);
+
+        let changes = diff_fields(old, new);
+        assert_eq!(
+            serde_json::to_value(changes).unwrap(),
+            json!([
+                { "field" : "network", "old" : null, "new" : "regtest" },
+                { "field" : "options.max_channel_balance_sat", "old" : "1000000", "new" : "2000000" },
+                { "field" : "website", "old" : "https://example.com", "new" : null },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_replaces_the_info() {
+        let state = test_state(get_db().await);
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listconfigs", listconfigs("1000000"))
+            .respond("listconfigs", listconfigs("2000000"));
+
+        reload_config(&state, &mut rpc, Network::Regtest)
+            .await
+            .unwrap();
+        let changes = reload_config(&state, &mut rpc, Network::Regtest)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange {
+                    field: "options.max_channel_balance_sat".to_string(),
+                    old: json!("1000000"),
+                    new: json!("2000000"),
+                },
+                ConfigChange {
+                    field: "options.max_initial_lsp_balance_sat".to_string(),
+                    old: json!("1000000"),
+                    new: json!("2000000"),
+                },
+            ]
+        );
+        let info = state.lsps1_info().unwrap();
+        assert_eq!(info.options.max_channel_balance_sat.sat_value(), 2_000_000);
+    }
+
+    #[tokio::test]
+    async fn reload_without_changes_is_a_noop() {
+        let state = test_state(get_db().await);
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listconfigs", listconfigs("1000000"))
+            .respond("listconfigs", listconfigs("1000000"));
+
+        reload_config(&state, &mut rpc, Network::Regtest)
+            .await
+            .unwrap();
+        let before = serde_json::to_value(state.lsps1_info().as_deref()).unwrap();
+        let changes = reload_config(&state, &mut rpc, Network::Regtest)
+            .await
+            .unwrap();
+
+        assert!(changes.is_empty());
+        assert_eq!(
+            serde_json::to_value(state.lsps1_info().as_deref()).unwrap(),
+            before
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_config_keeps_the_info() {
+        let state = test_state(get_db().await);
+        let before = state.lsps1_info().unwrap();
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listconfigs", listconfigs("lots"));
+
+        let err = reload_config(&state, &mut rpc, Network::Regtest)
+            .await
+            .unwrap_err();
+
+        let message = format!("{:#}", err);
+        assert!(message.starts_with("Invalid configuration"), "{}", message);
+        assert!(
+            message.contains(options::LSPS1_MAX_INITIAL_LSP_BALANCE_SAT),
+            "{}",
+            message
+        );
+        assert!(Arc::ptr_eq(&state.lsps1_info().unwrap(), &before));
+    }
+
+    #[tokio::test]
+    async fn disabling_lsps1_is_reported() {
+        let state = test_state(get_db().await);
+        let mut rpc = FakeClnRpc::default();
+        let mut response = listconfigs("1000000");
+        response["configs"]
+            .as_object_mut()
+            .unwrap()
+            .remove("lsps1-enable");
+        rpc.respond("listconfigs", response);
+
+        let changes = reload_config(&state, &mut rpc, Network::Regtest)
+            .await
+            .unwrap();
+
+        assert!(state.lsps1_info().is_none());
+        assert!(changes.contains(&ConfigChange {
+            field: "enabled".to_string(),
+            old: json!(true),
+            new: json!(false),
+        }));
+        // The other fields are removed
+        assert!(changes
+            .iter()
+            .filter(|change| change.field != "enabled")
+            .all(|change| change.new.is_null()));
+    }
+}
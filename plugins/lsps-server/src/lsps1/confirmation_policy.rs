@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Context, Result};
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+
+use crate::options;
+
+/// The number of confirmations an on-chain payment requires
+///
+/// Larger orders carry more risk. The operator configures tiers in
+/// `lsps1-onchain-confirmation-tiers`, e.g. `0:0,1000000:1,5000000:3`.
+/// An order whose `order_total_sat` is at least the threshold of a tier
+/// requires the confirmations of that tier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConfirmationPolicy {
+    /// Ordered by threshold. The first threshold is 0
+    tiers: Vec<(SatAmount, u8)>,
+}
+
+impl ConfirmationPolicy {
+    /// Every order requires `confs` confirmations
+    pub(crate) fn uniform(confs: u8) -> Self {
+        Self {
+            tiers: vec![(SatAmount::new(0), confs)],
+        }
+    }
+
+    /// Parses the value of the `lsps1-onchain-confirmation-tiers` option
+    ///
+    /// The thresholds must increase and start at 0 so every order has a tier.
+    /// A larger order can't require fewer confirmations than a smaller one.
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        let mut tiers: Vec<(SatAmount, u8)> = Vec::new();
+        for entry in value.split(',').map(|entry| entry.trim()) {
+            let (threshold, confs) = entry.split_once(':').ok_or_else(|| {
+                anyhow!(
+                    "Expected '<order_total_sat>:<confirmations>' but found '{}'",
+                    entry
+                )
+            })?;
+            let threshold: u64 = threshold
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid threshold in '{}'", entry))?;
+            let confs: u8 = confs
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid number of confirmations in '{}'", entry))?;
+
+            match tiers.last() {
+                None if threshold != 0 => {
+                    return Err(anyhow!(
+                        "The first tier should start at 0 but starts at {}",
+                        threshold
+                    ))
+                }
+                Some((previous, _)) if previous.sat_value() >= threshold => {
+                    return Err(anyhow!(
+                        "Thresholds should increase but {} follows {}",
+                        threshold,
+                        previous
+                    ))
+                }
+                Some((_, previous)) if *previous > confs => {
+                    return Err(anyhow!(
+                        "Tier '{}' requires fewer confirmations than the tier before",
+                        entry
+                    ))
+                }
+                _ => tiers.push((SatAmount::new(threshold), confs)),
+            }
+        }
+
+        Ok(Self { tiers })
+    }
+
+    /// Creates the policy from the values of the plugin options
+    ///
+    /// Returns `None` if the operator didn't configure on-chain confirmations
+    pub(crate) fn from_options(
+        tiers: Option<String>,
+        min_onchain_payment_confirmations: Option<i64>,
+    ) -> Result<Option<Self>> {
+        match (tiers, min_onchain_payment_confirmations) {
+            (Some(_), Some(_)) => Err(anyhow!(
+                "Set either '{}' or '{}' but not both",
+                options::LSPS1_ONCHAIN_CONFIRMATION_TIERS,
+                options::LSPS1_MIN_ONCHAIN_PAYMENT_CONFIRMATIONS
+            )),
+            (Some(tiers), None) => Self::parse(&tiers).map(Some).with_context(|| {
+                format!(
+                    "Invalid value for option '{}'",
+                    options::LSPS1_ONCHAIN_CONFIRMATION_TIERS
+                )
+            }),
+            (None, Some(confs)) => {
+                let confs = u8::try_from(confs).with_context(|| {
+                    format!(
+                        "Option '{}' should fit into u8",
+                        options::LSPS1_MIN_ONCHAIN_PAYMENT_CONFIRMATIONS
+                    )
+                })?;
+                Ok(Some(Self::uniform(confs)))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The confirmations an on-chain payment of `order_total` requires
+    pub(crate) fn required_confs(&self, order_total: SatAmount) -> u8 {
+        self.tiers
+            .iter()
+            .take_while(|(threshold, _)| *threshold <= order_total)
+            .last()
+            .map(|(_, confs)| *confs)
+            .unwrap_or(0)
+    }
+
+    /// The confirmations of the smallest orders
+    ///
+    /// This is advertised as `min_onchain_payment_confirmations`
+    pub(crate) fn min_confs(&self) -> u8 {
+        self.required_confs(SatAmount::new(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sat(value: u64) -> SatAmount {
+        SatAmount::new(value)
+    }
+
+    #[test]
+    fn required_confs_across_tier_boundaries() {
+        let policy = ConfirmationPolicy::parse("0:0, 1000000:1, 5000000:3").unwrap();
+        assert_eq!(policy.min_confs(), 0);
+        assert_eq!(policy.required_confs(sat(0)), 0);
+        assert_eq!(policy.required_confs(sat(999_999)), 0);
+        assert_eq!(policy.required_confs(sat(1_000_000)), 1);
+        assert_eq!(policy.required_confs(sat(4_999_999)), 1);
+        assert_eq!(policy.required_confs(sat(5_000_000)), 3);
+        assert_eq!(policy.required_confs(sat(u64::MAX)), 3);
+
+        let policy = ConfirmationPolicy::parse("0:2").unwrap();
+        assert_eq!(policy, ConfirmationPolicy::uniform(2));
+        assert_eq!(policy.min_confs(), 2);
+        assert_eq!(policy.required_confs(sat(u64::MAX)), 2);
+    }
+
+    #[test]
+    fn reject_malformed_tiers() {
+        for value in [
+            "",
+            "0",
+            "0:0,",
+            "0:0;1000:1",
+            "zero:0",
+            "0:-1",
+            "0:256",
+            "-1:0",
+            "1000:1",
+            "0:0,1000:1,1000:2",
+            "0:0,5000:3,1000:1",
+            "0:3,1000:1",
+        ] {
+            assert!(
+                ConfirmationPolicy::parse(value).is_err(),
+                "'{}' should be rejected",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn policy_from_options() {
+        assert_eq!(ConfirmationPolicy::from_options(None, None).unwrap(), None);
+        assert_eq!(
+            ConfirmationPolicy::from_options(None, Some(2)).unwrap(),
+            Some(ConfirmationPolicy::uniform(2))
+        );
+        let policy = ConfirmationPolicy::from_options(Some("0:1,100000:6".to_string()), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(policy.required_confs(sat(100_000)), 6);
+
+        assert!(ConfirmationPolicy::from_options(Some("0:1".to_string()), Some(1)).is_err());
+        assert!(ConfirmationPolicy::from_options(None, Some(-1)).is_err());
+        assert!(ConfirmationPolicy::from_options(None, Some(256)).is_err());
+    }
+}
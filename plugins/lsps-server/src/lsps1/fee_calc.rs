@@ -1,90 +1,244 @@
+use std::fmt;
+
 use anyhow::{Context, Result};
 
-use cln_rpc::model::requests::{FeeratesRequest, FeeratesStyle};
+use cln_plugin::Plugin;
 use cln_rpc::model::responses::FeeratesPerkwEstimates;
-use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::lsps0::common_schemas::{MsatAmount, SatAmount};
+use lsp_primitives::lsps1::channel_limits::DEFAULT_DUST_LIMIT_SAT;
 
-use crate::custom_msg::context::CustomMsgContext;
 use crate::db::schema::Lsps1Order;
-use crate::PluginState;
-
-// TODO: Improve this API
-// A practical fee calculator might want to have access to
-// - ClnRpc: For Fee estimations + funds available
-// - plugion.options or plugin.state: To see current configuration
-// - ...
-#[async_trait::async_trait]
+use crate::feerate_cache::Feerates;
+use crate::{options, PluginState};
+
 pub trait FeeCalculator: Send {
-    async fn calculate_fee(
+    /// Checks if the fee of `order` can be computed sensibly
+    ///
+    /// Every strategy declares the range of parameters it can price.
+    /// This is called before [`FeeCalculator::calculate_fee`].
+    fn validate_domain(&self, order: &Lsps1Order) -> Result<(), DomainError>;
+
+    /// Computes the fee of `order` using the fee estimates in `feerates`
+    fn calculate_fee(
         &self,
-        context: &mut CustomMsgContext<PluginState>,
-        order: Lsps1Order,
+        feerates: &Feerates,
+        order: &Lsps1Order,
     ) -> Result<FeeCalculationResult>;
 }
 
+/// A parameter of an order that lies outside the range a [`FeeCalculator`] can price
+///
+/// The client receives it as an `option_mismatch` that names the parameter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainError {
+    pub(crate) property: &'static str,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.property, self.message)
+    }
+}
+
+impl std::error::Error for DomainError {}
+
 pub struct FeeCalculationResult {
     pub(crate) fee_total_sat: SatAmount,
     pub(crate) order_total_sat: SatAmount,
 }
 
+/// The parts of an order that determine its fee
+#[derive(Debug, Clone)]
+pub struct ChannelRequest {
+    pub(crate) lsp_balance_sat: SatAmount,
+    pub(crate) client_balance_sat: SatAmount,
+    pub(crate) channel_expiry_blocks: u32,
+    pub(crate) funding_confirms_within_blocks: u16,
+}
+
+impl From<&Lsps1Order> for ChannelRequest {
+    fn from(order: &Lsps1Order) -> Self {
+        Self {
+            lsp_balance_sat: order.lsp_balance_sat,
+            client_balance_sat: order.client_balance_sat,
+            channel_expiry_blocks: order.channel_expiry_blocks,
+            funding_confirms_within_blocks: order.funding_confirms_within_blocks,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct StandardFeeCalculator {
-    pub fixed_msat: u64,
+    pub base_fee_sat: u64,
     pub weight_units: u64,
     pub sat_per_billion_sat_block: u64,
 }
 
-#[async_trait::async_trait]
 impl FeeCalculator for StandardFeeCalculator {
-    async fn calculate_fee(
+    fn validate_domain(&self, order: &Lsps1Order) -> Result<(), DomainError> {
+        self.validate_request(&ChannelRequest::from(order))
+    }
+
+    fn calculate_fee(
         &self,
-        context: &mut CustomMsgContext<PluginState>,
-        order: Lsps1Order,
+        feerates: &Feerates,
+        order: &Lsps1Order,
     ) -> Result<FeeCalculationResult> {
         // Compute the required onchain feerate
-        // We use the lightning-rpc and confirms_within_blocks parameter
-        let feerate_request = FeeratesRequest {
-            style: FeeratesStyle::PERKW,
-        };
-        let feerate_response = context.cln_rpc.call_typed(&feerate_request).await?;
-        let feerates = feerate_response
-            .perkw
-            .context("Failed to retreive feerates")?
-            .estimates
-            .context("Failed to retrieve feerates")?;
+        // We use the cached estimates and confirms_within_blocks parameter
+        let request = ChannelRequest::from(order);
+        let onchain_feerate_kwu = feerates
+            .perkw_within(request.funding_confirms_within_blocks)
+            .context("Failed to compute approriate feerate")?;
 
-        let onchain_feerate_kwu = calculate_onchain_feerate(order.funding_confirms_within_blocks, &feerates)
-            .context("Failed to compute approriate feerate")?
-            as u64;
-
-        // Compute the fee charged by the LSP
-        self.calculate_lsp_fee(order, onchain_feerate_kwu)
+        // bitcoind doesn't relay a funding transaction below its minimum
+        let onchain_feerate_kwu = onchain_feerate_kwu.max(feerates.min_acceptable_perkw);
+        self.calculate_lsp_fee(&request, u64::from(onchain_feerate_kwu))
     }
 }
 
 impl StandardFeeCalculator {
-    fn calculate_lsp_fee(
+    /// A funding output below the dust limit isn't relayed by bitcoind
+    pub(crate) const MIN_CHANNEL_CAPACITY_SAT: u64 = DEFAULT_DUST_LIMIT_SAT;
+
+    /// The liquidity fee is linear in the expiry.
+    /// Leases of more than about 2 years aren't priced sensibly
+    pub(crate) const MAX_CHANNEL_EXPIRY_BLOCKS: u32 = 105_120;
+
+    /// Creates the calculator from the (signed) values of the plugin options
+    pub fn from_options(
+        base_fee_sat: i64,
+        weight_units: i64,
+        sat_per_billion_sat_block: i64,
+    ) -> Result<Self> {
+        Ok(Self {
+            base_fee_sat: base_fee_sat
+                .try_into()
+                .context("The base fee should not be negative")?,
+            weight_units: weight_units
+                .try_into()
+                .context("The weight units should not be negative")?,
+            sat_per_billion_sat_block: sat_per_billion_sat_block
+                .try_into()
+                .context("The liquidity fee should not be negative")?,
+        })
+    }
+
+    /// Creates the calculator from the configured plugin options
+    pub fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        Self::from_options(
+            plugin.option(&options::lsps1_fee_computation_base_fee_sat())?,
+            plugin.option(&options::lsps1_fee_computation_onchain_ppm())?,
+            plugin.option(&options::lsps1_fee_computation_liquidity_ppb())?,
+        )
+    }
+
+    /// Checks that `request` lies within the bounds of this calculator
+    ///
+    /// The onchain fee assumes a standard funding transaction and the
+    /// liquidity fee assumes a lease of a bounded duration.
+    pub(crate) fn validate_request(&self, request: &ChannelRequest) -> Result<(), DomainError> {
+        if request.channel_expiry_blocks == 0 {
+            return Err(DomainError {
+                property: "channel_expiry_blocks",
+                message: "A channel must be leased for at least 1 block".to_string(),
+            });
+        }
+
+        if request.channel_expiry_blocks > Self::MAX_CHANNEL_EXPIRY_BLOCKS {
+            return Err(DomainError {
+                property: "channel_expiry_blocks",
+                message: format!(
+                    "The fee of a lease of {} blocks can't be computed. The maximum is {}",
+                    request.channel_expiry_blocks,
+                    Self::MAX_CHANNEL_EXPIRY_BLOCKS
+                ),
+            });
+        }
+
+        // An overflowing capacity is refused by the validation of the options
+        let capacity_sat = request
+            .lsp_balance_sat
+            .sat_value()
+            .saturating_add(request.client_balance_sat.sat_value());
+        if capacity_sat < Self::MIN_CHANNEL_CAPACITY_SAT {
+            return Err(DomainError {
+                property: "lsp_balance_sat",
+                message: format!(
+                    "A channel with capacity={} is below the dust limit of {} sat",
+                    capacity_sat,
+                    Self::MIN_CHANNEL_CAPACITY_SAT
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Computes the fee using the fee estimates in `feerates`
+    pub(crate) fn calculate_fee_for_feerates(
         &self,
-        order: Lsps1Order,
+        request: &ChannelRequest,
+        feerates: &[FeeratesPerkwEstimates],
+    ) -> Result<FeeCalculationResult> {
+        let onchain_feerate_kwu =
+            calculate_onchain_feerate(request.funding_confirms_within_blocks, feerates)
+                .context("Failed to compute approriate feerate")?;
+
+        // Compute the fee charged by the LSP
+        self.calculate_lsp_fee(request, u64::from(onchain_feerate_kwu))
+    }
+
+    pub(crate) fn calculate_lsp_fee(
+        &self,
+        order: &ChannelRequest,
         onchain_feerate_sat_per_kwu: u64,
     ) -> Result<FeeCalculationResult> {
-        let base_fee = self.fixed_msat;
-        let client_balance_sat = order.client_balance_sat.sat_value() as u64;
-        let channel_capacity =
-            order.client_balance_sat.sat_value() + order.lsp_balance_sat.sat_value() as u64;
-        let expiry_blocks = order.channel_expiry_blocks as u64;
+        let client_balance_sat = order.client_balance_sat;
+        let channel_capacity_sat = order
+            .lsp_balance_sat
+            .checked_add(&client_balance_sat)
+            .context("Channel capacity overflows")?;
+
+        // All terms are computed in msat using u128 intermediates.
+        // Every term except the liquidity fee is an exact amount of msat.
+        // The liquidity fee is rounded down to the msat and the total is
+        // rounded up to the next sat, so we never charge less than configured.
+        let base_fee_msat = u128::from(self.base_fee_sat) * 1000;
 
-        let fee_total_sat = base_fee + //fixed amount
-            (onchain_feerate_sat_per_kwu*self.weight_units)/1000 + // Fee to compensate for onchain costs
-            (channel_capacity * expiry_blocks)/1_000_000_000 * self.sat_per_billion_sat_block; // Fee for providing liquidity
+        // feerate [sat/kwu] * weight [wu] / 1000 = sat, times 1000 = msat
+        let onchain_fee_msat =
+            u128::from(onchain_feerate_sat_per_kwu) * u128::from(self.weight_units);
+
+        // capacity [sat] * blocks * ppb / 1_000_000_000 = sat, times 1000 = msat
+        let liquidity_fee_msat = u128::from(channel_capacity_sat.sat_value())
+            .checked_mul(u128::from(order.channel_expiry_blocks))
+            .and_then(|x| x.checked_mul(u128::from(self.sat_per_billion_sat_block)))
+            .context("Liquidity fee overflows")?
+            / 1_000_000;
+
+        let fee_total_msat = base_fee_msat
+            .checked_add(onchain_fee_msat)
+            .and_then(|x| x.checked_add(liquidity_fee_msat))
+            .context("Fee overflows")?;
+        let fee_total_msat =
+            MsatAmount::new(u64::try_from(fee_total_msat).context("Fee overflows")?);
+
+        let fee_total_sat =
+            SatAmount::try_from_msat_round_up(fee_total_msat).context("Fee overflows")?;
+        let order_total_sat = fee_total_sat
+            .checked_add(&client_balance_sat)
+            .context("Order total overflows")?;
 
         Ok(FeeCalculationResult {
-            fee_total_sat: SatAmount::new(fee_total_sat),
-            order_total_sat: SatAmount::new(fee_total_sat + client_balance_sat),
+            fee_total_sat,
+            order_total_sat,
         })
     }
 }
 
-fn calculate_onchain_feerate(
+pub(crate) fn calculate_onchain_feerate(
     confirms_within_blocks: u16,
     feerates: &[FeeratesPerkwEstimates],
 ) -> Option<u32> {
@@ -105,6 +259,7 @@ fn calculate_onchain_feerate(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::db::sqlite::test::create_test_order;
     use cln_rpc::model::responses::FeeratesPerkwEstimates;
 
     #[test]
@@ -139,4 +294,143 @@ mod test {
         assert_eq!(calculate_onchain_feerate(5, &feerates), Some(9_000));
         assert_eq!(calculate_onchain_feerate(6, &feerates), Some(5_000));
     }
+
+    fn default_fee_calculator() -> StandardFeeCalculator {
+        // The defaults documented in `options.rs`
+        StandardFeeCalculator::from_options(100, 500, 200).unwrap()
+    }
+
+    #[test]
+    fn lsp_fee_for_default_options() {
+        let fee_calc = default_fee_calculator();
+
+        // 100 sat base fee + 1000 sat onchain fee + 86.4 sat liquidity fee
+        let order = create_test_order();
+        let result = fee_calc
+            .calculate_lsp_fee(&ChannelRequest::from(&order), 2_000)
+            .unwrap();
+        assert_eq!(result.fee_total_sat, SatAmount::new(1_187));
+        assert_eq!(result.order_total_sat, SatAmount::new(1_187));
+
+        // 100 sat base fee + 126.5 sat onchain fee + 907.2 sat liquidity fee
+        let mut order = create_test_order();
+        order.lsp_balance_sat = SatAmount::new(1_000_000);
+        order.client_balance_sat = SatAmount::new(50_000);
+        let result = fee_calc
+            .calculate_lsp_fee(&ChannelRequest::from(&order), 253)
+            .unwrap();
+        assert_eq!(result.fee_total_sat, SatAmount::new(1_134));
+        assert_eq!(result.order_total_sat, SatAmount::new(51_134));
+    }
+
+    #[test]
+    fn fee_uses_at_least_the_minimum_feerate() {
+        let fee_calc = default_fee_calculator();
+        let feerates = Feerates {
+            estimates: vec![FeeratesPerkwEstimates {
+                blockcount: Some(6),
+                feerate: Some(1_000),
+                smoothed_feerate: Some(1_000),
+            }],
+            min_acceptable_perkw: 2_000,
+            fetched_at: std::time::Instant::now(),
+        };
+
+        // 100 sat base fee + 1000 sat onchain fee + 86.4 sat liquidity fee
+        let order = create_test_order();
+        let result = fee_calc.calculate_fee(&feerates, &order).unwrap();
+        assert_eq!(result.fee_total_sat, SatAmount::new(1_187));
+    }
+
+    #[test]
+    fn lsp_fee_does_not_overflow() {
+        let fee_calc = default_fee_calculator();
+        let max_capacity_sat = 21_000_000 * 100_000_000;
+
+        // The fee might not fit in a u64 for the largest values.
+        // We only care that it is reported as an error instead of panicking or wrapping.
+        for capacity_sat in [
+            0,
+            1,
+            100_000,
+            1 << 32,
+            max_capacity_sat / 2,
+            max_capacity_sat,
+        ] {
+            for expiry_blocks in [0, 1, 4_320, u32::MAX / 2, u32::MAX] {
+                for feerate in [0, 253, u32::MAX as u64] {
+                    let mut order = create_test_order();
+                    order.lsp_balance_sat = SatAmount::new(capacity_sat / 2);
+                    order.client_balance_sat = SatAmount::new(capacity_sat - capacity_sat / 2);
+                    order.channel_expiry_blocks = expiry_blocks;
+
+                    if let Ok(result) =
+                        fee_calc.calculate_lsp_fee(&ChannelRequest::from(&order), feerate)
+                    {
+                        assert!(result.order_total_sat >= result.fee_total_sat);
+                    }
+                }
+            }
+        }
+
+        // Absurd configurations are reported instead of wrapping around
+        let fee_calc = StandardFeeCalculator::from_options(i64::MAX, i64::MAX, i64::MAX).unwrap();
+        let mut order = create_test_order();
+        order.lsp_balance_sat = SatAmount::new(max_capacity_sat);
+        order.channel_expiry_blocks = u32::MAX;
+        assert!(fee_calc
+            .calculate_lsp_fee(&ChannelRequest::from(&order), u64::MAX)
+            .is_err());
+    }
+
+    fn request(capacity_sat: u64, channel_expiry_blocks: u32) -> ChannelRequest {
+        let mut order = create_test_order();
+        order.lsp_balance_sat = SatAmount::new(capacity_sat);
+        order.channel_expiry_blocks = channel_expiry_blocks;
+        ChannelRequest::from(&order)
+    }
+
+    #[test]
+    fn domain_of_standard_calculator() {
+        let fee_calc = default_fee_calculator();
+        let max_expiry = StandardFeeCalculator::MAX_CHANNEL_EXPIRY_BLOCKS;
+        let min_capacity = StandardFeeCalculator::MIN_CHANNEL_CAPACITY_SAT;
+
+        assert!(fee_calc.validate_request(&request(100_000, 1)).is_ok());
+        assert!(fee_calc
+            .validate_request(&request(100_000, max_expiry))
+            .is_ok());
+        assert!(fee_calc
+            .validate_request(&request(min_capacity, 4_320))
+            .is_ok());
+
+        let error = fee_calc.validate_request(&request(100_000, 0)).unwrap_err();
+        assert_eq!(error.property, "channel_expiry_blocks");
+
+        let error = fee_calc
+            .validate_request(&request(100_000, max_expiry + 1))
+            .unwrap_err();
+        assert_eq!(error.property, "channel_expiry_blocks");
+
+        let error = fee_calc
+            .validate_request(&request(min_capacity - 1, 4_320))
+            .unwrap_err();
+        assert_eq!(error.property, "lsp_balance_sat");
+    }
+
+    #[test]
+    fn client_balance_counts_towards_capacity() {
+        let fee_calc = default_fee_calculator();
+        let mut order = create_test_order();
+        order.lsp_balance_sat = SatAmount::new(0);
+        order.client_balance_sat = SatAmount::new(100_000);
+        assert!(fee_calc.validate_domain(&order).is_ok());
+    }
+
+    #[test]
+    fn negative_fee_options_are_rejected() {
+        assert!(StandardFeeCalculator::from_options(-1, 500, 200).is_err());
+        assert!(StandardFeeCalculator::from_options(100, -1, 200).is_err());
+        assert!(StandardFeeCalculator::from_options(100, 500, -1).is_err());
+    }
 }
@@ -0,0 +1,289 @@
+//! Checks that the fee options fit the advertised options
+//!
+//! A misconfigured LSP can advertise channels whose fee is larger than
+//! the channel is worth, or sell channels for free. At startup the fee
+//! is computed for the corners of the advertised ranges: the smallest
+//! and largest capacity leased for 1 block and for
+//! `max_channel_expiry_blocks`.
+//!
+//! The fee estimates aren't known at startup. The fees are computed at
+//! the minimum feerate bitcoind relays. Real fees are at least as high.
+use std::fmt;
+
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::lsps1::schema::Lsps1Options;
+
+use crate::lsps1::fee_calc::{ChannelRequest, StandardFeeCalculator};
+use crate::lsps1::fee_simulation::{fixed_feerate, simulate_fees, SimulationInput};
+
+/// The feerate used to evaluate the corners
+pub(crate) const REFERENCE_FEERATE_PERKW: u32 = 253;
+
+/// A combination of parameters at the edge of the advertised ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Corner {
+    pub(crate) capacity_sat: u64,
+    pub(crate) channel_expiry_blocks: u32,
+}
+
+impl fmt::Display for Corner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "capacity={} sat and channel_expiry_blocks={}",
+            self.capacity_sat, self.channel_expiry_blocks
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FeeIssue {
+    /// The fee exceeds the configured share of the capacity
+    TooHigh { fee_sat: u64, max_fee_sat: u64 },
+    /// The channel is sold for free
+    Zero,
+    /// The fee overflows or lies outside the domain of the calculator
+    Failed(String),
+}
+
+/// A corner whose fee doesn't fit the advertised options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FeeInconsistency {
+    pub(crate) corner: Corner,
+    pub(crate) issue: FeeIssue,
+}
+
+impl fmt::Display for FeeInconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.issue {
+            FeeIssue::TooHigh {
+                fee_sat,
+                max_fee_sat,
+            } => write!(
+                f,
+                "The fee of {} sat for {} exceeds the maximum of {} sat",
+                fee_sat, self.corner, max_fee_sat
+            ),
+            FeeIssue::Zero => write!(f, "The fee for {} is 0 sat", self.corner),
+            FeeIssue::Failed(err) => {
+                write!(f, "The fee for {} can't be computed: {}", self.corner, err)
+            }
+        }
+    }
+}
+
+/// The corners of the advertised ranges
+pub(crate) fn corners(options: &Lsps1Options) -> Vec<Corner> {
+    let capacities = [
+        options.min_channel_balance_sat.sat_value(),
+        options.max_channel_balance_sat.sat_value(),
+    ];
+    let expiries = [1, options.max_channel_expiry_blocks];
+
+    let mut corners = Vec::with_capacity(4);
+    for capacity_sat in capacities {
+        for channel_expiry_blocks in expiries {
+            let corner = Corner {
+                capacity_sat,
+                channel_expiry_blocks,
+            };
+            if !corners.contains(&corner) {
+                corners.push(corner);
+            }
+        }
+    }
+    corners
+}
+
+/// Computes the fee of every corner and returns the inconsistencies
+///
+/// A fee above `max_fee_percent` of the capacity is reported.
+pub(crate) fn evaluate_corners(
+    fee_calc: &StandardFeeCalculator,
+    options: &Lsps1Options,
+    max_fee_percent: u64,
+) -> Vec<FeeInconsistency> {
+    let corners = corners(options);
+    let inputs: Vec<SimulationInput> = corners
+        .iter()
+        .map(|corner| SimulationInput {
+            order_id: None,
+            charged_fee_total_sat: None,
+            request: ChannelRequest {
+                lsp_balance_sat: SatAmount::new(corner.capacity_sat),
+                client_balance_sat: SatAmount::new(0),
+                channel_expiry_blocks: corner.channel_expiry_blocks,
+                funding_confirms_within_blocks: options.min_funding_confirms_within_blocks,
+            },
+        })
+        .collect();
+    let report = simulate_fees(
+        fee_calc,
+        None,
+        &inputs,
+        &fixed_feerate(REFERENCE_FEERATE_PERKW),
+    );
+
+    let mut inconsistencies = vec![];
+    for ((corner, input), order) in corners.iter().zip(&inputs).zip(report.orders) {
+        let issue = match (
+            fee_calc.validate_request(&input.request),
+            order.fee_total_sat,
+        ) {
+            (Err(err), _) => Some(FeeIssue::Failed(err.to_string())),
+            (Ok(()), None) => Some(FeeIssue::Failed(order.error.unwrap_or_default())),
+            (Ok(()), Some(0)) => Some(FeeIssue::Zero),
+            (Ok(()), Some(fee_sat)) => {
+                let max_fee_sat = max_fee_sat(corner.capacity_sat, max_fee_percent);
+                (fee_sat > max_fee_sat).then_some(FeeIssue::TooHigh {
+                    fee_sat,
+                    max_fee_sat,
+                })
+            }
+        };
+        if let Some(issue) = issue {
+            inconsistencies.push(FeeInconsistency {
+                corner: *corner,
+                issue,
+            });
+        }
+    }
+    inconsistencies
+}
+
+fn max_fee_sat(capacity_sat: u64, max_fee_percent: u64) -> u64 {
+    let max_fee_sat = u128::from(capacity_sat) * u128::from(max_fee_percent) / 100;
+    u64::try_from(max_fee_sat).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::state::test_support::test_info;
+
+    /// Advertises channels of `min_sat` up to `max_sat`
+    fn options(min_sat: u64, max_sat: u64, max_channel_expiry_blocks: u32) -> Lsps1Options {
+        let mut options = test_info().options;
+        options.min_channel_balance_sat = SatAmount::new(min_sat);
+        options.max_channel_balance_sat = SatAmount::new(max_sat);
+        options.max_channel_expiry_blocks = max_channel_expiry_blocks;
+        options
+    }
+
+    fn fee_calc(base_fee_sat: i64, weight_units: i64, liquidity_ppb: i64) -> StandardFeeCalculator {
+        StandardFeeCalculator::from_options(base_fee_sat, weight_units, liquidity_ppb).unwrap()
+    }
+
+    #[test]
+    fn corners_of_the_advertised_ranges() {
+        let corners = corners(&options(20_000, 1_000_000, 5_000));
+        let corners: Vec<_> = corners
+            .iter()
+            .map(|c| (c.capacity_sat, c.channel_expiry_blocks))
+            .collect();
+        assert_eq!(
+            corners,
+            vec![
+                (20_000, 1),
+                (20_000, 5_000),
+                (1_000_000, 1),
+                (1_000_000, 5_000)
+            ]
+        );
+
+        // Identical corners are evaluated once
+        assert_eq!(super::corners(&options(50_000, 50_000, 1)).len(), 1);
+    }
+
+    #[test]
+    fn default_options_are_consistent() {
+        let inconsistencies = evaluate_corners(
+            &fee_calc(100, 500, 200),
+            &options(100_000, 1_000_000, 5_000),
+            10,
+        );
+        assert_eq!(inconsistencies, vec![]);
+    }
+
+    #[test]
+    fn warn_if_the_fee_exceeds_the_capacity_share() {
+        // A 50_000 sat channel leased for 5000 blocks costs 4000 + 126.5 + 50_000 sat
+        let inconsistencies = evaluate_corners(
+            &fee_calc(4_000, 500, 200_000),
+            &options(50_000, 1_000_000, 5_000),
+            10,
+        );
+        let issues: Vec<_> = inconsistencies
+            .iter()
+            .map(|i| {
+                (
+                    i.corner.capacity_sat,
+                    i.corner.channel_expiry_blocks,
+                    &i.issue,
+                )
+            })
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                (
+                    50_000,
+                    5_000,
+                    &FeeIssue::TooHigh {
+                        fee_sat: 54_127,
+                        max_fee_sat: 5_000
+                    }
+                ),
+                (
+                    1_000_000,
+                    5_000,
+                    &FeeIssue::TooHigh {
+                        fee_sat: 1_004_127,
+                        max_fee_sat: 100_000
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            inconsistencies[0].to_string(),
+            "The fee of 54127 sat for capacity=50000 sat and channel_expiry_blocks=5000 exceeds the maximum of 5000 sat"
+        );
+    }
+
+    #[test]
+    fn warn_if_channels_are_free() {
+        // The liquidity fee of small channels is below 1 msat
+        let inconsistencies = evaluate_corners(&fee_calc(0, 0, 1), &options(1_000, 1_000, 1), 10);
+        assert_eq!(inconsistencies.len(), 1);
+        assert_eq!(inconsistencies[0].issue, FeeIssue::Zero);
+    }
+
+    #[test]
+    fn warn_if_the_fee_overflows() {
+        let inconsistencies = evaluate_corners(
+            &fee_calc(0, 0, i64::MAX),
+            &options(100_000, u64::MAX, 5_000),
+            100,
+        );
+        let failed: Vec<_> = inconsistencies
+            .iter()
+            .filter(|i| matches!(i.issue, FeeIssue::Failed(_)))
+            .map(|i| (i.corner.capacity_sat, i.corner.channel_expiry_blocks))
+            .collect();
+        assert_eq!(failed, vec![(u64::MAX, 1), (u64::MAX, 5_000)]);
+
+        // Leases beyond the domain of the calculator can't be priced
+        let max_expiry = StandardFeeCalculator::MAX_CHANNEL_EXPIRY_BLOCKS + 1;
+        let inconsistencies = evaluate_corners(
+            &fee_calc(100, 500, 200),
+            &options(100_000, 100_000, max_expiry),
+            100,
+        );
+        assert_eq!(inconsistencies.len(), 1);
+        assert_eq!(inconsistencies[0].corner.channel_expiry_blocks, max_expiry);
+        assert!(inconsistencies[0]
+            .to_string()
+            .contains("can't be computed: channel_expiry_blocks"));
+    }
+}
@@ -0,0 +1,406 @@
+//! Simulates the fees of hypothetical or past orders
+//!
+//! This helps the operator to tune the `lsps1-fee-computation-*`-options.
+//! A simulation only reads from the database and calls `feerates`. It never
+//! creates an invoice or writes an order.
+use anyhow::{anyhow, Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::responses::FeeratesPerkwEstimates;
+use cln_rpc::ClnRpc;
+use serde::Serialize;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::sat_amount_json;
+
+use crate::db::sqlite::quarantine::CorruptRow;
+use crate::db::sqlite::queries::{GetPaymentDetailsQuery, GetRecentOrdersQuery};
+use crate::lsps1::fee_calc::{ChannelRequest, StandardFeeCalculator};
+use crate::plugin_rpc::{FeeParameters, Lsps1AdminSimulateFeesRequest};
+use crate::state::PluginState;
+
+/// The maximum number of orders that can be replayed at once
+pub(crate) const MAX_REPLAY_ORDERS: u32 = 1000;
+
+/// An order for which the fee is simulated
+#[derive(Debug, Clone)]
+pub(crate) struct SimulationInput {
+    /// Set if the order was replayed from the database
+    pub(crate) order_id: Option<Uuid>,
+    /// The fee that was charged for a replayed order
+    pub(crate) charged_fee_total_sat: Option<SatAmount>,
+    pub(crate) request: ChannelRequest,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct FeeParametersReport {
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) base_fee_sat: u64,
+    pub(crate) weight_units: u64,
+    pub(crate) liquidity_ppb: u64,
+}
+
+impl From<&StandardFeeCalculator> for FeeParametersReport {
+    fn from(fee_calc: &StandardFeeCalculator) -> Self {
+        Self {
+            base_fee_sat: fee_calc.base_fee_sat,
+            weight_units: fee_calc.weight_units,
+            liquidity_ppb: fee_calc.sat_per_billion_sat_block,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct SimulatedOrderReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) order_id: Option<Uuid>,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) lsp_balance_sat: u64,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) client_balance_sat: u64,
+    pub(crate) channel_expiry_blocks: u32,
+    pub(crate) funding_confirms_within_blocks: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "sat_amount_json::serialize_option")]
+    pub(crate) charged_fee_total_sat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "sat_amount_json::serialize_option")]
+    pub(crate) fee_total_sat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "sat_amount_json::serialize_option")]
+    pub(crate) candidate_fee_total_sat: Option<u64>,
+    /// `candidate_fee_total_sat - fee_total_sat`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) delta_sat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Totals over all orders for which every fee could be computed
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct SimulationSummary {
+    pub(crate) order_count: usize,
+    pub(crate) failed_count: usize,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) total_fee_sat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "sat_amount_json::serialize_option")]
+    pub(crate) charged_total_fee_sat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "sat_amount_json::serialize_option")]
+    pub(crate) candidate_total_fee_sat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) delta_sat: Option<i64>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct SimulationReport {
+    pub(crate) current: FeeParametersReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) candidate: Option<FeeParametersReport>,
+    pub(crate) orders: Vec<SimulatedOrderReport>,
+    pub(crate) summary: SimulationSummary,
+    /// Replayed orders that couldn't be read from the database
+    pub(crate) corrupt_rows: Vec<CorruptRow>,
+}
+
+/// Handles `lsps1-admin-simulate-fees`
+pub(crate) async fn lsps1_admin_simulate_fees(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminSimulateFeesRequest = serde_json::from_value(request)?;
+
+    let current = StandardFeeCalculator::from_plugin(&plugin)?;
+    let candidate = request
+        .candidate
+        .as_ref()
+        .map(|params| candidate_fee_calculator(&current, params));
+
+    let mut corrupt_rows = Vec::new();
+    let inputs = match (request.orders, request.replay_last) {
+        (Some(_), Some(_)) => return Err(anyhow!("Use either orders or replay_last, not both")),
+        (Some(orders), None) => orders
+            .into_iter()
+            .map(|order| SimulationInput {
+                order_id: None,
+                charged_fee_total_sat: None,
+                request: ChannelRequest {
+                    lsp_balance_sat: SatAmount::new(order.lsp_balance_sat),
+                    client_balance_sat: SatAmount::new(order.client_balance_sat),
+                    channel_expiry_blocks: order.channel_expiry_blocks,
+                    funding_confirms_within_blocks: order.funding_confirms_within_blocks,
+                },
+            })
+            .collect(),
+        (None, Some(replay_last)) => {
+            if replay_last > MAX_REPLAY_ORDERS {
+                return Err(anyhow!("Can replay at most {} orders", MAX_REPLAY_ORDERS));
+            }
+            let (inputs, corrupt) = replay_orders(&plugin, replay_last).await?;
+            corrupt_rows = corrupt;
+            inputs
+        }
+        (None, None) => return Err(anyhow!("Either orders or replay_last must be provided")),
+    };
+
+    let feerates = match request.onchain_feerate_perkw {
+        Some(feerate) => fixed_feerate(feerate),
+        None => {
+            let rpc_path = plugin.configuration().rpc_file;
+            let mut rpc = ClnRpc::new(rpc_path).await?;
+            let state = plugin.state();
+            let cached = state.feerates.get(&mut rpc, state.clock.as_ref()).await?;
+            cached.estimates.clone()
+        }
+    };
+
+    let mut report = simulate_fees(&current, candidate.as_ref(), &inputs, &feerates);
+    report.corrupt_rows = corrupt_rows;
+    Ok(serde_json::to_value(report)?)
+}
+
+/// Corrupt orders are skipped and returned separately
+async fn replay_orders(
+    plugin: &Plugin<PluginState>,
+    limit: u32,
+) -> Result<(Vec<SimulationInput>, Vec<CorruptRow>)> {
+    let state = plugin.state();
+    let mut tx = state.database.begin().await?;
+    let orders = GetRecentOrdersQuery::last(limit)
+        .execute_quarantined(&mut tx)
+        .await?;
+    state.quarantine.record(&orders.corrupt_rows);
+
+    let mut inputs = Vec::with_capacity(orders.rows.len());
+    for order in orders.rows {
+        let payment = GetPaymentDetailsQuery::by_uuid(order.uuid)
+            .execute(&mut tx)
+            .await?
+            .with_context(|| format!("Failed to find payment for order {}", order.uuid))?;
+        inputs.push(SimulationInput {
+            order_id: Some(order.uuid),
+            charged_fee_total_sat: Some(payment.fee_total_sat),
+            request: ChannelRequest::from(&order),
+        });
+    }
+    tx.commit().await?;
+    Ok((inputs, orders.corrupt_rows))
+}
+
+/// Uses the configured value for every parameter that isn't overridden
+fn candidate_fee_calculator(
+    current: &StandardFeeCalculator,
+    params: &FeeParameters,
+) -> StandardFeeCalculator {
+    StandardFeeCalculator {
+        base_fee_sat: params.base_fee_sat.unwrap_or(current.base_fee_sat),
+        weight_units: params.weight_units.unwrap_or(current.weight_units),
+        sat_per_billion_sat_block: params
+            .liquidity_ppb
+            .unwrap_or(current.sat_per_billion_sat_block),
+    }
+}
+
+/// A fee estimate that is used regardless of `funding_confirms_within_blocks`
+pub(crate) fn fixed_feerate(feerate_perkw: u32) -> Vec<FeeratesPerkwEstimates> {
+    vec![FeeratesPerkwEstimates {
+        blockcount: Some(0),
+        feerate: Some(feerate_perkw),
+        smoothed_feerate: Some(feerate_perkw),
+    }]
+}
+
+pub(crate) fn simulate_fees(
+    current: &StandardFeeCalculator,
+    candidate: Option<&StandardFeeCalculator>,
+    inputs: &[SimulationInput],
+    feerates: &[FeeratesPerkwEstimates],
+) -> SimulationReport {
+    let mut orders = Vec::with_capacity(inputs.len());
+    let mut summary = SimulationSummary {
+        order_count: inputs.len(),
+        failed_count: 0,
+        total_fee_sat: 0,
+        charged_total_fee_sat: None,
+        candidate_total_fee_sat: candidate.map(|_| 0),
+        delta_sat: None,
+    };
+    let mut charged_total_fee_sat: Option<u64> = None;
+
+    for input in inputs {
+        let mut report = SimulatedOrderReport {
+            order_id: input.order_id,
+            lsp_balance_sat: input.request.lsp_balance_sat.sat_value(),
+            client_balance_sat: input.request.client_balance_sat.sat_value(),
+            channel_expiry_blocks: input.request.channel_expiry_blocks,
+            funding_confirms_within_blocks: input.request.funding_confirms_within_blocks,
+            charged_fee_total_sat: input.charged_fee_total_sat.map(|f| f.sat_value()),
+            fee_total_sat: None,
+            candidate_fee_total_sat: None,
+            delta_sat: None,
+            error: None,
+        };
+
+        let result = simulate_order(current, candidate, &input.request, feerates);
+        match result {
+            Ok((fee_total_sat, candidate_fee_total_sat)) => {
+                report.fee_total_sat = Some(fee_total_sat);
+                report.candidate_fee_total_sat = candidate_fee_total_sat;
+                report.delta_sat = candidate_fee_total_sat.map(|c| delta_sat(fee_total_sat, c));
+
+                summary.total_fee_sat = summary.total_fee_sat.saturating_add(fee_total_sat);
+                if let (Some(total), Some(fee)) = (
+                    summary.candidate_total_fee_sat.as_mut(),
+                    candidate_fee_total_sat,
+                ) {
+                    *total = total.saturating_add(fee);
+                }
+                if let Some(charged) = report.charged_fee_total_sat {
+                    let total = charged_total_fee_sat.get_or_insert(0);
+                    *total = total.saturating_add(charged);
+                }
+            }
+            Err(err) => {
+                summary.failed_count += 1;
+                report.error = Some(format!("{:#}", err));
+            }
+        }
+        orders.push(report);
+    }
+
+    summary.charged_total_fee_sat = charged_total_fee_sat;
+    summary.delta_sat = summary
+        .candidate_total_fee_sat
+        .map(|c| delta_sat(summary.total_fee_sat, c));
+
+    SimulationReport {
+        current: FeeParametersReport::from(current),
+        candidate: candidate.map(FeeParametersReport::from),
+        orders,
+        summary,
+        corrupt_rows: Vec::new(),
+    }
+}
+
+fn simulate_order(
+    current: &StandardFeeCalculator,
+    candidate: Option<&StandardFeeCalculator>,
+    request: &ChannelRequest,
+    feerates: &[FeeratesPerkwEstimates],
+) -> Result<(u64, Option<u64>)> {
+    let fee = current.calculate_fee_for_feerates(request, feerates)?;
+    let candidate_fee = candidate
+        .map(|c| c.calculate_fee_for_feerates(request, feerates))
+        .transpose()
+        .context("Candidate parameters")?;
+    Ok((
+        fee.fee_total_sat.sat_value(),
+        candidate_fee.map(|f| f.fee_total_sat.sat_value()),
+    ))
+}
+
+fn delta_sat(current: u64, candidate: u64) -> i64 {
+    let delta = i128::from(candidate) - i128::from(current);
+    delta.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input(lsp_balance_sat: u64, client_balance_sat: u64, expiry: u32) -> SimulationInput {
+        SimulationInput {
+            order_id: None,
+            charged_fee_total_sat: None,
+            request: ChannelRequest {
+                lsp_balance_sat: SatAmount::new(lsp_balance_sat),
+                client_balance_sat: SatAmount::new(client_balance_sat),
+                channel_expiry_blocks: expiry,
+                funding_confirms_within_blocks: 6,
+            },
+        }
+    }
+
+    fn default_fee_calculator() -> StandardFeeCalculator {
+        StandardFeeCalculator::from_options(100, 500, 200).unwrap()
+    }
+
+    #[test]
+    fn simulate_representative_orders() {
+        let current = default_fee_calculator();
+        let candidate = candidate_fee_calculator(
+            &current,
+            &FeeParameters {
+                base_fee_sat: Some(1_000),
+                weight_units: None,
+                liquidity_ppb: Some(100),
+            },
+        );
+
+        let inputs = vec![
+            // Matches the golden values in `fee_calc`
+            input(1_000_000, 50_000, 4_320),
+            input(100_000, 0, 4_320),
+            input(10_000_000, 0, 52_560),
+        ];
+        let report = simulate_fees(&current, Some(&candidate), &inputs, &fixed_feerate(253));
+
+        let fees: Vec<_> = report
+            .orders
+            .iter()
+            .map(|o| (o.fee_total_sat, o.candidate_fee_total_sat, o.delta_sat))
+            .collect();
+        assert_eq!(
+            fees,
+            vec![
+                // 100 + 126.5 + 907.2 vs 1000 + 126.5 + 453.6
+                (Some(1_134), Some(1_581), Some(447)),
+                // 100 + 126.5 + 86.4 vs 1000 + 126.5 + 43.2
+                (Some(313), Some(1_170), Some(857)),
+                // 100 + 126.5 + 105_120 vs 1000 + 126.5 + 52_560
+                (Some(105_347), Some(53_687), Some(-51_660)),
+            ]
+        );
+
+        assert_eq!(
+            report.summary,
+            SimulationSummary {
+                order_count: 3,
+                failed_count: 0,
+                total_fee_sat: 106_794,
+                charged_total_fee_sat: None,
+                candidate_total_fee_sat: Some(56_438),
+                delta_sat: Some(-50_356),
+            }
+        );
+        assert_eq!(report.candidate.unwrap().weight_units, 500);
+    }
+
+    #[test]
+    fn replayed_orders_report_the_charged_fee() {
+        let current = default_fee_calculator();
+        let mut order = input(1_000_000, 50_000, 4_320);
+        order.order_id = Some(Uuid::new_v4());
+        order.charged_fee_total_sat = Some(SatAmount::new(1_000));
+
+        let report = simulate_fees(&current, None, &[order], &fixed_feerate(253));
+        assert_eq!(report.orders[0].charged_fee_total_sat, Some(1_000));
+        assert_eq!(report.orders[0].fee_total_sat, Some(1_134));
+        assert_eq!(report.summary.charged_total_fee_sat, Some(1_000));
+        assert_eq!(report.summary.candidate_total_fee_sat, None);
+        assert_eq!(report.summary.delta_sat, None);
+    }
+
+    #[test]
+    fn failed_orders_are_reported_but_not_summed() {
+        let current = default_fee_calculator();
+        let inputs = vec![input(1_000_000, 50_000, 4_320), input(u64::MAX, 1, 4_320)];
+
+        let report = simulate_fees(&current, None, &inputs, &fixed_feerate(253));
+        assert!(report.orders[1].error.is_some());
+        assert_eq!(report.orders[1].fee_total_sat, None);
+        assert_eq!(report.summary.failed_count, 1);
+        assert_eq!(report.summary.total_fee_sat, 1_134);
+    }
+}
@@ -0,0 +1,519 @@
+//! Reserves the wallet inputs that fund the channel of a paid order
+//!
+//! Between the payment and the funding transaction other wallet activity,
+//! e.g.: a `withdraw` or the channel of another order, can spend the
+//! inputs we intended to use and the channel open fails. The inputs are
+//! reserved when the order is opened or while it waits for the client to
+//! reconnect. [`fundchannel_fallible`] hands the reservation over to the
+//! funding transaction or releases it if the open fails.
+//!
+//! The reservation is stored with the order so it can be released after a
+//! restart. lightningd drops a reservation by itself after 72 blocks.
+//!
+//! Several channels can be opened at the same time. An order that is
+//! opened checks that the wallet can fund its channel before it reserves
+//! its inputs. The check and the reservation of one order happen before
+//! the next order checks the wallet.
+//!
+//! The channel of an order with a funding source is only funded by the
+//! outputs of that source. Its funds are checked and its inputs are
+//! reserved within the source. See [`crate::lsps1::funding_source`].
+//!
+//! [`fundchannel_fallible`]: crate::channel_open::fundchannel_fallible
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::ListfundsRequest;
+use cln_rpc::model::responses::{ListfundsOutputs, ListfundsOutputsStatus};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::channel_open::{release_inputs, reserve_inputs, ChannelDetails};
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::{Lsps1FundingReservation, OrderLogEvent};
+use crate::db::sqlite::queries::{
+    CreateFundingReservationQuery, DeleteFundingReservationQuery, GetFundingReservationQuery,
+    GetStaleFundingReservationsQuery,
+};
+use crate::lsps1::funding_source::UtxoFilter;
+use crate::state::PluginState;
+
+/// Returns the inputs that are reserved to fund the channel of an order
+///
+/// Inputs are only reserved if the order doesn't have a reservation yet.
+/// Returns `None` if no inputs could be reserved. E.g.: the wallet doesn't
+/// have enough confirmed funds. lightningd selects the inputs when the
+/// channel is funded in that case.
+pub(crate) async fn reserve_funding_inputs(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+    channel_details: &ChannelDetails,
+) -> Option<Lsps1FundingReservation> {
+    let _budget = state.open_queue.lock_funding_budget().await;
+    reserve_or_warn(state, rpc, order_uuid, channel_details).await
+}
+
+/// Returns the inputs that fund the channel of an order that is opened now
+///
+/// Fails if the order has no reservation and the confirmed funds in the
+/// wallet that aren't reserved don't cover the channel. The balance is
+/// checked again for every order. Orders that wait for the open worker
+/// don't rely on a balance that was checked before another channel was
+/// funded.
+///
+/// The check is skipped if lightningd fails to list the funds.
+///
+/// An order with a funding source fails if its inputs can't be reserved.
+/// lightningd would select the inputs of other sources otherwise.
+pub(crate) async fn reserve_funding_inputs_within_budget(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+    channel_details: &ChannelDetails,
+) -> Result<Option<Lsps1FundingReservation>> {
+    let _budget = state.open_queue.lock_funding_budget().await;
+
+    let mut tx = state.database.begin().await?;
+    let existing = GetFundingReservationQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_funding_reservation'-query on database")?;
+    tx.commit().await?;
+    if existing.is_some() {
+        return Ok(existing);
+    }
+
+    match available_funds_sat(rpc, channel_details.utxo_filter.as_ref()).await {
+        Ok(available_sat) => {
+            let needed_sat = channel_details.amount.sat_value();
+            anyhow::ensure!(
+                available_sat >= needed_sat,
+                "Insufficient funds to open channel: {} sat needed, {} sat available",
+                needed_sat,
+                available_sat
+            );
+        }
+        Err(err) => log::warn!(
+            "Failed to check the funds for order {}: {:?}",
+            order_uuid,
+            err
+        ),
+    }
+    if channel_details.utxo_filter.is_some() {
+        return try_reserve_funding_inputs(state, rpc, order_uuid, channel_details)
+            .await
+            .map(Some);
+    }
+    Ok(reserve_or_warn(state, rpc, order_uuid, channel_details).await)
+}
+
+/// The confirmed funds in the wallet that aren't reserved
+///
+/// Only the outputs that match `filter` are counted if it is set
+async fn available_funds_sat(rpc: &mut dyn ClnRpcApi, filter: Option<&UtxoFilter>) -> Result<u64> {
+    let response = rpc
+        .listfunds(&ListfundsRequest { spent: None })
+        .await
+        .context("Failed to call 'listfunds'")?;
+    let amount_sat = |output: &ListfundsOutputs| output.amount_msat.msat() / 1000;
+    Ok(match filter {
+        Some(filter) => filter.spendable(&response.outputs).map(amount_sat).sum(),
+        None => response
+            .outputs
+            .iter()
+            .filter(|output| output.status == ListfundsOutputsStatus::CONFIRMED && !output.reserved)
+            .map(amount_sat)
+            .sum(),
+    })
+}
+
+async fn reserve_or_warn(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+    channel_details: &ChannelDetails,
+) -> Option<Lsps1FundingReservation> {
+    match try_reserve_funding_inputs(state, rpc, order_uuid, channel_details).await {
+        Ok(reservation) => Some(reservation),
+        Err(err) => {
+            log::warn!(
+                "Failed to reserve inputs for order {}: {:?}",
+                order_uuid,
+                err
+            );
+            None
+        }
+    }
+}
+
+async fn try_reserve_funding_inputs(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+    channel_details: &ChannelDetails,
+) -> Result<Lsps1FundingReservation> {
+    let db = &state.database;
+    let mut tx = db.begin().await?;
+    let existing = GetFundingReservationQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_funding_reservation'-query on database")?;
+    tx.commit().await?;
+    if let Some(reservation) = existing {
+        return Ok(reservation);
+    }
+
+    let reservation = reserve_inputs(rpc, state.clock.as_ref(), channel_details).await?;
+
+    let mut tx = db.begin().await?;
+    let stored = CreateFundingReservationQuery {
+        order_uuid,
+        reservation: reservation.clone(),
+    }
+    .execute(&mut tx)
+    .await;
+    tx.commit().await?;
+
+    // Inputs that aren't stored can't be released after a restart
+    if !matches!(stored, Ok(true)) {
+        if let Err(err) = release_inputs(rpc, &reservation).await {
+            log::warn!("Failed to release unused reservation: {:?}", err);
+        }
+        stored.context("Failed to execute 'create_funding_reservation'-query on database")?;
+        anyhow::bail!("Order {} already has a reservation", order_uuid);
+    }
+
+    log::debug!(
+        "Reserved inputs {:?} for order {}",
+        reservation.utxos,
+        order_uuid
+    );
+    state.order_log.info(
+        order_uuid,
+        OrderLogEvent::InputsReserved,
+        json!({ "utxos" : utxos_json(&reservation) }),
+    );
+    Ok(reservation)
+}
+
+/// Releases the reserved inputs of an order that won't fund a channel
+///
+/// Returns false if the order had no reservation. A reservation that
+/// lightningd fails to release is forgotten anyway. lightningd drops it
+/// by itself after a number of blocks.
+pub(crate) async fn release_funding_inputs(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+) -> Result<bool> {
+    let mut tx = state.database.begin().await?;
+    let reservation = GetFundingReservationQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_funding_reservation'-query on database")?;
+    tx.commit().await?;
+    let Some(reservation) = reservation else {
+        return Ok(false);
+    };
+
+    let released = match release_inputs(rpc, &reservation).await {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!(
+                "Failed to release the reserved inputs of order {}: {:?}",
+                order_uuid,
+                err
+            );
+            false
+        }
+    };
+    forget_funding_inputs(
+        state,
+        order_uuid,
+        &reservation,
+        json!({ "released" : released }),
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Forgets the reservation once the channel open used it
+///
+/// [`fundchannel_fallible`] handed the inputs over to the funding
+/// transaction or released them.
+///
+/// [`fundchannel_fallible`]: crate::channel_open::fundchannel_fallible
+pub(crate) async fn funding_inputs_used(
+    state: &PluginState,
+    order_uuid: Uuid,
+    reservation: &Lsps1FundingReservation,
+    funded: bool,
+) -> Result<()> {
+    forget_funding_inputs(state, order_uuid, reservation, json!({ "funded" : funded })).await
+}
+
+async fn forget_funding_inputs(
+    state: &PluginState,
+    order_uuid: Uuid,
+    reservation: &Lsps1FundingReservation,
+    details: serde_json::Value,
+) -> Result<()> {
+    let mut tx = state.database.begin().await?;
+    let deleted = DeleteFundingReservationQuery { order_uuid }
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'delete_funding_reservation'-query on database")?;
+    tx.commit().await?;
+
+    if deleted {
+        let mut details = details;
+        details["utxos"] = utxos_json(reservation);
+        state
+            .order_log
+            .info(order_uuid, OrderLogEvent::InputsReleased, details);
+    }
+    Ok(())
+}
+
+/// Releases the reservations of orders that won't fund a channel anymore
+///
+/// The plugin might have stopped after an order failed or completed but
+/// before its reservation was released. This must run after
+/// [`reconcile_funding_orders`] at start-up. Returns the number of
+/// released reservations.
+///
+/// [`reconcile_funding_orders`]: crate::lsps1::channel_open::reconcile_funding_orders
+pub(crate) async fn release_stale_funding_inputs(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+) -> Result<usize> {
+    let mut tx = state.database.begin().await?;
+    let stale = GetStaleFundingReservationsQuery
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_stale_funding_reservations'-query on database")?;
+    tx.commit().await?;
+
+    let mut count = 0;
+    for (order_uuid, _) in stale {
+        match release_funding_inputs(state, rpc, order_uuid).await {
+            Ok(true) => count += 1,
+            Ok(false) => {}
+            Err(err) => log::warn!(
+                "Failed to release the reservation of order {}: {:?}",
+                order_uuid,
+                err
+            ),
+        }
+    }
+    Ok(count)
+}
+
+fn utxos_json(reservation: &Lsps1FundingReservation) -> serde_json::Value {
+    json!(reservation
+        .utxos
+        .iter()
+        .map(|utxo| utxo.to_string())
+        .collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, SatAmount};
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::schema::{Lsps1OrderState, OrderGeneration};
+    use crate::db::sqlite::queries::{GetOrderLogQuery, UpdateOrderStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::db::sqlite::Database;
+    use crate::lsps1::order_log::OrderLogWriter;
+    use crate::state::test_support::{test_state, test_state_with_order_log};
+
+    const RESERVED_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn channel_details() -> ChannelDetails {
+        ChannelDetails {
+            peer_id: create_order_query().order.client_node_id,
+            amount: SatAmount::new(100_000),
+            feerate: None,
+            announce: Some(false),
+            close_to: None,
+            push_msat: None,
+            mindepth: Some(0),
+            reserve: Some(SatAmount::new(0)),
+            channel_type: None,
+            reserved_inputs: None,
+            utxo_filter: None,
+        }
+    }
+
+    fn fundpsbt() -> serde_json::Value {
+        json!({
+            "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+            "feerate_per_kw" : 253,
+            "estimated_final_weight" : 652,
+            "reservations" : [{
+                "txid" : RESERVED_TXID,
+                "vout" : 1,
+                "was_reserved" : false,
+                "reserved" : true,
+                "reserved_to_block" : 175,
+            }],
+        })
+    }
+
+    async fn insert_order(db: &Database, order_state: Lsps1OrderState) -> Uuid {
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: order_state,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        uuid
+    }
+
+    /// Writes the order log once `state` is dropped and returns the events of the order
+    async fn order_log_events(
+        state: PluginState,
+        writer: OrderLogWriter,
+        order_uuid: Uuid,
+    ) -> Vec<&'static str> {
+        let db = state.database.clone();
+        drop(state);
+        writer.run(db.clone()).await;
+        let mut tx = db.begin().await.unwrap();
+        let entries = GetOrderLogQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        entries.iter().map(|entry| entry.event.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn inputs_are_reserved_once() {
+        let (db, _) = get_temp_db().await;
+        let (state, writer) = test_state_with_order_log(db.clone());
+        let uuid = insert_order(&db, Lsps1OrderState::PendingOpen).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("fundpsbt", fundpsbt());
+        let reservation = reserve_funding_inputs(&state, &mut rpc, uuid, &channel_details())
+            .await
+            .unwrap();
+        assert_eq!(
+            reservation.utxos[0].to_string(),
+            format!("{}:1", RESERVED_TXID)
+        );
+
+        // The inputs are selected for the capacity of the channel
+        let params = rpc.params_of("fundpsbt").unwrap();
+        assert_eq!(params["satoshi"], "100000000msat");
+        assert_eq!(params["startweight"], 214);
+
+        // The stored reservation is used when the order is opened
+        let again = reserve_funding_inputs(&state, &mut rpc, uuid, &channel_details())
+            .await
+            .unwrap();
+        assert_eq!(again, reservation);
+        assert_eq!(rpc.called_methods(), vec!["fundpsbt"]);
+        assert_eq!(
+            order_log_events(state, writer, uuid).await,
+            vec!["inputs_reserved"]
+        );
+    }
+
+    #[tokio::test]
+    async fn open_continues_without_reservation() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_order(&db, Lsps1OrderState::PendingOpen).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail(
+            "fundpsbt",
+            "Could not afford 100000sat using all 0 available UTXOs",
+        );
+        let reservation = reserve_funding_inputs(&state, &mut rpc, uuid, &channel_details()).await;
+        assert!(reservation.is_none());
+
+        let mut tx = db.begin().await.unwrap();
+        let stored = GetFundingReservationQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert!(stored.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_reservations_are_released_at_startup() {
+        let (db, _) = get_temp_db().await;
+        let (state, writer) = test_state_with_order_log(db.clone());
+        let pending_uuid = insert_order(&db, Lsps1OrderState::PendingOpen).await;
+        let failed_uuid = insert_order(&db, Lsps1OrderState::PendingOpen).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("fundpsbt", fundpsbt())
+            .respond("fundpsbt", fundpsbt());
+        for uuid in [pending_uuid, failed_uuid] {
+            reserve_funding_inputs(&state, &mut rpc, uuid, &channel_details())
+                .await
+                .unwrap();
+        }
+
+        // The plugin stopped after the order failed
+        let mut tx = db.begin().await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: failed_uuid,
+            state: Lsps1OrderState::Failed,
+            generation: OrderGeneration(1),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("unreserveinputs", json!({ "reservations" : [] }));
+        let count = release_stale_funding_inputs(&state, &mut rpc)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(rpc.called_methods(), vec!["unreserveinputs"]);
+        assert_eq!(
+            rpc.params_of("unreserveinputs").unwrap()["psbt"],
+            "cHNidP8BAAoCAAAAAAAAAAAAAA=="
+        );
+
+        // The order that waits for its channel keeps its reservation
+        let mut tx = db.begin().await.unwrap();
+        let pending = GetFundingReservationQuery::by_uuid(pending_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        let failed = GetFundingReservationQuery::by_uuid(failed_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert!(pending.is_some());
+        assert!(failed.is_none());
+        assert_eq!(
+            order_log_events(state, writer, failed_uuid).await,
+            vec!["inputs_reserved", "inputs_released"]
+        );
+    }
+}
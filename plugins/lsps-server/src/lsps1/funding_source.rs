@@ -0,0 +1,466 @@
+//! Separates the funds of the wallet into funding sources
+//!
+//! An operator can keep the funds of several accounts in the wallet of
+//! lightningd. E.g.: a set of outputs that is set aside for a partner.
+//! A funding source is a label and a filter that selects the outputs of
+//! the wallet that belong to it. The channel of an order that has a
+//! source is only funded by the outputs of that source.
+//!
+//! The sources are read from the JSON-file of `lsps1-funding-sources-file`
+//!
+//! ```json
+//! {
+//!   "hot" : { "addresses" : ["bcrt1q..."] },
+//!   "partner" : {
+//!     "outpoints" : ["4a5e1e...:0"],
+//!     "budget_sat" : 5000000,
+//!     "tokens" : ["partner-token"]
+//!   }
+//! }
+//! ```
+//!
+//! Orders use the source of `lsps1-funding-source-label`. A client can
+//! pick another source using the `funding_source` extension if its token
+//! is listed in the `tokens` of that source. Orders without a source are
+//! funded by any output of the wallet.
+//!
+//! The `budget_sat` of a source limits the capacity of its orders that
+//! wait for a channel. Every source has its own budget.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Context, Result};
+use cln_rpc::model::responses::{ListfundsOutputs, ListfundsOutputsStatus};
+use serde::Deserialize;
+
+use lsp_primitives::lsps0::common_schemas::Outpoint;
+
+/// Selects the outputs of the wallet that belong to a funding source
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct UtxoFilter {
+    /// Outputs formatted as `txid:vout`
+    outpoints: BTreeSet<String>,
+    /// Outputs that pay to one of these addresses
+    addresses: BTreeSet<String>,
+}
+
+impl UtxoFilter {
+    pub(crate) fn matches(&self, output: &ListfundsOutputs) -> bool {
+        let outpoint = format!("{}:{}", output.txid, output.output);
+        self.outpoints.contains(&outpoint)
+            || output
+                .address
+                .as_ref()
+                .is_some_and(|address| self.addresses.contains(address))
+    }
+
+    /// The confirmed outputs that match and aren't reserved
+    pub(crate) fn spendable<'a>(
+        &'a self,
+        outputs: &'a [ListfundsOutputs],
+    ) -> impl Iterator<Item = &'a ListfundsOutputs> {
+        outputs.iter().filter(|output| {
+            output.status == ListfundsOutputsStatus::CONFIRMED
+                && !output.reserved
+                && self.matches(output)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FundingSource {
+    pub(crate) label: String,
+    pub(crate) filter: UtxoFilter,
+    /// The maximum capacity of the orders that wait for a channel
+    ///
+    /// `None` if only the funds of the source limit the orders
+    pub(crate) budget_sat: Option<u64>,
+    /// The tokens that may pick this source
+    tokens: BTreeSet<String>,
+}
+
+/// The token may not pick the funding source
+///
+/// Unknown sources are reported the same way. A client can't find out
+/// which labels exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SourceNotAllowed {
+    pub(crate) label: String,
+}
+
+impl std::fmt::Display for SourceNotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The token may not use funding source '{}'", self.label)
+    }
+}
+
+/// An order would exceed the budget of its funding source
+///
+/// Returned wrapped in an [`anyhow::Error`] by the transaction that
+/// creates the order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FundingBudgetExceeded {
+    pub(crate) label: String,
+    pub(crate) committed_sat: u64,
+    pub(crate) needed_sat: u64,
+    pub(crate) budget_sat: u64,
+}
+
+impl std::fmt::Display for FundingBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Funding source '{}' has {} of {} sat committed and can't fund another {} sat",
+            self.label, self.committed_sat, self.budget_sat, self.needed_sat
+        )
+    }
+}
+
+impl std::error::Error for FundingBudgetExceeded {}
+
+impl FundingSource {
+    /// Fails if the orders that wait for a channel and `needed_sat` exceed the budget
+    pub(crate) fn check_budget(&self, committed_sat: u64, needed_sat: u64) -> Result<()> {
+        let Some(budget_sat) = self.budget_sat else {
+            return Ok(());
+        };
+        if committed_sat.saturating_add(needed_sat) > budget_sat {
+            return Err(FundingBudgetExceeded {
+                label: self.label.clone(),
+                committed_sat,
+                needed_sat,
+                budget_sat,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The funding sources of `lsps1-funding-sources-file`
+///
+/// The file is read at start-up and by [`FundingSources::reload`]. If the
+/// file can't be read during a reload the previous sources are kept.
+#[derive(Default)]
+pub(crate) struct FundingSources {
+    path: Option<PathBuf>,
+    /// The label of `lsps1-funding-source-label`
+    default_label: Option<String>,
+    sources: RwLock<BTreeMap<String, Arc<FundingSource>>>,
+}
+
+impl FundingSources {
+    /// Reads the configured file
+    ///
+    /// Fails if the file can't be read or doesn't define the default label
+    pub(crate) fn load(path: Option<PathBuf>, default_label: Option<String>) -> Result<Self> {
+        let sources = Self {
+            path,
+            default_label,
+            sources: RwLock::new(BTreeMap::new()),
+        };
+        let count = sources.reload()?;
+        log::info!("Loaded {} funding sources", count);
+        Ok(sources)
+    }
+
+    /// Re-reads the file and returns the number of sources
+    pub(crate) fn reload(&self) -> Result<usize> {
+        let sources = match &self.path {
+            Some(path) => read_funding_sources(path)?,
+            None => BTreeMap::new(),
+        };
+        if let Some(label) = &self.default_label {
+            anyhow::ensure!(
+                sources.contains_key(label),
+                "The default funding source '{}' is not configured",
+                label
+            );
+        }
+
+        let count = sources.len();
+        *self.sources.write().unwrap() = sources;
+        Ok(count)
+    }
+
+    pub(crate) fn get(&self, label: &str) -> Option<Arc<FundingSource>> {
+        self.sources.read().unwrap().get(label).cloned()
+    }
+
+    /// All sources ordered by label
+    pub(crate) fn all(&self) -> Vec<Arc<FundingSource>> {
+        self.sources.read().unwrap().values().cloned().collect()
+    }
+
+    pub(crate) fn default_label(&self) -> Option<&str> {
+        self.default_label.as_deref()
+    }
+
+    /// The source that funds a new order
+    ///
+    /// A client that requests a source must use a token of that source.
+    /// Otherwise the default source is used. Returns `None` if there is
+    /// no default source.
+    pub(crate) fn select(
+        &self,
+        requested: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<Option<Arc<FundingSource>>, SourceNotAllowed> {
+        let Some(label) = requested else {
+            return Ok(self.default_label().and_then(|label| self.get(label)));
+        };
+
+        match (self.get(label), token) {
+            (Some(source), Some(token)) if source.tokens.contains(token) => Ok(Some(source)),
+            _ => Err(SourceNotAllowed {
+                label: label.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FundingSourceConfig {
+    #[serde(default)]
+    outpoints: Vec<String>,
+    #[serde(default)]
+    addresses: Vec<String>,
+    budget_sat: Option<u64>,
+    #[serde(default)]
+    tokens: Vec<String>,
+}
+
+fn read_funding_sources(path: &Path) -> Result<BTreeMap<String, Arc<FundingSource>>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_funding_sources(&content)
+        .with_context(|| format!("Invalid funding sources {}", path.display()))
+}
+
+/// Parses a JSON-object that maps labels to sources
+///
+/// An output or address may only belong to a single source
+fn parse_funding_sources(content: &str) -> Result<BTreeMap<String, Arc<FundingSource>>> {
+    let configs: BTreeMap<String, FundingSourceConfig> =
+        serde_json::from_str(content).context("Expected a JSON-object of funding sources")?;
+
+    let mut owners: HashMap<String, String> = HashMap::new();
+    let mut sources = BTreeMap::new();
+    for (label, config) in configs {
+        anyhow::ensure!(!label.is_empty(), "A funding source has an empty label");
+        anyhow::ensure!(
+            !config.outpoints.is_empty() || !config.addresses.is_empty(),
+            "Funding source '{}' has neither outpoints nor addresses",
+            label
+        );
+
+        let mut filter = UtxoFilter::default();
+        for outpoint in &config.outpoints {
+            let outpoint = Outpoint::from_str(outpoint)
+                .with_context(|| format!("Invalid outpoint in funding source '{}'", label))?;
+            filter.outpoints.insert(outpoint.to_string());
+        }
+        filter.addresses.extend(config.addresses);
+
+        for owned in filter.outpoints.iter().chain(filter.addresses.iter()) {
+            if let Some(owner) = owners.insert(owned.clone(), label.clone()) {
+                anyhow::bail!(
+                    "'{}' belongs to funding sources '{}' and '{}'",
+                    owned,
+                    owner,
+                    label
+                );
+            }
+        }
+
+        let source = FundingSource {
+            label: label.clone(),
+            filter,
+            budget_sat: config.budget_sat,
+            tokens: config.tokens.into_iter().collect(),
+        };
+        sources.insert(label, Arc::new(source));
+    }
+    Ok(sources)
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// Funding sources parsed from `content` instead of a file
+    pub(crate) fn funding_sources(
+        content: serde_json::Value,
+        default_label: Option<&str>,
+    ) -> FundingSources {
+        FundingSources {
+            path: None,
+            default_label: default_label.map(str::to_string),
+            sources: RwLock::new(parse_funding_sources(&content.to_string()).unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    const TXID_A: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    const TXID_B: &str = "8a3a2d3e3f5bd1e5c3b3a1b9c4d0c7d1e9f2a3b4c5d6e7f8091a2b3c4d5e6f70";
+
+    fn sources_json() -> String {
+        json!({
+            "hot" : { "addresses" : ["bcrt1qhot"] },
+            "partner" : {
+                "outpoints" : [format!("{}:0", TXID_A.to_uppercase())],
+                "budget_sat" : 500_000,
+                "tokens" : ["partner-token"],
+            },
+        })
+        .to_string()
+    }
+
+    /// Writes `content` to a new file in the temp directory
+    fn write_temp_file(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("funding-sources-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn output(txid: &str, vout: u32, address: &str, reserved: bool) -> ListfundsOutputs {
+        serde_json::from_value(json!({
+            "txid" : txid,
+            "output" : vout,
+            "amount_msat" : 100_000_000,
+            "scriptpubkey" : "0014b6de7a9decf14629207cb32832fc9554678619f4",
+            "address" : address,
+            "status" : "confirmed",
+            "reserved" : reserved,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn filters_select_the_outputs_of_their_source() {
+        let sources = parse_funding_sources(&sources_json()).unwrap();
+        let hot = &sources["hot"].filter;
+        let partner = &sources["partner"].filter;
+
+        let outputs = vec![
+            output(TXID_A, 0, "bcrt1qpartner", false),
+            output(TXID_A, 1, "bcrt1qhot", false),
+            output(TXID_B, 0, "bcrt1qhot", true),
+            output(TXID_B, 1, "bcrt1qother", false),
+        ];
+        // The outpoint matches although it was configured in uppercase
+        let spendable: Vec<u32> = partner.spendable(&outputs).map(|o| o.output).collect();
+        assert_eq!(spendable, vec![0]);
+        // Reserved outputs can't be spent
+        assert!(hot.matches(&outputs[2]));
+        let spendable: Vec<u32> = hot.spendable(&outputs).map(|o| o.output).collect();
+        assert_eq!(spendable, vec![1]);
+    }
+
+    #[test]
+    fn outputs_belong_to_a_single_source() {
+        let content = json!({
+            "a" : { "addresses" : ["bcrt1qshared"] },
+            "b" : { "addresses" : ["bcrt1qshared"] },
+        });
+        let err = parse_funding_sources(&content.to_string()).unwrap_err();
+        assert!(err.to_string().contains("bcrt1qshared"), "{}", err);
+
+        let content = json!({ "empty" : { "budget_sat" : 1 } });
+        let err = parse_funding_sources(&content.to_string()).unwrap_err();
+        assert!(err.to_string().contains("neither"), "{}", err);
+
+        let content = json!({ "bad" : { "outpoints" : ["not-an-outpoint"] } });
+        assert!(parse_funding_sources(&content.to_string()).is_err());
+    }
+
+    #[test]
+    fn only_privileged_tokens_pick_a_source() {
+        let path = write_temp_file(&sources_json());
+        let sources = FundingSources::load(Some(path.clone()), Some("hot".to_string())).unwrap();
+
+        let default = sources.select(None, Some("partner-token")).unwrap();
+        assert_eq!(default.unwrap().label, "hot");
+        let partner = sources
+            .select(Some("partner"), Some("partner-token"))
+            .unwrap();
+        assert_eq!(partner.unwrap().label, "partner");
+
+        // Unknown sources and other tokens get the same error
+        for (label, token) in [
+            ("partner", Some("other-token")),
+            ("partner", None),
+            ("hot", Some("partner-token")),
+            ("missing", Some("partner-token")),
+        ] {
+            let err = sources.select(Some(label), token).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("The token may not use funding source '{}'", label)
+            );
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn default_label_must_be_configured() {
+        let err = FundingSources::load(None, Some("hot".to_string()))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("'hot'"), "{}", err);
+
+        // Orders have no source without a file
+        let sources = FundingSources::load(None, None).unwrap();
+        assert_eq!(sources.select(None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn budgets_are_checked_per_source() {
+        let sources = parse_funding_sources(&sources_json()).unwrap();
+        let partner = &sources["partner"];
+        assert!(partner.check_budget(400_000, 100_000).is_ok());
+
+        let err = partner.check_budget(400_000, 100_001).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FundingBudgetExceeded>(),
+            Some(&FundingBudgetExceeded {
+                label: "partner".to_string(),
+                committed_sat: 400_000,
+                needed_sat: 100_001,
+                budget_sat: 500_000,
+            })
+        );
+
+        // A source without a budget is only limited by its funds
+        assert!(sources["hot"].check_budget(u64::MAX, 1).is_ok());
+    }
+
+    #[test]
+    fn reload_keeps_sources_if_the_file_is_invalid() {
+        let path = write_temp_file(&sources_json());
+        let sources = FundingSources::load(Some(path.clone()), None).unwrap();
+        assert_eq!(sources.all().len(), 2);
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(sources.reload().is_err());
+        assert!(sources.get("partner").is_some());
+
+        std::fs::write(
+            &path,
+            json!({ "cold" : { "addresses" : ["a"] } }).to_string(),
+        )
+        .unwrap();
+        assert_eq!(sources.reload().unwrap(), 1);
+        assert!(sources.get("partner").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
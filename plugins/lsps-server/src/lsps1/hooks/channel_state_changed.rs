@@ -0,0 +1,701 @@
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::ListpeerchannelsRequest;
+use cln_rpc::ClnRpc;
+use uuid::Uuid;
+
+use crate::cln::channel_id::{derive_channel_id, short_channel_id_blockheight};
+use crate::cln::notifications::channel_state_changed::ChannelStateChangedNotification;
+use crate::cln::public_key::to_rpc_public_key;
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::Clock;
+use crate::db::schema::{Lsps1FailureReason, Lsps1OrderState};
+use crate::db::sqlite::queries::{
+    ActiveLease, ChannelOpeningOrder, GetActiveLeasesQuery, GetChannelOpeningOrdersQuery,
+};
+use crate::db::sqlite::queries::{SetFundingBlockheightQuery, UpdateOrderStateQuery};
+use crate::db::sqlite::Database;
+use crate::lsps1::order_watcher::OrderWatcher;
+use crate::lsps1::receipt::try_issue_receipt;
+use crate::lsps1::refund::refund_order;
+use crate::state::PluginState;
+
+/// How often the funding height of zero-conf channels is looked up
+pub(crate) const FUNDING_BLOCKHEIGHT_BACKFILL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(600);
+
+/// Completes or fails the order that corresponds to the channel
+pub(crate) async fn channel_state_changed(
+    plugin: Plugin<PluginState>,
+    notification: &ChannelStateChangedNotification,
+) -> Result<()> {
+    let state = plugin.state();
+    let order_uuid = process_channel_state_change(
+        &state.database,
+        &state.order_watcher,
+        state.clock.as_ref(),
+        notification,
+    )
+    .await?;
+
+    if let Some(order_uuid) = order_uuid {
+        let rpc_path = plugin.configuration().rpc_file;
+        let mut rpc = ClnRpc::new(rpc_path).await?;
+        if notification.is_aborted() {
+            refund_order(state, &mut rpc, order_uuid).await?;
+        } else {
+            try_issue_receipt(state, &mut rpc, order_uuid).await;
+        }
+    }
+    Ok(())
+}
+
+/// Completes or fails `order` based on the current state of its channel
+///
+/// This catches up on notifications that were missed. E.g: because the
+/// plugin wasn't running or because the channel became ready before
+/// the order was moved to `ChannelOpening`.
+pub(crate) async fn check_channel_opening_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order: &ChannelOpeningOrder,
+) -> Result<()> {
+    let notification = match get_channel_state(rpc, order).await? {
+        Some(notification) => notification,
+        None => {
+            log::warn!(
+                "Failed to find channel {}:{} for order {}",
+                order.channel.funding_txid,
+                order.channel.outnum,
+                order.order_uuid
+            );
+            return Ok(());
+        }
+    };
+
+    let order_uuid = process_channel_state_change(
+        &state.database,
+        &state.order_watcher,
+        state.clock.as_ref(),
+        &notification,
+    )
+    .await?;
+
+    if let Some(order_uuid) = order_uuid {
+        if notification.is_aborted() {
+            refund_order(state, rpc, order_uuid).await?;
+        } else {
+            try_issue_receipt(state, rpc, order_uuid).await;
+        }
+    }
+    Ok(())
+}
+
+/// Updates the order that corresponds to the channel in the notification
+///
+/// Returns the uuid of the order that was updated. Returns `None`
+/// if the channel doesn't belong to an order in `ChannelOpening`
+/// or if the order doesn't need to be updated yet.
+///
+/// An order whose channel was aborted is `Failed`. The caller must
+/// refund it using [`refund_order`].
+pub(crate) async fn process_channel_state_change(
+    db: &Database,
+    order_watcher: &OrderWatcher,
+    clock: &dyn Clock,
+    notification: &ChannelStateChangedNotification,
+) -> Result<Option<Uuid>> {
+    if !notification.is_ready() && !notification.is_aborted() {
+        return Ok(None);
+    }
+
+    let mut tx = db.begin().await?;
+    let orders = GetChannelOpeningOrdersQuery::by_peer_id(notification.peer_id)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_channel_opening_orders'-query on database")?;
+
+    let order = find_order_by_channel_id(&orders, &notification.channel_id)?;
+    let order = match order {
+        Some(order) => order,
+        None => {
+            tx.commit().await?;
+            return Ok(None);
+        }
+    };
+
+    if notification.is_ready() {
+        log::info!(
+            "Channel {} for order {} is ready",
+            notification.channel_id,
+            order.order_uuid
+        );
+        UpdateOrderStateQuery {
+            order_uuid: order.order_uuid,
+            state: Lsps1OrderState::Completed,
+            generation: order.generation,
+            changed_at: clock.now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await?;
+
+        // The lease starts once the funding transaction confirmed.
+        // A missing height is filled in by `backfill_funding_blockheights`
+        let funding_blockheight = notification
+            .short_channel_id
+            .as_deref()
+            .map(short_channel_id_blockheight);
+        match funding_blockheight {
+            Some(Ok(funding_blockheight)) => {
+                SetFundingBlockheightQuery {
+                    order_uuid: order.order_uuid,
+                    funding_blockheight,
+                }
+                .execute(&mut tx)
+                .await?;
+            }
+            Some(Err(err)) => log::warn!(
+                "Channel {} for order {} has an invalid short_channel_id. The start of the lease is unknown: {:?}",
+                notification.channel_id,
+                order.order_uuid,
+                err
+            ),
+            None => log::info!(
+                "Channel {} for order {} has no short_channel_id yet. The start of the lease is stored once it confirms",
+                notification.channel_id,
+                order.order_uuid
+            ),
+        }
+    } else {
+        log::warn!(
+            "Channel {} for order {} was aborted in state {} (cause={}, message={:?}). The payment will be refunded",
+            notification.channel_id,
+            order.order_uuid,
+            notification.new_state,
+            notification.cause,
+            notification.message
+        );
+        UpdateOrderStateQuery {
+            order_uuid: order.order_uuid,
+            state: Lsps1OrderState::Failed,
+            generation: order.generation,
+            changed_at: clock.now(),
+            failure_reason: Some(Lsps1FailureReason::ChannelAborted),
+        }
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    order_watcher.notify(order.order_uuid);
+    Ok(Some(order.order_uuid))
+}
+
+fn find_order_by_channel_id<'a>(
+    orders: &'a [ChannelOpeningOrder],
+    channel_id: &str,
+) -> Result<Option<&'a ChannelOpeningOrder>> {
+    for order in orders {
+        let order_channel_id =
+            derive_channel_id(&order.channel.funding_txid, order.channel.outnum)?;
+        if order_channel_id.eq_ignore_ascii_case(channel_id) {
+            return Ok(Some(order));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks the state of all channels that are still opening
+///
+/// Notifications are missed while the plugin isn't running.
+/// We use `listpeerchannels` to catch up.
+pub(crate) async fn reconcile_channel_opening_orders(plugin: &Plugin<PluginState>) -> Result<()> {
+    let state = plugin.state();
+
+    let orders = state
+        .database
+        .with_tx("get_channel_opening_orders", |tx| {
+            Box::pin(async move {
+                GetChannelOpeningOrdersQuery::all()
+                    .execute(tx)
+                    .await
+                    .context("Failed to execute 'get_channel_opening_orders'-query on database")
+            })
+        })
+        .await?;
+
+    if orders.is_empty() {
+        return Ok(());
+    }
+
+    let rpc_path = plugin.configuration().rpc_file;
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+
+    for order in orders {
+        if let Err(err) = check_channel_opening_order(state, &mut rpc, &order).await {
+            log::warn!(
+                "Failed to update channel state for order {}: {:?}",
+                order.order_uuid,
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the funding height of completed orders for which it is unknown
+///
+/// A zero-conf channel is ready before its funding transaction confirms.
+/// The notification has no short_channel_id in that case. We read it
+/// using `listpeerchannels` once the transaction has confirmed.
+/// Returns the number of leases that were updated.
+pub(crate) async fn backfill_funding_blockheights(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+) -> Result<usize> {
+    // Every lease is active at height 0
+    let mut tx = state.database.begin().await?;
+    let leases: Vec<ActiveLease> = GetActiveLeasesQuery::at_blockheight(0)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_active_leases'-query on database")?
+        .into_iter()
+        .filter(|lease| lease.funding_blockheight.is_none())
+        .collect();
+    tx.commit().await?;
+
+    if leases.is_empty() {
+        return Ok(0);
+    }
+
+    let response = rpc
+        .listpeerchannels(&ListpeerchannelsRequest { id: None })
+        .await
+        .context("Failed to call 'listpeerchannels'")?;
+    let channels = response.channels.unwrap_or_default();
+
+    let mut updated = 0;
+    let mut tx = state.database.begin().await?;
+    for lease in leases {
+        let funding_txid = lease.channel.funding_txid.to_string();
+        let short_channel_id = channels
+            .iter()
+            .find(|c| {
+                c.funding_txid.as_deref() == Some(funding_txid.as_str())
+                    && c.funding_outnum == Some(lease.channel.outnum)
+            })
+            .and_then(|c| c.short_channel_id);
+
+        if let Some(short_channel_id) = short_channel_id {
+            let stored = SetFundingBlockheightQuery {
+                order_uuid: lease.order_uuid,
+                funding_blockheight: short_channel_id.block(),
+            }
+            .execute(&mut tx)
+            .await?;
+            if stored {
+                updated += 1;
+            }
+        }
+    }
+    tx.commit().await?;
+
+    Ok(updated)
+}
+
+/// Describes the current state of the channel as if it was a notification
+async fn get_channel_state(
+    rpc: &mut dyn ClnRpcApi,
+    order: &ChannelOpeningOrder,
+) -> Result<Option<ChannelStateChangedNotification>> {
+    let request = ListpeerchannelsRequest {
+        id: Some(to_rpc_public_key(&order.client_node_id)?),
+    };
+    let response = rpc
+        .listpeerchannels(&request)
+        .await
+        .context("Failed to call 'listpeerchannels'")?;
+
+    let funding_txid = order.channel.funding_txid.to_string();
+    let channel = response.channels.unwrap_or_default().into_iter().find(|c| {
+        c.funding_txid.as_deref() == Some(funding_txid.as_str())
+            && c.funding_outnum == Some(order.channel.outnum)
+    });
+
+    let channel = match channel {
+        Some(channel) => channel,
+        None => return Ok(None),
+    };
+    let new_state = match channel.state {
+        Some(state) => serde_json::to_value(state)?
+            .as_str()
+            .context("Channel state should be a string")?
+            .to_string(),
+        None => return Ok(None),
+    };
+
+    Ok(Some(ChannelStateChangedNotification {
+        peer_id: order.client_node_id,
+        channel_id: derive_channel_id(&order.channel.funding_txid, order.channel.outnum)?,
+        short_channel_id: channel.short_channel_id.map(|scid| scid.to_string()),
+        old_state: None,
+        new_state,
+        cause: "unknown".to_string(),
+        message: Some("Retrieved using listpeerchannels".to_string()),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, TransactionId};
+    use lsp_primitives::lsps1::schema::PaymentState;
+    use serde_json::json;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::SystemClock;
+    use crate::db::schema::{Lsps1Channel, OrderGeneration};
+    use crate::db::sqlite::queries::{
+        CreateChannelQuery, GetOrderQuery, GetPaymentDetailsQuery, GetReceiptQuery,
+        UpdatePaymentStateQuery,
+    };
+    use crate::db::sqlite::test::{create_order_query, get_db, get_temp_db};
+    use crate::state::test_support::test_state;
+
+    const REFUND_ADDRESS: &str = "bcrt1qxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzx";
+
+    /// Creates a paid order in `ChannelOpening` and returns its uuid and channel_id
+    async fn create_channel_opening_order(db: &Database) -> (Uuid, String) {
+        let mut query = create_order_query();
+        query.order.refund_onchain_address = Some(REFUND_ADDRESS.to_string());
+        let order_uuid = query.order.uuid;
+        let payment = query.payment.clone();
+
+        // A random funding_txid ensures the channel_id is unique
+        let txid = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(&txid).unwrap(),
+            outnum: 1,
+            funded_at: IsoDatetime::now(),
+        };
+        let channel_id = derive_channel_id(&channel.funding_txid, channel.outnum).unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            changed_at: IsoDatetime::now(),
+            generation: payment.generation,
+            label: payment.bolt11_invoice_label,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        CreateChannelQuery::new(order_uuid, channel)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid,
+            state: Lsps1OrderState::ChannelOpening,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        (order_uuid, channel_id)
+    }
+
+    fn notification(channel_id: &str, new_state: &str) -> ChannelStateChangedNotification {
+        ChannelStateChangedNotification {
+            peer_id: create_order_query().order.client_node_id,
+            channel_id: channel_id.to_string(),
+            short_channel_id: None,
+            old_state: None,
+            new_state: new_state.to_string(),
+            cause: "remote".to_string(),
+            message: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn order_completes_when_channel_is_ready() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let (order_uuid, channel_id) = create_channel_opening_order(&db).await;
+
+        // The channel isn't ready yet
+        let result = process_channel_state_change(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            &notification(&channel_id, "CHANNELD_AWAITING_LOCKIN"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, None);
+
+        let result = process_channel_state_change(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            &notification(&channel_id, "CHANNELD_NORMAL"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, Some(order_uuid));
+
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(order.order_state, Lsps1OrderState::Completed);
+        assert_eq!(order.failure_reason, None);
+    }
+
+    #[tokio::test]
+    async fn order_fails_when_channel_is_aborted() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let (order_uuid, channel_id) = create_channel_opening_order(&db).await;
+
+        let result = process_channel_state_change(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            &notification(&channel_id, "ONCHAIN"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, Some(order_uuid));
+
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(
+            order.failure_reason,
+            Some(Lsps1FailureReason::ChannelAborted)
+        );
+        // The caller broadcasts the refund
+        assert_eq!(payment.state, PaymentState::Paid);
+    }
+
+    async fn get_channel_opening_order(db: &Database, order_uuid: Uuid) -> ChannelOpeningOrder {
+        let mut tx = db.begin().await.unwrap();
+        let orders = GetChannelOpeningOrdersQuery::all()
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        orders
+            .into_iter()
+            .find(|o| o.order_uuid == order_uuid)
+            .unwrap()
+    }
+
+    fn listpeerchannels(order: &ChannelOpeningOrder, state: &str) -> serde_json::Value {
+        json!({
+            "channels" : [{
+                "funding_txid" : order.channel.funding_txid.to_string(),
+                "funding_outnum" : order.channel.outnum,
+                "state" : state,
+            }]
+        })
+    }
+
+    #[tokio::test]
+    async fn check_completes_order_whose_channel_is_ready() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let (order_uuid, _) = create_channel_opening_order(&db).await;
+        let order = get_channel_opening_order(&db, order_uuid).await;
+
+        // The channel became ready before the order was in `ChannelOpening`
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(&order, "CHANNELD_NORMAL"),
+        )
+        .respond(
+            "signmessage",
+            json!({ "signature" : "00".repeat(64), "recid" : "00", "zbase" : "d6tqaeuo" }),
+        );
+        check_channel_opening_order(&state, &mut rpc, &order)
+            .await
+            .unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        let receipt = GetReceiptQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(order.order_state, Lsps1OrderState::Completed);
+
+        // The completed order has a signed receipt
+        assert_eq!(
+            rpc.params_of("signmessage").unwrap()["message"],
+            receipt.payload
+        );
+        assert_eq!(receipt.zbase_signature, "d6tqaeuo");
+    }
+
+    #[tokio::test]
+    async fn check_refunds_order_whose_channel_is_aborted() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let (order_uuid, _) = create_channel_opening_order(&db).await;
+        let order = get_channel_opening_order(&db, order_uuid).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeerchannels", listpeerchannels(&order, "ONCHAIN"))
+            .respond(
+                "withdraw",
+                json!({
+                    "tx" : "0200000000000000000000",
+                    "txid" : "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                    "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+                }),
+            );
+        check_channel_opening_order(&state, &mut rpc, &order)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            rpc.params_of("withdraw").unwrap()["destination"],
+            REFUND_ADDRESS
+        );
+        let mut tx = db.begin().await.unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(payment.state, PaymentState::Refunded);
+    }
+
+    async fn get_lease(db: &Database, order_uuid: Uuid) -> ActiveLease {
+        let mut tx = db.begin().await.unwrap();
+        let leases = GetActiveLeasesQuery::at_blockheight(0)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        leases
+            .into_iter()
+            .find(|l| l.order_uuid == order_uuid)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalid_short_channel_id_still_completes_order() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let (order_uuid, channel_id) = create_channel_opening_order(&db).await;
+
+        let mut notification = notification(&channel_id, "CHANNELD_NORMAL");
+        notification.short_channel_id = Some("not-a-scid".to_string());
+        let result = process_channel_state_change(&db, &order_watcher, &SystemClock, &notification)
+            .await
+            .unwrap();
+        assert_eq!(result, Some(order_uuid));
+
+        assert_eq!(get_lease(&db, order_uuid).await.funding_blockheight, None);
+    }
+
+    #[tokio::test]
+    async fn funding_blockheight_of_zero_conf_channel_is_backfilled() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let (order_uuid, channel_id) = create_channel_opening_order(&db).await;
+        let order = get_channel_opening_order(&db, order_uuid).await;
+
+        // The zero-conf channel is ready before the funding transaction confirmed
+        process_channel_state_change(
+            &db,
+            &state.order_watcher,
+            &SystemClock,
+            &notification(&channel_id, "CHANNELD_NORMAL"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(get_lease(&db, order_uuid).await.funding_blockheight, None);
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            json!({
+                "channels" : [{
+                    "funding_txid" : order.channel.funding_txid.to_string(),
+                    "funding_outnum" : order.channel.outnum,
+                    "short_channel_id" : "103x1x1",
+                    "state" : "CHANNELD_NORMAL",
+                }]
+            }),
+        );
+        let updated = backfill_funding_blockheights(&state, &mut rpc)
+            .await
+            .unwrap();
+        assert_eq!(updated, 1);
+        assert_eq!(
+            get_lease(&db, order_uuid).await.funding_blockheight,
+            Some(103)
+        );
+
+        // Leases with a known height don't need lightningd
+        let mut rpc = FakeClnRpc::default();
+        let updated = backfill_funding_blockheights(&state, &mut rpc)
+            .await
+            .unwrap();
+        assert_eq!(updated, 0);
+        assert!(rpc.called_methods().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_channel_is_ignored() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+
+        let channel_id = "00".repeat(32);
+        let result = process_channel_state_change(
+            &db,
+            &order_watcher,
+            &SystemClock,
+            &notification(&channel_id, "CHANNELD_NORMAL"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, None);
+    }
+}
@@ -0,0 +1,17 @@
+use anyhow::Result;
+use cln_plugin::Plugin;
+
+use crate::cln::notifications::connect::ConnectNotification;
+use crate::lsps1::channel_open::enqueue_pending_orders;
+use crate::state::PluginState;
+
+/// Opens the channels for paid orders of a client that just reconnected
+pub(crate) async fn connect(
+    plugin: Plugin<PluginState>,
+    notification: &ConnectNotification,
+) -> Result<()> {
+    log::debug!("Peer {:?} connected", notification.id);
+    let state = plugin.state();
+    enqueue_pending_orders(&state.database, &state.open_queue, Some(notification.id)).await?;
+    Ok(())
+}
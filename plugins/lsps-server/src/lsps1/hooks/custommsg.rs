@@ -1,56 +1,136 @@
-use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use cln_plugin::Plugin;
+use serde_json::json;
 use uuid::Uuid;
 
 use lsp_primitives::methods;
 
-use lsp_primitives::json_rpc::ErrorData;
-use lsp_primitives::lsps0::common_schemas::{IsoDatetime, NetworkCheckable, Outpoint};
+use lsp_primitives::json_rpc::{ErrorData, LspsErrorReason};
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, Network, NetworkCheckable, PublicKey};
 use lsp_primitives::lsps0::parameter_validation::ParamValidationError;
 use lsp_primitives::lsps1::builders::Lsps1CreateOrderResponseBuilder;
+use lsp_primitives::lsps1::receipt::OrderReceipt;
 use lsp_primitives::lsps1::schema::{
-    Channel, Lsps1CreateOrderResponse, Lsps1GetInfoResponse, OrderState, Payment
+    ChannelTypeFeature, Lsps1CreateOrderRequest, Lsps1CreateOrderResponse, Lsps1GetInfoResponse,
+    OrderState, PaymentState, Refund, MAX_WAIT_FOR_CHANGE_SECONDS,
 };
+use lsp_primitives::lsps1::util::ExpiryMode;
 
+use crate::cln::rpc_api::ClnRpcApi;
 use crate::custom_msg::context::CustomMsgContext;
-use crate::db::schema::Lsps1Order;
+use crate::custom_msg::error::HandlerError;
+use crate::db::schema::{
+    Lsps1Channel, Lsps1Order, Lsps1OrderState, Lsps1PaymentDetails, Lsps1Refund, OrderGeneration,
+    OrderLogEvent,
+};
 use crate::db::sqlite::queries::{
-    GetChannelQuery, GetOrderQuery, GetPaymentDetailsQuery, Lsps1CreateOrderQuery,
+    GetChannelQuery, GetChannelTypeQuery, GetCommittedLiquidityQuery, GetOrderQuery,
+    GetPaymentDetailsQuery, GetReceiptQuery, GetRefundQuery, Lsps1CreateOrderQuery,
+    SetChannelTypeQuery, SetClientNodeQuery, SetFundingSourceQuery,
 };
-use crate::lsps1::fee_calc::StandardFeeCalculator;
-use crate::lsps1::msg::{BuildLsps1Order, BuildUsingDbPayment};
+use crate::db::sqlite::{retry_on_busy, Database};
+use crate::lsps1::channel_type::{lookup_peer_features, AllowedChannelTypes};
+use crate::lsps1::client_node::lookup_client_node;
+use crate::lsps1::confirmation_policy::ConfirmationPolicy;
+use crate::lsps1::fee_calc::{FeeCalculator, StandardFeeCalculator};
+use crate::lsps1::funding_source::FundingBudgetExceeded;
+use crate::lsps1::msg::{BuildLsps1Order, BuildUsingDbRefund};
+use crate::lsps1::onchain_payment::OnchainPaymentConfig;
+use crate::lsps1::order_watcher::OrderWatcher;
 use crate::lsps1::payment_calc::PaymentCalc;
+use crate::lsps1::refund_address::AcceptedRefundAddressTypes;
 use crate::{options, PluginState};
 
+/// Clients are asked to retry this long if LSPS1 is disabled
+pub(crate) const LSPS1_DISABLED_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+/// The value of the `lsps1-disabled-response` option
+///
+/// Determines what a client sees while LSPS1 is disabled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum DisabledResponse {
+    /// The server acts as if it doesn't implement LSPS1
+    #[default]
+    NotFound,
+    /// The client is asked to retry later. E.g: during maintenance
+    Unavailable,
+}
+
+impl std::str::FromStr for DisabledResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "not_found" => Ok(Self::NotFound),
+            "unavailable" => Ok(Self::Unavailable),
+            _ => Err(anyhow!(
+                "Expected 'not_found' or 'unavailable' but found '{}'",
+                value
+            )),
+        }
+    }
+}
+
+impl DisabledResponse {
+    /// The error that is returned for a call to `method` while LSPS1 is disabled
+    pub(crate) fn error(&self, method: &str) -> ErrorData {
+        match self {
+            Self::NotFound => ErrorData::method_not_found(method),
+            Self::Unavailable => {
+                ErrorData::temporarily_unavailable().with_retry_after(LSPS1_DISABLED_RETRY_AFTER)
+            }
+        }
+    }
+}
+
 pub(crate) async fn check_lsps1_enabled(
     context: &mut CustomMsgContext<PluginState>,
-) -> Result<(), ErrorData> {
-    let lsps1_enabled = context.plugin.option(&options::lsps1_enable()).unwrap();
-
-    if lsps1_enabled {
-        Ok(())
-    } else {
+) -> Result<(), HandlerError> {
+    if !context.options.lsps1_enabled {
         log::debug!("Ignored call because lsps1 is disabled");
-        Err(ErrorData::method_not_found(&context.request.method))
+        let disabled_response = context.state.lsps1_disabled_response;
+        Err(disabled_response.error(&context.request.method).into())
+    } else if !context.state.is_lsps1_allowed_for(&context.peer_id) {
+        // LSPS1 isn't listed in `lsps0.list_protocols` for this peer
+        log::debug!("Ignored call because lsps1 is not allowed for this peer");
+        Err(ErrorData::method_not_found(&context.request.method).into())
+    } else {
+        Ok(())
     }
 }
 
 pub(crate) async fn do_lsps1_get_info(
     method: methods::Lsps1GetInfo,
     context: &mut CustomMsgContext<PluginState>,
-) -> Result<Lsps1GetInfoResponse, ErrorData> {
+) -> Result<Arc<Lsps1GetInfoResponse>, HandlerError> {
     log::debug!("lsps1_get_info");
 
     check_lsps1_enabled(context).await?;
     method.into_typed_request(context.request.clone())?;
-    let state = context.plugin.state();
-    let info_response = state.lsps1_info.as_ref().clone();
-    info_response.ok_or_else(|| ErrorData::method_not_found(method.name()))
+
+    get_info(&context.state, context.options.hide_info_when_unhealthy)
+}
+
+/// Returns the shared info. The response is serialized from it without copying
+pub(crate) fn get_info(
+    state: &PluginState,
+    hide_info: bool,
+) -> Result<Arc<Lsps1GetInfoResponse>, HandlerError> {
+    if hide_info && !state.health.is_healthy() {
+        return Err(ErrorData::temporarily_unavailable().into());
+    }
+    state
+        .lsps1_info()
+        .ok_or_else(|| ErrorData::method_not_found(methods::LSPS1_GETINFO.name()).into())
 }
 
 pub(crate) async fn do_lsps1_create_order(
     method: methods::Lsps1CreateOrder,
     context: &mut CustomMsgContext<PluginState>,
-) -> Result<Lsps1CreateOrderResponse, ErrorData> {
+) -> Result<Lsps1CreateOrderResponse, HandlerError> {
     log::debug!(
         "Handling lsps1.create_order from peer={:?}",
         context.peer_id
@@ -59,38 +139,175 @@ pub(crate) async fn do_lsps1_create_order(
     check_lsps1_enabled(context).await?;
     let typed_request = method.into_typed_request(context.request.clone())?;
 
-    let state = context.plugin.state();
+    // The client node is looked up on a separate connection while
+    // the invoice is created. The order doesn't depend on it
+    let mut lookup_rpc = match context.connector.connect().await {
+        Ok(rpc) => Some(rpc),
+        Err(err) => {
+            log::debug!("Can't look up the client node: {:?}", err);
+            None
+        }
+    };
+    create_order(
+        &context.state,
+        context.cln_rpc.as_mut(),
+        lookup_rpc
+            .as_deref_mut()
+            .map(|rpc| rpc as &mut dyn ClnRpcApi),
+        &context.options.network,
+        context.peer_id,
+        typed_request.params,
+        context.options.create_order.clone(),
+    )
+    .await
+}
+
+/// The plugin options that are used by `lsps1.create_order`
+#[derive(Debug, Clone)]
+pub(crate) struct CreateOrderSettings {
+    /// The number of seconds until an order expires
+    pub(crate) order_lifetime: i64,
+    pub(crate) fee_calc: StandardFeeCalculator,
+    /// The confirmations an on-chain payment of the order requires
+    pub(crate) confirmation_policy: Option<ConfirmationPolicy>,
+    /// Handles orders that exceed `max_channel_expiry_blocks`
+    pub(crate) expiry_mode: ExpiryMode,
+    /// Extension fields are only included in the response if set
+    pub(crate) extensions_enabled: bool,
+    /// The channel type features a client may request
+    pub(crate) allowed_channel_types: AllowedChannelTypes,
+    /// The types of `refund_onchain_address` a client may use
+    pub(crate) accepted_refund_address_types: AcceptedRefundAddressTypes,
+}
+
+impl CreateOrderSettings {
+    /// Reads the settings from the configured plugin options
+    pub(crate) fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        let expiry_mode = if plugin.option(&options::lsps1_clamp_expiry())? {
+            ExpiryMode::Clamp
+        } else {
+            ExpiryMode::Reject
+        };
+
+        Ok(Self {
+            // A paid order can be opened until it expires. This gives clients
+            // that go offline after paying a chance to reconnect
+            order_lifetime: plugin.option(&options::lsps1_order_lifetime_seconds())?,
+            fee_calc: StandardFeeCalculator::from_plugin(plugin)?,
+            confirmation_policy: OnchainPaymentConfig::from_plugin(plugin)?
+                .map(|config| config.confirmation_policy),
+            expiry_mode,
+            extensions_enabled: plugin.option(&options::lsps1_enable_extensions())?,
+            allowed_channel_types: AllowedChannelTypes::from_plugin(plugin)?,
+            accepted_refund_address_types: AcceptedRefundAddressTypes::from_plugin(plugin)?,
+        })
+    }
+}
+
+/// Creates the order requested by `peer_id`
+///
+/// This is the part of `lsps1.create_order` that doesn't read the
+/// plugin options. A snapshot of the client node is stored with the
+/// order if `lookup_rpc` is provided.
+pub(crate) async fn create_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    lookup_rpc: Option<&mut dyn ClnRpcApi>,
+    network: &Network,
+    peer_id: PublicKey,
+    mut order: Lsps1CreateOrderRequest,
+    settings: CreateOrderSettings,
+) -> Result<Lsps1CreateOrderResponse, HandlerError> {
+    // We can't promise to open a channel if lightningd or bitcoind is unhealthy
+    if !state.health.is_healthy() {
+        log::info!(
+            "Refused lsps1.create_order from peer {:?} because the node is unhealthy",
+            peer_id
+        );
+        return Err(ErrorData::temporarily_unavailable().into());
+    }
 
     // Define the relevant timestamps
-    let now = IsoDatetime::now();
+    let now = state.clock.now();
     let created_at = now.clone();
-    let expires_at = now.clone();
+    let expires_at =
+        IsoDatetime::from_unix_timestamp(now.unix_timestamp() + settings.order_lifetime)
+            .map_err(HandlerError::internal)?;
 
-    let order = typed_request.params;
     order
         .refund_onchain_address
-        .require_network(&context.network)
+        .require_network(network)
         .map_err(|e| {
-            ParamValidationError::invalid_params(
+            ErrorData::from(ParamValidationError::invalid_params(
                 "order.refund_onchain_address".to_string(),
                 e.to_string(),
-            )
+            ))
+            .with_reason(LspsErrorReason::NetworkMismatch)
         })?;
+    if let Some(refund_address) = &order.refund_onchain_address {
+        settings
+            .accepted_refund_address_types
+            .validate(refund_address)
+            .map_err(|message| {
+                ParamValidationError::invalid_params(
+                    "order.refund_onchain_address".to_string(),
+                    message,
+                )
+            })?;
+    }
 
     // TODO: find a nicer way to get the options
     let info_response = state
-        .lsps1_info
-        .as_ref()
-        .clone()
-        .ok_or_else(|| ErrorData::method_not_found(method.name()))?;
+        .lsps1_info()
+        .ok_or_else(|| ErrorData::method_not_found(methods::LSPS1_CREATE_ORDER.name()))?;
 
     // Return an error if the order is invalid
-    order.validate_options(&info_response.options)?;
+    // The requested expiry is kept for the order log if it gets clamped
+    let requested_channel_expiry_blocks = order.channel_expiry_blocks;
+    order.validate_free_form_fields()?;
+    let clamped_fields =
+        order.validate_options_with_mode(&info_response.options, settings.expiry_mode)?;
+    order.validate_channel_size(network, &info_response.options)?;
+
+    // The client can only use `channel_type` if we advertise the extension
+    let channel_type = match order.channel_type.take() {
+        Some(_) if !settings.extensions_enabled => {
+            return Err(
+                ParamValidationError::unrecognized(vec!["channel_type".to_string()]).into(),
+            );
+        }
+        Some(requested) => {
+            let peer_features = lookup_peer_features(rpc, &peer_id)
+                .await
+                .map_err(HandlerError::internal)?;
+            Some(settings.allowed_channel_types.validate(
+                &order,
+                &requested,
+                peer_features.as_deref(),
+            )?)
+        }
+        None => None,
+    };
+
+    // Only privileged tokens may pick the `funding_source`
+    let requested_source = order.funding_source.take();
+    if requested_source.is_some() && !settings.extensions_enabled {
+        return Err(ParamValidationError::unrecognized(vec!["funding_source".to_string()]).into());
+    }
+    let funding_source = state
+        .funding_sources
+        .select(requested_source.as_deref(), order.token.as_deref())
+        .map_err(|err| {
+            ParamValidationError::invalid_params(
+                "order.funding_source".to_string(),
+                err.to_string(),
+            )
+        })?;
 
     // Construct the database order object
     let lsps1_order = Lsps1Order {
         uuid: Uuid::new_v4(),
-        client_node_id: context.peer_id,
+        client_node_id: peer_id,
         announce_channel: order.announce_channel,
         created_at,
         expires_at,
@@ -101,75 +318,148 @@ pub(crate) async fn do_lsps1_create_order(
         channel_expiry_blocks: order.channel_expiry_blocks,
         token: order.token.clone(),
         refund_onchain_address: order.refund_onchain_address.as_ref().map(|x| x.to_string()),
-        order_state: OrderState::Created,
-        generation: 0,
+        order_state: Lsps1OrderState::Created,
+        failure_reason: None,
+        generation: OrderGeneration(0),
     };
 
     // Compute the fee
-    // TODO: Provide a way to configure the fee calculation
-    let opt = options::lsps1_fee_computation_base_fee_sat();
-    let base_fee = context.plugin.option(&opt).unwrap();
-    let weight_units = context
-        .plugin
-        .option(&options::lsps1_fee_computation_onchain_ppm())
-        .unwrap();
-    let sat_per_billion_sat_block = context
-        .plugin
-        .option(&options::lsps1_fee_computation_liquidity_ppb())
-        .unwrap();
-
-    let fee_calc = StandardFeeCalculator {
-        fixed_msat: base_fee as u64,
-        weight_units: weight_units as u64,
-        sat_per_billion_sat_block: sat_per_billion_sat_block as u64,
+    // The order must lie within the range the fee calculator can price
+    let fee_calc = settings.fee_calc;
+    fee_calc.validate_domain(&lsps1_order)?;
+    let fee_parameters = json!({
+        "base_fee_sat" : fee_calc.base_fee_sat,
+        "weight_units" : fee_calc.weight_units,
+        "liquidity_ppb" : fee_calc.sat_per_billion_sat_block,
+    });
+    let mut payment_calc = PaymentCalc {
+        fee_calc,
+        feerates: state.feerates.clone(),
+        clock: state.clock.clone(),
+        confirmation_policy: settings.confirmation_policy,
+        payment_grace: state.payment_grace,
     };
-    let mut payment_calc = PaymentCalc { fee_calc };
-    let payment = payment_calc
-        .compute_payment_details(context, &lsps1_order)
-        .await
-        .map_err(ErrorData::internalize)?;
+    let (payment, client_node) = tokio::join!(
+        payment_calc.compute_payment_details(rpc, &lsps1_order),
+        lookup_client_node(lookup_rpc, &peer_id),
+    );
+    let payment = payment.map_err(HandlerError::internal)?;
 
     // Write everything to the database
-    let db = context.plugin.state().database.clone();
+    // The insert is retried if concurrent hooks hold the lock
+    let db = &state.database;
+    let client_node_query = SetClientNodeQuery {
+        order_uuid: lsps1_order.uuid,
+        client_node,
+    };
+    let channel_type_query = channel_type
+        .clone()
+        .map(|channel_type| SetChannelTypeQuery {
+            order_uuid: lsps1_order.uuid,
+            channel_type,
+        });
+    let capacity_sat = lsps1_order
+        .lsp_balance_sat
+        .sat_value()
+        .saturating_add(lsps1_order.client_balance_sat.sat_value());
+    let funding_source_query = funding_source.as_ref().map(|source| SetFundingSourceQuery {
+        order_uuid: lsps1_order.uuid,
+        funding_source: source.label.clone(),
+    });
     let query = Lsps1CreateOrderQuery {
         order: lsps1_order,
         payment,
     };
 
-    let mut tx = db.begin().await.map_err(ErrorData::internalize)?;
-    let _ = query
-        .execute(&mut tx)
-        .await
-        .map_err(ErrorData::internalize)?;
+    // The budget is checked in the transaction that creates the order.
+    // Concurrent orders can't exceed it together
+    let create_order = &query;
+    let set_client_node = &client_node_query;
+    let set_channel_type = &channel_type_query;
+    let funding_source = &funding_source;
+    let set_funding_source = &funding_source_query;
+    retry_on_busy(move || {
+        db.with_tx("create_order", move |tx| {
+            Box::pin(async move {
+                if let Some(source) = funding_source {
+                    let committed = GetCommittedLiquidityQuery::by_source(source.label.clone())
+                        .execute(tx)
+                        .await?;
+                    let committed_sat = committed.get(&source.label).copied().unwrap_or(0);
+                    source.check_budget(committed_sat, capacity_sat)?;
+                }
+                create_order.execute(tx).await?;
+                set_client_node.execute(tx).await?;
+                if let Some(set_channel_type) = set_channel_type {
+                    set_channel_type.execute(tx).await?;
+                }
+                if let Some(set_funding_source) = set_funding_source {
+                    set_funding_source.execute(tx).await?;
+                }
+                Ok(())
+            })
+        })
+    })
+    .await
+    .map_err(|err| match err.downcast_ref::<FundingBudgetExceeded>() {
+        Some(exceeded) => {
+            log::info!(
+                "Refused lsps1.create_order from peer {:?}: {}",
+                peer_id,
+                exceeded
+            );
+            ErrorData::temporarily_unavailable().into()
+        }
+        None => HandlerError::internal(err),
+    })?;
 
-    tx.commit().await.map_err(ErrorData::internalize)?;
+    let mut details = json!({
+        "fee_parameters" : fee_parameters,
+        "fee_total_sat" : query.payment.fee_total_sat,
+        "order_total_sat" : query.payment.order_total_sat,
+    });
+    if !clamped_fields.is_empty() {
+        details["clamped_fields"] = json!(clamped_fields);
+        details["requested_channel_expiry_blocks"] = json!(requested_channel_expiry_blocks);
+    }
+    if let Some(channel_type) = &channel_type {
+        details["channel_type"] = json!(channel_type);
+    }
+    if let Some(source) = funding_source {
+        details["funding_source"] = json!(source.label);
+    }
+    state
+        .order_log
+        .info(query.order.uuid, OrderLogEvent::OrderCreated, details);
 
     // Construct the response that we will send to the user
-    let payment = Payment {
-        min_fee_for_0conf: None,
-        min_onchain_payment_confirmations: None,
-        onchain_address: None,
-        onchain_payment: None,
-        fee_total_sat: query.payment.fee_total_sat,
-        order_total_sat: query.payment.order_total_sat,
-        bolt11_invoice: query.payment.bolt11_invoice,
-        state: query.payment.state
-    };
+    let payment = query
+        .payment
+        .into_spec_payment()
+        .map_err(HandlerError::internal)?;
 
     let response = Lsps1CreateOrderResponse {
-        order_id: query.order.uuid,
+        order_id: query.order.uuid.into(),
         lsp_balance_sat: order.lsp_balance_sat,
         client_balance_sat: order.client_balance_sat,
         funding_confirms_within_blocks: order.funding_confirms_within_blocks,
         required_channel_confirmations: order.required_channel_confirmations,
         channel_expiry_blocks: order.channel_expiry_blocks,
-        token: order.token.unwrap_or(String::from("")),
-        order_state: query.order.order_state,
+        token: order.token,
+        order_state: query.order.order_state.into(),
         announce_channel: order.announce_channel,
         created_at,
         expires_at,
         payment,
-        channel: None
+        channel: None,
+        refund: None,
+        clamped_fields: if settings.extensions_enabled {
+            clamped_fields
+        } else {
+            vec![]
+        },
+        receipt: None,
+        channel_type,
     };
     Ok(response)
 }
@@ -177,66 +467,1359 @@ pub(crate) async fn do_lsps1_create_order(
 pub(crate) async fn do_lsps1_get_order(
     method: methods::Lsps1GetOrder,
     context: &mut CustomMsgContext<PluginState>,
-) -> Result<Lsps1CreateOrderResponse, ErrorData> {
+) -> Result<Lsps1CreateOrderResponse, HandlerError> {
     check_lsps1_enabled(context).await?;
     let typed_request = method.into_typed_request(context.request.clone())?;
+    let params = typed_request.params;
+
+    let extensions_enabled = context.options.create_order.extensions_enabled;
+
+    // The client can only use `wait_for_change_seconds` if we advertise the extension
+    if params.wait_for_change_seconds.is_some() && !extensions_enabled {
+        return Err(ParamValidationError::unrecognized(
+            vec!["wait_for_change_seconds".to_string()],
+        )
+        .into());
+    }
+
+    let uuid_value = Uuid::from(params.order_id);
 
-    let uuid_value =
-        Uuid::parse_str(&typed_request.params.order_id).map_err(ErrorData::internalize)?;
+    let wait_for_change = params
+        .wait_for_change_seconds
+        .filter(|seconds| *seconds > 0)
+        .map(|seconds| Duration::from_secs(seconds.min(MAX_WAIT_FOR_CHANGE_SECONDS)));
 
-    let db = context.plugin.state().database.clone();
+    let state = &context.state;
+    let mut response = get_order_with_wait(
+        &state.database,
+        &state.order_watcher,
+        context.peer_id,
+        uuid_value,
+        extensions_enabled,
+        wait_for_change,
+    )
+    .await
+    .map_err(|err| err.quarantine_corrupt_row(&state.quarantine))?;
 
-    let mut tx = db.begin().await.map_err(ErrorData::internalize)?;
+    if extensions_enabled {
+        add_channel_usability(
+            state,
+            context.cln_rpc.as_mut(),
+            &context.peer_id,
+            &mut response,
+        )
+        .await;
+    }
+    Ok(response)
+}
 
-    let get_order_query = GetOrderQuery {
-        order_id: uuid_value,
+/// Tells the client whether it can use the channel of the order
+///
+/// The fields are left out if lightningd doesn't know the channel or
+/// can't be reached. The order itself is still returned.
+async fn add_channel_usability(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    peer_id: &PublicKey,
+    response: &mut Lsps1CreateOrderResponse,
+) {
+    let channel = match response.channel.as_mut() {
+        Some(channel) => channel,
+        None => return,
     };
 
-    log::debug!("Retriever order details from database");
-    let order = get_order_query
-        .execute(&mut tx)
+    let usability = state
+        .channel_usability
+        .get(
+            rpc,
+            state.clock.as_ref(),
+            peer_id,
+            &channel.funding_outpoint,
+        )
+        .await;
+    match usability {
+        Ok(Some(usability)) => usability.apply_to(channel),
+        Ok(None) => log::debug!(
+            "Channel {} is unknown to lightningd",
+            channel.funding_outpoint
+        ),
+        Err(err) => log::warn!(
+            "Failed to look up channel {}: {:?}",
+            channel.funding_outpoint,
+            err
+        ),
+    }
+}
+
+/// Returns the order once it changes or `wait_for_change` elapses
+///
+/// The order is returned immediately if `wait_for_change` is `None`.
+/// A peer that has too many requests waiting gets an error.
+async fn get_order_with_wait(
+    db: &Database,
+    order_watcher: &OrderWatcher,
+    peer_id: PublicKey,
+    uuid_value: Uuid,
+    extensions_enabled: bool,
+    wait_for_change: Option<Duration>,
+) -> Result<Lsps1CreateOrderResponse, HandlerError> {
+    let _permit = match wait_for_change {
+        Some(_) => Some(order_watcher.reserve_wait(peer_id).ok_or_else(|| {
+            log::info!(
+                "Peer {:?} has too many lsps1.get_order requests waiting",
+                peer_id
+            );
+            ErrorData::temporarily_unavailable()
+        })?),
+        None => None,
+    };
+
+    // Subscribe before reading the order. Otherwise, we might miss a change
+    let subscription = wait_for_change.map(|_| order_watcher.subscribe(uuid_value));
+
+    let response = get_order_response(db, uuid_value, extensions_enabled).await?;
+
+    match (subscription, wait_for_change) {
+        (Some(subscription), Some(timeout)) => {
+            if subscription.wait(timeout).await {
+                log::debug!("Order {} changed while waiting", uuid_value);
+                get_order_response(db, uuid_value, extensions_enabled).await
+            } else {
+                Ok(response)
+            }
+        }
+        _ => Ok(response),
+    }
+}
+
+/// The rows that make up the response to `lsps1.get_order`
+struct StoredOrder {
+    order: Lsps1Order,
+    payment_details: Lsps1PaymentDetails,
+    channel_details: Option<Lsps1Channel>,
+    refund: Option<Lsps1Refund>,
+    receipt: Option<OrderReceipt>,
+    channel_type: Option<Vec<ChannelTypeFeature>>,
+}
+
+/// Reads all rows of the order in a single transaction
+///
+/// Returns `None` if the order doesn't exist
+async fn get_stored_order(
+    db: &Database,
+    uuid_value: Uuid,
+    extensions_enabled: bool,
+) -> Result<Option<StoredOrder>> {
+    db.with_tx("get_order", move |tx| {
+        Box::pin(async move {
+            log::debug!("Retriever order details from database");
+            let Some(order) = GetOrderQuery::by_uuid(uuid_value).execute(tx).await? else {
+                return Ok(None);
+            };
+
+            log::debug!("Retreive payment details from database");
+            let payment_details = GetPaymentDetailsQuery::by_uuid(uuid_value)
+                .execute(tx)
+                .await?
+                .context("Failed to find payment corresponding to order")?;
+
+            log::debug!("Retrieve channel info from database");
+            let channel_details = GetChannelQuery::by_order_id(uuid_value).execute(tx).await?;
+
+            let refund = if payment_details.state == PaymentState::Refunded && extensions_enabled {
+                log::debug!("Retrieve refund details from database");
+                GetRefundQuery::by_order_id(uuid_value).execute(tx).await?
+            } else {
+                None
+            };
+
+            let receipt = if order.order_state == Lsps1OrderState::Completed && extensions_enabled {
+                log::debug!("Retrieve receipt from database");
+                GetReceiptQuery::by_uuid(uuid_value).execute(tx).await?
+            } else {
+                None
+            };
+
+            let channel_type = if extensions_enabled {
+                GetChannelTypeQuery::by_uuid(uuid_value).execute(tx).await?
+            } else {
+                None
+            };
+
+            Ok(Some(StoredOrder {
+                order,
+                payment_details,
+                channel_details,
+                refund,
+                receipt,
+                channel_type,
+            }))
+        })
+    })
+    .await
+}
+
+async fn get_order_response(
+    db: &Database,
+    uuid_value: Uuid,
+    extensions_enabled: bool,
+) -> Result<Lsps1CreateOrderResponse, HandlerError> {
+    let StoredOrder {
+        order,
+        payment_details,
+        channel_details,
+        refund,
+        receipt,
+        channel_type,
+    } = get_stored_order(db, uuid_value, extensions_enabled)
         .await
-        .map_err(ErrorData::internalize)?
+        .map_err(HandlerError::internal)?
         .ok_or_else(ErrorData::not_found)?;
 
-    log::debug!("Retreive payment details from database");
-    let payment_details = GetPaymentDetailsQuery::by_uuid(uuid_value)
-        .execute(&mut tx)
+    let is_refunded = payment_details.state == PaymentState::Refunded;
+    let refund = refund
+        .map(Refund::from_db_refund)
+        .transpose()
+        .map_err(HandlerError::internal)?;
+
+    // A refund is only provided when the order failed
+    if is_refunded && OrderState::from(order.order_state) != OrderState::Failed {
+        log::warn!(
+            "Order {} is refunded but has state {:?}",
+            order.uuid,
+            order.order_state
+        );
+    }
+
+    let channel_expiry_blocks = order.channel_expiry_blocks;
+    let mut builder = Lsps1CreateOrderResponseBuilder::new()
+        .db_order(order)
+        .db_payment(payment_details)
+        .and_then(|builder| builder.db_channel(channel_details, channel_expiry_blocks))
+        .map_err(HandlerError::internal)?
+        .refund(refund)
+        .receipt(receipt)
+        .channel_type(channel_type);
+
+    if is_refunded {
+        builder = builder.order_state(OrderState::Failed);
+    }
+
+    builder.build().map_err(HandlerError::internal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use lsp_primitives::json_rpc::error::codes;
+    use lsp_primitives::lsps0::common_schemas::SatAmount;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::custom_msg::error::test_support::capture_logs;
+    use crate::db::schema::InvoiceLabel;
+    use crate::db::sqlite::quarantine::Quarantine;
+    use crate::db::sqlite::queries::{
+        CreateChannelQuery, GetClientNodeQuery, GetFundingSourceQuery, GetOrderLogQuery,
+        GetRecentOrdersQuery, SetReceiptQuery, UpdateOrderStateQuery,
+    };
+    use crate::db::sqlite::test::{create_order_query, get_db, get_temp_db};
+    use crate::lsps1::channel_usability::test_support::listpeerchannels;
+    use crate::lsps1::funding_source::test_support::funding_sources;
+    use crate::lsps1::order_watcher::MAX_WAITS_PER_PEER;
+    use crate::state::test_support::{test_info, test_state, test_state_with_order_log};
+
+    const PEER_ID: &str = "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170";
+    const FUNDING_TXID: &str = "0301e0480b374b32851a9462db29dc19fe830a7f7d7a88b81612b9d42099c0ae";
+
+    fn create_order_request() -> Lsps1CreateOrderRequest {
+        serde_json::from_value(json!({
+            "lsp_balance_sat" : "100000",
+            "client_balance_sat" : "0",
+            "funding_confirms_within_blocks" : 6,
+            "required_channel_confirmations" : 0,
+            "channel_expiry_blocks" : 1000,
+            "token" : null,
+            "refund_onchain_address" : null,
+            "announce_channel" : false,
+        }))
+        .unwrap()
+    }
+
+    fn feerates() -> serde_json::Value {
+        json!({
+            "perkw" : {
+                "min_acceptable" : 253,
+                "max_acceptable" : 100_000,
+                "estimates" : [
+                    { "blockcount" : 2, "feerate" : 2_000, "smoothed_feerate" : 2_000 },
+                    { "blockcount" : 6, "feerate" : 1_000, "smoothed_feerate" : 1_000 },
+                ]
+            }
+        })
+    }
+
+    fn settings(expiry_mode: ExpiryMode, extensions_enabled: bool) -> CreateOrderSettings {
+        CreateOrderSettings {
+            order_lifetime: 3600,
+            fee_calc: StandardFeeCalculator::from_options(1_000, 500, 100).unwrap(),
+            confirmation_policy: None,
+            expiry_mode,
+            extensions_enabled,
+            allowed_channel_types: AllowedChannelTypes::default(),
+            accepted_refund_address_types: AcceptedRefundAddressTypes::default(),
+        }
+    }
+
+    fn invoice() -> serde_json::Value {
+        json!({
+            "bolt11" : "lnbcrt15100n1pjscripted",
+            "payment_hash" : "00".repeat(32),
+            "payment_secret" : "00".repeat(32),
+            "expires_at" : 1_700_000_000,
+        })
+    }
+
+    async fn create_test_order(
+        state: &PluginState,
+        rpc: &mut FakeClnRpc,
+    ) -> Result<Lsps1CreateOrderResponse, HandlerError> {
+        create_order(
+            state,
+            rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            create_order_request(),
+            settings(ExpiryMode::Reject, false),
+        )
         .await
-        .map_err(ErrorData::internalize)?
-        .ok_or_else(|| ErrorData::internalize("Failed to find payment corresponding to order"))?;
+    }
 
-    let payment = Payment::from_db_payment(payment_details);
+    async fn count_orders(db: &Database) -> usize {
+        let mut tx = db.begin().await.unwrap();
+        let orders = GetRecentOrdersQuery::last(10)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        orders.len()
+    }
 
-    log::debug!("Retrieve channel info from database");
-    let channel_details = GetChannelQuery::by_order_id(uuid_value)
-        .execute(&mut tx)
+    #[test]
+    fn disabled_response_modes() {
+        assert_eq!(
+            "not_found".parse::<DisabledResponse>().unwrap(),
+            DisabledResponse::default()
+        );
+        assert!("disabled".parse::<DisabledResponse>().is_err());
+
+        let error = DisabledResponse::NotFound.error(methods::LSPS1_GETINFO.name());
+        assert_eq!(error.code, codes::METHOD_NOT_FOUND_CODE);
+        assert!(!error.is_retryable());
+
+        let mode: DisabledResponse = "unavailable".parse().unwrap();
+        let error = mode.error(methods::LSPS1_GETINFO.name());
+        assert_eq!(error.code, codes::TEMPORARILY_UNAVAILABLE_CODE);
+        assert_eq!(
+            error.reason(),
+            Some(LspsErrorReason::TemporarilyUnavailable)
+        );
+        assert_eq!(error.retry_after(), Some(LSPS1_DISABLED_RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn get_info_while_the_info_is_replaced() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+        let mut replaced = test_info();
+        replaced.website = Some("https://lsp.example.com".to_string());
+
+        // A reader keeps the info it has read
+        let before = get_info(&state, false).unwrap();
+        state.set_lsps1_info(Some(replaced.clone()));
+        assert_eq!(before.website, None);
+        assert_eq!(get_info(&state, false).unwrap().website, replaced.website);
+
+        let swapper = {
+            let state = state.clone();
+            let replaced = replaced.clone();
+            tokio::spawn(async move {
+                for i in 0..100 {
+                    let info = if i % 2 == 0 {
+                        test_info()
+                    } else {
+                        replaced.clone()
+                    };
+                    state.set_lsps1_info(Some(info));
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let readers: Vec<_> = (0..100)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { get_info(&state, false) })
+            })
+            .collect();
+
+        for reader in readers {
+            let info = reader.await.unwrap().unwrap();
+            assert!(info.website.is_none() || info.website == replaced.website);
+        }
+        swapper.await.unwrap();
+
+        state.set_lsps1_info(None);
+        let err = get_info(&state, false).unwrap_err().into_error_data();
+        assert_eq!(err.code, codes::METHOD_NOT_FOUND_CODE);
+    }
+
+    #[tokio::test]
+    async fn create_order_stores_order_with_invoice() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice());
+
+        let response = create_test_order(&state, &mut rpc).await.unwrap();
+
+        // base fee + 1_000 sat/kwu * 500 wu + 100_000 sat * 1_000 blocks * 100 ppb
+        assert_eq!(response.payment.fee_total_sat, SatAmount::new(1_510));
+        assert_eq!(response.payment.order_total_sat, SatAmount::new(1_510));
+        assert_eq!(response.payment.bolt11_invoice, "lnbcrt15100n1pjscripted");
+        assert_eq!(response.order_state, OrderState::Created);
+
+        assert_eq!(rpc.called_methods(), vec!["feerates", "invoice"]);
+        let invoice = rpc.params_of("invoice").unwrap();
+        assert_eq!(invoice["amount_msat"], "1510000msat");
+        assert_eq!(
+            invoice["label"],
+            InvoiceLabel::for_order(response.order_id.as_uuid())
+                .unwrap()
+                .to_string()
+        );
+
+        let mut tx = db.begin().await.unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(response.order_id.into())
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(payment.bolt11_invoice, "lnbcrt15100n1pjscripted");
+        assert_eq!(payment.state, PaymentState::ExpectPayment);
+    }
+
+    #[tokio::test]
+    async fn create_order_fails_if_invoice_fails() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .fail("invoice", "Duplicate label");
+
+        let error = create_test_order(&state, &mut rpc).await.unwrap_err();
+        assert!(matches!(error, HandlerError::Internal(_)));
+        assert_eq!(error.reason(), Some(LspsErrorReason::Internal));
+
+        // Nothing is stored for an order without an invoice
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_order_requires_confirmations_by_order_size() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice())
+            .respond("invoice", invoice());
+
+        // The order total of 1_510 sat lies in the second tier
+        let mut settings = settings(ExpiryMode::Reject, false);
+        settings.confirmation_policy =
+            Some(ConfirmationPolicy::parse("0:0,1510:1,5000:3").unwrap());
+        let response = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            peer_id(),
+            create_order_request(),
+            settings,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.payment.min_onchain_payment_confirmations, Some(1));
+
+        let mut tx = db.begin().await.unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(response.order_id.into())
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(payment.onchain_block_confirmations_required, Some(1));
+
+        // Nothing is required if the operator didn't configure confirmations
+        let response = create_test_order(&state, &mut rpc).await.unwrap();
+        assert_eq!(response.payment.min_onchain_payment_confirmations, None);
+    }
+
+    #[tokio::test]
+    async fn create_order_uses_cached_feerates() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice())
+            .respond("invoice", invoice());
+
+        create_test_order(&state, &mut rpc).await.unwrap();
+        let response = create_test_order(&state, &mut rpc).await.unwrap();
+
+        // The second order is priced using the estimates of the first one
+        assert_eq!(response.payment.fee_total_sat, SatAmount::new(1_510));
+        assert_eq!(rpc.called_methods(), vec!["feerates", "invoice", "invoice"]);
+    }
+
+    #[tokio::test]
+    async fn create_order_stores_client_node() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice());
+        let mut lookup_rpc = FakeClnRpc::default();
+        lookup_rpc
+            .respond(
+                "listnodes",
+                json!({ "nodes" : [{
+                    "nodeid" : PEER_ID,
+                    "alias" : "SLEEPYDRAGON",
+                    "addresses" : [{ "type" : "ipv4", "address" : "203.0.113.7", "port" : 9735 }]
+                }]}),
+            )
+            .respond(
+                "listpeers",
+                json!({ "peers" : [{
+                    "id" : PEER_ID,
+                    "connected" : true,
+                    "netaddr" : ["198.51.100.3:41234"]
+                }]}),
+            );
+
+        let response = create_order(
+            &state,
+            &mut rpc,
+            Some(&mut lookup_rpc),
+            &Network::Regtest,
+            peer_id(),
+            create_order_request(),
+            settings(ExpiryMode::Reject, false),
+        )
+        .await
+        .unwrap();
+
+        // The lookup doesn't use the connection that creates the invoice
+        assert_eq!(rpc.called_methods(), vec!["feerates", "invoice"]);
+        let mut tx = db.begin().await.unwrap();
+        let client_node = GetClientNodeQuery::by_uuid(response.order_id.into())
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(client_node.alias.as_deref(), Some("SLEEPYDRAGON"));
+        assert_eq!(client_node.addresses, vec!["203.0.113.7:9735"]);
+        assert_eq!(
+            client_node.remote_address.as_deref(),
+            Some("198.51.100.3:41234")
+        );
+    }
+
+    #[tokio::test]
+    async fn create_order_doesnt_call_rpc_when_unhealthy() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        for _ in 0..2 {
+            state
+                .health
+                .record(Err(anyhow::anyhow!("Connection refused")));
+        }
+        let mut rpc = FakeClnRpc::default();
+
+        let error = create_test_order(&state, &mut rpc).await.unwrap_err();
+        assert_eq!(
+            error.reason(),
+            Some(LspsErrorReason::TemporarilyUnavailable)
+        );
+        assert_eq!(
+            error.into_error_data().code,
+            codes::TEMPORARILY_UNAVAILABLE_CODE
+        );
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_order_rejects_refund_address_of_other_network() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+
+        let mut request = create_order_request();
+        request.refund_onchain_address =
+            serde_json::from_value(json!("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")).unwrap();
+
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            settings(ExpiryMode::Reject, false),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::NetworkMismatch));
+        assert!(rpc.called_methods().is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_order_rejects_refund_address_types_that_are_not_accepted() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+
+        let mut request = create_order_request();
+        request.refund_onchain_address =
+            serde_json::from_value(json!("mfcHP2WMCVLsVZA8yrovmhMgxNFW9r98xw")).unwrap();
+
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            CreateOrderSettings {
+                accepted_refund_address_types: AcceptedRefundAddressTypes::parse("p2wpkh,p2tr")
+                    .unwrap(),
+                ..settings(ExpiryMode::Reject, false)
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::InvalidParams));
+        let error = error.into_error_data();
+        assert_eq!(error.code, codes::INVALID_PARAMS_CODE);
+        assert!(error.data.unwrap().to_string().contains("p2pkh"));
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_order_rejects_expiry_beyond_max() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+
+        let mut request = create_order_request();
+        request.channel_expiry_blocks = 30_000;
+
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            settings(ExpiryMode::Reject, true),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::OptionMismatch));
+        let data = error.into_error_data().data.unwrap();
+        assert_eq!(data["property"], "max_channel_expiry_blocks");
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_order_rejects_channel_below_dust_and_reserve() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+
+        // The options allow it but the channel would be unusable
+        let mut request = create_order_request();
+        request.lsp_balance_sat = SatAmount::new(500);
+
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            settings(ExpiryMode::Reject, false),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::OptionMismatch));
+        let data = error.into_error_data().data.unwrap();
+        assert_eq!(data["property"], "min_channel_balance_sat");
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_order_rejects_order_outside_fee_domain() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+
+        // The options allow it but the fee calculator can't price it
+        let mut request = create_order_request();
+        request.channel_expiry_blocks = 0;
+
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            settings(ExpiryMode::Reject, false),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::OptionMismatch));
+        let data = error.into_error_data().data.unwrap();
+        assert_eq!(data["property"], "channel_expiry_blocks");
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn create_order_clamps_expiry_beyond_max() {
+        let (db, _) = get_temp_db().await;
+        let (state, writer) = test_state_with_order_log(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice());
+
+        let mut request = create_order_request();
+        request.lsp_balance_sat = SatAmount::new(1_000_000);
+        request.channel_expiry_blocks = 30_000;
+
+        let response = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            settings(ExpiryMode::Clamp, true),
+        )
         .await
-        .map_err(ErrorData::internalize)?;
+        .unwrap();
+        assert_eq!(response.channel_expiry_blocks, 20_160);
+        assert_eq!(response.clamped_fields, vec!["channel_expiry_blocks"]);
+
+        // base fee + 1_000 sat/kwu * 500 wu + 1_000_000 sat * 20_160 blocks * 100 ppb
+        assert_eq!(response.payment.fee_total_sat, SatAmount::new(3_516));
+
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(response.order_id.into())
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(order.channel_expiry_blocks, 20_160);
+
+        // The order log keeps the value that was requested
+        drop(state);
+        writer.run(db.clone()).await;
+        let mut tx = db.begin().await.unwrap();
+        let log = GetOrderLogQuery::by_uuid(response.order_id.into())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(log[0].event.as_str(), "order_created");
+        assert_eq!(log[0].details["requested_channel_expiry_blocks"], 30_000);
+        assert_eq!(
+            log[0].details["clamped_fields"],
+            json!(["channel_expiry_blocks"])
+        );
+    }
+
+    #[tokio::test]
+    async fn clamped_fields_require_extensions() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice());
+
+        let mut request = create_order_request();
+        request.channel_expiry_blocks = 30_000;
+
+        let response = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            PublicKey::from_hex(PEER_ID).unwrap(),
+            request,
+            settings(ExpiryMode::Clamp, false),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.channel_expiry_blocks, 20_160);
+        assert!(response.clamped_fields.is_empty());
+        let value = serde_json::to_value(&response).unwrap();
+        assert!(value.get("clamped_fields").is_none());
+    }
+
+    fn channel_type_settings(allowed: &str) -> CreateOrderSettings {
+        CreateOrderSettings {
+            allowed_channel_types: AllowedChannelTypes::parse(allowed).unwrap(),
+            ..settings(ExpiryMode::Reject, true)
+        }
+    }
+
+    /// `listpeers` of a client that supports anchors and zeroconf (odd bits 23 and 51)
+    fn connected_peer() -> serde_json::Value {
+        json!({ "peers" : [{
+            "id" : PEER_ID,
+            "connected" : true,
+            "features" : "08000000800000",
+        }]})
+    }
+
+    #[tokio::test]
+    async fn create_order_stores_requested_channel_type() {
+        use lsp_primitives::lsps1::schema::ChannelTypeFeature::{Anchors, Zeroconf};
+
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeers", connected_peer())
+            .respond("feerates", feerates())
+            .respond("invoice", invoice());
 
-    let channel_details = match channel_details {
-        Some(channel_details) => {
-            // Convert from database type to Lsps1 spec type
-            let channel = Channel {
-                funded_at: channel_details.funded_at,
-                funding_outpoint: Outpoint {
-                    txid: channel_details.funding_txid,
-                    outnum: channel_details.outnum,
+        let mut request = create_order_request();
+        request.channel_type = Some(vec![Zeroconf, Anchors]);
+
+        let response = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            peer_id(),
+            request,
+            channel_type_settings("anchors,zeroconf"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.channel_type, Some(vec![Anchors, Zeroconf]));
+        assert_eq!(
+            rpc.called_methods(),
+            vec!["listpeers", "feerates", "invoice"]
+        );
+
+        let uuid = response.order_id.into();
+        let mut tx = db.begin().await.unwrap();
+        let stored = GetChannelTypeQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(stored, Some(vec![Anchors, Zeroconf]));
+
+        // The order echoes the accepted type
+        let response = get_order_response(&db, uuid, true).await.unwrap();
+        assert_eq!(response.channel_type, Some(vec![Anchors, Zeroconf]));
+        let response = get_order_response(&db, uuid, false).await.unwrap();
+        assert!(response.channel_type.is_none());
+    }
+
+    #[tokio::test]
+    async fn create_order_rejects_channel_type_mismatch() {
+        use lsp_primitives::lsps1::schema::ChannelTypeFeature::{Anchors, ScidAlias};
+
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+
+        // The server doesn't allow scid_alias
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeers", connected_peer());
+        let mut request = create_order_request();
+        request.channel_type = Some(vec![ScidAlias]);
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            peer_id(),
+            request,
+            channel_type_settings("anchors,zeroconf"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::OptionMismatch));
+        let data = error.into_error_data().data.unwrap();
+        assert_eq!(data["property"], "channel_type");
+
+        // The client doesn't signal support for anchors
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeers",
+            json!({ "peers" : [{ "id" : PEER_ID, "connected" : true, "features" : "" }]}),
+        );
+        let mut request = create_order_request();
+        request.channel_type = Some(vec![Anchors]);
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            peer_id(),
+            request,
+            channel_type_settings("anchors"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::OptionMismatch));
+        let data = error.into_error_data().data.unwrap();
+        assert_eq!(data["property"], "channel_type");
+        assert_eq!(rpc.called_methods(), vec!["listpeers"]);
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn channel_type_requires_extensions() {
+        use lsp_primitives::lsps1::schema::ChannelTypeFeature::Anchors;
+
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let mut rpc = FakeClnRpc::default();
+
+        let mut request = create_order_request();
+        request.channel_type = Some(vec![Anchors]);
+        let error = create_order(
+            &state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            peer_id(),
+            request,
+            CreateOrderSettings {
+                allowed_channel_types: AllowedChannelTypes::parse("anchors").unwrap(),
+                ..settings(ExpiryMode::Reject, false)
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.into_error_data().code, codes::INVALID_PARAMS_CODE);
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    /// Orders use `hot` unless the partner picks `partner`
+    fn state_with_funding_sources(db: &Database) -> PluginState {
+        test_state(db.clone()).with_funding_sources(funding_sources(
+            json!({
+                "hot" : { "addresses" : ["bcrt1qhot"] },
+                "partner" : {
+                    "addresses" : ["bcrt1qpartner"],
+                    "budget_sat" : 150_000,
+                    "tokens" : ["partner-token"],
                 },
-                expires_at: IsoDatetime::now(),
-            };
+            }),
+            Some("hot"),
+        ))
+    }
 
-            Some(channel)
+    fn request_for_source(token: &str, funding_source: Option<&str>) -> Lsps1CreateOrderRequest {
+        let mut request = create_order_request();
+        request.token = Some(token.to_string());
+        request.funding_source = funding_source.map(str::to_string);
+        request
+    }
+
+    async fn stored_funding_source(db: &Database, uuid: Uuid) -> Option<String> {
+        let mut tx = db.begin().await.unwrap();
+        let stored = GetFundingSourceQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        stored
+    }
+
+    #[tokio::test]
+    async fn create_order_stores_the_funding_source() {
+        let (db, _) = get_temp_db().await;
+        let state = state_with_funding_sources(&db);
+
+        for (funding_source, expected) in [(Some("partner"), "partner"), (None, "hot")] {
+            let mut rpc = FakeClnRpc::default();
+            rpc.respond("feerates", feerates())
+                .respond("invoice", invoice());
+            let response = create_order(
+                &state,
+                &mut rpc,
+                None,
+                &Network::Regtest,
+                peer_id(),
+                request_for_source("partner-token", funding_source),
+                settings(ExpiryMode::Reject, true),
+            )
+            .await
+            .unwrap();
+
+            let stored = stored_funding_source(&db, response.order_id.into()).await;
+            assert_eq!(stored.as_deref(), Some(expected));
         }
-        None => None,
-    };
+    }
 
-    tx.commit().await.map_err(ErrorData::internalize)?;
+    #[tokio::test]
+    async fn create_order_rejects_funding_source_of_other_tokens() {
+        let (db, _) = get_temp_db().await;
+        let state = state_with_funding_sources(&db);
 
-    Lsps1CreateOrderResponseBuilder::new()
-        .db_order(order)
-        .payment(payment)
-        .channel(channel_details)
-        .build()
-        .map_err(ErrorData::internalize)
+        for (token, extensions_enabled) in [("other-token", true), ("partner-token", false)] {
+            let mut rpc = FakeClnRpc::default();
+            let error = create_order(
+                &state,
+                &mut rpc,
+                None,
+                &Network::Regtest,
+                peer_id(),
+                request_for_source(token, Some("partner")),
+                settings(ExpiryMode::Reject, extensions_enabled),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(error.into_error_data().code, codes::INVALID_PARAMS_CODE);
+            assert!(rpc.called_methods().is_empty());
+        }
+        assert_eq!(count_orders(&db).await, 0);
+    }
+
+    async fn create_partner_order(
+        state: &PluginState,
+    ) -> Result<Lsps1CreateOrderResponse, HandlerError> {
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice());
+        create_order(
+            state,
+            &mut rpc,
+            None,
+            &Network::Regtest,
+            peer_id(),
+            request_for_source("partner-token", Some("partner")),
+            settings(ExpiryMode::Reject, true),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn create_order_is_refused_beyond_the_budget_of_the_source() {
+        let (db, _) = get_temp_db().await;
+        let state = state_with_funding_sources(&db);
+
+        // The budget of 150_000 sat fits one order of 100_000 sat
+        create_partner_order(&state).await.unwrap();
+        let error = create_partner_order(&state)
+            .await
+            .unwrap_err()
+            .into_error_data();
+        assert_eq!(error.code, codes::TEMPORARILY_UNAVAILABLE_CODE);
+        assert_eq!(count_orders(&db).await, 1);
+
+        // The default source has no budget
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("feerates", feerates())
+            .respond("invoice", invoice());
+        create_test_order(&state, &mut rpc).await.unwrap();
+        assert_eq!(count_orders(&db).await, 2);
+    }
+
+    fn peer_id() -> PublicKey {
+        PublicKey::from_hex(PEER_ID).unwrap()
+    }
+
+    async fn insert_order(db: &Database) -> Uuid {
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        uuid
+    }
+
+    #[tokio::test]
+    async fn get_order_returns_when_order_changes() {
+        let db = get_db().await;
+        let order_watcher = Arc::new(OrderWatcher::default());
+        let uuid = insert_order(&db).await;
+
+        let task_db = db.clone();
+        let task_watcher = order_watcher.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut tx = task_db.begin().await.unwrap();
+            UpdateOrderStateQuery {
+                order_uuid: uuid,
+                state: Lsps1OrderState::Failed,
+                generation: OrderGeneration(0),
+                changed_at: IsoDatetime::now(),
+                failure_reason: None,
+            }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+            tx.commit().await.unwrap();
+            task_watcher.notify(uuid);
+        });
+
+        let start = Instant::now();
+        let timeout = Duration::from_secs(30);
+        let response =
+            get_order_with_wait(&db, &order_watcher, peer_id(), uuid, true, Some(timeout))
+                .await
+                .unwrap();
+
+        assert!(start.elapsed() < timeout);
+        assert_eq!(response.order_state, OrderState::Failed);
+    }
+
+    #[tokio::test]
+    async fn get_order_returns_current_state_after_timeout() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let uuid = insert_order(&db).await;
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(50);
+        let response =
+            get_order_with_wait(&db, &order_watcher, peer_id(), uuid, true, Some(timeout))
+                .await
+                .unwrap();
+
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(response.order_state, OrderState::Created);
+    }
+
+    #[tokio::test]
+    async fn get_order_without_wait_returns_immediately() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let uuid = insert_order(&db).await;
+
+        let response = get_order_with_wait(&db, &order_watcher, peer_id(), uuid, true, None)
+            .await
+            .unwrap();
+        assert_eq!(Uuid::from(response.order_id), uuid);
+    }
+
+    #[tokio::test]
+    async fn get_order_limits_waits_per_peer() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+        let uuid = insert_order(&db).await;
+
+        let _permits: Vec<_> = (0..MAX_WAITS_PER_PEER)
+            .map(|_| order_watcher.reserve_wait(peer_id()).unwrap())
+            .collect();
+
+        let timeout = Some(Duration::from_secs(30));
+        let error = get_order_with_wait(&db, &order_watcher, peer_id(), uuid, true, timeout)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.reason(),
+            Some(LspsErrorReason::TemporarilyUnavailable)
+        );
+
+        // Requests that don't wait are still served
+        get_order_with_wait(&db, &order_watcher, peer_id(), uuid, true, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_order_includes_receipt_of_completed_order() {
+        let db = get_db().await;
+        let uuid = insert_order(&db).await;
+        let receipt = OrderReceipt {
+            payload: format!(r#"{{"order_id":"{}"}}"#, uuid),
+            zbase_signature: "d6tqaeuo".to_string(),
+        };
+
+        let mut tx = db.begin().await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: Lsps1OrderState::Completed,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        SetReceiptQuery {
+            order_uuid: uuid,
+            receipt: receipt.clone(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let response = get_order_response(&db, uuid, true).await.unwrap();
+        assert_eq!(response.receipt, Some(receipt));
+
+        // The receipt is an extension
+        let response = get_order_response(&db, uuid, false).await.unwrap();
+        assert!(response.receipt.is_none());
+        let value = serde_json::to_value(&response).unwrap();
+        assert!(value.get("receipt").is_none());
+    }
+
+    /// Stores the channel of the order created by [`insert_order`]
+    async fn insert_order_with_channel(db: &Database) -> Uuid {
+        let uuid = insert_order(db).await;
+        let channel = Lsps1Channel {
+            funding_txid: FUNDING_TXID.parse().unwrap(),
+            outnum: 1,
+            funded_at: IsoDatetime::now(),
+        };
+
+        let mut tx = db.begin().await.unwrap();
+        CreateChannelQuery::new(uuid, channel)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        uuid
+    }
+
+    #[tokio::test]
+    async fn get_order_reports_alias_of_zero_conf_channel() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_order_with_channel(&db).await;
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 1, "CHANNELD_NORMAL", Some("8573349x2947x1")),
+        );
+
+        let mut response = get_order_response(&db, uuid, true).await.unwrap();
+        add_channel_usability(&state, &mut rpc, &peer_id(), &mut response).await;
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["channel"]["scid_alias"], "8573349x2947x1");
+        assert_eq!(value["channel"]["usable"], true);
+    }
+
+    #[tokio::test]
+    async fn get_order_reports_unconfirmed_channel_as_unusable() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_order_with_channel(&db).await;
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("listpeerchannels", "Connection refused").respond(
+            "listpeerchannels",
+            listpeerchannels(FUNDING_TXID, 1, "CHANNELD_AWAITING_LOCKIN", None),
+        );
+
+        // The order is returned without the extension if the lookup fails
+        let mut response = get_order_response(&db, uuid, true).await.unwrap();
+        add_channel_usability(&state, &mut rpc, &peer_id(), &mut response).await;
+        let value = serde_json::to_value(&response).unwrap();
+        assert!(value["channel"].get("usable").is_none());
+
+        let mut response = get_order_response(&db, uuid, true).await.unwrap();
+        add_channel_usability(&state, &mut rpc, &peer_id(), &mut response).await;
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["channel"]["usable"], false);
+        assert!(value["channel"].get("scid_alias").is_none());
+    }
+
+    #[tokio::test]
+    async fn get_unknown_order_is_not_found() {
+        let db = get_db().await;
+        let order_watcher = OrderWatcher::default();
+
+        let error = get_order_with_wait(&db, &order_watcher, peer_id(), Uuid::new_v4(), true, None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.reason(), Some(LspsErrorReason::NotFound));
+    }
+
+    #[tokio::test]
+    async fn database_failure_is_not_sent_to_peer() {
+        let logs = capture_logs();
+        let (db, _) = get_temp_db().await;
+        let uuid = insert_order(&db).await;
+        let mut tx = db.begin().await.unwrap();
+        sqlx::query("DROP TABLE lsps1_channel")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let error = get_order_response(&db, uuid, true).await.unwrap_err();
+        let error = serde_json::to_value(error.into_error_data()).unwrap();
+        let incident_id = error["data"]["incident_id"].as_str().unwrap();
+        assert_eq!(error["data"]["reason"], "internal");
+        assert!(!error.to_string().contains("lsps1_channel"));
+
+        let logged = logs.containing(incident_id);
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].contains("no such table: lsps1_channel"));
+    }
+
+    #[tokio::test]
+    async fn corrupt_order_is_quarantined() {
+        let (db, _) = get_temp_db().await;
+        let order_watcher = OrderWatcher::default();
+        let quarantine = Quarantine::default();
+        let uuid = insert_order(&db).await;
+        let mut tx = db.begin().await.unwrap();
+        sqlx::query("UPDATE lsps1_order SET created_at = -1 WHERE uuid = ?1")
+            .bind(uuid.to_string())
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let error = get_order_with_wait(&db, &order_watcher, peer_id(), uuid, true, None)
+            .await
+            .unwrap_err()
+            .quarantine_corrupt_row(&quarantine);
+        assert_eq!(
+            error.reason(),
+            Some(LspsErrorReason::TemporarilyUnavailable)
+        );
+
+        let quarantined = quarantine.list();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].table, "lsps1_order");
+        assert!(quarantined[0].error.contains("-1"));
+    }
 }
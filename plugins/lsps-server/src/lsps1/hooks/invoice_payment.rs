@@ -1,156 +1,508 @@
-use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
 use cln_plugin::Plugin;
 use cln_rpc::ClnRpc;
+use serde_json::json;
+use uuid::Uuid;
 
 use lsp_primitives::lsps0::common_schemas::SatAmount;
-use lsp_primitives::lsps1::schema::{OrderState, PaymentState};
+use lsp_primitives::lsps1::schema::PaymentState;
 
-use crate::channel_open::{fundchannel_fallible, ChannelDetails};
 use crate::cln::hooks::invoice_payment::InvoicePaymentHookResponse;
 use crate::cln::hooks::invoice_payment::Payment;
-use crate::db::sqlite::queries::{CreateChannelQuery, GetOrderQuery, UpdateOrderStateQuery};
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::Clock;
+use crate::db::schema::{InvoiceLabel, Lsps1OrderState, OrderLogEvent};
+use crate::db::sqlite::queries::{GetOrderQuery, UpdateOrderStateQuery};
 use crate::db::sqlite::queries::{GetPaymentDetailsQuery, UpdatePaymentStateQuery};
+use crate::db::sqlite::{retry_on_busy, Database};
+use crate::lsps1::channel_open::is_peer_connected;
+use crate::lsps1::open_queue::OpenQueue;
+use crate::lsps1::order_expiry::is_past_payment_grace;
+use crate::lsps1::order_log::OrderLogger;
+use crate::lsps1::order_watcher::OrderWatcher;
+use crate::metrics::PaymentMetrics;
 use crate::state::PluginState;
 
 pub(crate) async fn invoice_payment(
     plugin: Plugin<PluginState>,
     payment: &Payment,
 ) -> Result<InvoicePaymentHookResponse> {
-    let db = &plugin.state().database;
-    let mut tx = db.begin().await?;
-
-    log::debug!("Looking for payment with label in database");
-    // Check if we should handle the invoice_payment hook
-    // We'll only handle the hook if we are sure the payment
-    // is lsps1-related
-    let payment_details: Option<crate::db::schema::Lsps1PaymentDetails> =
-        GetPaymentDetailsQuery::ByLabel(String::from(payment.label.clone()))
-            .execute(&mut tx)
-            .await
-            .with_context(|| {
-                "Failed to execute 'get_payment_details_by_label'-query on database"
-            })?;
+    let state = plugin.state();
+    let rpc_path = plugin.configuration().rpc_file;
+    let rpc_path = &rpc_path;
+
+    // Concurrent hooks can find the database locked. The payment is
+    // accepted from scratch. A payment that was accepted by the failed
+    // attempt was rolled back, so it is accepted once
+    retry_on_busy(move || async move {
+        let mut rpc = ClnRpc::new(rpc_path).await?;
+        accept_payment(
+            &state.database,
+            &mut rpc,
+            &state.order_watcher,
+            &state.open_queue,
+            &state.order_log,
+            state.clock.as_ref(),
+            state.payment_grace,
+            &state.metrics.payments,
+            payment,
+        )
+        .await
+    })
+    .await
+}
 
-    if payment_details.is_none() {
-        // The lsps1-plugin can ignore this payment
-        // This payment is unrelated
+/// Accepts the payment for an order and queues the channel open
+///
+/// The hook is called for every invoice paid to the node. Invoices whose
+/// label wasn't created by [`InvoiceLabel::for_order`] are ignored without
+/// touching the database.
+///
+/// The hook doesn't wait for the channel to be opened. That would delay
+/// the settlement of the invoice and can exceed the timeout of the hook.
+/// The order moves to `PendingOpen` and is picked up by the [`OpenQueue`].
+///
+/// If the client is offline the order can expire before the channel is
+/// opened. The LSP can only refund such an order if it has a
+/// `refund_onchain_address`. Otherwise the payment is rejected.
+///
+/// A payment that arrives after the order expired is accepted until
+/// `payment_grace` has elapsed as well.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn accept_payment(
+    db: &Database,
+    rpc: &mut dyn ClnRpcApi,
+    order_watcher: &OrderWatcher,
+    open_queue: &OpenQueue,
+    order_log: &OrderLogger,
+    clock: &dyn Clock,
+    payment_grace: Duration,
+    metrics: &PaymentMetrics,
+    payment: &Payment,
+) -> Result<InvoicePaymentHookResponse> {
+    let Some(order_uuid) = InvoiceLabel::parse_order_uuid(&payment.label) else {
+        // The invoice doesn't pay for an LSPS1-order
+        metrics.skipped();
         return Ok(InvoicePaymentHookResponse::Continue);
-    }
-    let payment_details = payment_details.ok_or_else(|| anyhow!("No payment details"))?;
-
-    // Set the payment-state to hold in the database
-    // The hook is called so we have received the HTLC
-    UpdatePaymentStateQuery {
-        state: PaymentState::Hold,
-        generation: payment_details.generation,
-        label: payment.label.to_string(),
-    }
-    .execute(&mut tx)
-    .await?;
+    };
+    let label = InvoiceLabel::for_order(&order_uuid)?;
 
-    tx.commit().await?;
+    let decision = db
+        .with_tx("accept_payment", move |tx| {
+            Box::pin(async move {
+                let payment_details = GetPaymentDetailsQuery::ByLabel(label.clone())
+                    .execute(tx)
+                    .await
+                    .context("Failed to execute 'get_payment_details_by_label'-query on database")?;
 
-    let mut tx = db.begin().await?;
+                let Some(payment_details) = payment_details else {
+                    // We created an invoice with this label but lost track of the order
+                    log::warn!("Received payment for unknown order with label {}", label);
+                    metrics.mismatched();
+                    return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Continue));
+                };
 
-    let order_details: crate::db::schema::Lsps1Order =
-        GetOrderQuery::by_uuid(payment_details.order_uuid)
-            .execute(&mut tx)
-            .await
-            .context("Failed to execute 'get_order_details'-query on database")?
-            .context("Failed to find order that corresponds to payment")?;
-    let peer_id = order_details.client_node_id;
-    tx.commit().await?;
-
-    // Here we attempt to open the channel
-    // This might take a while because we need to reach out
-    // to our peer and might want to wait for channel confirmation
-    //
-    // This op should be cancellable
-    let rpc_path = plugin.configuration().rpc_file;
-    log::debug!("Attempt to connect to rpc-interface '{}'", rpc_path);
-    let mut rpc = ClnRpc::new(rpc_path).await?;
-
-    let timeout = std::time::Duration::from_secs(60);
-    let amount = order_details
-        .client_balance_sat
-        .checked_add(&order_details.lsp_balance_sat)
-        .context("Overflow when computing channel capacity")?;
-
-    // Adjust the fee-rate based on funding_confirms_within_blocks
-    // TODO
-
-    // Get the mindepth from the config
-    let mindepth = plugin
-        .state()
-        .lsps1_info
-        .as_ref()
-        .as_ref()
-        .map(|x| x.options.min_required_channel_confirmations);
-
-    let channel_details = ChannelDetails {
-        peer_id: order_details.client_node_id,
-        amount,
-        feerate: None,
-        announce: Some(order_details.announce_channel),
-        mindepth,
-        push_msat: Some(order_details.client_balance_sat),
-        reserve: Some(SatAmount::new(0)),
-        close_to: None,
-    };
+                let order = GetOrderQuery::by_uuid(payment_details.order_uuid)
+                    .execute(tx)
+                    .await
+                    .context("Failed to execute 'get_order_details'-query on database")?
+                    .context("Failed to find order that corresponds to payment")?;
 
-    log::debug!("Atempting to open channel ");
-    let channel_result = fundchannel_fallible(&mut rpc, &channel_details, timeout).await;
+                match payment_details.state {
+                    PaymentState::ExpectPayment => {}
+                    PaymentState::Hold | PaymentState::Paid => {
+                        // The hook can be called again for a payment we already accepted,
+                        // e.g. after a restart. The queue ignores orders that are in progress
+                        log::debug!("Payment for order {} was already accepted", order.uuid);
+                        if order.order_state == Lsps1OrderState::PendingOpen {
+                            open_queue.enqueue(order.uuid);
+                        }
+                        return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Continue));
+                    }
+                    PaymentState::Refunded => {
+                        log::info!("Rejecting payment for refunded order {}", order.uuid);
+                        return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Reject));
+                    }
+                }
 
-    let mut tx = db.begin().await?;
-    match channel_result {
-        Ok(channelopen_response) => {
-            log::info!(
-                "Successfully opened channel to {:?} and received payment",
-                peer_id
-            );
+                if order.order_state != Lsps1OrderState::Created {
+                    log::info!(
+                        "Rejecting payment for order {} in state {:?}",
+                        order.uuid,
+                        order.order_state
+                    );
+                    return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Reject));
+                }
 
-            CreateChannelQuery::new(order_details.uuid, channelopen_response)
-                .execute(&mut tx)
+                let now = clock.now();
+                if is_past_payment_grace(&order.expires_at, payment_grace, &now) {
+                    log::info!("Rejecting payment for expired order {}", order.uuid);
+                    return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Reject));
+                }
+                if order.expires_at.unix_timestamp() < now.unix_timestamp() {
+                    log::info!(
+                        "Accepting payment for order {} within the grace period after it expired",
+                        order.uuid
+                    );
+                }
+
+                let order_total_msat = payment_details
+                    .order_total_sat
+                    .sat_value()
+                    .saturating_mul(1000);
+                if payment.msat.msat < order_total_msat {
+                    log::warn!(
+                        "Rejecting payment of {} msat for order {} which costs {} msat",
+                        payment.msat.msat,
+                        order.uuid,
+                        order_total_msat
+                    );
+                    metrics.mismatched();
+                    order_log.warn(
+                        order.uuid,
+                        OrderLogEvent::PaymentRejected,
+                        json!({
+                            "paid_msat" : payment.msat.msat,
+                            "order_total_sat" : payment_details.order_total_sat,
+                        }),
+                    );
+                    return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Reject));
+                }
+
+                if order.refund_onchain_address.is_none()
+                    && !is_peer_connected(rpc, &order.client_node_id).await?
+                {
+                    log::info!(
+                        "Rejecting payment for order {}. Peer {:?} is offline and there is no refund address",
+                        order.uuid,
+                        order.client_node_id
+                    );
+                    order_log.warn(
+                        order.uuid,
+                        OrderLogEvent::PaymentRejected,
+                        json!({ "peer_connected" : false }),
+                    );
+                    return Ok(PaymentDecision::Respond(InvoicePaymentHookResponse::Reject));
+                }
+
+                UpdatePaymentStateQuery {
+                    state: PaymentState::Paid,
+                    changed_at: clock.now(),
+                    generation: payment_details.generation,
+                    label,
+                }
+                .execute(tx)
                 .await?;
 
-            UpdatePaymentStateQuery {
-                state: PaymentState::Paid,
-                generation: payment_details.generation + 1,
-                label: payment.label.to_string(),
-            }
-            .execute(&mut tx)
-            .await?;
+                UpdateOrderStateQuery {
+                    order_uuid: order.uuid,
+                    state: Lsps1OrderState::PendingOpen,
+                    generation: order.generation,
+                    changed_at: clock.now(),
+                    failure_reason: None,
+                }
+                .execute(tx)
+                .await?;
+
+                Ok(PaymentDecision::Accepted {
+                    order_uuid: order.uuid,
+                    order_total_sat: payment_details.order_total_sat,
+                })
+            })
+        })
+        .await?;
+
+    let (order_uuid, order_total_sat) = match decision {
+        PaymentDecision::Respond(response) => return Ok(response),
+        PaymentDecision::Accepted {
+            order_uuid,
+            order_total_sat,
+        } => (order_uuid, order_total_sat),
+    };
+    order_watcher.notify(order_uuid);
+
+    log::info!("Received payment for order {}", order_uuid);
+    metrics.processed();
+    order_log.info(
+        order_uuid,
+        OrderLogEvent::PaymentAccepted,
+        json!({ "order_total_sat" : order_total_sat }),
+    );
+    open_queue.enqueue(order_uuid);
+    Ok(InvoicePaymentHookResponse::Continue)
+}
+
+/// What [`accept_payment`] decided while the transaction was open
+enum PaymentDecision {
+    /// The hook responds without accepting the payment
+    Respond(InvoicePaymentHookResponse),
+    /// The order moved to `PendingOpen`
+    Accepted {
+        order_uuid: Uuid,
+        order_total_sat: SatAmount,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-            UpdateOrderStateQuery {
-                order_uuid: order_details.uuid,
-                state: OrderState::Completed,
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::cln::hooks::invoice_payment::AmountMsat;
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+    use crate::clock::SystemClock;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+    use crate::lsps1::order_expiry::DEFAULT_PAYMENT_GRACE;
+    use crate::lsps1::order_log::ORDER_LOG_CAPACITY;
+
+    const REFUND_ADDRESS: &str = "bcrt1qxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzx";
+
+    struct Hook {
+        db: Database,
+        rpc: FakeClnRpc,
+        clock: MockClock,
+        watcher: OrderWatcher,
+        queue: OpenQueue,
+        metrics: PaymentMetrics,
+    }
+
+    impl Hook {
+        async fn new() -> Self {
+            Self {
+                db: get_db().await,
+                rpc: FakeClnRpc::default(),
+                clock: MockClock::new(IsoDatetime::now()),
+                watcher: OrderWatcher::default(),
+                queue: OpenQueue::default(),
+                metrics: PaymentMetrics::default(),
             }
-            .execute(&mut tx)
-            .await?;
+        }
 
-            tx.commit().await?;
-            return Ok(InvoicePaymentHookResponse::Continue);
+        /// Creates an order that costs 500 sat and returns the label of its invoice
+        async fn create_order(&self) -> (uuid::Uuid, String) {
+            self.create_order_with_refund_address(Some(REFUND_ADDRESS))
+                .await
         }
-        Err(err) => {
-            log::info!("Refund payment for LSPS1-channel. Channel open failed");
-            log::warn!("Error: {}", err);
-            UpdatePaymentStateQuery {
-                state: PaymentState::Refunded,
-                generation: payment_details.generation + 1,
-                label: payment.label.to_string(),
-            }
-            .execute(&mut tx)
-            .await?;
 
-            UpdateOrderStateQuery {
-                order_uuid: order_details.uuid,
-                state: OrderState::Failed,
-            }
-            .execute(&mut tx)
-            .await?;
+        async fn create_order_with_refund_address(
+            &self,
+            refund_address: Option<&str>,
+        ) -> (uuid::Uuid, String) {
+            let mut query = create_order_query();
+            query.order.expires_at =
+                IsoDatetime::from_unix_timestamp(self.clock.now().unix_timestamp() + 3600).unwrap();
+            query.order.refund_onchain_address = refund_address.map(|a| a.to_string());
+            let uuid = query.order.uuid;
+            let label = query.payment.bolt11_invoice_label.to_string();
 
-            tx.commit().await?;
-            return Ok(InvoicePaymentHookResponse::Reject);
+            let mut tx = self.db.begin().await.unwrap();
+            query.execute(&mut tx).await.unwrap();
+            tx.commit().await.unwrap();
+            (uuid, label)
         }
+
+        async fn pay(&self, label: &str, msat: u64) -> InvoicePaymentHookResponse {
+            let order_log = OrderLogger::new(Arc::new(SystemClock), ORDER_LOG_CAPACITY).0;
+            let payment = Payment {
+                label: label.to_string(),
+                preimage: "00".repeat(32),
+                msat: AmountMsat { msat },
+            };
+            accept_payment(
+                &self.db,
+                &mut self.rpc.clone(),
+                &self.watcher,
+                &self.queue,
+                &order_log,
+                &self.clock,
+                DEFAULT_PAYMENT_GRACE,
+                &self.metrics,
+                &payment,
+            )
+            .await
+            .unwrap()
+        }
+
+        async fn get_states(&self, uuid: uuid::Uuid) -> (Lsps1OrderState, PaymentState) {
+            let mut tx = self.db.begin().await.unwrap();
+            let order = GetOrderQuery::by_uuid(uuid)
+                .execute(&mut tx)
+                .await
+                .unwrap()
+                .unwrap();
+            let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+                .execute(&mut tx)
+                .await
+                .unwrap()
+                .unwrap();
+            tx.commit().await.unwrap();
+            (order.order_state, payment.state)
+        }
+    }
+
+    #[tokio::test]
+    async fn payment_is_accepted_and_queued() {
+        let hook = Hook::new().await;
+        let (uuid, label) = hook.create_order().await;
+
+        // The hook doesn't wait for the channel to be opened
+        let start = Instant::now();
+        let response = hook.pay(&label, 500_000).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        assert!(hook.queue.is_in_flight(&uuid));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::PendingOpen, PaymentState::Paid)
+        );
+
+        // Calling the hook again doesn't change the order
+        let response = hook.pay(&label, 500_000).await;
+        assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::PendingOpen, PaymentState::Paid)
+        );
+        assert_eq!(
+            hook.metrics.to_json(),
+            json!({ "skipped" : 0, "processed" : 1, "mismatched" : 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_within_grace_period_is_accepted() {
+        let hook = Hook::new().await;
+        let (uuid, label) = hook.create_order().await;
+
+        // The order expired but the grace period hasn't elapsed
+        hook.clock
+            .advance(Duration::from_secs(3600) + DEFAULT_PAYMENT_GRACE - Duration::from_secs(1));
+        let response = hook.pay(&label, 500_000).await;
+
+        assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        assert!(hook.queue.is_in_flight(&uuid));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::PendingOpen, PaymentState::Paid)
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_for_expired_order_is_rejected() {
+        let hook = Hook::new().await;
+        let (uuid, label) = hook.create_order().await;
+
+        // The grace period has elapsed as well
+        hook.clock
+            .advance(Duration::from_secs(3600) + DEFAULT_PAYMENT_GRACE + Duration::from_secs(1));
+        let response = hook.pay(&label, 500_000).await;
+
+        assert!(matches!(response, InvoicePaymentHookResponse::Reject));
+        assert!(!hook.queue.is_in_flight(&uuid));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::Created, PaymentState::ExpectPayment)
+        );
+    }
+
+    #[tokio::test]
+    async fn unrelated_payment_is_ignored() {
+        let hook = Hook::new().await;
+        // The database is never queried
+        hook.db.close().await;
+
+        let uuid = uuid::Uuid::new_v4();
+        let labels = [
+            "not-an-lsps1-label".to_string(),
+            "label with\nnewline".to_string(),
+            uuid.to_string(),
+            format!("lsps1_{}", uuid.to_string().to_uppercase()),
+            format!("lsps1_{}_tip", uuid),
+        ];
+        for label in labels {
+            let response = hook.pay(&label, 500_000).await;
+            assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        }
+        assert_eq!(
+            hook.metrics.to_json(),
+            json!({ "skipped" : 5, "processed" : 0, "mismatched" : 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_for_unknown_order_is_ignored() {
+        let hook = Hook::new().await;
+        let label = InvoiceLabel::for_order(&uuid::Uuid::new_v4()).unwrap();
+
+        let response = hook.pay(label.as_str(), 500_000).await;
+        assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        assert_eq!(
+            hook.metrics.to_json(),
+            json!({ "skipped" : 0, "processed" : 0, "mismatched" : 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn underpayment_is_rejected() {
+        let hook = Hook::new().await;
+        let (uuid, label) = hook.create_order().await;
+
+        let response = hook.pay(&label, 499_999).await;
+        assert!(matches!(response, InvoicePaymentHookResponse::Reject));
+        assert!(!hook.queue.is_in_flight(&uuid));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::Created, PaymentState::ExpectPayment)
+        );
+
+        // Paying more than required is fine
+        let response = hook.pay(&label, 500_001).await;
+        assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::PendingOpen, PaymentState::Paid)
+        );
+        assert_eq!(
+            hook.metrics.to_json(),
+            json!({ "skipped" : 0, "processed" : 1, "mismatched" : 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn payment_without_refund_address_requires_online_peer() {
+        let hook = Hook::new().await;
+        let (uuid, label) = hook.create_order_with_refund_address(None).await;
+        let client_node_id = create_order_query().order.client_node_id.to_hex();
+
+        // The order couldn't be refunded if the peer doesn't come back
+        hook.rpc.respond(
+            "listpeers",
+            json!({ "peers" : [{ "id" : client_node_id, "connected" : false }] }),
+        );
+        let response = hook.pay(&label, 500_000).await;
+        assert!(matches!(response, InvoicePaymentHookResponse::Reject));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::Created, PaymentState::ExpectPayment)
+        );
+
+        hook.rpc.respond(
+            "listpeers",
+            json!({ "peers" : [{ "id" : client_node_id, "connected" : true }] }),
+        );
+        let response = hook.pay(&label, 500_000).await;
+        assert!(matches!(response, InvoicePaymentHookResponse::Continue));
+        assert_eq!(
+            hook.get_states(uuid).await,
+            (Lsps1OrderState::PendingOpen, PaymentState::Paid)
+        );
     }
 }
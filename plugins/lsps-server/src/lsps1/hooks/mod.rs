@@ -1,7 +1,14 @@
+mod channel_state_changed;
+mod connect;
 mod custommsg;
 mod invoice_payment;
 
+pub(crate) use crate::lsps1::hooks::channel_state_changed::{
+    backfill_funding_blockheights, channel_state_changed, check_channel_opening_order,
+    reconcile_channel_opening_orders, FUNDING_BLOCKHEIGHT_BACKFILL_INTERVAL,
+};
+pub(crate) use crate::lsps1::hooks::connect::connect;
 pub(crate) use crate::lsps1::hooks::custommsg::{
-    do_lsps1_create_order, do_lsps1_get_info, do_lsps1_get_order,
+    do_lsps1_create_order, do_lsps1_get_info, do_lsps1_get_order, DisabledResponse,
 };
 pub(crate) use crate::lsps1::hooks::invoice_payment::*;
@@ -0,0 +1,387 @@
+//! Reports the leases the LSP is obligated to keep open
+//!
+//! A lease starts in the block in which the funding transaction confirmed
+//! and lasts `channel_expiry_blocks`. The LSP shouldn't close the channel
+//! before the lease ends.
+//!
+//! If `lsps1-usage-sampling-minutes` is set the report also shows how
+//! much each channel was used. See [`crate::lsps1::channel_usage`].
+//!
+//! Leases that can't be read from the database are skipped and listed in
+//! `corrupt_rows`. See [`crate::db::sqlite::quarantine`].
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::GetinfoRequest;
+use cln_rpc::ClnRpc;
+use serde::Serialize;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::{IsoDatetime, PublicKey};
+use lsp_primitives::sat_amount_json;
+
+use crate::admin_timestamp;
+use crate::db::sqlite::quarantine::CorruptRow;
+use crate::db::sqlite::queries::{ActiveLease, GetActiveLeasesQuery, GetChannelUsageQuery};
+use crate::lsps1::channel_usage::{summarize_channel_usage, ChannelUsageSummary};
+use crate::plugin_rpc::Lsps1AdminLeaseReportRequest;
+use crate::state::PluginState;
+
+/// Used to estimate when a lease ends
+pub(crate) const SECONDS_PER_BLOCK: i64 = 600;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct LeaseReportEntry {
+    pub(crate) order_id: Uuid,
+    pub(crate) client_node_id: PublicKey,
+    pub(crate) funding_outpoint: String,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) capacity_sat: u64,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) lsp_balance_sat: u64,
+    pub(crate) channel_expiry_blocks: u32,
+    pub(crate) funding_blockheight: Option<u32>,
+    pub(crate) expires_at_blockheight: Option<u32>,
+    pub(crate) blocks_remaining: Option<u32>,
+    /// An estimate based on 10 minute blocks
+    #[serde(serialize_with = "admin_timestamp::serialize_option")]
+    pub(crate) earliest_close_at: Option<IsoDatetime>,
+    /// `None` if the channel has never been sampled
+    pub(crate) usage: Option<ChannelUsageSummary>,
+    /// The label of the funding source that funded the channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) funding_source: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct LeaseReportTotals {
+    pub(crate) lease_count: usize,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) committed_lsp_balance_sat: u64,
+    /// Average of `blocks_remaining` weighted by `lsp_balance_sat`
+    ///
+    /// Leases with an unknown funding height are not included
+    pub(crate) weighted_average_blocks_remaining: Option<u64>,
+    pub(crate) unknown_funding_height_count: usize,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct LeaseReport {
+    pub(crate) blockheight: u32,
+    pub(crate) leases: Vec<LeaseReportEntry>,
+    pub(crate) totals: LeaseReportTotals,
+    pub(crate) corrupt_rows: Vec<CorruptRow>,
+}
+
+/// Handles `lsps1-admin-lease-report`
+pub(crate) async fn lsps1_admin_lease_report(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminLeaseReportRequest = serde_json::from_value(request)?;
+    let rpc_path = plugin.configuration().rpc_file;
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let blockheight = rpc
+        .call_typed(&GetinfoRequest {})
+        .await
+        .context("Failed to call 'getinfo'")?
+        .blockheight;
+
+    let state = plugin.state();
+    let mut tx = state.database.begin().await?;
+    let leases = GetActiveLeasesQuery::at_blockheight(blockheight)
+        .execute_quarantined(&mut tx)
+        .await
+        .context("Failed to execute 'get_active_leases'-query on database")?;
+    state.quarantine.record(&leases.corrupt_rows);
+
+    let mut usage = HashMap::new();
+    for lease in &leases.rows {
+        let samples = GetChannelUsageQuery::by_order_id(lease.order_uuid)
+            .execute(&mut tx)
+            .await
+            .context("Failed to execute 'get_channel_usage'-query on database")?;
+        if !samples.is_empty() {
+            usage.insert(lease.order_uuid, summarize_channel_usage(&samples)?);
+        }
+    }
+    tx.commit().await?;
+
+    let mut report = build_lease_report(&leases.rows, &usage, blockheight, &state.clock.now())?;
+    report.corrupt_rows = leases.corrupt_rows;
+    Ok(admin_timestamp::render(
+        serde_json::to_value(report)?,
+        request.epoch_only,
+    ))
+}
+
+pub(crate) fn build_lease_report(
+    leases: &[ActiveLease],
+    usage: &HashMap<Uuid, ChannelUsageSummary>,
+    blockheight: u32,
+    now: &IsoDatetime,
+) -> Result<LeaseReport> {
+    let mut entries = Vec::with_capacity(leases.len());
+    let mut committed_lsp_balance_sat: u64 = 0;
+    let mut weighted_blocks: u128 = 0;
+    let mut total_weight: u128 = 0;
+    let mut unknown_funding_height_count = 0;
+
+    for lease in leases {
+        let lsp_balance_sat = lease.lsp_balance_sat.sat_value();
+        let capacity_sat = lease
+            .lsp_balance_sat
+            .checked_add(&lease.client_balance_sat)
+            .context("Channel capacity overflows")?
+            .sat_value();
+
+        let expires_at_blockheight = lease
+            .funding_blockheight
+            .map(|height| height.saturating_add(lease.channel_expiry_blocks));
+        let blocks_remaining = expires_at_blockheight.map(|h| h.saturating_sub(blockheight));
+        let earliest_close_at = blocks_remaining
+            .map(|blocks| {
+                let timestamp = now.unix_timestamp() + i64::from(blocks) * SECONDS_PER_BLOCK;
+                IsoDatetime::from_unix_timestamp(timestamp)
+            })
+            .transpose()?;
+
+        committed_lsp_balance_sat = committed_lsp_balance_sat
+            .checked_add(lsp_balance_sat)
+            .context("Committed balance overflows")?;
+        match blocks_remaining {
+            Some(blocks) => {
+                weighted_blocks += u128::from(lsp_balance_sat) * u128::from(blocks);
+                total_weight += u128::from(lsp_balance_sat);
+            }
+            None => unknown_funding_height_count += 1,
+        }
+
+        entries.push(LeaseReportEntry {
+            order_id: lease.order_uuid,
+            client_node_id: lease.client_node_id,
+            funding_outpoint: format!("{}:{}", lease.channel.funding_txid, lease.channel.outnum),
+            capacity_sat,
+            lsp_balance_sat,
+            channel_expiry_blocks: lease.channel_expiry_blocks,
+            funding_blockheight: lease.funding_blockheight,
+            expires_at_blockheight,
+            blocks_remaining,
+            earliest_close_at,
+            usage: usage.get(&lease.order_uuid).cloned(),
+            funding_source: lease.funding_source.clone(),
+        });
+    }
+
+    // The leases that end first come first. Unknown heights come last
+    entries.sort_by_key(|e| {
+        (
+            e.expires_at_blockheight.is_none(),
+            e.expires_at_blockheight,
+            e.order_id,
+        )
+    });
+
+    let weighted_average_blocks_remaining = weighted_blocks
+        .checked_div(total_weight)
+        .map(u64::try_from)
+        .transpose()?;
+
+    Ok(LeaseReport {
+        blockheight,
+        totals: LeaseReportTotals {
+            lease_count: entries.len(),
+            committed_lsp_balance_sat,
+            weighted_average_blocks_remaining,
+            unknown_funding_height_count,
+        },
+        leases: entries,
+        corrupt_rows: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use lsp_primitives::lsps0::common_schemas::{SatAmount, TransactionId};
+
+    use crate::db::schema::Lsps1Channel;
+    use crate::db::sqlite::test::create_test_order;
+
+    fn lease(
+        lsp_balance_sat: u64,
+        channel_expiry_blocks: u32,
+        funding_blockheight: Option<u32>,
+    ) -> ActiveLease {
+        let order = create_test_order();
+        ActiveLease {
+            order_uuid: Uuid::new_v4(),
+            client_node_id: order.client_node_id,
+            lsp_balance_sat: SatAmount::new(lsp_balance_sat),
+            client_balance_sat: SatAmount::new(10_000),
+            channel_expiry_blocks,
+            channel: Lsps1Channel {
+                funding_txid: TransactionId::from_str(
+                    "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                )
+                .unwrap(),
+                outnum: 1,
+                funded_at: IsoDatetime::now(),
+            },
+            funding_blockheight,
+            funding_source: None,
+        }
+    }
+
+    #[test]
+    fn remaining_blocks_and_totals() {
+        let now = IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap();
+        let leases = vec![
+            // Ends at 5_320: 320 blocks remaining
+            lease(1_000_000, 4_320, Some(1_000)),
+            // Ends at 5_100: 100 blocks remaining
+            lease(3_000_000, 1_000, Some(4_100)),
+            lease(500_000, 4_320, None),
+        ];
+
+        let report = build_lease_report(&leases, &HashMap::new(), 5_000, &now).unwrap();
+
+        let remaining: Vec<_> = report
+            .leases
+            .iter()
+            .map(|l| (l.expires_at_blockheight, l.blocks_remaining))
+            .collect();
+        assert_eq!(
+            remaining,
+            vec![
+                (Some(5_100), Some(100)),
+                (Some(5_320), Some(320)),
+                (None, None)
+            ]
+        );
+        assert_eq!(report.leases[0].order_id, leases[1].order_uuid);
+        assert_eq!(report.leases[0].capacity_sat, 3_010_000);
+        assert_eq!(
+            report.leases[0].funding_outpoint,
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1"
+        );
+
+        // 100 blocks of 10 minutes after `now`
+        let close_at = IsoDatetime::from_unix_timestamp(1_700_060_000).unwrap();
+        assert_eq!(report.leases[0].earliest_close_at, Some(close_at));
+        assert_eq!(report.leases[2].earliest_close_at, None);
+        assert_eq!(report.leases[0].usage, None);
+
+        // (1_000_000 * 320 + 3_000_000 * 100) / 4_000_000 = 155
+        assert_eq!(
+            report.totals,
+            LeaseReportTotals {
+                lease_count: 3,
+                committed_lsp_balance_sat: 4_500_000,
+                weighted_average_blocks_remaining: Some(155),
+                unknown_funding_height_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn usage_is_attached_to_its_lease() {
+        let leases = vec![
+            lease(1_000_000, 4_320, Some(1_000)),
+            lease(2_000_000, 4_320, None),
+        ];
+        let summary = ChannelUsageSummary {
+            sample_count: 2,
+            first_sampled_at: Some(IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap()),
+            last_sampled_at: Some(IsoDatetime::from_unix_timestamp(1_700_003_600).unwrap()),
+            utilization_percent: Some(25),
+            forwarded_in_count: 4,
+            forwarded_in_msat: 40_000,
+            forwarded_out_count: 3,
+            forwarded_out_msat: 30_000,
+            max_htlc_count: 1,
+        };
+        let usage = HashMap::from([(leases[1].order_uuid, summary.clone())]);
+
+        let report = build_lease_report(&leases, &usage, 5_000, &IsoDatetime::now()).unwrap();
+        assert_eq!(report.leases[0].usage, None);
+        assert_eq!(report.leases[1].order_id, leases[1].order_uuid);
+        assert_eq!(report.leases[1].usage, Some(summary));
+    }
+
+    #[test]
+    fn serialize_lease_report() {
+        let now = IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap();
+        let leases = vec![lease(1_000_000, 1_000, Some(4_100))];
+        let summary = ChannelUsageSummary {
+            sample_count: 2,
+            first_sampled_at: Some(now),
+            last_sampled_at: Some(IsoDatetime::from_unix_timestamp(1_700_003_600).unwrap()),
+            utilization_percent: None,
+            forwarded_in_count: 0,
+            forwarded_in_msat: 0,
+            forwarded_out_count: 0,
+            forwarded_out_msat: 0,
+            max_htlc_count: 0,
+        };
+        let usage = HashMap::from([(leases[0].order_uuid, summary)]);
+
+        let report = build_lease_report(&leases, &usage, 5_000, &now).unwrap();
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["leases"][0],
+            serde_json::json!({
+                "order_id" : leases[0].order_uuid,
+                "client_node_id" : leases[0].client_node_id,
+                "funding_outpoint" : "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:1",
+                "capacity_sat" : 1_010_000,
+                "lsp_balance_sat" : 1_000_000,
+                "channel_expiry_blocks" : 1_000,
+                "funding_blockheight" : 4_100,
+                "expires_at_blockheight" : 5_100,
+                "blocks_remaining" : 100,
+                "earliest_close_at" : { "iso" : "2023-11-15T14:53:20.000Z", "unix" : 1_700_060_000 },
+                "usage" : {
+                    "sample_count" : 2,
+                    "first_sampled_at" : { "iso" : "2023-11-14T22:13:20.000Z", "unix" : 1_700_000_000 },
+                    "last_sampled_at" : { "iso" : "2023-11-14T23:13:20.000Z", "unix" : 1_700_003_600 },
+                    "utilization_percent" : null,
+                    "forwarded_in_count" : 0,
+                    "forwarded_in_msat" : 0,
+                    "forwarded_out_count" : 0,
+                    "forwarded_out_msat" : 0,
+                    "max_htlc_count" : 0,
+                },
+            })
+        );
+
+        // The funding source is only shown if the order has one
+        let mut leases = leases;
+        leases[0].funding_source = Some("partner".to_string());
+        let with_source = build_lease_report(&leases, &HashMap::new(), 5_000, &now).unwrap();
+        let with_source = serde_json::to_value(&with_source).unwrap();
+        assert_eq!(with_source["leases"][0]["funding_source"], "partner");
+
+        let json = admin_timestamp::render(json, Some(true));
+        assert_eq!(json["leases"][0]["earliest_close_at"], 1_700_060_000);
+        assert_eq!(
+            json["leases"][0]["usage"]["first_sampled_at"],
+            1_700_000_000
+        );
+        assert_eq!(json["leases"][0]["usage"]["last_sampled_at"], 1_700_003_600);
+    }
+
+    #[test]
+    fn empty_report() {
+        let report =
+            build_lease_report(&[], &HashMap::new(), 800_000, &IsoDatetime::now()).unwrap();
+        assert!(report.leases.is_empty());
+        assert_eq!(report.totals.lease_count, 0);
+        assert_eq!(report.totals.committed_lsp_balance_sat, 0);
+        assert_eq!(report.totals.weighted_average_blocks_remaining, None);
+    }
+}
@@ -1,5 +1,30 @@
+pub(crate) mod admin;
+pub(crate) mod channel_open;
+pub(crate) mod channel_type;
+pub(crate) mod channel_usability;
+pub(crate) mod channel_usage;
+pub(crate) mod client_node;
+pub(crate) mod config_reload;
+pub(crate) mod confirmation_policy;
 pub(crate) mod fee_calc;
+pub(crate) mod fee_consistency;
+pub(crate) mod fee_simulation;
+pub(crate) mod funding_reservation;
+pub(crate) mod funding_source;
 pub(crate) mod hooks;
+pub(crate) mod info;
+pub(crate) mod lease_report;
 pub(crate) mod msg;
+pub(crate) mod onchain_payment;
+pub(crate) mod open_queue;
+pub(crate) mod order_expiry;
+pub(crate) mod order_log;
+pub(crate) mod order_watcher;
 pub(crate) mod payment_calc;
+pub(crate) mod receipt;
+pub(crate) mod refund;
+pub(crate) mod refund_address;
+pub(crate) mod reorg;
 pub(crate) mod state;
+pub(crate) mod status;
+pub(crate) mod token_report;
@@ -1,9 +1,23 @@
-use crate::db::schema::{Lsps1Order, Lsps1PaymentDetails};
-use lsp_primitives::lsps1::builders::{Lsps1CreateOrderResponseBuilder};
-use lsp_primitives::lsps1::schema::Payment;
+use anyhow::Result;
+use std::str::FromStr;
 
-pub trait BuildLsps1Order {
+use crate::db::schema::{Lsps1Channel, Lsps1Order, Lsps1PaymentDetails, Lsps1Refund};
+use lsp_primitives::lsps0::common_schemas::OnchainAddress;
+use lsp_primitives::lsps1::builders::Lsps1CreateOrderResponseBuilder;
+use lsp_primitives::lsps1::schema::Refund;
+
+/// Fills an LSPS1 order response using the rows of the database
+pub trait BuildLsps1Order: Sized {
     fn db_order(self, lsps1_order: Lsps1Order) -> Self;
+
+    fn db_payment(self, lsps1_payment: Lsps1PaymentDetails) -> Result<Self>;
+
+    /// The lease of the channel lasts `channel_expiry_blocks`
+    fn db_channel(
+        self,
+        lsps1_channel: Option<Lsps1Channel>,
+        channel_expiry_blocks: u32,
+    ) -> Result<Self>;
 }
 
 impl BuildLsps1Order for Lsps1CreateOrderResponseBuilder {
@@ -14,31 +28,36 @@ impl BuildLsps1Order for Lsps1CreateOrderResponseBuilder {
             .funding_confirms_within_blocks(order.funding_confirms_within_blocks)
             .required_channel_confirmations(order.required_channel_confirmations)
             .channel_expiry_blocks(order.channel_expiry_blocks)
-            .token(order.token.unwrap_or("".to_string()))
+            .token(order.token)
             .announce_channel(order.announce_channel)
             .created_at(order.created_at)
             .expires_at(order.expires_at)
-            .order_state(order.order_state)
+            .order_state(order.order_state.into())
     }
-}
 
+    fn db_payment(self, payment: Lsps1PaymentDetails) -> Result<Self> {
+        Ok(self.payment(payment.into_spec_payment()?))
+    }
 
+    fn db_channel(self, channel: Option<Lsps1Channel>, channel_expiry_blocks: u32) -> Result<Self> {
+        let channel = channel
+            .map(|channel| channel.into_spec_channel(channel_expiry_blocks))
+            .transpose()?;
+        Ok(self.channel(channel))
+    }
+}
 
-pub trait BuildUsingDbPayment {
-    fn from_db_payment(lsps1_payment: Lsps1PaymentDetails) -> Self;
+pub trait BuildUsingDbRefund: Sized {
+    fn from_db_refund(lsps1_refund: Lsps1Refund) -> Result<Self>;
 }
 
-impl BuildUsingDbPayment for Payment {
-    fn from_db_payment(payment: Lsps1PaymentDetails) -> Self {
-        Self {
-            fee_total_sat: payment.fee_total_sat,
-            order_total_sat: payment.order_total_sat,
-            bolt11_invoice: payment.bolt11_invoice,
-            min_fee_for_0conf: None,
-            min_onchain_payment_confirmations: None,
-            onchain_address: None,
-            onchain_payment: None,
-            state: payment.state,
-        }
+impl BuildUsingDbRefund for Refund {
+    fn from_db_refund(refund: Lsps1Refund) -> Result<Self> {
+        Ok(Self {
+            txid: refund.txid,
+            amount_sat: refund.amount_sat,
+            address: OnchainAddress::from_str(&refund.address)?,
+            broadcast_at: refund.broadcast_at,
+        })
     }
 }
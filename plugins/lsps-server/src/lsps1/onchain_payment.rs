@@ -0,0 +1,176 @@
+//! The options that enable on-chain payments
+//!
+//! An LSP that accepts on-chain payments advertises the smallest payment
+//! it accepts and the confirmations it requires. These are configured in
+//! `lsps1-min-onchain-payment-size-sat` and either
+//! `lsps1-min-onchain-payment-confirmations` or
+//! `lsps1-onchain-confirmation-tiers`. Setting only some of them is a
+//! mistake that is refused at startup.
+use anyhow::{bail, Result};
+use cln_plugin::Plugin;
+use lsp_primitives::lsps0::common_schemas::SatAmount;
+
+use crate::lsps1::confirmation_policy::ConfirmationPolicy;
+use crate::lsps1::state::sat_option;
+use crate::{options, PluginState};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OnchainPaymentConfig {
+    /// The smallest `order_total_sat` that can be paid on-chain
+    pub(crate) min_payment_size: SatAmount,
+    /// The confirmations an on-chain payment of an order requires
+    pub(crate) confirmation_policy: ConfirmationPolicy,
+}
+
+impl OnchainPaymentConfig {
+    /// Creates the config from the values of the plugin options
+    ///
+    /// Returns `None` if none of the options is set. The error lists
+    /// every option that is missing or conflicts with another one.
+    pub(crate) fn from_options(
+        min_payment_size_sat: Option<String>,
+        tiers: Option<String>,
+        min_onchain_payment_confirmations: Option<i64>,
+    ) -> Result<Option<Self>> {
+        let mut problems = Vec::new();
+        match (
+            &min_payment_size_sat,
+            &tiers,
+            &min_onchain_payment_confirmations,
+        ) {
+            (None, None, None) => return Ok(None),
+            (_, Some(_), Some(_)) => problems.push(format!(
+                "set either '{}' or '{}' but not both",
+                options::LSPS1_ONCHAIN_CONFIRMATION_TIERS,
+                options::LSPS1_MIN_ONCHAIN_PAYMENT_CONFIRMATIONS
+            )),
+            (_, None, None) => problems.push(format!(
+                "'{}' or '{}' is missing",
+                options::LSPS1_MIN_ONCHAIN_PAYMENT_CONFIRMATIONS,
+                options::LSPS1_ONCHAIN_CONFIRMATION_TIERS
+            )),
+            _ => {}
+        }
+        if min_payment_size_sat.is_none() {
+            problems.push(format!(
+                "'{}' is missing",
+                options::LSPS1_MIN_ONCHAIN_PAYMENT_SIZE_SAT
+            ));
+        }
+        if !problems.is_empty() {
+            bail!(
+                "On-chain payments are partially configured: {}",
+                problems.join(", ")
+            );
+        }
+
+        let min_payment_size = sat_option(
+            options::LSPS1_MIN_ONCHAIN_PAYMENT_SIZE_SAT,
+            min_payment_size_sat,
+        )?;
+        let confirmation_policy =
+            ConfirmationPolicy::from_options(tiers, min_onchain_payment_confirmations)?;
+        Ok(min_payment_size.zip(confirmation_policy).map(
+            |(min_payment_size, confirmation_policy)| Self {
+                min_payment_size,
+                confirmation_policy,
+            },
+        ))
+    }
+
+    /// Creates the config from the configured plugin options
+    pub(crate) fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Option<Self>> {
+        Self::from_options(
+            plugin.option(&options::lsps1_min_onchain_payment_size_sat())?,
+            plugin.option(&options::lsps1_onchain_confirmation_tiers())?,
+            plugin.option(&options::lsps1_min_onchain_payment_confirmations())?,
+        )
+    }
+
+    /// The confirmations of the smallest orders
+    ///
+    /// This is advertised as `min_onchain_payment_confirmations`
+    pub(crate) fn min_onchain_payment_confirmations(&self) -> u16 {
+        u16::from(self.confirmation_policy.min_confs())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn complete_config() {
+        let config = OnchainPaymentConfig::from_options(some("20_000"), None, Some(2))
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.min_payment_size, SatAmount::new(20_000));
+        assert_eq!(config.confirmation_policy, ConfirmationPolicy::uniform(2));
+        assert_eq!(config.min_onchain_payment_confirmations(), 2);
+
+        let config = OnchainPaymentConfig::from_options(some("20000"), some("0:1,1000000:6"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.min_onchain_payment_confirmations(), 1);
+        assert_eq!(
+            config
+                .confirmation_policy
+                .required_confs(SatAmount::new(1_000_000)),
+            6
+        );
+    }
+
+    #[test]
+    fn absent_config_disables_onchain_payments() {
+        assert_eq!(
+            OnchainPaymentConfig::from_options(None, None, None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn partial_config_lists_the_missing_options() {
+        let err = OnchainPaymentConfig::from_options(some("20000"), None, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "On-chain payments are partially configured: \
+            'lsps1-min-onchain-payment-confirmations' or \
+            'lsps1-onchain-confirmation-tiers' is missing"
+        );
+
+        let err = OnchainPaymentConfig::from_options(None, None, Some(1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "On-chain payments are partially configured: \
+            'lsps1-min-onchain-payment-size-sat' is missing"
+        );
+
+        let err = OnchainPaymentConfig::from_options(None, some("0:1"), Some(1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "On-chain payments are partially configured: \
+            set either 'lsps1-onchain-confirmation-tiers' or \
+            'lsps1-min-onchain-payment-confirmations' but not both, \
+            'lsps1-min-onchain-payment-size-sat' is missing"
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported() {
+        let err = OnchainPaymentConfig::from_options(some("lots"), None, Some(1)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(options::LSPS1_MIN_ONCHAIN_PAYMENT_SIZE_SAT));
+
+        let err =
+            OnchainPaymentConfig::from_options(some("20000"), some("1000:1"), None).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(options::LSPS1_ONCHAIN_CONFIRMATION_TIERS));
+        assert!(OnchainPaymentConfig::from_options(some("20000"), None, Some(256)).is_err());
+    }
+}
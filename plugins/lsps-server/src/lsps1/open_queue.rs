@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde_json::json;
+use tokio::sync::{mpsc, MutexGuard, Semaphore};
+use uuid::Uuid;
+
+/// The number of channels that are opened at the same time by default
+///
+/// The operator can change it using `lsps1-max-concurrent-opens`
+pub(crate) const DEFAULT_MAX_CONCURRENT_OPENS: i64 = 2;
+
+/// Queue of paid orders whose channel should be opened
+///
+/// The `invoice_payment`-hook only marks the order as `PendingOpen` and
+/// enqueues it. A background worker opens the channel. Because the queue
+/// lives in memory the `PendingOpen`-orders are enqueued again at start-up.
+///
+/// An order is processed at most once at a time. If it is enqueued while
+/// it is being processed it is processed again afterwards.
+///
+/// Orders are processed in the order they were enqueued.
+pub(crate) struct OpenQueue {
+    sender: mpsc::UnboundedSender<Uuid>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Uuid>>>,
+    // Orders that are queued or being processed.
+    // The flag is set if the order was enqueued again in the mean time
+    in_flight: Mutex<HashMap<Uuid, bool>>,
+    // The number of orders that are being processed
+    running: AtomicUsize,
+    // The concurrency of the worker. Zero if it isn't running
+    max_concurrent: AtomicUsize,
+    // Held while an order checks the funds of the wallet
+    funding_budget: tokio::sync::Mutex<()>,
+}
+
+impl Default for OpenQueue {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            in_flight: Mutex::new(HashMap::new()),
+            running: AtomicUsize::new(0),
+            max_concurrent: AtomicUsize::new(0),
+            funding_budget: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl OpenQueue {
+    /// Returns true if the order was added to the queue
+    pub(crate) fn enqueue(&self, order_id: Uuid) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(requeue) = in_flight.get_mut(&order_id) {
+            *requeue = true;
+            return false;
+        }
+
+        in_flight.insert(order_id, false);
+        self.sender.send(order_id).is_ok()
+    }
+
+    /// Processes the queue until all senders are dropped
+    ///
+    /// At most `concurrency` orders are processed at the same time.
+    /// An order whose processing panicked can be enqueued again.
+    /// The worker can only be started once.
+    pub(crate) async fn run<F, Fut>(self: Arc<Self>, concurrency: usize, process: F)
+    where
+        F: Fn(Uuid) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let receiver = self.receiver.lock().unwrap().take();
+        let Some(mut receiver) = receiver else {
+            log::warn!("The worker of the OpenQueue is already running");
+            return;
+        };
+
+        self.max_concurrent.store(concurrency, Ordering::Relaxed);
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        while let Some(order_id) = receiver.recv().await {
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+
+            let queue = self.clone();
+            let future = process(order_id);
+            tokio::spawn(async move {
+                queue.running.fetch_add(1, Ordering::Relaxed);
+                // A panic must not leave the order in flight forever
+                match tokio::spawn(future).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => log::warn!("Failed to process order {}: {:?}", order_id, err),
+                    Err(err) => log::error!("Processing order {} panicked: {}", order_id, err),
+                }
+                queue.running.fetch_sub(1, Ordering::Relaxed);
+                drop(permit);
+                queue.complete(order_id);
+            });
+        }
+    }
+
+    fn complete(&self, order_id: Uuid) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.remove(&order_id) == Some(true) {
+            in_flight.insert(order_id, false);
+            let _ = self.sender.send(order_id);
+        }
+    }
+
+    /// The number of orders that are queued or being processed
+    pub(crate) fn depth(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// The number of orders that are being processed
+    pub(crate) fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// The number of orders that wait for the worker
+    pub(crate) fn queued(&self) -> usize {
+        self.depth().saturating_sub(self.running())
+    }
+
+    /// Waits until no other order checks the funds of the wallet
+    ///
+    /// Orders that are processed at the same time check the wallet balance
+    /// and reserve their inputs while holding the guard. Otherwise they
+    /// could all pass a check against the same balance.
+    pub(crate) async fn lock_funding_budget(&self) -> MutexGuard<'_, ()> {
+        self.funding_budget.lock().await
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "running" : self.running(),
+            "queued" : self.queued(),
+            "max_concurrent" : self.max_concurrent.load(Ordering::Relaxed),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_in_flight(&self, order_id: &Uuid) -> bool {
+        self.in_flight.lock().unwrap().contains_key(order_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn worker_processes_queued_orders() {
+        let queue = Arc::new(OpenQueue::default());
+        let (done_sender, mut done_receiver) = mpsc::unbounded_channel();
+
+        let order_ids: Vec<Uuid> = (0..10).map(|_| Uuid::new_v4()).collect();
+        for order_id in &order_ids {
+            assert!(queue.enqueue(*order_id));
+        }
+
+        let running = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(queue.clone().run(2, move |order_id| {
+            let done_sender = done_sender.clone();
+            let running = running.clone();
+            async move {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                assert!(now_running <= 2, "Concurrency limit exceeded");
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done_sender.send(order_id).unwrap();
+                Ok(())
+            }
+        }));
+
+        let mut processed = Vec::new();
+        for _ in 0..order_ids.len() {
+            processed.push(done_receiver.recv().await.unwrap());
+        }
+        processed.sort();
+        let mut expected = order_ids.clone();
+        expected.sort();
+        assert_eq!(processed, expected);
+    }
+
+    #[tokio::test]
+    async fn count_running_and_queued_orders() {
+        let queue = Arc::new(OpenQueue::default());
+        let (started_sender, mut started_receiver) = mpsc::unbounded_channel();
+
+        for _ in 0..5 {
+            assert!(queue.enqueue(Uuid::new_v4()));
+        }
+        assert_eq!(queue.queued(), 5);
+
+        // The orders are never processed completely
+        tokio::spawn(queue.clone().run(2, move |order_id| {
+            let started_sender = started_sender.clone();
+            async move {
+                started_sender.send(order_id).unwrap();
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        }));
+
+        started_receiver.recv().await.unwrap();
+        started_receiver.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(started_receiver.try_recv().is_err());
+        assert_eq!(
+            queue.to_json(),
+            json!({ "running" : 2, "queued" : 3, "max_concurrent" : 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn order_enqueued_while_processing_is_processed_again() {
+        let queue = Arc::new(OpenQueue::default());
+        let order_id = Uuid::new_v4();
+        let (started_sender, mut started_receiver) = mpsc::unbounded_channel();
+        let (release_sender, release_receiver) = mpsc::unbounded_channel::<()>();
+        let release_receiver = Arc::new(tokio::sync::Mutex::new(release_receiver));
+
+        assert!(queue.enqueue(order_id));
+        tokio::spawn(queue.clone().run(1, move |order_id| {
+            let started_sender = started_sender.clone();
+            let release_receiver = release_receiver.clone();
+            async move {
+                started_sender.send(order_id).unwrap();
+                release_receiver.lock().await.recv().await;
+                Ok(())
+            }
+        }));
+
+        // The order isn't queued twice while it is being processed
+        started_receiver.recv().await.unwrap();
+        assert!(!queue.enqueue(order_id));
+        assert!(!queue.enqueue(order_id));
+        assert_eq!(queue.depth(), 1);
+
+        // But it is processed once more afterwards
+        release_sender.send(()).unwrap();
+        assert_eq!(started_receiver.recv().await, Some(order_id));
+        release_sender.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while queue.is_in_flight(&order_id) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(started_receiver.try_recv().is_err());
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn panic_doesnt_stop_the_worker() {
+        let queue = Arc::new(OpenQueue::default());
+        let (done_sender, mut done_receiver) = mpsc::unbounded_channel();
+        let panicking = Uuid::new_v4();
+        let order_id = Uuid::new_v4();
+
+        assert!(queue.enqueue(panicking));
+        tokio::spawn(queue.clone().run(1, move |order_id| {
+            let done_sender = done_sender.clone();
+            async move {
+                if order_id == panicking {
+                    panic!("Failed to open channel");
+                }
+                done_sender.send(order_id).unwrap();
+                Ok(())
+            }
+        }));
+
+        // The order that panicked is no longer in flight
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while queue.is_in_flight(&panicking) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The worker keeps processing orders
+        assert!(queue.enqueue(order_id));
+        assert_eq!(done_receiver.recv().await, Some(order_id));
+        assert!(queue.enqueue(panicking));
+    }
+}
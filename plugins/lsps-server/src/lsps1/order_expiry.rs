@@ -0,0 +1,499 @@
+//! Fails orders that expired before they were paid
+//!
+//! The invoice of such an order is deleted from lightningd. Otherwise
+//! it stays in the invoice database forever and keeps its label reserved.
+//!
+//! A payment that arrives shortly after the order expired is still
+//! accepted. The order is only failed once the grace period
+//! (`lsps1-payment-grace-seconds`) has elapsed as well.
+//!
+//! An invoice can be paid while the order expires. If lightningd reports
+//! the invoice as paid, the payment is processed instead and the order
+//! follows the path of a paid order.
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::{DelinvoiceRequest, DelinvoiceStatus};
+use serde_json::json;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+use lsp_primitives::lsps1::schema::PaymentState;
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::{InvoiceLabel, Lsps1FailureReason, Lsps1OrderState, OrderLogEvent};
+use crate::db::sqlite::queries::{
+    GetExpiredUnpaidOrdersQuery, GetOrderQuery, GetPaymentDetailsQuery, RecordInvoiceDeletedQuery,
+    UpdateOrderStateQuery, UpdatePaymentStateQuery,
+};
+use crate::state::PluginState;
+
+/// How often we look for orders that expired unpaid
+pub(crate) const ORDER_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long after expiry a payment is accepted unless the operator configured otherwise
+pub(crate) const DEFAULT_PAYMENT_GRACE: Duration = Duration::from_secs(120);
+
+/// Returns true if the grace period of an order that expires at `expires_at` has elapsed
+///
+/// A payment that arrives at the last second of the grace period is accepted.
+pub(crate) fn is_past_payment_grace(
+    expires_at: &IsoDatetime,
+    payment_grace: Duration,
+    now: &IsoDatetime,
+) -> bool {
+    let grace = i64::try_from(payment_grace.as_secs()).unwrap_or(i64::MAX);
+    expires_at.unix_timestamp().saturating_add(grace) < now.unix_timestamp()
+}
+
+/// The state of the invoice of an order after we tried to delete it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InvoiceCleanup {
+    /// The invoice was deleted
+    Deleted,
+    /// The invoice had already been deleted
+    NotFound,
+    /// The invoice was paid before we could delete it
+    Paid,
+}
+
+impl InvoiceCleanup {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Deleted => "deleted",
+            Self::NotFound => "not_found",
+            Self::Paid => "paid",
+        }
+    }
+}
+
+/// Fails all orders that expired before they were paid
+///
+/// Orders within their grace period are left alone. Returns the number
+/// of orders that were failed
+pub(crate) async fn expire_unpaid_orders(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+) -> Result<usize> {
+    let now = state.clock.now();
+    let grace = i64::try_from(state.payment_grace.as_secs())?;
+    let expired_before = IsoDatetime::from_unix_timestamp(now.unix_timestamp() - grace)?;
+
+    let mut tx = state.database.begin().await?;
+    let orders = GetExpiredUnpaidOrdersQuery { expired_before }
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let mut expired = 0;
+    for order in orders {
+        match expire_unpaid_order(state, rpc, order.uuid).await {
+            Ok(InvoiceCleanup::Deleted | InvoiceCleanup::NotFound) => expired += 1,
+            Ok(InvoiceCleanup::Paid) => {}
+            Err(err) => log::warn!("Failed to expire order {}: {:?}", order.uuid, err),
+        }
+    }
+    Ok(expired)
+}
+
+/// Deletes the invoice of an expired order and fails the order
+///
+/// If the invoice was paid in the meantime the payment is processed
+/// instead.
+pub(crate) async fn expire_unpaid_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+) -> Result<InvoiceCleanup> {
+    let label = InvoiceLabel::for_order(&order_uuid)?;
+    let cleanup = delete_unpaid_invoice(rpc, &label).await?;
+    if cleanup == InvoiceCleanup::Paid {
+        process_late_payment(state, order_uuid, &label).await?;
+        return Ok(cleanup);
+    }
+
+    let clock = state.clock.as_ref();
+    let mut tx = state.database.begin().await?;
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await?
+        .context("Failed to find expired order")?;
+    let payment = GetPaymentDetailsQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await?
+        .context("Failed to find payment of expired order")?;
+
+    RecordInvoiceDeletedQuery {
+        label,
+        generation: payment.generation,
+        deleted_at: clock.now(),
+    }
+    .execute(&mut tx)
+    .await?;
+
+    UpdateOrderStateQuery {
+        order_uuid,
+        state: Lsps1OrderState::Failed,
+        generation: order.generation,
+        changed_at: clock.now(),
+        failure_reason: Some(Lsps1FailureReason::OrderExpired),
+    }
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    log::info!("Order {} expired before it was paid", order_uuid);
+    state.order_watcher.notify(order_uuid);
+    state.order_log.info(
+        order_uuid,
+        OrderLogEvent::OrderExpired,
+        json!({
+            "expires_at" : order.expires_at,
+            "invoice" : cleanup.as_str(),
+        }),
+    );
+    Ok(cleanup)
+}
+
+/// Deletes the invoice if it hasn't been paid
+///
+/// The invoice of an order expires together with the order. Depending on
+/// timing lightningd considers it `unpaid` or `expired`.
+async fn delete_unpaid_invoice(
+    rpc: &mut dyn ClnRpcApi,
+    label: &InvoiceLabel,
+) -> Result<InvoiceCleanup> {
+    let mut status = DelinvoiceStatus::UNPAID;
+    loop {
+        let request = DelinvoiceRequest {
+            label: label.to_string(),
+            status,
+            desconly: None,
+        };
+        let err = match rpc.delinvoice(&request).await {
+            Ok(_) => return Ok(InvoiceCleanup::Deleted),
+            Err(err) => err,
+        };
+
+        let message = err.to_string();
+        if message.contains("Unknown invoice") {
+            return Ok(InvoiceCleanup::NotFound);
+        } else if message.contains("status is paid") {
+            return Ok(InvoiceCleanup::Paid);
+        } else if message.contains("status is expired") && status == DelinvoiceStatus::UNPAID {
+            status = DelinvoiceStatus::EXPIRED;
+        } else {
+            return Err(err).with_context(|| format!("Failed to delete invoice {}", label));
+        }
+    }
+}
+
+/// Processes the payment of an order whose invoice was paid during expiry
+///
+/// The `invoice_payment`-hook has usually accepted the payment already.
+/// If it hasn't, e.g. because the hook failed, the payment is accepted
+/// here. The open queue fails and refunds the order if it has expired.
+async fn process_late_payment(
+    state: &PluginState,
+    order_uuid: Uuid,
+    label: &InvoiceLabel,
+) -> Result<()> {
+    let clock = state.clock.as_ref();
+    let mut tx = state.database.begin().await?;
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await?
+        .context("Failed to find paid order")?;
+    let payment = GetPaymentDetailsQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await?
+        .context("Failed to find payment of paid order")?;
+
+    if payment.state == PaymentState::ExpectPayment && order.order_state == Lsps1OrderState::Created
+    {
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            generation: payment.generation,
+            label: label.clone(),
+            changed_at: clock.now(),
+        }
+        .execute(&mut tx)
+        .await?;
+
+        UpdateOrderStateQuery {
+            order_uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: order.generation,
+            changed_at: clock.now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        log::warn!(
+            "The invoice of order {} was paid but the payment wasn't recorded",
+            order_uuid
+        );
+        state.order_watcher.notify(order_uuid);
+        state.order_log.warn(
+            order_uuid,
+            OrderLogEvent::PaymentAccepted,
+            json!({
+                "order_total_sat" : payment.order_total_sat,
+                "during_expiry" : true,
+            }),
+        );
+        state.open_queue.enqueue(order_uuid);
+        return Ok(());
+    }
+
+    tx.commit().await?;
+    log::info!("Order {} was paid while it expired", order_uuid);
+    if order.order_state == Lsps1OrderState::PendingOpen {
+        state.open_queue.enqueue(order_uuid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::schema::{Lsps1Order, OrderGeneration, PaymentGeneration};
+    use crate::db::sqlite::queries::GetInvoiceDeletedAtQuery;
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::db::sqlite::Database;
+    use crate::state::test_support::test_state;
+
+    /// Inserts an order that expired `seconds_ago`
+    async fn insert_order_expired_since(db: &Database, seconds_ago: i64) -> Uuid {
+        let mut query = create_order_query();
+        query.order.expires_at =
+            IsoDatetime::from_unix_timestamp(IsoDatetime::now().unix_timestamp() - seconds_ago)
+                .unwrap();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        uuid
+    }
+
+    /// Inserts an order whose grace period has elapsed
+    async fn insert_expired_order(db: &Database) -> Uuid {
+        insert_order_expired_since(db, 3600).await
+    }
+
+    async fn get_order(db: &Database, uuid: Uuid) -> Lsps1Order {
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        order
+    }
+
+    async fn get_invoice_deleted_at(db: &Database, uuid: Uuid) -> Option<IsoDatetime> {
+        let mut tx = db.begin().await.unwrap();
+        let deleted_at = GetInvoiceDeletedAtQuery {
+            label: InvoiceLabel::for_order(&uuid).unwrap(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        deleted_at
+    }
+
+    fn delinvoice_response(uuid: Uuid) -> serde_json::Value {
+        json!({
+            "label" : InvoiceLabel::for_order(&uuid).unwrap().to_string(),
+            "payment_hash" : "00".repeat(32),
+            "status" : "unpaid",
+            "expires_at" : 1_700_000_000,
+            "created_index" : 1,
+        })
+    }
+
+    #[tokio::test]
+    async fn expired_order_deletes_its_invoice() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_expired_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("delinvoice", delinvoice_response(uuid));
+
+        let expired = expire_unpaid_orders(&state, &mut rpc).await.unwrap();
+        assert_eq!(expired, 1);
+
+        let params = rpc.params_of("delinvoice").unwrap();
+        assert_eq!(
+            params["label"],
+            InvoiceLabel::for_order(&uuid).unwrap().as_str()
+        );
+        assert_eq!(params["status"], "unpaid");
+
+        let order = get_order(&db, uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert_eq!(order.failure_reason, Some(Lsps1FailureReason::OrderExpired));
+        assert!(get_invoice_deleted_at(&db, uuid).await.is_some());
+
+        // The order isn't expired twice
+        assert_eq!(expire_unpaid_orders(&state, &mut rpc).await.unwrap(), 0);
+        assert_eq!(rpc.called_methods(), vec!["delinvoice"]);
+    }
+
+    #[tokio::test]
+    async fn order_is_kept_during_the_grace_period() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let grace = i64::try_from(state.payment_grace.as_secs()).unwrap();
+        let within_grace = insert_order_expired_since(&db, grace - 1).await;
+        let past_grace = insert_order_expired_since(&db, grace + 1).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("delinvoice", delinvoice_response(past_grace));
+
+        assert_eq!(expire_unpaid_orders(&state, &mut rpc).await.unwrap(), 1);
+        let params = rpc.params_of("delinvoice").unwrap();
+        assert_eq!(
+            params["label"],
+            InvoiceLabel::for_order(&past_grace).unwrap().as_str()
+        );
+        assert_eq!(rpc.called_methods(), vec!["delinvoice"]);
+
+        // The client still sees an order that expects payment
+        let order = get_order(&db, within_grace).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Created);
+        assert!(get_invoice_deleted_at(&db, within_grace).await.is_none());
+        assert_eq!(
+            get_order(&db, past_grace).await.order_state,
+            Lsps1OrderState::Failed
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_invoice_is_deleted() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_expired_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("delinvoice", "Invoice status is expired not unpaid")
+            .respond("delinvoice", delinvoice_response(uuid));
+
+        let cleanup = expire_unpaid_order(&state, &mut rpc, uuid).await.unwrap();
+        assert_eq!(cleanup, InvoiceCleanup::Deleted);
+        assert_eq!(rpc.called_methods(), vec!["delinvoice", "delinvoice"]);
+        assert_eq!(
+            get_order(&db, uuid).await.order_state,
+            Lsps1OrderState::Failed
+        );
+    }
+
+    #[tokio::test]
+    async fn already_deleted_invoice_still_fails_the_order() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_expired_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("delinvoice", "Unknown invoice");
+
+        let cleanup = expire_unpaid_order(&state, &mut rpc, uuid).await.unwrap();
+        assert_eq!(cleanup, InvoiceCleanup::NotFound);
+
+        let order = get_order(&db, uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::Failed);
+        assert!(get_invoice_deleted_at(&db, uuid).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn failed_delete_keeps_the_order() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_expired_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("delinvoice", "Connection refused");
+
+        assert_eq!(expire_unpaid_orders(&state, &mut rpc).await.unwrap(), 0);
+        assert_eq!(
+            get_order(&db, uuid).await.order_state,
+            Lsps1OrderState::Created
+        );
+        assert!(get_invoice_deleted_at(&db, uuid).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn invoice_paid_during_expiry_is_processed() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_expired_order(&db).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("delinvoice", "Invoice status is paid not unpaid");
+
+        let cleanup = expire_unpaid_order(&state, &mut rpc, uuid).await.unwrap();
+        assert_eq!(cleanup, InvoiceCleanup::Paid);
+
+        // The payment is recorded and the order is opened or refunded
+        let order = get_order(&db, uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::PendingOpen);
+        let mut tx = db.begin().await.unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(payment.state, PaymentState::Paid);
+        assert!(get_invoice_deleted_at(&db, uuid).await.is_none());
+        assert!(state.open_queue.is_in_flight(&uuid));
+    }
+
+    #[tokio::test]
+    async fn payment_accepted_by_the_hook_is_kept() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_expired_order(&db).await;
+
+        let order = get_order(&db, uuid).await;
+        let mut tx = db.begin().await.unwrap();
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            generation: PaymentGeneration(0),
+            label: InvoiceLabel::for_order(&uuid).unwrap(),
+            changed_at: IsoDatetime::now(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: order.generation,
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("delinvoice", "Invoice status is paid not unpaid");
+        let cleanup = expire_unpaid_order(&state, &mut rpc, uuid).await.unwrap();
+        assert_eq!(cleanup, InvoiceCleanup::Paid);
+
+        let order = get_order(&db, uuid).await;
+        assert_eq!(order.order_state, Lsps1OrderState::PendingOpen);
+        assert_eq!(order.generation, OrderGeneration(1));
+        assert!(state.open_queue.is_in_flight(&uuid));
+    }
+}
@@ -0,0 +1,310 @@
+//! Audit log of the events that happened to an order
+//!
+//! The state history only tells where an order ended up. The audit log
+//! explains how it got there: which fee parameters were used, why opening
+//! the channel failed and which actions the operator took.
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use uuid::Uuid;
+
+use lsp_primitives::lsps1::schema::OrderState;
+
+use crate::admin_timestamp::{self, AdminTimestamp};
+use crate::clock::Clock;
+use crate::db::schema::{Lsps1OrderLogEntry, OrderLogEvent, OrderLogSeverity};
+use crate::db::sqlite::queries::{
+    CreateOrderLogEntryQuery, GetClientNodeQuery, GetOrderLogQuery, GetOrderQuery,
+};
+use crate::db::sqlite::Database;
+use crate::plugin_rpc::Lsps1AdminOrderLogRequest;
+use crate::state::PluginState;
+
+/// The maximum number of entries that wait to be written
+pub(crate) const ORDER_LOG_CAPACITY: usize = 1024;
+
+/// Writes entries to the audit log of an order
+///
+/// Entries are written to the database by the [`OrderLogWriter`].
+/// Logging never blocks. If the writer falls behind, entries are dropped
+/// and a warning is logged.
+#[derive(Clone)]
+pub(crate) struct OrderLogger {
+    sender: mpsc::Sender<Lsps1OrderLogEntry>,
+    clock: Arc<dyn Clock>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Stores the entries sent by all [`OrderLogger`]s in the database
+pub(crate) struct OrderLogWriter {
+    receiver: mpsc::Receiver<Lsps1OrderLogEntry>,
+}
+
+impl OrderLogger {
+    pub(crate) fn new(clock: Arc<dyn Clock>, capacity: usize) -> (Self, OrderLogWriter) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let logger = Self {
+            sender,
+            clock,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (logger, OrderLogWriter { receiver })
+    }
+
+    /// The number of entries that were dropped because the log was full
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn info(&self, order_uuid: Uuid, event: OrderLogEvent, details: serde_json::Value) {
+        self.log(order_uuid, OrderLogSeverity::Info, event, details)
+    }
+
+    pub(crate) fn warn(&self, order_uuid: Uuid, event: OrderLogEvent, details: serde_json::Value) {
+        self.log(order_uuid, OrderLogSeverity::Warning, event, details)
+    }
+
+    pub(crate) fn error(&self, order_uuid: Uuid, event: OrderLogEvent, details: serde_json::Value) {
+        self.log(order_uuid, OrderLogSeverity::Error, event, details)
+    }
+
+    fn log(
+        &self,
+        order_uuid: Uuid,
+        severity: OrderLogSeverity,
+        event: OrderLogEvent,
+        details: serde_json::Value,
+    ) {
+        let entry = Lsps1OrderLogEntry {
+            order_uuid,
+            created_at: self.clock.now(),
+            severity,
+            event,
+            details,
+        };
+
+        match self.sender.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(entry)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!(
+                    "The order log is full. Dropped {:?} ({} entries dropped in total)",
+                    entry,
+                    dropped
+                )
+            }
+            Err(TrySendError::Closed(entry)) => {
+                log::debug!("The order log is closed. Dropped {:?}", entry)
+            }
+        }
+    }
+}
+
+impl OrderLogWriter {
+    /// Writes entries until all [`OrderLogger`]s are dropped
+    pub(crate) async fn run(mut self, db: Database) {
+        while let Some(entry) = self.receiver.recv().await {
+            if let Err(err) = write_entry(&db, &entry).await {
+                log::warn!("Failed to store {:?} in the order log: {:?}", entry, err);
+            }
+        }
+    }
+}
+
+async fn write_entry(db: &Database, entry: &Lsps1OrderLogEntry) -> Result<()> {
+    let mut tx = db.begin().await?;
+    CreateOrderLogEntryQuery::new(entry)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Handles `lsps1-admin-order-log`
+pub(crate) async fn lsps1_admin_order_log(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminOrderLogRequest = serde_json::from_value(request)?;
+    let order_uuid = Uuid::from_str(&request.order_id).context("Invalid order_id")?;
+
+    let mut tx = plugin.state().database.begin().await?;
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order'-query on database")?
+        .with_context(|| format!("Unknown order {}", order_uuid))?;
+    let entries = GetOrderLogQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order_log'-query on database")?;
+    let client_node = GetClientNodeQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_client_node'-query on database")?
+        .unwrap_or_default();
+    tx.commit().await?;
+
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|entry| {
+            json!({
+                "created_at" : AdminTimestamp(entry.created_at),
+                "severity" : entry.severity.as_str(),
+                "event_code" : entry.event.as_str(),
+                "details" : entry.details,
+            })
+        })
+        .collect();
+
+    let log = json!({
+        "order_id" : order_uuid,
+        "order_state" : OrderState::from(order.order_state),
+        "failure_reason" : order.failure_reason.map(|r| r.as_str()),
+        "client_node" : {
+            "alias" : client_node.alias,
+            "addresses" : client_node.addresses,
+            "remote_address" : client_node.remote_address,
+        },
+        "entries" : entries,
+    });
+    Ok(admin_timestamp::render(log, request.epoch_only))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+    use lsp_primitives::lsps1::schema::PaymentState;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+    use crate::db::schema::{Lsps1OrderState, OrderGeneration};
+    use crate::db::sqlite::queries::{UpdateOrderStateQuery, UpdatePaymentStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::lsps1::admin::retry_open;
+    use crate::lsps1::channel_open::open_pending_order;
+    use crate::state::test_support::test_state_with_order_log;
+
+    fn feerates() -> serde_json::Value {
+        json!({
+            "perkw" : {
+                "min_acceptable" : 253,
+                "max_acceptable" : 100_000,
+                "estimates" : [{ "blockcount" : 6, "feerate" : 1_000, "smoothed_feerate" : 1_000 }]
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn failed_then_retried_open_is_logged_in_order() {
+        // Other tests open a channel with the same funding outpoint
+        let (db, _) = get_temp_db().await;
+        let (state, writer) = test_state_with_order_log(db.clone());
+
+        // A paid order that waits for its channel
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+        let payment = query.payment.clone();
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            changed_at: IsoDatetime::now(),
+            generation: payment.generation,
+            label: payment.bolt11_invoice_label,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: Lsps1OrderState::PendingOpen,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // The first attempt fails while the client is online
+        let online =
+            json!({ "peers" : [{ "id" : order.client_node_id.to_hex(), "connected" : true }] });
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeers", online.clone())
+            .respond("feerates", feerates())
+            .fail("fundchannel_start", "Insufficient funds")
+            .respond("listpeers", online.clone());
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        // The operator retries and the worker opens the channel
+        assert!(retry_open(&state, uuid).await.unwrap());
+        let mut tx = db.begin().await.unwrap();
+        let order = GetOrderQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("listpeers", online)
+            .respond("feerates", feerates());
+        rpc.script_fundchannel(100_000);
+        rpc.respond("listpeerchannels", json!({ "channels" : [] }));
+        open_pending_order(&state, &mut rpc, &order).await.unwrap();
+
+        // The writer stops once all loggers are dropped
+        drop(state);
+        writer.run(db.clone()).await;
+
+        let mut tx = db.begin().await.unwrap();
+        let log = GetOrderLogQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let events: Vec<_> = log.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(
+            events,
+            vec![
+                "channel_open_started",
+                "channel_open_failed",
+                "refund_required",
+                "admin_retry_open",
+                "channel_open_started",
+                "channel_opened",
+            ]
+        );
+        assert_eq!(log[1].severity, OrderLogSeverity::Error);
+        assert_eq!(log[1].details["peer_connected"], true);
+    }
+
+    #[tokio::test]
+    async fn logging_doesnt_block_when_full() {
+        let clock = Arc::new(MockClock::new(IsoDatetime::now()));
+        let (logger, _writer) = OrderLogger::new(clock, 2);
+
+        // The writer isn't running. Entries beyond the capacity are dropped
+        let uuid = Uuid::new_v4();
+        for _ in 0..10 {
+            logger.info(uuid, OrderLogEvent::OrderCreated, json!({}));
+        }
+        assert_eq!(logger.dropped(), 8);
+    }
+}
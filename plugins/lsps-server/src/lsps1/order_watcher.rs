@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+/// The number of requests a single peer can have waiting at the same time
+pub(crate) const MAX_WAITS_PER_PEER: usize = 8;
+
+/// Allows requests to wait until an order changes
+///
+/// Every state transition of an order must call [`OrderWatcher::notify`]
+/// after the change has been committed to the database.
+///
+/// A channel is created when the first request subscribes to an order.
+/// It is removed once the order changes or when the last subscriber is gone.
+///
+/// Every waiting request holds a [`WaitPermit`]. A peer can hold at most
+/// [`MAX_WAITS_PER_PEER`] permits.
+#[derive(Default)]
+pub(crate) struct OrderWatcher {
+    senders: Mutex<HashMap<Uuid, broadcast::Sender<()>>>,
+    waits: Mutex<HashMap<PublicKey, usize>>,
+}
+
+/// Allows a peer to wait for an order
+///
+/// The permit is returned once it is dropped
+pub(crate) struct WaitPermit<'a> {
+    watcher: &'a OrderWatcher,
+    peer_id: PublicKey,
+}
+
+/// Receives a message when the order changes
+pub(crate) struct OrderSubscription<'a> {
+    watcher: &'a OrderWatcher,
+    order_id: Uuid,
+    receiver: Option<broadcast::Receiver<()>>,
+}
+
+impl OrderWatcher {
+    pub(crate) fn subscribe(&self, order_id: Uuid) -> OrderSubscription<'_> {
+        let mut senders = self.senders.lock().unwrap();
+        let receiver = senders
+            .entry(order_id)
+            .or_insert_with(|| broadcast::channel(1).0)
+            .subscribe();
+
+        OrderSubscription {
+            watcher: self,
+            order_id,
+            receiver: Some(receiver),
+        }
+    }
+
+    /// Returns `None` if `peer_id` already has [`MAX_WAITS_PER_PEER`] requests waiting
+    pub(crate) fn reserve_wait(&self, peer_id: PublicKey) -> Option<WaitPermit<'_>> {
+        let mut waits = self.waits.lock().unwrap();
+        let count = waits.entry(peer_id).or_insert(0);
+        if *count >= MAX_WAITS_PER_PEER {
+            return None;
+        }
+        *count += 1;
+
+        Some(WaitPermit {
+            watcher: self,
+            peer_id,
+        })
+    }
+
+    /// Wakes up all requests that are waiting for `order_id`
+    pub(crate) fn notify(&self, order_id: Uuid) {
+        let sender = self.senders.lock().unwrap().remove(&order_id);
+        if let Some(sender) = sender {
+            // Fails if all receivers are gone. That's fine
+            let _ = sender.send(());
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.senders.lock().unwrap().len()
+    }
+}
+
+impl OrderSubscription<'_> {
+    /// Returns true if the order changed before the `timeout` elapsed
+    pub(crate) async fn wait(mut self, timeout: Duration) -> bool {
+        let mut receiver = match self.receiver.take() {
+            Some(receiver) => receiver,
+            None => return false,
+        };
+
+        // A closed channel means the sender was removed by `notify`
+        matches!(
+            tokio::time::timeout(timeout, receiver.recv()).await,
+            Ok(Ok(())) | Ok(Err(broadcast::error::RecvError::Closed))
+        )
+    }
+}
+
+impl Drop for OrderSubscription<'_> {
+    fn drop(&mut self) {
+        // Drop the receiver before checking if any receivers are left
+        self.receiver.take();
+
+        let mut senders = self.watcher.senders.lock().unwrap();
+        let is_unused = senders
+            .get(&self.order_id)
+            .map(|sender| sender.receiver_count() == 0)
+            .unwrap_or(false);
+
+        if is_unused {
+            senders.remove(&self.order_id);
+        }
+    }
+}
+
+impl Drop for WaitPermit<'_> {
+    fn drop(&mut self) {
+        let mut waits = self.watcher.waits.lock().unwrap();
+        if let Some(count) = waits.get_mut(&self.peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                waits.remove(&self.peer_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn wait_returns_true_on_change() {
+        let watcher = Arc::new(OrderWatcher::default());
+        let order_id = Uuid::new_v4();
+
+        let subscription = watcher.subscribe(order_id);
+
+        let notifier = watcher.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            notifier.notify(order_id);
+        });
+
+        assert!(subscription.wait(Duration::from_secs(10)).await);
+        assert_eq!(watcher.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn wait_returns_false_on_timeout() {
+        let watcher = OrderWatcher::default();
+        let order_id = Uuid::new_v4();
+
+        // A change to another order doesn't wake us up
+        let subscription = watcher.subscribe(order_id);
+        watcher.notify(Uuid::new_v4());
+
+        assert!(!subscription.wait(Duration::from_millis(10)).await);
+        assert_eq!(watcher.len(), 0, "The channel is removed after the timeout");
+    }
+
+    #[tokio::test]
+    async fn notify_wakes_all_subscribers() {
+        let watcher = OrderWatcher::default();
+        let order_id = Uuid::new_v4();
+
+        let subscription_1 = watcher.subscribe(order_id);
+        let subscription_2 = watcher.subscribe(order_id);
+        assert_eq!(watcher.len(), 1);
+
+        watcher.notify(order_id);
+
+        assert!(subscription_1.wait(Duration::from_millis(10)).await);
+        assert!(subscription_2.wait(Duration::from_millis(10)).await);
+        assert_eq!(watcher.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn channel_is_kept_while_other_subscribers_wait() {
+        let watcher = OrderWatcher::default();
+        let order_id = Uuid::new_v4();
+
+        let subscription_1 = watcher.subscribe(order_id);
+        let subscription_2 = watcher.subscribe(order_id);
+
+        drop(subscription_1);
+        assert_eq!(watcher.len(), 1);
+
+        drop(subscription_2);
+        assert_eq!(watcher.len(), 0);
+    }
+
+    #[test]
+    fn waits_are_limited_per_peer() {
+        let watcher = OrderWatcher::default();
+        let peer_1 = PublicKey::from_hex(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let peer_2 = PublicKey::from_hex(
+            "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170",
+        )
+        .unwrap();
+
+        let mut permits: Vec<_> = (0..MAX_WAITS_PER_PEER)
+            .map(|_| watcher.reserve_wait(peer_1).unwrap())
+            .collect();
+        assert!(watcher.reserve_wait(peer_1).is_none());
+
+        // Other peers are not affected
+        assert!(watcher.reserve_wait(peer_2).is_some());
+
+        // A permit becomes available once a request stops waiting
+        permits.pop();
+        assert!(watcher.reserve_wait(peer_1).is_some());
+
+        permits.clear();
+        assert!(watcher.waits.lock().unwrap().is_empty());
+    }
+}
@@ -1,3 +1,6 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
 
 use cln_rpc::model::requests::InvoiceRequest;
@@ -6,28 +9,38 @@ use cln_rpc::primitives::AmountOrAny;
 use lsp_primitives::lsps0::common_schemas::SatAmount;
 use lsp_primitives::lsps1::schema::PaymentState;
 
-use crate::custom_msg::context::CustomMsgContext;
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::Clock;
+use crate::feerate_cache::FeerateCache;
+use crate::lsps1::confirmation_policy::ConfirmationPolicy;
 use crate::lsps1::fee_calc::FeeCalculator;
-use crate::PluginState;
 
-use crate::db::schema::{Lsps1Order, Lsps1PaymentDetails};
+use crate::db::schema::{InvoiceLabel, Lsps1Order, Lsps1PaymentDetails, PaymentGeneration};
 
 pub struct PaymentCalc<T: FeeCalculator> {
     pub(crate) fee_calc: T,
+    pub(crate) feerates: Arc<FeerateCache>,
+    pub(crate) clock: Arc<dyn Clock>,
+    /// `None` if the operator didn't configure on-chain confirmations
+    pub(crate) confirmation_policy: Option<ConfirmationPolicy>,
+    /// How long after the order expires a payment is still accepted
+    pub(crate) payment_grace: Duration,
 }
 
 impl<T: FeeCalculator> PaymentCalc<T> {
     pub async fn compute_payment_details(
         &mut self,
-        context: &mut CustomMsgContext<PluginState>,
+        rpc: &mut dyn ClnRpcApi,
         order: &Lsps1Order,
     ) -> Result<Lsps1PaymentDetails> {
         log::debug!("Computing payment details for order {}", order.uuid);
         // Compute the fee-rate and the bolt11-invoice
-        let fee = self.fee_calc.calculate_fee(context, order.clone()).await?;
-        let bolt_11_invoice_label = format!("lsps1_{}", order.uuid);
+        // The cached estimates are only fetched if they are stale
+        let feerates = self.feerates.get(rpc, self.clock.as_ref()).await?;
+        let fee = self.fee_calc.calculate_fee(&feerates, order)?;
+        let bolt_11_invoice_label = InvoiceLabel::for_order(&order.uuid)?;
         let bolt11_invoice = self
-            .construct_bolt11_invoice(context, order, fee.order_total_sat, &bolt_11_invoice_label)
+            .construct_bolt11_invoice(rpc, order, fee.order_total_sat, &bolt_11_invoice_label)
             .await?;
 
         // We do not support onchain payments.
@@ -38,23 +51,24 @@ impl<T: FeeCalculator> PaymentCalc<T> {
             bolt11_invoice: bolt11_invoice,
             bolt11_invoice_label: bolt_11_invoice_label,
             state: PaymentState::ExpectPayment,
-            generation: 0,
+            generation: PaymentGeneration(0),
             minimum_fee_for_0conf: None,
             onchain_address: None,
-            onchain_block_confirmations_required: None,
+            onchain_block_confirmations_required: self
+                .confirmation_policy
+                .as_ref()
+                .map(|policy| u16::from(policy.required_confs(fee.order_total_sat))),
             order_uuid: order.uuid,
         })
     }
 
     async fn construct_bolt11_invoice(
         &mut self,
-        context: &mut CustomMsgContext<PluginState>,
+        rpc: &mut dyn ClnRpcApi,
         order: &Lsps1Order,
         amount: SatAmount,
-        label: &str,
+        label: &InvoiceLabel,
     ) -> Result<String> {
-        // cln_rpc
-        let cln_rpc = &mut context.cln_rpc;
         log::debug!("Constructing a BOLT-11 invoice for order {}", order.uuid);
         // Construct the description
         let channel_capacity = SatAmount::new(
@@ -72,14 +86,111 @@ impl<T: FeeCalculator> PaymentCalc<T> {
             amount_msat: AmountOrAny::Amount(cln_amount),
             label: label.to_string(),
             description,
-            expiry: Some(order.expires_at.unix_timestamp() as u64),
+            expiry: Some(self.invoice_expiry(order)),
             cltv: None,
             deschashonly: None,
             fallbacks: Some(vec![]), //Don't use an onchan fallback address
             preimage: None,
         };
 
-        let invoice_response = cln_rpc.call_typed(&invoice_request).await?;
+        let invoice_response = rpc.invoice(&invoice_request).await?;
         return Ok(invoice_response.bolt11);
     }
+
+    /// The number of seconds until the invoice expires
+    ///
+    /// lightningd expects a duration rather than a timestamp. The invoice
+    /// stays payable until the payment grace of the order has elapsed.
+    fn invoice_expiry(&self, order: &Lsps1Order) -> u64 {
+        let now = self.clock.now().unix_timestamp();
+        let grace = i64::try_from(self.payment_grace.as_secs()).unwrap_or(i64::MAX);
+        let expiry = order
+            .expires_at
+            .unix_timestamp()
+            .saturating_sub(now)
+            .saturating_add(grace);
+        u64::try_from(expiry).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+    use serde_json::json;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::clock::test_support::MockClock;
+    use crate::db::sqlite::test::create_test_order;
+    use crate::lsps1::fee_calc::StandardFeeCalculator;
+
+    fn payment_calc(now: i64) -> PaymentCalc<StandardFeeCalculator> {
+        PaymentCalc {
+            fee_calc: StandardFeeCalculator::from_options(1_000, 500, 100).unwrap(),
+            feerates: Arc::new(FeerateCache::default()),
+            clock: Arc::new(MockClock::new(
+                IsoDatetime::from_unix_timestamp(now).unwrap(),
+            )),
+            confirmation_policy: None,
+            payment_grace: Duration::from_secs(120),
+        }
+    }
+
+    fn scripted_rpc() -> FakeClnRpc {
+        let rpc = FakeClnRpc::default();
+        rpc.respond(
+            "feerates",
+            json!({
+                "perkw" : {
+                    "min_acceptable" : 253,
+                    "max_acceptable" : 100_000,
+                    "estimates" : [
+                        { "blockcount" : 6, "feerate" : 1_000, "smoothed_feerate" : 1_000 },
+                    ]
+                }
+            }),
+        )
+        .respond(
+            "invoice",
+            json!({
+                "bolt11" : "lnbcrt15100n1pjscripted",
+                "payment_hash" : "00".repeat(32),
+                "payment_secret" : "00".repeat(32),
+                "expires_at" : 1_700_003_720,
+            }),
+        );
+        rpc
+    }
+
+    #[tokio::test]
+    async fn invoice_expires_after_the_payment_grace() {
+        let mut order = create_test_order();
+        order.expires_at = IsoDatetime::from_unix_timestamp(1_700_003_600).unwrap();
+        let mut rpc = scripted_rpc();
+
+        payment_calc(1_700_000_000)
+            .compute_payment_details(&mut rpc, &order)
+            .await
+            .unwrap();
+
+        // The expiry is relative to now
+        let invoice = rpc.params_of("invoice").unwrap();
+        assert_eq!(invoice["expiry"], 3_600 + 120);
+    }
+
+    #[tokio::test]
+    async fn invoice_of_an_expired_order_lasts_the_rest_of_the_payment_grace() {
+        let mut order = create_test_order();
+        order.expires_at = IsoDatetime::from_unix_timestamp(1_700_000_000).unwrap();
+        let mut rpc = scripted_rpc();
+
+        payment_calc(1_700_000_060)
+            .compute_payment_details(&mut rpc, &order)
+            .await
+            .unwrap();
+
+        let invoice = rpc.params_of("invoice").unwrap();
+        assert_eq!(invoice["expiry"], 120 - 60);
+    }
 }
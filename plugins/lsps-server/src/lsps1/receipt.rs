@@ -0,0 +1,240 @@
+//! Signed receipts of completed orders
+//!
+//! A client can use the receipt to prove it purchased a channel. E.g: to
+//! get support or to claim a promotion. See [`lsp_primitives::lsps1::receipt`]
+//! for the format of the receipt.
+use anyhow::{anyhow, Context, Result};
+use cln_rpc::model::requests::SignmessageRequest;
+use serde_json::json;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::Outpoint;
+use lsp_primitives::lsps1::receipt::{OrderReceipt, ReceiptPayload};
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::{Lsps1OrderState, OrderLogEvent};
+use crate::db::sqlite::queries::{
+    GetChannelQuery, GetOrderQuery, GetPaymentDetailsQuery, GetReceiptQuery, SetReceiptQuery,
+};
+use crate::state::PluginState;
+
+/// Signs and stores the receipt of a completed order
+///
+/// Returns the stored receipt if the order already has one.
+/// Fails if the order isn't completed.
+pub(crate) async fn issue_receipt(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+) -> Result<OrderReceipt> {
+    let db = &state.database;
+    let mut tx = db.begin().await?;
+    let existing = GetReceiptQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_receipt'-query on database")?;
+    if let Some(receipt) = existing {
+        tx.commit().await?;
+        return Ok(receipt);
+    }
+
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order'-query on database")?
+        .with_context(|| format!("Unknown order {}", order_uuid))?;
+    let payment = GetPaymentDetailsQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_payment_details'-query on database")?
+        .with_context(|| format!("Order {} has no payment details", order_uuid))?;
+    let channel = GetChannelQuery::by_order_id(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_channel'-query on database")?
+        .with_context(|| format!("Order {} has no channel", order_uuid))?;
+    tx.commit().await?;
+
+    if order.order_state != Lsps1OrderState::Completed {
+        return Err(anyhow!(
+            "Can't issue a receipt for order {} in state {:?}",
+            order_uuid,
+            order.order_state
+        ));
+    }
+
+    let capacity = order
+        .lsp_balance_sat
+        .checked_add(&order.client_balance_sat)
+        .context("Channel capacity overflows")?;
+    let payload = ReceiptPayload {
+        capacity,
+        client_node_id: order.client_node_id,
+        completed_at: state.clock.now(),
+        fee_total_sat: payment.fee_total_sat,
+        funding_outpoint: Outpoint {
+            txid: channel.funding_txid,
+            outnum: channel.outnum,
+        },
+        order_id: order_uuid,
+    }
+    .to_canonical_json()?;
+
+    let signed = rpc
+        .signmessage(&SignmessageRequest {
+            message: payload.clone(),
+        })
+        .await
+        .context("Failed to call 'signmessage'")?;
+    let receipt = OrderReceipt {
+        payload,
+        zbase_signature: signed.zbase,
+    };
+
+    // The receipt might have been issued while we were signing
+    let mut tx = db.begin().await?;
+    let is_new = SetReceiptQuery {
+        order_uuid,
+        receipt: receipt.clone(),
+    }
+    .execute(&mut tx)
+    .await
+    .context("Failed to execute 'set_receipt'-query on database")?;
+    let receipt = if is_new {
+        receipt
+    } else {
+        GetReceiptQuery::by_uuid(order_uuid)
+            .execute(&mut tx)
+            .await
+            .context("Failed to execute 'get_receipt'-query on database")?
+            .with_context(|| format!("Failed to store the receipt of order {}", order_uuid))?
+    };
+    tx.commit().await?;
+
+    if is_new {
+        log::info!("Issued receipt for order {}", order_uuid);
+        state.order_log.info(
+            order_uuid,
+            OrderLogEvent::ReceiptIssued,
+            json!({ "payload" : receipt.payload }),
+        );
+    }
+    Ok(receipt)
+}
+
+/// Like [`issue_receipt`] but failures are only logged
+///
+/// The order stays completed if the receipt can't be issued.
+pub(crate) async fn try_issue_receipt(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+) {
+    if let Err(err) = issue_receipt(state, rpc, order_uuid).await {
+        log::warn!(
+            "Failed to issue a receipt for order {}: {:?}",
+            order_uuid,
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, SatAmount, TransactionId};
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::schema::{Lsps1Channel, OrderGeneration};
+    use crate::db::sqlite::queries::{CreateChannelQuery, UpdateOrderStateQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::db::sqlite::Database;
+    use crate::state::test_support::test_state;
+
+    const ZBASE: &str = "d6tqaeuonjhi98mmont9m4wag7gg4krg1f4txonug3h31e9h6p6k6";
+    const FUNDING_TXID: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    async fn create_order(db: &Database, order_state: Lsps1OrderState) -> Uuid {
+        let query = create_order_query();
+        let uuid = query.order.uuid;
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        CreateChannelQuery::new(
+            uuid,
+            Lsps1Channel {
+                funding_txid: TransactionId::from_str(FUNDING_TXID).unwrap(),
+                outnum: 1,
+                funded_at: IsoDatetime::now(),
+            },
+        )
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: uuid,
+            state: order_state,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        uuid
+    }
+
+    #[tokio::test]
+    async fn receipt_is_signed_once() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = create_order(&db, Lsps1OrderState::Completed).await;
+        let order = create_order_query().order;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "signmessage",
+            json!({ "signature" : "00".repeat(64), "recid" : "00", "zbase" : ZBASE }),
+        );
+        let receipt = issue_receipt(&state, &mut rpc, uuid).await.unwrap();
+        assert_eq!(receipt.zbase_signature, ZBASE);
+
+        // The signed message is the payload
+        let params = rpc.params_of("signmessage").unwrap();
+        assert_eq!(params["message"], receipt.payload);
+        let payload = ReceiptPayload::from_canonical_json(&receipt.payload).unwrap();
+        assert_eq!(payload.order_id, uuid);
+        assert_eq!(payload.client_node_id, order.client_node_id);
+        assert_eq!(
+            payload.capacity,
+            SatAmount::new(
+                order.lsp_balance_sat.sat_value() + order.client_balance_sat.sat_value()
+            )
+        );
+        assert_eq!(
+            payload.funding_outpoint.to_string(),
+            format!("{}:1", FUNDING_TXID)
+        );
+
+        // The stored receipt is returned without signing again
+        let again = issue_receipt(&state, &mut rpc, uuid).await.unwrap();
+        assert_eq!(again, receipt);
+        assert_eq!(rpc.called_methods(), vec!["signmessage"]);
+    }
+
+    #[tokio::test]
+    async fn receipt_requires_completed_order() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let uuid = create_order(&db, Lsps1OrderState::ChannelOpening).await;
+
+        let mut rpc = FakeClnRpc::default();
+        let err = issue_receipt(&state, &mut rpc, uuid).await.unwrap_err();
+        assert!(err.to_string().contains("Can't issue a receipt"));
+        assert!(rpc.called_methods().is_empty());
+    }
+}
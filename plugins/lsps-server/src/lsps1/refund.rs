@@ -0,0 +1,280 @@
+//! Refunds for orders that were paid but didn't result in a channel
+//!
+//! The LSP sends the `order_total_sat` to the `refund_onchain_address` of
+//! the order. If the client didn't provide an address the payment must be
+//! refunded by the operator. The order log tells which orders are owed a refund.
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use cln_rpc::model::requests::WithdrawRequest;
+use cln_rpc::primitives::{Amount, AmountOrAll};
+use serde_json::json;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::TransactionId;
+use lsp_primitives::lsps1::schema::PaymentState;
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::{Lsps1Refund, OrderLogEvent};
+use crate::db::sqlite::queries::{
+    CreateRefundQuery, GetOrderQuery, GetPaymentDetailsQuery, GetRefundQuery,
+    UpdatePaymentStateQuery,
+};
+use crate::state::PluginState;
+
+/// Refunds the payment of a failed order
+///
+/// Returns the refund that was broadcast. An order is refunded at most once.
+/// If a refund exists it is returned without broadcasting a new one.
+///
+/// Returns `None` if the order has no `refund_onchain_address`. The
+/// payment remains `Paid` and the operator must refund it.
+pub(crate) async fn refund_order(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    order_uuid: Uuid,
+) -> Result<Option<Lsps1Refund>> {
+    let db = &state.database;
+    let mut tx = db.begin().await?;
+    let order = GetOrderQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order'-query on database")?
+        .with_context(|| format!("Unknown order {}", order_uuid))?;
+    let payment_details = GetPaymentDetailsQuery::by_uuid(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_payment_details'-query on database")?
+        .context("Failed to find payment that corresponds to order")?;
+    let refund = GetRefundQuery::by_order_id(order_uuid)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_refund'-query on database")?;
+    tx.commit().await?;
+
+    if refund.is_some() {
+        log::debug!("Order {} was already refunded", order_uuid);
+        return Ok(refund);
+    }
+
+    if payment_details.state != PaymentState::Paid {
+        return Err(anyhow!(
+            "Can't refund order {}. Payment state is {:?}",
+            order_uuid,
+            payment_details.state
+        ));
+    }
+
+    let amount_sat = payment_details.order_total_sat;
+    let Some(address) = order.refund_onchain_address.clone() else {
+        log::warn!(
+            "Order {} has no refund_onchain_address. The operator must refund {} sat",
+            order_uuid,
+            amount_sat.sat_value()
+        );
+        state.order_log.error(
+            order_uuid,
+            OrderLogEvent::RefundRequired,
+            json!({ "order_total_sat" : amount_sat }),
+        );
+        return Ok(None);
+    };
+
+    let request = WithdrawRequest {
+        destination: address.clone(),
+        satoshi: Some(AmountOrAll::Amount(Amount::from_sat(
+            amount_sat.sat_value(),
+        ))),
+        feerate: None,
+        minconf: None,
+        utxos: None,
+    };
+    let response = match rpc.withdraw(&request).await {
+        Ok(response) => response,
+        Err(err) => {
+            state.order_log.error(
+                order_uuid,
+                OrderLogEvent::RefundRequired,
+                json!({
+                    "order_total_sat" : amount_sat,
+                    "error" : err.to_string(),
+                }),
+            );
+            return Err(err.context(format!("Failed to refund order {}", order_uuid)));
+        }
+    };
+
+    let refund = Lsps1Refund {
+        txid: TransactionId::from_str(&response.txid).context("Invalid txid in 'withdraw'")?,
+        amount_sat,
+        address,
+        broadcast_at: state.clock.now(),
+    };
+
+    let mut tx = db.begin().await?;
+    CreateRefundQuery::new(order_uuid, refund.clone())
+        .execute(&mut tx)
+        .await?;
+    UpdatePaymentStateQuery {
+        state: PaymentState::Refunded,
+        changed_at: state.clock.now(),
+        generation: payment_details.generation,
+        label: payment_details.bolt11_invoice_label,
+    }
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    state.order_watcher.notify(order_uuid);
+
+    log::info!("Refunded order {} in {}", order_uuid, refund.txid);
+    state.order_log.info(
+        order_uuid,
+        OrderLogEvent::RefundBroadcast,
+        json!({
+            "txid" : refund.txid,
+            "amount_sat" : refund.amount_sat,
+            "address" : refund.address,
+        }),
+    );
+    Ok(Some(refund))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::sqlite::test::{create_order_query, get_db};
+    use crate::db::sqlite::Database;
+    use crate::state::test_support::test_state;
+
+    const REFUND_ADDRESS: &str = "bcrt1qxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzxyzx";
+    const REFUND_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    /// Inserts a paid order
+    async fn insert_paid_order(db: &Database, refund_address: Option<&str>) -> Uuid {
+        let mut query = create_order_query();
+        query.order.refund_onchain_address = refund_address.map(|a| a.to_string());
+        let uuid = query.order.uuid;
+        let payment = query.payment.clone();
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        UpdatePaymentStateQuery {
+            state: PaymentState::Paid,
+            changed_at: IsoDatetime::now(),
+            generation: payment.generation,
+            label: payment.bolt11_invoice_label,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        uuid
+    }
+
+    async fn get_payment_state(db: &Database, uuid: Uuid) -> PaymentState {
+        let mut tx = db.begin().await.unwrap();
+        let payment = GetPaymentDetailsQuery::by_uuid(uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap();
+        tx.commit().await.unwrap();
+        payment.state
+    }
+
+    #[tokio::test]
+    async fn refund_to_onchain_address() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_paid_order(&db, Some(REFUND_ADDRESS)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "withdraw",
+            json!({
+                "tx" : "0200000000000000000000",
+                "txid" : REFUND_TXID,
+                "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+            }),
+        );
+
+        let refund = refund_order(&state, &mut rpc, uuid).await.unwrap().unwrap();
+        assert_eq!(refund.txid.to_string(), REFUND_TXID);
+        assert_eq!(refund.address, REFUND_ADDRESS);
+
+        // The full order_total is sent to the refund address
+        let withdraw = rpc.params_of("withdraw").unwrap();
+        assert_eq!(withdraw["destination"], REFUND_ADDRESS);
+        assert_eq!(
+            withdraw["satoshi"],
+            format!("{}msat", refund.amount_sat.sat_value() * 1000)
+        );
+        assert_eq!(get_payment_state(&db, uuid).await, PaymentState::Refunded);
+
+        // A second call returns the stored refund and doesn't withdraw again
+        let second = refund_order(&state, &mut rpc, uuid).await.unwrap().unwrap();
+        assert_eq!(second.txid, refund.txid);
+        assert_eq!(rpc.called_methods(), vec!["withdraw"]);
+    }
+
+    #[tokio::test]
+    async fn refund_to_taproot_address() {
+        // lightningd picks the fee of the withdrawal. The output type
+        // doesn't change the amount that is sent
+        let taproot = "bcrt1pyqsjygeyy5nzw2pf9g4jctfw9ucrzv3nxs6nvdec8yark0pa8clsjqmhvl";
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_paid_order(&db, Some(taproot)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond(
+            "withdraw",
+            json!({
+                "tx" : "0200000000000000000000",
+                "txid" : REFUND_TXID,
+                "psbt" : "cHNidP8BAAoCAAAAAAAAAAAAAA==",
+            }),
+        );
+
+        let refund = refund_order(&state, &mut rpc, uuid).await.unwrap().unwrap();
+        assert_eq!(refund.address, taproot);
+
+        let withdraw = rpc.params_of("withdraw").unwrap();
+        assert_eq!(withdraw["destination"], taproot);
+        assert_eq!(
+            withdraw["satoshi"],
+            format!("{}msat", refund.amount_sat.sat_value() * 1000)
+        );
+    }
+
+    #[tokio::test]
+    async fn order_without_refund_address_is_left_to_operator() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_paid_order(&db, None).await;
+
+        let mut rpc = FakeClnRpc::default();
+        let refund = refund_order(&state, &mut rpc, uuid).await.unwrap();
+
+        assert!(refund.is_none());
+        assert!(rpc.called_methods().is_empty());
+        assert_eq!(get_payment_state(&db, uuid).await, PaymentState::Paid);
+    }
+
+    #[tokio::test]
+    async fn failed_withdraw_keeps_payment() {
+        let db = get_db().await;
+        let state = test_state(db.clone());
+        let uuid = insert_paid_order(&db, Some(REFUND_ADDRESS)).await;
+
+        let mut rpc = FakeClnRpc::default();
+        rpc.fail("withdraw", "Could not afford");
+
+        refund_order(&state, &mut rpc, uuid).await.unwrap_err();
+        assert_eq!(get_payment_state(&db, uuid).await, PaymentState::Paid);
+    }
+}
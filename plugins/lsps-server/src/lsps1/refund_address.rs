@@ -0,0 +1,132 @@
+//! The types of `refund_onchain_address` the server accepts
+//!
+//! The operator can restrict the accepted types in
+//! `lsps1-accepted-refund-address-types`. All standard types are
+//! accepted by default. Addresses of an unknown type are never accepted
+//! because lightningd might fail to refund them.
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+
+use lsp_primitives::lsps0::common_schemas::{AddressType, OnchainAddress};
+
+use crate::{options, PluginState};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AcceptedRefundAddressTypes {
+    address_types: Vec<AddressType>,
+}
+
+impl Default for AcceptedRefundAddressTypes {
+    fn default() -> Self {
+        Self {
+            address_types: AddressType::STANDARD.to_vec(),
+        }
+    }
+}
+
+impl AcceptedRefundAddressTypes {
+    /// Parses the value of the `lsps1-accepted-refund-address-types` option
+    ///
+    /// E.g: `p2wpkh,p2tr`
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        let mut address_types = value
+            .split(',')
+            .map(|entry| entry.trim().parse::<AddressType>())
+            .collect::<Result<Vec<_>, _>>()?;
+        address_types.sort();
+        address_types.dedup();
+        Ok(Self { address_types })
+    }
+
+    /// Creates the policy from the value of the plugin option
+    ///
+    /// All standard types are accepted if the option isn't set
+    pub(crate) fn from_option(value: Option<String>) -> Result<Self> {
+        value
+            .map(|value| Self::parse(&value))
+            .transpose()
+            .with_context(|| {
+                format!(
+                    "Invalid value for option '{}'",
+                    options::LSPS1_ACCEPTED_REFUND_ADDRESS_TYPES
+                )
+            })
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Creates the policy from the configured plugin options
+    pub(crate) fn from_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        Self::from_option(plugin.option(&options::lsps1_accepted_refund_address_types())?)
+    }
+
+    /// Checks if refunds can be sent to `address`
+    ///
+    /// Returns a message that names the type of the address if it isn't accepted
+    pub(crate) fn validate(&self, address: &OnchainAddress) -> Result<(), String> {
+        let address_type = address.address_type();
+        if self.address_types.contains(&address_type) {
+            return Ok(());
+        }
+
+        let accepted: Vec<&str> = self.address_types.iter().map(|t| t.as_str()).collect();
+        Err(format!(
+            "Refunds to {} addresses are not accepted. Use one of {}",
+            address_type,
+            accepted.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    const P2PKH: &str = "mfcHP2WMCVLsVZA8yrovmhMgxNFW9r98xw";
+    const P2WPKH: &str = "bcrt1qkm08480v79rzjp7tx2pjrly423ncv85k65nsmu";
+    const P2TR: &str = "bcrt1pyqsjygeyy5nzw2pf9g4jctfw9ucrzv3nxs6nvdec8yark0pa8clsjqmhvl";
+    const FUTURE_SEGWIT: &str = "bcrt1sw50qt2uwha";
+
+    fn address(address: &str) -> OnchainAddress {
+        OnchainAddress::from_str(address).unwrap()
+    }
+
+    #[test]
+    fn parse_accepted_refund_address_types() {
+        let accepted = AcceptedRefundAddressTypes::parse("p2tr, p2wpkh,p2tr").unwrap();
+        assert_eq!(
+            accepted.address_types,
+            vec![AddressType::P2wpkh, AddressType::P2tr]
+        );
+
+        assert!(AcceptedRefundAddressTypes::parse("p2wpkh,p2pk").is_err());
+        assert!(AcceptedRefundAddressTypes::parse("unknown").is_err());
+        assert!(AcceptedRefundAddressTypes::parse("").is_err());
+        assert_eq!(
+            AcceptedRefundAddressTypes::from_option(None).unwrap(),
+            AcceptedRefundAddressTypes::default()
+        );
+    }
+
+    #[test]
+    fn all_standard_types_are_accepted_by_default() {
+        let accepted = AcceptedRefundAddressTypes::default();
+        for refund_address in [P2PKH, P2WPKH, P2TR] {
+            assert_eq!(accepted.validate(&address(refund_address)), Ok(()));
+        }
+        assert!(accepted.validate(&address(FUTURE_SEGWIT)).is_err());
+    }
+
+    #[test]
+    fn reject_address_types_that_are_not_accepted() {
+        let accepted = AcceptedRefundAddressTypes::parse("p2wpkh,p2tr").unwrap();
+        assert_eq!(accepted.validate(&address(P2TR)), Ok(()));
+
+        let err = accepted.validate(&address(P2PKH)).unwrap_err();
+        assert_eq!(
+            err,
+            "Refunds to p2pkh addresses are not accepted. Use one of p2wpkh, p2tr"
+        );
+    }
+}
@@ -0,0 +1,506 @@
+//! Detects chain reorgs and re-verifies the orders they affect
+//!
+//! A reorg can unconfirm the funding transaction of a recently completed
+//! order. The plugin remembers the hashes of the most recent blocks. If
+//! lightningd reports a block at or below the known chain tip that isn't
+//! the known block at that height, the chain was replaced from that height.
+//!
+//! After a reorg every completed order whose funding transaction confirmed
+//! in a replaced block is verified against the channels of lightningd.
+//! An order whose funding transaction is no longer confirmed moves to
+//! `AwaitingConfirmation`. It completes again once the funding transaction
+//! confirms in a later block.
+use anyhow::{Context, Result};
+use cln_rpc::model::requests::ListpeerchannelsRequest;
+use cln_rpc::model::responses::ListpeerchannelsChannels;
+use serde_json::json;
+
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::schema::{Lsps1Channel, Lsps1OrderState, OrderLogEvent};
+use crate::db::sqlite::queries::{
+    ChainBlock, ClearFundingBlockheightQuery, FundedOrder, GetBlockQuery, GetChainTipQuery,
+    GetFundedOrdersQuery, RecordBlockQuery, SetFundingBlockheightQuery, UpdateOrderStateQuery,
+};
+use crate::db::sqlite::Database;
+use crate::state::PluginState;
+
+/// The number of recent blocks whose hashes are remembered
+///
+/// A block below the tip that is older than the remembered blocks is
+/// treated as a reorg. This might verify orders that weren't affected.
+pub(crate) const TRACKED_BLOCKS: u32 = 144;
+
+/// Reads the block from a `block_added` notification
+pub(crate) fn parse_block_added(notification: &serde_json::Value) -> Result<ChainBlock> {
+    // Older versions of lightningd name the object `block`
+    let block = notification
+        .get("block_added")
+        .or_else(|| notification.get("block"))
+        .context("Notification doesn't contain a block")?;
+    let height = block
+        .get("height")
+        .and_then(|height| height.as_u64())
+        .context("Block doesn't contain a height")?;
+    let hash = block
+        .get("hash")
+        .and_then(|hash| hash.as_str())
+        .context("Block doesn't contain a hash")?;
+    Ok(ChainBlock {
+        height: u32::try_from(height)?,
+        hash: hash.to_string(),
+    })
+}
+
+/// Records `block` as the chain tip
+///
+/// Returns the height from which the chain was replaced or `None` if
+/// `block` extends the known chain. A block that was seen before isn't
+/// a reorg. Lightningd reports the most recent blocks again when it starts.
+pub(crate) async fn detect_reorg(database: &Database, block: &ChainBlock) -> Result<Option<u32>> {
+    let mut tx = database.begin().await?;
+    let known = GetBlockQuery {
+        height: block.height,
+    }
+    .execute(&mut tx)
+    .await?;
+    if matches!(&known, Some(known) if known.hash == block.hash) {
+        tx.commit().await?;
+        return Ok(None);
+    }
+
+    let tip = GetChainTipQuery.execute(&mut tx).await?;
+    let reorg_height = match tip {
+        Some(tip) if block.height <= tip.height => Some(block.height),
+        _ => None,
+    };
+    RecordBlockQuery {
+        block: block.clone(),
+        keep: TRACKED_BLOCKS,
+    }
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(reorg_height)
+}
+
+/// Handles a `block_added` notification
+///
+/// Demotes the completed orders whose funding transaction was unconfirmed
+/// by a reorg and completes the orders whose funding transaction confirmed
+/// again. Returns the number of orders that changed state.
+pub(crate) async fn check_chain_reorg(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    notification: &serde_json::Value,
+) -> Result<usize> {
+    let block = parse_block_added(notification)?;
+    let reorg_height = detect_reorg(&state.database, &block).await?;
+    if let Some(reorg_height) = reorg_height {
+        log::warn!(
+            "Detected a reorg from height {}. The new tip is {} at height {}",
+            reorg_height,
+            block.hash,
+            block.height
+        );
+    }
+
+    let mut tx = state.database.begin().await?;
+    let reorged = match reorg_height {
+        Some(reorg_height) => {
+            GetFundedOrdersQuery::completed_since(reorg_height)
+                .execute(&mut tx)
+                .await?
+        }
+        None => Vec::new(),
+    };
+    let awaiting = GetFundedOrdersQuery::awaiting_confirmation()
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    if reorged.is_empty() && awaiting.is_empty() {
+        return Ok(0);
+    }
+
+    let channels = rpc
+        .listpeerchannels(&ListpeerchannelsRequest { id: None })
+        .await
+        .context("Failed to call 'listpeerchannels'")?
+        .channels
+        .unwrap_or_default();
+
+    let mut changed = 0;
+    for order in reorged {
+        if verify_completed_order(state, &channels, &order).await? {
+            changed += 1;
+        }
+    }
+    for order in awaiting {
+        if confirm_awaiting_order(state, &channels, &order).await? {
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+/// The confirmation of a funding transaction as seen by lightningd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FundingStatus {
+    /// Confirmed in the block at this height
+    Confirmed(u32),
+    Unconfirmed,
+    /// Lightningd doesn't know the channel
+    Unknown,
+}
+
+/// Lightningd assigns a `short_channel_id` once the funding transaction
+/// confirms and replaces it if the transaction confirms in another block
+fn funding_status(channels: &[ListpeerchannelsChannels], channel: &Lsps1Channel) -> FundingStatus {
+    let funding_txid = channel.funding_txid.to_string();
+    let peer_channel = channels.iter().find(|c| {
+        c.funding_txid.as_deref() == Some(funding_txid.as_str())
+            && c.funding_outnum == Some(channel.outnum)
+    });
+    match peer_channel {
+        Some(c) => match c.short_channel_id {
+            Some(short_channel_id) => FundingStatus::Confirmed(short_channel_id.block()),
+            None => FundingStatus::Unconfirmed,
+        },
+        None => FundingStatus::Unknown,
+    }
+}
+
+/// Moves a completed order to `AwaitingConfirmation` if its funding
+/// transaction is no longer confirmed
+///
+/// Returns `true` if the order was moved
+async fn verify_completed_order(
+    state: &PluginState,
+    channels: &[ListpeerchannelsChannels],
+    order: &FundedOrder,
+) -> Result<bool> {
+    match funding_status(channels, &order.channel) {
+        FundingStatus::Confirmed(height) if Some(height) == order.funding_blockheight => Ok(false),
+        FundingStatus::Confirmed(height) => {
+            // The funding transaction confirmed in a block of the new chain
+            let mut tx = state.database.begin().await?;
+            ClearFundingBlockheightQuery {
+                order_uuid: order.order_uuid,
+            }
+            .execute(&mut tx)
+            .await?;
+            SetFundingBlockheightQuery {
+                order_uuid: order.order_uuid,
+                funding_blockheight: height,
+            }
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            log::info!(
+                "The funding transaction of order {} moved from block {:?} to {} in a reorg",
+                order.order_uuid,
+                order.funding_blockheight,
+                height
+            );
+            Ok(false)
+        }
+        FundingStatus::Unconfirmed => {
+            let mut tx = state.database.begin().await?;
+            UpdateOrderStateQuery {
+                order_uuid: order.order_uuid,
+                state: Lsps1OrderState::AwaitingConfirmation,
+                generation: order.generation,
+                failure_reason: None,
+                changed_at: state.clock.now(),
+            }
+            .execute(&mut tx)
+            .await?;
+            ClearFundingBlockheightQuery {
+                order_uuid: order.order_uuid,
+            }
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            state.order_watcher.notify(order.order_uuid);
+
+            log::warn!(
+                "A reorg unconfirmed the funding transaction {}:{} of completed order {}",
+                order.channel.funding_txid,
+                order.channel.outnum,
+                order.order_uuid
+            );
+            state.order_log.warn(
+                order.order_uuid,
+                OrderLogEvent::FundingUnconfirmed,
+                json!({
+                    "funding_txid" : order.channel.funding_txid.to_string(),
+                    "funding_outnum" : order.channel.outnum,
+                    "funding_blockheight" : order.funding_blockheight,
+                }),
+            );
+            Ok(true)
+        }
+        FundingStatus::Unknown => {
+            log::warn!(
+                "Can't verify order {} after a reorg. Lightningd doesn't know channel {}:{}",
+                order.order_uuid,
+                order.channel.funding_txid,
+                order.channel.outnum
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Completes an order in `AwaitingConfirmation` once its funding
+/// transaction confirmed again
+///
+/// Returns `true` if the order was completed
+async fn confirm_awaiting_order(
+    state: &PluginState,
+    channels: &[ListpeerchannelsChannels],
+    order: &FundedOrder,
+) -> Result<bool> {
+    let height = match funding_status(channels, &order.channel) {
+        FundingStatus::Confirmed(height) => height,
+        FundingStatus::Unconfirmed | FundingStatus::Unknown => return Ok(false),
+    };
+
+    let mut tx = state.database.begin().await?;
+    UpdateOrderStateQuery {
+        order_uuid: order.order_uuid,
+        state: Lsps1OrderState::Completed,
+        generation: order.generation,
+        failure_reason: None,
+        changed_at: state.clock.now(),
+    }
+    .execute(&mut tx)
+    .await?;
+    SetFundingBlockheightQuery {
+        order_uuid: order.order_uuid,
+        funding_blockheight: height,
+    }
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    state.order_watcher.notify(order.order_uuid);
+
+    log::info!(
+        "The funding transaction of order {} confirmed again at height {}",
+        order.order_uuid,
+        height
+    );
+    state.order_log.info(
+        order.order_uuid,
+        OrderLogEvent::FundingReconfirmed,
+        json!({ "funding_blockheight" : height }),
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use lsp_primitives::lsps0::common_schemas::{IsoDatetime, TransactionId};
+    use uuid::Uuid;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::schema::OrderGeneration;
+    use crate::db::sqlite::queries::{CreateChannelQuery, GetOrderQuery};
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::state::test_support::test_state;
+
+    fn block_added(height: u32, hash: &str) -> serde_json::Value {
+        json!({ "block_added" : { "hash" : hash, "height" : height } })
+    }
+
+    fn block(height: u32, hash: &str) -> ChainBlock {
+        parse_block_added(&block_added(height, hash)).unwrap()
+    }
+
+    /// Creates a completed order whose funding transaction confirmed at `funding_blockheight`
+    async fn create_completed_order(db: &Database, funding_blockheight: u32) -> Lsps1Channel {
+        let query = create_order_query();
+        let order_uuid = query.order.uuid;
+        let txid = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let channel = Lsps1Channel {
+            funding_txid: TransactionId::from_str(&txid).unwrap(),
+            outnum: 0,
+            funded_at: IsoDatetime::now(),
+        };
+
+        let mut tx = db.begin().await.unwrap();
+        query.execute(&mut tx).await.unwrap();
+        CreateChannelQuery::new(order_uuid, channel.clone())
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        SetFundingBlockheightQuery {
+            order_uuid,
+            funding_blockheight,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        UpdateOrderStateQuery {
+            order_uuid,
+            state: Lsps1OrderState::Completed,
+            generation: OrderGeneration(0),
+            changed_at: IsoDatetime::now(),
+            failure_reason: None,
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        channel
+    }
+
+    fn listpeerchannels(channels: &[(&Lsps1Channel, Option<&str>)]) -> serde_json::Value {
+        let channels: Vec<_> = channels
+            .iter()
+            .map(|(channel, short_channel_id)| {
+                json!({
+                    "funding_txid" : channel.funding_txid.to_string(),
+                    "funding_outnum" : channel.outnum,
+                    "short_channel_id" : short_channel_id,
+                    "state" : "CHANNELD_NORMAL",
+                })
+            })
+            .collect();
+        json!({ "channels" : channels })
+    }
+
+    async fn funded_order(db: &Database, channel: &Lsps1Channel) -> (Lsps1OrderState, Option<u32>) {
+        let mut tx = db.begin().await.unwrap();
+        let mut orders = GetFundedOrdersQuery::completed_since(0)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        orders.extend(
+            GetFundedOrdersQuery::awaiting_confirmation()
+                .execute(&mut tx)
+                .await
+                .unwrap(),
+        );
+        let order = orders
+            .into_iter()
+            .find(|o| o.channel.funding_txid == channel.funding_txid)
+            .expect("order with a confirmed or unconfirmed funding transaction");
+        let state = GetOrderQuery::by_uuid(order.order_uuid)
+            .execute(&mut tx)
+            .await
+            .unwrap()
+            .unwrap()
+            .order_state;
+        tx.commit().await.unwrap();
+        (state, order.funding_blockheight)
+    }
+
+    #[tokio::test]
+    async fn detect_reorgs_in_block_sequence() {
+        let (db, _) = get_temp_db().await;
+
+        assert_eq!(detect_reorg(&db, &block(100, "a")).await.unwrap(), None);
+        assert_eq!(detect_reorg(&db, &block(101, "b")).await.unwrap(), None);
+        // A block that was seen before isn't a reorg
+        assert_eq!(detect_reorg(&db, &block(100, "a")).await.unwrap(), None);
+        assert_eq!(detect_reorg(&db, &block(102, "c")).await.unwrap(), None);
+
+        // Block 101 was replaced
+        assert_eq!(
+            detect_reorg(&db, &block(101, "b2")).await.unwrap(),
+            Some(101)
+        );
+        assert_eq!(detect_reorg(&db, &block(102, "c2")).await.unwrap(), None);
+        // The replaced block isn't known anymore
+        assert_eq!(
+            detect_reorg(&db, &block(102, "c")).await.unwrap(),
+            Some(102)
+        );
+
+        // Blocks can be skipped while the plugin isn't running
+        assert_eq!(detect_reorg(&db, &block(500, "d")).await.unwrap(), None);
+        // A block below the tip that isn't remembered
+        assert_eq!(
+            detect_reorg(&db, &block(200, "e")).await.unwrap(),
+            Some(200)
+        );
+
+        assert!(parse_block_added(&json!({ "block_added" : { "height" : 1 } })).is_err());
+    }
+
+    #[tokio::test]
+    async fn reorg_demotes_and_completes_orders() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        let unconfirmed = create_completed_order(&db, 101).await;
+        let moved = create_completed_order(&db, 100).await;
+        let settled = create_completed_order(&db, 90).await;
+
+        // lightningd isn't called while no order is affected
+        let mut rpc = FakeClnRpc::default();
+        for (height, hash) in [(100, "a"), (101, "b"), (102, "c")] {
+            let changed = check_chain_reorg(&state, &mut rpc, &block_added(height, hash))
+                .await
+                .unwrap();
+            assert_eq!(changed, 0);
+        }
+        assert!(rpc.called_methods().is_empty());
+
+        // The chain is replaced from block 100. One funding transaction
+        // confirmed in another block and the other one isn't confirmed
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(&[
+                (&unconfirmed, None),
+                (&moved, Some("101x2x0")),
+                (&settled, Some("90x1x0")),
+            ]),
+        );
+        let changed = check_chain_reorg(&state, &mut rpc, &block_added(100, "a2"))
+            .await
+            .unwrap();
+        assert_eq!(changed, 1);
+        assert_eq!(
+            funded_order(&db, &unconfirmed).await,
+            (Lsps1OrderState::AwaitingConfirmation, None)
+        );
+        assert_eq!(
+            funded_order(&db, &moved).await,
+            (Lsps1OrderState::Completed, Some(101))
+        );
+        assert_eq!(
+            funded_order(&db, &settled).await,
+            (Lsps1OrderState::Completed, Some(90))
+        );
+
+        // The order waits until the funding transaction confirms again
+        rpc.respond(
+            "listpeerchannels",
+            listpeerchannels(&[(&unconfirmed, None)]),
+        )
+        .respond(
+            "listpeerchannels",
+            listpeerchannels(&[(&unconfirmed, Some("102x1x0"))]),
+        );
+        for (height, changed) in [(101, 0), (102, 1)] {
+            let result = check_chain_reorg(&state, &mut rpc, &block_added(height, "new"))
+                .await
+                .unwrap();
+            assert_eq!(result, changed);
+        }
+        assert_eq!(
+            funded_order(&db, &unconfirmed).await,
+            (Lsps1OrderState::Completed, Some(102))
+        );
+        assert_eq!(
+            rpc.called_methods(),
+            vec!["listpeerchannels", "listpeerchannels", "listpeerchannels"]
+        );
+    }
+}
@@ -1,161 +1,499 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use lsp_primitives::lsps1::schema::Lsps1Options;
 use lsp_primitives::methods::Lsps1GetInfoResponse;
 
+use cln_plugin::options::{ConfigOption, OptionType};
 use cln_plugin::ConfiguredPlugin;
 
-use lsp_primitives::lsps0::common_schemas::SatAmount;
+use lsp_primitives::lsps0::common_schemas::{Network, SatAmount};
 use lsp_primitives::lsps1::builders::{Lsps1InfoResponseBuilder, Lsps1OptionsBuilder};
+use lsp_primitives::lsps1::channel_limits::min_channel_capacity_sat;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::lsps1::channel_type::AllowedChannelTypes;
+use crate::lsps1::fee_calc::StandardFeeCalculator;
+use crate::lsps1::fee_consistency::evaluate_corners;
+use crate::lsps1::info::{parse_extra_json, ServerFeatures, WebsiteOption};
+use crate::lsps1::onchain_payment::OnchainPaymentConfig;
+use crate::lsps1::refund_address::AcceptedRefundAddressTypes;
+use crate::network::parse_network;
 use crate::options;
 use crate::state::PluginState;
 
-fn create_sat_amount(amount: i64, name: &str) -> Result<SatAmount> {
-    let amount: u64 = amount
-        .try_into()
-        .context(format!("{} should be a positive number", name))?;
-    Ok(SatAmount::new(amount))
+/// Provides the values of the options of the plugin
+///
+/// At startup the values are read from the [`ConfiguredPlugin`].
+/// `lsps1-reload-config` reads them using `listconfigs` instead.
+pub(crate) trait OptionSource {
+    fn option<'a, V: OptionType<'a>>(&self, opt: &ConfigOption<'a, V>) -> Result<V::OutputValue>;
+
+    /// The network lightningd runs on
+    fn network(&self) -> Result<Network>;
 }
 
 // TODO: We don't need the trait-bounds here.
 // We should modify the cln-plugin crate to allow us to remove them
-pub fn get_options<I, O>(plugin: &ConfiguredPlugin<PluginState, I, O>) -> Result<Lsps1Options>
+impl<I, O> OptionSource for ConfiguredPlugin<PluginState, I, O>
 where
     I: AsyncRead + Send + Unpin + 'static,
     O: AsyncWrite,
     O: Send,
     O: Unpin + 'static,
 {
+    fn option<'a, V: OptionType<'a>>(&self, opt: &ConfigOption<'a, V>) -> Result<V::OutputValue> {
+        ConfiguredPlugin::option(self, opt)
+    }
+
+    fn network(&self) -> Result<Network> {
+        parse_network(&self.configuration().network)
+    }
+}
+
+/// Parses the value of an option that is an amount of satoshis
+///
+/// Accepts plain digits, digits grouped by underscores and an optional
+/// `sat` suffix. E.g: `100000`, `100_000` or `100_000sat`.
+pub(crate) fn parse_sat_option(value: &str) -> Result<SatAmount> {
+    // Most configs use a bare integer
+    if let Ok(amount) = value.parse::<u64>() {
+        return Ok(SatAmount::new(amount));
+    }
+
+    let value = value.trim();
+    let unquoted = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    let digits = unquoted.strip_suffix("sat").unwrap_or(unquoted).trim_end();
+
+    let is_valid = digits
+        .split('_')
+        .all(|group| !group.is_empty() && group.bytes().all(|b| b.is_ascii_digit()));
+    if !is_valid {
+        bail!(
+            "Expected an amount of satoshis such as '100000' or '100_000sat' but got '{}'",
+            value
+        );
+    }
+
+    let amount = digits
+        .replace('_', "")
+        .parse::<u64>()
+        .with_context(|| format!("'{}' is too large", value))?;
+    Ok(SatAmount::new(amount))
+}
+
+pub(crate) fn sat_option(name: &str, value: Option<String>) -> Result<Option<SatAmount>> {
+    value
+        .map(|value| {
+            let amount = parse_sat_option(&value)
+                .with_context(|| format!("Invalid value for option '{}'", name))?;
+            if value.contains('_') {
+                log::warn!(
+                    "Option '{}' uses underscores in '{}'. This is deprecated, use '{}' instead",
+                    name,
+                    value,
+                    amount.sat_value()
+                );
+            }
+            Ok(amount)
+        })
+        .transpose()
+}
+
+fn required_sat_option(name: &str, value: Option<String>) -> Result<SatAmount> {
+    sat_option(name, value)?.context(format!("No value set for option {}", name))
+}
+
+/// The options that are an amount of satoshis
+#[derive(Debug)]
+struct SatAmountOptions {
+    min_initial_client_balance_sat: SatAmount,
+    max_initial_client_balance_sat: SatAmount,
+    min_initial_lsp_balance_sat: SatAmount,
+    max_initial_lsp_balance_sat: SatAmount,
+    min_channel_balance_sat: SatAmount,
+    max_channel_balance_sat: SatAmount,
+}
+
+impl SatAmountOptions {
+    /// Reads the options using `value`, which returns the configured value
+    fn read<F>(mut value: F) -> Result<Self>
+    where
+        F: FnMut(&options::StringConfigOption<'static>) -> Result<Option<String>>,
+    {
+        let mut required =
+            |opt: options::StringConfigOption<'static>| required_sat_option(opt.name, value(&opt)?);
+
+        Ok(Self {
+            min_initial_client_balance_sat: required(
+                options::lsps1_min_initial_client_balance_sat(),
+            )?,
+            max_initial_client_balance_sat: required(
+                options::lsps1_max_initial_client_balance_sat(),
+            )?,
+            min_initial_lsp_balance_sat: required(options::lsps1_min_initial_lsp_balance_sat())?,
+            max_initial_lsp_balance_sat: required(options::lsps1_max_initial_lsp_balance_sat())?,
+            min_channel_balance_sat: required(options::lsps1_min_channel_balance_sat())?,
+            max_channel_balance_sat: required(options::lsps1_max_channel_balance_sat())?,
+        })
+    }
+}
+
+impl From<SatAmountOptions> for Lsps1OptionsBuilder {
+    fn from(options: SatAmountOptions) -> Self {
+        Self {
+            min_initial_client_balance_sat: Some(options.min_initial_client_balance_sat),
+            max_initial_client_balance_sat: Some(options.max_initial_client_balance_sat),
+            min_initial_lsp_balance_sat: Some(options.min_initial_lsp_balance_sat),
+            max_initial_lsp_balance_sat: Some(options.max_initial_lsp_balance_sat),
+            min_channel_balance_sat: Some(options.min_channel_balance_sat),
+            max_channel_balance_sat: Some(options.max_channel_balance_sat),
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) fn get_options<S: OptionSource>(plugin: &S) -> Result<Lsps1Options> {
     let opt = options::lsps1_min_required_channel_confirmations();
     let min_required_channel_confirmations: u16 = plugin
-        .option(&opt)
-        .unwrap()
+        .option(&opt)?
         .try_into()
         .context(format!("Option '{}' should be an u16", opt.name))?;
 
-    let opt = options::lsps1_min_onchain_payment_confirmations();
-    let min_onchain_payment_confirmations: Option<u16> = plugin
-        .option(&opt)
-        .unwrap()
-        .map(|x| x.try_into())
-        .transpose()
-        .context("min_onchain_payment_confirmations does not fit into u16")?;
+    // The advertised on-chain options are derived from the config that
+    // is used when an order is created
+    let onchain_payment = OnchainPaymentConfig::from_options(
+        plugin.option(&options::lsps1_min_onchain_payment_size_sat())?,
+        plugin.option(&options::lsps1_onchain_confirmation_tiers())?,
+        plugin.option(&options::lsps1_min_onchain_payment_confirmations())?,
+    )?;
 
     let opt = options::lsps1_supports_zero_channel_reserve();
-    let supports_zero_channel_reserve: bool = plugin.option(&opt).unwrap();
-
-    let opt = options::lsps1_min_onchain_payment_size_sat();
-    let min_onchain_payment_size_sat: Option<SatAmount> = plugin
-        .option(&opt)
-        .unwrap()
-        .map(|x| create_sat_amount(x, opt.name))
-        .transpose()?;
+    let supports_zero_channel_reserve: bool = plugin.option(&opt)?;
 
     let opt = options::lsps1_max_channel_expiry_blocks();
     let max_channel_expiry_blocks: u32 = plugin
-        .option(&opt)
-        .unwrap()
+        .option(&opt)?
         .try_into()
         .context(format!("Option '{}' should fit into u32", opt.name))?;
 
-    let opt = options::lsps1_min_initial_client_balance_sat();
-    let min_initial_client_balance_sat: i64 = plugin
-        .option(&opt)
-        .unwrap()
-        .context(format!("No value set for option {}", opt.name()))?;
-    let min_initial_client_balance_sat: SatAmount =
-        create_sat_amount(min_initial_client_balance_sat, opt.name)?;
-
-    let opt = options::lsps1_max_initial_client_balance_sat();
-    let max_initial_client_balance_sat: i64 = plugin
-        .option(&opt)
-        .unwrap()
-        .context(format!("No value set for option {}", opt.name))?;
-    let max_initial_client_balance_sat =
-        create_sat_amount(max_initial_client_balance_sat, opt.name)?;
-
-    let opt = options::lsps1_min_initial_lsp_balance_sat();
-    let min_initial_lsp_balance_sat: i64 = plugin
-        .option(&opt)
-        .unwrap()
-        .context(format!("No value set for option {}", opt.name))?;
-    let min_initial_lsp_balance_sat: SatAmount =
-        create_sat_amount(min_initial_lsp_balance_sat, opt.name)?;
-
-    let opt = options::lsps1_max_initial_lsp_balance_sat();
-    let max_initial_lsp_balance_sat: i64 = plugin
-        .option(&opt)
-        .unwrap()
-        .context(format!("No value set for option {}", opt.name))?;
-    let max_initial_lsp_balance_sat: SatAmount =
-        create_sat_amount(max_initial_lsp_balance_sat, opt.name)?;
+    let mut sat_options = SatAmountOptions::read(|opt| plugin.option(opt))?;
 
-    let opt = options::lsps1_min_channel_balance_sat();
-    let min_channel_balance_sat: i64 = plugin
-        .option(&opt)
-        .unwrap()
-        .context(format!("No value set for option {}", opt.name))?;
-    let min_channel_balance_sat: SatAmount = create_sat_amount(min_channel_balance_sat, opt.name)?;
-
-    let opt = options::lsps1_max_channel_balance_sat();
-    let max_channel_balance_sat: i64 = plugin
-        .option(&opt)
-        .unwrap()
-        .context(format!("No value set for option {}", opt.name))?;
-    let max_channel_balance_sat: SatAmount = create_sat_amount(max_channel_balance_sat, opt.name)?;
+    // Smaller channels are refused because of the dust limit and reserve.
+    // We advertise the real minimum instead
+    let network = plugin.network()?;
+    let min_capacity = min_channel_capacity_sat(&network, supports_zero_channel_reserve);
+    if sat_options.min_channel_balance_sat < min_capacity {
+        log::warn!(
+            "Option '{}' is below the dust limit and reserve. Using {} instead of {}",
+            options::LSPS1_MIN_CHANNEL_BALANCE_SAT,
+            min_capacity,
+            sat_options.min_channel_balance_sat
+        );
+        sat_options.min_channel_balance_sat = min_capacity;
+    }
 
     let opt = options::lsps1_min_funding_confirms_within_blocks();
     let min_funding_confirms_within_blocks: u16 = plugin
-        .option(&opt)
-        .unwrap()
+        .option(&opt)?
         .try_into()
         .context(format!("{} should fit into u16", opt.name))?;
 
-    Lsps1OptionsBuilder {
+    let options = Lsps1OptionsBuilder {
         min_funding_confirms_within_blocks: Some(min_funding_confirms_within_blocks),
-        min_channel_balance_sat: Some(min_channel_balance_sat),
-        max_channel_balance_sat: Some(max_channel_balance_sat),
-        min_initial_client_balance_sat: Some(min_initial_client_balance_sat),
-        max_initial_client_balance_sat: Some(max_initial_client_balance_sat),
-        min_initial_lsp_balance_sat: Some(min_initial_lsp_balance_sat),
-        max_initial_lsp_balance_sat: Some(max_initial_lsp_balance_sat),
         min_required_channel_confirmations: Some(min_required_channel_confirmations),
         supports_zero_channel_reserve: Some(supports_zero_channel_reserve),
         max_channel_expiry_blocks: Some(max_channel_expiry_blocks),
-        min_onchain_payment_confirmations,
-        min_onchain_payment_size_sat,
+        min_onchain_payment_confirmations: onchain_payment
+            .as_ref()
+            .map(OnchainPaymentConfig::min_onchain_payment_confirmations),
+        min_onchain_payment_size_sat: onchain_payment.map(|config| config.min_payment_size),
+        ..sat_options.into()
     }
-    .build()
+    .build()?;
+    Ok(options)
 }
 
-pub fn get_info<I, O>(plugin: &ConfiguredPlugin<PluginState, I, O>) -> Result<Lsps1GetInfoResponse>
-where
-    I: AsyncRead + Send + Unpin + 'static,
-    O: AsyncWrite,
-    O: Send,
-    O: Unpin + 'static,
-{
-    let options = get_options(&plugin)?;
+pub(crate) fn get_info<S: OptionSource>(plugin: &S) -> Result<Lsps1GetInfoResponse> {
+    let options = get_options(plugin)?;
+
+    let network = plugin.network()?;
+    let opt = options::lsps1_info_website();
+    let website = match plugin
+        .option(&opt)?
+        .map(|x| WebsiteOption::parse(&x))
+        .transpose()
+        .context(format!("Invalid value for option '{}'", opt.name))
+    {
+        Ok(website) => website.and_then(|x| x.resolve(network)),
+        Err(err) if plugin.option(&options::lsps1_info_website_lenient())? => {
+            log::warn!("{:#}. The website is not served", err);
+            None
+        }
+        Err(err) => return Err(err),
+    };
 
-    Lsps1InfoResponseBuilder::default().options(options).build()
+    let opt = options::lsps1_info_extra_json();
+    let extra_fields = plugin
+        .option(&opt)?
+        .map(|x| parse_extra_json(&x))
+        .transpose()
+        .context(format!("Invalid value for option '{}'", opt.name))?
+        .unwrap_or_default();
+
+    let features = ServerFeatures {
+        extensions_enabled: plugin.option(&options::lsps1_enable_extensions())?,
+        clamp_expiry: plugin.option(&options::lsps1_clamp_expiry())?,
+        channel_type: !AllowedChannelTypes::from_option(
+            plugin.option(&options::lsps1_allowed_channel_types())?,
+        )?
+        .is_empty(),
+    };
+    let extra_fields = if features.extensions_enabled {
+        extra_fields
+    } else {
+        Default::default()
+    };
+
+    // Allows clients to detect that they connected to an LSP on another network
+    let network = features.extensions_enabled.then_some(network);
+
+    let info = Lsps1InfoResponseBuilder::default()
+        .options(options)
+        .extensions(features.extension_names())
+        .website(website)
+        .network(network)
+        .lsp_extensions(features.lsp_extensions())
+        .extra_fields(extra_fields)
+        .build()?;
+    Ok(info)
 }
 
-pub fn get_state<I, O>(
-    plugin: &ConfiguredPlugin<PluginState, I, O>,
-) -> Result<Option<Lsps1GetInfoResponse>>
-where
-    I: AsyncRead + Send + Unpin + 'static,
-    O: AsyncWrite,
-    O: Send,
-    O: Unpin + 'static,
-{
-    let lsps1_enabled = plugin.option(&options::lsps1_enable()).unwrap();
+fn validate_fee_options<S: OptionSource>(plugin: &S) -> Result<StandardFeeCalculator> {
+    let base_fee = plugin.option(&options::lsps1_fee_computation_base_fee_sat())?;
+    let weight_units = plugin.option(&options::lsps1_fee_computation_onchain_ppm())?;
+    let sat_per_billion_sat_block =
+        plugin.option(&options::lsps1_fee_computation_liquidity_ppb())?;
+    StandardFeeCalculator::from_options(base_fee, weight_units, sat_per_billion_sat_block)
+}
+
+/// Warns about advertised channels whose fee doesn't fit
+///
+/// Refuses to start if `lsps1-strict-config-check` is set
+fn check_fee_consistency<S: OptionSource>(
+    plugin: &S,
+    fee_calc: &StandardFeeCalculator,
+    lsps1_options: &Lsps1Options,
+) -> Result<()> {
+    let opt = options::lsps1_max_fee_percent();
+    let max_fee_percent: u64 = plugin
+        .option(&opt)?
+        .try_into()
+        .context(format!("Option '{}' should not be negative", opt.name))?;
+    let strict = plugin.option(&options::lsps1_strict_config_check())?;
+
+    let inconsistencies = evaluate_corners(fee_calc, lsps1_options, max_fee_percent);
+    for inconsistency in &inconsistencies {
+        log::warn!("{}", inconsistency);
+    }
+    if strict && !inconsistencies.is_empty() {
+        bail!(
+            "The fee options don't fit the advertised options. Unset '{}' to start anyway",
+            options::LSPS1_STRICT_CONFIG_CHECK
+        );
+    }
+    Ok(())
+}
+
+/// Reads and validates the options of LSPS1
+///
+/// Returns `None` if LSPS1 is disabled
+pub(crate) fn get_state<S: OptionSource>(plugin: &S) -> Result<Option<Lsps1GetInfoResponse>> {
+    let lsps1_enabled = plugin.option(&options::lsps1_enable())?;
 
     if lsps1_enabled {
-        let info = get_info(&plugin)?;
+        let fee_calc = validate_fee_options(plugin)?;
+        AcceptedRefundAddressTypes::from_option(
+            plugin.option(&options::lsps1_accepted_refund_address_types())?,
+        )?;
+        let info = get_info(plugin)?;
+        check_fee_consistency(plugin, &fee_calc, &info.options)?;
         Ok(Some(info))
     } else {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    /// The config of a node that ran the lsps0-server plugin
+    const OLD_CONFIG: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/lsps0-server.conf"
+    ));
+
+    fn sat(value: u64) -> SatAmount {
+        SatAmount::new(value)
+    }
+
+    fn parse_config(config: &str) -> HashMap<String, String> {
+        config
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_sat_option_accepts_integers() {
+        assert_eq!(parse_sat_option("0").unwrap(), sat(0));
+        assert_eq!(parse_sat_option("100000").unwrap(), sat(100_000));
+        assert_eq!(
+            parse_sat_option("18446744073709551615").unwrap(),
+            sat(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn parse_sat_option_accepts_underscores_and_suffix() {
+        assert_eq!(parse_sat_option("100_000").unwrap(), sat(100_000));
+        assert_eq!(parse_sat_option("1_0_0").unwrap(), sat(100));
+        assert_eq!(parse_sat_option("100000sat").unwrap(), sat(100_000));
+        assert_eq!(parse_sat_option("16_777_215sat").unwrap(), sat(16_777_215));
+        assert_eq!(parse_sat_option("100000 sat").unwrap(), sat(100_000));
+        assert_eq!(parse_sat_option("\"100_000\"").unwrap(), sat(100_000));
+        assert_eq!(parse_sat_option(" 100000 ").unwrap(), sat(100_000));
+    }
+
+    #[test]
+    fn parse_sat_option_rejects_invalid_values() {
+        for value in [
+            "",
+            "sat",
+            "-1",
+            "1.5",
+            "abc",
+            "100msat",
+            "100 000",
+            "_100",
+            "100_",
+            "1__000",
+            "\"100",
+            "18446744073709551616",
+        ] {
+            assert!(parse_sat_option(value).is_err(), "{} should fail", value);
+        }
+    }
+
+    #[test]
+    fn sat_options_mention_the_option_name() {
+        let err = required_sat_option("lsps1-max-channel-balance-sat", Some("lots".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value for option 'lsps1-max-channel-balance-sat'"
+        );
+
+        let err = required_sat_option("lsps1-max-channel-balance-sat", None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No value set for option lsps1-max-channel-balance-sat"
+        );
+        assert_eq!(
+            sat_option("lsps1-min-onchain-payment-size-sat", None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn old_config_yields_the_same_options() {
+        let config = parse_config(OLD_CONFIG);
+        let sat_options = SatAmountOptions::read(|opt| Ok(config.get(opt.name).cloned())).unwrap();
+        // The old plugin didn't require confirmations for on-chain payments
+        let onchain_payment = OnchainPaymentConfig::from_options(
+            config
+                .get(options::LSPS1_MIN_ONCHAIN_PAYMENT_SIZE_SAT)
+                .cloned(),
+            None,
+            Some(0),
+        )
+        .unwrap()
+        .unwrap();
+        let options = Lsps1OptionsBuilder {
+            min_required_channel_confirmations: Some(3),
+            min_funding_confirms_within_blocks: Some(6),
+            supports_zero_channel_reserve: Some(false),
+            max_channel_expiry_blocks: Some(4032),
+            min_onchain_payment_confirmations: Some(0),
+            min_onchain_payment_size_sat: Some(onchain_payment.min_payment_size),
+            ..sat_options.into()
+        }
+        .build()
+        .unwrap();
+
+        // The values the options had when they were parsed as integers
+        let expected = Lsps1OptionsBuilder {
+            min_required_channel_confirmations: Some(3),
+            min_funding_confirms_within_blocks: Some(6),
+            supports_zero_channel_reserve: Some(false),
+            max_channel_expiry_blocks: Some(4032),
+            min_onchain_payment_confirmations: Some(0),
+            min_onchain_payment_size_sat: Some(sat(20_000)),
+            min_initial_client_balance_sat: Some(sat(0)),
+            max_initial_client_balance_sat: Some(sat(0)),
+            min_initial_lsp_balance_sat: Some(sat(100_000)),
+            max_initial_lsp_balance_sat: Some(sat(16_777_215)),
+            min_channel_balance_sat: Some(sat(100_000)),
+            max_channel_balance_sat: Some(sat(16_777_215)),
+        }
+        .build()
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(options).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn old_config_without_confirmations_is_refused() {
+        let config = parse_config(OLD_CONFIG);
+        let err = OnchainPaymentConfig::from_options(
+            config
+                .get(options::LSPS1_MIN_ONCHAIN_PAYMENT_SIZE_SAT)
+                .cloned(),
+            config
+                .get(options::LSPS1_ONCHAIN_CONFIRMATION_TIERS)
+                .cloned(),
+            None,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(options::LSPS1_MIN_ONCHAIN_PAYMENT_CONFIRMATIONS));
+    }
+
+    #[test]
+    fn missing_sat_options_are_reported() {
+        let mut config = parse_config(OLD_CONFIG);
+        config.remove(options::LSPS1_MAX_INITIAL_LSP_BALANCE_SAT);
+
+        let err = SatAmountOptions::read(|opt| Ok(config.get(opt.name).cloned())).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(options::LSPS1_MAX_INITIAL_LSP_BALANCE_SAT));
+
+        config.insert(
+            options::LSPS1_MAX_INITIAL_LSP_BALANCE_SAT.to_string(),
+            "1000000".to_string(),
+        );
+        let sat_options = SatAmountOptions::read(|opt| Ok(config.get(opt.name).cloned())).unwrap();
+        assert_eq!(sat_options.max_initial_lsp_balance_sat, sat(1_000_000));
+    }
+}
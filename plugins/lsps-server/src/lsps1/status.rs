@@ -0,0 +1,574 @@
+//! A one-shot overview of the LSP-server for operators
+//!
+//! `lsps1-status` combines the configured options, the orders of the last
+//! day and week, the active leases, the funding sources, the open queue
+//! and the health gate.
+//! The response has a stable set of numeric fields for scripts and a
+//! pre-rendered `summary` to read in a terminal.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::GetinfoRequest;
+use cln_rpc::ClnRpc;
+use serde::Serialize;
+use uuid::Uuid;
+
+use lsp_primitives::lsps0::common_schemas::IsoDatetime;
+use lsp_primitives::lsps1::schema::Lsps1Options;
+use lsp_primitives::sat_amount_json;
+
+use crate::admin_timestamp;
+use crate::db::sqlite::queries::{
+    GetActiveLeasesQuery, GetCommittedLiquidityQuery, GetLastOrderFailureQuery, GetOrderStatsQuery,
+    OrderStats,
+};
+use crate::lsps1::lease_report::build_lease_report;
+use crate::options;
+use crate::plugin_rpc::Lsps1StatusRequest;
+use crate::state::PluginState;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct SatRange {
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) min: u64,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) max: u64,
+}
+
+/// The ranges advertised in `lsps1.get_info`
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct AdvertisedRanges {
+    pub(crate) channel_balance_sat: SatRange,
+    pub(crate) initial_lsp_balance_sat: SatRange,
+    pub(crate) initial_client_balance_sat: SatRange,
+    pub(crate) max_channel_expiry_blocks: u32,
+}
+
+impl From<&Lsps1Options> for AdvertisedRanges {
+    fn from(options: &Lsps1Options) -> Self {
+        Self {
+            channel_balance_sat: SatRange {
+                min: options.min_channel_balance_sat.sat_value(),
+                max: options.max_channel_balance_sat.sat_value(),
+            },
+            initial_lsp_balance_sat: SatRange {
+                min: options.min_initial_lsp_balance_sat.sat_value(),
+                max: options.max_initial_lsp_balance_sat.sat_value(),
+            },
+            initial_client_balance_sat: SatRange {
+                min: options.min_initial_client_balance_sat.sat_value(),
+                max: options.max_initial_client_balance_sat.sat_value(),
+            },
+            max_channel_expiry_blocks: options.max_channel_expiry_blocks,
+        }
+    }
+}
+
+/// The channels the LSP is obligated to keep open
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct LeaseStatus {
+    pub(crate) lease_count: usize,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) capacity_sat: u64,
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) committed_lsp_balance_sat: u64,
+}
+
+/// The liquidity that is committed to the orders of a funding source
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct FundingSourceStatus {
+    pub(crate) label: String,
+    /// The capacity of the orders that wait for a channel
+    #[serde(serialize_with = "sat_amount_json::serialize")]
+    pub(crate) committed_sat: u64,
+    #[serde(serialize_with = "sat_amount_json::serialize_option")]
+    pub(crate) budget_sat: Option<u64>,
+    /// Orders use this source unless the client picks another one
+    pub(crate) is_default: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct HealthStatus {
+    pub(crate) healthy: bool,
+    pub(crate) problem: Option<String>,
+}
+
+/// The order that failed most recently
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct LastFailure {
+    pub(crate) order_id: Uuid,
+    pub(crate) failure_reason: Option<&'static str>,
+    #[serde(serialize_with = "admin_timestamp::serialize")]
+    pub(crate) failed_at: IsoDatetime,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct Lsps1Status {
+    pub(crate) enabled: bool,
+    /// `None` if LSPS1 isn't configured
+    pub(crate) advertised: Option<AdvertisedRanges>,
+    /// The orders created in the last 24 hours
+    pub(crate) orders_24h: OrderStats,
+    /// The orders created in the last 7 days
+    pub(crate) orders_7d: OrderStats,
+    pub(crate) leases: LeaseStatus,
+    /// Omitted if `lsps1-funding-sources-file` isn't set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) funding_sources: Vec<FundingSourceStatus>,
+    /// The number of paid orders whose channel is waiting to be opened
+    pub(crate) open_queue_depth: usize,
+    pub(crate) health: HealthStatus,
+    pub(crate) last_failure: Option<LastFailure>,
+    pub(crate) blockheight: u32,
+    pub(crate) summary: String,
+}
+
+/// Handles `lsps1-status`
+pub(crate) async fn lsps1_status(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1StatusRequest = serde_json::from_value(request)?;
+    let enabled = plugin.option(&options::lsps1_enable())?;
+    let rpc_path = plugin.configuration().rpc_file;
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let blockheight = rpc
+        .call_typed(&GetinfoRequest {})
+        .await
+        .context("Failed to call 'getinfo'")?
+        .blockheight;
+
+    let status = build_status(plugin.state(), enabled, blockheight).await?;
+    Ok(admin_timestamp::render(
+        serde_json::to_value(status)?,
+        request.epoch_only,
+    ))
+}
+
+async fn build_status(state: &PluginState, enabled: bool, blockheight: u32) -> Result<Lsps1Status> {
+    let now = state.clock.now();
+    let day_ago = IsoDatetime::from_unix_timestamp(now.unix_timestamp() - SECONDS_PER_DAY)?;
+    let week_ago = IsoDatetime::from_unix_timestamp(now.unix_timestamp() - 7 * SECONDS_PER_DAY)?;
+
+    let mut tx = state.database.begin().await?;
+    let orders_24h = GetOrderStatsQuery::between(day_ago, now)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order_stats'-query on database")?;
+    let orders_7d = GetOrderStatsQuery::between(week_ago, now)
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_order_stats'-query on database")?;
+    let leases = GetActiveLeasesQuery::at_blockheight(blockheight)
+        .execute_quarantined(&mut tx)
+        .await
+        .context("Failed to execute 'get_active_leases'-query on database")?;
+    let last_failure = GetLastOrderFailureQuery
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_last_order_failure'-query on database")?;
+    let committed = GetCommittedLiquidityQuery::all()
+        .execute(&mut tx)
+        .await
+        .context("Failed to execute 'get_committed_liquidity'-query on database")?;
+    tx.commit().await?;
+    state.quarantine.record(&leases.corrupt_rows);
+
+    let report = build_lease_report(&leases.rows, &HashMap::new(), blockheight, &now)?;
+    let capacity_sat = report
+        .leases
+        .iter()
+        .try_fold(0u64, |total, lease| total.checked_add(lease.capacity_sat))
+        .context("Leased capacity overflows")?;
+
+    let mut status = Lsps1Status {
+        enabled,
+        advertised: state
+            .lsps1_info()
+            .map(|info| AdvertisedRanges::from(&info.options)),
+        orders_24h,
+        orders_7d,
+        leases: LeaseStatus {
+            lease_count: report.totals.lease_count,
+            capacity_sat,
+            committed_lsp_balance_sat: report.totals.committed_lsp_balance_sat,
+        },
+        funding_sources: state
+            .funding_sources
+            .all()
+            .iter()
+            .map(|source| FundingSourceStatus {
+                label: source.label.clone(),
+                committed_sat: committed.get(&source.label).copied().unwrap_or(0),
+                budget_sat: source.budget_sat,
+                is_default: state.funding_sources.default_label() == Some(source.label.as_str()),
+            })
+            .collect(),
+        open_queue_depth: state.open_queue.depth(),
+        health: HealthStatus {
+            healthy: state.health.is_healthy(),
+            problem: state.health.problem(),
+        },
+        last_failure: last_failure.map(|failure| LastFailure {
+            order_id: failure.order_uuid,
+            failure_reason: failure.failure_reason.map(|reason| reason.as_str()),
+            failed_at: failure.failed_at,
+        }),
+        blockheight,
+        summary: String::new(),
+    };
+    status.summary = render_summary(&status, &now);
+    Ok(status)
+}
+
+/// Renders the status as a few lines of text
+fn render_summary(status: &Lsps1Status, now: &IsoDatetime) -> String {
+    let mut summary = String::new();
+    let yes_no = if status.enabled { "yes" } else { "no" };
+    let _ = writeln!(summary, "LSPS1 enabled: {}", yes_no);
+
+    match &status.advertised {
+        Some(ranges) => {
+            let _ = writeln!(
+                summary,
+                "Channel balance: {} - {} sat (lsp {} - {} sat, client {} - {} sat), expiry up to {} blocks",
+                ranges.channel_balance_sat.min,
+                ranges.channel_balance_sat.max,
+                ranges.initial_lsp_balance_sat.min,
+                ranges.initial_lsp_balance_sat.max,
+                ranges.initial_client_balance_sat.min,
+                ranges.initial_client_balance_sat.max,
+                ranges.max_channel_expiry_blocks
+            );
+        }
+        None => {
+            let _ = writeln!(summary, "Channel balance: not configured");
+        }
+    }
+
+    for (window, stats) in [("24h", &status.orders_24h), ("7d", &status.orders_7d)] {
+        let _ = writeln!(
+            summary,
+            "Orders {}: {}, revenue {} sat",
+            window,
+            render_order_stats(stats),
+            stats.revenue_sat
+        );
+    }
+
+    let _ = writeln!(
+        summary,
+        "Leases: {} channels, {} sat capacity, {} sat committed",
+        status.leases.lease_count,
+        status.leases.capacity_sat,
+        status.leases.committed_lsp_balance_sat
+    );
+    if !status.funding_sources.is_empty() {
+        let sources: Vec<String> = status
+            .funding_sources
+            .iter()
+            .map(render_funding_source)
+            .collect();
+        let _ = writeln!(summary, "Funding sources: {}", sources.join(", "));
+    }
+    let _ = writeln!(summary, "Open queue: {} orders", status.open_queue_depth);
+
+    if status.health.healthy {
+        let _ = writeln!(summary, "Health: ok");
+    } else {
+        let problem = status
+            .health
+            .problem
+            .as_deref()
+            .unwrap_or("unknown problem");
+        let _ = writeln!(summary, "Health: refusing orders ({})", problem);
+    }
+
+    match &status.last_failure {
+        Some(failure) => {
+            let age = now.unix_timestamp() - failure.failed_at.unix_timestamp();
+            let _ = write!(
+                summary,
+                "Last failure: order {} ({}) {} ago",
+                failure.order_id,
+                failure.failure_reason.unwrap_or("unknown reason"),
+                render_age(age)
+            );
+        }
+        None => {
+            let _ = write!(summary, "Last failure: none");
+        }
+    }
+    summary
+}
+
+/// E.g: `3 orders (1 created, 2 completed)`
+fn render_order_stats(stats: &OrderStats) -> String {
+    let counts = [
+        ("created", stats.created),
+        ("pending_open", stats.pending_open),
+        ("funding", stats.funding),
+        ("channel_opening", stats.channel_opening),
+        ("awaiting_confirmation", stats.awaiting_confirmation),
+        ("completed", stats.completed),
+        ("failed", stats.failed),
+    ];
+    let by_state: Vec<String> = counts
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(state, count)| format!("{} {}", count, state))
+        .collect();
+
+    if by_state.is_empty() {
+        format!("{} orders", stats.total())
+    } else {
+        format!("{} orders ({})", stats.total(), by_state.join(", "))
+    }
+}
+
+/// E.g: `partner 100000 of 150000 sat committed` or `hot (default) 0 sat committed`
+fn render_funding_source(source: &FundingSourceStatus) -> String {
+    let default = if source.is_default { " (default)" } else { "" };
+    match source.budget_sat {
+        Some(budget_sat) => format!(
+            "{}{} {} of {} sat committed",
+            source.label, default, source.committed_sat, budget_sat
+        ),
+        None => format!(
+            "{}{} {} sat committed",
+            source.label, default, source.committed_sat
+        ),
+    }
+}
+
+/// E.g: `5m`, `3h` or `2d`
+fn render_age(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    if seconds < 3_600 {
+        format!("{}m", seconds / 60)
+    } else if seconds < 2 * SECONDS_PER_DAY {
+        format!("{}h", seconds / 3_600)
+    } else {
+        format!("{}d", seconds / SECONDS_PER_DAY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use lsp_primitives::lsps1::schema::PaymentState;
+
+    use crate::clock::test_support::MockClock;
+    use crate::db::schema::{
+        Lsps1FailureReason, Lsps1OrderState, OrderGeneration, PaymentGeneration,
+    };
+    use crate::db::sqlite::queries::{
+        SetFundingSourceQuery, UpdateOrderStateQuery, UpdatePaymentStateQuery,
+    };
+    use crate::db::sqlite::test::{create_order_query, get_temp_db};
+    use crate::lsps1::funding_source::test_support::funding_sources;
+    use crate::state::test_support::test_state;
+
+    const NOW: i64 = 1_700_000_000;
+    const HOUR: i64 = 3_600;
+
+    fn at(timestamp: i64) -> IsoDatetime {
+        IsoDatetime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    #[tokio::test]
+    async fn status_aggregates_the_last_day_and_week() {
+        let (db, _) = get_temp_db().await;
+        let mut state = test_state(db.clone());
+        state.clock = Arc::new(MockClock::new(at(NOW)));
+
+        // Orders of 2 hours, 3 days and 10 days ago that are paid. The
+        // test payment has a fee of 500 sat
+        let mut tx = db.begin().await.unwrap();
+        for age in [2 * HOUR, 72 * HOUR, 240 * HOUR] {
+            let mut query = create_order_query();
+            query.order.created_at = at(NOW - age);
+            let label = query.payment.bolt11_invoice_label.clone();
+            query.execute(&mut tx).await.unwrap();
+            UpdatePaymentStateQuery {
+                state: PaymentState::Paid,
+                generation: PaymentGeneration(0),
+                label,
+                changed_at: at(NOW - age),
+            }
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+
+        // An unpaid order of 30 hours ago that expired
+        let mut query = create_order_query();
+        query.order.created_at = at(NOW - 30 * HOUR);
+        let failed = query.order.uuid;
+        query.execute(&mut tx).await.unwrap();
+        UpdateOrderStateQuery {
+            order_uuid: failed,
+            state: Lsps1OrderState::Failed,
+            generation: OrderGeneration(0),
+            changed_at: at(NOW - 29 * HOUR),
+            failure_reason: Some(Lsps1FailureReason::OrderExpired),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let status = build_status(&state, true, 800_000).await.unwrap();
+        assert_eq!(
+            status.orders_24h,
+            OrderStats {
+                created: 1,
+                revenue_sat: 500,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            status.orders_7d,
+            OrderStats {
+                created: 2,
+                failed: 1,
+                revenue_sat: 1_000,
+                ..Default::default()
+            }
+        );
+        assert_eq!(status.leases.lease_count, 0);
+        assert_eq!(status.open_queue_depth, 0);
+        assert!(status.health.healthy);
+        assert_eq!(
+            status.advertised.as_ref().unwrap().channel_balance_sat,
+            SatRange {
+                min: 0,
+                max: 1_000_000
+            }
+        );
+
+        let last_failure = status.last_failure.as_ref().unwrap();
+        assert_eq!(last_failure.order_id, failed);
+        assert_eq!(last_failure.failure_reason, Some("order_expired"));
+
+        // Admin outputs render timestamps in both forms
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            json["last_failure"],
+            serde_json::json!({
+                "order_id" : failed,
+                "failure_reason" : "order_expired",
+                "failed_at" : { "iso" : "2023-11-13T17:13:20.000Z", "unix" : NOW - 29 * HOUR },
+            })
+        );
+        let json = admin_timestamp::render(json, Some(true));
+        assert_eq!(json["last_failure"]["failed_at"], NOW - 29 * HOUR);
+
+        assert_eq!(
+            status.summary,
+            format!(
+                "LSPS1 enabled: yes\n\
+                Channel balance: 0 - 1000000 sat (lsp 0 - 1000000 sat, client 0 - 0 sat), expiry up to 20160 blocks\n\
+                Orders 24h: 1 orders (1 created), revenue 500 sat\n\
+                Orders 7d: 3 orders (2 created, 1 failed), revenue 1000 sat\n\
+                Leases: 0 channels, 0 sat capacity, 0 sat committed\n\
+                Open queue: 0 orders\n\
+                Health: ok\n\
+                Last failure: order {} (order_expired) 29h ago",
+                failed
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn status_of_an_empty_server() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+        state.set_lsps1_info(None);
+
+        let status = build_status(&state, false, 800_000).await.unwrap();
+        assert_eq!(status.orders_24h, OrderStats::default());
+        assert_eq!(status.orders_7d, OrderStats::default());
+        assert_eq!(status.advertised, None);
+        assert_eq!(status.last_failure, None);
+        assert!(status.summary.starts_with("LSPS1 enabled: no\n"));
+        assert!(status.summary.contains("Channel balance: not configured\n"));
+        assert!(status
+            .summary
+            .contains("Orders 24h: 0 orders, revenue 0 sat\n"));
+        assert!(status.summary.ends_with("Last failure: none"));
+        assert!(!status.summary.contains("Funding sources"));
+
+        // The response keeps the numeric fields next to the summary
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["orders_7d"]["revenue_sat"], 0);
+        assert_eq!(json["leases"]["capacity_sat"], 0);
+        assert_eq!(json["health"]["healthy"], true);
+        assert!(json["summary"].is_string());
+        assert!(json.get("funding_sources").is_none());
+    }
+
+    #[tokio::test]
+    async fn status_reports_committed_liquidity_per_funding_source() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone()).with_funding_sources(funding_sources(
+            serde_json::json!({
+                "hot" : { "addresses" : ["bcrt1qhot"] },
+                "partner" : { "addresses" : ["bcrt1qpartner"], "budget_sat" : 500_000 },
+            }),
+            Some("hot"),
+        ));
+
+        // The test order has a capacity of 100_000 sat
+        let mut tx = db.begin().await.unwrap();
+        let query = create_order_query();
+        let order_uuid = query.order.uuid;
+        query.execute(&mut tx).await.unwrap();
+        SetFundingSourceQuery {
+            order_uuid,
+            funding_source: "partner".to_string(),
+        }
+        .execute(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let status = build_status(&state, true, 800_000).await.unwrap();
+        assert_eq!(
+            status.funding_sources,
+            vec![
+                FundingSourceStatus {
+                    label: "hot".to_string(),
+                    committed_sat: 0,
+                    budget_sat: None,
+                    is_default: true,
+                },
+                FundingSourceStatus {
+                    label: "partner".to_string(),
+                    committed_sat: 100_000,
+                    budget_sat: Some(500_000),
+                    is_default: false,
+                },
+            ]
+        );
+        assert!(status.summary.contains(
+            "Funding sources: hot (default) 0 sat committed, partner 100000 of 500000 sat committed\n"
+        ));
+    }
+
+    #[test]
+    fn ages_are_rounded_down() {
+        assert_eq!(render_age(59), "0m");
+        assert_eq!(render_age(30 * 60), "30m");
+        assert_eq!(render_age(47 * HOUR), "47h");
+        assert_eq!(render_age(50 * HOUR), "2d");
+        assert_eq!(render_age(-10), "0m");
+    }
+}
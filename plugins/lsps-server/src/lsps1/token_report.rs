@@ -0,0 +1,50 @@
+//! Reports how the orders that were created with a token fared
+//!
+//! Operators hand out tokens to clients, e.g. for a campaign. The report
+//! shows per token how many orders were created, completed and failed,
+//! how much they paid and when the token was first and last used.
+use anyhow::{Context, Result};
+use cln_plugin::Plugin;
+use serde::Serialize;
+
+use crate::admin_timestamp;
+use crate::db::sqlite::queries::{GetTokenUsageQuery, TokenUsage};
+use crate::plugin_rpc::Lsps1AdminTokenReportRequest;
+use crate::state::PluginState;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct TokenReport {
+    pub(crate) tokens: Vec<TokenUsage>,
+}
+
+/// Handles `lsps1-admin-token-report`
+///
+/// Reports all tokens if the request doesn't name one
+pub(crate) async fn lsps1_admin_token_report(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1AdminTokenReportRequest = serde_json::from_value(request)?;
+    let query = match request.token {
+        Some(token) => GetTokenUsageQuery::by_token(token),
+        None => GetTokenUsageQuery::all(),
+    };
+
+    let tokens = plugin
+        .state()
+        .database
+        .with_tx("get_token_usage", move |tx| {
+            Box::pin(async move {
+                query
+                    .execute(tx)
+                    .await
+                    .context("Failed to execute 'get_token_usage'-query on database")
+            })
+        })
+        .await?;
+
+    Ok(admin_timestamp::render(
+        serde_json::to_value(TokenReport { tokens })?,
+        request.epoch_only,
+    ))
+}
@@ -1,57 +1,114 @@
+mod admin_timestamp;
 mod channel_open;
 mod cln;
+mod clock;
 mod custom_msg;
 mod db;
+mod feerate_cache;
+mod health;
+mod instance_lock;
 mod lsps1;
+mod metrics;
 mod network;
 mod options;
+mod peer_policy;
+mod plugin_rpc;
+mod selfcheck;
 mod state;
+mod tasks;
 
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use log;
 
 use cln_plugin::{Builder, FeatureBitsKind, Plugin};
+use cln_rpc::model::requests::GetinfoRequest;
+use cln_rpc::ClnRpc;
 
-use lsp_primitives::json_rpc::{
-    DefaultError, ErrorData, JsonRpcId, JsonRpcRequest, JsonRpcResponse,
-};
-
-use lsp_primitives::lsps0::schema::ListprotocolsResponse;
-use lsp_primitives::methods;
-use lsp_primitives::methods::JsonRpcMethodEnum;
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+use lsp_primitives::lsps0::features::{set_feature_bit, LSPS_FEATURE_BIT};
+use lsp_primitives::lsps0::message_id::is_lsps_message;
 
-use cln_lsps::client::{LSPS_MESSAGE_ID, LSPS_MESSAGE_ID_U16};
+use cln_lsps::client::LSPS_MESSAGE_ID_U16;
 use cln_lsps::custom_msg_hook::RpcCustomMsgMessage;
 
 use serde_json::json;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::custom_msg::context::{CustomMsgContext, CustomMsgContextBuilder};
-use crate::custom_msg::util::send_response;
-
-use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
-use sqlx::Connection;
+use crate::custom_msg::outbox::{deliver_outbox, send_or_enqueue_payload, OUTBOX_RETRY_INTERVAL};
+use crate::custom_msg::peer_queue::{PeerQueues, QueueFull};
+use crate::custom_msg::router::{Dispatched, OutboundMessage, Router};
 
+use crate::channel_open::parse_close_to;
 use crate::cln::hooks::invoice_payment::{InvoicePaymentHookData, InvoicePaymentHookResponse};
-use crate::db::sqlite::Database;
+use crate::cln::notifications::channel_state_changed::ChannelStateChangedNotification;
+use crate::cln::notifications::connect::ConnectNotification;
+use crate::cln::reconnecting_rpc::ReconnectingRpc;
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::clock::SystemClock;
+use crate::db::sqlite::url::{resolve_database_url, DEFAULT_DATABASE_FILE};
+use crate::db::sqlite::{connect_options, run_migrations, Database};
+use crate::feerate_cache::refresh_on_block_added;
+use crate::health::{take_snapshot, HealthGate, HEALTH_CHECK_INTERVAL};
+use crate::instance_lock::{
+    ClaimedByOther, InstanceLock, HEARTBEAT_INTERVAL, MAX_HEARTBEAT_FAILURES,
+};
+use crate::lsps1::channel_open::{
+    enqueue_pending_orders, process_queued_order, reconcile_funding_orders,
+};
+use crate::lsps1::channel_usage::sample_channel_usage;
+use crate::lsps1::funding_reservation::release_stale_funding_inputs;
+use crate::lsps1::funding_source::FundingSources;
 use crate::lsps1::hooks::{
-    do_lsps1_create_order, do_lsps1_get_info, do_lsps1_get_order,
-    invoice_payment as lsps1_invoice_payment,
+    backfill_funding_blockheights, channel_state_changed as lsps1_channel_state_changed,
+    connect as lsps1_connect, invoice_payment as lsps1_invoice_payment,
+    reconcile_channel_opening_orders, DisabledResponse, FUNDING_BLOCKHEIGHT_BACKFILL_INTERVAL,
 };
+use crate::lsps1::order_expiry::{expire_unpaid_orders, ORDER_EXPIRY_INTERVAL};
+use crate::lsps1::order_log::{OrderLogger, ORDER_LOG_CAPACITY};
+use crate::lsps1::reorg::check_chain_reorg;
 use crate::network::parse_network;
+use crate::peer_policy::PeerPolicy;
 use crate::state::PluginState;
-
-const FEATURE_BIT_STRING : & str = "0200000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
+use crate::tasks::spawn_supervised;
+
+/// Unwraps a startup `Result` or disables the plugin
+///
+/// lightningd shows the reason to the operator, so the error is logged and
+/// reported with its full context chain before `main` returns it.
+/// `ConfiguredPlugin::disable` consumes the plugin, which is why this is a
+/// macro and not a function.
+macro_rules! disable_on_err {
+    ($plugin:ident, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(err) => {
+                let err: anyhow::Error = err;
+                log::error!("{:#}", err);
+                $plugin.disable(&format!("{:#}", err)).await?;
+                return Err(err);
+            }
+        }
+    };
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let featurebits = set_feature_bit("", LSPS_FEATURE_BIT)?;
+
     let configured_plugin =
         match Builder::<PluginState, _, _>::new(tokio::io::stdin(), tokio::io::stdout())
             .option(options::lsp_server_database_url())
+            .option(options::lsp_server_force_start())
             .option(options::lsps1_enable())
+            .option(options::lsps1_disabled_response())
+            .option(options::lsps1_enable_extensions())
             .option(options::lsps1_min_required_channel_confirmations())
             .option(options::lsps1_min_onchain_payment_confirmations())
+            .option(options::lsps1_onchain_confirmation_tiers())
             .option(options::lsps1_min_funding_confirms_within_blocks())
             .option(options::lsps1_supports_zero_channel_reserve())
             .option(options::lsps1_max_channel_expiry_blocks())
@@ -59,6 +116,8 @@ async fn main() -> Result<()> {
             .option(options::lsps1_fee_computation_base_fee_sat())
             .option(options::lsps1_fee_computation_onchain_ppm())
             .option(options::lsps1_fee_computation_liquidity_ppb())
+            .option(options::lsps1_max_fee_percent())
+            .option(options::lsps1_strict_config_check())
             .option(options::lsps1_order_lifetime_seconds())
             .option(options::lsps1_min_initial_client_balance_sat())
             .option(options::lsps1_max_initial_client_balance_sat())
@@ -66,11 +125,48 @@ async fn main() -> Result<()> {
             .option(options::lsps1_max_initial_lsp_balance_sat())
             .option(options::lsps1_min_channel_balance_sat())
             .option(options::lsps1_max_channel_balance_sat())
+            .option(options::lsps1_peer_allowlist_file())
+            .option(options::lsps1_peer_denylist_file())
+            .option(options::lsps1_funding_sources_file())
+            .option(options::lsps1_funding_source_label())
+            .option(options::lsps1_info_website())
+            .option(options::lsps1_info_website_lenient())
+            .option(options::lsps1_info_extra_json())
+            .option(options::lsps1_max_block_lag())
+            .option(options::lsps1_hide_info_when_unhealthy())
+            .option(options::lsps1_clamp_expiry())
+            .option(options::lsps1_allowed_channel_types())
+            .option(options::lsps1_accepted_refund_address_types())
+            .option(options::lsps1_close_to_address())
+            .option(options::lsps1_usage_sampling_minutes())
+            .option(options::lsps0_response_retry_ttl_minutes())
+            .option(options::lsps0_per_peer_queue_depth())
+            .option(options::lsps1_feerate_refresh_seconds())
+            .option(options::lsps1_payment_grace_seconds())
+            .option(options::lsps1_max_concurrent_opens())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_fulfill_order())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_retry_open())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_reload_peer_lists())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_reload_config())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_simulate_fees())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_lease_report())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_token_report())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_channel_usage())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_order_log())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_metrics())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_db_schema_version())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_admin_quarantine_list())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_selfcheck())
+            .rpcmethod_from_builder(crate::plugin_rpc::lsps1_status())
             .custommessages(vec![LSPS_MESSAGE_ID_U16])
             .hook("custommsg", handle_custom_msg)
             .hook("invoice_payment", handle_paid_invoice)
-            .featurebits(FeatureBitsKind::Node, String::from(FEATURE_BIT_STRING))
-            .featurebits(FeatureBitsKind::Init, String::from(FEATURE_BIT_STRING))
+            .subscribe("connect", handle_connect)
+            .subscribe("channel_state_changed", handle_channel_state_changed)
+            .subscribe("block_added", handle_block_added)
+            .subscribe("shutdown", handle_shutdown)
+            .featurebits(FeatureBitsKind::Node, featurebits.clone())
+            .featurebits(FeatureBitsKind::Init, featurebits)
             .configure()
             .await?
         {
@@ -83,197 +179,556 @@ async fn main() -> Result<()> {
         "This implementation is developped for testing the corresponding LSP-client implementation"
     );
 
-    let lsps1_info = match crate::lsps1::state::get_state(&configured_plugin) {
-        Ok(info) => {
-            log::info!("{:?}", info);
-            info
-        }
-        Err(err) => {
-            log::warn!("Failed to read configuration for LSPS1");
-            configured_plugin
-                .disable(&format!("Invalid configuration: {}", err))
-                .await?;
-            return Err(err);
-        }
-    };
+    let lsps1_info = disable_on_err!(
+        configured_plugin,
+        crate::lsps1::state::get_state(&configured_plugin).context("Invalid configuration")
+    );
+    log::info!("{:?}", lsps1_info);
 
     // Connect to the database and run migration scripts
-    let connection_string: String =
-        match configured_plugin.option(&options::lsp_server_database_url()) {
-            Ok(None) => {
-                log::info!("No config found for 'lsp_server_database_url'");
-                let lightning_dir = configured_plugin.configuration().lightning_dir;
-                let connection_string = format!("sqlite://{}/lsp_server.db", lightning_dir);
-                log::info!("Using default: '{}'", connection_string);
-                connection_string
-            }
-            Ok(Some(connection_string)) => connection_string, // User configured a database
-            Err(_) => panic!(
-                "Failed to read config variable {}",
-                options::lsp_server_database_url().name
-            ),
-        };
+    let database_url = match configured_plugin.option(&options::lsp_server_database_url()) {
+        Ok(None) => {
+            log::info!("No config found for 'lsp_server_database_url'");
+            DEFAULT_DATABASE_FILE.to_string()
+        }
+        Ok(Some(database_url)) => database_url, // User configured a database
+        Err(_) => panic!(
+            "Failed to read config variable {}",
+            options::lsp_server_database_url().name
+        ),
+    };
+    let lightning_dir = PathBuf::from(configured_plugin.configuration().lightning_dir);
+    let database_url = disable_on_err!(
+        configured_plugin,
+        resolve_database_url(&database_url, &lightning_dir)
+            .with_context(|| format!("Invalid {}", options::LSP_SERVER_DATABASE_URL))
+    );
+    log::info!("Using the database at '{}'", database_url.path.display());
 
-    let options = SqliteConnectOptions::from_str(&connection_string)?.create_if_missing(true);
-    let mut connection = SqliteConnection::connect_with(&options).await.unwrap();
+    let options = connect_options(&database_url.connection_string()?)?;
     log::info!("Running database migration scripts");
-    sqlx::migrate!().run(&mut connection).await?;
+    run_migrations(&options).await?;
     log::info!("Successfully executed migrations");
     log::warn!("Trying database connection");
 
     let database = Database::connect_with_options(options).await?;
 
-    let plugin = configured_plugin
-        .start(PluginState::new(database, lsps1_info))
-        .await?;
+    // A peer list that can't be read is a configuration error.
+    // We refuse to start instead of guessing which peers are allowed
+    let peer_policy = disable_on_err!(
+        configured_plugin,
+        PeerPolicy::load(
+            configured_plugin
+                .option(&options::lsps1_peer_allowlist_file())?
+                .map(PathBuf::from),
+            configured_plugin
+                .option(&options::lsps1_peer_denylist_file())?
+                .map(PathBuf::from),
+        )
+        .context("Failed to load the peer lists")
+    );
+
+    // Orders of a funding source may only spend its outputs. We refuse
+    // to start instead of funding them with any output of the wallet
+    let funding_sources = disable_on_err!(
+        configured_plugin,
+        FundingSources::load(
+            configured_plugin
+                .option(&options::lsps1_funding_sources_file())?
+                .map(PathBuf::from),
+            configured_plugin.option(&options::lsps1_funding_source_label())?,
+        )
+        .context("Failed to load the funding sources")
+    );
+
+    // Funds sent to an address of another network are lost.
+    // We refuse to start instead of opening channels that close to it
+    let network = parse_network(&configured_plugin.configuration().network)?;
+    let close_to = disable_on_err!(
+        configured_plugin,
+        configured_plugin
+            .option(&options::lsps1_close_to_address())?
+            .map(|address| parse_close_to(&address, &network))
+            .transpose()
+            .with_context(|| format!("Invalid {}", options::LSPS1_CLOSE_TO_ADDRESS))
+    );
+
+    // Refuse to share the database with another instance.
+    // The claim is tied to our node id so we can reclaim it after a crash
+    let force_start = configured_plugin.option(&options::lsp_server_force_start())?;
+    let rpc_file = configured_plugin.configuration().rpc_file;
+    let node_id = ClnRpc::new(&rpc_file)
+        .await?
+        .call_typed(&GetinfoRequest {})
+        .await
+        .context("Failed to call 'getinfo'")?
+        .id;
+    let instance_lock = Arc::new(disable_on_err!(
+        configured_plugin,
+        InstanceLock::acquire(
+            database.clone(),
+            node_id.to_string(),
+            &SystemClock,
+            force_start
+        )
+        .await
+    ));
+
+    let max_block_lag = configured_plugin.option(&options::lsps1_max_block_lag())?;
+    let max_block_lag = disable_on_err!(
+        configured_plugin,
+        u32::try_from(max_block_lag).context("Invalid lsps1-max-block-lag")
+    );
+    let health = HealthGate::new(max_block_lag);
+
+    let usage_sampling_interval = disable_on_err!(
+        configured_plugin,
+        configured_plugin
+            .option(&options::lsps1_usage_sampling_minutes())?
+            .map(|minutes| {
+                let minutes =
+                    u64::try_from(minutes).context("Invalid lsps1-usage-sampling-minutes")?;
+                anyhow::ensure!(minutes > 0, "lsps1-usage-sampling-minutes must be positive");
+                let seconds = minutes
+                    .checked_mul(60)
+                    .context("Invalid lsps1-usage-sampling-minutes")?;
+                Ok(Duration::from_secs(seconds))
+            })
+            .transpose()
+    );
+
+    let response_retry_ttl =
+        configured_plugin.option(&options::lsps0_response_retry_ttl_minutes())?;
+    let response_retry_ttl = disable_on_err!(
+        configured_plugin,
+        u64::try_from(response_retry_ttl)
+            .ok()
+            .and_then(|minutes| minutes.checked_mul(60))
+            .map(Duration::from_secs)
+            .context("Invalid lsps0-response-retry-ttl-minutes")
+    );
+
+    let peer_queues = disable_on_err!(
+        configured_plugin,
+        configured_plugin
+            .option(&options::lsps0_per_peer_queue_depth())?
+            .map(|depth| {
+                let depth = usize::try_from(depth).context("Invalid lsps0-per-peer-queue-depth")?;
+                anyhow::ensure!(depth > 0, "lsps0-per-peer-queue-depth must be positive");
+                Ok(PeerQueues::new(depth))
+            })
+            .transpose()
+    );
+
+    let feerate_refresh = configured_plugin.option(&options::lsps1_feerate_refresh_seconds())?;
+    let feerate_refresh_interval = disable_on_err!(
+        configured_plugin,
+        u64::try_from(feerate_refresh)
+            .context("Invalid lsps1-feerate-refresh-seconds")
+            .and_then(|seconds| {
+                anyhow::ensure!(
+                    seconds > 0,
+                    "lsps1-feerate-refresh-seconds must be positive"
+                );
+                Ok(Duration::from_secs(seconds))
+            })
+    );
+
+    let payment_grace = configured_plugin.option(&options::lsps1_payment_grace_seconds())?;
+    let payment_grace = disable_on_err!(
+        configured_plugin,
+        u64::try_from(payment_grace)
+            .context("Invalid lsps1-payment-grace-seconds")
+            .map(Duration::from_secs)
+    );
+
+    let max_concurrent_opens = configured_plugin.option(&options::lsps1_max_concurrent_opens())?;
+    let max_concurrent_opens = disable_on_err!(
+        configured_plugin,
+        usize::try_from(max_concurrent_opens)
+            .context("Invalid lsps1-max-concurrent-opens")
+            .and_then(|max_concurrent_opens| {
+                anyhow::ensure!(
+                    max_concurrent_opens > 0,
+                    "lsps1-max-concurrent-opens must be positive"
+                );
+                Ok(max_concurrent_opens)
+            })
+    );
+
+    let lsps1_disabled_response: DisabledResponse = disable_on_err!(
+        configured_plugin,
+        configured_plugin
+            .option(&options::lsps1_disabled_response())?
+            .parse()
+            .context("Invalid lsps1-disabled-response")
+    );
+
+    let clock = Arc::new(SystemClock);
+    let (order_log, order_log_writer) = OrderLogger::new(clock.clone(), ORDER_LOG_CAPACITY);
+
+    let state = PluginState::new(
+        database.clone(),
+        lsps1_info,
+        clock,
+        peer_policy,
+        order_log,
+        health,
+        close_to,
+    )
+    .with_response_retry_ttl(response_retry_ttl)
+    .with_lsps1_disabled_response(lsps1_disabled_response)
+    .with_payment_grace(payment_grace)
+    .with_peer_queues(peer_queues)
+    .with_funding_sources(funding_sources);
+    let plugin = configured_plugin.start(state).await?;
+
+    // Entries of the order log are written in the background
+    tokio::spawn(order_log_writer.run(database));
+
+    // The operator can send SIGHUP to reload the peer lists and funding sources
+    let sighup_plugin = plugin.clone();
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                log::warn!("Failed to listen for SIGHUP: {:?}", err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match sighup_plugin.state().peer_policy.reload() {
+                Ok(sizes) => log::info!("Reloaded peer lists: {:?}", sizes),
+                Err(err) => log::error!(
+                    "Failed to reload peer lists. The previous lists are used: {:#}",
+                    err
+                ),
+            }
+            match sighup_plugin.state().funding_sources.reload() {
+                Ok(count) => log::info!("Reloaded {} funding sources", count),
+                Err(err) => log::error!(
+                    "Failed to reload funding sources. The previous sources are used: {:#}",
+                    err
+                ),
+            }
+        }
+    });
+
+    // Orders are priced using cached fee estimates
+    let feerate_plugin = plugin.clone();
+    spawn_supervised(
+        plugin.state().metrics.clone(),
+        "feerate_refresh",
+        move || refresh_feerates(feerate_plugin.clone(), feerate_refresh_interval),
+    );
+
+    // Orders are refused while lightningd or bitcoind is unhealthy
+    let health_plugin = plugin.clone();
+    spawn_supervised(plugin.state().metrics.clone(), "health_check", move || {
+        check_health(health_plugin.clone())
+    });
+
+    // We stop serving if we lose the claim on the database or
+    // can't refresh it for so long that another instance could take it
+    let heartbeat_lock = instance_lock.clone();
+    let heartbeat_plugin = plugin.clone();
+    tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            let err = match heartbeat_lock.heartbeat(&SystemClock).await {
+                Ok(()) => {
+                    failures = 0;
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            failures += 1;
+            if err.is::<ClaimedByOther>() || failures >= MAX_HEARTBEAT_FAILURES {
+                log::error!(
+                    "Stopping because the claim on the database is lost: {:#}",
+                    err
+                );
+                if let Err(err) = heartbeat_plugin.shutdown() {
+                    log::warn!("Failed to stop the plugin: {:?}", err);
+                }
+                return;
+            }
+            log::warn!(
+                "Failed to refresh the instance heartbeat ({}/{}): {:?}",
+                failures,
+                MAX_HEARTBEAT_FAILURES,
+                err
+            );
+        }
+    });
+
+    // Channels for paid orders are opened in the background
+    let worker_plugin = plugin.clone();
+    let open_queue = plugin.state().open_queue.clone();
+    tokio::spawn(open_queue.run(max_concurrent_opens, move |order_uuid| {
+        process_queued_order(worker_plugin.clone(), order_uuid)
+    }));
+
+    // The queue only lives in memory. Paid orders that were queued
+    // or whose client was offline are queued again. Orders whose channel
+    // was being funded are reconciled first
+    let reconcile_plugin = plugin.clone();
+    spawn_supervised(plugin.state().metrics.clone(), "reconcile", move || {
+        reconcile_at_startup(reconcile_plugin.clone())
+    });
+
+    // Leases of zero-conf channels get their funding height
+    // once the funding transaction confirms
+    let backfill_plugin = plugin.clone();
+    spawn_supervised(
+        plugin.state().metrics.clone(),
+        "funding_backfill",
+        move || backfill_funding_heights(backfill_plugin.clone()),
+    );
+
+    // Orders that expire unpaid are failed and their invoice is deleted
+    let expiry_plugin = plugin.clone();
+    spawn_supervised(plugin.state().metrics.clone(), "order_expiry", move || {
+        expire_orders(expiry_plugin.clone())
+    });
+
+    // Responses that failed to reach a peer are retried until they expire
+    let outbox_plugin = plugin.clone();
+    spawn_supervised(plugin.state().metrics.clone(), "outbox", move || {
+        retry_outbox(outbox_plugin.clone())
+    });
+
+    // The usage of leased channels is sampled if the operator opted in
+    if let Some(interval) = usage_sampling_interval {
+        let usage_plugin = plugin.clone();
+        spawn_supervised(plugin.state().metrics.clone(), "usage_sampler", move || {
+            sample_usage(usage_plugin.clone(), interval)
+        });
+    }
 
     plugin.join().await.unwrap();
 
+    if let Err(err) = instance_lock.release().await {
+        log::warn!("Failed to release the database: {:?}", err);
+    }
+
     return Ok(());
 }
 
+/// Refuses orders while lightningd or bitcoind is unhealthy
+async fn check_health(plugin: Plugin<PluginState>) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("health_check", rpc_file, state.metrics.clone());
+    loop {
+        let snapshot = take_snapshot(&mut rpc).await;
+        state.health.record(snapshot);
+        tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
+/// Keeps the cached fee estimates fresh
+async fn refresh_feerates(plugin: Plugin<PluginState>, interval: Duration) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("feerate_refresh", rpc_file, state.metrics.clone());
+    loop {
+        if let Err(err) = state.feerates.refresh(&mut rpc, state.clock.as_ref()).await {
+            log::warn!("Failed to refresh the cached feerates: {:?}", err);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Picks up the orders that were in progress when the plugin stopped
+async fn reconcile_at_startup(plugin: Plugin<PluginState>) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("reconcile", rpc_file, state.metrics.clone());
+    if let Err(err) = reconcile_funding_orders(state, &mut rpc).await {
+        log::warn!("Failed to reconcile funding orders at start-up: {:?}", err);
+    }
+    match release_stale_funding_inputs(state, &mut rpc).await {
+        Ok(0) => {}
+        Ok(count) => log::info!("Released {} stale input reservations at start-up", count),
+        Err(err) => log::warn!("Failed to release stale input reservations: {:?}", err),
+    }
+    match enqueue_pending_orders(&state.database, &state.open_queue, None).await {
+        Ok(count) => log::info!("Queued {} pending orders at start-up", count),
+        Err(err) => log::warn!("Failed to queue pending orders at start-up: {:?}", err),
+    }
+    if let Err(err) = reconcile_channel_opening_orders(&plugin).await {
+        log::warn!("Failed to check opening channels at start-up: {:?}", err);
+    }
+}
+
+async fn backfill_funding_heights(plugin: Plugin<PluginState>) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("funding_backfill", rpc_file, state.metrics.clone());
+    loop {
+        match backfill_funding_blockheights(state, &mut rpc).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Stored the funding height of {} leases", count),
+            Err(err) => log::warn!("Failed to store the funding height of leases: {:?}", err),
+        }
+        tokio::time::sleep(FUNDING_BLOCKHEIGHT_BACKFILL_INTERVAL).await;
+    }
+}
+
+async fn expire_orders(plugin: Plugin<PluginState>) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("order_expiry", rpc_file, state.metrics.clone());
+    loop {
+        match expire_unpaid_orders(state, &mut rpc).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Failed {} orders that expired unpaid", count),
+            Err(err) => log::warn!("Failed to expire unpaid orders: {:?}", err),
+        }
+        tokio::time::sleep(ORDER_EXPIRY_INTERVAL).await;
+    }
+}
+
+async fn retry_outbox(plugin: Plugin<PluginState>) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("outbox", rpc_file, state.metrics.clone());
+    loop {
+        tokio::time::sleep(OUTBOX_RETRY_INTERVAL).await;
+        match deliver_outbox(state, &mut rpc, None).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Delivered {} responses from the outbox", count),
+            Err(err) => log::warn!("Failed to deliver responses from the outbox: {:?}", err),
+        }
+    }
+}
+
+async fn sample_usage(plugin: Plugin<PluginState>, interval: Duration) {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("usage_sampler", rpc_file, state.metrics.clone());
+    loop {
+        let result = match rpc
+            .getinfo(&GetinfoRequest {})
+            .await
+            .context("Failed to call 'getinfo'")
+        {
+            Ok(info) => sample_channel_usage(state, &mut rpc, info.blockheight, interval).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(0) => {}
+            Ok(count) => log::debug!("Sampled the usage of {} channels", count),
+            Err(err) => log::warn!("Failed to sample the usage of channels: {:?}", err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
 fn do_continue() -> Result<serde_json::Value> {
     Ok(json!({"result" : "continue"}))
 }
 
 /// Handles an incoming custom message
+///
+/// The hook always continues. Other plugins, such as the LSP-client that
+/// runs on the same node, might be interested in the same message.
 async fn handle_custom_msg(
     plugin: Plugin<PluginState>,
     request: serde_json::Value,
 ) -> Result<serde_json::Value> {
-    log::debug!("LSP-server received a custom-msg {:?}", request);
-
-    // Opening the cln-rpc connection. We'll use this to send
-    // custom messages
-    let rpc_path = plugin.configuration().rpc_file;
-    let mut cln_rpc = cln_rpc::ClnRpc::new(rpc_path).await?;
+    if let Err(err) = process_custom_msg(plugin, request).await {
+        log::warn!("Failed to process custom message: {:?}", err);
+    }
+    do_continue()
+}
 
+/// Responds to an LSPS request
+///
+/// Messages that aren't LSPS requests are ignored
+async fn process_custom_msg(plugin: Plugin<PluginState>, request: serde_json::Value) -> Result<()> {
     // Parsing the customMsgHook
     // Struct of peer_id and payload
     let rpc_message = serde_json::from_value::<RpcCustomMsgMessage>(request)
         .with_context(|| "Failed to parse custom msg hook")?;
     let raw_message = rpc_message.to_raw()?;
     let peer_id = raw_message.peer_id();
+    log::debug!(
+        "LSP-server received a custom-msg of {} bytes from peer {:?}",
+        raw_message.msg().len(),
+        peer_id
+    );
 
     // Ignore the custom message if it is unrelated to LSPS
-    // We use continue because other plug-ins might still be interested
-    // in the message
-    if raw_message.bolt_8_msg_id() != LSPS_MESSAGE_ID {
-        return do_continue();
+    if !is_lsps_message(u16::from_be_bytes(raw_message.bolt_8_msg_id())) {
+        return Ok(());
     }
 
-    // BOLT-8 messages are already limited in length.
-    // By consequence, we don't need to check the length of these messages
-
-    // We'll expect that all incoming messages are JSON-RPC Requests.
-    // Here we parse the JSON and receive a `serde_json::Value`-struct
-    // This will succeed as long as the `raw_message` is valid json
-    // If it isn't we'll respond to our peer with a parse_error
-    let json_msg: Result<serde_json::Value, _> = serde_json::from_slice(raw_message.msg());
-    let json_msg = match json_msg {
-        Ok(ok) => ok,
-        Err(_) => {
-            let error = ErrorData::parse_error(format!("Invalid JSON"));
-            let rpc_response = JsonRpcResponse::<(), DefaultError>::error(JsonRpcId::None, error);
-            send_response(&mut cln_rpc, peer_id.clone(), rpc_response).await?;
-            return do_continue();
-        }
-    };
-
-    // Let's try to read the id of the JSON-rpc request
-    // If the json doesn't include an id, we'll respond to the peer and tell
-    // them they've sent an invalid message.
-    let id: Option<&serde_json::Value> = json_msg.get("id");
-    let id = match id {
-        Some(value) => serde_json::from_value::<JsonRpcId>(value.clone()).unwrap(),
-        None => {
-            let error = ErrorData::invalid_request(format!("Missing field `id`"));
-            let rpc_response = JsonRpcResponse::<(), DefaultError>::error(JsonRpcId::None, error);
-            send_response(&mut cln_rpc, peer_id.clone(), rpc_response).await?;
-            return do_continue();
-        }
-    };
-
-    // We'll parse to `JsonRpcRequest<serde_json::Value>`.
-    // Here we ensure it is a valid JsonRpcRequest. (id-field, jsonrpc="2.0")
-    // However, we don't parse the params yet
-    let json_rpc_request = serde_json::from_value::<JsonRpcRequest<serde_json::Value>>(json_msg);
-    let json_rpc_request = match json_rpc_request {
-        Ok(request) => request,
-        Err(parse_error) => {
-            let error =
-                ErrorData::invalid_request(format!("Invalid JSON-RPC request. {}", parse_error));
-            let rpc_response = JsonRpcResponse::<(), DefaultError>::error(id.clone(), error);
-            send_response(&mut cln_rpc, peer_id.clone(), rpc_response).await?;
-            return do_continue();
-        }
+    let router = Router::from_plugin(&plugin)?;
+    let peer_id = *peer_id;
+    let msg = raw_message.msg().to_vec();
+    let peer_queues = match plugin.state().peer_queues.clone() {
+        Some(peer_queues) => peer_queues,
+        None => return respond_to_request(plugin, router, peer_id, &msg).await,
     };
 
-    // Let's check if we know the method in the JSON-rpc request.
-    // We return a user to the error of the JsonRpcMethod isn't known
-    let method_str = json_rpc_request.method.clone();
-    let method = JsonRpcMethodEnum::from_method_name(&method_str);
-    let method = match method {
-        Ok(m) => m,
-        Err(_) => {
-            log::debug!(
-                "Invalid rpc-method '{}' from peer '{:?}'",
-                method_str,
-                &peer_id
-            );
-            let error = ErrorData::method_not_found(&method_str);
-            let rpc_response = JsonRpcResponse::<(), DefaultError>::error(id.clone(), error);
-            send_response(&mut cln_rpc, peer_id.clone(), rpc_response).await?;
-            return do_continue();
+    // The requests of a peer are handled in the order they arrived
+    let job_plugin = plugin.clone();
+    let job_router = router.clone();
+    let job_msg = msg.clone();
+    let job = Box::pin(async move {
+        if let Err(err) = respond_to_request(job_plugin, job_router, peer_id, &job_msg).await {
+            log::warn!("Failed to process custom message: {:?}", err);
         }
-    };
-
-    let network = parse_network(&plugin.configuration().network).unwrap();
-
-    let mut context = CustomMsgContextBuilder::new()
-        .network(network)
-        .request(json_rpc_request)
-        .plugin(plugin)
-        .peer_id(peer_id.clone())
-        .cln_rpc(cln_rpc)
-        .build()?;
-
-    type JRM = JsonRpcMethodEnum;
-    let result = match method {
-        JRM::Lsps0ListProtocols(m) => do_list_protocols(m, &mut context)
-            .await
-            .map(|x| serde_json::to_value(x).unwrap()),
-        JRM::Lsps1Info(m) => do_lsps1_get_info(m, &mut context)
-            .await
-            .map(|x| serde_json::to_value(x).unwrap()),
-        JRM::Lsps1CreateOrder(m) => do_lsps1_create_order(m, &mut context)
-            .await
-            .map(|x| serde_json::to_value(x).unwrap()),
-        JRM::Lsps1GetOrder(m) => do_lsps1_get_order(m, &mut context)
-            .await
-            .map(|x| serde_json::to_value(x).unwrap()),
-    };
+    });
+    if let Err(QueueFull) = peer_queues.submit(peer_id, job) {
+        log::debug!("Too many pending requests from peer {:?}", peer_id);
+        let messages = router.reject_busy(peer_id, &msg);
+        send_messages(plugin.state(), &plugin.configuration().rpc_file, messages).await?;
+    }
+    Ok(())
+}
 
-    match result {
-        Ok(result) => {
-            let json_rpc_response = JsonRpcResponse::<_, DefaultError>::success(id, result);
-            send_response(&mut context.cln_rpc, *peer_id, json_rpc_response).await?;
-        }
-        Err(err) => {
-            log::warn!("Error {:?}", err);
-            let error_data = ErrorData::try_from(err);
-            if error_data.is_ok() {
-                let json_rpc_response =
-                    JsonRpcResponse::<(), DefaultError>::error(id, error_data.unwrap());
-                send_response(&mut context.cln_rpc, *peer_id, json_rpc_response).await?;
-            } else {
-                log::debug!("Ignored message {:?}.{:?}", peer_id, id);
-                log::debug!("Reason {:?}", error_data);
-            }
+/// Responds to the LSPS request `msg` of `peer_id`
+async fn respond_to_request(
+    plugin: Plugin<PluginState>,
+    router: Router,
+    peer_id: PublicKey,
+    msg: &[u8],
+) -> Result<()> {
+    let rpc_file = plugin.configuration().rpc_file;
+    match router.dispatch(peer_id, msg).await {
+        Dispatched::Ready(messages) => send_messages(plugin.state(), &rpc_file, messages).await,
+        Dispatched::Pending(responses) => {
+            // We respond from a separate task to avoid holding up the hook
+            tokio::spawn(async move {
+                let messages = responses.await;
+                if let Err(err) = send_messages(plugin.state(), &rpc_file, messages).await {
+                    log::warn!("Failed to respond to lsps1.get_order: {:?}", err);
+                }
+            });
+            Ok(())
         }
-    };
+    }
+}
 
-    do_continue()
+/// Sends the responses of the router to the peers
+async fn send_messages(
+    state: &PluginState,
+    rpc_file: &str,
+    messages: Vec<OutboundMessage>,
+) -> Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let mut cln_rpc = ClnRpc::new(rpc_file).await?;
+    for message in messages {
+        send_or_enqueue_payload(state, &mut cln_rpc, message.peer_id, &message.payload).await?;
+    }
+    Ok(())
 }
 
 /// Hook method for a paid invoice
@@ -303,17 +758,99 @@ async fn handle_paid_invoice(
     }
 }
 
-async fn do_list_protocols(
-    method: methods::Lsps0ListProtocols,
-    context: &mut CustomMsgContext<PluginState>,
-) -> Result<ListprotocolsResponse, ErrorData> {
-    method.into_typed_request(context.request.clone())?;
+/// Notification handler for a peer that connects
+async fn handle_connect(plugin: Plugin<PluginState>, value: serde_json::Value) -> Result<()> {
+    let notification = match ConnectNotification::from_value(value) {
+        Ok(notification) => notification,
+        Err(err) => {
+            log::warn!("Error in parsing connect notification: {}", err);
+            return Ok(());
+        }
+    };
+
+    // Responses that failed to reach the peer can be delivered now
+    let rpc_file = plugin.configuration().rpc_file;
+    let delivered = match ClnRpc::new(&rpc_file).await {
+        Ok(mut rpc) => deliver_outbox(plugin.state(), &mut rpc, Some(notification.id)).await,
+        Err(err) => Err(err),
+    };
+    match delivered {
+        Ok(0) => {}
+        Ok(count) => log::info!(
+            "Delivered {} responses to peer {:?} after it connected",
+            count,
+            notification.id
+        ),
+        Err(err) => log::warn!("Failed to deliver responses from the outbox: {:?}", err),
+    }
+
+    if let Err(err) = lsps1_connect(plugin, &notification).await {
+        log::warn!("Error in processing connect notification");
+        log::warn!("{:?}", err);
+    }
+    Ok(())
+}
+
+/// Notification handler for a channel that changes state
+async fn handle_channel_state_changed(
+    plugin: Plugin<PluginState>,
+    value: serde_json::Value,
+) -> Result<()> {
+    let notification = match ChannelStateChangedNotification::from_value(value) {
+        Ok(notification) => notification,
+        Err(err) => {
+            log::warn!(
+                "Error in parsing channel_state_changed notification: {}",
+                err
+            );
+            return Ok(());
+        }
+    };
+
+    if let Err(err) = lsps1_channel_state_changed(plugin, &notification).await {
+        log::warn!("Error in processing channel_state_changed notification");
+        log::warn!("{:?}", err);
+    }
+    Ok(())
+}
+
+/// Notification handler for a new block
+///
+/// The fee estimates change when a block is found. A block might also
+/// reveal a reorg that unconfirmed the funding transaction of an order.
+async fn handle_block_added(plugin: Plugin<PluginState>, value: serde_json::Value) -> Result<()> {
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = match ClnRpc::new(&rpc_file).await {
+        Ok(rpc) => rpc,
+        Err(err) => {
+            log::warn!("Failed to handle block_added: {:?}", err);
+            return Ok(());
+        }
+    };
 
-    let lsps1_enabled = context.plugin.option(&options::lsps1_enable()).unwrap();
+    let refreshed =
+        refresh_on_block_added(&state.feerates, &mut rpc, state.clock.as_ref(), &value).await;
+    if let Err(err) = refreshed {
+        log::warn!(
+            "Failed to refresh the cached feerates after a block: {:?}",
+            err
+        );
+    }
 
-    let protocols = if lsps1_enabled { vec![0, 1] } else { vec![0] };
+    match check_chain_reorg(state, &mut rpc, &value).await {
+        Ok(0) => {}
+        Ok(changed) => log::info!("Updated {} orders after a reorg", changed),
+        Err(err) => log::warn!("Failed to check for a reorg: {:?}", err),
+    }
+    Ok(())
+}
 
-    Ok(ListprotocolsResponse {
-        protocols
-    })
+/// Notification handler for `shutdown`
+///
+/// Stops the plugin so the claim on the database is released
+/// before lightningd kills us
+async fn handle_shutdown(plugin: Plugin<PluginState>, _value: serde_json::Value) -> Result<()> {
+    log::info!("Received shutdown notification");
+    plugin.shutdown()
 }
@@ -0,0 +1,66 @@
+//! Counters that describe what the plugin has been doing
+//!
+//! The counters live in memory and start at zero when the plugin starts.
+//! They can be inspected using `lsps1-admin-metrics`.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use cln_plugin::Plugin;
+use serde_json::json;
+
+use crate::state::PluginState;
+use crate::tasks::TaskHealth;
+
+/// Counts the invoices seen by the `invoice_payment`-hook
+#[derive(Debug, Default)]
+pub(crate) struct PaymentMetrics {
+    /// The label doesn't belong to an LSPS1-order
+    skipped: AtomicU64,
+    /// The payment was accepted for an LSPS1-order
+    processed: AtomicU64,
+    /// The label belongs to an LSPS1-order but the order is unknown
+    /// or the amount is too low
+    mismatched: AtomicU64,
+}
+
+impl PaymentMetrics {
+    pub(crate) fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn processed(&self) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mismatched(&self) {
+        self.mismatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "skipped" : self.skipped.load(Ordering::Relaxed),
+            "processed" : self.processed.load(Ordering::Relaxed),
+            "mismatched" : self.mismatched.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) payments: PaymentMetrics,
+    pub(crate) tasks: TaskHealth,
+}
+
+pub(crate) async fn lsps1_admin_metrics(
+    plugin: Plugin<PluginState>,
+    _request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let state = plugin.state();
+    Ok(json!({
+        "payments" : state.metrics.payments.to_json(),
+        "order_log" : { "dropped" : state.order_log.dropped() },
+        "tasks" : state.metrics.tasks.to_json(),
+        "open_queue" : state.open_queue.to_json(),
+        "peer_queues" : state.peer_queues.as_ref().map(|queues| queues.to_json()),
+    }))
+}
@@ -10,6 +10,7 @@ use cln_plugin::options;
 // the options are sensible and spec-compliant.
 
 pub(crate) const LSPS1_ENABLE: &str = "lsps1-enable";
+pub(crate) const LSPS1_ENABLE_EXTENSIONS: &str = "lsps1-enable-extensions";
 pub(crate) const LSPS1_MIN_CHANNEL_CONFIRMATIONS: &str = "lsps1-min-required-channel-confirmations";
 pub(crate) const LSPS1_MIN_FUNDING_CONFIRMS_WITHIN_BLOCKS: &str =
     "lsps1-min-funding-confirms-within-blocks";
@@ -32,12 +33,43 @@ pub(crate) const LSPS1_ORDER_LIFETIME: &str = "lsps1-order-lifetime";
 pub(crate) const LSPS1_FEE_COMPUTATION_BASE_FEE_SAT: &str = "lsps1-fee-computation-base-fee-sat";
 pub(crate) const LSPS1_FEE_COMPUTATION_WEIGHT_UNITS: &str = "lsps1-fee-computation-weight-units";
 pub(crate) const LSPS1_FEE_COMPUTATION_LIQUIDITY_PPB: &str = "lsps1-fee-computation-liquidity-ppb";
+pub(crate) const LSPS1_MAX_FEE_PERCENT: &str = "lsps1-max-fee-percent";
+pub(crate) const LSPS1_STRICT_CONFIG_CHECK: &str = "lsps1-strict-config-check";
+pub(crate) const LSPS1_PEER_ALLOWLIST_FILE: &str = "lsps1-peer-allowlist-file";
+pub(crate) const LSPS1_PEER_DENYLIST_FILE: &str = "lsps1-peer-denylist-file";
+pub(crate) const LSPS1_FUNDING_SOURCES_FILE: &str = "lsps1-funding-sources-file";
+pub(crate) const LSPS1_FUNDING_SOURCE_LABEL: &str = "lsps1-funding-source-label";
+pub(crate) const LSPS1_INFO_WEBSITE: &str = "lsps1-info-website";
+pub(crate) const LSPS1_INFO_WEBSITE_LENIENT: &str = "lsps1-info-website-lenient";
+pub(crate) const LSPS1_INFO_EXTRA_JSON: &str = "lsps1-info-extra-json";
+pub(crate) const LSPS1_MAX_BLOCK_LAG: &str = "lsps1-max-block-lag";
+pub(crate) const LSPS1_HIDE_INFO_WHEN_UNHEALTHY: &str = "lsps1-hide-info-when-unhealthy";
+pub(crate) const LSPS1_CLAMP_EXPIRY: &str = "lsps1-clamp-expiry";
+pub(crate) const LSPS1_ALLOWED_CHANNEL_TYPES: &str = "lsps1-allowed-channel-types";
+pub(crate) const LSPS1_ACCEPTED_REFUND_ADDRESS_TYPES: &str = "lsps1-accepted-refund-address-types";
+pub(crate) const LSPS1_CLOSE_TO_ADDRESS: &str = "lsps1-close-to-address";
+pub(crate) const LSPS1_USAGE_SAMPLING_MINUTES: &str = "lsps1-usage-sampling-minutes";
+pub(crate) const LSPS0_RESPONSE_RETRY_TTL_MINUTES: &str = "lsps0-response-retry-ttl-minutes";
+pub(crate) const LSPS0_PER_PEER_QUEUE_DEPTH: &str = "lsps0-per-peer-queue-depth";
+pub(crate) const LSPS1_FEERATE_REFRESH_SECONDS: &str = "lsps1-feerate-refresh-seconds";
+pub(crate) const LSPS1_PAYMENT_GRACE_SECONDS: &str = "lsps1-payment-grace-seconds";
+pub(crate) const LSPS1_MAX_CONCURRENT_OPENS: &str = "lsps1-max-concurrent-opens";
+pub(crate) const LSPS1_ONCHAIN_CONFIRMATION_TIERS: &str = "lsps1-onchain-confirmation-tiers";
+pub(crate) const LSPS1_DISABLED_RESPONSE: &str = "lsps1-disabled-response";
 pub(crate) const LSP_SERVER_DATABASE_URL: &str = "lsp-server-database-url";
+pub(crate) const LSP_SERVER_FORCE_START: &str = "lsp-server-force-start";
 
 pub fn lsps1_enable() -> options::FlagConfigOption<'static> {
     options::FlagConfigOption::new_flag(LSPS1_ENABLE, "If set LSPS1 is enabled")
 }
 
+pub fn lsps1_enable_extensions() -> options::FlagConfigOption<'static> {
+    options::FlagConfigOption::new_flag(
+        LSPS1_ENABLE_EXTENSIONS,
+        "If set the server includes extensions such as refund details in LSPS1 responses",
+    )
+}
+
 pub fn lsps1_min_required_channel_confirmations() -> options::DefaultIntegerConfigOption<'static> {
     options::DefaultIntegerConfigOption::new_i64_with_default(
         LSPS1_MIN_CHANNEL_CONFIRMATIONS,
@@ -53,6 +85,21 @@ pub fn lsps1_min_onchain_payment_confirmations() -> options::IntegerConfigOption
     )
 }
 
+pub fn lsps1_onchain_confirmation_tiers() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_ONCHAIN_CONFIRMATION_TIERS,
+        "Confirmations required for an on-chain payment by order_total_sat, e.g. '0:0,1000000:1,5000000:3'. Replaces lsps1-min-onchain-payment-confirmations",
+    )
+}
+
+pub fn lsps1_disabled_response() -> options::DefaultStringConfigOption<'static> {
+    options::DefaultStringConfigOption::new_str_with_default(
+        LSPS1_DISABLED_RESPONSE,
+        "not_found",
+        "Response to LSPS1 requests while lsps1-enable isn't set. Either 'not_found' or 'unavailable' which asks clients to retry later. (Default is not_found)",
+    )
+}
+
 pub fn lsps1_supports_zero_channel_reserve() -> options::DefaultBooleanConfigOption<'static> {
     options::DefaultBooleanConfigOption::new_bool_with_default(
         LSPS1_SUPPORTS_ZERO_CHANNEL_RESERVE,
@@ -69,8 +116,8 @@ pub fn lsps1_max_channel_expiry_blocks() -> options::DefaultIntegerConfigOption<
     )
 }
 
-pub fn lsps1_min_onchain_payment_size_sat() -> options::IntegerConfigOption<'static> {
-    options::IntegerConfigOption::new_i64_no_default(
+pub fn lsps1_min_onchain_payment_size_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MIN_ONCHAIN_PAYMENT_SIZE_SAT,
         "Indicates the minimum amount of satoshi (order_total_sat) that is required for the LSP to accept a payment on-chain"
     )
@@ -100,6 +147,21 @@ pub fn lsps1_fee_computation_liquidity_ppb() -> options::DefaultIntegerConfigOpt
     )
 }
 
+pub fn lsps1_max_fee_percent() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS1_MAX_FEE_PERCENT,
+        10,
+        "A warning is logged at startup if the fee of an advertised channel exceeds this percentage of its capacity. (Default is 10 percent)",
+    )
+}
+
+pub fn lsps1_strict_config_check() -> options::FlagConfigOption<'static> {
+    options::FlagConfigOption::new_flag(
+        LSPS1_STRICT_CONFIG_CHECK,
+        "If set the server refuses to start if the fee options don't fit the advertised options",
+    )
+}
+
 pub fn lsps1_order_lifetime_seconds() -> options::DefaultIntegerConfigOption<'static> {
     options::ConfigOption::new_i64_with_default(
         LSPS1_ORDER_LIFETIME,
@@ -108,49 +170,195 @@ pub fn lsps1_order_lifetime_seconds() -> options::DefaultIntegerConfigOption<'st
     )
 }
 
+pub fn lsps1_peer_allowlist_file() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_PEER_ALLOWLIST_FILE,
+        "File with one public key per line. If set only these peers can use LSPS1",
+    )
+}
+
+pub fn lsps1_funding_sources_file() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_FUNDING_SOURCES_FILE,
+        "JSON-file that maps a funding source label to the wallet outputs, budget and tokens of that source",
+    )
+}
+
+pub fn lsps1_funding_source_label() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_FUNDING_SOURCE_LABEL,
+        "The funding source of orders that don't pick one. If unset these orders are funded by any output of the wallet",
+    )
+}
+
+pub fn lsps1_info_website() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_INFO_WEBSITE,
+        "Website returned by lsps1.get_info. Either an url or a mapping such as 'bitcoin:<url>,signet:<url>'",
+    )
+}
+
+pub fn lsps1_info_website_lenient() -> options::DefaultBooleanConfigOption<'static> {
+    options::DefaultBooleanConfigOption::new_bool_with_default(
+        LSPS1_INFO_WEBSITE_LENIENT,
+        false,
+        "Drop an invalid lsps1-info-website with a warning instead of refusing to start. (Default is false)",
+    )
+}
+
+pub fn lsps1_info_extra_json() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_INFO_EXTRA_JSON,
+        "JSON-object whose fields are added to lsps1.get_info if lsps1-enable-extensions is set",
+    )
+}
+
+pub fn lsps1_peer_denylist_file() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_PEER_DENYLIST_FILE,
+        "File with one public key per line. These peers cannot use LSPS1",
+    )
+}
+
+pub fn lsps1_max_block_lag() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS1_MAX_BLOCK_LAG,
+        3,
+        "Orders are refused while lightningd is more blocks behind bitcoind",
+    )
+}
+
+pub fn lsps1_hide_info_when_unhealthy() -> options::FlagConfigOption<'static> {
+    options::FlagConfigOption::new_flag(
+        LSPS1_HIDE_INFO_WHEN_UNHEALTHY,
+        "If set lsps1.get_info returns an error while orders are refused because the node is unhealthy",
+    )
+}
+
+pub fn lsps1_clamp_expiry() -> options::FlagConfigOption<'static> {
+    options::FlagConfigOption::new_flag(
+        LSPS1_CLAMP_EXPIRY,
+        "If set orders that exceed lsps1-max-channel-expiry-blocks are lowered to the maximum instead of refused",
+    )
+}
+
+pub fn lsps1_allowed_channel_types() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_ALLOWED_CHANNEL_TYPES,
+        "The channel type features a client may request, e.g. 'anchors,scid_alias,zeroconf'. Requires lsps1-enable-extensions",
+    )
+}
+
+pub fn lsps1_accepted_refund_address_types() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_ACCEPTED_REFUND_ADDRESS_TYPES,
+        "Comma-separated list of the refund_onchain_address types clients can use. E.g: p2wpkh,p2tr. (Default is p2pkh,p2sh,p2wpkh,p2wsh,p2tr)",
+    )
+}
+
+pub fn lsps1_close_to_address() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
+        LSPS1_CLOSE_TO_ADDRESS,
+        "If set the funds of the LSP are sent to this address when a leased channel is closed",
+    )
+}
+
+pub fn lsps1_usage_sampling_minutes() -> options::IntegerConfigOption<'static> {
+    options::IntegerConfigOption::new_i64_no_default(
+        LSPS1_USAGE_SAMPLING_MINUTES,
+        "If set the usage of leased channels is sampled every this many minutes. Disabled by default",
+    )
+}
+
+pub fn lsps0_response_retry_ttl_minutes() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS0_RESPONSE_RETRY_TTL_MINUTES,
+        60,
+        "Responses that fail to reach a peer are retried for this many minutes. (Default is 60 minutes)",
+    )
+}
+
+pub fn lsps0_per_peer_queue_depth() -> options::IntegerConfigOption<'static> {
+    options::IntegerConfigOption::new_i64_no_default(
+        LSPS0_PER_PEER_QUEUE_DEPTH,
+        "If set the requests of a peer are handled one at a time and in order. At most this many requests of a peer can wait. Disabled by default",
+    )
+}
+
+pub fn lsps1_feerate_refresh_seconds() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS1_FEERATE_REFRESH_SECONDS,
+        crate::feerate_cache::DEFAULT_FEERATE_REFRESH_SECONDS,
+        "The cached fee estimates are refreshed every this many seconds. (Default is 30 seconds)",
+    )
+}
+
+pub fn lsps1_payment_grace_seconds() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS1_PAYMENT_GRACE_SECONDS,
+        120,
+        "Payments that arrive this many seconds after an order expired are still accepted. (Default is 120 seconds)",
+    )
+}
+
+pub fn lsps1_max_concurrent_opens() -> options::DefaultIntegerConfigOption<'static> {
+    options::DefaultIntegerConfigOption::new_i64_with_default(
+        LSPS1_MAX_CONCURRENT_OPENS,
+        crate::lsps1::open_queue::DEFAULT_MAX_CONCURRENT_OPENS,
+        "The maximum number of channels that are opened at the same time. Orders are opened in the order they were paid. (Default is 2)",
+    )
+}
+
 pub fn lsp_server_database_url() -> options::StringConfigOption<'static> {
     options::StringConfigOption::new_str_no_default(
         LSP_SERVER_DATABASE_URL,
-        "The fully qualfied patth to the database. E.g: sqlite://home/user/data/lsp_server_database.db")
+        "The path to the database. E.g: sqlite:///home/user/data/lsp_server.db. Relative paths are relative to the lightning-dir. Add ?create-dirs to create missing directories. (Default is lsp_server.db)")
 }
 
-pub fn lsps1_min_initial_client_balance_sat() -> options::IntegerConfigOption<'static> {
-    options::ConfigOption::new_i64_no_default(
+pub fn lsp_server_force_start() -> options::FlagConfigOption<'static> {
+    options::FlagConfigOption::new_flag(
+        LSP_SERVER_FORCE_START,
+        "Start even if another instance of the server appears to use the same database",
+    )
+}
+
+pub fn lsps1_min_initial_client_balance_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MIN_INITIAL_CLIENT_BALANCE_SAT,
         "Minimum number of satoshis the client can request",
     )
 }
 
-pub fn lsps1_max_initial_client_balance_sat() -> options::IntegerConfigOption<'static> {
-    options::ConfigOption::new_i64_no_default(
+pub fn lsps1_max_initial_client_balance_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MAX_INITIAL_CLIENT_BALANCE_SAT,
         "Maximum number of satoshis the client can request",
     )
 }
 
-pub fn lsps1_min_initial_lsp_balance_sat() -> options::IntegerConfigOption<'static> {
-    options::IntegerConfigOption::new_i64_no_default(
+pub fn lsps1_min_initial_lsp_balance_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MIN_INITIAL_LSP_BALANCE_SAT,
         "Minimum numbers of satoshis the server will provide",
     )
 }
 
-pub fn lsps1_max_initial_lsp_balance_sat() -> options::IntegerConfigOption<'static> {
-    options::IntegerConfigOption::new_i64_no_default(
+pub fn lsps1_max_initial_lsp_balance_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MAX_INITIAL_LSP_BALANCE_SAT,
         "Minimum numbers of satoshis the server will provide",
     )
 }
 
-pub fn lsps1_min_channel_balance_sat() -> options::IntegerConfigOption<'static> {
-    options::IntegerConfigOption::new_i64_no_default(
+pub fn lsps1_min_channel_balance_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MIN_CHANNEL_BALANCE_SAT,
         "Minimum numbers of satoshis the server will provide",
     )
 }
 
-pub fn lsps1_max_channel_balance_sat() -> options::IntegerConfigOption<'static> {
-    options::IntegerConfigOption::new_i64_no_default(
+pub fn lsps1_max_channel_balance_sat() -> options::StringConfigOption<'static> {
+    options::StringConfigOption::new_str_no_default(
         LSPS1_MAX_CHANNEL_BALANCE_SAT,
         "Minimum numbers of satoshis the server will provide",
     )
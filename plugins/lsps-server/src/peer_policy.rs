@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::{Context, Result};
+
+use lsp_primitives::lsps0::common_schemas::PublicKey;
+
+/// Decides which peers can use LSPS1
+///
+/// The operator can configure an allowlist and a denylist.
+/// If an allowlist is configured only the listed peers can use LSPS1.
+/// Peers on the denylist can never use LSPS1.
+///
+/// The files are read at start-up and by [`PeerPolicy::reload`]. Checking
+/// a peer doesn't touch the file system. If a file can't be read during a
+/// reload the previous lists are kept.
+#[derive(Default)]
+pub(crate) struct PeerPolicy {
+    allowlist_path: Option<PathBuf>,
+    denylist_path: Option<PathBuf>,
+    lists: RwLock<PeerLists>,
+}
+
+#[derive(Default)]
+struct PeerLists {
+    allowlist: Option<HashSet<PublicKey>>,
+    denylist: Option<HashSet<PublicKey>>,
+}
+
+/// The number of peers in each list after a reload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeerListSizes {
+    pub(crate) allowlist: Option<usize>,
+    pub(crate) denylist: Option<usize>,
+}
+
+impl PeerPolicy {
+    /// Reads the configured files
+    ///
+    /// Fails if one of the files can't be read
+    pub(crate) fn load(allowlist: Option<PathBuf>, denylist: Option<PathBuf>) -> Result<Self> {
+        let policy = Self {
+            allowlist_path: allowlist,
+            denylist_path: denylist,
+            lists: RwLock::new(PeerLists::default()),
+        };
+        let sizes = policy.reload()?;
+        log::info!("Loaded peer lists: {:?}", sizes);
+        Ok(policy)
+    }
+
+    /// Returns true if `peer_id` is allowed to use LSPS1
+    pub(crate) fn is_allowed(&self, peer_id: &PublicKey) -> bool {
+        let lists = self.lists.read().unwrap();
+        if let Some(denylist) = &lists.denylist {
+            if denylist.contains(peer_id) {
+                return false;
+            }
+        }
+
+        match &lists.allowlist {
+            Some(allowlist) => allowlist.contains(peer_id),
+            None => true,
+        }
+    }
+
+    /// Re-reads both files
+    ///
+    /// The lists are only replaced if both files could be read
+    pub(crate) fn reload(&self) -> Result<PeerListSizes> {
+        let allowlist = self
+            .allowlist_path
+            .as_deref()
+            .map(read_peer_list)
+            .transpose()?;
+        let denylist = self
+            .denylist_path
+            .as_deref()
+            .map(read_peer_list)
+            .transpose()?;
+
+        let sizes = PeerListSizes {
+            allowlist: allowlist.as_ref().map(|peers| peers.len()),
+            denylist: denylist.as_ref().map(|peers| peers.len()),
+        };
+        *self.lists.write().unwrap() = PeerLists {
+            allowlist,
+            denylist,
+        };
+        Ok(sizes)
+    }
+}
+
+fn read_peer_list(path: &Path) -> Result<HashSet<PublicKey>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_peer_list(&content).with_context(|| format!("Invalid peer list {}", path.display()))
+}
+
+/// Parses a list of newline-separated public keys
+///
+/// Empty lines and lines starting with `#` are ignored
+fn parse_peer_list(content: &str) -> Result<HashSet<PublicKey>> {
+    let mut peers = HashSet::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let peer_id = PublicKey::from_hex(line)
+            .with_context(|| format!("Invalid public key on line {}", index + 1))?;
+        peers.insert(peer_id);
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PEER_A: &str = "026d58c2b93d278acef549167e34cf6c541fc2332b1e36e7fe57e54576cd5fa170";
+    const PEER_B: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    fn peer(hex: &str) -> PublicKey {
+        PublicKey::from_hex(hex).unwrap()
+    }
+
+    /// Writes `content` to a new file in the temp directory
+    fn write_temp_file(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("peer-list-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_peer_list_ignores_comments() {
+        let content = format!("# Wallet users\n\n{}\n  {}  \n", PEER_A, PEER_B);
+        let peers = parse_peer_list(&content).unwrap();
+
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&peer(PEER_A)));
+        assert!(peers.contains(&peer(PEER_B)));
+    }
+
+    #[test]
+    fn parse_peer_list_reports_invalid_line() {
+        let content = format!("{}\nnot-a-key\n", PEER_A);
+        let err = parse_peer_list(&content).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn everyone_is_allowed_without_lists() {
+        let policy = PeerPolicy::default();
+        assert!(policy.is_allowed(&peer(PEER_A)));
+    }
+
+    #[test]
+    fn allowlist_and_denylist() {
+        let allowlist = write_temp_file(&format!("{}\n{}\n", PEER_A, PEER_B));
+        let denylist = write_temp_file(&format!("{}\n", PEER_B));
+
+        let policy = PeerPolicy::load(Some(allowlist.clone()), None).unwrap();
+        assert!(policy.is_allowed(&peer(PEER_A)));
+        assert!(policy.is_allowed(&peer(PEER_B)));
+
+        // The denylist takes precedence
+        let policy = PeerPolicy::load(Some(allowlist.clone()), Some(denylist.clone())).unwrap();
+        assert!(policy.is_allowed(&peer(PEER_A)));
+        assert!(!policy.is_allowed(&peer(PEER_B)));
+
+        let policy = PeerPolicy::load(None, Some(denylist.clone())).unwrap();
+        assert!(policy.is_allowed(&peer(PEER_A)));
+        assert!(!policy.is_allowed(&peer(PEER_B)));
+
+        std::fs::remove_file(allowlist).unwrap();
+        std::fs::remove_file(denylist).unwrap();
+    }
+
+    #[test]
+    fn missing_list_fails_to_load() {
+        let path = std::env::temp_dir().join(format!("missing-{}", uuid::Uuid::new_v4()));
+        let err = PeerPolicy::load(None, Some(path.clone())).err().unwrap();
+        assert!(format!("{:#}", err).contains(&path.display().to_string()));
+    }
+
+    #[test]
+    fn list_is_only_read_on_reload() {
+        let path = write_temp_file(&format!("{}\n", PEER_A));
+        let policy = PeerPolicy::load(Some(path.clone()), None).unwrap();
+        assert!(policy.is_allowed(&peer(PEER_A)));
+        assert!(!policy.is_allowed(&peer(PEER_B)));
+
+        std::fs::write(&path, format!("# Replaced A by B\n{}\n", PEER_B)).unwrap();
+        assert!(policy.is_allowed(&peer(PEER_A)));
+
+        let sizes = policy.reload().unwrap();
+        assert_eq!(
+            sizes,
+            PeerListSizes {
+                allowlist: Some(1),
+                denylist: None
+            }
+        );
+        assert!(!policy.is_allowed(&peer(PEER_A)));
+        assert!(policy.is_allowed(&peer(PEER_B)));
+
+        // A failed reload keeps the previous list
+        std::fs::remove_file(&path).unwrap();
+        assert!(policy.reload().is_err());
+        assert!(policy.is_allowed(&peer(PEER_B)));
+    }
+}
@@ -0,0 +1,211 @@
+/// This is the schema of all RPC-commands created
+/// by the LSP-server plugin.
+///
+/// These commands are used by the operator of the LSP-server.
+/// They are not exposed to clients.
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lsps1AdminFulfillOrderRequest {
+    pub order_id: String,
+    pub funding_txid: String,
+    pub outnum: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lsps1AdminRetryOpenRequest {
+    pub order_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lsps1AdminOrderLogRequest {
+    pub order_id: String,
+    #[serde(default)]
+    pub epoch_only: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lsps1AdminChannelUsageRequest {
+    pub order_id: String,
+    #[serde(default)]
+    pub epoch_only: Option<bool>,
+}
+
+/// Render timestamps as unix timestamps only if `epoch_only` is set
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Lsps1AdminLeaseReportRequest {
+    pub epoch_only: Option<bool>,
+}
+
+/// Reports all tokens if `token` is omitted
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Lsps1AdminTokenReportRequest {
+    pub token: Option<String>,
+    pub epoch_only: Option<bool>,
+}
+
+/// Render timestamps as unix timestamps only if `epoch_only` is set
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Lsps1StatusRequest {
+    pub epoch_only: Option<bool>,
+}
+
+/// The names of the checks to skip. E.g: `["invoice", "listfunds"]`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Lsps1SelfcheckRequest {
+    pub skip: Option<Vec<String>>,
+}
+
+/// An order for which `lsps1-admin-simulate-fees` computes the fee
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimulatedOrder {
+    pub lsp_balance_sat: u64,
+    pub client_balance_sat: u64,
+    pub channel_expiry_blocks: u32,
+    pub funding_confirms_within_blocks: u16,
+}
+
+/// Fee parameters to compare against the configured ones
+///
+/// A parameter that is omitted uses the configured value
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FeeParameters {
+    pub base_fee_sat: Option<u64>,
+    pub weight_units: Option<u64>,
+    pub liquidity_ppb: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lsps1AdminSimulateFeesRequest {
+    pub orders: Option<Vec<SimulatedOrder>>,
+    pub replay_last: Option<u32>,
+    pub candidate: Option<FeeParameters>,
+    pub onchain_feerate_perkw: Option<u32>,
+}
+
+type RpcMethodBuilder = cln_plugin::RpcMethodBuilder<crate::state::PluginState>;
+
+pub fn lsps1_admin_fulfill_order() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-fulfill-order",
+        crate::lsps1::admin::lsps1_admin_fulfill_order,
+    )
+    .description("Mark a paid order as fulfilled by a channel that was opened manually")
+    .usage("order_id funding_txid outnum")
+}
+
+pub fn lsps1_admin_lease_report() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-lease-report",
+        crate::lsps1::lease_report::lsps1_admin_lease_report,
+    )
+    .description("List the leases of completed orders that haven't expired yet")
+    .usage("[epoch_only]")
+}
+
+pub fn lsps1_admin_token_report() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-token-report",
+        crate::lsps1::token_report::lsps1_admin_token_report,
+    )
+    .description("Summarize the orders that were created with a token")
+    .usage("[token] [epoch_only]")
+}
+
+pub fn lsps1_admin_channel_usage() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-channel-usage",
+        crate::lsps1::channel_usage::lsps1_admin_channel_usage,
+    )
+    .description("Show the sampled usage of the channel of an order")
+    .usage("order_id [epoch_only]")
+}
+
+pub fn lsps1_admin_order_log() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-order-log",
+        crate::lsps1::order_log::lsps1_admin_order_log,
+    )
+    .description("Show the audit log of an order")
+    .usage("order_id [epoch_only]")
+}
+
+pub fn lsps1_admin_metrics() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-admin-metrics", crate::metrics::lsps1_admin_metrics)
+        .description("Show counters of the payments and the order log since the plugin started")
+}
+
+pub fn lsps1_db_schema_version() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-db-schema-version",
+        crate::db::sqlite::views::lsps1_db_schema_version,
+    )
+    .description("List the read-only database views of LSPS1-orders and their columns")
+}
+
+pub fn lsps1_admin_quarantine_list() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-quarantine-list",
+        crate::db::sqlite::quarantine::lsps1_admin_quarantine_list,
+    )
+    .description("Scan the orders and list the rows of the database that can't be read")
+}
+
+pub fn lsps1_selfcheck() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-selfcheck", crate::selfcheck::lsps1_selfcheck)
+        .description(
+            "Verify the database, options, feature bits, invoices and wallet of the LSP-server",
+        )
+        .usage("[skip]")
+}
+
+pub fn lsps1_status() -> RpcMethodBuilder {
+    RpcMethodBuilder::new("lsps1-status", crate::lsps1::status::lsps1_status)
+        .description(
+            "Summarize the options, recent orders, leases, open queue and health of the LSP-server",
+        )
+        .usage("[epoch_only]")
+}
+
+pub fn lsps1_admin_reload_peer_lists() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-reload-peer-lists",
+        crate::lsps1::admin::lsps1_admin_reload_peer_lists,
+    )
+    .description(
+        "Reload the files configured in lsps1-peer-allowlist-file and lsps1-peer-denylist-file",
+    )
+}
+
+pub fn lsps1_reload_config() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-reload-config",
+        crate::lsps1::config_reload::lsps1_reload_config,
+    )
+    .description(
+        "Read the LSPS1 options from lightningd and use them if they are valid. Lists the fields of lsps1.get_info that changed",
+    )
+}
+
+pub fn lsps1_admin_simulate_fees() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-simulate-fees",
+        crate::lsps1::fee_simulation::lsps1_admin_simulate_fees,
+    )
+    .description(
+        "Compute the fees of hypothetical or recent orders using the configured and candidate fee parameters",
+    )
+    .usage("[orders] [replay_last] [candidate] [onchain_feerate_perkw]")
+}
+
+pub fn lsps1_admin_retry_open() -> RpcMethodBuilder {
+    RpcMethodBuilder::new(
+        "lsps1-admin-retry-open",
+        crate::lsps1::admin::lsps1_admin_retry_open,
+    )
+    .description("Queue a pending or failed order to open its channel again")
+    .usage("order_id")
+}
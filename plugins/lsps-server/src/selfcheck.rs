@@ -0,0 +1,470 @@
+//! Verifies that the server is fully functional
+//!
+//! Deployments and CI call `lsps1-selfcheck` once the plugin has started.
+//! It checks every dependency an order relies on and reports the outcome
+//! and duration of every check. The top-level `ok` is false if any check
+//! failed. A check can be skipped in environments where it can't pass.
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use cln_plugin::Plugin;
+use cln_rpc::model::requests::{
+    DelinvoiceRequest, DelinvoiceStatus, GetinfoRequest, InvoiceRequest, ListfundsRequest,
+};
+use cln_rpc::primitives::{Amount, AmountOrAny};
+use lsp_primitives::lsps0::bounded_range::BoundedRange;
+use lsp_primitives::lsps0::features::{has_feature_bit, LSPS_FEATURE_BIT};
+use lsp_primitives::lsps1::schema::Lsps1Options;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::cln::reconnecting_rpc::ReconnectingRpc;
+use crate::cln::rpc_api::ClnRpcApi;
+use crate::db::sqlite::latest_migration_version;
+use crate::db::sqlite::queries::GetMigrationVersionQuery;
+use crate::plugin_rpc::Lsps1SelfcheckRequest;
+use crate::state::PluginState;
+
+/// Labels of test invoices start with this prefix
+///
+/// The prefix is reserved. It never matches the label of an order.
+pub(crate) const SELFCHECK_INVOICE_LABEL_PREFIX: &str = "lsps1-selfcheck-";
+
+/// A part of the server that is verified by `lsps1-selfcheck`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelfCheck {
+    /// The database is reachable and fully migrated
+    Database,
+    /// The active LSPS1-options are consistent
+    Options,
+    /// The LSPS feature bit is announced
+    FeatureBits,
+    /// lightningd can create and delete invoices
+    Invoice,
+    /// The funds of the wallet can be read
+    Listfunds,
+}
+
+impl SelfCheck {
+    pub(crate) const ALL: [SelfCheck; 5] = [
+        SelfCheck::Database,
+        SelfCheck::Options,
+        SelfCheck::FeatureBits,
+        SelfCheck::Invoice,
+        SelfCheck::Listfunds,
+    ];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            SelfCheck::Database => "database",
+            SelfCheck::Options => "options",
+            SelfCheck::FeatureBits => "featurebits",
+            SelfCheck::Invoice => "invoice",
+            SelfCheck::Listfunds => "listfunds",
+        }
+    }
+}
+
+impl FromStr for SelfCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        SelfCheck::ALL
+            .into_iter()
+            .find(|check| check.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = SelfCheck::ALL.iter().map(|check| check.name()).collect();
+                anyhow!(
+                    "Unknown check '{}'. Expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The outcome of a single check
+#[derive(Debug)]
+pub(crate) enum CheckOutcome {
+    Passed { duration: Duration },
+    Failed { duration: Duration, error: String },
+    Skipped,
+}
+
+impl CheckOutcome {
+    fn is_failed(&self) -> bool {
+        matches!(self, CheckOutcome::Failed { .. })
+    }
+
+    fn to_json(&self, check: SelfCheck) -> serde_json::Value {
+        match self {
+            CheckOutcome::Passed { duration } => json!({
+                "name" : check.name(),
+                "status" : "passed",
+                "duration_ms" : duration.as_millis() as u64,
+            }),
+            CheckOutcome::Failed { duration, error } => json!({
+                "name" : check.name(),
+                "status" : "failed",
+                "duration_ms" : duration.as_millis() as u64,
+                "error" : error,
+            }),
+            CheckOutcome::Skipped => json!({
+                "name" : check.name(),
+                "status" : "skipped",
+            }),
+        }
+    }
+}
+
+/// The outcomes of all checks in the order they ran
+#[derive(Debug)]
+pub(crate) struct SelfCheckReport {
+    pub(crate) outcomes: Vec<(SelfCheck, CheckOutcome)>,
+}
+
+impl SelfCheckReport {
+    /// True if no check failed
+    pub(crate) fn is_ok(&self) -> bool {
+        !self.outcomes.iter().any(|(_, outcome)| outcome.is_failed())
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let checks: Vec<serde_json::Value> = self
+            .outcomes
+            .iter()
+            .map(|(check, outcome)| outcome.to_json(*check))
+            .collect();
+        json!({
+            "ok" : self.is_ok(),
+            "checks" : checks,
+        })
+    }
+}
+
+/// Handles `lsps1-selfcheck`
+pub(crate) async fn lsps1_selfcheck(
+    plugin: Plugin<PluginState>,
+    request: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let request: Lsps1SelfcheckRequest = serde_json::from_value(request)?;
+    let skip = request
+        .skip
+        .unwrap_or_default()
+        .iter()
+        .map(|name| name.parse())
+        .collect::<Result<Vec<SelfCheck>>>()?;
+
+    let state = plugin.state();
+    let rpc_file = plugin.configuration().rpc_file;
+    let mut rpc = ReconnectingRpc::new("selfcheck", rpc_file, state.metrics.clone());
+    let report = run_selfcheck(state, &mut rpc, &skip).await;
+    if !report.is_ok() {
+        log::warn!("Self-check failed: {}", report.to_json());
+    }
+    Ok(report.to_json())
+}
+
+/// Runs every check that isn't in `skip`
+///
+/// A failed check doesn't stop the checks that follow it
+pub(crate) async fn run_selfcheck(
+    state: &PluginState,
+    rpc: &mut dyn ClnRpcApi,
+    skip: &[SelfCheck],
+) -> SelfCheckReport {
+    let mut outcomes = Vec::with_capacity(SelfCheck::ALL.len());
+    for check in SelfCheck::ALL {
+        if skip.contains(&check) {
+            outcomes.push((check, CheckOutcome::Skipped));
+            continue;
+        }
+
+        let started = state.clock.instant();
+        let result = match check {
+            SelfCheck::Database => check_database(state).await,
+            SelfCheck::Options => check_options(state),
+            SelfCheck::FeatureBits => check_feature_bits(rpc).await,
+            SelfCheck::Invoice => check_invoice(rpc).await,
+            SelfCheck::Listfunds => check_listfunds(rpc).await,
+        };
+        let duration = state.clock.instant().saturating_duration_since(started);
+
+        let outcome = match result {
+            Ok(()) => CheckOutcome::Passed { duration },
+            Err(err) => CheckOutcome::Failed {
+                duration,
+                error: format!("{:#}", err),
+            },
+        };
+        outcomes.push((check, outcome));
+    }
+    SelfCheckReport { outcomes }
+}
+
+async fn check_database(state: &PluginState) -> Result<()> {
+    let mut tx = state.database.begin().await?;
+    let version = GetMigrationVersionQuery.execute(&mut tx).await?;
+    tx.commit().await?;
+
+    let expected = latest_migration_version();
+    if version != expected {
+        bail!(
+            "Database is at migration {:?} but the plugin expects {:?}",
+            version,
+            expected
+        );
+    }
+    Ok(())
+}
+
+fn check_options(state: &PluginState) -> Result<()> {
+    let info = state
+        .lsps1_info()
+        .context("LSPS1 is disabled. No options are active")?;
+    validate_options(&info.options)
+}
+
+/// Checks that a client could order a channel using `options`
+pub(crate) fn validate_options(options: &Lsps1Options) -> Result<()> {
+    BoundedRange::new(
+        "channel_balance_sat",
+        options.min_channel_balance_sat,
+        options.max_channel_balance_sat,
+    )?;
+    BoundedRange::new(
+        "initial_client_balance_sat",
+        options.min_initial_client_balance_sat,
+        options.max_initial_client_balance_sat,
+    )?;
+    BoundedRange::new(
+        "initial_lsp_balance_sat",
+        options.min_initial_lsp_balance_sat,
+        options.max_initial_lsp_balance_sat,
+    )?;
+
+    let min_channel = options.min_initial_lsp_balance_sat.sat_value()
+        + options.min_initial_client_balance_sat.sat_value();
+    if min_channel > options.max_channel_balance_sat.sat_value() {
+        bail!(
+            "The minimal initial balances ({} sat) exceed max_channel_balance_sat ({})",
+            min_channel,
+            options.max_channel_balance_sat
+        );
+    }
+    if options.max_channel_expiry_blocks == 0 {
+        bail!("max_channel_expiry_blocks should be positive");
+    }
+    Ok(())
+}
+
+async fn check_feature_bits(rpc: &mut dyn ClnRpcApi) -> Result<()> {
+    let getinfo = rpc
+        .getinfo(&GetinfoRequest {})
+        .await
+        .context("Failed to call 'getinfo'")?;
+    let features = getinfo
+        .our_features
+        .context("lightningd didn't report its features")?;
+    ensure_lsps_feature_bit("node", &features.node)?;
+    ensure_lsps_feature_bit("init", &features.init)
+}
+
+/// Fails if the LSPS feature bit isn't set in the hex-encoded `features`
+pub(crate) fn ensure_lsps_feature_bit(kind: &str, features: &str) -> Result<()> {
+    if !has_feature_bit(features, LSPS_FEATURE_BIT)? {
+        bail!(
+            "Feature bit {} is not set in the {} features",
+            LSPS_FEATURE_BIT,
+            kind
+        );
+    }
+    Ok(())
+}
+
+/// Creates a test invoice of 1 msat and deletes it immediately
+async fn check_invoice(rpc: &mut dyn ClnRpcApi) -> Result<()> {
+    let label = format!("{}{}", SELFCHECK_INVOICE_LABEL_PREFIX, Uuid::new_v4());
+    let request = InvoiceRequest {
+        amount_msat: AmountOrAny::Amount(Amount::from_msat(1)),
+        label: label.clone(),
+        description: "LSPS1 self-check".to_string(),
+        expiry: Some(60),
+        cltv: None,
+        deschashonly: None,
+        fallbacks: Some(vec![]),
+        preimage: None,
+    };
+    rpc.invoice(&request)
+        .await
+        .context("Failed to call 'invoice'")?;
+
+    let request = DelinvoiceRequest {
+        label: label.clone(),
+        status: DelinvoiceStatus::UNPAID,
+        desconly: None,
+    };
+    rpc.delinvoice(&request)
+        .await
+        .with_context(|| format!("Failed to delete the test invoice '{}'", label))?;
+    Ok(())
+}
+
+async fn check_listfunds(rpc: &mut dyn ClnRpcApi) -> Result<()> {
+    rpc.listfunds(&ListfundsRequest { spent: None })
+        .await
+        .context("Failed to call 'listfunds'")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lsp_primitives::lsps0::common_schemas::SatAmount;
+    use lsp_primitives::lsps0::features::set_feature_bit;
+
+    use crate::cln::rpc_api::test_support::FakeClnRpc;
+    use crate::db::sqlite::test::get_temp_db;
+    use crate::state::test_support::{test_info, test_state};
+
+    fn invoice() -> serde_json::Value {
+        json!({
+            "bolt11" : "lnbcrt10p1pjscripted",
+            "payment_hash" : "00".repeat(32),
+            "payment_secret" : "00".repeat(32),
+            "expires_at" : 1_700_000_000,
+        })
+    }
+
+    fn delinvoice(label: &str) -> serde_json::Value {
+        json!({
+            "label" : label,
+            "payment_hash" : "00".repeat(32),
+            "status" : "unpaid",
+            "expires_at" : 1_700_000_000,
+            "created_index" : 1,
+        })
+    }
+
+    fn statuses(report: &SelfCheckReport) -> Vec<(&'static str, &'static str)> {
+        report
+            .outcomes
+            .iter()
+            .map(|(check, outcome)| {
+                let status = match outcome {
+                    CheckOutcome::Passed { .. } => "passed",
+                    CheckOutcome::Failed { .. } => "failed",
+                    CheckOutcome::Skipped => "skipped",
+                };
+                (check.name(), status)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn healthy_server_passes() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+        let mut rpc = FakeClnRpc::default();
+        rpc.respond("invoice", invoice())
+            .respond("delinvoice", delinvoice("lsps1-selfcheck-test"))
+            .respond("listfunds", json!({ "outputs" : [], "channels" : [] }));
+
+        let report = run_selfcheck(&state, &mut rpc, &[SelfCheck::FeatureBits]).await;
+        assert!(report.is_ok(), "{}", report.to_json());
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("database", "passed"),
+                ("options", "passed"),
+                ("featurebits", "skipped"),
+                ("invoice", "passed"),
+                ("listfunds", "passed"),
+            ]
+        );
+
+        // The test invoice uses a reserved label and is deleted again
+        let label = rpc.params_of("invoice").unwrap()["label"].clone();
+        assert!(label
+            .as_str()
+            .unwrap()
+            .starts_with(SELFCHECK_INVOICE_LABEL_PREFIX));
+        assert_eq!(rpc.params_of("invoice").unwrap()["amount_msat"], "1msat");
+        assert_eq!(rpc.params_of("delinvoice").unwrap()["label"], label);
+    }
+
+    #[tokio::test]
+    async fn broken_dependencies_are_reported() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db.clone());
+        db.close().await;
+        let mut rpc = FakeClnRpc::default();
+        rpc.disconnect("getinfo")
+            .respond("invoice", invoice())
+            .fail("delinvoice", "Unknown invoice")
+            .respond("listfunds", json!({ "outputs" : [], "channels" : [] }));
+
+        let report = run_selfcheck(&state, &mut rpc, &[]).await;
+        assert!(!report.is_ok());
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("database", "failed"),
+                ("options", "passed"),
+                ("featurebits", "failed"),
+                ("invoice", "failed"),
+                ("listfunds", "passed"),
+            ]
+        );
+
+        let report = report.to_json();
+        assert_eq!(report["ok"], false);
+        let error = report["checks"][3]["error"].as_str().unwrap();
+        assert!(error.contains("Failed to delete the test invoice"));
+    }
+
+    #[tokio::test]
+    async fn disabled_lsps1_fails_the_options_check() {
+        let (db, _) = get_temp_db().await;
+        let state = test_state(db);
+        state.set_lsps1_info(None);
+        let mut rpc = FakeClnRpc::default();
+
+        let skip = [
+            SelfCheck::Database,
+            SelfCheck::FeatureBits,
+            SelfCheck::Invoice,
+            SelfCheck::Listfunds,
+        ];
+        let report = run_selfcheck(&state, &mut rpc, &skip).await;
+        assert!(!report.is_ok());
+        assert!(rpc.called_methods().is_empty());
+    }
+
+    #[test]
+    fn inconsistent_options_are_invalid() {
+        let mut options = test_info().options;
+        assert!(validate_options(&options).is_ok());
+
+        options.min_initial_lsp_balance_sat = SatAmount::new(2_000_000);
+        let err = validate_options(&options).unwrap_err();
+        assert!(err.to_string().contains("min_initial_lsp_balance_sat"));
+    }
+
+    #[test]
+    fn feature_bit_must_be_set() {
+        let features = set_feature_bit("", LSPS_FEATURE_BIT).unwrap();
+        assert!(ensure_lsps_feature_bit("node", &features).is_ok());
+        assert!(ensure_lsps_feature_bit("node", "08a0000a8a5961").is_err());
+    }
+
+    #[test]
+    fn parse_check_names() {
+        for check in SelfCheck::ALL {
+            assert_eq!(check.name().parse::<SelfCheck>().unwrap(), check);
+        }
+        assert!("wallet".parse::<SelfCheck>().is_err());
+    }
+}
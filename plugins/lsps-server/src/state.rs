@@ -1,19 +1,201 @@
 use lsp_primitives::methods::Lsps1GetInfoResponse;
 
+use crate::clock::Clock;
+use crate::custom_msg::outbox::DEFAULT_RESPONSE_RETRY_TTL;
+use crate::custom_msg::peer_queue::PeerQueues;
+use crate::db::sqlite::quarantine::Quarantine;
 use crate::db::sqlite::Database;
-use std::sync::Arc;
+use crate::feerate_cache::FeerateCache;
+use crate::health::HealthGate;
+use crate::lsps1::channel_usability::ChannelUsabilityCache;
+use crate::lsps1::funding_source::FundingSources;
+use crate::lsps1::hooks::DisabledResponse;
+use crate::lsps1::open_queue::OpenQueue;
+use crate::lsps1::order_expiry::DEFAULT_PAYMENT_GRACE;
+use crate::lsps1::order_log::OrderLogger;
+use crate::lsps1::order_watcher::OrderWatcher;
+use crate::metrics::Metrics;
+use crate::peer_policy::PeerPolicy;
+use lsp_primitives::lsps0::common_schemas::{OnchainAddress, PublicKey};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 #[derive(Clone)]
 pub(crate) struct PluginState {
     pub(crate) database: Database, // Already uses Arc under the hood. Cheap and safe to clone
-    pub(crate) lsps1_info: Arc<Option<Lsps1GetInfoResponse>>, //
+    /// Use [`PluginState::lsps1_info`] to read it
+    ///
+    /// The info is replaced as a whole. Readers keep the info they have
+    /// read even if it is replaced.
+    lsps1_info: Arc<RwLock<Option<Arc<Lsps1GetInfoResponse>>>>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) order_watcher: Arc<OrderWatcher>,
+    pub(crate) open_queue: Arc<OpenQueue>,
+    pub(crate) peer_policy: Arc<PeerPolicy>,
+    /// Restrict the wallet outputs that fund the channel of an order
+    pub(crate) funding_sources: Arc<FundingSources>,
+    pub(crate) order_log: OrderLogger,
+    pub(crate) health: Arc<HealthGate>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) quarantine: Arc<Quarantine>,
+    /// The fee estimates used to price orders and open channels
+    pub(crate) feerates: Arc<FeerateCache>,
+    /// The channels of the peers that poll `lsps1.get_order`
+    pub(crate) channel_usability: Arc<ChannelUsabilityCache>,
+    /// Receives the funds of the LSP when a leased channel is closed
+    pub(crate) close_to: Option<OnchainAddress>,
+    /// Responses that fail to reach a peer are retried this long
+    pub(crate) response_retry_ttl: Duration,
+    /// What clients see while LSPS1 is disabled
+    pub(crate) lsps1_disabled_response: DisabledResponse,
+    /// Payments that arrive this long after an order expired are accepted
+    pub(crate) payment_grace: Duration,
+    /// Handles the requests of a peer in order. `None` if disabled
+    pub(crate) peer_queues: Option<Arc<PeerQueues>>,
 }
 
 impl PluginState {
-    pub(crate) fn new(database: Database, lsps1_info: Option<Lsps1GetInfoResponse>) -> Self {
+    pub(crate) fn new(
+        database: Database,
+        lsps1_info: Option<Lsps1GetInfoResponse>,
+        clock: Arc<dyn Clock>,
+        peer_policy: PeerPolicy,
+        order_log: OrderLogger,
+        health: HealthGate,
+        close_to: Option<OnchainAddress>,
+    ) -> Self {
         Self {
             database,
-            lsps1_info: Arc::new(lsps1_info),
+            lsps1_info: Arc::new(RwLock::new(lsps1_info.map(Arc::new))),
+            clock,
+            order_watcher: Arc::new(OrderWatcher::default()),
+            open_queue: Arc::new(OpenQueue::default()),
+            peer_policy: Arc::new(peer_policy),
+            funding_sources: Arc::new(FundingSources::default()),
+            order_log,
+            health: Arc::new(health),
+            metrics: Arc::new(Metrics::default()),
+            quarantine: Arc::new(Quarantine::default()),
+            feerates: Arc::new(FeerateCache::default()),
+            channel_usability: Arc::new(ChannelUsabilityCache::default()),
+            close_to,
+            response_retry_ttl: DEFAULT_RESPONSE_RETRY_TTL,
+            lsps1_disabled_response: DisabledResponse::default(),
+            payment_grace: DEFAULT_PAYMENT_GRACE,
+            peer_queues: None,
         }
     }
+
+    pub(crate) fn with_response_retry_ttl(mut self, response_retry_ttl: Duration) -> Self {
+        self.response_retry_ttl = response_retry_ttl;
+        self
+    }
+
+    pub(crate) fn with_lsps1_disabled_response(
+        mut self,
+        lsps1_disabled_response: DisabledResponse,
+    ) -> Self {
+        self.lsps1_disabled_response = lsps1_disabled_response;
+        self
+    }
+
+    pub(crate) fn with_payment_grace(mut self, payment_grace: Duration) -> Self {
+        self.payment_grace = payment_grace;
+        self
+    }
+
+    pub(crate) fn with_funding_sources(mut self, funding_sources: FundingSources) -> Self {
+        self.funding_sources = Arc::new(funding_sources);
+        self
+    }
+
+    pub(crate) fn with_peer_queues(mut self, peer_queues: Option<PeerQueues>) -> Self {
+        self.peer_queues = peer_queues.map(Arc::new);
+        self
+    }
+
+    /// The response to `lsps1.get_info`
+    ///
+    /// `None` if LSPS1 is not configured
+    pub(crate) fn lsps1_info(&self) -> Option<Arc<Lsps1GetInfoResponse>> {
+        self.lsps1_info.read().unwrap().clone()
+    }
+
+    /// Replaces the response to `lsps1.get_info`
+    ///
+    /// Handlers that are running keep using the previous info
+    pub(crate) fn set_lsps1_info(&self, lsps1_info: Option<Lsps1GetInfoResponse>) {
+        *self.lsps1_info.write().unwrap() = lsps1_info.map(Arc::new);
+    }
+
+    /// Like [`PluginState::set_lsps1_info`] but returns the info that was replaced
+    pub(crate) fn replace_lsps1_info(
+        &self,
+        lsps1_info: Option<Lsps1GetInfoResponse>,
+    ) -> Option<Arc<Lsps1GetInfoResponse>> {
+        std::mem::replace(
+            &mut *self.lsps1_info.write().unwrap(),
+            lsps1_info.map(Arc::new),
+        )
+    }
+
+    /// Returns true if `peer_id` may use LSPS1.
+    ///
+    /// This doesn't check if LSPS1 is enabled
+    pub(crate) fn is_lsps1_allowed_for(&self, peer_id: &PublicKey) -> bool {
+        self.peer_policy.is_allowed(peer_id)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::clock::SystemClock;
+    use crate::lsps1::order_log::{OrderLogWriter, ORDER_LOG_CAPACITY};
+
+    /// A server that sells channels of up to 1_000_000 sat
+    pub(crate) fn test_info() -> Lsps1GetInfoResponse {
+        serde_json::from_value(json!({
+            "options" : {
+                "min_required_channel_confirmations": 0,
+                "min_funding_confirms_within_blocks": 1,
+                "min_onchain_payment_confirmations": null,
+                "supports_zero_channel_reserve": true,
+                "min_onchain_payment_size_sat": null,
+                "max_channel_expiry_blocks": 20160,
+                "min_initial_client_balance_sat": "0",
+                "max_initial_client_balance_sat": "0",
+                "min_initial_lsp_balance_sat": "0",
+                "max_initial_lsp_balance_sat": "1000000",
+                "min_channel_balance_sat": "0",
+                "max_channel_balance_sat": "1000000"
+            }
+        }))
+        .unwrap()
+    }
+
+    /// The state of a healthy server that serves every peer
+    ///
+    /// Entries in the order log are dropped
+    pub(crate) fn test_state(database: Database) -> PluginState {
+        test_state_with_order_log(database).0
+    }
+
+    /// Like [`test_state`] but the order log can be written using the returned writer
+    pub(crate) fn test_state_with_order_log(database: Database) -> (PluginState, OrderLogWriter) {
+        let (order_log, writer) = OrderLogger::new(Arc::new(SystemClock), ORDER_LOG_CAPACITY);
+        let state = PluginState::new(
+            database,
+            Some(test_info()),
+            Arc::new(SystemClock),
+            PeerPolicy::default(),
+            order_log,
+            HealthGate::new(3),
+            None,
+        );
+        (state, writer)
+    }
 }
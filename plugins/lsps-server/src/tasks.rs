@@ -0,0 +1,273 @@
+//! Supervises the background tasks of the plugin
+//!
+//! A task that panics is restarted after [`TASK_RESTART_DELAY`]. A task
+//! that keeps panicking is stopped after [`MAX_TASK_RESTARTS`] restarts
+//! so a persistent bug doesn't flood the log. The state of every task is
+//! shown by `lsps1-admin-metrics` so operators notice tasks that died.
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::metrics::Metrics;
+
+/// The number of times a task is restarted before it is given up
+pub(crate) const MAX_TASK_RESTARTS: u32 = 5;
+
+/// The time between a panic and the restart of the task
+pub(crate) const TASK_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TaskState {
+    Running,
+    /// The task panicked and will be restarted
+    Restarting,
+    /// The task completed its work
+    Finished,
+    /// The task panicked too often or was cancelled
+    Dead,
+}
+
+impl TaskState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Restarting => "restarting",
+            Self::Finished => "finished",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TaskStatus {
+    state: TaskState,
+    restarts: u32,
+    /// The number of times the task reconnected to lightningd
+    reconnects: u64,
+    last_panic: Option<String>,
+}
+
+impl Default for TaskStatus {
+    fn default() -> Self {
+        Self {
+            state: TaskState::Running,
+            restarts: 0,
+            reconnects: 0,
+            last_panic: None,
+        }
+    }
+}
+
+/// The health of all background tasks by name
+#[derive(Debug, Default)]
+pub(crate) struct TaskHealth {
+    tasks: Mutex<BTreeMap<&'static str, TaskStatus>>,
+}
+
+impl TaskHealth {
+    fn set_state(&self, name: &'static str, state: TaskState) {
+        self.tasks.lock().unwrap().entry(name).or_default().state = state;
+    }
+
+    /// Records that the task lost its connection to lightningd and reconnected
+    pub(crate) fn reconnected(&self, name: &'static str) {
+        self.tasks
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .reconnects += 1;
+    }
+
+    /// Records a panic of the task
+    ///
+    /// Returns true if the task may be restarted
+    fn panicked(&self, name: &'static str, message: String, max_restarts: u32) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        let status = tasks.entry(name).or_default();
+        status.last_panic = Some(message);
+        if status.restarts >= max_restarts {
+            status.state = TaskState::Dead;
+            return false;
+        }
+        status.restarts += 1;
+        status.state = TaskState::Restarting;
+        true
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state_of(&self, name: &str) -> Option<TaskState> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|status| status.state)
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let tasks = self.tasks.lock().unwrap();
+        let tasks: serde_json::Map<String, serde_json::Value> = tasks
+            .iter()
+            .map(|(name, status)| {
+                let status = json!({
+                    "state" : status.state.as_str(),
+                    "restarts" : status.restarts,
+                    "reconnects" : status.reconnects,
+                    "last_panic" : status.last_panic,
+                });
+                (name.to_string(), status)
+            })
+            .collect();
+        serde_json::Value::Object(tasks)
+    }
+}
+
+/// Spawns a background task that is restarted if it panics
+///
+/// `task` is called again to create the future of every restart.
+pub(crate) fn spawn_supervised<F, Fut>(
+    metrics: Arc<Metrics>,
+    name: &'static str,
+    task: F,
+) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(supervise(
+        metrics,
+        name,
+        task,
+        MAX_TASK_RESTARTS,
+        TASK_RESTART_DELAY,
+    ))
+}
+
+async fn supervise<F, Fut>(
+    metrics: Arc<Metrics>,
+    name: &'static str,
+    task: F,
+    max_restarts: u32,
+    restart_delay: Duration,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let health = &metrics.tasks;
+    loop {
+        health.set_state(name, TaskState::Running);
+        let err = match tokio::spawn(task()).await {
+            Ok(()) => {
+                log::debug!("Task '{}' finished", name);
+                health.set_state(name, TaskState::Finished);
+                return;
+            }
+            Err(err) => err,
+        };
+
+        if !err.is_panic() {
+            log::warn!("Task '{}' was cancelled", name);
+            health.set_state(name, TaskState::Dead);
+            return;
+        }
+
+        let message = panic_message(err.into_panic());
+        if !health.panicked(name, message.clone(), max_restarts) {
+            log::error!(
+                "Task '{}' panicked {} times and is stopped: {}",
+                name,
+                max_restarts + 1,
+                message
+            );
+            return;
+        }
+        log::error!(
+            "Task '{}' panicked and is restarted in {:?}: {}",
+            name,
+            restart_delay,
+            message
+        );
+        tokio::time::sleep(restart_delay).await;
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn task_that_panics_is_restarted() {
+        let metrics = Arc::new(Metrics::default());
+        let runs = Arc::new(AtomicU32::new(0));
+
+        // The task panics twice before it completes its work
+        let task_runs = runs.clone();
+        supervise(
+            metrics.clone(),
+            "flaky",
+            move || {
+                let runs = task_runs.clone();
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                        panic!("Lost the database");
+                    }
+                }
+            },
+            MAX_TASK_RESTARTS,
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(metrics.tasks.state_of("flaky"), Some(TaskState::Finished));
+        let status = &metrics.tasks.to_json()["flaky"];
+        assert_eq!(status["restarts"], 2);
+        assert_eq!(status["last_panic"], "Lost the database");
+    }
+
+    #[tokio::test]
+    async fn task_that_keeps_panicking_is_stopped() {
+        let metrics = Arc::new(Metrics::default());
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let task_runs = runs.clone();
+        supervise(
+            metrics.clone(),
+            "broken",
+            move || {
+                let runs = task_runs.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    panic!("Invalid state {}", 42);
+                }
+            },
+            2,
+            Duration::from_millis(1),
+        )
+        .await;
+
+        // The first run and two restarts
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(metrics.tasks.state_of("broken"), Some(TaskState::Dead));
+        let status = &metrics.tasks.to_json()["broken"];
+        assert_eq!(status["restarts"], 2);
+        assert_eq!(status["last_panic"], "Invalid state 42");
+    }
+}
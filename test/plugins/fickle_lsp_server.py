@@ -0,0 +1,106 @@
+#!/usr/bin/env python
+"""
+This plugin is used to test how the LSPS-client handles an LSP that
+changes an order after it was created.
+
+`lsps1.create_order` returns a fixed order. `lsps1.get_order` returns the
+same order with the fields listed in `test-changed-fields` changed.
+"""
+import copy
+import json
+import threading
+
+from pyln.client import Plugin
+
+LSPS_MESSAGE_ID = bytes.fromhex("9419")
+
+ORDER = {
+    "order_id": "bb4b5d0a-8334-49d8-9463-90a6d413af7c",
+    "lsp_balance_sat": "100000",
+    "client_balance_sat": "0",
+    "funding_confirms_within_blocks": 6,
+    "required_channel_confirmations": 0,
+    "channel_expiry_blocks": 1000,
+    "announce_channel": False,
+    "created_at": "2024-01-01T00:00:00.000Z",
+    "expires_at": "2099-01-01T00:00:00.000Z",
+    "order_state": "CREATED",
+    "payment": {
+        "state": "EXPECT_PAYMENT",
+        "fee_total_sat": "1000",
+        "order_total_sat": "1000",
+        "bolt11_invoice": "lnbcrt10u1pjfirstinvoice",
+        "onchain_address": None,
+        "min_onchain_payment_confirmations": None,
+        "min_fee_for_0conf": None,
+        "onchain_payment": None,
+    },
+    "channel": None,
+}
+
+INFO = {
+    "options": {
+        "min_required_channel_confirmations": 0,
+        "min_funding_confirms_within_blocks": 6,
+        "min_onchain_payment_confirmations": None,
+        "supports_zero_channel_reserve": False,
+        "min_onchain_payment_size_sat": None,
+        "max_channel_expiry_blocks": 20000,
+        "min_initial_client_balance_sat": "0",
+        "max_initial_client_balance_sat": "0",
+        "min_initial_lsp_balance_sat": "0",
+        "max_initial_lsp_balance_sat": "1000000",
+        "min_channel_balance_sat": "0",
+        "max_channel_balance_sat": "1000000",
+    }
+}
+
+plugin = Plugin(dynamic=False)
+
+plugin.add_option(
+    name="test-changed-fields",
+    default="",
+    description="Comma-separated payment fields that change in lsps1.get_order",
+    opt_type="string",
+)
+
+
+def get_order():
+    order = copy.deepcopy(ORDER)
+    changed_fields = plugin.get_option("test-changed-fields").split(",")
+    if "bolt11_invoice" in changed_fields:
+        order["payment"]["bolt11_invoice"] = "lnbcrt20u1pjsecondinvoice"
+    if "fee_total_sat" in changed_fields:
+        order["payment"]["fee_total_sat"] = "2000"
+    if "order_total_sat" in changed_fields:
+        order["payment"]["order_total_sat"] = "2000"
+    return order
+
+
+def respond(peer_id, request):
+    if request["method"] == "lsps1.get_info":
+        result = INFO
+    elif request["method"] == "lsps1.create_order":
+        result = ORDER
+    elif request["method"] == "lsps1.get_order":
+        result = get_order()
+    else:
+        result = {}
+
+    response = {"jsonrpc": "2.0", "id": request["id"], "result": result}
+    msg = LSPS_MESSAGE_ID + json.dumps(response).encode()
+    plugin.rpc.sendcustommsg(peer_id, msg.hex())
+
+
+@plugin.hook("custommsg")
+def on_custommsg(peer_id, payload, **kwargs):
+    data = bytes.fromhex(payload)
+    if data[:2] != LSPS_MESSAGE_ID:
+        return {"result": "continue"}
+
+    request = json.loads(data[2:])
+    threading.Thread(target=respond, args=(peer_id, request)).start()
+    return {"result": "continue"}
+
+
+plugin.run()
@@ -0,0 +1,47 @@
+#!/usr/bin/env python
+"""
+This plugin is used to test time-outs and retries of the LSPS-client.
+
+It responds to every LSPS0 request with an empty result after
+waiting for `test-response-delay-ms`.
+"""
+import json
+import threading
+import time
+
+from pyln.client import Plugin
+
+LSPS_MESSAGE_ID = bytes.fromhex("9419")
+
+plugin = Plugin(dynamic=False)
+
+plugin.add_option(
+    name="test-response-delay-ms",
+    default=0,
+    description="The amount of milliseconds to wait before responding",
+    opt_type="int",
+)
+
+
+def respond_later(peer_id, request):
+    delay_ms = plugin.get_option("test-response-delay-ms")
+    time.sleep(delay_ms / 1000)
+
+    response = {"jsonrpc": "2.0", "id": request["id"], "result": {}}
+    msg = LSPS_MESSAGE_ID + json.dumps(response).encode()
+    plugin.rpc.sendcustommsg(peer_id, msg.hex())
+    plugin.log(f"Responded to request id={request['id']}")
+
+
+@plugin.hook("custommsg")
+def on_custommsg(peer_id, payload, **kwargs):
+    data = bytes.fromhex(payload)
+    if data[:2] != LSPS_MESSAGE_ID:
+        return {"result": "continue"}
+
+    request = json.loads(data[2:])
+    threading.Thread(target=respond_later, args=(peer_id, request)).start()
+    return {"result": "continue"}
+
+
+plugin.run()
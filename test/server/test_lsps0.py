@@ -20,6 +20,7 @@ def test_server_responds_to_unknown_method(lsps_server, lsps_client):
 
     assert response["error"]["code"] == -32601
     assert response["error"]["message"] == "Method not found"
+    assert response["error"]["data"]["reason"] == "method_not_found"
 
 
 def test_server_responds_to_lsps0_list_protocols(lsps_server, lsps_client):
@@ -0,0 +1,80 @@
+import logging
+from concurrent.futures import ThreadPoolExecutor
+
+from pyln.testing.fixtures import *
+from pyln.testing.utils import NodeFactory, LightningNode, wait_for
+
+from test.fixtures import get_client_plugin_path, get_server_plugin_path
+from test.util.options import developer_options, lsps1_server_options
+
+logger = logging.getLogger(__name__)
+
+
+def get_dual_role_node(node_factory: NodeFactory) -> LightningNode:
+    """A node that runs both the LSP-client and the LSP-server"""
+    return node_factory.get_node(
+        options={
+            "plugin": [get_server_plugin_path(), get_client_plugin_path()],
+            **lsps1_server_options(),
+            **developer_options(),
+        }
+    )
+
+
+def test_dual_role_nodes_order_channels_from_each_other(
+    node_factory: NodeFactory, bitcoind
+):
+    """Two nodes that are both LSP and client order a channel from each other"""
+    node_a = get_dual_role_node(node_factory)
+    node_b = get_dual_role_node(node_factory)
+    node_a.fundwallet(100_000_000 * 10)
+    node_b.fundwallet(100_000_000 * 10)
+
+    # Both nodes need a channel to pay the other one
+    node_a.connect(node_b)
+    node_a.openchannel(node_b)
+    node_b.openchannel(node_a)
+
+    for client, server in [(node_a, node_b), (node_b, node_a)]:
+        result = client.rpc.lsps0_list_protocols(server.info["id"])
+        assert 1 in result["raw"]
+
+    def order_channel(client: LightningNode, server: LightningNode) -> str:
+        response = client.rpc.lsps1_create_order(
+            peer_id=server.info["id"],
+            lsp_balance_sat="123456",
+            channel_expiry_blocks=144,
+        )
+        client.rpc.pay(response["payment"]["bolt11_invoice"])
+        return response["order_id"]
+
+    # Both orders are created and paid at the same time
+    with ThreadPoolExecutor(max_workers=2) as executor:
+        futures = [
+            executor.submit(order_channel, node_a, node_b),
+            executor.submit(order_channel, node_b, node_a),
+        ]
+        order_ids = [future.result() for future in futures]
+
+    orders = [
+        (node_a, node_b, order_ids[0]),
+        (node_b, node_a, order_ids[1]),
+    ]
+
+    def get_order(client: LightningNode, server: LightningNode, order_id: str):
+        return client.rpc.lsps1_get_order(peer_id=server.info["id"], order_id=order_id)
+
+    for client, server, order_id in orders:
+        wait_for(lambda: get_order(client, server, order_id)["channel"] is not None)
+    bitcoind.generate_block(6)
+    for client, server, order_id in orders:
+        wait_for(
+            lambda: get_order(client, server, order_id)["order_state"] == "COMPLETED"
+        )
+
+    # Neither plugin handled a message that was meant for the other one
+    for node in [node_a, node_b]:
+        assert not node.daemon.is_in_log("Received unmatched response")
+        assert not node.daemon.is_in_log("Failed to process custom message")
+        assert node.daemon.is_in_log("Ignoring JSON-RPC request")
+        assert node.daemon.is_in_log("Ignoring JSON-RPC response")
@@ -4,6 +4,8 @@ import os
 from threading import Thread
 
 import typing as t
+import pytest
+from pyln.client import RpcError
 
 from pyln.testing.fixtures import *
 from pyln.testing.utils import NodeFactory, LightningNode, wait_for
@@ -112,10 +114,11 @@ def test_lsps0_list_protocols(node_factory: NodeFactory):
 
     logger.info("Client requests lsps0.list_protocols")
     result = lsps_client.rpc.lsps0_list_protocols(server_node_id)
-    protocols = result["protocols"]
+    protocols = result["raw"]
 
     assert len(protocols) >= 0
     assert 0 in protocols
+    assert result["protocols"][protocols.index(0)]["name"] == "LSPS0"
 
 
 def test_lsps1_get_info(node_factory: NodeFactory):
@@ -152,3 +155,10 @@ def test_lsps1_get_info(node_factory: NodeFactory):
     result = lsps_client.rpc.lsps1_get_info(server_node_id)
 
     assert "options" in result, "The response should have an options dict"
+
+    # Params can be passed by name or by position
+    result = lsps_client.rpc.call("lsps1-get-info", {"peer_id": server_node_id})
+    assert "options" in result
+
+    with pytest.raises(RpcError, match="Too many positional params"):
+        lsps_client.rpc.call("lsps1-get-info", [server_node_id, "unexpected"])
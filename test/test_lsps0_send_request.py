@@ -0,0 +1,81 @@
+import os
+
+from pyln.testing.fixtures import *
+from pyln.testing.utils import NodeFactory, LightningNode
+
+from test.fixtures import lsps_client
+from test.util.options import developer_options
+
+
+def get_slow_server_plugin_path() -> str:
+    return os.path.join(os.path.dirname(__file__), "plugins/slow_lsp_server.py")
+
+
+def test_send_request_reports_metadata(node_factory: NodeFactory, lsps_client):
+    server: LightningNode = node_factory.get_node(
+        options={
+            "plugin": get_slow_server_plugin_path(),
+            "test-response-delay-ms": 0,
+            **developer_options(),
+        }
+    )
+    lsps_client.connect(server)
+
+    response = lsps_client.rpc.lsps0_send_request(
+        peer_id=server.info["id"], method="lsps0.list_protocols", params="{}"
+    )
+
+    assert response["result"] == {}
+    assert response["metadata"]["attempts"] == 1
+    assert response["metadata"]["answered_id"] == response["id"]
+
+
+def test_send_request_accepts_late_response_after_retry(
+    node_factory: NodeFactory, lsps_client
+):
+    """The response to the first attempt arrives after it timed out"""
+    server: LightningNode = node_factory.get_node(
+        options={
+            "plugin": get_slow_server_plugin_path(),
+            "test-response-delay-ms": 1500,
+            **developer_options(),
+        }
+    )
+    lsps_client.connect(server)
+
+    response = lsps_client.rpc.lsps0_send_request(
+        peer_id=server.info["id"],
+        method="lsps0.list_protocols",
+        params="{}",
+        timeout_ms=1000,
+        retries=2,
+    )
+
+    assert response["result"] == {}
+    assert response["metadata"]["attempts"] == 2
+    assert response["metadata"]["answered_id"] == response["id"]
+    assert response["metadata"]["elapsed_ms"] >= 1500
+
+    # The response to the second attempt is ignored
+    lsps_client.daemon.wait_for_log("Ignoring late response to abandoned request")
+    assert not lsps_client.daemon.is_in_log("Received unmatched response")
+
+
+def test_send_request_times_out(node_factory: NodeFactory, lsps_client):
+    server: LightningNode = node_factory.get_node(
+        options={
+            "plugin": get_slow_server_plugin_path(),
+            "test-response-delay-ms": 5000,
+            **developer_options(),
+        }
+    )
+    lsps_client.connect(server)
+
+    with pytest.raises(Exception, match="after 2 attempts"):
+        lsps_client.rpc.lsps0_send_request(
+            peer_id=server.info["id"],
+            method="lsps0.list_protocols",
+            params="{}",
+            timeout_ms=500,
+            retries=1,
+        )
@@ -0,0 +1,152 @@
+import os
+
+import pytest
+from pyln.client import RpcError
+from pyln.testing.fixtures import *
+from pyln.testing.utils import NodeFactory, LightningNode
+
+from test.fixtures import get_client_plugin_path, lsps_client
+from test.util.options import developer_options
+
+ORDER_ID = "bb4b5d0a-8334-49d8-9463-90a6d413af7c"
+
+
+def get_fickle_server(node_factory: NodeFactory, changed_fields: str) -> LightningNode:
+    return node_factory.get_node(
+        options={
+            "plugin": os.path.join(
+                os.path.dirname(__file__), "plugins/fickle_lsp_server.py"
+            ),
+            "test-changed-fields": changed_fields,
+            **developer_options(),
+        }
+    )
+
+
+def create_order(lsps_client, server):
+    return lsps_client.rpc.lsps1_create_order(
+        peer_id=server.info["id"],
+        lsp_balance_sat="100000",
+        channel_expiry_blocks=1000,
+    )
+
+
+def test_consistent_order_has_no_warnings(node_factory: NodeFactory, lsps_client):
+    server = get_fickle_server(node_factory, "")
+    lsps_client.connect(server)
+
+    order = create_order(lsps_client, server)
+    assert order["warnings"] == []
+
+    order = lsps_client.rpc.lsps1_get_order(
+        peer_id=server.info["id"], order_id=ORDER_ID
+    )
+    assert order["warnings"] == []
+
+    orders = lsps_client.rpc.lsps1_list_orders()["orders"]
+    assert len(orders) == 1
+    assert orders[0]["order_id"] == ORDER_ID
+    assert orders[0]["warnings"] == []
+
+
+def test_changed_invoice_is_flagged(node_factory: NodeFactory, lsps_client):
+    server = get_fickle_server(node_factory, "bolt11_invoice")
+    lsps_client.connect(server)
+
+    create_order(lsps_client, server)
+    order = lsps_client.rpc.lsps1_get_order(
+        peer_id=server.info["id"], order_id=ORDER_ID
+    )
+    assert order["warnings"] == ["lsp_inconsistent"]
+
+    with pytest.raises(RpcError, match="Refusing to pay order"):
+        lsps_client.rpc.lsps1_get_invoice(
+            peer_id=server.info["id"], order_id=ORDER_ID
+        )
+
+    orders = lsps_client.rpc.lsps1_list_orders()["orders"]
+    assert orders[0]["changed_fields"] == ["bolt11_invoice"]
+    assert orders[0]["warnings"] == ["lsp_inconsistent"]
+
+
+def test_changed_totals_are_flagged(node_factory: NodeFactory, lsps_client):
+    server = get_fickle_server(node_factory, "fee_total_sat,order_total_sat")
+    lsps_client.connect(server)
+
+    create_order(lsps_client, server)
+    order = lsps_client.rpc.lsps1_get_order(
+        peer_id=server.info["id"], order_id=ORDER_ID
+    )
+    assert order["warnings"] == ["lsp_inconsistent"]
+
+    orders = lsps_client.rpc.lsps1_list_orders()["orders"]
+    assert orders[0]["changed_fields"] == ["fee_total_sat", "order_total_sat"]
+    # The totals of the first response are kept
+    assert orders[0]["order_total_sat"] == 1000
+
+
+def test_await_order_refuses_changed_order(node_factory: NodeFactory, lsps_client):
+    server = get_fickle_server(node_factory, "bolt11_invoice")
+    lsps_client.connect(server)
+
+    create_order(lsps_client, server)
+    with pytest.raises(RpcError, match="Refusing to wait for order"):
+        lsps_client.rpc.lsps1_await_order(
+            peer_id=server.info["id"], order_id=ORDER_ID, timeout_seconds=1
+        )
+
+
+def test_pins_survive_a_restart(node_factory: NodeFactory, lsps_client):
+    server = get_fickle_server(node_factory, "order_total_sat")
+    lsps_client.connect(server)
+
+    create_order(lsps_client, server)
+    lsps_client.rpc.lsps1_get_order(peer_id=server.info["id"], order_id=ORDER_ID)
+
+    lsps_client.restart()
+    lsps_client.connect(server)
+
+    orders = lsps_client.rpc.lsps1_list_orders()["orders"]
+    assert len(orders) == 1
+    assert orders[0]["changed_fields"] == ["order_total_sat"]
+    assert orders[0]["warnings"] == ["lsp_inconsistent"]
+
+    with pytest.raises(RpcError, match="Refusing to pay order"):
+        lsps_client.rpc.lsps1_get_invoice(
+            peer_id=server.info["id"], order_id=ORDER_ID
+        )
+
+
+def test_orders_are_exported_and_imported(
+    node_factory: NodeFactory, lsps_client, tmp_path
+):
+    server = get_fickle_server(node_factory, "order_total_sat")
+    lsps_client.connect(server)
+
+    create_order(lsps_client, server)
+    lsps_client.rpc.lsps1_get_order(peer_id=server.info["id"], order_id=ORDER_ID)
+    orders = lsps_client.rpc.lsps1_list_orders()["orders"]
+
+    path = str(tmp_path / "orders.json")
+    export = lsps_client.rpc.lsps1_export_orders(path=path)
+    assert export["schema_version"] == 1
+    assert export["orders"] == 1
+
+    # Restore the orders on another node
+    restored = node_factory.get_node(
+        options={"plugin": get_client_plugin_path(), **developer_options()}
+    )
+    result = restored.rpc.lsps1_import_orders(path=path)
+    assert result == {"imported": 1, "unchanged": 0, "conflicts": []}
+    assert restored.rpc.lsps1_list_orders()["orders"] == orders
+
+    # Importing again doesn't duplicate the order
+    result = restored.rpc.lsps1_import_orders(path=path)
+    assert result == {"imported": 0, "unchanged": 1, "conflicts": []}
+
+    # The imported orders are stored
+    restored.restart()
+    assert restored.rpc.lsps1_list_orders()["orders"] == orders
+
+    with pytest.raises(RpcError, match="Failed to read orders"):
+        restored.rpc.lsps1_import_orders(path=str(tmp_path / "missing.json"))